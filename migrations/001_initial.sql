@@ -66,3 +66,62 @@ CREATE TABLE IF NOT EXISTS message (
     PRIMARY KEY (chat_uid, chat_receiver, msg_id),
     FOREIGN KEY (chat_uid, chat_receiver) REFERENCES portal(uid, receiver) ON DELETE CASCADE
 );
+
+CREATE TABLE IF NOT EXISTS reaction (
+    chat_uid TEXT,
+    chat_receiver TEXT,
+    target_msg_id TEXT,
+    sender TEXT,
+    mxid TEXT UNIQUE NOT NULL,
+    reaction_key TEXT NOT NULL,
+    PRIMARY KEY (chat_uid, chat_receiver, target_msg_id, sender),
+    FOREIGN KEY (chat_uid, chat_receiver) REFERENCES portal(uid, receiver) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS user_setting (
+    mxid TEXT PRIMARY KEY,
+    message_marker BOOLEAN,
+    FOREIGN KEY (mxid) REFERENCES "user"(mxid) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS chat_watermark (
+    chat_id TEXT PRIMARY KEY,
+    last_ts BIGINT NOT NULL,
+    last_msg_id TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS portal_relay (
+    chat_uid TEXT,
+    chat_receiver TEXT,
+    relay_mxid TEXT NOT NULL,
+    PRIMARY KEY (chat_uid, chat_receiver),
+    FOREIGN KEY (chat_uid, chat_receiver) REFERENCES portal(uid, receiver) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS outgoing_retry (
+    event_id TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    event TEXT NOT NULL,
+    notice_id TEXT
+);
+
+CREATE TABLE IF NOT EXISTS appservice_txn (
+    txn_id TEXT PRIMARY KEY,
+    processed_at BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS contact (
+    user_mxid TEXT NOT NULL,
+    uin TEXT NOT NULL,
+    name TEXT NOT NULL,
+    remark TEXT,
+    avatar TEXT,
+    deleted BOOLEAN NOT NULL DEFAULT false,
+    updated_at BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_mxid, uin)
+);
+
+CREATE TABLE IF NOT EXISTS permission_override (
+    mxid TEXT PRIMARY KEY,
+    level TEXT NOT NULL
+);
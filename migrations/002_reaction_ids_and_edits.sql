@@ -0,0 +1,15 @@
+-- The WeChat ID of a reaction, when it has one, and when it was sent.
+ALTER TABLE reaction ADD COLUMN msg_id TEXT;
+ALTER TABLE reaction ADD COLUMN timestamp BIGINT NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS message_edit (
+    chat_uid TEXT,
+    chat_receiver TEXT,
+    msg_id TEXT,
+    target_msg_id TEXT NOT NULL,
+    mxid TEXT UNIQUE NOT NULL,
+    sender TEXT NOT NULL,
+    timestamp BIGINT NOT NULL,
+    PRIMARY KEY (chat_uid, chat_receiver, msg_id),
+    FOREIGN KEY (chat_uid, chat_receiver) REFERENCES portal(uid, receiver) ON DELETE CASCADE
+);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS portal_setting (
+    chat_uid TEXT,
+    chat_receiver TEXT,
+    user_mxid TEXT,
+    muted BOOLEAN NOT NULL DEFAULT false,
+    plaintext_only BOOLEAN NOT NULL DEFAULT false,
+    relay BOOLEAN NOT NULL DEFAULT true,
+    room_name TEXT,
+    PRIMARY KEY (chat_uid, chat_receiver, user_mxid),
+    FOREIGN KEY (chat_uid, chat_receiver) REFERENCES portal(uid, receiver) ON DELETE CASCADE
+);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS media_cache (
+    hash TEXT PRIMARY KEY,
+    mxc TEXT NOT NULL,
+    mimetype TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    created_at BIGINT NOT NULL,
+    last_used BIGINT NOT NULL
+);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS agent_token (
+    id TEXT PRIMARY KEY,
+    token_hash TEXT NOT NULL,
+    description TEXT NOT NULL DEFAULT '',
+    created_at BIGINT NOT NULL,
+    last_used BIGINT NOT NULL DEFAULT 0,
+    revoked BOOLEAN NOT NULL DEFAULT false
+);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS event_sequence (
+    mxid TEXT PRIMARY KEY,
+    seq BIGINT NOT NULL,
+    updated_at BIGINT NOT NULL
+);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS matrix_queue (
+    mxid TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    chat_id TEXT NOT NULL,
+    event TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    queued_at BIGINT NOT NULL,
+    next_attempt_at BIGINT NOT NULL,
+    PRIMARY KEY (mxid, event_id)
+);
+CREATE INDEX IF NOT EXISTS idx_matrix_queue_queued_at ON matrix_queue (queued_at);
@@ -0,0 +1,11 @@
+-- Watermarks were shared by every account in a chat, so one account's progress dropped the
+-- events of the others. The old ones can't be attributed to an account and are dropped.
+DROP TABLE IF EXISTS chat_watermark;
+
+CREATE TABLE chat_watermark (
+    mxid TEXT NOT NULL,
+    chat_id TEXT NOT NULL,
+    last_ts BIGINT NOT NULL,
+    last_msg_id TEXT NOT NULL,
+    PRIMARY KEY (mxid, chat_id)
+);
@@ -0,0 +1,82 @@
+//! Administration commands that work on the database directly, for fixing things while the
+//! bridge is stopped.
+
+use anyhow::Result;
+
+use crate::config::Config;
+use crate::database::Database;
+
+async fn connect(config: &Config) -> Result<Database> {
+    let db_config = &config.appservice.database;
+    Database::connect(&db_config.r#type, &db_config.uri, db_config.max_open_conns, db_config.max_idle_conns).await
+}
+
+pub async fn migrate(config: &Config) -> Result<()> {
+    let db = connect(config).await?;
+    db.run_migrations().await?;
+    println!("Database schema is at version {}.", db.schema_version().await?);
+    Ok(())
+}
+
+/// Prints every portal as a line of JSON.
+pub async fn dump_portals(config: &Config) -> Result<()> {
+    let db = connect(config).await?;
+    for portal in db.get_all_portals().await? {
+        println!("{}", serde_json::to_string(&portal)?);
+    }
+    Ok(())
+}
+
+/// Forgets the WeChat account of a user, who has to log in again. Their portals are kept.
+pub async fn logout_user(config: &Config, mxid: &str) -> Result<()> {
+    let db = connect(config).await?;
+    let Some(mut user) = db.get_user_by_mxid(mxid).await? else {
+        anyhow::bail!("{} isn't a user of the bridge", mxid);
+    };
+    match user.uin.take() {
+        Some(uin) => {
+            db.update_user(&user).await?;
+            println!("Logged {} out of {}.", mxid, uin);
+        }
+        None => println!("{} isn't logged in.", mxid),
+    }
+    Ok(())
+}
+
+/// Deletes the portals of a WeChat chat, for all its users. Their Matrix rooms are left as they
+/// are, and listed so they can be cleaned up on the homeserver.
+pub async fn delete_portal(config: &Config, uid: &str) -> Result<()> {
+    let db = connect(config).await?;
+    let portals = db.get_portals_by_uid(uid).await?;
+    if portals.is_empty() {
+        anyhow::bail!("no portal for {}", uid);
+    }
+    for portal in portals {
+        db.delete_portal(&portal.key()).await?;
+        match &portal.mxid {
+            Some(room_id) => println!("Deleted the portal of {} for {}, room {}", uid, portal.receiver, room_id),
+            None => println!("Deleted the portal of {} for {}", uid, portal.receiver),
+        }
+    }
+    Ok(())
+}
+
+/// Makes the bridge upload media to the homeserver again, e.g. after its media store was lost:
+/// cached uploads are forgotten, and portal avatars are set again at the next sync.
+pub async fn reupload_media(config: &Config) -> Result<()> {
+    let db = connect(config).await?;
+    let cleared = db.clear_media_cache().await?;
+    let mut avatars = 0;
+    for mut portal in db.get_all_portals().await? {
+        if portal.avatar.is_empty() && portal.avatar_url.is_none() {
+            continue;
+        }
+        portal.avatar.clear();
+        portal.avatar_url = None;
+        portal.avatar_set = false;
+        db.update_portal(&portal).await?;
+        avatars += 1;
+    }
+    println!("Forgot {} cached uploads and {} portal avatars.", cleared, avatars);
+    Ok(())
+}
@@ -0,0 +1,34 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+/// When events of a user were last bridged, in unix seconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LastEvents {
+    /// An event from their WeChat agent.
+    pub wechat: Option<i64>,
+    /// An event they sent on Matrix.
+    pub matrix: Option<i64>,
+}
+
+/// Tracks the last bridged events of every user, for `/status`.
+#[derive(Clone, Default)]
+pub struct UserActivity {
+    users: Arc<RwLock<HashMap<String, LastEvents>>>,
+}
+
+impl UserActivity {
+    pub async fn wechat_event(&self, mxid: &str) {
+        self.users.write().await.entry(mxid.to_string()).or_default().wechat = Some(chrono::Utc::now().timestamp());
+    }
+
+    pub async fn matrix_event(&self, mxid: &str) {
+        self.users.write().await.entry(mxid.to_string()).or_default().matrix = Some(chrono::Utc::now().timestamp());
+    }
+
+    pub async fn get(&self, mxid: &str) -> LastEvents {
+        self.users.read().await.get(mxid).copied().unwrap_or_default()
+    }
+}
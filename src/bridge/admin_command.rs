@@ -0,0 +1,124 @@
+use crate::config::PermissionLevel;
+
+/// Parses the bridge-wide management commands only admins may use. They take precedence over
+/// the regular commands of the same name when given their arguments.
+#[derive(Clone, Default)]
+pub struct AdminCommandProcessor;
+
+impl AdminCommandProcessor {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Returns `None` when the command isn't an admin command.
+    pub fn process(&self, command: &str, args: &[String]) -> Option<AdminCommandResult> {
+        Some(match command {
+            "admin-help" => self.cmd_help(),
+            "list-users" => AdminCommandResult::ListUsers,
+            "list-portals" => AdminCommandResult::ListPortals,
+            "bridge-status" => AdminCommandResult::BridgeStatus,
+            "disconnect" => self.cmd_disconnect(args),
+            // Without an ID, `delete-portal` deletes the portal of the current room.
+            "delete-portal" if !args.is_empty() => AdminCommandResult::DeletePortal(args[0].clone()),
+            "clean-users" => AdminCommandResult::CleanUsers,
+            "set-permission" => self.cmd_set_permission(args),
+            "agent-token" => self.cmd_agent_token(args),
+            "continue-portal" => self.cmd_continue_portal(args),
+            _ => return None,
+        })
+    }
+
+    fn cmd_help(&self) -> AdminCommandResult {
+        AdminCommandResult::Success(
+            r#"Admin commands:
+- list-users: List the users logged in to WeChat
+- list-portals: List all bridged rooms
+- bridge-status: Show bridge-wide statistics
+- disconnect <mxid>: Log a user out of WeChat
+- delete-portal <wxid>: Delete the portals of a WeChat chat for all users
+- clean-users: Log out users whose Matrix account was deactivated and remove their portals and double puppets
+- set-permission <mxid> <block|relay|user|puppeting|admin|default>: Change the permission level of a user
+- agent-token <create [description]|list|rotate <id>|revoke <id>>: Manage the tokens WeChat agents connect with
+- continue-portal <old room ID> <new room ID>: Move a portal to the room that replaced its room after an upgrade
+"#
+            .to_string(),
+        )
+    }
+
+    fn cmd_disconnect(&self, args: &[String]) -> AdminCommandResult {
+        match args.first() {
+            Some(mxid) if is_user_id(mxid) => AdminCommandResult::Disconnect(mxid.clone()),
+            _ => AdminCommandResult::Error("Usage: disconnect <mxid>".to_string()),
+        }
+    }
+
+    fn cmd_set_permission(&self, args: &[String]) -> AdminCommandResult {
+        const USAGE: &str = "Usage: set-permission <mxid> <block|relay|user|puppeting|admin|default>";
+        let [mxid, level] = args else {
+            return AdminCommandResult::Error(USAGE.to_string());
+        };
+        if !is_user_id(mxid) {
+            return AdminCommandResult::Error(USAGE.to_string());
+        }
+        if level == "default" {
+            return AdminCommandResult::SetPermission(mxid.clone(), None);
+        }
+        match level.parse() {
+            Ok(level) => AdminCommandResult::SetPermission(mxid.clone(), Some(level)),
+            Err(_) => AdminCommandResult::Error(USAGE.to_string()),
+        }
+    }
+
+    fn cmd_agent_token(&self, args: &[String]) -> AdminCommandResult {
+        const USAGE: &str = "Usage: agent-token <create [description]|list|rotate <id>|revoke <id>>";
+        match args {
+            [action, description @ ..] if action == "create" => {
+                AdminCommandResult::CreateAgentToken(description.join(" "))
+            }
+            [action] if action == "list" => AdminCommandResult::ListAgentTokens,
+            [action, id] if action == "rotate" => AdminCommandResult::RotateAgentToken(id.clone()),
+            [action, id] if action == "revoke" => AdminCommandResult::RevokeAgentToken(id.clone()),
+            _ => AdminCommandResult::Error(USAGE.to_string()),
+        }
+    }
+
+    fn cmd_continue_portal(&self, args: &[String]) -> AdminCommandResult {
+        match args {
+            [old, new] if is_room_id(old) && is_room_id(new) => {
+                AdminCommandResult::ContinuePortal(old.clone(), new.clone())
+            }
+            _ => AdminCommandResult::Error("Usage: continue-portal <old room ID> <new room ID>".to_string()),
+        }
+    }
+}
+
+fn is_room_id(room_id: &str) -> bool {
+    room_id.starts_with('!') && room_id.contains(':')
+}
+
+fn is_user_id(mxid: &str) -> bool {
+    mxid.starts_with('@') && mxid.contains(':')
+}
+
+#[derive(Debug, Clone)]
+pub enum AdminCommandResult {
+    Success(String),
+    Error(String),
+    ListUsers,
+    ListPortals,
+    BridgeStatus,
+    Disconnect(String),
+    DeletePortal(String),
+    /// Cleans up after users whose Matrix account no longer exists.
+    CleanUsers,
+    /// `None` removes the override, going back to the level from the config.
+    SetPermission(String, Option<PermissionLevel>),
+    /// Issues an agent token with a description.
+    CreateAgentToken(String),
+    ListAgentTokens,
+    /// Replaces the token with this ID by a new one, disconnecting its agents.
+    RotateAgentToken(String),
+    RevokeAgentToken(String),
+    /// Moves the portal of the first room to the second, which replaced it.
+    ContinuePortal(String, String),
+}
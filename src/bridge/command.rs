@@ -1,3 +1,7 @@
+use crate::config::PermissionLevel;
+use crate::database::PortalSetting;
+use crate::formatter::escape_html;
+
 #[derive(Clone)]
 pub struct CommandProcessor {
     command_prefix: String,
@@ -31,58 +35,362 @@ impl CommandProcessor {
         Some((command, args))
     }
 
-    pub fn process(&self, command: &str, args: &[String]) -> CommandResult {
-        match command {
-            "help" | "h" | "?" => self.cmd_help(),
-            "login" => CommandResult::Login,
-            "logout" => CommandResult::Logout,
-            "ping" => CommandResult::Success("Pong!".to_string()),
-            "list" => self.cmd_list(args),
-            "sync" => self.cmd_sync(args),
-            "delete-portal" => CommandResult::DeletePortal,
-            "delete-all-portals" => CommandResult::DeleteAllPortals,
-            "double-puppet" | "dp" => CommandResult::DoublePuppet(args.get(0).cloned()),
-            _ => CommandResult::Error(format!("Unknown command: {}", command)),
-        }
+    /// Looks up a command by its name or one of its aliases.
+    pub fn find(&self, command: &str) -> Option<&'static CommandSpec> {
+        COMMANDS.iter().find(|spec| spec.name == command || spec.aliases.contains(&command))
     }
 
-    fn cmd_help(&self) -> CommandResult {
-        CommandResult::Success(
-            r#"Available commands:
-- help: Show this help message
-- login: Login to WeChat via QR code
-- logout: Logout from WeChat
-- ping: Check connection status
-- list contacts/groups: List contacts or groups
-- sync contacts/groups/space: Sync data
-- delete-portal: Delete current portal
-- delete-all-portals: Delete all portals
-- double-puppet <token>: Enable double puppeting with access token
-"#
-            .to_string(),
-        )
+    pub fn process(&self, command: &str, args: &[String], level: PermissionLevel) -> CommandResult {
+        let Some(spec) = self.find(command) else {
+            return CommandResult::Error(format!(
+                "Unknown command: {}. Use `{} help` to list the commands.",
+                command, self.command_prefix
+            ));
+        };
+        if level < spec.level {
+            return CommandResult::Error("You don't have permission to use this command.".to_string());
+        }
+        if spec.name == "help" {
+            return self.cmd_help(args, level);
+        }
+        (spec.handler)(args).unwrap_or_else(|| CommandResult::Error(format!("Usage: {}", spec.usage_line())))
     }
 
-    fn cmd_list(&self, args: &[String]) -> CommandResult {
-        if args.is_empty() {
-            return CommandResult::Error("Usage: list contacts|groups".to_string());
+    /// Lists the commands available at `level`, or describes a single command.
+    fn cmd_help(&self, args: &[String], level: PermissionLevel) -> CommandResult {
+        if let Some(name) = args.first() {
+            let Some(spec) = self.find(&name.to_lowercase()).filter(|spec| level >= spec.level) else {
+                return CommandResult::Error(format!("Unknown command: {}", name));
+            };
+            let mut body = format!("{} {}\n{}", self.command_prefix, spec.usage_line(), spec.help);
+            let mut html = format!(
+                "<p><code>{} {}</code></p><p>{}</p>",
+                escape_html(&self.command_prefix),
+                escape_html(&spec.usage_line()),
+                escape_html(spec.help)
+            );
+            if !spec.aliases.is_empty() {
+                body.push_str(&format!("\nAliases: {}", spec.aliases.join(", ")));
+                html.push_str(&format!("<p>Aliases: {}</p>", escape_html(&spec.aliases.join(", "))));
+            }
+            return CommandResult::Help { body, html };
         }
-        match args[0].as_str() {
-            "contacts" => CommandResult::ListContacts,
-            "groups" => CommandResult::ListGroups,
-            _ => CommandResult::Error("Usage: list contacts|groups".to_string()),
+
+        let mut body = format!("Available commands (prefix them with {}):\n", self.command_prefix);
+        let mut html = format!(
+            "<p>Available commands (prefix them with <code>{}</code>):</p><ul>",
+            escape_html(&self.command_prefix)
+        );
+        for spec in COMMANDS.iter().filter(|spec| level >= spec.level) {
+            body.push_str(&format!("- {}: {}\n", spec.usage_line(), spec.help));
+            html.push_str(&format!(
+                "<li><code>{}</code>: {}</li>",
+                escape_html(&spec.usage_line()),
+                escape_html(spec.help)
+            ));
         }
+        html.push_str("</ul>");
+        CommandResult::Help { body, html }
     }
+}
+
+/// A command the bridge understands, along with what's shown for it in `help`.
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    /// The arguments, as shown after the name in the usage line.
+    pub usage: &'static str,
+    pub help: &'static str,
+    /// The lowest permission level allowed to run the command.
+    pub level: PermissionLevel,
+    /// Parses the arguments, returning `None` when they don't match the usage.
+    handler: fn(&[String]) -> Option<CommandResult>,
+}
 
-    fn cmd_sync(&self, args: &[String]) -> CommandResult {
-        if args.is_empty() {
-            return CommandResult::Error("Usage: sync contacts|groups|space".to_string());
+impl CommandSpec {
+    pub fn usage_line(&self) -> String {
+        if self.usage.is_empty() {
+            self.name.to_string()
+        } else {
+            format!("{} {}", self.name, self.usage)
         }
-        match args[0].as_str() {
-            "contacts" => CommandResult::SyncContacts,
-            "groups" => CommandResult::SyncGroups,
-            "space" => CommandResult::SyncSpace,
-            _ => CommandResult::Error("Usage: sync contacts|groups|space".to_string()),
+    }
+}
+
+/// Every regular command, in the order `help` lists them.
+pub static COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "help",
+        aliases: &["h", "?"],
+        usage: "[command]",
+        help: "Show the available commands, or how to use one of them",
+        level: PermissionLevel::Relay,
+        // Handled by the processor, which knows the permission level.
+        handler: |_| None,
+    },
+    CommandSpec {
+        name: "ping",
+        aliases: &[],
+        usage: "",
+        help: "Check that the bridge is responding",
+        level: PermissionLevel::Relay,
+        handler: |_| Some(CommandResult::Success("Pong!".to_string())),
+    },
+    CommandSpec {
+        name: "login",
+        aliases: &[],
+        usage: "",
+        help: "Log in to WeChat by scanning a QR code",
+        level: PermissionLevel::User,
+        handler: |_| Some(CommandResult::Login),
+    },
+    CommandSpec {
+        name: "logout",
+        aliases: &[],
+        usage: "",
+        help: "Log out of WeChat",
+        level: PermissionLevel::User,
+        handler: |_| Some(CommandResult::Logout),
+    },
+    CommandSpec {
+        name: "list",
+        aliases: &[],
+        usage: "contacts|groups|portals",
+        help: "List your WeChat contacts or groups, or the portal rooms you're in",
+        level: PermissionLevel::User,
+        handler: |args| match args.first()?.as_str() {
+            "contacts" => Some(CommandResult::ListContacts),
+            "groups" => Some(CommandResult::ListGroups),
+            "portals" => Some(CommandResult::ListPortals),
+            _ => None,
+        },
+    },
+    CommandSpec {
+        name: "sync",
+        aliases: &[],
+        usage: "contacts|groups|space",
+        help: "Sync your contacts, groups or space from WeChat",
+        level: PermissionLevel::User,
+        handler: |args| match args.first()?.as_str() {
+            "contacts" => Some(CommandResult::SyncContacts),
+            "groups" => Some(CommandResult::SyncGroups),
+            "space" => Some(CommandResult::SyncSpace),
+            _ => None,
+        },
+    },
+    CommandSpec {
+        name: "id",
+        aliases: &[],
+        usage: "",
+        help: "Show the WeChat chat ID of the current portal",
+        level: PermissionLevel::User,
+        handler: |_| Some(CommandResult::ShowId),
+    },
+    CommandSpec {
+        name: "open",
+        aliases: &[],
+        usage: "<wxid>",
+        help: "Find and join the portal for a WeChat chat ID",
+        level: PermissionLevel::User,
+        handler: |args| Some(CommandResult::Open(args.first().cloned())),
+    },
+    CommandSpec {
+        name: "pm",
+        aliases: &[],
+        usage: "<name, remark or wxid>",
+        help: "Search your contacts and open a private chat portal",
+        level: PermissionLevel::User,
+        handler: |args| (!args.is_empty()).then(|| CommandResult::StartPrivateChat(args.join(" "))),
+    },
+    CommandSpec {
+        name: "create-group",
+        aliases: &[],
+        usage: "<name>",
+        help: "Create a WeChat group with the contacts in this room and bridge it here",
+        level: PermissionLevel::User,
+        handler: |args| (!args.is_empty()).then(|| CommandResult::CreateGroup(args.join(" "))),
+    },
+    CommandSpec {
+        name: "bridge",
+        aliases: &[],
+        usage: "<group_id>",
+        help: "Bridge this room to an existing WeChat group",
+        level: PermissionLevel::User,
+        handler: |args| args.first().map(|id| CommandResult::Bridge(id.clone())),
+    },
+    CommandSpec {
+        name: "unbridge",
+        aliases: &[],
+        usage: "",
+        help: "Disconnect this room from WeChat without deleting it",
+        level: PermissionLevel::User,
+        handler: |_| Some(CommandResult::Unbridge),
+    },
+    CommandSpec {
+        name: "leave-group",
+        aliases: &[],
+        usage: "<group_id>",
+        help: "Quit a WeChat group",
+        level: PermissionLevel::User,
+        handler: |args| args.first().map(|id| CommandResult::LeaveGroup(id.clone())),
+    },
+    CommandSpec {
+        name: "accept-friend",
+        aliases: &[],
+        usage: "<id>",
+        help: "Accept a WeChat friend request, with the ID from its notice",
+        level: PermissionLevel::User,
+        handler: |args| args.first().map(|id| CommandResult::AcceptFriend(id.clone())),
+    },
+    CommandSpec {
+        name: "join-group",
+        aliases: &[],
+        usage: "<invite>",
+        help: "Join a WeChat group you were invited to, with the invite from its notice",
+        level: PermissionLevel::User,
+        handler: |args| args.first().map(|invite| CommandResult::JoinGroup(invite.clone())),
+    },
+    CommandSpec {
+        name: "marker",
+        aliases: &[],
+        usage: "[on|off]",
+        help: "Show or toggle the \"sent from Matrix\" marker on your messages",
+        level: PermissionLevel::User,
+        handler: |args| match args.first().map(|s| s.as_str()) {
+            None => Some(CommandResult::MessageMarker(None)),
+            Some("on") => Some(CommandResult::MessageMarker(Some(true))),
+            Some("off") => Some(CommandResult::MessageMarker(Some(false))),
+            Some(_) => None,
+        },
+    },
+    CommandSpec {
+        name: "set",
+        aliases: &[],
+        usage: "mute|plaintext|relay on|off, or name <name>|reset",
+        help: "Change your settings for this portal",
+        level: PermissionLevel::User,
+        handler: |args| PortalSettingChange::parse(args).map(CommandResult::SetPortalSetting),
+    },
+    CommandSpec {
+        name: "settings",
+        aliases: &[],
+        usage: "",
+        help: "Show your settings for this portal",
+        level: PermissionLevel::User,
+        handler: |_| Some(CommandResult::ShowPortalSettings),
+    },
+    CommandSpec {
+        name: "members",
+        aliases: &[],
+        usage: "[query]",
+        help: "List the members of this WeChat group, or search them by name or ID",
+        level: PermissionLevel::User,
+        handler: |args| Some(CommandResult::GroupMembers((!args.is_empty()).then(|| args.join(" ")))),
+    },
+    CommandSpec {
+        name: "info",
+        aliases: &[],
+        usage: "",
+        help: "Show the ID, member count and owner of this WeChat group",
+        level: PermissionLevel::User,
+        handler: |_| Some(CommandResult::GroupInfo),
+    },
+    CommandSpec {
+        name: "set-relay",
+        aliases: &[],
+        usage: "",
+        help: "Relay messages from users who aren't logged in through your WeChat account in this portal",
+        level: PermissionLevel::User,
+        handler: |_| Some(CommandResult::SetRelay),
+    },
+    CommandSpec {
+        name: "unset-relay",
+        aliases: &[],
+        usage: "",
+        help: "Stop relaying messages in this portal",
+        level: PermissionLevel::User,
+        handler: |_| Some(CommandResult::UnsetRelay),
+    },
+    CommandSpec {
+        name: "double-puppet",
+        aliases: &["dp"],
+        usage: "<access_token>",
+        help: "Enable double puppeting with a Matrix access token",
+        level: PermissionLevel::Puppeting,
+        handler: |args| Some(CommandResult::DoublePuppet(args.first().cloned())),
+    },
+    CommandSpec {
+        name: "delete-portal",
+        aliases: &[],
+        usage: "",
+        help: "Delete the portal of the current room",
+        level: PermissionLevel::Admin,
+        handler: |_| Some(CommandResult::DeletePortal),
+    },
+    CommandSpec {
+        name: "delete-all-portals",
+        aliases: &[],
+        usage: "",
+        help: "Delete all portals",
+        level: PermissionLevel::Admin,
+        handler: |_| Some(CommandResult::DeleteAllPortals),
+    },
+    CommandSpec {
+        name: "list-connections",
+        aliases: &[],
+        usage: "",
+        help: "Show the connected WeChat agents and the users they serve",
+        level: PermissionLevel::Admin,
+        handler: |_| Some(CommandResult::ListConnections),
+    },
+    CommandSpec {
+        name: "admin-help",
+        aliases: &[],
+        usage: "",
+        help: "List the bridge-wide management commands",
+        level: PermissionLevel::Admin,
+        // Handled by the admin command processor before it gets here.
+        handler: |_| None,
+    },
+];
+
+/// A change to the caller's settings for the current portal, made with `set`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortalSettingChange {
+    Muted(bool),
+    PlaintextOnly(bool),
+    Relay(bool),
+    /// A room name to use instead of the chat's, or `None` to go back to it.
+    RoomName(Option<String>),
+}
+
+impl PortalSettingChange {
+    pub fn parse(args: &[String]) -> Option<Self> {
+        let (setting, value) = args.split_first()?;
+        let toggle = || match value {
+            [v] if v == "on" => Some(true),
+            [v] if v == "off" => Some(false),
+            _ => None,
+        };
+        match setting.to_lowercase().as_str() {
+            "mute" => toggle().map(Self::Muted),
+            "plaintext" => toggle().map(Self::PlaintextOnly),
+            "relay" => toggle().map(Self::Relay),
+            "name" => match value {
+                [] => None,
+                [v] if v == "reset" => Some(Self::RoomName(None)),
+                _ => Some(Self::RoomName(Some(value.join(" ")))),
+            },
+            _ => None,
+        }
+    }
+
+    pub fn apply(&self, setting: &mut PortalSetting) {
+        match self {
+            Self::Muted(muted) => setting.muted = *muted,
+            Self::PlaintextOnly(plaintext) => setting.plaintext_only = *plaintext,
+            Self::Relay(relay) => setting.relay = *relay,
+            Self::RoomName(name) => setting.room_name = name.clone(),
         }
     }
 }
@@ -91,6 +399,8 @@ impl CommandProcessor {
 pub enum CommandResult {
     Success(String),
     Error(String),
+    /// A formatted reply, such as the command list of `help`.
+    Help { body: String, html: String },
     NeedsLogin,
     Login,
     Logout,
@@ -102,4 +412,23 @@ pub enum CommandResult {
     DeletePortal,
     DeleteAllPortals,
     DoublePuppet(Option<String>),
+    ShowId,
+    Open(Option<String>),
+    MessageMarker(Option<bool>),
+    CreateGroup(String),
+    SetRelay,
+    UnsetRelay,
+    StartPrivateChat(String),
+    Bridge(String),
+    Unbridge,
+    ListConnections,
+    LeaveGroup(String),
+    AcceptFriend(String),
+    JoinGroup(String),
+    ListPortals,
+    SetPortalSetting(PortalSettingChange),
+    ShowPortalSettings,
+    /// Lists the members of the portal's group, or those matching a query.
+    GroupMembers(Option<String>),
+    GroupInfo,
 }
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::RwLock;
+
+use crate::util::retry::{ConnectionState, ReconnectionManager};
+
+/// A notice about a user's WeChat connection, posted in their management room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionNotice {
+    AgentDisconnected,
+    AgentReconnected,
+    /// The agent is still gone after the configured time.
+    StillDisconnected(Duration),
+    LoggedOut(Option<String>),
+}
+
+impl ConnectionNotice {
+    pub fn text(&self) -> String {
+        match self {
+            Self::AgentDisconnected => {
+                "Your WeChat agent disconnected from the bridge. Messages can't be bridged until it reconnects.".to_string()
+            }
+            Self::AgentReconnected => "Your WeChat agent reconnected, messages are being bridged again.".to_string(),
+            Self::StillDisconnected(after) => format!(
+                "Your WeChat agent still hasn't reconnected after {} minutes. Check that it's running and can reach the bridge.",
+                after.as_secs() / 60
+            ),
+            Self::LoggedOut(Some(reason)) => {
+                format!("You were logged out of WeChat: {}. Use `login` to log in again.", reason)
+            }
+            Self::LoggedOut(None) => "You were logged out of WeChat. Use `login` to log in again.".to_string(),
+        }
+    }
+}
+
+struct UserConnection {
+    manager: ReconnectionManager,
+    disconnected_at: Option<Instant>,
+}
+
+/// Tracks the agent connection of every user to decide which connection notices to send.
+#[derive(Clone)]
+pub struct ConnectionNotifier {
+    users: Arc<RwLock<HashMap<String, UserConnection>>>,
+    /// How long an agent may stay disconnected before the user is told again.
+    alert_after: Option<Duration>,
+}
+
+impl ConnectionNotifier {
+    pub fn new(alert_after: Option<Duration>) -> Self {
+        Self {
+            users: Arc::new(RwLock::new(HashMap::new())),
+            alert_after,
+        }
+    }
+
+    /// The first connection of a user is expected and isn't announced, only reconnects are.
+    pub async fn on_connected(&self, mxid: &str) -> Option<ConnectionNotice> {
+        let mut users = self.users.write().await;
+        let user = users.entry(mxid.to_string()).or_insert_with(|| UserConnection {
+            manager: ReconnectionManager::default_manager(),
+            disconnected_at: None,
+        });
+        let was_disconnected = user.disconnected_at.take().is_some();
+        user.manager.on_connected().await;
+        was_disconnected.then_some(ConnectionNotice::AgentReconnected)
+    }
+
+    pub async fn on_disconnected(&self, mxid: &str) -> Option<ConnectionNotice> {
+        let mut users = self.users.write().await;
+        let user = users.get_mut(mxid)?;
+        if user.disconnected_at.is_some() {
+            return None;
+        }
+        user.disconnected_at = Some(Instant::now());
+        user.manager.on_disconnected().await;
+        user.manager.on_reconnecting().await;
+        Some(ConnectionNotice::AgentDisconnected)
+    }
+
+    /// Users whose agent has been reconnecting for longer than the alert time, each returned
+    /// once per disconnect.
+    pub async fn overdue(&self, now: Instant) -> Vec<(String, ConnectionNotice)> {
+        let Some(alert_after) = self.alert_after else {
+            return Vec::new();
+        };
+        let mut overdue = Vec::new();
+        for (mxid, user) in self.users.read().await.iter() {
+            let Some(since) = user.disconnected_at else {
+                continue;
+            };
+            if user.manager.state().await == ConnectionState::Reconnecting
+                && now.saturating_duration_since(since) >= alert_after
+            {
+                user.manager.on_failed().await;
+                overdue.push((mxid.clone(), ConnectionNotice::StillDisconnected(alert_after)));
+            }
+        }
+        overdue
+    }
+}
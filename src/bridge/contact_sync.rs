@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+use crate::database::Contact;
+use crate::wechat::UserInfo;
+
+/// How a user's WeChat friend list changed since the last contact sync.
+#[derive(Debug, Default)]
+pub struct ContactChanges {
+    pub added: Vec<UserInfo>,
+    pub updated: Vec<UserInfo>,
+    /// IDs of friends that are no longer in the list.
+    pub removed: Vec<String>,
+}
+
+impl ContactChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.updated.is_empty() && self.removed.is_empty()
+    }
+
+    pub fn summary(&self) -> String {
+        if self.is_empty() {
+            return "Contacts are up to date.".to_string();
+        }
+        format!(
+            "Synced contacts: {} added, {} updated, {} removed.",
+            self.added.len(),
+            self.updated.len(),
+            self.removed.len()
+        )
+    }
+}
+
+/// Compares the friend list fetched from WeChat with the contacts stored at the last sync.
+pub fn diff_contacts(stored: &[Contact], friends: &[UserInfo]) -> ContactChanges {
+    let stored: HashMap<&str, &Contact> = stored.iter().map(|c| (c.uin.as_str(), c)).collect();
+    let mut changes = ContactChanges::default();
+
+    for friend in friends {
+        match stored.get(friend.id.as_str()) {
+            None => changes.added.push(friend.clone()),
+            Some(contact) if contact.deleted => changes.added.push(friend.clone()),
+            Some(contact) if has_changed(contact, friend) => changes.updated.push(friend.clone()),
+            Some(_) => {}
+        }
+    }
+
+    let current: Vec<&str> = friends.iter().map(|f| f.id.as_str()).collect();
+    changes.removed = stored.values()
+        .filter(|c| !c.deleted && !current.contains(&c.uin.as_str()))
+        .map(|c| c.uin.clone())
+        .collect();
+    changes
+}
+
+fn has_changed(contact: &Contact, friend: &UserInfo) -> bool {
+    contact.name != friend.name
+        || contact.remark.as_deref().unwrap_or("") != friend.remark.as_deref().unwrap_or("")
+        || contact.avatar.as_deref().unwrap_or("") != friend.avatar.as_deref().unwrap_or("")
+}
+
+pub fn contact_from_info(user_mxid: &str, info: &UserInfo) -> Contact {
+    Contact {
+        user_mxid: user_mxid.to_string(),
+        uin: info.id.clone(),
+        name: info.name.clone(),
+        remark: info.remark.clone(),
+        avatar: info.avatar.clone(),
+        deleted: false,
+        updated_at: chrono::Utc::now().timestamp(),
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::matrix::types::RoomEvent;
+use crate::wechat::Event;
+
+/// A WeChat event as it arrived from an agent.
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordedEvent {
+    pub received_at: i64,
+    pub mxid: String,
+    pub event: Event,
+}
+
+/// A transaction as it arrived from the homeserver.
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordedTransaction {
+    pub received_at: i64,
+    pub txn_id: String,
+    pub events: Vec<RoomEvent>,
+}
+
+/// Keeps the last inbound WeChat events and Matrix transactions for the debug API. Nothing
+/// is kept when its capacity is `0`, which it is while the debug API is disabled.
+#[derive(Clone)]
+pub struct DebugLog {
+    capacity: usize,
+    events: Arc<Mutex<VecDeque<RecordedEvent>>>,
+    transactions: Arc<Mutex<VecDeque<RecordedTransaction>>>,
+}
+
+impl DebugLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            events: Arc::new(Mutex::new(VecDeque::new())),
+            transactions: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    pub async fn record_event(&self, event: &Event) {
+        if self.capacity == 0 {
+            return;
+        }
+        let recorded = RecordedEvent {
+            received_at: chrono::Utc::now().timestamp_millis(),
+            mxid: event.mxid.clone(),
+            event: event.clone(),
+        };
+        push(&mut *self.events.lock().await, recorded, self.capacity);
+    }
+
+    pub async fn record_transaction(&self, txn_id: &str, events: &[RoomEvent]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let recorded = RecordedTransaction {
+            received_at: chrono::Utc::now().timestamp_millis(),
+            txn_id: txn_id.to_string(),
+            events: events.to_vec(),
+        };
+        push(&mut *self.transactions.lock().await, recorded, self.capacity);
+    }
+
+    /// The last `limit` WeChat events, newest first.
+    pub async fn events(&self, limit: usize) -> Vec<RecordedEvent> {
+        self.events.lock().await.iter().rev().take(limit).cloned().collect()
+    }
+
+    /// The last `limit` transactions, newest first.
+    pub async fn transactions(&self, limit: usize) -> Vec<RecordedTransaction> {
+        self.transactions.lock().await.iter().rev().take(limit).cloned().collect()
+    }
+
+    /// The most recent WeChat event with this ID that's still kept.
+    pub async fn find_event(&self, id: &str) -> Option<Event> {
+        self.events.lock().await
+            .iter()
+            .rev()
+            .find(|recorded| recorded.event.id == id)
+            .map(|recorded| recorded.event.clone())
+    }
+}
+
+fn push<T>(buffer: &mut VecDeque<T>, item: T, capacity: usize) {
+    if buffer.len() >= capacity {
+        buffer.pop_front();
+    }
+    buffer.push_back(item);
+}
@@ -0,0 +1,68 @@
+use crate::database::{Database, PortalKey};
+use crate::util::LruCache;
+use crate::wechat::EventType;
+
+/// How many recently bridged WeChat events are kept in memory in front of the database.
+const RECENT_EVENTS: usize = 4096;
+
+/// Drops WeChat events that were already bridged, such as the ones an agent replays after
+/// reconnecting. Events are remembered by portal, type and ID in memory, and events that create
+/// a Matrix message are also looked up by their message ID in the portal in the database. The
+/// portal includes the receiving account, so users sharing a chat don't drop each other's events.
+///
+/// Calls, presence, read receipts and group changes aren't deduplicated: their IDs aren't
+/// unique per event and bridging them twice is harmless.
+#[derive(Clone)]
+pub struct MessageDedup {
+    recent: LruCache<String, ()>,
+    db: Database,
+}
+
+impl MessageDedup {
+    pub fn new(db: Database) -> Self {
+        Self {
+            recent: LruCache::new(RECENT_EVENTS),
+            db,
+        }
+    }
+
+    pub async fn is_duplicate(&self, portal: &PortalKey, event_type: &EventType, id: &str) -> anyhow::Result<bool> {
+        let Some(key) = dedup_key(portal, event_type, id) else {
+            return Ok(false);
+        };
+        if self.recent.contains(&key).await {
+            return Ok(true);
+        }
+        if !creates_message(event_type) {
+            return Ok(false);
+        }
+        // Messages whose send was interrupted or failed are bridged again.
+        let bridged = self.db.get_message_by_id(portal, id).await?.is_some_and(|m| m.sent);
+        if bridged {
+            self.recent.insert(key, ()).await;
+        }
+        Ok(bridged)
+    }
+
+    pub async fn mark_seen(&self, portal: &PortalKey, event_type: &EventType, id: &str) {
+        if let Some(key) = dedup_key(portal, event_type, id) {
+            self.recent.insert(key, ()).await;
+        }
+    }
+}
+
+fn dedup_key(portal: &PortalKey, event_type: &EventType, id: &str) -> Option<String> {
+    if id.is_empty() {
+        return None;
+    }
+    match event_type {
+        EventType::Voip | EventType::Presence | EventType::Read
+            | EventType::GroupUpdate | EventType::GroupRemove | EventType::Logout => None,
+        // A revoke may carry the ID of the message it revokes, so the type is part of the key.
+        _ => Some(format!("{}:{}:{}:{}", portal.receiver, portal.uid, event_type, id)),
+    }
+}
+
+fn creates_message(event_type: &EventType) -> bool {
+    !matches!(event_type, EventType::Revoke | EventType::Reaction)
+}
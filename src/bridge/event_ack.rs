@@ -0,0 +1,93 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+use crate::database::{Database, EventSequence};
+
+#[derive(Default)]
+struct Sequences {
+    /// Every event up to this one was handled, and the agent was told so.
+    acked: u64,
+    /// The highest sequence number handled so far.
+    handled: u64,
+    /// Events received but not handled yet. Events arrive in order, so everything below the
+    /// lowest of these that was received was handled.
+    in_flight: BTreeSet<u64>,
+}
+
+/// Tracks which of the events an agent numbered were handled, so the agent can stop keeping
+/// them for replay. Events are handled concurrently per portal, so only the point up to which
+/// all were handled is acknowledged and stored, to be sent to the agent when it reconnects.
+#[derive(Clone)]
+pub struct EventAcks {
+    users: Arc<Mutex<HashMap<String, Sequences>>>,
+    db: Database,
+}
+
+impl EventAcks {
+    pub fn new(db: Database) -> Self {
+        Self {
+            users: Arc::new(Mutex::new(HashMap::new())),
+            db,
+        }
+    }
+
+    /// Records that an event of a user's agent arrived. Returns `false` when it was already
+    /// handled, or is being handled, and the agent replayed it.
+    pub async fn received(&self, mxid: &str, seq: u64) -> anyhow::Result<bool> {
+        let mut users = self.users.lock().await;
+        let seqs = self.load(&mut users, mxid).await?;
+        if seq <= seqs.acked || seqs.in_flight.contains(&seq) {
+            return Ok(false);
+        }
+        seqs.in_flight.insert(seq);
+        Ok(true)
+    }
+
+    /// Records that an event was handled. Returns the new acknowledged sequence number when
+    /// it moved, after storing it.
+    pub async fn handled(&self, mxid: &str, seq: u64) -> anyhow::Result<Option<u64>> {
+        let mut users = self.users.lock().await;
+        let seqs = self.load(&mut users, mxid).await?;
+        seqs.in_flight.remove(&seq);
+        seqs.handled = seqs.handled.max(seq);
+        let done = match seqs.in_flight.first() {
+            Some(lowest) => (lowest - 1).min(seqs.handled),
+            None => seqs.handled,
+        };
+        if done <= seqs.acked {
+            return Ok(None);
+        }
+        seqs.acked = done;
+        self.db
+            .upsert_event_sequence(&EventSequence {
+                mxid: mxid.to_string(),
+                seq: done as i64,
+                updated_at: chrono::Utc::now().timestamp(),
+            })
+            .await?;
+        Ok(Some(done))
+    }
+
+    /// The acknowledged sequence number of a user's events, `0` when none were.
+    pub async fn acked(&self, mxid: &str) -> anyhow::Result<u64> {
+        let mut users = self.users.lock().await;
+        Ok(self.load(&mut users, mxid).await?.acked)
+    }
+
+    async fn load<'a>(&self, users: &'a mut HashMap<String, Sequences>, mxid: &str) -> anyhow::Result<&'a mut Sequences> {
+        Ok(match users.entry(mxid.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let acked = self.db.get_event_sequence(mxid).await?.map(|s| s.seq as u64).unwrap_or(0);
+                entry.insert(Sequences {
+                    acked,
+                    handled: acked,
+                    in_flight: BTreeSet::new(),
+                })
+            }
+        })
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+
+use crate::util::{ConcurrencyLimiter, MessageQueue, QueueMessage};
+
+/// How long dispatching waits before checking again whether a full portal queue has room.
+const FULL_QUEUE_BACKOFF: Duration = Duration::from_millis(50);
+
+type Handler<T> = Arc<dyn Fn(T) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// Hands events to a queue per portal, each drained by its own worker, so a slow event only
+/// holds up its own chat. Events of a portal are handled in order; events of different portals
+/// run concurrently, up to a global limit.
+pub struct EventDispatcher<T> {
+    queues: Arc<Mutex<HashMap<String, MessageQueue<T>>>>,
+    limiter: ConcurrencyLimiter,
+    capacity: usize,
+    handler: Handler<T>,
+}
+
+impl<T: Send + 'static> EventDispatcher<T> {
+    /// `capacity` bounds the events waiting per portal, `max_concurrency` the events being
+    /// handled at once across all portals.
+    pub fn new<F, Fut>(capacity: usize, max_concurrency: usize, handler: F) -> Self
+    where
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            queues: Arc::new(Mutex::new(HashMap::new())),
+            limiter: ConcurrencyLimiter::new("portal events", max_concurrency.max(1)),
+            capacity: capacity.max(1),
+            handler: Arc::new(move |event| Box::pin(handler(event))),
+        }
+    }
+
+    /// Queues `event` for the portal `key`. When the portal already has a full queue, this
+    /// waits for room rather than dropping the event.
+    pub async fn dispatch(&self, key: &str, event: T) {
+        loop {
+            let mut queues = self.queues.lock().await;
+            let queue = match queues.get(key) {
+                Some(queue) => queue.clone(),
+                None => {
+                    let queue = MessageQueue::new(self.capacity, 1);
+                    queues.insert(key.to_string(), queue.clone());
+                    self.spawn_worker(key.to_string(), queue.clone());
+                    queue
+                }
+            };
+            if queue.len().await >= self.capacity {
+                drop(queues);
+                tokio::time::sleep(FULL_QUEUE_BACKOFF).await;
+                continue;
+            }
+            // Only the dispatcher pushes, so there's still room.
+            let _ = queue.push(QueueMessage::new(key, event)).await;
+            return;
+        }
+    }
+
+    fn spawn_worker(&self, key: String, queue: MessageQueue<T>) {
+        let queues = self.queues.clone();
+        let limiter = self.limiter.clone();
+        let handler = self.handler.clone();
+        tokio::spawn(async move {
+            loop {
+                // The worker is removed while holding the map, so an event dispatched at the
+                // same time either lands in this queue before it's checked or starts a new one.
+                let event = {
+                    let mut queues = queues.lock().await;
+                    match queue.pop().await {
+                        Some(event) => event,
+                        None => {
+                            queues.remove(&key);
+                            break;
+                        }
+                    }
+                };
+                let _permit = limiter.acquire().await;
+                handler(event.data).await;
+            }
+        });
+    }
+}
+
+impl<T> Clone for EventDispatcher<T> {
+    fn clone(&self) -> Self {
+        Self {
+            queues: self.queues.clone(),
+            limiter: self.limiter.clone(),
+            capacity: self.capacity,
+            handler: self.handler.clone(),
+        }
+    }
+}
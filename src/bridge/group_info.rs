@@ -0,0 +1,91 @@
+use crate::formatter::escape_html;
+use crate::wechat::{GroupInfo, GroupMember};
+
+/// How many members the `members` command lists at most.
+pub const MAX_LISTED_MEMBERS: usize = 50;
+
+/// The members whose name, group nickname or ID contains the query, ignoring case.
+pub fn search_members<'a>(members: &'a [GroupMember], query: Option<&str>) -> Vec<&'a GroupMember> {
+    let Some(query) = query.map(str::to_lowercase).filter(|q| !q.is_empty()) else {
+        return members.iter().collect();
+    };
+    members
+        .iter()
+        .filter(|m| {
+            [Some(&m.name), m.nickname.as_ref(), Some(&m.id)]
+                .into_iter()
+                .flatten()
+                .any(|field| field.to_lowercase().contains(&query))
+        })
+        .collect()
+}
+
+/// The reply to `members`, as plain text and HTML.
+pub fn format_members(found: &[&GroupMember], total: usize, query: Option<&str>) -> (String, String) {
+    let heading = match query {
+        Some(query) if found.is_empty() => return no_match(query),
+        Some(query) => format!("{} of {} members match \"{}\":", found.len(), total, query),
+        None => format!("The group has {} members:", total),
+    };
+    let mut body = heading.clone();
+    let mut html = format!("<p>{}</p><ul>", escape_html(&heading));
+    for member in found.iter().take(MAX_LISTED_MEMBERS) {
+        let nickname = member.nickname.as_deref().filter(|n| !n.is_empty() && *n != member.name);
+        match nickname {
+            Some(nickname) => {
+                body.push_str(&format!("\n- {} ({}) {}", nickname, member.name, member.id));
+                html.push_str(&format!(
+                    "<li><strong>{}</strong> ({}) <code>{}</code></li>",
+                    escape_html(nickname),
+                    escape_html(&member.name),
+                    escape_html(&member.id)
+                ));
+            }
+            None => {
+                body.push_str(&format!("\n- {} {}", member.name, member.id));
+                html.push_str(&format!(
+                    "<li><strong>{}</strong> <code>{}</code></li>",
+                    escape_html(&member.name),
+                    escape_html(&member.id)
+                ));
+            }
+        }
+    }
+    html.push_str("</ul>");
+    if found.len() > MAX_LISTED_MEMBERS {
+        let more = format!("... and {} more, search with `members <query>`", found.len() - MAX_LISTED_MEMBERS);
+        body.push_str(&format!("\n{}", more));
+        html.push_str(&format!("<p>{}</p>", escape_html(&more)));
+    }
+    (body, html)
+}
+
+fn no_match(query: &str) -> (String, String) {
+    let body = format!("No members match \"{}\".", query);
+    let html = escape_html(&body);
+    (body, html)
+}
+
+/// The reply to `info`, as plain text and HTML. `owner` is the owner's name, when known.
+pub fn format_group_info(info: &GroupInfo, member_count: usize, owner: Option<&str>, encrypted: bool) -> (String, String) {
+    let mut fields = vec![
+        ("Group ID", info.id.clone()),
+        ("Members", member_count.to_string()),
+    ];
+    if let Some(owner) = owner {
+        fields.push(("Owner", owner.to_string()));
+    }
+    fields.push(("Encrypted", if encrypted { "yes" } else { "no" }.to_string()));
+    if let Some(notice) = info.notice.as_deref().filter(|n| !n.is_empty()) {
+        fields.push(("Announcement", notice.to_string()));
+    }
+
+    let mut body = info.name.clone();
+    let mut html = format!("<p><strong>{}</strong></p><ul>", escape_html(&info.name));
+    for (label, value) in fields {
+        body.push_str(&format!("\n- {}: {}", label, value));
+        html.push_str(&format!("<li>{}: {}</li>", label, escape_html(&value).replace('\n', "<br/>")));
+    }
+    html.push_str("</ul>");
+    (body, html)
+}
@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+/// The result of checking that the bridge and the homeserver can reach each other.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HomeserverStatus {
+    pub checked_at: i64,
+    /// The homeserver answered a `whoami` made with the `as_token`.
+    pub reachable: bool,
+    /// The homeserver reached the bridge when asked to ping it. `None` when it couldn't be
+    /// asked, because it's unreachable or doesn't support appservice pings.
+    pub pingable: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ping_duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl HomeserverStatus {
+    pub fn is_ok(&self) -> bool {
+        self.reachable && self.pingable != Some(false)
+    }
+}
+
+/// Keeps the last homeserver connectivity check for `/status`.
+#[derive(Clone, Default)]
+pub struct HomeserverMonitor {
+    last: Arc<RwLock<Option<HomeserverStatus>>>,
+}
+
+impl HomeserverMonitor {
+    pub async fn last(&self) -> Option<HomeserverStatus> {
+        self.last.read().await.clone()
+    }
+
+    /// Records a check, returning whether connectivity changed since the previous one. The
+    /// first check always counts as a change.
+    pub async fn record(&self, status: HomeserverStatus) -> bool {
+        let ok = status.is_ok();
+        let previous = self.last.write().await.replace(status);
+        previous.map(|p| p.is_ok()) != Some(ok)
+    }
+}
@@ -0,0 +1,174 @@
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// How long fetching a page or its image may take.
+pub const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pages are only read this far, the metadata is in the head.
+const MAX_PAGE_SIZE: usize = 512 * 1024;
+/// Larger preview images are skipped.
+const MAX_IMAGE_SIZE: usize = 5 * 1024 * 1024;
+
+static META_TAG: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?is)<meta\s[^>]*>").unwrap());
+static META_ATTR: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?is)(property|name|content)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+static TITLE_TAG: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap());
+
+/// The preview of a link shared in a WeChat app message, from the card's own metadata or the
+/// Open Graph tags of the page.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Where to download the preview image from.
+    pub image_url: Option<String>,
+}
+
+/// A preview image uploaded to Matrix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreviewImage {
+    pub mxc: String,
+    pub mimetype: String,
+    pub size: usize,
+}
+
+impl LinkPreview {
+    /// Reads the Open Graph tags of a page, falling back to its `<title>`.
+    pub fn from_html(url: &str, html: &str) -> Self {
+        let mut preview = Self {
+            url: url.to_string(),
+            ..Self::default()
+        };
+        for tag in META_TAG.find_iter(html) {
+            let (mut key, mut content) = (None, None);
+            for attr in META_ATTR.captures_iter(tag.as_str()) {
+                let value = attr.get(2).or(attr.get(3)).map_or("", |v| v.as_str());
+                match attr[1].to_ascii_lowercase().as_str() {
+                    "content" => content = Some(unescape(value)),
+                    _ => key = Some(value.to_ascii_lowercase()),
+                }
+            }
+            let (Some(key), Some(content)) = (key, content.filter(|c| !c.is_empty())) else {
+                continue;
+            };
+            let field = match key.as_str() {
+                "og:title" => &mut preview.title,
+                "og:description" | "description" => &mut preview.description,
+                "og:image" | "og:image:url" => &mut preview.image_url,
+                _ => continue,
+            };
+            field.get_or_insert(content);
+        }
+        if preview.title.is_none() {
+            preview.title = TITLE_TAG
+                .captures(html)
+                .map(|c| unescape(c[1].trim()))
+                .filter(|t| !t.is_empty());
+        }
+        if let Some(image) = &preview.image_url {
+            preview.image_url = resolve(url, image);
+        }
+        preview
+    }
+
+    /// Fills in what the card is missing from the page's preview.
+    pub fn merge(&mut self, page: LinkPreview) {
+        self.title = self.title.take().or(page.title);
+        self.description = self.description.take().or(page.description);
+        self.image_url = self.image_url.take().or(page.image_url);
+    }
+
+    /// Whether the card has everything a preview shows, so the page needn't be fetched.
+    pub fn is_complete(&self) -> bool {
+        self.title.is_some() && self.image_url.is_some()
+    }
+
+    /// The entry for the `com.beeper.linkpreviews` list of a message.
+    pub fn to_beeper(&self, image: Option<&PreviewImage>) -> serde_json::Value {
+        let mut preview = serde_json::json!({
+            "matched_url": self.url,
+            "og:url": self.url,
+        });
+        if let Some(title) = &self.title {
+            preview["og:title"] = title.as_str().into();
+        }
+        if let Some(description) = &self.description {
+            preview["og:description"] = description.as_str().into();
+        }
+        if let Some(image) = image {
+            preview["og:image"] = image.mxc.as_str().into();
+            preview["og:image:type"] = image.mimetype.as_str().into();
+            preview["matrix:image:size"] = image.size.into();
+        }
+        preview
+    }
+}
+
+/// Fetches the preview of a page.
+pub async fn fetch_preview(http: &reqwest::Client, url: &str) -> Result<LinkPreview> {
+    let (body, mimetype) = fetch_limited(http, url, MAX_PAGE_SIZE, true).await?;
+    if !mimetype.contains("html") {
+        return Err(anyhow!("{} is {}, not a web page", url, mimetype));
+    }
+    Ok(LinkPreview::from_html(url, &String::from_utf8_lossy(&body)))
+}
+
+/// Downloads a preview image, returning it with its type.
+pub async fn fetch_image(http: &reqwest::Client, url: &str) -> Result<(Vec<u8>, String)> {
+    let (data, mimetype) = fetch_limited(http, url, MAX_IMAGE_SIZE, false).await?;
+    if !mimetype.starts_with("image/") {
+        return Err(anyhow!("{} is {}, not an image", url, mimetype));
+    }
+    Ok((data, mimetype))
+}
+
+/// Reads a response up to `max` bytes. Longer ones are cut off if `truncate`, or else rejected.
+async fn fetch_limited(http: &reqwest::Client, url: &str, max: usize, truncate: bool) -> Result<(Vec<u8>, String)> {
+    let mut response = http.get(url).timeout(FETCH_TIMEOUT).send().await?.error_for_status()?;
+    let mimetype = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or(v).trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() > max {
+            if !truncate {
+                return Err(anyhow!("{} is larger than {} bytes", url, max));
+            }
+            body.truncate(max);
+            break;
+        }
+    }
+    Ok((body, mimetype))
+}
+
+/// Resolves a possibly relative image URL against the page's.
+fn resolve(page: &str, url: &str) -> Option<String> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        return Some(url.to_string());
+    }
+    let scheme_end = page.find("://")? + 3;
+    if let Some(rest) = url.strip_prefix("//") {
+        return Some(format!("{}{}", &page[..scheme_end], rest));
+    }
+    let origin_end = page[scheme_end..].find('/').map_or(page.len(), |i| scheme_end + i);
+    if url.starts_with('/') {
+        return Some(format!("{}{}", &page[..origin_end], url));
+    }
+    None
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
@@ -0,0 +1,98 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::Duration;
+
+use tokio::sync::{Mutex, Notify};
+
+use crate::config::SendRetryConfig;
+use crate::database::MatrixQueueItem;
+use crate::wechat::Event;
+
+/// Keeps WeChat events that couldn't be bridged while the homeserver was unreachable. The
+/// events live in the `matrix_queue` table; this tracks which chats have events waiting, so
+/// that newer events of those chats queue up behind them instead of overtaking them.
+#[derive(Clone)]
+pub struct MatrixQueue {
+    config: SendRetryConfig,
+    /// Users and chats with queued events.
+    waiting: Arc<Mutex<HashSet<(String, String)>>>,
+    wake: Arc<Notify>,
+    /// Set when the homeserver is reachable again, to retry everything without waiting.
+    flush_all: Arc<AtomicBool>,
+    last_queued_at: Arc<AtomicI64>,
+}
+
+impl MatrixQueue {
+    pub fn new(config: SendRetryConfig) -> Self {
+        Self {
+            config,
+            waiting: Arc::new(Mutex::new(HashSet::new())),
+            wake: Arc::new(Notify::new()),
+            flush_all: Arc::new(AtomicBool::new(false)),
+            last_queued_at: Arc::new(AtomicI64::new(0)),
+        }
+    }
+
+    /// Whether events of the chat of `event` are queued, so it must be queued after them.
+    pub async fn is_waiting(&self, event: &Event) -> bool {
+        self.waiting.lock().await.contains(&(event.mxid.clone(), event.chat.id.clone()))
+    }
+
+    pub async fn set_waiting(&self, mxid: &str, chat_id: &str, waiting: bool) {
+        let key = (mxid.to_string(), chat_id.to_string());
+        let mut chats = self.waiting.lock().await;
+        if waiting {
+            chats.insert(key);
+        } else {
+            chats.remove(&key);
+        }
+    }
+
+    /// A new queue entry for an event, due for its first retry after the initial delay.
+    pub fn item(&self, event: &Event) -> anyhow::Result<MatrixQueueItem> {
+        let now = chrono::Utc::now();
+        let micros = now.timestamp_micros();
+        // Events queued within the same microsecond keep their order.
+        let previous = self.last_queued_at
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(micros.max(last + 1)))
+            .unwrap_or_else(|last| last);
+        let queued_at = micros.max(previous + 1);
+        Ok(MatrixQueueItem {
+            mxid: event.mxid.clone(),
+            event_id: event.id.clone(),
+            chat_id: event.chat.id.clone(),
+            event: serde_json::to_string(event)?,
+            attempts: 0,
+            queued_at,
+            next_attempt_at: now.timestamp_millis() + self.delay(0).unwrap_or_default().as_millis() as i64,
+        })
+    }
+
+    /// How long to wait before retry number `attempt`, or `None` once retries are exhausted.
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        self.config.delay(attempt)
+    }
+
+    /// Wakes the flush loop, e.g. after an event was queued.
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Retries every queued event right away, once the homeserver is reachable again.
+    pub fn flush_now(&self) {
+        self.flush_all.store(true, Ordering::SeqCst);
+        self.wake.notify_one();
+    }
+
+    /// Waits until woken or `timeout` passes, returning whether everything should be retried.
+    pub async fn wait(&self, timeout: Option<Duration>) -> bool {
+        match timeout {
+            Some(timeout) => {
+                let _ = tokio::time::timeout(timeout, self.wake.notified()).await;
+            }
+            None => self.wake.notified().await,
+        }
+        self.flush_all.swap(false, Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use crate::database::{Database, MediaCacheEntry};
+use crate::matrix::MatrixClient;
+
+/// Reuses earlier uploads of the same content, so avatars and stickers that come with every
+/// sync aren't uploaded to the media repo again. Uploads are forgotten once they haven't been
+/// reused for the TTL; without one, everything is uploaded.
+#[derive(Clone)]
+pub struct MediaCache {
+    db: Database,
+    ttl: Option<Duration>,
+}
+
+impl MediaCache {
+    pub fn new(db: Database, ttl: Option<Duration>) -> Self {
+        Self { db, ttl }
+    }
+
+    /// Uploads `data`, or returns the mxc URI of an earlier upload of the same content.
+    pub async fn upload(
+        &self,
+        client: &MatrixClient,
+        data: &[u8],
+        mimetype: &str,
+        filename: &str,
+    ) -> anyhow::Result<String> {
+        let Some(ttl) = self.ttl else {
+            return client.upload_media(data, mimetype, filename).await;
+        };
+        let hash = content_hash(data);
+        let now = chrono::Utc::now().timestamp();
+        if let Some(entry) = self.db.get_media_cache(&hash).await?
+            && entry.last_used + ttl.as_secs() as i64 > now
+        {
+            self.db.touch_media_cache(&hash, now).await?;
+            return Ok(entry.mxc);
+        }
+
+        let mxc = client.upload_media(data, mimetype, filename).await?;
+        self.db.upsert_media_cache(&MediaCacheEntry {
+            hash,
+            mxc: mxc.clone(),
+            mimetype: mimetype.to_string(),
+            size: data.len() as i64,
+            created_at: now,
+            last_used: now,
+        }).await?;
+        Ok(mxc)
+    }
+
+    /// Forgets uploads that haven't been reused within the TTL.
+    pub async fn prune(&self) -> anyhow::Result<usize> {
+        let Some(ttl) = self.ttl else {
+            return Ok(0);
+        };
+        let cutoff = chrono::Utc::now().timestamp() - ttl.as_secs() as i64;
+        self.db.delete_media_cache_unused_before(cutoff).await
+    }
+}
+
+/// The hex SHA-256 of media content, which identifies it in the cache.
+pub fn content_hash(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
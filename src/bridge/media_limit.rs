@@ -0,0 +1,50 @@
+use thiserror::Error;
+
+use crate::formatter::system::tag;
+
+/// Media over a size limit set in `bridge.media`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("the file is {}, larger than the limit of {}", format_size(*.size), format_size(*.limit))]
+pub struct MediaTooLarge {
+    pub size: u64,
+    pub limit: u64,
+}
+
+/// Checks a size against a limit, `None` being no limit.
+pub fn check_size(size: u64, limit: Option<u64>) -> Result<(), MediaTooLarge> {
+    match limit {
+        Some(limit) if size > limit => Err(MediaTooLarge { size, limit }),
+        _ => Ok(()),
+    }
+}
+
+/// The size of the media of a WeChat event, when the agent or the message XML tells it before
+/// it's downloaded.
+pub fn declared_size(data: &serde_json::Value) -> Option<u64> {
+    if let Some(size) = data.get("size").and_then(|v| v.as_u64()) {
+        return Some(size);
+    }
+    let xml = data.get("xml")?.as_str()?;
+    // Files carry `<totallen>`, images and videos a `length` attribute.
+    if let Some(size) = tag(xml, "totallen").and_then(|s| s.parse().ok()) {
+        return Some(size);
+    }
+    let start = xml.find(" length=\"")? + " length=\"".len();
+    xml[start..].split('"').next()?.parse().ok()
+}
+
+/// A size for people, such as `12.5 MB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["bytes", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} bytes", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::matrix::client::MatrixClient;
+use crate::matrix::types::RoomEvent;
+
+const STATUS_EVENT_TYPE: &str = "com.beeper.message_send_status";
+
+/// Why a Matrix message didn't reach WeChat, stored in the `error` column of its message row.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SendFailure {
+    pub reason: String,
+    #[serde(default)]
+    pub retry_num: u32,
+    /// Whether the message is queued to be sent again.
+    #[serde(default)]
+    pub will_retry: bool,
+}
+
+impl SendFailure {
+    pub fn new(reason: impl Into<String>, retry_num: u32) -> Self {
+        Self {
+            reason: reason.into(),
+            retry_num,
+            will_retry: false,
+        }
+    }
+
+    pub fn retriable(mut self) -> Self {
+        self.will_retry = true;
+        self
+    }
+
+    /// Parses the `error` column, treating plain text from older rows as a first failure.
+    pub fn from_db(error: &str) -> Self {
+        serde_json::from_str(error).unwrap_or_else(|_| Self::new(error, 0))
+    }
+
+    pub fn to_db(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| self.reason.clone())
+    }
+}
+
+/// Reports whether Matrix messages were delivered to WeChat, as MSC2311 send checkpoints
+/// to the configured endpoint and as status events or notices in the room.
+#[derive(Clone)]
+pub struct MessageStatusReporter {
+    http: reqwest::Client,
+    checkpoint_endpoint: Option<String>,
+    as_token: String,
+    status_events: bool,
+    error_notices: bool,
+}
+
+impl MessageStatusReporter {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            checkpoint_endpoint: config.homeserver.message_send_checkpoint_endpoint.clone()
+                .filter(|e| !e.is_empty()),
+            as_token: config.appservice.as_token.clone(),
+            status_events: config.bridge.message_status_events,
+            error_notices: config.bridge.message_error_notices,
+        }
+    }
+
+    /// Reports the outcome of an attempt to send `event`. `notice_id` is the error notice sent
+    /// for an earlier attempt, which is edited instead of sending another one. Returns the notice
+    /// that now describes the message, if any.
+    pub async fn report(
+        &self,
+        client: &MatrixClient,
+        event: &RoomEvent,
+        msgtype: &str,
+        failure: Option<&SendFailure>,
+        notice_id: Option<&str>,
+    ) -> Option<String> {
+        let (Some(room_id), Some(event_id)) = (&event.room_id, &event.event_id) else {
+            return None;
+        };
+
+        if let Err(e) = self.send_checkpoint(room_id, event_id, &event.event_type, msgtype, failure).await {
+            warn!("Failed to send message checkpoint for {}: {}", event_id, e);
+        }
+
+        if self.status_events {
+            let content = status_event_content(event_id, failure);
+            if let Err(e) = client.send_message(room_id, STATUS_EVENT_TYPE, &content, None).await {
+                warn!("Failed to send message status for {}: {}", event_id, e);
+            }
+        }
+
+        if !self.error_notices {
+            return None;
+        }
+        let body = match (failure, notice_id) {
+            // Keep the first notice as is while the message is still being retried.
+            (Some(failure), Some(_)) if failure.will_retry => return notice_id.map(str::to_string),
+            (Some(failure), _) if failure.will_retry => format!(
+                "\u{26a0} Your message couldn't be bridged yet, the bridge will retry: {}",
+                failure.reason
+            ),
+            (Some(failure), _) => format!("\u{26a0} Your message was not bridged: {}", failure.reason),
+            (None, Some(_)) => "\u{2705} Your message was bridged after retrying".to_string(),
+            (None, None) => return None,
+        };
+
+        let content = match notice_id {
+            Some(notice_id) => json!({
+                "msgtype": "m.notice",
+                "body": format!("* {}", body),
+                "m.new_content": { "msgtype": "m.notice", "body": body },
+                "m.relates_to": { "rel_type": "m.replace", "event_id": notice_id },
+            }),
+            None => json!({
+                "msgtype": "m.notice",
+                "body": body,
+                "m.relates_to": {
+                    "m.in_reply_to": { "event_id": event_id },
+                },
+            }),
+        };
+        match client.send_message(room_id, "m.room.message", &content, None).await {
+            Ok(sent_id) => Some(notice_id.map(str::to_string).unwrap_or(sent_id)),
+            Err(e) => {
+                warn!("Failed to send error notice for {}: {}", event_id, e);
+                notice_id.map(str::to_string)
+            }
+        }
+    }
+
+    async fn send_checkpoint(
+        &self,
+        room_id: &str,
+        event_id: &str,
+        event_type: &str,
+        msgtype: &str,
+        failure: Option<&SendFailure>,
+    ) -> anyhow::Result<()> {
+        let Some(endpoint) = &self.checkpoint_endpoint else {
+            return Ok(());
+        };
+
+        let checkpoint = json!({
+            "event_id": event_id,
+            "room_id": room_id,
+            "step": "BRIDGE",
+            "timestamp": chrono::Utc::now().timestamp_millis(),
+            "status": match failure {
+                None => "SUCCESS",
+                Some(f) if f.will_retry => "WILL_RETRY",
+                Some(_) => "PERM_FAILURE",
+            },
+            "event_type": event_type,
+            "message_type": msgtype,
+            "reported_by": "BRIDGE",
+            "retry_num": failure.map(|f| f.retry_num).unwrap_or(0),
+            "info": failure.map(|f| f.reason.as_str()),
+        });
+
+        self.http
+            .post(endpoint)
+            .bearer_auth(&self.as_token)
+            .json(&json!({ "checkpoints": [checkpoint] }))
+            .send()
+            .await?
+            .error_for_status()?;
+        debug!("Sent message checkpoint for {}", event_id);
+        Ok(())
+    }
+}
+
+fn status_event_content(event_id: &str, failure: Option<&SendFailure>) -> serde_json::Value {
+    let mut content = json!({
+        "network": "wechat",
+        "m.relates_to": {
+            "rel_type": "m.reference",
+            "event_id": event_id,
+        },
+    });
+    match failure {
+        None => content["status"] = json!("SUCCESS"),
+        Some(failure) => {
+            content["status"] = json!(if failure.will_retry { "FAIL_RETRIABLE" } else { "FAIL_PERMANENT" });
+            content["reason"] = json!("m.foreign_network_error");
+            content["error"] = json!(failure.reason);
+            content["message"] = json!(if failure.will_retry {
+                format!("Your message couldn't be bridged yet: {}", failure.reason)
+            } else {
+                format!("Your message was not bridged: {}", failure.reason)
+            });
+            content["retry_num"] = json!(failure.retry_num);
+        }
+    }
+    content
+}
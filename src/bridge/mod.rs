@@ -3,6 +3,27 @@ pub mod user;
 pub mod portal;
 pub mod puppet;
 pub mod command;
+pub mod admin_command;
+pub mod contact_sync;
+pub mod dedup;
+pub mod activity;
+pub mod debug_log;
+pub mod event_ack;
+pub mod event_dispatcher;
+pub mod group_info;
+pub mod homeserver_check;
+pub mod link_preview;
+pub mod matrix_queue;
+pub mod media_limit;
+pub mod media_cache;
+pub mod portal_gc;
+pub mod preflight;
+pub mod connection_notifier;
+pub mod message_status;
+pub mod send_retry;
+pub mod session_supervisor;
+pub mod transactions;
+pub mod voip;
 
 pub use wechat_bridge::WechatBridge;
 pub use user::BridgeUser;
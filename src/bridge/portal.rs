@@ -5,13 +5,28 @@ use tracing::{info, debug, warn, error};
 
 use crate::database::{Portal as DbPortal, PortalKey, Database};
 use crate::matrix::client::MatrixClient;
-use crate::matrix::types::{CreateRoomRequest, RoomMemberContent, PowerLevelsContent};
+use crate::matrix::state_cache::RoomStateCache;
+use crate::matrix::types::{CreateRoomRequest, JoinedMember, RoomMemberContent, PowerLevelsContent};
 use crate::wechat::ChatType;
 
+/// The portal key of a private chat seen by the logged-in account `own_uin`: the other side of
+/// the conversation and the account. Messages the user sent from another device are from
+/// themselves, so the peer is the chat then. Without a known account the chat and sender are
+/// used, as before accounts were known.
+pub fn private_chat_key(chat_id: &str, from_id: &str, own_uin: Option<&str>) -> PortalKey {
+    match own_uin {
+        Some(own) if chat_id != own => PortalKey::new(chat_id, own),
+        Some(own) => PortalKey::new(from_id, own),
+        None => PortalKey::new(chat_id, from_id),
+    }
+}
+
 pub struct BridgePortal {
     pub key: PortalKey,
     pub inner: DbPortal,
     db: Database,
+    /// Alias added to the room when it's created.
+    alias: Option<String>,
 }
 
 impl BridgePortal {
@@ -35,6 +50,7 @@ impl BridgePortal {
                 next_batch_id: None,
             },
             db,
+            alias: None,
         }
     }
 
@@ -44,9 +60,15 @@ impl BridgePortal {
             key,
             inner: portal,
             db,
+            alias: None,
         }
     }
 
+    pub fn with_alias(mut self, alias: String) -> Self {
+        self.alias = Some(alias);
+        self
+    }
+
     pub fn mxid(&self) -> Option<&str> {
         self.inner.mxid.as_deref()
     }
@@ -90,11 +112,40 @@ impl BridgePortal {
         Ok(())
     }
 
+    pub fn bridge_info_state_key(&self) -> String {
+        format!("net.maunium.wechat://wechat/{}", self.key.uid)
+    }
+
+    /// Content of the `m.room.bridge` state event describing this portal.
+    pub fn bridge_info(&self, bot_mxid: &str, name: &str, avatar_url: &str) -> serde_json::Value {
+        serde_json::json!({
+            "bridgebot": bot_mxid,
+            "creator": bot_mxid,
+            "protocol": {
+                "id": "wechat",
+                "displayname": "WeChat",
+                "avatar_url": "",
+                "external_url": "",
+            },
+            "network": {
+                "id": "wechat",
+                "displayname": "WeChat",
+                "avatar_url": "",
+                "external_url": "",
+            },
+            "channel": {
+                "id": self.key.uid,
+                "displayname": name,
+                "avatar_url": avatar_url,
+            },
+        })
+    }
+
     pub async fn get_matrix_room(
         &mut self,
         client: &MatrixClient,
         user_mxid: &str,
-        puppet_mxid: &str,
+        puppet_mxid: Option<&str>,
         name: Option<&str>,
         avatar_url: Option<&str>,
         is_direct: bool,
@@ -111,7 +162,7 @@ impl BridgePortal {
         &mut self,
         client: &MatrixClient,
         user_mxid: &str,
-        puppet_mxid: &str,
+        puppet_mxid: Option<&str>,
         name: Option<&str>,
         avatar_url: Option<&str>,
         is_direct: bool,
@@ -123,28 +174,8 @@ impl BridgePortal {
         
         initial_state.push(serde_json::json!({
             "type": "m.room.bridge",
-            "state_key": format!("net.maunium.wechat://wechat/{}", self.key.uid),
-            "content": {
-                "bridgebot": client.user_id().unwrap_or(""),
-                "creator": client.user_id().unwrap_or(""),
-                "protocol": {
-                    "id": "wechat",
-                    "displayname": "WeChat",
-                    "avatar_url": "",
-                    "external_url": "",
-                },
-                "network": {
-                    "id": "wechat",
-                    "displayname": "WeChat",
-                    "avatar_url": "",
-                    "external_url": "",
-                },
-                "channel": {
-                    "id": self.key.uid,
-                    "displayname": room_name,
-                    "avatar_url": avatar_url.unwrap_or(""),
-                },
-            }
+            "state_key": self.bridge_info_state_key(),
+            "content": self.bridge_info(client.user_id().unwrap_or(""), room_name, avatar_url.unwrap_or("")),
         }));
 
         if encrypted {
@@ -165,20 +196,25 @@ impl BridgePortal {
 
         let mut power_levels = PowerLevelsContent::default();
         power_levels.users.insert(user_mxid.to_string(), 100);
-        power_levels.users.insert(puppet_mxid.to_string(), 100);
+        let mut invite = vec![user_mxid.to_string()];
+        if let Some(puppet_mxid) = puppet_mxid {
+            power_levels.users.insert(puppet_mxid.to_string(), 100);
+            invite.push(puppet_mxid.to_string());
+        }
 
         let request = CreateRoomRequest {
             visibility: Some("private".to_string()),
             room_alias_name: None,
             name: Some(room_name.to_string()),
             topic: None,
-            invite: vec![user_mxid.to_string(), puppet_mxid.to_string()],
+            invite,
             invite_3pid: vec![],
             room_version: None,
             preset: Some(preset.to_string()),
             is_direct,
             initial_state: Some(initial_state),
             power_level_content_override: Some(power_levels),
+            creation_content: None,
         };
 
         let room_id = client.create_room(&request).await?;
@@ -192,6 +228,13 @@ impl BridgePortal {
         }
         self.db.update_portal(&self.inner).await?;
 
+        // Group portals are shared between users, so another user's portal may hold the alias already.
+        if let Some(alias) = &self.alias
+            && let Err(e) = client.set_room_alias(&room_id, alias).await
+        {
+            debug!("Failed to add alias {} to {}: {}", alias, room_id, e);
+        }
+
         Ok(room_id)
     }
 
@@ -234,37 +277,56 @@ impl BridgePortal {
         Ok(())
     }
 
+    /// Joins the given puppets to the portal room and kicks puppets that are no longer members.
     pub async fn sync_participants(
         &mut self,
         client: &MatrixClient,
+        room_state: &RoomStateCache,
+        puppet_prefix: &str,
         puppet_mxids: &[(&str, &str, Option<&str>)],
     ) -> anyhow::Result<()> {
         let Some(room_id) = &self.inner.mxid else {
             return Ok(());
         };
 
-        let members = client.get_joined_members(room_id).await?;
+        let members = room_state.joined_members(client, room_id).await?;
         let mut joined_mxids: std::collections::HashSet<String> = members.joined.keys().cloned().collect();
 
-        for (uin, puppet_mxid, displayname) in puppet_mxids {
-            if joined_mxids.contains(*puppet_mxid) {
-                joined_mxids.remove(*puppet_mxid);
+        for (_uin, puppet_mxid, displayname) in puppet_mxids {
+            if joined_mxids.remove(*puppet_mxid) {
                 continue;
             }
 
-            let content = if let Some(name) = displayname {
-                RoomMemberContent::join_with(*name, "")
-            } else {
-                RoomMemberContent::join()
+            if let Err(e) = client.invite_user(room_id, puppet_mxid).await {
+                debug!("Failed to invite puppet {} to room {}: {}", puppet_mxid, room_id, e);
+            }
+            let puppet_client = client.clone().impersonate(*puppet_mxid);
+            if let Err(e) = puppet_client.join_room(room_id).await {
+                warn!("Failed to join puppet {} to room {}: {}", puppet_mxid, room_id, e);
+                continue;
+            }
+            if let Some(name) = displayname {
+                // Group nicknames only apply to this room, so set them on the member event.
+                let content = RoomMemberContent::join_with(*name, "");
+                if let Err(e) = puppet_client.set_membership(room_id, puppet_mxid, &content).await {
+                    warn!("Failed to set room nickname of {} in {}: {}", puppet_mxid, room_id, e);
+                }
+            }
+            let member = JoinedMember {
+                displayname: displayname.map(str::to_string),
+                avatar_url: None,
             };
+            room_state.set_member(room_id, puppet_mxid, Some(member)).await;
+            debug!("Joined puppet {} to room {}", puppet_mxid, room_id);
+        }
 
-            match client.set_membership(room_id, puppet_mxid, &content).await {
-                Ok(_) => {
-                    debug!("Invited puppet {} to room {}", puppet_mxid, room_id);
-                }
-                Err(e) => {
-                    warn!("Failed to invite puppet {} to room {}: {}", puppet_mxid, room_id, e);
+        for mxid in joined_mxids.iter().filter(|mxid| mxid.starts_with(puppet_prefix)) {
+            match client.kick_user(room_id, mxid, Some("Left the WeChat group")).await {
+                Ok(()) => {
+                    room_state.set_member(room_id, mxid, None).await;
+                    debug!("Removed puppet {} from room {}", mxid, room_id);
                 }
+                Err(e) => warn!("Failed to remove puppet {} from room {}: {}", mxid, room_id, e),
             }
         }
 
@@ -299,6 +361,7 @@ impl BridgePortal {
             key: self.key.clone(),
             inner: self.inner.clone(),
             db: self.db.clone(),
+            alias: self.alias.clone(),
         }
     }
 }
@@ -0,0 +1,64 @@
+use std::fmt;
+
+use crate::database::PortalKey;
+
+/// Why a portal room is no longer needed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcReason {
+    /// The WeChat account the portal belongs to isn't used by anyone any more.
+    ReceiverGone,
+    /// The contact or group isn't in the account's friend or group list.
+    ChatGone,
+    /// Every Matrix user left, only the bot and WeChat ghosts remain.
+    NoUsers,
+}
+
+impl fmt::Display for GcReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            GcReason::ReceiverGone => "no bridge user has its WeChat account",
+            GcReason::ChatGone => "the WeChat chat no longer exists",
+            GcReason::NoUsers => "all Matrix users left",
+        })
+    }
+}
+
+impl GcReason {
+    /// Decides whether a portal is garbage. `chat_exists` is `None` when the account's chats
+    /// couldn't be listed, e.g. because it's logged out, which doesn't count as the chat being gone.
+    pub fn of(receiver_exists: bool, chat_exists: Option<bool>, human_members: usize) -> Option<Self> {
+        if !receiver_exists {
+            Some(GcReason::ReceiverGone)
+        } else if chat_exists == Some(false) {
+            Some(GcReason::ChatGone)
+        } else if human_members == 0 {
+            Some(GcReason::NoUsers)
+        } else {
+            None
+        }
+    }
+}
+
+/// A portal room found by the garbage collector.
+#[derive(Debug, Clone)]
+pub struct GcCandidate {
+    pub key: PortalKey,
+    pub room_id: String,
+    pub reason: GcReason,
+}
+
+/// The notice sent to the admin room about a garbage collection run.
+pub fn gc_report(candidates: &[GcCandidate], dry_run: bool) -> String {
+    let mut report = if dry_run {
+        format!("Portal cleanup (dry run) found {} unused portal rooms:", candidates.len())
+    } else {
+        format!("Portal cleanup removed {} unused portal rooms:", candidates.len())
+    };
+    for candidate in candidates {
+        report.push_str(&format!(
+            "\n- {} ({} of {}): {}",
+            candidate.room_id, candidate.key.uid, candidate.key.receiver, candidate.reason
+        ));
+    }
+    report
+}
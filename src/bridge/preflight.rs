@@ -0,0 +1,89 @@
+//! Checks made before the bridge starts, and by `--validate-config`, so that everything wrong
+//! with a deployment shows up at once instead of one failure per restart.
+
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::database::Database;
+use crate::matrix::MatrixClient;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// The bridge can't work with it. Other problems, like the homeserver being down, may go
+    /// away on their own.
+    pub fatal: bool,
+    pub message: String,
+}
+
+impl Problem {
+    fn fatal(message: impl Into<String>) -> Self {
+        Self { fatal: true, message: message.into() }
+    }
+}
+
+/// The parts of a registration file that have to match the config.
+#[derive(Deserialize)]
+struct Registration {
+    id: String,
+    url: String,
+    as_token: String,
+    hs_token: String,
+    sender_localpart: String,
+}
+
+/// Runs every check, `registration` being the registration file given to the homeserver if
+/// there is one.
+pub async fn run(config: &Config, registration: Option<&Path>) -> Vec<Problem> {
+    let mut problems: Vec<Problem> = config.problems().into_iter().map(Problem::fatal).collect();
+    if let Some(path) = registration.filter(|path| path.exists()) {
+        problems.extend(check_registration(config, path));
+    }
+
+    let db_config = &config.appservice.database;
+    let db = Database::connect(&db_config.r#type, &db_config.uri, db_config.max_open_conns, db_config.max_idle_conns).await;
+    if let Err(e) = async { db?.ping().await }.await {
+        problems.push(Problem::fatal(format!("can't connect to the {} database: {}", db_config.r#type, e)));
+    }
+
+    let bot = config.appservice.bot.mxid(&config.homeserver.domain);
+    match MatrixClient::new(&config.homeserver.address, &config.appservice.as_token).get_user_id().await {
+        Ok(user_id) if user_id != bot => problems.push(Problem::fatal(format!(
+            "the as_token belongs to {} instead of {}",
+            user_id, bot
+        ))),
+        Ok(_) => {}
+        // Matrix requests are queued while the homeserver is down.
+        Err(e) => problems.push(Problem {
+            fatal: false,
+            message: format!("homeserver {} didn't answer whoami: {}", config.homeserver.address, e),
+        }),
+    }
+
+    problems
+}
+
+/// Compares the config with the registration file the homeserver was given.
+pub fn check_registration(config: &Config, path: &Path) -> Vec<Problem> {
+    let registration = match std::fs::read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|content| Ok(serde_yaml::from_str::<Registration>(&content)?))
+    {
+        Ok(registration) => registration,
+        Err(e) => return vec![Problem::fatal(format!("can't read {}: {}", path.display(), e))],
+    };
+
+    let appservice = &config.appservice;
+    [
+        ("id", &registration.id, &appservice.id),
+        ("url", &registration.url, &appservice.address),
+        ("as_token", &registration.as_token, &appservice.as_token),
+        ("hs_token", &registration.hs_token, &appservice.hs_token),
+        ("sender_localpart", &registration.sender_localpart, &appservice.bot.username),
+    ]
+    .into_iter()
+    .filter(|(_, registered, configured)| registered != configured)
+    .map(|(field, _, _)| Problem::fatal(format!("{} in {} doesn't match the config", field, path.display())))
+    .collect()
+}
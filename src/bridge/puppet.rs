@@ -1,11 +1,14 @@
 use std::sync::Arc;
 
-use tracing::{info, debug, warn};
+use tracing::{info, debug};
 
 use crate::database::{Puppet as DbPuppet, Database};
 use crate::matrix::client::MatrixClient;
+use crate::matrix::Intent;
 use crate::util::UID;
 use crate::config::BridgeConfig;
+use crate::wechat::{UserInfo, WechatBackend};
+use super::media_cache::MediaCache;
 
 pub struct BridgePuppet {
     pub uid: UID,
@@ -127,65 +130,60 @@ impl BridgePuppet {
         Ok(())
     }
 
-    pub async fn sync(
+    /// Fetches the contact's WeChat profile and applies it to the puppet.
+    pub async fn sync_profile(
         &mut self,
-        client: &MatrixClient,
-        name: Option<&str>,
-        avatar_url: Option<&str>,
-        force: bool,
+        wechat: &dyn WechatBackend,
+        intent: &Intent,
+        config: &BridgeConfig,
     ) -> anyhow::Result<()> {
-        let mxid = self.mxid(
-            client.user_id()
-                .and_then(|id| id.split(':').nth(1))
-                .unwrap_or("localhost"),
-            "",
-        );
-
-        if let Some(name) = name {
-            let quality = crate::config::NAME_QUALITY_NAME as i16;
-            if force || !self.inner.name_set || self.inner.name_quality < quality {
-                if let Err(e) = client.set_displayname(&mxid, name).await {
-                    warn!("Failed to set displayname for {}: {}", mxid, e);
-                } else {
-                    self.inner.displayname = Some(name.to_string());
-                    self.inner.name_quality = quality;
-                    self.inner.name_set = true;
-                }
-            }
+        let info = wechat.get_user_info(&self.inner.uin).await?;
+        self.update_profile(intent, config, &info).await
+    }
+
+    /// Updates the puppet's Matrix displayname and avatar from WeChat contact info.
+    ///
+    /// `intent` must act as the puppet. Double puppets are left alone, as their
+    /// profile belongs to the real Matrix user.
+    pub async fn update_profile(
+        &mut self,
+        intent: &Intent,
+        config: &BridgeConfig,
+        info: &UserInfo,
+    ) -> anyhow::Result<()> {
+        if self.is_custom_puppet() {
+            return Ok(());
+        }
+        let remark = info.remark.as_deref().unwrap_or("");
+        let (displayname, quality) = config.format_displayname(&self.inner.uin, &info.name, remark);
+        let quality = quality as i16;
+        if !self.inner.name_set
+            || self.inner.name_quality < quality
+            || (quality >= self.inner.name_quality && self.displayname() != Some(displayname.as_str()))
+        {
+            intent.set_displayname(&displayname).await?;
+            self.inner.displayname = Some(displayname);
+            self.inner.name_quality = quality;
+            self.inner.name_set = true;
         }
 
-        if let Some(url) = avatar_url {
-            if force || !self.inner.avatar_set {
-                if let Err(e) = client.set_avatar_url(&mxid, url).await {
-                    warn!("Failed to set avatar for {}: {}", mxid, e);
-                } else {
-                    self.inner.avatar_url = Some(url.to_string());
-                    self.inner.avatar_set = true;
-                }
-            }
+        if let Some(avatar) = info.avatar.as_deref().filter(|a| !a.is_empty())
+            && config.user_avatar_sync
+            && (!self.inner.avatar_set || self.avatar() != Some(avatar))
+        {
+            let data = reqwest::get(avatar).await?.error_for_status()?.bytes().await?;
+            let mimetype = crate::util::media::image::detect_format(&data).mimetype();
+            let media_cache = MediaCache::new(self.db.clone(), config.media_cache_ttl_duration());
+            let avatar_url = media_cache.upload(intent.client(), &data, mimetype, "avatar").await?;
+            intent.set_avatar_url(&avatar_url).await?;
+            self.inner.avatar = Some(avatar.to_string());
+            self.inner.avatar_url = Some(avatar_url);
+            self.inner.avatar_set = true;
         }
 
+        self.inner.last_sync = chrono::Utc::now().timestamp();
         self.db.update_puppet(&self.inner).await?;
-        Ok(())
-    }
-
-    pub async fn register(&self, client: &MatrixClient) -> anyhow::Result<()> {
-        let domain = client.user_id()
-            .and_then(|id| id.split(':').nth(1))
-            .unwrap_or("localhost");
-        let mxid = self.mxid(domain, "");
-        
-        let profile = client.get_profile(&mxid).await;
-        
-        match profile {
-            Ok(_) => {
-                debug!("Puppet {} already registered", mxid);
-            }
-            Err(_) => {
-                info!("Registering puppet {}", mxid);
-            }
-        }
-        
+        debug!("Synced profile of puppet {}", intent.mxid());
         Ok(())
     }
 
@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+use crate::config::SendRetryConfig;
+use crate::error::WeChatError;
+use crate::util::{DelayedQueue, QueueMessage};
+
+/// Schedules Matrix messages that couldn't be sent because no WeChat agent was connected.
+/// The events themselves live in the `outgoing_retry` table, so only their IDs are queued here.
+#[derive(Clone)]
+pub struct SendRetryQueue {
+    queue: DelayedQueue<()>,
+    config: SendRetryConfig,
+}
+
+impl SendRetryQueue {
+    pub fn new(config: SendRetryConfig) -> Self {
+        Self {
+            queue: DelayedQueue::new(),
+            config,
+        }
+    }
+
+    /// Only failures where the request never reached the agent are retried, as anything else
+    /// might already have been delivered.
+    pub fn is_retriable(error: &anyhow::Error) -> bool {
+        error.chain().any(|e| {
+            matches!(e.downcast_ref::<WeChatError>(), Some(WeChatError::ServiceUnavailable(_)))
+        })
+    }
+
+    pub fn can_retry(&self, retry_num: u32) -> bool {
+        self.config.delay(retry_num).is_some()
+    }
+
+    /// Queues the next attempt for an event that has failed `retry_num` times before.
+    pub async fn schedule(&self, event_id: &str, retry_num: u32) {
+        let delay = self.config.delay(retry_num).unwrap_or(Duration::ZERO);
+        self.queue.push_after(delay, QueueMessage::new(event_id, ())).await;
+    }
+
+    /// Waits for the next event that is due to be sent again.
+    pub async fn next(&self) -> String {
+        self.queue.wait_for_ready().await.id
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::RwLock;
+
+use crate::util::retry::{BackoffConfig, ExponentialBackoff};
+
+/// What to do about a user whose WeChat session was found to be gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionAction {
+    /// Ask the agent to restore the session.
+    Relogin,
+    /// A re-login was attempted recently, wait for the backoff to pass.
+    Wait,
+    /// Automatic re-login failed too often, the user has to log in again.
+    Escalate,
+}
+
+struct LostSession {
+    backoff: ExponentialBackoff,
+    next_attempt: Instant,
+}
+
+/// Decides when to retry restoring dropped WeChat sessions, backing off between attempts.
+#[derive(Clone)]
+pub struct SessionSupervisor {
+    lost: Arc<RwLock<HashMap<String, LostSession>>>,
+    backoff: BackoffConfig,
+}
+
+impl SessionSupervisor {
+    pub fn new(backoff: BackoffConfig) -> Self {
+        Self {
+            lost: Arc::new(RwLock::new(HashMap::new())),
+            backoff,
+        }
+    }
+
+    pub fn default_supervisor() -> Self {
+        Self::new(BackoffConfig {
+            initial_delay: Duration::from_secs(30),
+            max_delay: Duration::from_secs(600),
+            multiplier: 2.0,
+            max_retries: 5,
+            jitter: true,
+        })
+    }
+
+    /// Records that the user's session is alive, returning whether it had been lost.
+    pub async fn on_session_ok(&self, mxid: &str) -> bool {
+        self.lost.write().await.remove(mxid).is_some()
+    }
+
+    pub async fn on_session_lost(&self, mxid: &str, now: Instant) -> SessionAction {
+        let mut lost = self.lost.write().await;
+        let session = lost.entry(mxid.to_string()).or_insert_with(|| LostSession {
+            backoff: ExponentialBackoff::new(self.backoff.clone()),
+            next_attempt: now,
+        });
+        if now < session.next_attempt {
+            return SessionAction::Wait;
+        }
+        match session.backoff.next_delay() {
+            Some(delay) => {
+                session.next_attempt = now + delay;
+                SessionAction::Relogin
+            }
+            None => {
+                lost.remove(mxid);
+                SessionAction::Escalate
+            }
+        }
+    }
+}
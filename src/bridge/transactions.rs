@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use crate::database::{AppserviceTxn, Database};
+use crate::util::LruCache;
+
+/// How many recent transaction IDs are kept in memory in front of the database.
+const RECENT_TRANSACTIONS: usize = 1024;
+/// Homeserver retries happen within minutes, so older transactions can be forgotten.
+const TRANSACTION_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Remembers which homeserver transactions were handled, so ones the homeserver retries
+/// aren't bridged twice.
+#[derive(Clone)]
+pub struct TransactionStore {
+    recent: LruCache<String, ()>,
+    db: Database,
+}
+
+impl TransactionStore {
+    pub fn new(db: Database) -> Self {
+        Self {
+            recent: LruCache::new(RECENT_TRANSACTIONS),
+            db,
+        }
+    }
+
+    pub async fn is_processed(&self, txn_id: &str) -> anyhow::Result<bool> {
+        if self.recent.contains(&txn_id.to_string()).await {
+            return Ok(true);
+        }
+        let processed = self.db.is_transaction_processed(txn_id).await?;
+        if processed {
+            self.recent.insert(txn_id.to_string(), ()).await;
+        }
+        Ok(processed)
+    }
+
+    pub async fn mark_processed(&self, txn_id: &str) -> anyhow::Result<()> {
+        self.db.insert_processed_transaction(&AppserviceTxn {
+            txn_id: txn_id.to_string(),
+            processed_at: chrono::Utc::now().timestamp_millis(),
+        }).await?;
+        self.recent.insert(txn_id.to_string(), ()).await;
+        Ok(())
+    }
+
+    /// Drops transactions older than the retention period from the database.
+    pub async fn prune(&self) -> anyhow::Result<usize> {
+        let cutoff = chrono::Utc::now().timestamp_millis() - TRANSACTION_RETENTION.as_millis() as i64;
+        self.db.delete_processed_transactions_before(cutoff).await
+    }
+}
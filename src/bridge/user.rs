@@ -1,16 +1,21 @@
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use tracing::{info, warn, debug};
 
 use crate::database::{User as DbUser, Database};
-use crate::wechat::WechatClient;
+use crate::wechat::WechatBackend;
 use crate::matrix::MatrixClient;
 use crate::config::Config;
 
+const QR_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const QR_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const QR_MAX_REFRESHES: u32 = 5;
+
 pub struct BridgeUser {
     pub mxid: String,
     pub inner: DbUser,
-    pub client: Option<WechatClient>,
+    pub client: Option<Arc<dyn WechatBackend>>,
     db: Database,
     config: Option<Arc<Config>>,
 }
@@ -73,7 +78,7 @@ impl BridgeUser {
         self.inner.uin.is_some() && self.client.is_some()
     }
 
-    pub fn get_client(&self) -> Option<&WechatClient> {
+    pub fn get_client(&self) -> Option<&Arc<dyn WechatBackend>> {
         self.client.as_ref()
     }
 
@@ -98,13 +103,11 @@ impl BridgeUser {
         Ok(())
     }
 
-    pub fn set_client(&mut self, client: WechatClient) {
+    pub fn set_client(&mut self, client: Arc<dyn WechatBackend>) {
         self.client = Some(client);
     }
 
-    pub async fn login(&mut self, wechat_service: Arc<crate::wechat::WechatService>) -> anyhow::Result<()> {
-        let client = WechatClient::new(self.mxid.clone(), wechat_service);
-        
+    pub async fn login(&mut self, client: Arc<dyn WechatBackend>) -> anyhow::Result<()> {
         client.connect().await?;
         
         let is_logged = client.is_logged_in().await?;
@@ -122,6 +125,83 @@ impl BridgeUser {
         Ok(())
     }
 
+    pub async fn login_with_qr(
+        &mut self,
+        client: Arc<dyn WechatBackend>,
+        matrix_client: &MatrixClient,
+        room_id: &str,
+    ) -> anyhow::Result<bool> {
+        client.connect().await?;
+
+        let mut qr_event_id: Option<String> = None;
+        let mut logged_in = client.is_logged_in().await?;
+
+        for attempt in 0..QR_MAX_REFRESHES {
+            if logged_in {
+                break;
+            }
+
+            let qrcode = client.get_qrcode().await?;
+            let mxc_url = matrix_client.upload_media(&qrcode, "image/png", "wechat-login-qr.png").await?;
+            let content = serde_json::json!({
+                "msgtype": "m.image",
+                "body": "Scan this QR code with WeChat to log in",
+                "url": mxc_url,
+                "info": {
+                    "mimetype": "image/png",
+                    "size": qrcode.len() as u64,
+                }
+            });
+
+            match &qr_event_id {
+                Some(event_id) => {
+                    matrix_client.edit_message(room_id, event_id, &content).await?;
+                    debug!("Refreshed login QR code for {} (attempt {})", self.mxid, attempt + 1);
+                }
+                None => {
+                    qr_event_id = Some(matrix_client.send_message(room_id, "m.room.message", &content, None).await?);
+                }
+            }
+
+            logged_in = Self::wait_for_qr_scan(client.as_ref()).await?;
+        }
+
+        if let Some(event_id) = &qr_event_id {
+            let reason = if logged_in { "Logged in" } else { "Login QR code expired" };
+            let _ = matrix_client.redact(room_id, event_id, Some(reason)).await;
+        }
+
+        if !logged_in {
+            let _ = client.disconnect().await;
+            matrix_client.send_notice(room_id, "Login timed out, use `login` to try again.").await?;
+            info!("QR login for {} timed out", self.mxid);
+            return Ok(false);
+        }
+
+        let user_info = client.get_self().await?;
+        self.inner.uin = Some(user_info.id.clone());
+        self.client = Some(client);
+        self.db.update_user(&self.inner).await?;
+
+        matrix_client.send_notice(
+            room_id,
+            format!("Successfully logged in as {} ({})", user_info.name, user_info.id),
+        ).await?;
+        info!("User {} logged in as {}", self.mxid, user_info.id);
+        Ok(true)
+    }
+
+    async fn wait_for_qr_scan(client: &dyn WechatBackend) -> anyhow::Result<bool> {
+        let deadline = Instant::now() + QR_REFRESH_INTERVAL;
+        while Instant::now() < deadline {
+            tokio::time::sleep(QR_POLL_INTERVAL).await;
+            if client.is_logged_in().await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
     pub async fn logout(&mut self) -> anyhow::Result<()> {
         if let Some(client) = &self.client {
             let _ = client.disconnect().await;
@@ -0,0 +1,101 @@
+use serde_json::json;
+
+use crate::wechat::Event;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallStatus {
+    Started,
+    Missed,
+    Rejected,
+    Cancelled,
+    Ended,
+}
+
+/// A WeChat voice or video call event, read from the `data` of a `voip` event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallEvent {
+    pub call_id: String,
+    pub status: CallStatus,
+    pub video: bool,
+    /// Length of an ended call in seconds.
+    pub duration: Option<u64>,
+}
+
+impl CallEvent {
+    pub fn from_event(event: &Event) -> Option<Self> {
+        let data = event.data.as_ref()?;
+        let status = match data.get("status").and_then(|v| v.as_str())? {
+            "start" | "started" | "ringing" => CallStatus::Started,
+            "missed" => CallStatus::Missed,
+            "rejected" | "declined" => CallStatus::Rejected,
+            "cancelled" | "canceled" => CallStatus::Cancelled,
+            "ended" | "end" => CallStatus::Ended,
+            _ => return None,
+        };
+        Some(Self {
+            call_id: data.get("call_id")
+                .and_then(|v| v.as_str())
+                .unwrap_or(&event.id)
+                .to_string(),
+            status,
+            video: data.get("media").and_then(|v| v.as_str()) == Some("video"),
+            duration: data.get("duration").and_then(|v| v.as_u64()).filter(|d| *d > 0),
+        })
+    }
+
+    pub fn notice(&self) -> String {
+        let kind = if self.video { "video call" } else { "voice call" };
+        match self.status {
+            CallStatus::Started => format!("\u{1f4de} Started a {} on WeChat", kind),
+            CallStatus::Missed => format!("\u{1f4de} Missed {}", kind),
+            CallStatus::Rejected => format!("\u{1f4de} {} was declined", capitalize(kind)),
+            CallStatus::Cancelled => format!("\u{1f4de} {} was cancelled", capitalize(kind)),
+            CallStatus::Ended => match self.duration {
+                Some(secs) => format!("\u{1f4de} {} ended after {}", capitalize(kind), format_duration(secs)),
+                None => format!("\u{1f4de} {} ended", capitalize(kind)),
+            },
+        }
+    }
+
+    /// The `m.call.*` event that shows the call in the timeline of Matrix clients. The calls
+    /// can't be answered from Matrix, so the invite has no usable offer.
+    pub fn matrix_event(&self) -> (&'static str, serde_json::Value) {
+        match self.status {
+            CallStatus::Started => ("m.call.invite", json!({
+                "call_id": self.call_id,
+                "version": "1",
+                "lifetime": 60000,
+                "offer": { "type": "offer", "sdp": "" },
+            })),
+            CallStatus::Ended => ("m.call.hangup", json!({
+                "call_id": self.call_id,
+                "version": "1",
+            })),
+            CallStatus::Missed | CallStatus::Cancelled => ("m.call.hangup", json!({
+                "call_id": self.call_id,
+                "version": "1",
+                "reason": "invite_timeout",
+            })),
+            CallStatus::Rejected => ("m.call.reject", json!({
+                "call_id": self.call_id,
+                "version": "1",
+            })),
+        }
+    }
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
@@ -4,23 +4,94 @@ use std::pin::Pin;
 use std::future::Future;
 
 use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use tracing::{info, error, warn, debug};
 
-use crate::config::Config;
-use crate::database::{Database, PortalKey, User as DbUser, Portal as DbPortal, Puppet as DbPuppet, Message as DbMessage};
-use crate::wechat::{WechatService, WechatClient, Event, EventType};
-use crate::matrix::types::RoomEvent;
-use crate::matrix::AppServiceBridge;
+use crate::config::{AgentTransport, Config, PermissionLevel};
+use crate::database::{Contact, Database, PortalKey, User as DbUser, Portal as DbPortal, Puppet as DbPuppet, Message as DbMessage, Reaction, ChatWatermark};
+use crate::wechat::{AgentStatus, BackendFactory, Keepalive, WechatBackend, WechatService, WechatClient, Event, EventType, GroupInfo, UserInfo};
+use crate::wecom::WecomClient;
+use crate::crypto::{CryptoMachine, DeviceKeys};
+use crate::matrix::types::{RoomEvent, ToDeviceEvent};
+use crate::matrix::{AppServiceBridge, Intent};
+use crate::util::{MessageQueue, QueueMessage};
+use crate::matrix::state_cache::RoomStateCache;
+use crate::metrics::Direction;
 use super::user::BridgeUser;
-use super::portal::BridgePortal;
+use super::portal::{BridgePortal, private_chat_key};
 use super::puppet::BridgePuppet;
 use super::command::CommandProcessor;
+use super::admin_command::AdminCommandProcessor;
+use super::voip::CallEvent;
+use crate::formatter::wechat_to_matrix::{Mention, add_reply_fallback};
+use super::connection_notifier::{ConnectionNotice, ConnectionNotifier};
+use super::dedup::MessageDedup;
+use super::activity::UserActivity;
+use super::debug_log::DebugLog;
+use super::event_ack::EventAcks;
+use super::event_dispatcher::EventDispatcher;
+use super::homeserver_check::{HomeserverMonitor, HomeserverStatus};
+use super::media_limit::{MediaTooLarge, check_size, declared_size};
+use super::link_preview::{LinkPreview, PreviewImage, fetch_image, fetch_preview};
+use super::media_cache::MediaCache;
+use super::portal_gc::{GcCandidate, GcReason, gc_report};
+use super::session_supervisor::{SessionAction, SessionSupervisor};
+use super::contact_sync::{ContactChanges, contact_from_info, diff_contacts};
+use super::message_status::MessageStatusReporter;
+use super::send_retry::SendRetryQueue;
+use super::matrix_queue::MatrixQueue;
+use super::transactions::TransactionStore;
+
+/// How long a puppet's profile is trusted before it is fetched from WeChat again.
+const PUPPET_PROFILE_SYNC_INTERVAL_SECS: i64 = 24 * 60 * 60;
+/// Transactions waiting to be handled in async transaction mode before new ones are handled inline.
+const TRANSACTION_QUEUE_CAPACITY: usize = 256;
+/// How long shutdown waits for in-flight messages before closing the agent connections.
+const SHUTDOWN_DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// Device ID of the bridge bot's encryption device.
+const BOT_DEVICE_ID: &str = "WECHATBRIDGE";
+/// How often the custom emoji map file is checked for changes.
+const EMOJI_MAP_RELOAD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+/// The ID WeChat uses for `@all` mentions in groups.
+const WECHAT_MENTION_ALL: &str = "notify@all";
+/// How often to check for agents that have been disconnected for too long.
+const CONNECTION_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+/// How often uploads that are no longer reused are dropped from the media cache.
+const MEDIA_CACHE_CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+/// Matrix messages interrupted by a restart are sent again at startup unless they're older than this.
+const PENDING_RESEND_WINDOW: std::time::Duration = std::time::Duration::from_secs(30 * 60);
 
 pub struct WechatBridge {
     pub config: Config,
     pub db: Database,
     pub wechat_service: Arc<WechatService>,
+    /// Creates the WeChat backend of a user, the agent websocket unless replaced.
+    backends: BackendFactory,
+    pub room_state: RoomStateCache,
+    /// Shared by all Matrix clients of the bridge, so they reuse connections to the homeserver.
+    http: reqwest::Client,
+    pub message_status: MessageStatusReporter,
+    pub send_retry: SendRetryQueue,
+    /// WeChat events waiting for the homeserver to be reachable again.
+    matrix_queue: MatrixQueue,
+    connection_notifier: ConnectionNotifier,
+    dedup: MessageDedup,
+    event_acks: EventAcks,
+    pub homeserver_status: HomeserverMonitor,
+    pub activity: UserActivity,
+    pub debug_log: DebugLog,
+    media_cache: MediaCache,
+    session_supervisor: SessionSupervisor,
+    crypto: Option<Arc<CryptoMachine>>,
+    intents: Arc<RwLock<HashMap<String, Intent>>>,
     command_processor: CommandProcessor,
+    admin_command_processor: AdminCommandProcessor,
+    permission_overrides: Arc<std::sync::RwLock<HashMap<String, PermissionLevel>>>,
+    shutdown: CancellationToken,
+    tasks: TaskTracker,
+    transactions: TransactionStore,
+    transaction_queue: MessageQueue<Vec<RoomEvent>>,
     
     users_by_mxid: RwLock<HashMap<String, Arc<BridgeUser>>>,
     users_by_uin: RwLock<HashMap<String, Arc<BridgeUser>>>,
@@ -37,21 +108,85 @@ impl WechatBridge {
         let max_open = config.appservice.database.max_open_conns;
         let max_idle = config.appservice.database.max_idle_conns;
         
+        crate::matrix::rate_limit::configure(&config.bridge.rate_limits);
         let db = Database::connect(db_type, db_uri, max_open, max_idle).await?;
         db.run_migrations().await?;
         
         let wechat_service = Arc::new(WechatService::new(
             config.bridge.listen_address.clone(),
             config.bridge.listen_secret.clone(),
-        ));
+        )
+        .with_agent_tokens(db.clone(), &config.bridge.agent_auth)
+        .with_http_agents(config.bridge.http_agents.clone())
+        .with_keepalive(config.bridge.agent_ping_interval_duration().map(|interval| Keepalive {
+            interval,
+            timeout: config.bridge.agent_ping_timeout_duration(),
+        })));
         
         let command_processor = CommandProcessor::new(config.bridge.command_prefix.clone());
+        let mut permission_overrides = HashMap::new();
+        for item in db.get_permission_overrides().await? {
+            match item.level.parse() {
+                Ok(level) => {
+                    permission_overrides.insert(item.mxid, level);
+                }
+                Err(e) => warn!("Ignoring permission override for {}: {}", item.mxid, e),
+            }
+        }
+        let http = reqwest::Client::new();
+        let service = wechat_service.clone();
+        let wecom = config.wecom.clone().map(|wecom| Arc::new(WecomClient::new(wecom, http.clone())));
+        let backends: BackendFactory = Arc::new(move |mxid: &str| -> Arc<dyn WechatBackend> {
+            match &wecom {
+                Some(wecom) if wecom.mxid() == mxid => wecom.clone(),
+                _ => Arc::new(WechatClient::new(mxid.to_string(), service.clone())),
+            }
+        });
+        let room_state = RoomStateCache::new(config.bridge.room_state_cache_ttl_duration());
+        let message_status = MessageStatusReporter::new(&config);
+        let transactions = TransactionStore::new(db.clone());
+        let dedup = MessageDedup::new(db.clone());
+        let event_acks = EventAcks::new(db.clone());
+        let debug_api = &config.bridge.debug_api;
+        let debug_log = DebugLog::new(if debug_api.enabled() { debug_api.history_size } else { 0 });
+        let media_cache = MediaCache::new(db.clone(), config.bridge.media_cache_ttl_duration());
+        let send_retry = SendRetryQueue::new(config.bridge.send_retry.clone());
+        let matrix_queue = MatrixQueue::new(config.bridge.matrix_send_retry.clone());
+        let connection_notifier = ConnectionNotifier::new(config.bridge.agent_offline_alert_duration());
+        let crypto = if config.bridge.encryption.allow {
+            let bot_mxid = config.appservice.bot.mxid(&config.homeserver.domain);
+            Some(Arc::new(CryptoMachine::new_with_memory_store(bot_mxid, BOT_DEVICE_ID.to_string()).await?))
+        } else {
+            None
+        };
         
         Ok(Self {
             config,
             db,
             wechat_service,
+            backends,
+            room_state,
+            http,
+            message_status,
+            send_retry,
+            matrix_queue,
+            connection_notifier,
+            dedup,
+            event_acks,
+            homeserver_status: HomeserverMonitor::default(),
+            activity: UserActivity::default(),
+            debug_log,
+            media_cache,
+            session_supervisor: SessionSupervisor::default_supervisor(),
+            crypto,
+            intents: Arc::new(RwLock::new(HashMap::new())),
             command_processor,
+            admin_command_processor: AdminCommandProcessor::new(),
+            permission_overrides: Arc::new(std::sync::RwLock::new(permission_overrides)),
+            shutdown: CancellationToken::new(),
+            tasks: TaskTracker::new(),
+            transactions,
+            transaction_queue: MessageQueue::new(TRANSACTION_QUEUE_CAPACITY, 1),
             users_by_mxid: RwLock::new(HashMap::new()),
             users_by_uin: RwLock::new(HashMap::new()),
             portals_by_key: RwLock::new(HashMap::new()),
@@ -64,22 +199,75 @@ impl WechatBridge {
     pub async fn start(&self) -> anyhow::Result<()> {
         info!("Starting WeChat bridge");
         
-        let service = self.wechat_service.clone();
-        tokio::spawn(async move {
-            if let Err(e) = service.start().await {
-                error!("WeChat service error: {}", e);
-            }
-        });
+        match self.config.bridge.agent_transport {
+            AgentTransport::Websocket => self.wechat_service.clone().start().await?,
+            #[cfg(feature = "grpc")]
+            AgentTransport::Grpc => self.wechat_service.clone().start_grpc().await?,
+            #[cfg(not(feature = "grpc"))]
+            AgentTransport::Grpc => anyhow::bail!("the bridge was built without gRPC support"),
+        }
         
+        self.start_emoji_map_reload();
         self.start_users().await;
+        self.start_puppet_warmup();
+        self.start_member_sync();
+        self.start_contact_sync();
+        self.start_group_info_sync();
+        self.start_user_existence_check();
+        self.start_portal_gc();
+        if let Err(e) = self.reconcile_pending_messages().await {
+            warn!("Failed to reconcile messages interrupted by the last shutdown: {}", e);
+        }
+        self.start_send_retries().await;
+        self.start_matrix_queue().await;
+        self.start_missing_agent_notices();
+        self.start_connection_notices();
+        self.start_session_supervisor();
+        self.start_transaction_queue();
+        self.start_media_cache_cleanup();
+        self.start_homeserver_check();
+        match self.transactions.prune().await {
+            Ok(0) => {}
+            Ok(pruned) => debug!("Pruned {} old appservice transactions", pruned),
+            Err(e) => warn!("Failed to prune old appservice transactions: {}", e),
+        }
         
         let bridge = Arc::new(self.clone());
+        let dispatcher = EventDispatcher::new(
+            self.config.bridge.portal_message_buffer,
+            self.config.bridge.event_handling_concurrency,
+            move |event: Event| {
+                let bridge = bridge.clone();
+                async move {
+                    let seq = event.seq.map(|seq| (event.mxid.clone(), seq));
+                    if let Err(e) = bridge.tasks.track_future(bridge.handle_or_queue_event(event)).await {
+                        error!("Error handling WeChat event: {}", e);
+                    }
+                    if let Some((mxid, seq)) = seq {
+                        bridge.ack_event(&mxid, seq).await;
+                    }
+                }
+            },
+        );
+        let bridge = self.clone();
+        let shutdown = self.shutdown.clone();
         let mut event_rx = self.wechat_service.subscribe_events();
         tokio::spawn(async move {
-            while let Ok(event) = event_rx.recv().await {
-                if let Err(e) = bridge.handle_wechat_event(event).await {
-                    error!("Error handling WeChat event: {}", e);
+            loop {
+                let event = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    event = event_rx.recv() => match event {
+                        Ok(event) => event,
+                        Err(_) => break,
+                    },
+                };
+                bridge.activity.wechat_event(&event.mxid).await;
+                bridge.debug_log.record_event(&event).await;
+                if !bridge.is_new_event(&event).await {
+                    continue;
                 }
+                let chat_id = event.chat.id.clone();
+                dispatcher.dispatch(&chat_id, event).await;
             }
         });
         
@@ -95,14 +283,1626 @@ impl WechatBridge {
                     info!("Found logged in user: {}", user.mxid);
                 }
             }
-            Err(e) => {
-                error!("Failed to get logged in users: {}", e);
-            }
+            Err(e) => {
+                error!("Failed to get logged in users: {}", e);
+            }
+        }
+    }
+
+    fn start_puppet_warmup(&self) {
+        if self.config.bridge.puppet_warmup_concurrency == 0 {
+            return;
+        }
+        let bridge = self.clone();
+        tokio::spawn(async move {
+            match bridge.warm_up_puppets().await {
+                Ok(count) => info!("Warmed up {} puppets of bridged chats", count),
+                Err(e) => warn!("Failed to warm up puppets: {}", e),
+            }
+        });
+    }
+
+    /// Loads the puppets of every portal into the cache, and makes sure their Matrix users are
+    /// registered, have their stored profile and are in their portal rooms. Returns how many
+    /// puppets were checked.
+    pub async fn warm_up_puppets(&self) -> anyhow::Result<usize> {
+        let client = self.get_matrix_client();
+        let puppet_prefix = format!("@{}", self.config.bridge.user_prefix);
+        let mut rooms_by_uin: HashMap<String, Vec<String>> = HashMap::new();
+        for portal in self.db.get_all_portals_with_mxid().await? {
+            let Some(room_id) = portal.mxid else {
+                continue;
+            };
+            if !portal.uid.starts_with("@@") {
+                rooms_by_uin.entry(portal.uid).or_default().push(room_id);
+                continue;
+            }
+            match self.room_state.joined_members(&client, &room_id).await {
+                Ok(members) => {
+                    for uin in members.joined.keys()
+                        .filter(|m| m.starts_with(&puppet_prefix))
+                        .filter_map(|m| self.puppet_uin(m))
+                    {
+                        rooms_by_uin.entry(uin).or_default().push(room_id.clone());
+                    }
+                }
+                Err(e) => warn!("Failed to get members of {}: {}", room_id, e),
+            }
+        }
+
+        let limiter = crate::util::ConcurrencyLimiter::new("puppet warm-up", self.config.bridge.puppet_warmup_concurrency);
+        let count = rooms_by_uin.len();
+        let warmups = rooms_by_uin.iter().map(|(uin, rooms)| {
+            let limiter = &limiter;
+            async move {
+                let _permit = limiter.acquire().await;
+                if let Err(e) = self.warm_up_puppet(uin, rooms).await {
+                    warn!("Failed to warm up puppet {}: {}", uin, e);
+                }
+            }
+        });
+        futures_util::future::join_all(warmups).await;
+        Ok(count)
+    }
+
+    async fn warm_up_puppet(&self, uin: &str, rooms: &[String]) -> anyhow::Result<()> {
+        let puppet = self.get_puppet_by_uin(uin).await?;
+        if puppet.is_custom_puppet() {
+            return Ok(());
+        }
+        let intent = self.intent(&self.puppet_mxid(uin)).await;
+        intent.ensure_registered().await?;
+
+        let profile = self.get_matrix_client().get_profile(intent.mxid()).await?;
+        intent.assume_profile(profile.displayname.as_deref(), profile.avatar_url.as_deref()).await;
+        if puppet.inner.name_set
+            && let Some(displayname) = puppet.displayname()
+        {
+            intent.set_displayname(displayname).await?;
+        }
+        if puppet.inner.avatar_set
+            && let Some(avatar_url) = puppet.inner.avatar_url.as_deref()
+        {
+            intent.set_avatar_url(avatar_url).await?;
+        }
+
+        for room_id in rooms {
+            if let Err(e) = intent.ensure_joined(room_id).await {
+                warn!("Failed to join {} to {}: {}", intent.mxid(), room_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn start_member_sync(&self) {
+        let Some(interval) = self.config.bridge.member_sync_interval_duration() else {
+            return;
+        };
+
+        let bridge = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick completes immediately; new portals are synced on creation anyway.
+            ticker.tick().await;
+            loop {
+                tokio::select! {
+                    _ = bridge.shutdown.cancelled() => break,
+                    _ = ticker.tick() => {}
+                }
+                if let Err(e) = bridge.sync_all_group_members().await {
+                    error!("Failed to sync group members: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Loads the custom emoji map and reloads it whenever the file is modified.
+    fn start_emoji_map_reload(&self) {
+        let Some(path) = self.config.bridge.emoji_map_path.clone().filter(|p| !p.is_empty()) else {
+            return;
+        };
+        let path = std::path::PathBuf::from(path);
+        let modified = |path: &std::path::Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let load = |path: &std::path::Path| match crate::formatter::emoji::load_custom_map(path) {
+            Ok(count) => info!("Loaded {} custom emoji from {}", count, path.display()),
+            Err(e) => error!("Failed to load custom emoji from {}: {}", path.display(), e),
+        };
+
+        let mut last_modified = modified(&path);
+        load(&path);
+
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(EMOJI_MAP_RELOAD_INTERVAL);
+            ticker.tick().await;
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = ticker.tick() => {}
+                }
+                let current = modified(&path);
+                if current != last_modified {
+                    last_modified = current;
+                    load(&path);
+                }
+            }
+        });
+    }
+
+    fn start_media_cache_cleanup(&self) {
+        let media_cache = self.media_cache.clone();
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(MEDIA_CACHE_CLEANUP_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = ticker.tick() => {}
+                }
+                match media_cache.prune().await {
+                    Ok(0) => {}
+                    Ok(pruned) => debug!("Dropped {} unused uploads from the media cache", pruned),
+                    Err(e) => warn!("Failed to clean up the media cache: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Checks homeserver connectivity now and then periodically, logging when it changes.
+    fn start_homeserver_check(&self) {
+        let interval = std::time::Duration::from_secs(self.config.homeserver.connectivity_check_interval_seconds);
+        let bridge = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = (!interval.is_zero()).then(|| tokio::time::interval(interval));
+            loop {
+                let status = bridge.check_homeserver().await;
+                let (ok, error) = (status.is_ok(), status.error.clone());
+                if bridge.homeserver_status.record(status).await {
+                    if ok {
+                        info!("The bridge and the homeserver can reach each other");
+                        bridge.matrix_queue.flush_now();
+                    } else {
+                        warn!("Homeserver connectivity problem: {}", error.as_deref().unwrap_or("unknown"));
+                    }
+                }
+                let Some(ticker) = ticker.as_mut() else {
+                    break;
+                };
+                tokio::select! {
+                    _ = bridge.shutdown.cancelled() => break,
+                    _ = ticker.tick() => {}
+                }
+            }
+        });
+    }
+
+    /// Checks that the homeserver accepts the `as_token`, and that it can reach the appservice
+    /// by asking it to ping the bridge.
+    pub async fn check_homeserver(&self) -> HomeserverStatus {
+        let client = self.get_matrix_client();
+        let now = chrono::Utc::now();
+        let mut status = HomeserverStatus {
+            checked_at: now.timestamp(),
+            reachable: false,
+            pingable: None,
+            ping_duration_ms: None,
+            error: None,
+        };
+        if let Err(e) = client.get_user_id().await {
+            status.error = Some(format!("the bridge couldn't reach the homeserver: {}", e));
+            return status;
+        }
+        status.reachable = true;
+
+        let txn_id = format!("wechat-ping-{}", now.timestamp_millis());
+        match client.ping_appservice(&self.config.appservice.id, &txn_id).await {
+            Ok(duration_ms) => {
+                status.pingable = Some(true);
+                status.ping_duration_ms = Some(duration_ms);
+            }
+            // The homeserver predates appservice pings.
+            Err(e) if crate::matrix::client::matrix_errcode(&e) == Some("M_UNRECOGNIZED") => {}
+            Err(e) => {
+                status.pingable = Some(false);
+                status.error = Some(format!("the homeserver couldn't reach the bridge: {}", e));
+            }
+        }
+        status
+    }
+
+    fn start_group_info_sync(&self) {
+        let Some(interval) = self.config.bridge.group_info_sync_interval_duration() else {
+            return;
+        };
+
+        let bridge = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                tokio::select! {
+                    _ = bridge.shutdown.cancelled() => break,
+                    _ = ticker.tick() => {}
+                }
+                if let Err(e) = bridge.sync_all_group_info().await {
+                    error!("Failed to sync group info: {}", e);
+                }
+            }
+        });
+    }
+
+    fn start_user_existence_check(&self) {
+        let Some(interval) = self.config.bridge.user_existence_check_interval_duration() else {
+            return;
+        };
+
+        let bridge = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = bridge.shutdown.cancelled() => break,
+                    _ = ticker.tick() => {}
+                }
+                match bridge.tasks.track_future(bridge.clean_gone_users()).await {
+                    Ok(cleaned) if !cleaned.is_empty() => info!("Cleaned up deactivated users: {}", cleaned.join(", ")),
+                    Ok(_) => {}
+                    Err(e) => error!("Failed to check for deactivated users: {}", e),
+                }
+            }
+        });
+    }
+
+    fn start_portal_gc(&self) {
+        let Some(interval) = self.config.bridge.portal_gc.interval_duration() else {
+            return;
+        };
+
+        let bridge = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = bridge.shutdown.cancelled() => break,
+                    _ = ticker.tick() => {}
+                }
+                let dry_run = bridge.config.bridge.portal_gc.dry_run;
+                let candidates = match bridge.tasks.track_future(bridge.collect_portal_garbage(dry_run)).await {
+                    Ok(candidates) => candidates,
+                    Err(e) => {
+                        error!("Failed to clean up unused portals: {}", e);
+                        continue;
+                    }
+                };
+                if candidates.is_empty() {
+                    continue;
+                }
+                let report = gc_report(&candidates, dry_run);
+                info!("{}", report);
+                if let Some(admin_room) = &bridge.config.bridge.admin_room
+                    && let Err(e) = bridge.get_matrix_client().send_notice(admin_room, report).await
+                {
+                    warn!("Failed to report the portal cleanup to the admin room: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Finds portal rooms nobody uses any more and, unless `dry_run`, cleans them up. Portals
+    /// whose room can't be inspected are left alone.
+    pub async fn collect_portal_garbage(&self, dry_run: bool) -> anyhow::Result<Vec<GcCandidate>> {
+        let client = self.get_matrix_client();
+        let bot_mxid = self.config.appservice.bot.mxid(&self.config.homeserver.domain);
+        // The chats of each WeChat account, `None` when they couldn't be listed.
+        let mut chats: HashMap<String, Option<std::collections::HashSet<String>>> = HashMap::new();
+        let mut candidates = Vec::new();
+
+        for portal in self.db.get_all_portals_with_mxid().await? {
+            let Some(room_id) = portal.mxid.clone() else {
+                continue;
+            };
+            let members = match client.get_joined_members(&room_id).await {
+                Ok(members) => members.joined,
+                Err(e) => {
+                    debug!("Skipping portal {} in garbage collection: {}", room_id, e);
+                    continue;
+                }
+            };
+            let humans = members
+                .keys()
+                .filter(|mxid| **mxid != bot_mxid && !self.is_user_in_namespace(mxid))
+                .count();
+
+            let user = self.db.get_user_by_uin(&portal.receiver).await?;
+            let chat_exists = match &user {
+                Some(user) if self.config.bridge.portal_gc.check_chats => {
+                    if !chats.contains_key(&portal.receiver) {
+                        let listed = self.list_chats(&user.mxid).await;
+                        chats.insert(portal.receiver.clone(), listed);
+                    }
+                    chats[&portal.receiver].as_ref().map(|c| c.contains(&portal.uid))
+                }
+                _ => None,
+            };
+
+            let Some(reason) = GcReason::of(user.is_some(), chat_exists, humans) else {
+                continue;
+            };
+            let candidate = GcCandidate {
+                key: portal.key(),
+                room_id,
+                reason,
+            };
+            if !dry_run {
+                let ghosts: Vec<String> = members.into_keys().filter(|mxid| self.is_user_in_namespace(mxid)).collect();
+                if let Err(e) = self.tear_down_portal(portal, &ghosts).await {
+                    warn!("Failed to clean up portal {}: {}", candidate.room_id, e);
+                    continue;
+                }
+            }
+            candidates.push(candidate);
+        }
+        Ok(candidates)
+    }
+
+    /// The IDs of a user's WeChat contacts and groups, `None` when they can't be listed.
+    async fn list_chats(&self, mxid: &str) -> Option<std::collections::HashSet<String>> {
+        let client = self.get_client(mxid);
+        let (friends, groups) = match tokio::try_join!(client.get_friend_list(), client.get_group_list()) {
+            Ok(lists) => lists,
+            Err(e) => {
+                debug!("Failed to list the chats of {}: {}", mxid, e);
+                return None;
+            }
+        };
+        Some(friends.into_iter().map(|f| f.id).chain(groups.into_iter().map(|g| g.id)).collect())
+    }
+
+    /// Makes the ghosts and the bot leave a portal room, removes its alias and deletes the portal.
+    async fn tear_down_portal(&self, portal: DbPortal, ghosts: &[String]) -> anyhow::Result<()> {
+        let Some(room_id) = portal.mxid.clone() else {
+            return Ok(());
+        };
+        for ghost in ghosts {
+            if let Err(e) = self.intent(ghost).await.leave(&room_id).await {
+                debug!("Failed to make {} leave {}: {}", ghost, room_id, e);
+            }
+        }
+        let client = self.get_matrix_client();
+        let alias = self.config.format_alias(&portal.uid);
+        if let Err(e) = client.delete_room_alias(&alias).await {
+            debug!("Failed to remove alias {}: {}", alias, e);
+        }
+        self.remove_portal_from_spaces(&room_id).await?;
+
+        let key = portal.key();
+        self.portals_by_key.write().await.remove(&key);
+        self.portals_by_mxid.write().await.remove(&room_id);
+        let mut portal = BridgePortal::from_db(portal, self.db.clone());
+        portal.cleanup(&client).await?;
+        portal.delete().await
+    }
+
+    fn start_contact_sync(&self) {
+        let Some(interval) = self.config.bridge.contact_sync_interval_duration() else {
+            return;
+        };
+
+        let bridge = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = bridge.shutdown.cancelled() => break,
+                    _ = ticker.tick() => {}
+                }
+                let users = match bridge.db.get_all_logged_in_users().await {
+                    Ok(users) => users,
+                    Err(e) => {
+                        error!("Failed to load users for contact sync: {}", e);
+                        continue;
+                    }
+                };
+                for user in users {
+                    match bridge.tasks.track_future(bridge.sync_contacts(&user.mxid)).await {
+                        Ok(changes) if !changes.is_empty() => info!("Contact sync for {}: {}", user.mxid, changes.summary()),
+                        Ok(_) => {}
+                        Err(e) => warn!("Failed to sync contacts of {}: {}", user.mxid, e),
+                    }
+                }
+            }
+        });
+    }
+
+    /// Fetches a user's friend list and applies what changed since the last sync: new and
+    /// changed contacts get their puppet profile updated, removed ones are marked as deleted.
+    pub async fn sync_contacts(&self, user_mxid: &str) -> anyhow::Result<ContactChanges> {
+        let friends = self.get_client(user_mxid).get_friend_list().await?;
+        let stored = self.db.get_contacts(user_mxid).await?;
+        let changes = diff_contacts(&stored, &friends);
+
+        for info in changes.added.iter().chain(&changes.updated) {
+            if let Err(e) = self.sync_puppet_profile(user_mxid, &info.id, Some(info)).await {
+                warn!("Failed to sync profile of {}: {}", info.id, e);
+            }
+            self.db.upsert_contact(&contact_from_info(user_mxid, info)).await?;
+        }
+        for contact in stored.into_iter().filter(|c| changes.removed.contains(&c.uin)) {
+            self.db.upsert_contact(&Contact {
+                deleted: true,
+                updated_at: chrono::Utc::now().timestamp(),
+                ..contact
+            }).await?;
+        }
+
+        Ok(changes)
+    }
+
+    /// Settles the messages that were being sent when the bridge last stopped. Recent Matrix
+    /// messages are queued to be sent to WeChat again and older ones are marked as failed.
+    /// WeChat messages are marked as failed, so the agent replaying them bridges them again.
+    async fn reconcile_pending_messages(&self) -> anyhow::Result<()> {
+        let pending = self.db.get_pending_messages().await?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+        info!("Reconciling {} messages interrupted by the last shutdown", pending.len());
+
+        let now = chrono::Utc::now().timestamp_millis();
+        for msg in pending {
+            let key = msg.key();
+            if msg.is_pending_mxid() {
+                self.db.complete_message(&key, &msg.msg_id, &msg.msg_id, &msg.mxid, Some("interrupted before it was sent to Matrix")).await?;
+                continue;
+            }
+
+            let recent = now - msg.timestamp < PENDING_RESEND_WINDOW.as_millis() as i64;
+            let room_id = self.db.get_portal_by_key(&key).await?.and_then(|p| p.mxid);
+            let event = match room_id {
+                Some(room_id) if recent => self.get_matrix_client().get_event(&room_id, &msg.mxid).await
+                    .map(|event| (room_id, event))
+                    .map_err(|e| warn!("Failed to fetch interrupted message {}: {}", msg.mxid, e))
+                    .ok(),
+                _ => None,
+            };
+            match event {
+                Some((room_id, event)) => {
+                    debug!("Queueing interrupted message {} to be sent again", msg.mxid);
+                    self.db.upsert_outgoing_retry(&crate::database::OutgoingRetry {
+                        event_id: msg.mxid.clone(),
+                        room_id,
+                        event: serde_json::to_string(&event)?,
+                        notice_id: None,
+                    }).await?;
+                }
+                None => {
+                    let failure = super::message_status::SendFailure::new("the bridge stopped before the message was sent", 0);
+                    self.db.complete_message(&key, &msg.msg_id, &msg.msg_id, &msg.mxid, Some(&failure.to_db())).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resends Matrix messages that failed while no WeChat agent was connected, including the
+    /// ones still queued when the bridge was stopped.
+    async fn start_send_retries(&self) {
+        match self.db.get_all_outgoing_retries().await {
+            Ok(retries) => {
+                for retry in retries {
+                    self.send_retry.schedule(&retry.event_id, 0).await;
+                }
+            }
+            Err(e) => {
+                error!("Failed to load queued message retries: {}", e);
+            }
+        }
+
+        let bridge = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let event_id = tokio::select! {
+                    _ = bridge.shutdown.cancelled() => break,
+                    event_id = async {
+                        let event_id = bridge.send_retry.next().await;
+                        bridge.wechat_service.wait_for_connection().await;
+                        event_id
+                    } => event_id,
+                };
+                if let Err(e) = bridge.tasks.track_future(bridge.retry_outgoing_message(&event_id)).await {
+                    warn!("Failed to retry sending {}: {}", event_id, e);
+                }
+            }
+        });
+    }
+
+    /// Handles a WeChat event, or queues it when the homeserver is unreachable or earlier events
+    /// of its chat are still queued.
+    async fn handle_or_queue_event(&self, event: Event) -> anyhow::Result<()> {
+        if self.matrix_queue.is_waiting(&event).await {
+            return self.queue_matrix_event(&event).await;
+        }
+        match self.handle_wechat_event(event.clone()).await {
+            Err(e) if crate::matrix::is_homeserver_unavailable(&e) => {
+                warn!("Queueing WeChat event {} until the homeserver is reachable: {:#}", event.id, e);
+                self.queue_matrix_event(&event).await
+            }
+            result => result,
+        }
+    }
+
+    async fn queue_matrix_event(&self, event: &Event) -> anyhow::Result<()> {
+        self.forget_failed_message(event).await?;
+        self.db.upsert_matrix_queue_item(&self.matrix_queue.item(event)?).await?;
+        self.matrix_queue.set_waiting(&event.mxid, &event.chat.id, true).await;
+        self.matrix_queue.wake();
+        Ok(())
+    }
+
+    /// Removes the error a failed attempt left on a message that will be retried.
+    async fn forget_failed_message(&self, event: &Event) -> anyhow::Result<()> {
+        if let Some(msg) = self.db.get_message_by_wechat_id(&event.id).await?
+            && !msg.sent
+        {
+            self.db.delete_message(&msg.key(), &msg.msg_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn start_matrix_queue(&self) {
+        match self.db.get_matrix_queue().await {
+            Ok(items) => {
+                for item in &items {
+                    self.matrix_queue.set_waiting(&item.mxid, &item.chat_id, true).await;
+                }
+                if !items.is_empty() {
+                    info!("{} WeChat events are queued for the homeserver", items.len());
+                }
+            }
+            Err(e) => error!("Failed to load the queued WeChat events: {}", e),
+        }
+
+        let bridge = self.clone();
+        tokio::spawn(async move {
+            let mut flush_all = false;
+            loop {
+                let next_attempt = match bridge.flush_matrix_queue(flush_all).await {
+                    Ok(next_attempt) => next_attempt,
+                    Err(e) => {
+                        warn!("Failed to flush the queued WeChat events: {}", e);
+                        Some(std::time::Duration::from_secs(10))
+                    }
+                };
+                tokio::select! {
+                    _ = bridge.shutdown.cancelled() => break,
+                    all = bridge.matrix_queue.wait(next_attempt) => flush_all = all,
+                }
+            }
+        });
+    }
+
+    /// Bridges the queued events that are due, or all of them with `flush_all`, in order per
+    /// chat. Returns how long until the next one is due.
+    async fn flush_matrix_queue(&self, flush_all: bool) -> anyhow::Result<Option<std::time::Duration>> {
+        let mut chats: Vec<((String, String), Vec<crate::database::MatrixQueueItem>)> = Vec::new();
+        for item in self.db.get_matrix_queue().await? {
+            let key = (item.mxid.clone(), item.chat_id.clone());
+            match chats.iter_mut().find(|(chat, _)| *chat == key) {
+                Some((_, items)) => items.push(item),
+                None => chats.push((key, vec![item])),
+            }
+        }
+
+        let mut next_attempt_at: Option<i64> = None;
+        for ((mxid, chat_id), items) in chats {
+            let mut blocked = false;
+            for mut item in items {
+                let now = chrono::Utc::now().timestamp_millis();
+                if !flush_all && item.next_attempt_at > now {
+                    next_attempt_at = Some(next_attempt_at.map_or(item.next_attempt_at, |n| n.min(item.next_attempt_at)));
+                    blocked = true;
+                    break;
+                }
+                let event = match serde_json::from_str::<Event>(&item.event) {
+                    // The receiving user isn't part of the serialized event.
+                    Ok(event) => Event { mxid: item.mxid.clone(), ..event },
+                    Err(e) => {
+                        warn!("Dropping unreadable queued WeChat event {}: {}", item.event_id, e);
+                        self.db.delete_matrix_queue_item(&item.mxid, &item.event_id).await?;
+                        continue;
+                    }
+                };
+                let result = self.tasks.track_future(self.handle_wechat_event(event.clone())).await;
+                match result {
+                    Err(e) if crate::matrix::is_homeserver_unavailable(&e) => {
+                        item.attempts += 1;
+                        if let Some(delay) = self.matrix_queue.delay(item.attempts as u32) {
+                            debug!("Homeserver still unreachable for queued event {}: {:#}", item.event_id, e);
+                            self.forget_failed_message(&event).await?;
+                            item.next_attempt_at = now + delay.as_millis() as i64;
+                            next_attempt_at = Some(next_attempt_at.map_or(item.next_attempt_at, |n| n.min(item.next_attempt_at)));
+                            self.db.upsert_matrix_queue_item(&item).await?;
+                            blocked = true;
+                            break;
+                        }
+                        // The failed attempt left the message marked with its error.
+                        error!("Giving up on bridging WeChat event {} after {} attempts: {:#}", item.event_id, item.attempts, e);
+                    }
+                    Err(e) => warn!("Failed to bridge queued WeChat event {}: {:#}", item.event_id, e),
+                    Ok(()) => debug!("Bridged queued WeChat event {}", item.event_id),
+                }
+                self.db.delete_matrix_queue_item(&item.mxid, &item.event_id).await?;
+            }
+            if !blocked {
+                self.matrix_queue.set_waiting(&mxid, &chat_id, false).await;
+                // Events queued while this chat was flushed still go first.
+                let queued = self.db.get_matrix_queue().await?;
+                if queued.iter().any(|item| item.mxid == mxid && item.chat_id == chat_id) {
+                    self.matrix_queue.set_waiting(&mxid, &chat_id, true).await;
+                    self.matrix_queue.wake();
+                }
+            }
+        }
+
+        let now = chrono::Utc::now().timestamp_millis();
+        Ok(next_attempt_at.map(|at| std::time::Duration::from_millis((at - now).max(0) as u64)))
+    }
+
+    /// Tells users in their management room when requests fail because their agent is offline.
+    fn start_missing_agent_notices(&self) {
+        let bridge = self.clone();
+        let mut missing_rx = self.wechat_service.subscribe_missing_agents();
+        tokio::spawn(async move {
+            loop {
+                let mxid = tokio::select! {
+                    _ = bridge.shutdown.cancelled() => break,
+                    mxid = missing_rx.recv() => match mxid {
+                        Ok(mxid) => mxid,
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(_) => break,
+                    },
+                };
+                if let Err(e) = bridge.notify_missing_agent(&mxid).await {
+                    warn!("Failed to notify {} about their missing agent: {}", mxid, e);
+                }
+            }
+        });
+    }
+
+    async fn notify_missing_agent(&self, mxid: &str) -> anyhow::Result<()> {
+        let Some(room_id) = self.db.get_user_by_mxid(mxid).await?.and_then(|u| u.management_room) else {
+            return Ok(());
+        };
+        self.get_matrix_client()
+            .send_notice(&room_id, "Your WeChat agent isn't connected to the bridge, so messages can't be sent until it reconnects.")
+            .await?;
+        Ok(())
+    }
+
+    /// Tells users in their management room when their agent disconnects or reconnects, when
+    /// it stays away for too long, and when their WeChat session ends.
+    fn start_connection_notices(&self) {
+        let bridge = self.clone();
+        let mut status_rx = self.wechat_service.subscribe_agent_status();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CONNECTION_CHECK_INTERVAL);
+            interval.tick().await;
+            loop {
+                let notices = tokio::select! {
+                    _ = bridge.shutdown.cancelled() => break,
+                    _ = interval.tick() => {
+                        bridge.connection_notifier.overdue(std::time::Instant::now()).await
+                    }
+                    status = status_rx.recv() => match status {
+                        Ok(status) => bridge.handle_agent_status(status).await.into_iter().collect(),
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(_) => break,
+                    },
+                };
+                for (mxid, notice) in notices {
+                    if let Err(e) = bridge.send_connection_notice(&mxid, &notice).await {
+                        warn!("Failed to notify {} about their connection: {}", mxid, e);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Drops numbered events that were already handled, or are being handled, which agents
+    /// replay after reconnecting. The agent is told again that they were handled.
+    async fn is_new_event(&self, event: &Event) -> bool {
+        let Some(seq) = event.seq else {
+            return true;
+        };
+        match self.event_acks.received(&event.mxid, seq).await {
+            Ok(true) => true,
+            Ok(false) => {
+                debug!("Dropping replayed event {} ({}) of {}", seq, event.id, event.mxid);
+                self.resend_event_ack(&event.mxid).await;
+                false
+            }
+            Err(e) => {
+                warn!("Failed to check the sequence number of event {}: {}", event.id, e);
+                true
+            }
+        }
+    }
+
+    async fn ack_event(&self, mxid: &str, seq: u64) {
+        match self.event_acks.handled(mxid, seq).await {
+            Ok(Some(acked)) => self.wechat_service.ack_events(mxid, acked).await,
+            Ok(None) => {}
+            Err(e) => warn!("Failed to record that event {} of {} was handled: {}", seq, mxid, e),
+        }
+    }
+
+    /// Tells a user's agent up to which event it doesn't need to replay.
+    async fn resend_event_ack(&self, mxid: &str) {
+        match self.event_acks.acked(mxid).await {
+            Ok(0) => {}
+            Ok(acked) => self.wechat_service.ack_events(mxid, acked).await,
+            Err(e) => warn!("Failed to load the handled events of {}: {}", mxid, e),
+        }
+    }
+
+    async fn handle_agent_status(&self, status: AgentStatus) -> Option<(String, ConnectionNotice)> {
+        match status {
+            AgentStatus::Connected(mxid) => {
+                self.resend_event_ack(&mxid).await;
+                let notice = self.connection_notifier.on_connected(&mxid).await?;
+                Some((mxid, notice))
+            }
+            AgentStatus::Disconnected(mxid) => {
+                let notice = self.connection_notifier.on_disconnected(&mxid).await?;
+                Some((mxid, notice))
+            }
+            AgentStatus::LoggedOut { mxid, reason } => {
+                info!("WeChat session of {} ended: {}", mxid, reason.as_deref().unwrap_or("no reason given"));
+                if let Err(e) = self.mark_logged_out(&mxid).await {
+                    warn!("Failed to log out {}: {}", mxid, e);
+                }
+                Some((mxid, ConnectionNotice::LoggedOut(reason)))
+            }
+        }
+    }
+
+    /// Periodically checks that the WeChat sessions of logged-in users are alive.
+    fn start_session_supervisor(&self) {
+        let Some(interval) = self.config.bridge.session_check_interval_duration() else {
+            return;
+        };
+
+        let bridge = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                tokio::select! {
+                    _ = bridge.shutdown.cancelled() => break,
+                    _ = ticker.tick() => {}
+                }
+                if let Err(e) = bridge.check_sessions().await {
+                    error!("Failed to check WeChat sessions: {}", e);
+                }
+            }
+        });
+    }
+
+    async fn check_sessions(&self) -> anyhow::Result<()> {
+        for user in self.db.get_all_logged_in_users().await? {
+            let client = self.get_client(&user.mxid);
+            let logged_in = match client.is_logged_in().await {
+                Ok(logged_in) => logged_in,
+                Err(e) => {
+                    // Agent outages are reported by the connection notices.
+                    debug!("Couldn't check the WeChat session of {}: {}", user.mxid, e);
+                    continue;
+                }
+            };
+            if logged_in {
+                if self.session_supervisor.on_session_ok(&user.mxid).await {
+                    info!("WeChat session of {} is alive again", user.mxid);
+                }
+                continue;
+            }
+
+            match self.session_supervisor.on_session_lost(&user.mxid, std::time::Instant::now()).await {
+                SessionAction::Wait => {}
+                SessionAction::Relogin => {
+                    info!("WeChat session of {} dropped, asking the agent to restore it", user.mxid);
+                    let restored = match client.connect().await {
+                        Ok(()) => client.is_logged_in().await.unwrap_or(false),
+                        Err(e) => {
+                            warn!("Failed to restore the WeChat session of {}: {}", user.mxid, e);
+                            false
+                        }
+                    };
+                    if restored {
+                        self.session_supervisor.on_session_ok(&user.mxid).await;
+                        info!("Restored the WeChat session of {}", user.mxid);
+                    }
+                }
+                SessionAction::Escalate => {
+                    if let Err(e) = self.request_relogin(&user.mxid).await {
+                        warn!("Failed to ask {} to log in again: {}", user.mxid, e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Logs the user out after their session couldn't be restored and sends them a login QR code.
+    async fn request_relogin(&self, mxid: &str) -> anyhow::Result<()> {
+        warn!("Couldn't restore the WeChat session of {}, they need to log in again", mxid);
+        self.mark_logged_out(mxid).await?;
+        if self.config.bridge.disable_bridge_alerts {
+            return Ok(());
+        }
+
+        let Some(room_id) = self.db.get_user_by_mxid(mxid).await?.and_then(|u| u.management_room) else {
+            return Ok(());
+        };
+        let matrix_client = self.get_matrix_client();
+        matrix_client.send_notice(
+            &room_id,
+            "Your WeChat session ended and couldn't be restored automatically. Scan the QR code below to log in again.",
+        ).await?;
+
+        let mut user = self.get_user_by_mxid(mxid).await?.as_ref().clone();
+        let wechat = self.get_client(mxid);
+        tokio::spawn(async move {
+            if let Err(e) = user.login_with_qr(wechat, &matrix_client, &room_id).await {
+                warn!("QR login for {} failed: {}", user.mxid, e);
+                let _ = matrix_client.send_notice(&room_id, format!("Login failed: {}", e)).await;
+            }
+        });
+        Ok(())
+    }
+
+    async fn mark_logged_out(&self, mxid: &str) -> anyhow::Result<()> {
+        let Some(mut user) = self.db.get_user_by_mxid(mxid).await? else {
+            return Ok(());
+        };
+        if user.uin.take().is_some() {
+            self.db.update_user(&user).await?;
+        }
+        self.invalidate_user(mxid).await;
+        Ok(())
+    }
+
+    async fn send_connection_notice(&self, mxid: &str, notice: &ConnectionNotice) -> anyhow::Result<()> {
+        if self.config.bridge.disable_bridge_alerts {
+            return Ok(());
+        }
+        let Some(room_id) = self.db.get_user_by_mxid(mxid).await?.and_then(|u| u.management_room) else {
+            return Ok(());
+        };
+        self.get_matrix_client().send_notice(&room_id, notice.text()).await?;
+        Ok(())
+    }
+
+    /// Handles transactions queued in async transaction mode in the order they arrived. The
+    /// queue is drained before the task exits on shutdown.
+    fn start_transaction_queue(&self) {
+        if !self.config.appservice.async_transactions {
+            return;
+        }
+
+        let bridge = self.clone();
+        self.tasks.spawn(async move {
+            loop {
+                let txn = tokio::select! {
+                    biased;
+                    txn = bridge.transaction_queue.wait_for_message() => txn,
+                    _ = bridge.shutdown.cancelled() => break,
+                };
+                if let Err(e) = bridge.process_transaction(&txn.id, txn.data).await {
+                    warn!("Error handling transaction {}: {}", txn.id, e);
+                }
+            }
+        });
+    }
+
+    async fn process_transaction(&self, txn_id: &str, events: Vec<RoomEvent>) -> anyhow::Result<()> {
+        let handler = crate::matrix::event_handler::MatrixEventHandler::new(Arc::new(self.clone()));
+        for event in events {
+            if let Err(e) = handler.handle_event(&event).await {
+                warn!("Error handling event: {}", e);
+            }
+        }
+        if !txn_id.is_empty() {
+            self.transactions.mark_processed(txn_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn retry_outgoing_message(&self, event_id: &str) -> anyhow::Result<()> {
+        // The row is gone if the message was redacted in the meantime.
+        let Some(retry) = self.db.get_outgoing_retry(event_id).await? else {
+            return Ok(());
+        };
+        let event: RoomEvent = serde_json::from_str(&retry.event)?;
+
+        debug!("Retrying to send {} to WeChat", event_id);
+        let handler = crate::matrix::event_handler::MatrixEventHandler::new(Arc::new(self.clone()));
+        handler.retry_message_event(&event).await
+    }
+
+    async fn sync_all_group_members(&self) -> anyhow::Result<()> {
+        let portals = self.db.get_all_portals_with_mxid().await?;
+        for portal in portals {
+            if !BridgePortal::from_db(portal.clone(), self.db.clone()).is_group() {
+                continue;
+            }
+            let uid = portal.uid.clone();
+            if let Err(e) = self.sync_group_members(portal).await {
+                warn!("Failed to sync members of group {}: {}", uid, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn sync_all_group_info(&self) -> anyhow::Result<()> {
+        let portals = self.db.get_all_portals_with_mxid().await?;
+        for portal in portals {
+            if !BridgePortal::from_db(portal.clone(), self.db.clone()).is_group() {
+                continue;
+            }
+            let uid = portal.uid.clone();
+            if let Err(e) = self.sync_group_info(portal).await {
+                warn!("Failed to sync info of group {}: {}", uid, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a WeChat group's current name, avatar and announcement to its portal room. The
+    /// announcement is used as the room topic.
+    pub async fn sync_group_info(&self, db_portal: DbPortal) -> anyhow::Result<()> {
+        let Some(room_id) = db_portal.mxid.clone() else {
+            return Ok(());
+        };
+
+        let user_mxid = self.db.get_user_by_uin(&db_portal.receiver).await?
+            .map(|u| u.mxid)
+            .unwrap_or_default();
+        let info = self.get_client(&user_mxid).get_group_info(&db_portal.uid).await?;
+        let client = self.get_matrix_client();
+
+        let key = db_portal.key();
+        let mut portal = BridgePortal::from_db(db_portal, self.db.clone());
+        // Only upload the avatar again when WeChat reports a different one.
+        let avatar = info.avatar.as_deref().unwrap_or_default();
+        let avatar_url = if avatar.is_empty() || (portal.inner.avatar_set && avatar == portal.inner.avatar) {
+            None
+        } else {
+            match self.upload_remote_image(&client, avatar).await {
+                Ok(url) => {
+                    portal.inner.avatar = avatar.to_string();
+                    Some(url)
+                }
+                Err(e) => {
+                    warn!("Failed to bridge avatar of group {}: {}", key.uid, e);
+                    None
+                }
+            }
+        };
+
+        // The portal's owner may have named the room themselves.
+        let name = self.db.get_portal_setting(&key, &user_mxid).await?
+            .and_then(|s| s.room_name)
+            .unwrap_or(info.name);
+        portal.update_matrix_room(&client, Some(&name), info.notice.as_deref(), avatar_url.as_deref()).await?;
+        // The cached portal still holds the old metadata and would write it back on shutdown.
+        self.invalidate_portal(&key, &room_id).await;
+        debug!("Synced info of group {} into {}", key.uid, room_id);
+        Ok(())
+    }
+
+    /// Mirrors the members of a WeChat group into its portal room.
+    pub async fn sync_group_members(&self, portal: DbPortal) -> anyhow::Result<()> {
+        if portal.mxid.is_none() {
+            return Ok(());
+        }
+
+        let user = self.db.get_user_by_uin(&portal.receiver).await?
+            .ok_or_else(|| anyhow::anyhow!("no user is logged in as {}", portal.receiver))?;
+        let members = self.get_client(&user.mxid).get_group_members(&portal.uid).await?;
+        let names: Vec<&str> = members.iter()
+            .map(|m| m.nickname.as_deref().filter(|n| !n.is_empty()).unwrap_or(&m.name))
+            .collect();
+
+        let infos: Vec<UserInfo> = members.iter()
+            .map(|m| UserInfo {
+                id: m.id.clone(),
+                name: m.name.clone(),
+                avatar: m.avatar.clone(),
+                remark: None,
+            })
+            .collect();
+        let profile_syncs = infos.iter()
+            .map(|info| self.sync_puppet_profile(&user.mxid, &info.id, Some(info)));
+        let results = if self.config.bridge.parallel_member_sync {
+            futures_util::future::join_all(profile_syncs).await
+        } else {
+            let mut results = Vec::with_capacity(members.len());
+            for sync in profile_syncs {
+                results.push(sync.await);
+            }
+            results
+        };
+        for (member, result) in members.iter().zip(results) {
+            if let Err(e) = result {
+                warn!("Failed to sync profile of {}: {}", member.id, e);
+            }
+        }
+
+        let puppet_mxids: Vec<String> = members.iter().map(|m| self.puppet_mxid(&m.id)).collect();
+        let participants: Vec<(&str, &str, Option<&str>)> = members.iter()
+            .zip(&puppet_mxids)
+            .zip(&names)
+            .map(|((m, mxid), name)| (m.id.as_str(), mxid.as_str(), Some(*name)))
+            .collect();
+
+        let mut portal = BridgePortal::from_db(portal, self.db.clone());
+        let puppet_prefix = format!("@{}", self.config.bridge.user_prefix);
+        portal.sync_participants(&self.get_matrix_client(), &self.room_state, &puppet_prefix, &participants).await?;
+
+        debug!("Synced {} members of group {}", members.len(), portal.key.uid);
+        Ok(())
+    }
+
+    /// Syncs a puppet's Matrix profile, fetching the contact info from WeChat if not given with
+    /// the agent of `user_mxid`, a logged-in user who has the contact.
+    pub async fn sync_puppet_profile(&self, user_mxid: &str, uin: &str, info: Option<&UserInfo>) -> anyhow::Result<()> {
+        if self.config.bridge.no_puppet.enabled {
+            return Ok(());
+        }
+
+        let puppet = self.get_puppet_by_uin(uin).await?;
+        let mut puppet = Arc::try_unwrap(puppet).unwrap_or_else(|p| (*p).clone());
+        let intent = self.intent(&self.puppet_mxid(uin)).await;
+        match info {
+            Some(info) => puppet.update_profile(&intent, &self.config.bridge, info).await?,
+            None => puppet.sync_profile(self.get_client(user_mxid).as_ref(), &intent, &self.config.bridge).await?,
+        }
+
+        self.puppets_by_uin.write().await.insert(uin.to_string(), Arc::new(puppet));
+        Ok(())
+    }
+
+    /// Refreshes the sender's puppet profile in the background if it was never or long ago synced,
+    /// asking the agent of `user_mxid`, who received a message from them.
+    async fn ensure_puppet_profile(&self, user_mxid: &str, uin: &str) -> anyhow::Result<()> {
+        if self.config.bridge.no_puppet.enabled {
+            return Ok(());
+        }
+
+        let puppet = self.get_puppet_by_uin(uin).await?;
+        let now = chrono::Utc::now().timestamp();
+        if now - puppet.inner.last_sync < PUPPET_PROFILE_SYNC_INTERVAL_SECS {
+            return Ok(());
+        }
+
+        let bridge = self.clone();
+        let user_mxid = user_mxid.to_string();
+        let uin = uin.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = bridge.sync_puppet_profile(&user_mxid, &uin, None).await {
+                warn!("Failed to sync profile of {}: {}", uin, e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Returns the user's personal space, creating it if needed.
+    pub async fn ensure_space(&self, user_mxid: &str) -> anyhow::Result<String> {
+        let mut user = self.db.get_user_by_mxid(user_mxid).await?
+            .ok_or_else(|| anyhow::anyhow!("user {} not found", user_mxid))?;
+        if let Some(space) = &user.space_room {
+            return Ok(space.clone());
+        }
+
+        let bot_mxid = self.config.appservice.bot.mxid(&self.config.homeserver.domain);
+        let mut power_levels = crate::matrix::types::PowerLevelsContent::default();
+        power_levels.users.insert(bot_mxid, 100);
+        power_levels.users.insert(user_mxid.to_string(), 50);
+        let mut request = crate::matrix::types::CreateRoomRequest::space("WeChat")
+            .with_invite(user_mxid);
+        request.topic = Some("Your WeChat bridged chats".to_string());
+        request.power_level_content_override = Some(power_levels);
+
+        let space = self.get_matrix_client().create_room(&request).await?;
+        user.space_room = Some(space.clone());
+        self.db.update_user(&user).await?;
+        self.invalidate_user(user_mxid).await;
+
+        info!("Created space {} for {}", space, user_mxid);
+        Ok(space)
+    }
+
+    /// Adds every portal the user is in to their space, returning how many were added.
+    pub async fn sync_space(&self, user_mxid: &str) -> anyhow::Result<usize> {
+        let space = self.ensure_space(user_mxid).await?;
+        let client = self.get_matrix_client();
+
+        let mut count = 0;
+        for portal in self.db.get_all_portals_with_mxid().await? {
+            let Some(room_id) = portal.mxid else {
+                continue;
+            };
+            let members = match self.room_state.joined_members(&client, &room_id).await {
+                Ok(members) => members,
+                Err(e) => {
+                    warn!("Failed to get members of {}: {}", room_id, e);
+                    continue;
+                }
+            };
+            if !members.joined.contains_key(user_mxid) {
+                continue;
+            }
+            self.add_portal_to_space(&space, &room_id).await?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    pub async fn add_portal_to_space(&self, space: &str, room_id: &str) -> anyhow::Result<()> {
+        let client = self.get_matrix_client();
+        let via = serde_json::json!([self.config.homeserver.domain]);
+        client.send_state(space, "m.space.child", room_id, &serde_json::json!({ "via": via })).await?;
+        client.send_state(
+            room_id,
+            "m.space.parent",
+            space,
+            &serde_json::json!({ "via": via, "canonical": true }),
+        ).await?;
+        debug!("Added {} to space {}", room_id, space);
+        Ok(())
+    }
+
+    pub async fn remove_portal_from_space(&self, space: &str, room_id: &str) -> anyhow::Result<()> {
+        let client = self.get_matrix_client();
+        // Space relations are removed by replacing them with empty content.
+        let empty = serde_json::json!({});
+        client.send_state(space, "m.space.child", room_id, &empty).await?;
+        client.send_state(room_id, "m.space.parent", space, &empty).await?;
+        debug!("Removed {} from space {}", room_id, space);
+        Ok(())
+    }
+
+    /// Removes a portal from the spaces of all users, e.g. before it is deleted.
+    pub async fn remove_portal_from_spaces(&self, room_id: &str) -> anyhow::Result<()> {
+        for user in self.db.get_all_logged_in_users().await? {
+            if let Some(space) = &user.space_room
+                && let Err(e) = self.remove_portal_from_space(space, room_id).await
+            {
+                warn!("Failed to remove {} from space {}: {}", room_id, space, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds or creates the private chat portal with a WeChat contact and invites the user to it.
+    pub async fn start_private_chat(&self, user_mxid: &str, wxid: &str) -> anyhow::Result<(String, bool)> {
+        let uin = self.db.get_user_by_mxid(user_mxid).await?
+            .and_then(|u| u.uin)
+            .ok_or_else(|| anyhow::anyhow!("{} is not logged in to WeChat", user_mxid))?;
+        let key = PortalKey::new(wxid, uin);
+        let portal = self.get_portal_by_key(&key).await?;
+        let client = self.get_matrix_client();
+
+        if let Some(room_id) = portal.inner.mxid.clone() {
+            if let Err(e) = client.invite_user(&room_id, user_mxid).await {
+                debug!("Failed to invite {} to {}: {}", user_mxid, room_id, e);
+            }
+            return Ok((room_id, false));
+        }
+
+        let info = self.get_client(user_mxid).get_user_info(wxid).await?;
+        if let Err(e) = self.sync_puppet_profile(user_mxid, wxid, Some(&info)).await {
+            warn!("Failed to sync profile of {}: {}", wxid, e);
+        }
+
+        let puppet_mxid = self.puppet_mxid(wxid);
+        let mut portal = Arc::try_unwrap(portal).unwrap_or_else(|p| (*p).clone());
+        let room_id = portal.create_matrix_room(
+            &client,
+            &self.config.appservice.bot.mxid(&self.config.homeserver.domain),
+            self.room_puppet(&puppet_mxid),
+            Some(&info.name),
+            None,
+            true,
+            self.config.bridge.encryption.default,
+        ).await?;
+        client.invite_user(&room_id, user_mxid).await?;
+
+        let portal = Arc::new(portal);
+        self.portals_by_key.write().await.insert(key, portal.clone());
+        self.portals_by_mxid.write().await.insert(room_id.clone(), portal);
+        info!("Started private chat {} with {} for {}", room_id, wxid, user_mxid);
+        Ok((room_id, true))
+    }
+
+    /// Answers a homeserver query for an alias in the bridge's namespace, creating the room of a
+    /// group portal on demand. Returns whether the alias exists now.
+    pub async fn handle_alias_query(&self, alias: &str) -> anyhow::Result<bool> {
+        let Some(uid) = self.config.parse_alias(alias) else {
+            return Ok(false);
+        };
+        let client = self.get_matrix_client();
+        let portals = self.db.get_portals_by_uid(&uid).await?;
+        if let Some(room_id) = portals.iter().find_map(|p| p.mxid.clone()) {
+            client.set_room_alias(&room_id, alias).await?;
+            return Ok(true);
+        }
+
+        // Private chat portals belong to a single user, so only groups are created on demand.
+        let Some(db_portal) = portals.into_iter().find(|p| p.uid.starts_with("@@")) else {
+            return Ok(false);
+        };
+        let Some(user) = self.db.get_user_by_uin(&db_portal.receiver).await? else {
+            return Ok(false);
+        };
+        let room_id = self.open_group_portal(&user.mxid, &db_portal.key()).await?;
+        info!("Created portal {} for {} on alias query", room_id, alias);
+        Ok(true)
+    }
+
+    /// Looks a WeChat user up for a third-party user query, asking the agents of the logged-in
+    /// users in turn, and returns their ghost's MXID with their info.
+    pub async fn lookup_remote_user(&self, wxid: &str) -> anyhow::Result<Option<(String, UserInfo)>> {
+        for user in self.db.get_all_logged_in_users().await? {
+            match self.get_client(&user.mxid).get_user_info(wxid).await {
+                Ok(info) if !info.id.is_empty() => {
+                    if let Err(e) = self.sync_puppet_profile(&user.mxid, wxid, Some(&info)).await {
+                        warn!("Failed to sync profile of {}: {}", wxid, e);
+                    }
+                    return Ok(Some((self.puppet_mxid(wxid), info)));
+                }
+                Ok(_) => {}
+                Err(e) => debug!("{} couldn't look up {}: {}", user.mxid, wxid, e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Resolves a WeChat chat for a third-party location query to the alias of its portal.
+    /// Groups a logged-in user is in get a portal whose room is created when the alias is
+    /// joined, see [`Self::handle_alias_query`].
+    pub async fn lookup_remote_location(&self, chat_id: &str) -> anyhow::Result<Option<String>> {
+        let alias = self.config.format_alias(chat_id);
+        if self.db.get_portals_by_uid(chat_id).await?.iter().any(|p| p.mxid.is_some()) {
+            return Ok(Some(alias));
+        }
+        if !chat_id.starts_with("@@") {
+            return Ok(None);
+        }
+        for user in self.db.get_all_logged_in_users().await? {
+            let Some(uin) = user.uin.clone() else {
+                continue;
+            };
+            match self.get_client(&user.mxid).get_group_info(chat_id).await {
+                Ok(_) => {
+                    self.get_portal_by_key(&PortalKey::new(chat_id, &uin)).await?;
+                    return Ok(Some(alias));
+                }
+                Err(e) => debug!("{} couldn't look up group {}: {}", user.mxid, chat_id, e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the room of a group portal, creating it and syncing its members if it has none,
+    /// and invites the Matrix user to it.
+    pub async fn open_group_portal(&self, user_mxid: &str, key: &PortalKey) -> anyhow::Result<String> {
+        let portal = self.get_portal_by_key(key).await?;
+        let client = self.get_matrix_client();
+        if let Some(room_id) = portal.inner.mxid.clone() {
+            if let Err(e) = client.invite_user(&room_id, user_mxid).await {
+                debug!("Failed to invite {} to {}: {}", user_mxid, room_id, e);
+            }
+            return Ok(room_id);
+        }
+
+        let info = self.get_client(user_mxid).get_group_info(&key.uid).await?;
+        let mut portal = Arc::try_unwrap(portal).unwrap_or_else(|p| (*p).clone());
+        let room_id = portal.create_matrix_room(
+            &client,
+            &self.config.appservice.bot.mxid(&self.config.homeserver.domain),
+            None,
+            Some(&info.name),
+            None,
+            false,
+            self.config.bridge.encryption.default,
+        ).await?;
+        if let Err(e) = client.invite_user(&room_id, user_mxid).await {
+            debug!("Failed to invite {} to {}: {}", user_mxid, room_id, e);
+        }
+
+        let db_portal = portal.inner.clone();
+        let portal = Arc::new(portal);
+        self.portals_by_key.write().await.insert(key.clone(), portal.clone());
+        self.portals_by_mxid.write().await.insert(room_id.clone(), portal);
+        if let Err(e) = self.sync_group_members(db_portal).await {
+            warn!("Failed to sync members of group {}: {}", key.uid, e);
+        }
+        Ok(room_id)
+    }
+
+    /// Links an existing Matrix room to a WeChat group, then syncs the group's metadata and members.
+    pub async fn bridge_group_to_room(
+        &self,
+        user_mxid: &str,
+        uin: &str,
+        group_id: &str,
+        room_id: &str,
+    ) -> anyhow::Result<GroupInfo> {
+        let info = self.get_client(user_mxid).get_group_info(group_id).await?;
+        let client = self.get_matrix_client();
+
+        let key = PortalKey::new(group_id, uin);
+        let db_portal = match self.db.get_portal_by_key(&key).await? {
+            Some(mut portal) => {
+                portal.mxid = Some(room_id.to_string());
+                self.db.update_portal(&portal).await?;
+                portal
+            }
+            None => {
+                let portal = DbPortal {
+                    uid: group_id.to_string(),
+                    receiver: uin.to_string(),
+                    mxid: Some(room_id.to_string()),
+                    name: String::new(),
+                    name_set: false,
+                    topic: String::new(),
+                    topic_set: false,
+                    avatar: String::new(),
+                    avatar_url: None,
+                    avatar_set: false,
+                    encrypted: self.room_state.is_encrypted(&client, room_id).await.unwrap_or(false),
+                    last_sync: 0,
+                    first_event_id: None,
+                    next_batch_id: None,
+                };
+                self.db.insert_portal(&portal).await?;
+                portal
+            }
+        };
+        self.invalidate_portal(&key, room_id).await;
+
+        let avatar_url = match info.avatar.as_deref().filter(|a| !a.is_empty()) {
+            Some(avatar) => match self.upload_remote_image(&client, avatar).await {
+                Ok(url) => Some(url),
+                Err(e) => {
+                    warn!("Failed to bridge avatar of group {}: {}", group_id, e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let mut portal = BridgePortal::from_db(db_portal, self.db.clone());
+        let bot_mxid = self.config.appservice.bot.mxid(&self.config.homeserver.domain);
+        client.send_state(
+            room_id,
+            "m.room.bridge",
+            &portal.bridge_info_state_key(),
+            &portal.bridge_info(&bot_mxid, &info.name, avatar_url.as_deref().unwrap_or("")),
+        ).await?;
+        portal.update_matrix_room(&client, Some(&info.name), info.notice.as_deref(), avatar_url.as_deref()).await?;
+
+        self.sync_group_members(portal.inner.clone()).await?;
+        info!("Bridged room {} to WeChat group {}", room_id, group_id);
+        Ok(info)
+    }
+
+    /// Disconnects a portal room from its WeChat chat, kicking the puppets but keeping the room.
+    pub async fn unbridge_room(&self, room_id: &str) -> anyhow::Result<()> {
+        let Some(db_portal) = self.db.get_portal_by_mxid(room_id).await? else {
+            anyhow::bail!("{} is not a portal", room_id);
+        };
+        let key = PortalKey::new(db_portal.uid.clone(), db_portal.receiver.clone());
+        let portal = BridgePortal::from_db(db_portal, self.db.clone());
+        let client = self.get_matrix_client();
+
+        self.remove_portal_from_spaces(room_id).await?;
+        client.send_state(room_id, "m.room.bridge", &portal.bridge_info_state_key(), &serde_json::json!({})).await?;
+
+        let puppet_prefix = format!("@{}", self.config.bridge.user_prefix);
+        let members = self.room_state.joined_members(&client, room_id).await?;
+        for mxid in members.joined.keys().filter(|m| m.starts_with(&puppet_prefix)) {
+            if let Err(e) = client.kick_user(room_id, mxid, Some("Room unbridged")).await {
+                warn!("Failed to kick {} from {}: {}", mxid, room_id, e);
+            }
+        }
+
+        portal.delete().await?;
+        self.invalidate_portal(&key, room_id).await;
+        self.room_state.invalidate(room_id).await;
+        info!("Unbridged room {} from WeChat chat {}", room_id, key.uid);
+        Ok(())
+    }
+
+    /// Moves a portal to the room that replaced its room, e.g. in a room upgrade: the bot and the
+    /// ghosts join the new room, and the alias and space entries follow it.
+    pub async fn continue_portal(&self, old_room_id: &str, new_room_id: &str) -> anyhow::Result<()> {
+        let portal = self.get_portal_by_mxid(old_room_id).await?
+            .ok_or_else(|| anyhow::anyhow!("{} is not a portal", old_room_id))?;
+        if self.get_portal_by_mxid(new_room_id).await?.is_some() {
+            anyhow::bail!("{} is already a portal", new_room_id);
+        }
+        let client = self.get_matrix_client();
+        client.join_room(new_room_id).await?;
+
+        let puppet_prefix = format!("@{}", self.config.bridge.user_prefix);
+        let ghosts: Vec<String> = match self.room_state.joined_members(&client, old_room_id).await {
+            Ok(members) => members.joined.into_keys().filter(|m| m.starts_with(&puppet_prefix)).collect(),
+            Err(e) => {
+                warn!("Failed to get members of {}: {}", old_room_id, e);
+                Vec::new()
+            }
+        };
+
+        let key = portal.key.clone();
+        let mut portal = Arc::try_unwrap(portal).unwrap_or_else(|p| (*p).clone());
+        portal.set_mxid(new_room_id).await?;
+        self.invalidate_portal(&key, old_room_id).await;
+        self.room_state.invalidate(old_room_id).await;
+        let portal = Arc::new(portal);
+        self.portals_by_key.write().await.insert(key.clone(), portal.clone());
+        self.portals_by_mxid.write().await.insert(new_room_id.to_string(), portal);
+
+        for ghost in &ghosts {
+            if let Err(e) = self.intent(ghost).await.ensure_joined(new_room_id).await {
+                debug!("Failed to make {} join {}: {}", ghost, new_room_id, e);
+            }
+        }
+
+        // The homeserver may have moved the alias already, but not across servers.
+        let alias = self.config.format_alias(&key.uid);
+        if let Err(e) = client.delete_room_alias(&alias).await {
+            debug!("Failed to remove alias {}: {}", alias, e);
+        }
+        if let Err(e) = client.set_room_alias(new_room_id, &alias).await {
+            warn!("Failed to move alias {} to {}: {}", alias, new_room_id, e);
+        }
+
+        self.remove_portal_from_spaces(old_room_id).await?;
+        if let Some(user) = self.db.get_user_by_uin(&key.receiver).await?
+            && let Some(space) = &user.space_room
+            && let Err(e) = self.add_portal_to_space(space, new_room_id).await
+        {
+            warn!("Failed to add {} to space {}: {}", new_room_id, space, e);
+        }
+
+        info!("Moved portal of {} from {} to {} with {} ghosts", key.uid, old_room_id, new_room_id, ghosts.len());
+        Ok(())
+    }
+
+    async fn invalidate_portal(&self, key: &PortalKey, room_id: &str) {
+        self.portals_by_key.write().await.remove(key);
+        self.portals_by_mxid.write().await.remove(room_id);
+    }
+
+    async fn upload_remote_image(
+        &self,
+        client: &crate::matrix::client::MatrixClient,
+        url: &str,
+    ) -> anyhow::Result<String> {
+        let data = reqwest::get(url).await?.error_for_status()?.bytes().await?;
+        let mimetype = crate::util::media::image::detect_format(&data).mimetype();
+        self.media_cache.upload(client, &data, mimetype, "avatar").await
+    }
+
+    async fn download(&self, url: &str) -> anyhow::Result<Vec<u8>> {
+        let response = self.http.get(url).send().await?.error_for_status()?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    /// Drops the cached user so the next lookup sees login state changed outside of it.
+    pub async fn invalidate_user(&self, mxid: &str) {
+        self.users_by_mxid.write().await.remove(mxid);
+    }
+
+    /// Whether a Matrix account still exists, going by its profile. Deactivated accounts have
+    /// none. Errors other than the profile not being found are returned, as they say nothing
+    /// about the account.
+    pub async fn matrix_user_exists(&self, mxid: &str) -> anyhow::Result<bool> {
+        match self.get_matrix_client().get_profile(mxid).await {
+            Ok(_) => Ok(true),
+            Err(e) if crate::matrix::client::matrix_errcode(&e) == Some("M_NOT_FOUND") => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Cleans up after every user whose Matrix account no longer exists, returning their IDs.
+    pub async fn clean_gone_users(&self) -> anyhow::Result<Vec<String>> {
+        let mut cleaned = Vec::new();
+        for user in self.db.get_all_users().await? {
+            match self.matrix_user_exists(&user.mxid).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.clean_up_user(&user.mxid).await?;
+                    cleaned.push(user.mxid);
+                }
+                Err(e) => warn!("Failed to check whether {} still exists: {}", user.mxid, e),
+            }
+        }
+        Ok(cleaned)
+    }
+
+    /// Removes what the bridge keeps for a user whose Matrix account is gone: logs them out of
+    /// WeChat, tears down their portals, stops double puppeting as them and forgets the user.
+    pub async fn clean_up_user(&self, mxid: &str) -> anyhow::Result<()> {
+        let Some(db_user) = self.db.get_user_by_mxid(mxid).await? else {
+            return Ok(());
+        };
+
+        if let Some(uin) = &db_user.uin {
+            let client = self.get_matrix_client();
+            for portal in self.db.get_portals_by_receiver(uin).await? {
+                if let Some(room_id) = &portal.mxid
+                    && let Some(space) = &db_user.space_room
+                    && let Err(e) = self.remove_portal_from_space(space, room_id).await
+                {
+                    debug!("Failed to remove {} from the space of {}: {}", room_id, mxid, e);
+                }
+                let mut portal = BridgePortal::from_db(portal, self.db.clone());
+                if let Err(e) = portal.cleanup(&client).await {
+                    warn!("Failed to clean up portal {}: {}", portal.key.uid, e);
+                }
+                portal.delete().await?;
+            }
+
+            let user = self.get_user_by_mxid(mxid).await?;
+            user.as_ref().clone().logout().await?;
+        }
+
+        if let Some(puppet) = self.db.get_puppet_by_custom_mxid(mxid).await? {
+            let puppet = self.get_puppet_by_uin(&puppet.uin).await?;
+            let mut puppet = Arc::try_unwrap(puppet).unwrap_or_else(|p| (*p).clone());
+            puppet.clear_custom_mxid().await?;
+            self.puppets_by_uin.write().await.insert(puppet.uin().to_string(), Arc::new(puppet));
         }
+
+        self.invalidate_user(mxid).await;
+        self.db.delete_user(mxid).await?;
+        info!("Cleaned up after deactivated Matrix user {}", mxid);
+        Ok(())
     }
 
+    /// Stops background tasks, waits for messages that are being bridged, closes the agent
+    /// connections and writes cached portals and puppets back to the database. Messages still
+    /// queued for retry stay in the database and are picked up on the next start.
     pub async fn stop(&self) {
         info!("Stopping WeChat bridge");
+        self.shutdown.cancel();
+
+        self.tasks.close();
+        if tokio::time::timeout(SHUTDOWN_DRAIN_TIMEOUT, self.tasks.wait()).await.is_err() {
+            warn!("Timed out waiting for {} in-flight messages", self.tasks.len());
+        }
+
+        self.wechat_service.stop().await;
+        self.persist_caches().await;
+    }
+
+    async fn persist_caches(&self) {
+        let portals: Vec<_> = self.portals_by_key.read().await.values().cloned().collect();
+        for portal in portals {
+            if let Err(e) = portal.save().await {
+                warn!("Failed to save portal {}: {}", portal.key.uid, e);
+            }
+        }
+        let puppets: Vec<_> = self.puppets_by_uin.read().await.values().cloned().collect();
+        for puppet in puppets {
+            if let Err(e) = puppet.save().await {
+                warn!("Failed to save puppet {}: {}", puppet.inner.uin, e);
+            }
+        }
     }
 
     pub async fn get_user_by_mxid(&self, mxid: &str) -> anyhow::Result<Arc<BridgeUser>> {
@@ -115,7 +1915,13 @@ impl WechatBridge {
         
         let db_user = self.db.get_user_by_mxid(mxid).await?;
         let user = if let Some(db_user) = db_user {
-            BridgeUser::from_db(db_user, self.db.clone())
+            let logged_in = db_user.uin.is_some();
+            let mut user = BridgeUser::from_db(db_user, self.db.clone());
+            // Users loaded after a login or a restart talk to WeChat through their backend.
+            if logged_in {
+                user.set_client(self.get_client(mxid));
+            }
+            user
         } else {
             let new_user = DbUser::new(mxid);
             self.db.insert_user(&new_user).await?;
@@ -163,7 +1969,7 @@ impl WechatBridge {
             BridgePortal::from_db(new_portal, self.db.clone())
         };
         
-        let portal = Arc::new(portal);
+        let portal = Arc::new(portal.with_alias(self.config.format_alias(&key.uid)));
         {
             let mut portals = self.portals_by_key.write().await;
             portals.insert(key.clone(), portal.clone());
@@ -218,15 +2024,232 @@ impl WechatBridge {
         Ok(puppet)
     }
 
-    pub fn get_client(&self, mxid: &str) -> WechatClient {
-        WechatClient::new(mxid.to_string(), self.wechat_service.clone())
+    pub fn get_client(&self, mxid: &str) -> Arc<dyn WechatBackend> {
+        (self.backends)(mxid)
+    }
+
+    /// The WeChat backend of the user logged in as `uin`, e.g. the receiver of a portal.
+    pub async fn get_client_by_uin(&self, uin: &str) -> anyhow::Result<Arc<dyn WechatBackend>> {
+        let user = self.db.get_user_by_uin(uin).await?
+            .ok_or_else(|| anyhow::anyhow!("no user is logged in as {}", uin))?;
+        Ok(self.get_client(&user.mxid))
+    }
+
+    /// Uses another WeChat backend than the agent websocket, e.g. another agent protocol.
+    pub fn with_backends(mut self, backends: BackendFactory) -> Self {
+        self.backends = backends;
+        self
+    }
+
+    /// Handles a WeChat event kept by the debug log again, returning `false` when it isn't
+    /// kept. Events that were already bridged are still dropped as duplicates.
+    pub async fn redeliver_event(&self, id: &str) -> anyhow::Result<bool> {
+        let Some(event) = self.debug_log.find_event(id).await else {
+            return Ok(false);
+        };
+        info!("Re-delivering WeChat event {} of {}", id, event.mxid);
+        self.tasks.track_future(self.handle_wechat_event(event)).await?;
+        Ok(true)
+    }
+
+    /// How many users, portals and puppets are cached in memory.
+    pub async fn cache_stats(&self) -> (usize, usize, usize) {
+        (
+            self.users_by_mxid.read().await.len(),
+            self.portals_by_key.read().await.len(),
+            self.puppets_by_uin.read().await.len(),
+        )
     }
 
     pub fn get_matrix_client(&self) -> crate::matrix::client::MatrixClient {
         crate::matrix::client::MatrixClient::new(
             &self.config.homeserver.address,
             &self.config.appservice.as_token,
-        ).with_user_id(&self.config.appservice.bot.mxid(&self.config.homeserver.domain))
+        )
+        .with_http_client(self.http.clone())
+        .with_user_id(self.config.appservice.bot.mxid(&self.config.homeserver.domain))
+    }
+
+    /// The intent acting as `mxid`, shared so each user is only registered and joined once.
+    pub async fn intent(&self, mxid: &str) -> Intent {
+        if let Some(intent) = self.intents.read().await.get(mxid) {
+            return intent.clone();
+        }
+        self.intents.write().await
+            .entry(mxid.to_string())
+            .or_insert_with(|| Intent::new(self.get_matrix_client(), mxid).with_room_state(self.room_state.clone()))
+            .clone()
+    }
+
+    /// Whether the user receiving an event muted its portal with `set mute on`. Other users
+    /// with a portal for the same chat still get its messages.
+    async fn is_chat_muted(&self, key: &PortalKey, user_mxid: &str) -> anyhow::Result<bool> {
+        Ok(self.db.get_portal_setting(key, user_mxid).await?.is_some_and(|s| s.muted))
+    }
+
+    /// Sends a WeChat message to Matrix with its row written first, so a send interrupted by a
+    /// restart is found by the startup reconciliation instead of leaving no trace, and a failed
+    /// send keeps its error.
+    async fn record_send(
+        &self,
+        msg: DbMessage,
+        send: impl Future<Output = anyhow::Result<String>>,
+    ) -> anyhow::Result<String> {
+        let key = msg.key();
+        // A row left by an earlier, failed attempt at the same message is replaced.
+        if self.db.get_message_by_id(&key, &msg.msg_id).await?.is_some_and(|m| !m.sent) {
+            self.db.delete_message(&key, &msg.msg_id).await?;
+        }
+        self.db.insert_message(&msg).await?;
+        match send.await {
+            Ok(event_id) => {
+                self.db.complete_message(&key, &msg.msg_id, &msg.msg_id, &event_id, None).await?;
+                Ok(event_id)
+            }
+            Err(e) => {
+                let error = format!("{:#}", e);
+                self.db.complete_message(&key, &msg.msg_id, &msg.msg_id, &msg.mxid, Some(&error)).await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// The intent that sent a bridged message, or the bot if it came from a real Matrix user.
+    async fn message_intent(&self, msg: &DbMessage) -> Intent {
+        let bot_mxid = self.config.appservice.bot.mxid(&self.config.homeserver.domain);
+        if self.is_user_in_namespace(&msg.sender) {
+            self.intent(&msg.sender).await
+        } else {
+            self.intent(&bot_mxid).await
+        }
+    }
+
+    /// Sends a media message before its file is available (MSC2246): an mxc URI is reserved
+    /// and referenced right away, and the file is downloaded from WeChat and uploaded to it in
+    /// the background, so large files don't hold up delivery. Returns `false` without sending
+    /// when the homeserver can't reserve media, so the caller uploads the file first instead.
+    async fn send_async_media(
+        &self,
+        event: &Event,
+        room_id: &str,
+        intent: &Intent,
+        mut content: serde_json::Value,
+        filename: &str,
+        download: impl Future<Output = anyhow::Result<Vec<u8>>> + Send + 'static,
+    ) -> anyhow::Result<bool> {
+        let client = self.get_matrix_client();
+        let mxc_url = match client.create_media().await {
+            Ok(mxc_url) => mxc_url,
+            Err(e) => {
+                warn!("Failed to reserve media for {}, uploading it first: {}", event.id, e);
+                return Ok(false);
+            }
+        };
+        content["url"] = mxc_url.clone().into();
+        self.apply_sender_prefix(event, intent, &mut content);
+        let key = self.event_portal_key(event).await?;
+
+        let msg = pending_message(&key, event, intent);
+        let event_id = self.record_send(msg, intent.send_message(room_id, "m.room.message", &content)).await?;
+        debug!("Bridged media message {} -> {}, uploading its file in the background", event.id, event_id);
+
+        let content_type = content["info"]["mimetype"].as_str().unwrap_or("application/octet-stream").to_string();
+        let (msg_id, filename) = (event.id.clone(), filename.to_string());
+        let (room_id, what) = (room_id.to_string(), event.event_type.to_string());
+        let limit = self.config.bridge.media.incoming_limit();
+        self.tasks.spawn(async move {
+            let uploaded = match download.await {
+                Ok(data) => match check_size(data.len() as u64, limit) {
+                    Ok(()) => client.upload_media_to(&mxc_url, &data, &content_type, &filename).await,
+                    Err(e) => {
+                        let _ = client.send_notice(&room_id, format!("A WeChat {} wasn't bridged: {}.", what, e)).await;
+                        Err(e.into())
+                    }
+                },
+                Err(e) => Err(e),
+            };
+            match uploaded {
+                Ok(()) => debug!("Uploaded the file of media message {}", msg_id),
+                Err(e) => warn!("Failed to upload the file of media message {}: {}", msg_id, e),
+            }
+        });
+        Ok(true)
+    }
+
+    /// Checks WeChat media against `bridge.media.max_incoming_bytes`, telling the room when
+    /// it's skipped. Media of unknown size passes.
+    async fn check_incoming_size(&self, event: &Event, room_id: &str, what: &str, size: Option<u64>) -> Result<(), MediaTooLarge> {
+        let Some(Err(e)) = size.map(|size| check_size(size, self.config.bridge.media.incoming_limit())) else {
+            return Ok(());
+        };
+        info!("Skipping the {} of WeChat message {}: {}", what, event.id, e);
+        let notice = format!("A WeChat {} wasn't bridged: {}.", what, e);
+        if let Err(e) = self.get_matrix_client().send_notice(room_id, notice).await {
+            warn!("Failed to send notice about skipped media to {}: {}", room_id, e);
+        }
+        Err(e)
+    }
+
+    /// Applies the size limit to downloaded media, whose size may not have been known before.
+    async fn check_downloaded(
+        &self,
+        event: &Event,
+        room_id: &str,
+        download: anyhow::Result<Vec<u8>>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let data = download?;
+        self.check_incoming_size(event, room_id, &event.event_type.to_string(), Some(data.len() as u64)).await?;
+        Ok(data)
+    }
+
+    /// The portal a WeChat event belongs in. Group portals are keyed on the group and the account
+    /// receiving the event, like the portals of bridged, created or joined groups.
+    pub async fn event_portal_key(&self, event: &Event) -> anyhow::Result<PortalKey> {
+        let own_uin = self.own_uin(event).await?;
+        if event.chat.chat_type != crate::wechat::ChatType::Private {
+            let receiver = own_uin.unwrap_or_else(|| event.from.id.clone());
+            return Ok(PortalKey::new(event.chat.id.clone(), receiver));
+        }
+        Ok(private_chat_key(&event.chat.id, &event.from.id, own_uin.as_deref()))
+    }
+
+    /// The WeChat ID of the account whose agent sent an event.
+    async fn own_uin(&self, event: &Event) -> anyhow::Result<Option<String>> {
+        if event.mxid.is_empty() {
+            return Ok(None);
+        }
+        Ok(self.db.get_user_by_mxid(&event.mxid).await?.and_then(|u| u.uin))
+    }
+
+    /// The double puppet of a user for the messages they sent from another device, if they set
+    /// one up with `double-puppet`.
+    async fn double_puppet_intent(&self, event: &Event) -> Option<Intent> {
+        let own_uin = self.own_uin(event).await.ok().flatten()?;
+        let puppet = self.get_puppet_by_uin(&own_uin).await.ok()?;
+        let client = puppet.get_custom_client(&self.config.homeserver.address)?;
+        Some(Intent::double_puppet(self.get_matrix_client(), client))
+    }
+
+    /// WeChat messages are sent by the sender's ghost, or by the bot when puppets are disabled,
+    /// dated to when they were sent on WeChat unless timestamp massaging is turned off. Messages
+    /// the user sent from WeChat are posted by their double puppet, or else by the bot as a
+    /// notice, see [`Self::apply_sender_prefix`].
+    async fn sender_intent(&self, event: &Event) -> Intent {
+        if event.from_self
+            && let Some(intent) = self.double_puppet_intent(event).await
+        {
+            return intent;
+        }
+        let intent = if self.config.bridge.no_puppet.enabled || event.from_self {
+            self.intent(&self.config.appservice.bot.mxid(&self.config.homeserver.domain)).await
+        } else {
+            self.intent(&self.puppet_mxid(&event.from.id)).await
+        };
+        if self.config.bridge.timestamp_massaging && event.timestamp > 0 {
+            intent.at(event.timestamp)
+        } else {
+            intent
+        }
     }
 
     pub fn format_username(&self, username: &str) -> String {
@@ -238,52 +2261,150 @@ impl WechatBridge {
         format!("@{}{}:{}", prefix, uin, self.config.homeserver.domain)
     }
 
-    pub async fn handle_wechat_event(&self, event: Event) -> anyhow::Result<()> {
+    /// Extracts the WeChat ID from a puppet's Matrix ID.
+    pub fn puppet_uin(&self, mxid: &str) -> Option<String> {
+        let localpart = mxid.strip_prefix('@')?
+            .strip_suffix(&format!(":{}", self.config.homeserver.domain))?;
+        let uin = localpart.strip_prefix(&self.config.bridge.user_prefix)?;
+        (!uin.is_empty()).then(|| uin.to_string())
+    }
+
+    pub async fn handle_wechat_event(&self, mut event: Event) -> anyhow::Result<()> {
         debug!("Handling WeChat event: {:?} from {}", event.event_type, event.from.id);
+        if !event.from_self && self.own_uin(&event).await?.is_some_and(|uin| uin == event.from.id) {
+            event.from_self = true;
+        }
         
         let receiver = event.from.id.clone();
-        
-        match event.event_type {
-            EventType::Text => {
-                self.handle_text_event(event).await?;
-            }
-            EventType::Photo => {
-                self.handle_photo_event(event).await?;
-            }
-            EventType::Video => {
-                self.handle_video_event(event).await?;
-            }
-            EventType::Audio => {
-                self.handle_audio_event(event).await?;
-            }
-            EventType::File => {
-                self.handle_file_event(event).await?;
-            }
-            EventType::Sticker => {
-                self.handle_sticker_event(event).await?;
-            }
-            EventType::Location => {
-                self.handle_location_event(event).await?;
-            }
-            EventType::App => {
-                self.handle_app_event(event).await?;
-            }
-            EventType::Revoke => {
-                self.handle_revoke_event(event).await?;
+
+        let is_message = matches!(
+            event.event_type,
+            EventType::Text | EventType::Photo | EventType::Video | EventType::Audio
+                | EventType::File | EventType::Sticker | EventType::Location | EventType::App
+        );
+        let group_chat = event.chat.chat_type == crate::wechat::ChatType::Group;
+        let chat_id = event.chat.id.clone();
+        let sender_id = event.from.id.clone();
+        let dedup_key = self.event_portal_key(&event).await?;
+        if self.dedup.is_duplicate(&dedup_key, &event.event_type, &event.id).await? {
+            debug!("Dropping already bridged {} event {}", event.event_type, event.id);
+            return Ok(());
+        }
+        let seen = (event.event_type.clone(), event.id.clone());
+        // Agents may report the messages the bridge sent for Matrix users as sent by the account.
+        if is_message
+            && event.from_self
+            && (self.wechat_service.echoes().is_echo(&event)
+                || self.db.get_message_by_wechat_id(&event.id).await?.is_some())
+        {
+            debug!("Dropping echo of message {} sent from Matrix", event.id);
+            return Ok(());
+        }
+        if is_message && self.is_chat_muted(&dedup_key, &event.mxid).await? {
+            debug!("Dropping event {} in muted chat {}", event.id, chat_id);
+            return Ok(());
+        }
+
+        let watermark = if is_message && self.config.bridge.replay_protection.enabled {
+            let watermark = self.db.get_chat_watermark(&event.mxid, &event.chat.id).await?;
+            let tolerance = self.config.bridge.replay_protection.tolerance_duration().as_millis() as i64;
+            if let Some(watermark) = &watermark
+                && watermark.is_replay(&event.id, event.timestamp, tolerance)
+            {
+                debug!("Dropping replayed event {} in chat {}", event.id, event.chat.id);
+                return Ok(());
             }
-            EventType::Notice | EventType::Voip | EventType::System => {
-                debug!("Unhandled event type: {:?}", event.event_type);
+            Some((event.chat.id.clone(), event.id.clone(), event.timestamp, watermark))
+        } else {
+            None
+        };
+
+        let msg_type = event.event_type.to_string();
+        let mxid = event.mxid.clone();
+        let result = match event.event_type {
+            EventType::Text => self.handle_text_event(event).await,
+            EventType::Photo => self.handle_photo_event(event).await,
+            EventType::Video => self.handle_video_event(event).await,
+            EventType::Audio => self.handle_audio_event(event).await,
+            EventType::File => self.handle_file_event(event).await,
+            EventType::Sticker => self.handle_sticker_event(event).await,
+            EventType::Location => self.handle_location_event(event).await,
+            EventType::App => self.handle_app_event(event).await,
+            EventType::Revoke => self.handle_revoke_event(event).await,
+            EventType::Reaction => self.handle_reaction_event(event).await,
+            EventType::Read => self.handle_read_event(event).await,
+            EventType::Presence => self.handle_presence_event(event).await,
+            EventType::GroupUpdate => self.handle_group_update_event(event).await,
+            EventType::GroupRemove => self.handle_group_remove_event(event).await,
+            EventType::Notice | EventType::System => self.handle_system_event(event).await,
+            EventType::Voip => self.handle_voip_event(event).await,
+            EventType::FriendRequest => self.handle_friend_request_event(event).await,
+            EventType::GroupInvite => self.handle_group_invite_event(event).await,
+            EventType::Logout => {
+                // Reported by the agent connection as an `AgentStatus` instead.
+                debug!("Ignoring logout event {}", event.id);
+                Ok(())
             }
+            EventType::Unknown(_) => self.handle_unsupported_event(event).await,
+        };
+        if is_message {
+            crate::metrics::metrics().record_message(Direction::WechatToMatrix, &msg_type, &mxid, result.is_ok());
+        }
+        result?;
+        self.dedup.mark_seen(&dedup_key, &seen.0, &seen.1).await;
+
+        if is_message {
+            self.ensure_puppet_profile(&mxid, &sender_id).await?;
+        }
+
+        if is_message && group_chat {
+            self.sync_new_group_portals(&chat_id).await?;
+        }
+
+        if let Some((chat_id, msg_id, timestamp, previous)) = watermark
+            && previous.is_none_or(|w| timestamp > w.last_ts)
+        {
+            self.db.upsert_chat_watermark(&ChatWatermark {
+                mxid: mxid.clone(),
+                chat_id,
+                last_ts: timestamp,
+                last_msg_id: msg_id,
+            }).await?;
         }
         
         Ok(())
     }
 
+    /// Syncs members into group portals that were created but never synced.
+    async fn sync_new_group_portals(&self, chat_id: &str) -> anyhow::Result<()> {
+        let portals = self.db.get_portals_by_uid(chat_id).await?;
+        for portal in portals.into_iter().filter(|p| p.mxid.is_some() && p.last_sync == 0) {
+            let bridge = self.clone();
+            tokio::spawn(async move {
+                let uid = portal.uid.clone();
+                if let Err(e) = bridge.sync_group_members(portal).await {
+                    warn!("Failed to sync members of new group portal {}: {}", uid, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Mentions of a logged-in user's own account ping their Matrix user instead of the puppet.
+    async fn mention_target(&self, wxid: &str) -> anyhow::Result<Mention> {
+        if wxid == WECHAT_MENTION_ALL {
+            return Ok(Mention::Room);
+        }
+        Ok(match self.db.get_user_by_uin(wxid).await? {
+            Some(user) => Mention::User(user.mxid),
+            None => Mention::User(self.puppet_mxid(wxid)),
+        })
+    }
+
     async fn handle_text_event(&self, event: Event) -> anyhow::Result<()> {
-        let chat_id = &event.chat.id;
         let sender_id = &event.from.id;
         
-        let key = PortalKey::new(chat_id.clone(), sender_id.clone());
+        let key = self.event_portal_key(&event).await?;
         let portal = self.get_portal_by_key(&key).await?;
         let puppet = self.get_puppet_by_uin(sender_id).await?;
         
@@ -292,14 +2413,15 @@ impl WechatBridge {
         };
 
         let client = self.get_matrix_client();
-        let puppet_mxid = self.puppet_mxid(sender_id);
+        let intent = self.sender_intent(&event).await;
+        let puppet_mxid = self.portal_puppet_mxid(&event, &key);
         
         let mut portal = Arc::try_unwrap(portal).unwrap_or_else(|p| (*p).clone());
         
         let room_id = portal.get_matrix_room(
             &client,
             &self.config.appservice.bot.mxid(&self.config.homeserver.domain),
-            &puppet_mxid,
+            self.room_puppet(&puppet_mxid),
             Some(content),
             None,
             event.chat.chat_type == crate::wechat::ChatType::Private,
@@ -311,59 +2433,84 @@ impl WechatBridge {
             portals.insert(room_id.clone(), Arc::new(portal.clone()));
         }
 
-        let formatted = crate::formatter::wechat_to_matrix(content);
-        
-        let event_id = if let Some(reply) = &event.reply {
-            if let Some(msg) = self.db.get_message_by_wechat_id(&reply.id).await? {
-                let reply_content = serde_json::json!({
-                    "m.relates_to": {
+        let mut mentions = Vec::with_capacity(event.mentions.len());
+        for id in &event.mentions {
+            mentions.push(self.mention_target(id).await?);
+        }
+        let text = crate::formatter::wechat_to_matrix::format_text(content, &mentions);
+        let mut message = serde_json::to_value(
+            crate::matrix::types::EventContent::text_html(text.body.as_str(), text.html.as_str()),
+        )?;
+        message["m.mentions"] = text.mentions_content();
+
+        // Corrections (including recall-and-resend) are flagged by the agent with the original message ID.
+        let edit_of = event.data.as_ref()
+            .and_then(|d| d.get("edit_of"))
+            .and_then(|v| v.as_str());
+        if let Some(edit_of) = edit_of
+            && let Some(original) = self.db.get_message_by_id(&key, edit_of).await?
+        {
+            self.apply_sender_prefix(&event, &intent, &mut message);
+            // Clients only show edits from the sender of the original message.
+            let editor = self.message_intent(&original).await;
+            let msg = pending_message(&key, &event, &intent);
+            let event_id = self.record_send(msg, editor.edit_message(&room_id, &original.mxid, &message)).await?;
+            self.db.insert_message_edit(&crate::database::MessageEdit {
+                chat_uid: original.chat_uid.clone(),
+                chat_receiver: original.chat_receiver.clone(),
+                msg_id: event.id.clone(),
+                target_msg_id: original.msg_id.clone(),
+                mxid: event_id.clone(),
+                sender: intent.mxid().to_string(),
+                timestamp: event.timestamp,
+            }).await?;
+
+            debug!("Bridged text edit {} of {} -> {}", event.id, edit_of, event_id);
+            return Ok(());
+        }
+
+        self.apply_sender_prefix(&event, &intent, &mut message);
+
+        if let Some(reply) = &event.reply {
+            let quoted = crate::formatter::wechat_to_matrix(&reply.content);
+            match self.db.get_message_by_id(&key, &reply.id).await? {
+                Some(msg) => {
+                    message["m.relates_to"] = serde_json::json!({
                         "m.in_reply_to": {
                             "event_id": msg.mxid
                         }
-                    }
-                });
-                client.send_text_html(&room_id, content, &formatted).await?
-            } else {
-                client.send_text_html(&room_id, content, &formatted).await?
+                    });
+                    let sender = if msg.sender.is_empty() { self.puppet_mxid(&reply.sender) } else { msg.sender.clone() };
+                    add_reply_fallback(&mut message, &sender, &quoted, Some((&room_id, &msg.mxid)));
+                }
+                // Quotes of messages from before the portal existed can only be shown as text.
+                None => add_reply_fallback(&mut message, &self.puppet_mxid(&reply.sender), &quoted, None),
             }
-        } else {
-            client.send_text_html(&room_id, content, &formatted).await?
-        };
-
-        let msg = DbMessage {
-            chat_uid: chat_id.clone(),
-            chat_receiver: sender_id.to_string(),
-            msg_id: event.id.clone(),
-            mxid: event_id.clone(),
-            sender: puppet_mxid,
-            timestamp: event.timestamp,
-            sent: true,
-            error: None,
-            msg_type: String::new(),
-        };
-        self.db.insert_message(&msg).await?;
+        }
+        let msg = pending_message(&key, &event, &intent);
+        let event_id = self.record_send(msg, intent.send_message(&room_id, "m.room.message", &message)).await?;
         
         debug!("Bridged text message {} -> {}", event.id, event_id);
         Ok(())
     }
 
     async fn handle_photo_event(&self, event: Event) -> anyhow::Result<()> {
-        let chat_id = &event.chat.id;
         let sender_id = &event.from.id;
         
-        let key = PortalKey::new(chat_id.clone(), sender_id.clone());
+        let key = self.event_portal_key(&event).await?;
         let portal = self.get_portal_by_key(&key).await?;
         let puppet = self.get_puppet_by_uin(sender_id).await?;
         
         let client = self.get_matrix_client();
-        let puppet_mxid = self.puppet_mxid(sender_id);
+        let intent = self.sender_intent(&event).await;
+        let puppet_mxid = self.portal_puppet_mxid(&event, &key);
         
         let mut portal = Arc::try_unwrap(portal).unwrap_or_else(|p| (*p).clone());
         
         let room_id = portal.get_matrix_room(
             &client,
             &self.config.appservice.bot.mxid(&self.config.homeserver.domain),
-            &puppet_mxid,
+            self.room_puppet(&puppet_mxid),
             None,
             None,
             event.chat.chat_type == crate::wechat::ChatType::Private,
@@ -379,43 +2526,51 @@ impl WechatBridge {
             warn!("Photo event without data");
             return Ok(());
         };
+        if self.check_incoming_size(&event, &room_id, &event.event_type.to_string(), declared_size(data)).await.is_err() {
+            return Ok(());
+        }
         
         let xml = data.get("xml")
             .and_then(|v| v.as_str())
             .unwrap_or("");
 
-        let wechat_client = self.get_client("");
-        match wechat_client.download_image(xml).await {
+        let wechat_client = self.get_client(&event.mxid);
+        match self.check_downloaded(&event, &room_id, wechat_client.download_image(xml).await).await {
             Ok(image_data) => {
-                let content_type = "image/jpeg";
-                let filename = format!("image_{}.jpg", event.timestamp);
+                use crate::util::media::image::{self, ImageFormat};
+
+                let format = match image::detect_format(&image_data) {
+                    ImageFormat::Unknown => ImageFormat::Jpeg,
+                    format => format,
+                };
+                let content_type = format.mimetype();
+                let filename = format!("image_{}.{}", event.timestamp, format.extension());
                 
-                match client.upload_media(&image_data, content_type, &filename).await {
+                match self.media_cache.upload(&client, &image_data, content_type, &filename).await {
                     Ok(mxc_url) => {
-                        let content = serde_json::json!({
+                        let mut info = serde_json::json!({
+                            "mimetype": content_type,
+                            "size": image_data.len() as u64,
+                        });
+                        if let Some((w, h)) = image::dimensions(&image_data) {
+                            info["w"] = w.into();
+                            info["h"] = h.into();
+                            if w.max(h) > image::THUMBNAIL_SIZE {
+                                let thumbnail = image::thumbnail(&image_data).await;
+                                self.attach_thumbnail(&client, &mut info, thumbnail, &filename).await;
+                            }
+                        }
+
+                        let mut content = serde_json::json!({
                             "msgtype": "m.image",
                             "body": filename,
                             "url": mxc_url,
-                            "info": {
-                                "mimetype": content_type,
-                                "size": image_data.len() as u64,
-                            }
+                            "info": info,
                         });
+                        self.apply_sender_prefix(&event, &intent, &mut content);
                         
-                        let event_id = client.send_message(&room_id, "m.room.message", &content, None).await?;
-                        
-                        let msg = DbMessage {
-                            chat_uid: chat_id.clone(),
-                            chat_receiver: sender_id.to_string(),
-                            msg_id: event.id.clone(),
-                            mxid: event_id.clone(),
-                            sender: puppet_mxid.clone(),
-                            timestamp: event.timestamp,
-                            sent: true,
-                            error: None,
-                            msg_type: String::new(),
-                        };
-                        self.db.insert_message(&msg).await?;
+                        let msg = pending_message(&key, &event, &intent);
+                        let event_id = self.record_send(msg, intent.send_message(&room_id, "m.room.message", &content)).await?;
                         
                         debug!("Bridged photo message {} -> {}", event.id, event_id);
                     }
@@ -433,22 +2588,22 @@ impl WechatBridge {
     }
 
     async fn handle_video_event(&self, event: Event) -> anyhow::Result<()> {
-        let chat_id = &event.chat.id;
         let sender_id = &event.from.id;
         
-        let key = PortalKey::new(chat_id.clone(), sender_id.clone());
+        let key = self.event_portal_key(&event).await?;
         let portal = self.get_portal_by_key(&key).await?;
         let puppet = self.get_puppet_by_uin(sender_id).await?;
         
         let client = self.get_matrix_client();
-        let puppet_mxid = self.puppet_mxid(sender_id);
+        let intent = self.sender_intent(&event).await;
+        let puppet_mxid = self.portal_puppet_mxid(&event, &key);
         
         let mut portal = Arc::try_unwrap(portal).unwrap_or_else(|p| (*p).clone());
         
         let room_id = portal.get_matrix_room(
             &client,
             &self.config.appservice.bot.mxid(&self.config.homeserver.domain),
-            &puppet_mxid,
+            self.room_puppet(&puppet_mxid),
             None,
             None,
             event.chat.chat_type == crate::wechat::ChatType::Private,
@@ -464,43 +2619,65 @@ impl WechatBridge {
             warn!("Video event without data");
             return Ok(());
         };
+        if self.check_incoming_size(&event, &room_id, &event.event_type.to_string(), declared_size(data)).await.is_err() {
+            return Ok(());
+        }
         
         let xml = data.get("xml")
             .and_then(|v| v.as_str())
             .unwrap_or("");
 
-        let wechat_client = self.get_client("");
-        match wechat_client.download_video(xml).await {
+        let content_type = "video/mp4";
+        let filename = format!("video_{}.mp4", event.timestamp);
+        let wechat_client = self.get_client(&event.mxid);
+        if self.config.homeserver.async_media {
+            let content = serde_json::json!({
+                "msgtype": "m.video",
+                "body": filename,
+                "info": { "mimetype": content_type },
+            });
+            let (wechat_client, xml) = (wechat_client.clone(), xml.to_string());
+            let download = async move { wechat_client.download_video(&xml).await };
+            if self.send_async_media(&event, &room_id, &intent, content, &filename, download).await? {
+                return Ok(());
+            }
+        }
+
+        match self.check_downloaded(&event, &room_id, wechat_client.download_video(xml).await).await {
             Ok(video_data) => {
-                let content_type = "video/mp4";
-                let filename = format!("video_{}.mp4", event.timestamp);
-                
-                match client.upload_media(&video_data, content_type, &filename).await {
+                match self.media_cache.upload(&client, &video_data, content_type, &filename).await {
                     Ok(mxc_url) => {
-                        let content = serde_json::json!({
+                        use crate::util::media::video;
+
+                        let mut info = serde_json::json!({
+                            "mimetype": content_type,
+                            "size": video_data.len() as u64,
+                        });
+                        match video::probe(&video_data).await {
+                            Ok(probe) => {
+                                if let (Some(w), Some(h)) = (probe.width, probe.height) {
+                                    info["w"] = w.into();
+                                    info["h"] = h.into();
+                                }
+                                if let Some(duration) = probe.duration_ms {
+                                    info["duration"] = duration.into();
+                                }
+                            }
+                            Err(e) => warn!("Failed to probe video {}: {}", event.id, e),
+                        }
+                        let thumbnail = video::thumbnail(&video_data).await;
+                        self.attach_thumbnail(&client, &mut info, thumbnail, &filename).await;
+
+                        let mut content = serde_json::json!({
                             "msgtype": "m.video",
                             "body": filename,
                             "url": mxc_url,
-                            "info": {
-                                "mimetype": content_type,
-                                "size": video_data.len() as u64,
-                            }
+                            "info": info,
                         });
+                        self.apply_sender_prefix(&event, &intent, &mut content);
                         
-                        let event_id = client.send_message(&room_id, "m.room.message", &content, None).await?;
-                        
-                        let msg = DbMessage {
-                            chat_uid: chat_id.clone(),
-                            chat_receiver: sender_id.to_string(),
-                            msg_id: event.id.clone(),
-                            mxid: event_id.clone(),
-                            sender: puppet_mxid.clone(),
-                            timestamp: event.timestamp,
-                            sent: true,
-                            error: None,
-                            msg_type: String::new(),
-                        };
-                        self.db.insert_message(&msg).await?;
+                        let msg = pending_message(&key, &event, &intent);
+                        let event_id = self.record_send(msg, intent.send_message(&room_id, "m.room.message", &content)).await?;
                         
                         debug!("Bridged video message {} -> {}", event.id, event_id);
                     }
@@ -518,21 +2695,20 @@ impl WechatBridge {
     }
 
     async fn handle_audio_event(&self, event: Event) -> anyhow::Result<()> {
-        let chat_id = &event.chat.id;
-        let sender_id = &event.from.id;
         
-        let key = PortalKey::new(chat_id.clone(), sender_id.clone());
+        let key = self.event_portal_key(&event).await?;
         let portal = self.get_portal_by_key(&key).await?;
         
         let client = self.get_matrix_client();
-        let puppet_mxid = self.puppet_mxid(sender_id);
+        let intent = self.sender_intent(&event).await;
+        let puppet_mxid = self.portal_puppet_mxid(&event, &key);
         
         let mut portal = Arc::try_unwrap(portal).unwrap_or_else(|p| (*p).clone());
         
         let room_id = portal.get_matrix_room(
             &client,
             &self.config.appservice.bot.mxid(&self.config.homeserver.domain),
-            &puppet_mxid,
+            self.room_puppet(&puppet_mxid),
             None,
             None,
             event.chat.chat_type == crate::wechat::ChatType::Private,
@@ -548,20 +2724,33 @@ impl WechatBridge {
             warn!("Audio event without data");
             return Ok(());
         };
+        if self.check_incoming_size(&event, &room_id, &event.event_type.to_string(), declared_size(data)).await.is_err() {
+            return Ok(());
+        }
         
         let xml = data.get("xml")
             .and_then(|v| v.as_str())
             .unwrap_or("");
 
-        let wechat_client = self.get_client("");
-        match wechat_client.download_audio(xml).await {
+        let wechat_client = self.get_client(&event.mxid);
+        match self.check_downloaded(&event, &room_id, wechat_client.download_audio(xml).await).await {
             Ok(audio_data) => {
-                let content_type = "audio/ogg";
-                let filename = format!("audio_{}.ogg", event.timestamp);
+                use crate::util::media::audio;
+
+                let (audio_data, format, duration) = match audio::wechat_to_matrix(&audio_data).await {
+                    Ok(converted) => (converted.data, converted.format, Some(converted.duration_ms)),
+                    Err(e) => {
+                        warn!("Failed to convert voice message {}: {}", event.id, e);
+                        let format = audio::detect_format(&audio_data);
+                        (audio_data, format, None)
+                    }
+                };
+                let content_type = format.mimetype();
+                let filename = format!("audio_{}.{}", event.timestamp, format.extension());
                 
-                match client.upload_media(&audio_data, content_type, &filename).await {
+                match self.media_cache.upload(&client, &audio_data, content_type, &filename).await {
                     Ok(mxc_url) => {
-                        let content = serde_json::json!({
+                        let mut content = serde_json::json!({
                             "msgtype": "m.audio",
                             "body": filename,
                             "url": mxc_url,
@@ -570,21 +2759,15 @@ impl WechatBridge {
                                 "size": audio_data.len() as u64,
                             }
                         });
+                        if let Some(duration) = duration {
+                            content["info"]["duration"] = duration.into();
+                            content["org.matrix.msc1767.audio"] = serde_json::json!({ "duration": duration });
+                            content["org.matrix.msc3245.voice"] = serde_json::json!({});
+                        }
+                        self.apply_sender_prefix(&event, &intent, &mut content);
                         
-                        let event_id = client.send_message(&room_id, "m.room.message", &content, None).await?;
-                        
-                        let msg = DbMessage {
-                            chat_uid: chat_id.clone(),
-                            chat_receiver: sender_id.to_string(),
-                            msg_id: event.id.clone(),
-                            mxid: event_id.clone(),
-                            sender: puppet_mxid.clone(),
-                            timestamp: event.timestamp,
-                            sent: true,
-                            error: None,
-                            msg_type: String::new(),
-                        };
-                        self.db.insert_message(&msg).await?;
+                        let msg = pending_message(&key, &event, &intent);
+                        let event_id = self.record_send(msg, intent.send_message(&room_id, "m.room.message", &content)).await?;
                         
                         debug!("Bridged audio message {} -> {}", event.id, event_id);
                     }
@@ -602,21 +2785,20 @@ impl WechatBridge {
     }
 
     async fn handle_file_event(&self, event: Event) -> anyhow::Result<()> {
-        let chat_id = &event.chat.id;
-        let sender_id = &event.from.id;
         
-        let key = PortalKey::new(chat_id.clone(), sender_id.clone());
+        let key = self.event_portal_key(&event).await?;
         let portal = self.get_portal_by_key(&key).await?;
         
         let client = self.get_matrix_client();
-        let puppet_mxid = self.puppet_mxid(sender_id);
+        let intent = self.sender_intent(&event).await;
+        let puppet_mxid = self.portal_puppet_mxid(&event, &key);
         
         let mut portal = Arc::try_unwrap(portal).unwrap_or_else(|p| (*p).clone());
         
         let room_id = portal.get_matrix_room(
             &client,
             &self.config.appservice.bot.mxid(&self.config.homeserver.domain),
-            &puppet_mxid,
+            self.room_puppet(&puppet_mxid),
             None,
             None,
             event.chat.chat_type == crate::wechat::ChatType::Private,
@@ -632,6 +2814,9 @@ impl WechatBridge {
             warn!("File event without data");
             return Ok(());
         };
+        if self.check_incoming_size(&event, &room_id, &event.event_type.to_string(), declared_size(data)).await.is_err() {
+            return Ok(());
+        }
         
         let xml = data.get("xml")
             .and_then(|v| v.as_str())
@@ -640,14 +2825,26 @@ impl WechatBridge {
             .and_then(|v| v.as_str())
             .unwrap_or(&event.id);
 
-        let wechat_client = self.get_client("");
-        match wechat_client.download_file(xml).await {
+        let content_type = "application/octet-stream";
+        let wechat_client = self.get_client(&event.mxid);
+        if self.config.homeserver.async_media {
+            let content = serde_json::json!({
+                "msgtype": "m.file",
+                "body": filename,
+                "info": { "mimetype": content_type },
+            });
+            let (wechat_client, xml) = (wechat_client.clone(), xml.to_string());
+            let download = async move { wechat_client.download_file(&xml).await };
+            if self.send_async_media(&event, &room_id, &intent, content, filename, download).await? {
+                return Ok(());
+            }
+        }
+
+        match self.check_downloaded(&event, &room_id, wechat_client.download_file(xml).await).await {
             Ok(file_data) => {
-                let content_type = "application/octet-stream";
-                
-                match client.upload_media(&file_data, content_type, filename).await {
+                match self.media_cache.upload(&client, &file_data, content_type, filename).await {
                     Ok(mxc_url) => {
-                        let content = serde_json::json!({
+                        let mut content = serde_json::json!({
                             "msgtype": "m.file",
                             "body": filename,
                             "url": mxc_url,
@@ -656,21 +2853,10 @@ impl WechatBridge {
                                 "size": file_data.len() as u64,
                             }
                         });
+                        self.apply_sender_prefix(&event, &intent, &mut content);
                         
-                        let event_id = client.send_message(&room_id, "m.room.message", &content, None).await?;
-                        
-                        let msg = DbMessage {
-                            chat_uid: chat_id.clone(),
-                            chat_receiver: sender_id.to_string(),
-                            msg_id: event.id.clone(),
-                            mxid: event_id.clone(),
-                            sender: puppet_mxid.clone(),
-                            timestamp: event.timestamp,
-                            sent: true,
-                            error: None,
-                            msg_type: String::new(),
-                        };
-                        self.db.insert_message(&msg).await?;
+                        let msg = pending_message(&key, &event, &intent);
+                        let event_id = self.record_send(msg, intent.send_message(&room_id, "m.room.message", &content)).await?;
                         
                         debug!("Bridged file message {} -> {}", event.id, event_id);
                     }
@@ -683,31 +2869,230 @@ impl WechatBridge {
                 warn!("Failed to download file: {}", e);
             }
         }
-        
+        
+        Ok(())
+    }
+
+    async fn handle_sticker_event(&self, event: Event) -> anyhow::Result<()> {
+        
+        let key = self.event_portal_key(&event).await?;
+        let portal = self.get_portal_by_key(&key).await?;
+        
+        let client = self.get_matrix_client();
+        let intent = self.sender_intent(&event).await;
+        let puppet_mxid = self.portal_puppet_mxid(&event, &key);
+        
+        let mut portal = Arc::try_unwrap(portal).unwrap_or_else(|p| (*p).clone());
+        
+        let room_id = portal.get_matrix_room(
+            &client,
+            &self.config.appservice.bot.mxid(&self.config.homeserver.domain),
+            self.room_puppet(&puppet_mxid),
+            None,
+            None,
+            event.chat.chat_type == crate::wechat::ChatType::Private,
+            self.config.bridge.encryption.default,
+        ).await?;
+
+        {
+            let mut portals = self.portals_by_mxid.write().await;
+            portals.insert(room_id.clone(), Arc::new(portal.clone()));
+        }
+
+        let Some(data) = &event.data else {
+            warn!("Sticker event without data");
+            return Ok(());
+        };
+
+        // Custom emoji come with a CDN URL, built-in ones have to be fetched through the agent.
+        let downloaded = match data.get("url").and_then(|v| v.as_str()) {
+            Some(url) => self.download(url).await,
+            None => {
+                let xml = data.get("xml")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("");
+                self.get_client(&event.mxid).download_image(xml).await
+            }
+        };
+        let emoji_data = match self.check_downloaded(&event, &room_id, downloaded).await {
+            Ok(emoji_data) => emoji_data,
+            // The room was already told.
+            Err(e) if e.is::<MediaTooLarge>() => return Ok(()),
+            Err(e) => {
+                warn!("Failed to download sticker {}: {}", event.id, e);
+                let notice = format!("A WeChat sticker wasn't bridged: {}.", e);
+                if let Err(e) = client.send_notice(&room_id, notice).await {
+                    warn!("Failed to send notice about sticker {} to {}: {}", event.id, room_id, e);
+                }
+                return Ok(());
+            }
+        };
+
+        let sticker = match crate::util::media::image::sticker_to_matrix(&emoji_data).await {
+            Ok(sticker) => sticker,
+            Err(e) => {
+                warn!("Failed to convert sticker {}: {}", event.id, e);
+                crate::util::media::image::ConvertedImage::from_data(emoji_data)
+            }
+        };
+        let content_type = sticker.format.mimetype();
+        let filename = format!("sticker_{}.{}", event.timestamp, sticker.format.extension());
+
+        let mxc_url = match self.media_cache.upload(&client, &sticker.data, content_type, &filename).await {
+            Ok(mxc_url) => mxc_url,
+            Err(e) => {
+                warn!("Failed to upload sticker: {}", e);
+                return Ok(());
+            }
+        };
+
+        let mut content = serde_json::json!({
+            "body": filename,
+            "url": mxc_url,
+            "info": {
+                "mimetype": content_type,
+                "size": sticker.data.len() as u64,
+            }
+        });
+        if let (Some(w), Some(h)) = (sticker.width, sticker.height) {
+            content["info"]["w"] = w.into();
+            content["info"]["h"] = h.into();
+        }
+        self.apply_sender_prefix(&event, &intent, &mut content);
+
+        let msg = pending_message(&key, &event, &intent);
+        let event_id = self.record_send(msg, intent.send_message(&room_id, "m.sticker", &content)).await?;
+
+        debug!("Bridged sticker {} -> {}", event.id, event_id);
+        Ok(())
+    }
+
+    /// The Matrix room of the portal an event belongs in, created if it has none yet.
+    async fn event_room(&self, event: &Event, key: &PortalKey) -> anyhow::Result<String> {
+        let portal = self.get_portal_by_key(key).await?;
+        let mut portal = Arc::try_unwrap(portal).unwrap_or_else(|p| (*p).clone());
+        let puppet_mxid = self.portal_puppet_mxid(event, key);
+        let room_id = portal.get_matrix_room(
+            &self.get_matrix_client(),
+            &self.config.appservice.bot.mxid(&self.config.homeserver.domain),
+            self.room_puppet(&puppet_mxid),
+            None,
+            None,
+            event.chat.chat_type == crate::wechat::ChatType::Private,
+            self.config.bridge.encryption.default,
+        ).await?;
+        self.portals_by_mxid.write().await.insert(room_id.clone(), Arc::new(portal));
+        Ok(room_id)
+    }
+
+    /// Bridges WeChat system messages, such as group invites, renames or red packets, as
+    /// notices. Notices about a contact come from their puppet, system messages from the bot.
+    async fn handle_system_event(&self, event: Event) -> anyhow::Result<()> {
+        let Some(text) = event.content.as_deref().and_then(crate::formatter::system::format_system_message) else {
+            return self.handle_unsupported_event(event).await;
+        };
+
+        let key = self.event_portal_key(&event).await?;
+        let room_id = self.event_room(&event, &key).await?;
+        let bot_mxid = self.config.appservice.bot.mxid(&self.config.homeserver.domain);
+        let from_puppet = event.event_type == EventType::Notice;
+        let intent = if from_puppet {
+            self.sender_intent(&event).await
+        } else if self.config.bridge.timestamp_massaging && event.timestamp > 0 {
+            self.intent(&bot_mxid).await.at(event.timestamp)
+        } else {
+            self.intent(&bot_mxid).await
+        };
+
+        let mut content = serde_json::to_value(crate::matrix::types::EventContent::notice(text))?;
+        if from_puppet {
+            self.apply_sender_prefix(&event, &intent, &mut content);
+        }
+        let msg = pending_message(&key, &event, &intent);
+        let event_id = self.record_send(msg, intent.send_message(&room_id, "m.room.message", &content)).await?;
+
+        debug!("Bridged {} event {} -> {}", event.event_type, event.id, event_id);
+        Ok(())
+    }
+
+    async fn handle_voip_event(&self, event: Event) -> anyhow::Result<()> {
+        let Some(call) = CallEvent::from_event(&event) else {
+            return self.handle_unsupported_event(event).await;
+        };
+
+        let key = self.event_portal_key(&event).await?;
+        let room_id = self.event_room(&event, &key).await?;
+        let intent = self.sender_intent(&event).await;
+
+        if self.config.bridge.call_events {
+            let (event_type, content) = call.matrix_event();
+            if let Err(e) = intent.send_message(&room_id, event_type, &content).await {
+                warn!("Failed to send {} for call {}: {}", event_type, call.call_id, e);
+            }
+        }
+
+        let mut content = serde_json::to_value(crate::matrix::types::EventContent::notice(call.notice()))?;
+        self.apply_sender_prefix(&event, &intent, &mut content);
+        let msg = pending_message(&key, &event, &intent);
+        let event_id = self.record_send(msg, intent.send_message(&room_id, "m.room.message", &content)).await?;
+
+        debug!("Bridged call event {} -> {}", event.id, event_id);
         Ok(())
     }
 
-    async fn handle_sticker_event(&self, event: Event) -> anyhow::Result<()> {
-        debug!("Sticker event received: {}", event.id);
+    async fn handle_unsupported_event(&self, event: Event) -> anyhow::Result<()> {
+        let event_type = event.event_type.to_string();
+        crate::metrics::metrics().unsupported_messages.inc(&event_type);
+
+        if !self.config.bridge.unsupported_messages.should_notify(&event_type) {
+            debug!("Dropping unsupported {} event {}", event_type, event.id);
+            return Ok(());
+        }
+
+        let key = self.event_portal_key(&event).await?;
+        let room_id = self.event_room(&event, &key).await?;
+        let intent = self.sender_intent(&event).await;
+
+        let description = match &event.event_type {
+            EventType::Voip => "voice or video call",
+            EventType::System => "system message",
+            EventType::Notice => "notice",
+            _ => "message",
+        };
+        let body = format!("Received an unsupported WeChat {} of type {}", description, event_type);
+        let raw = serde_json::to_string_pretty(&event).unwrap_or_default();
+        let html = format!(
+            "{}<br/><span data-mx-spoiler=\"raw event\"><pre><code>{}</code></pre></span>",
+            crate::formatter::escape_html(&body),
+            crate::formatter::escape_html(&raw),
+        );
+
+        let mut content = serde_json::to_value(crate::matrix::types::EventContent::notice(body))?;
+        content["format"] = "org.matrix.custom.html".into();
+        content["formatted_body"] = html.into();
+        self.apply_sender_prefix(&event, &intent, &mut content);
+        let msg = pending_message(&key, &event, &intent);
+        let event_id = self.record_send(msg, intent.send_message(&room_id, "m.room.message", &content)).await?;
+
+        debug!("Bridged unsupported {} event {} -> {}", event_type, event.id, event_id);
         Ok(())
     }
 
     async fn handle_location_event(&self, event: Event) -> anyhow::Result<()> {
-        let chat_id = &event.chat.id;
-        let sender_id = &event.from.id;
         
-        let key = PortalKey::new(chat_id.clone(), sender_id.clone());
+        let key = self.event_portal_key(&event).await?;
         let portal = self.get_portal_by_key(&key).await?;
         
         let client = self.get_matrix_client();
-        let puppet_mxid = self.puppet_mxid(sender_id);
+        let intent = self.sender_intent(&event).await;
+        let puppet_mxid = self.portal_puppet_mxid(&event, &key);
         
         let mut portal = Arc::try_unwrap(portal).unwrap_or_else(|p| (*p).clone());
         
         let room_id = portal.get_matrix_room(
             &client,
             &self.config.appservice.bot.mxid(&self.config.homeserver.domain),
-            &puppet_mxid,
+            self.room_puppet(&puppet_mxid),
             None,
             None,
             event.chat.chat_type == crate::wechat::ChatType::Private,
@@ -737,7 +3122,7 @@ impl WechatBridge {
 
         let geo_uri = format!("geo:{},{}", lat, lon);
         
-        let content = serde_json::json!({
+        let mut content = serde_json::json!({
             "msgtype": "m.location",
             "body": body,
             "geo_uri": geo_uri,
@@ -745,42 +3130,30 @@ impl WechatBridge {
                 "name": name,
             }
         });
+        self.apply_sender_prefix(&event, &intent, &mut content);
         
-        let event_id = client.send_message(&room_id, "m.room.message", &content, None).await?;
-        
-        let msg = DbMessage {
-            chat_uid: chat_id.clone(),
-            chat_receiver: sender_id.to_string(),
-            msg_id: event.id.clone(),
-            mxid: event_id.clone(),
-            sender: puppet_mxid,
-            timestamp: event.timestamp,
-            sent: true,
-            error: None,
-            msg_type: String::new(),
-        };
-        self.db.insert_message(&msg).await?;
+        let msg = pending_message(&key, &event, &intent);
+        let event_id = self.record_send(msg, intent.send_message(&room_id, "m.room.message", &content)).await?;
         
         debug!("Bridged location message {} -> {}", event.id, event_id);
         Ok(())
     }
 
     async fn handle_app_event(&self, event: Event) -> anyhow::Result<()> {
-        let chat_id = &event.chat.id;
-        let sender_id = &event.from.id;
         
-        let key = PortalKey::new(chat_id.clone(), sender_id.clone());
+        let key = self.event_portal_key(&event).await?;
         let portal = self.get_portal_by_key(&key).await?;
         
         let client = self.get_matrix_client();
-        let puppet_mxid = self.puppet_mxid(sender_id);
+        let intent = self.sender_intent(&event).await;
+        let puppet_mxid = self.portal_puppet_mxid(&event, &key);
         
         let mut portal = Arc::try_unwrap(portal).unwrap_or_else(|p| (*p).clone());
         
         let room_id = portal.get_matrix_room(
             &client,
             &self.config.appservice.bot.mxid(&self.config.homeserver.domain),
-            &puppet_mxid,
+            self.room_puppet(&puppet_mxid),
             None,
             None,
             event.chat.chat_type == crate::wechat::ChatType::Private,
@@ -796,35 +3169,206 @@ impl WechatBridge {
             return Ok(());
         };
 
-        let title = data.get("title").and_then(|v| v.as_str()).unwrap_or("Link");
-        let desc = data.get("desc").and_then(|v| v.as_str()).unwrap_or("");
-        let url = data.get("url").and_then(|v| v.as_str()).unwrap_or("");
+        let xml = data.get("xml").and_then(|v| v.as_str());
+        let record = xml.and_then(crate::formatter::chat_record::ChatRecord::parse);
+        let payment = xml.and_then(crate::formatter::payment::PaymentMessage::parse);
+        let card = xml.and_then(crate::formatter::app_card::AppCard::parse);
+        let mut content = if let Some(record) = &record {
+            serde_json::to_value(
+                crate::matrix::types::EventContent::text_html(record.body(), record.html()),
+            )?
+        } else if let Some(card) = card {
+            let thumb = match &card.thumb_url {
+                Some(thumb_url) => match self.upload_preview_image(thumb_url).await {
+                    Ok(thumb) => Some(thumb),
+                    Err(e) => {
+                        debug!("Failed to bridge thumbnail {} of app card {}: {}", thumb_url, event.id, e);
+                        None
+                    }
+                },
+                None => None,
+            };
+            let mut content = serde_json::to_value(crate::matrix::types::EventContent::text_html(
+                card.body(),
+                card.html(thumb.as_ref().map(|t| t.mxc.as_str())),
+            ))?;
+            if self.config.bridge.url_previews
+                && let Some(url) = &card.url
+            {
+                let preview = LinkPreview {
+                    url: url.clone(),
+                    title: Some(card.title.clone()).filter(|t| !t.is_empty()),
+                    description: card.source.clone(),
+                    image_url: card.thumb_url.clone(),
+                };
+                content["com.beeper.linkpreviews"] = serde_json::json!([preview.to_beeper(thumb.as_ref())]);
+            }
+            content
+        } else if let Some(payment) = payment {
+            if payment.is_status_update() {
+                serde_json::to_value(crate::matrix::types::EventContent::notice(payment.body()))?
+            } else {
+                serde_json::to_value(
+                    crate::matrix::types::EventContent::text_html(payment.body(), payment.html()),
+                )?
+            }
+        } else {
+            let title = data.get("title").and_then(|v| v.as_str()).unwrap_or("Link");
+            let desc = data.get("desc").and_then(|v| v.as_str()).unwrap_or("");
+            let url = data.get("url").and_then(|v| v.as_str()).unwrap_or("");
 
-        let body = format!("{}\n\n{}", title, url);
-        let html = format!(
-            "<strong>{}</strong><br/><br/><a href=\"{}\">{}</a>",
-            title, url, url
-        );
-        
-        let event_id = client.send_text_html(&room_id, &body, &html).await?;
-        
-        let msg = DbMessage {
-            chat_uid: chat_id.clone(),
-            chat_receiver: sender_id.to_string(),
-            msg_id: event.id.clone(),
-            mxid: event_id.clone(),
-            sender: puppet_mxid,
-            timestamp: event.timestamp,
-            sent: true,
-            error: None,
-            msg_type: String::new(),
+            let body = format!("{}\n\n{}", title, url);
+            let html = format!(
+                "<strong>{}</strong><br/><br/><a href=\"{}\">{}</a>",
+                title, url, url
+            );
+            let mut content = serde_json::to_value(
+                crate::matrix::types::EventContent::text_html(body, html),
+            )?;
+            if self.config.bridge.url_previews
+                && let Some(preview) = self.link_preview(data).await
+            {
+                content["com.beeper.linkpreviews"] = serde_json::json!([preview]);
+            }
+            content
         };
-        self.db.insert_message(&msg).await?;
+        self.apply_sender_prefix(&event, &intent, &mut content);
+        let msg = pending_message(&key, &event, &intent);
+        let event_id = self.record_send(msg, intent.send_message(&room_id, "m.room.message", &content)).await?;
         
         debug!("Bridged app message {} -> {}", event.id, event_id);
+        if let Some(record) = record {
+            self.send_record_media(&event, &intent, &room_id, &event_id, &record).await;
+        }
         Ok(())
     }
 
+    /// Sends the images, videos and files of a chat record as replies to it. Media that can't
+    /// be downloaded stays a placeholder in the record.
+    async fn send_record_media(
+        &self,
+        event: &Event,
+        intent: &Intent,
+        room_id: &str,
+        record_event_id: &str,
+        record: &crate::formatter::chat_record::ChatRecord,
+    ) {
+        use crate::formatter::chat_record::RecordItemKind;
+        use crate::util::media::image::{self, ImageFormat};
+
+        let client = self.get_matrix_client();
+        let wechat_client = self.get_client(&event.mxid);
+        for (i, item) in record.items.iter().enumerate().filter(|(_, item)| item.has_media()) {
+            let download = match item.kind {
+                RecordItemKind::Image => wechat_client.download_image(&item.xml).await,
+                RecordItemKind::Video => wechat_client.download_video(&item.xml).await,
+                _ => wechat_client.download_file(&item.xml).await,
+            };
+            let data = match download {
+                Ok(data) => data,
+                Err(e) => {
+                    debug!("Failed to download item {} of chat record {}: {}", i, event.id, e);
+                    continue;
+                }
+            };
+            let what = match item.kind {
+                RecordItemKind::Image => "image in a chat record",
+                RecordItemKind::Video => "video in a chat record",
+                _ => "file in a chat record",
+            };
+            if self.check_incoming_size(event, room_id, what, Some(data.len() as u64)).await.is_err() {
+                continue;
+            }
+            let (msgtype, mimetype, filename) = match item.kind {
+                RecordItemKind::Image => {
+                    let format = match image::detect_format(&data) {
+                        ImageFormat::Unknown => ImageFormat::Jpeg,
+                        format => format,
+                    };
+                    ("m.image", format.mimetype(), format!("image_{}_{}.{}", event.timestamp, i, format.extension()))
+                }
+                RecordItemKind::Video => ("m.video", "video/mp4", format!("video_{}_{}.mp4", event.timestamp, i)),
+                _ => (
+                    "m.file",
+                    "application/octet-stream",
+                    item.title.clone().unwrap_or_else(|| format!("file_{}_{}", event.timestamp, i)),
+                ),
+            };
+            let mxc_url = match self.media_cache.upload(&client, &data, mimetype, &filename).await {
+                Ok(mxc_url) => mxc_url,
+                Err(e) => {
+                    warn!("Failed to upload item {} of chat record {}: {}", i, event.id, e);
+                    continue;
+                }
+            };
+            let content = serde_json::json!({
+                "msgtype": msgtype,
+                "body": filename,
+                "url": mxc_url,
+                "info": {
+                    "mimetype": mimetype,
+                    "size": data.len() as u64,
+                },
+                "m.relates_to": {
+                    "m.in_reply_to": { "event_id": record_event_id },
+                },
+            });
+            if let Err(e) = intent.send_message(room_id, "m.room.message", &content).await {
+                warn!("Failed to send item {} of chat record {}: {}", i, event.id, e);
+            }
+        }
+    }
+
+    /// The `com.beeper.linkpreviews` entry of an app message card. What the card lacks is
+    /// taken from the page, and failing to fetch it or its image only leaves that out.
+    async fn link_preview(&self, data: &serde_json::Value) -> Option<serde_json::Value> {
+        let url = data.get("url")?.as_str().filter(|u| u.starts_with("http://") || u.starts_with("https://"))?;
+        let xml = data.get("xml").and_then(|v| v.as_str());
+        let field = |names: &[&str]| {
+            names.iter().find_map(|name| {
+                data.get(*name)
+                    .and_then(|v| v.as_str())
+                    .map(str::to_string)
+                    .or_else(|| xml.and_then(|xml| crate::formatter::system::tag(xml, name)))
+                    .filter(|v| !v.is_empty())
+            })
+        };
+        let mut preview = LinkPreview {
+            url: url.to_string(),
+            title: field(&["title"]),
+            description: field(&["desc", "des"]),
+            image_url: field(&["thumb", "thumburl"]),
+        };
+        if !preview.is_complete() {
+            match fetch_preview(&self.http, url).await {
+                Ok(page) => preview.merge(page),
+                Err(e) => debug!("Failed to fetch link preview of {}: {}", url, e),
+            }
+        }
+
+        let image = match &preview.image_url {
+            Some(image_url) => match self.upload_preview_image(image_url).await {
+                Ok(image) => Some(image),
+                Err(e) => {
+                    debug!("Failed to bridge preview image {}: {}", image_url, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        Some(preview.to_beeper(image.as_ref()))
+    }
+
+    async fn upload_preview_image(&self, url: &str) -> anyhow::Result<PreviewImage> {
+        let (data, mimetype) = fetch_image(&self.http, url).await?;
+        let mxc = self.get_matrix_client().upload_media(&data, &mimetype, "preview").await?;
+        Ok(PreviewImage {
+            mxc,
+            mimetype,
+            size: data.len(),
+        })
+    }
+
     async fn handle_revoke_event(&self, event: Event) -> anyhow::Result<()> {
         let Some(data) = &event.data else {
             return Ok(());
@@ -834,24 +3378,431 @@ impl WechatBridge {
             .and_then(|v| v.as_str())
             .unwrap_or(&event.id);
 
-        if let Some(msg) = self.db.get_message_by_wechat_id(msg_id).await? {
-            let client = self.get_matrix_client();
-            match client.redact(&msg.chat_uid, &msg.mxid, Some("Message revoked")).await {
-                Ok(redact_event_id) => {
-                    info!("Revoked message {} -> {}", msg_id, redact_event_id);
-                }
-                Err(e) => {
-                    warn!("Failed to redact message: {}", e);
+        let key = self.event_portal_key(&event).await?;
+        if let Some(msg) = self.db.get_message_by_id(&key, msg_id).await? {
+            let room_id = self.db.get_portal_by_key(&msg.key()).await?.and_then(|p| p.mxid);
+            if let Some(room_id) = room_id {
+                let intent = self.message_intent(&msg).await;
+                match intent.redact(&room_id, &msg.mxid, Some("Message revoked")).await {
+                    Ok(redact_event_id) => {
+                        info!("Revoked message {} -> {}", msg_id, redact_event_id);
+                    }
+                    Err(e) => {
+                        warn!("Failed to redact message: {}", e);
+                    }
                 }
             }
+            self.db.delete_message_edits(&msg.key(), &msg.msg_id).await?;
+        } else if let Some(reaction) = self.db.get_reaction_by_wechat_id(msg_id).await? {
+            let room_id = self.db.get_portal_by_key(&reaction.key()).await?.and_then(|p| p.mxid);
+            if let Some(room_id) = room_id
+                && let Err(e) = self.sender_intent(&event).await.redact(&room_id, &reaction.mxid, None).await
+            {
+                warn!("Failed to redact reaction {}: {}", reaction.mxid, e);
+            }
+            self.db.delete_reaction_by_mxid(&reaction.mxid).await?;
+            debug!("Revoked reaction {} -> {}", msg_id, reaction.mxid);
         }
         
         Ok(())
     }
 
+    async fn handle_reaction_event(&self, event: Event) -> anyhow::Result<()> {
+        let Some(data) = &event.data else {
+            return Ok(());
+        };
+        let sender_id = &event.from.id;
+        let is_pat = data.get("type").and_then(|v| v.as_str()) == Some("pat");
+
+        // A pat isn't tied to a message, so it's attached to the latest message in the chat.
+        let key = self.event_portal_key(&event).await?;
+        let target = match data.get("msg_id").and_then(|v| v.as_str()) {
+            Some(msg_id) => self.db.get_message_by_id(&key, msg_id).await?,
+            None if is_pat => self.db.get_last_message(&key).await?,
+            None => None,
+        };
+        let Some(target) = target else {
+            debug!("Reaction target for {} not found", event.id);
+            return Ok(());
+        };
+
+        let Some(portal) = self.db.get_portal_by_key(&target.key()).await? else {
+            return Ok(());
+        };
+        let Some(room_id) = portal.mxid else {
+            return Ok(());
+        };
+
+        let emoji = data.get("emoji").and_then(|v| v.as_str()).unwrap_or("");
+        let emoji = if emoji.is_empty() && is_pat {
+            "👋".to_string()
+        } else {
+            crate::formatter::emoji::wechat_to_unicode(emoji)
+        };
+
+        let intent = self.sender_intent(&event).await;
+        let existing = self.db.get_reaction_by_target(&target.key(), &target.msg_id, sender_id).await?;
+        if let Some(existing) = &existing {
+            if existing.reaction_key == emoji {
+                return Ok(());
+            }
+            if let Err(e) = intent.redact(&room_id, &existing.mxid, None).await {
+                warn!("Failed to redact reaction {}: {}", existing.mxid, e);
+            }
+            self.db.delete_reaction_by_mxid(&existing.mxid).await?;
+        }
+
+        if emoji.is_empty() {
+            debug!("Removed reaction from {} on {}", sender_id, target.msg_id);
+            return Ok(());
+        }
+
+        let event_id = intent.send_reaction(&room_id, &target.mxid, &emoji).await?;
+        let reaction = Reaction {
+            chat_uid: target.chat_uid.clone(),
+            chat_receiver: target.chat_receiver.clone(),
+            target_msg_id: target.msg_id.clone(),
+            sender: sender_id.clone(),
+            mxid: event_id.clone(),
+            reaction_key: emoji,
+            msg_id: Some(event.id.clone()),
+            timestamp: event.timestamp,
+        };
+        self.db.upsert_reaction(&reaction).await?;
+
+        debug!("Bridged reaction {} -> {}", event.id, event_id);
+        Ok(())
+    }
+
+    async fn handle_read_event(&self, event: Event) -> anyhow::Result<()> {
+        if self.config.bridge.no_puppet.enabled {
+            return Ok(());
+        }
+
+        let msg_id = event.data.as_ref()
+            .and_then(|d| d.get("msg_id"))
+            .and_then(|v| v.as_str());
+        let key = self.event_portal_key(&event).await?;
+        let target = match msg_id {
+            Some(msg_id) => self.db.get_message_by_id(&key, msg_id).await?,
+            None => self.db.get_last_message(&key).await?,
+        };
+        let Some(target) = target else {
+            debug!("Read receipt target for {} not found", event.id);
+            return Ok(());
+        };
+
+        let Some(portal) = self.db.get_portal_by_key(&target.key()).await? else {
+            return Ok(());
+        };
+        let Some(room_id) = portal.mxid else {
+            return Ok(());
+        };
+
+        let intent = self.intent(&self.puppet_mxid(&event.from.id)).await;
+        intent.send_read_receipt(&room_id, &target.mxid).await?;
+
+        debug!("Bridged read receipt from {} for {}", intent.mxid(), target.mxid);
+        Ok(())
+    }
+
+    async fn handle_presence_event(&self, event: Event) -> anyhow::Result<()> {
+        if !self.config.bridge.presence || self.config.bridge.no_puppet.enabled {
+            return Ok(());
+        }
+
+        let presence = match event.data.as_ref()
+            .and_then(|d| d.get("presence"))
+            .and_then(|v| v.as_str())
+        {
+            Some("online") => "online",
+            Some("away") | Some("unavailable") => "unavailable",
+            _ => "offline",
+        };
+
+        let intent = self.intent(&self.puppet_mxid(&event.from.id)).await;
+        intent.set_presence(presence).await?;
+
+        debug!("Set presence of {} to {}", intent.mxid(), presence);
+        Ok(())
+    }
+
+    async fn handle_group_update_event(&self, event: Event) -> anyhow::Result<()> {
+        // Every user in the group has their own portal, so update all of them.
+        for portal in self.db.get_portals_by_uid(&event.chat.id).await? {
+            self.sync_group_info(portal).await?;
+        }
+        Ok(())
+    }
+
+    /// Tells the user about a friend request in their management room, with the command that
+    /// accepts it.
+    async fn handle_friend_request_event(&self, event: Event) -> anyhow::Result<()> {
+        let Some(room_id) = self.db.get_user_by_mxid(&event.mxid).await?.and_then(|u| u.management_room) else {
+            debug!("Dropping friend request {} for {} without a management room", event.id, event.mxid);
+            return Ok(());
+        };
+        let mut notice = format!("{} ({}) wants to be your WeChat friend", event.from.username, event.from.id);
+        if let Some(greeting) = event.content.as_deref().filter(|g| !g.is_empty()) {
+            notice.push_str(&format!(": \"{}\"", greeting));
+        }
+        notice.push_str(&format!(
+            ".\nAccept with `{} accept-friend {}`.",
+            self.command_processor().command_prefix(),
+            event.friend_request_ticket()
+        ));
+        self.get_matrix_client().send_notice(&room_id, notice).await?;
+        Ok(())
+    }
+
+    /// Opens the portal of a group the user was added to, or asks them to confirm the invite
+    /// with `join-group` when it needs confirmation.
+    async fn handle_group_invite_event(&self, event: Event) -> anyhow::Result<()> {
+        let group = event.chat.title.as_deref().unwrap_or(&event.chat.id);
+        if let Some(ticket) = event.group_invite_ticket() {
+            let Some(room_id) = self.db.get_user_by_mxid(&event.mxid).await?.and_then(|u| u.management_room) else {
+                debug!("Dropping group invite {} for {} without a management room", event.id, event.mxid);
+                return Ok(());
+            };
+            let notice = format!(
+                "{} invited you to the WeChat group {}.\nJoin with `{} join-group {}`.",
+                event.from.username,
+                group,
+                self.command_processor().command_prefix(),
+                ticket
+            );
+            self.get_matrix_client().send_notice(&room_id, notice).await?;
+            return Ok(());
+        }
+
+        let Some(uin) = self.own_uin(&event).await? else {
+            return Ok(());
+        };
+        let room_id = self.open_group_portal(&event.mxid, &PortalKey::new(event.chat.id.clone(), uin)).await?;
+        self.get_matrix_client()
+            .send_notice(&room_id, format!("{} invited you to the WeChat group {}.", event.from.username, group))
+            .await?;
+        info!("Opened portal {} of group {} {} was invited to", room_id, event.chat.id, event.mxid);
+        Ok(())
+    }
+
+    /// Kicks members that were removed from a WeChat group out of its portal rooms, including
+    /// the Matrix user when their own account was removed.
+    async fn handle_group_remove_event(&self, event: Event) -> anyhow::Result<()> {
+        let members: Vec<String> = event.data.as_ref()
+            .and_then(|d| d.get("members"))
+            .and_then(|m| serde_json::from_value(m.clone()).ok())
+            .unwrap_or_default();
+        let client = self.get_matrix_client();
+
+        for portal in self.db.get_portals_by_uid(&event.chat.id).await? {
+            let Some(room_id) = &portal.mxid else {
+                continue;
+            };
+            for member in &members {
+                let mxid = if *member == portal.receiver {
+                    match self.db.get_user_by_uin(member).await? {
+                        Some(user) => user.mxid,
+                        None => continue,
+                    }
+                } else {
+                    self.puppet_mxid(member)
+                };
+                match client.kick_user(room_id, &mxid, Some("Removed from the WeChat group")).await {
+                    Ok(()) => info!("Kicked {} from {} after removal from group {}", mxid, room_id, portal.uid),
+                    Err(e) => debug!("Failed to kick {} from {}: {}", mxid, room_id, e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The ghost a portal room is created with: the other side of a private chat, also when
+    /// the user sent the first message from another device, or the sender in a group.
+    fn portal_puppet_mxid(&self, event: &Event, key: &PortalKey) -> String {
+        if event.chat.chat_type == crate::wechat::ChatType::Private {
+            self.puppet_mxid(&key.uid)
+        } else {
+            self.puppet_mxid(&event.from.id)
+        }
+    }
+
+    fn room_puppet<'a>(&self, puppet_mxid: &'a str) -> Option<&'a str> {
+        if self.config.bridge.no_puppet.enabled {
+            None
+        } else {
+            Some(puppet_mxid)
+        }
+    }
+
+    /// Uploads a generated thumbnail and references it from the media `info`.
+    async fn attach_thumbnail(
+        &self,
+        client: &crate::matrix::client::MatrixClient,
+        info: &mut serde_json::Value,
+        thumbnail: anyhow::Result<crate::util::media::image::ConvertedImage>,
+        filename: &str,
+    ) {
+        let thumbnail = match thumbnail {
+            Ok(thumbnail) => thumbnail,
+            Err(e) => {
+                warn!("Failed to generate thumbnail for {}: {}", filename, e);
+                return;
+            }
+        };
+
+        let mimetype = thumbnail.format.mimetype();
+        let name = format!("thumbnail_{}.{}", filename, thumbnail.format.extension());
+        match self.media_cache.upload(client, &thumbnail.data, mimetype, &name).await {
+            Ok(mxc_url) => {
+                info["thumbnail_url"] = mxc_url.into();
+                info["thumbnail_info"] = serde_json::json!({
+                    "mimetype": mimetype,
+                    "size": thumbnail.data.len() as u64,
+                    "w": thumbnail.width,
+                    "h": thumbnail.height,
+                });
+            }
+            Err(e) => warn!("Failed to upload thumbnail for {}: {}", filename, e),
+        }
+    }
+
+    /// Names the sender in the message when it isn't sent by their own ghost: with puppets
+    /// disabled, and for messages the user sent from WeChat without a double puppet, which the
+    /// bot posts as a notice.
+    fn apply_sender_prefix(&self, event: &Event, intent: &Intent, content: &mut serde_json::Value) {
+        if event.from_self {
+            if !intent.is_double_puppet() {
+                if content.get("msgtype").and_then(|v| v.as_str()) == Some("m.text") {
+                    content["msgtype"] = "m.notice".into();
+                }
+                prefix_content(content, "You, from WeChat: ", "<b>You</b>, from WeChat: ");
+            }
+            return;
+        }
+        let no_puppet = &self.config.bridge.no_puppet;
+        if !no_puppet.enabled {
+            return;
+        }
+        if event.chat.chat_type == crate::wechat::ChatType::Private && !no_puppet.prefix_private_chats {
+            return;
+        }
+
+        let name = event.from.remark.as_deref()
+            .filter(|r| !r.is_empty())
+            .unwrap_or(&event.from.username);
+        let (text_prefix, html_prefix) = no_puppet.format_prefix(&event.from.id, name);
+        prefix_content(content, &text_prefix, &html_prefix);
+    }
+
+    /// Routes key sharing and verification to-device events to the crypto machine and sends
+    /// its replies from the bridge bot.
+    async fn process_to_device(&self, events: Vec<ToDeviceEvent>) {
+        let Some(crypto) = &self.crypto else {
+            return;
+        };
+        let bot_mxid = self.config.appservice.bot.mxid(&self.config.homeserver.domain);
+        let client = self.get_matrix_client();
+
+        for event in events {
+            // Appservices receive to-device events for all of their users, but only the bot has a device.
+            if event.to_user_id.as_deref().is_some_and(|to| to != bot_mxid) {
+                continue;
+            }
+            let (Some(sender), Some(content)) = (&event.sender, &event.content) else {
+                continue;
+            };
+            if event.event_type == "m.room_key_request" {
+                match self.can_share_room_key(crypto, &client, sender, content).await {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        warn!("Failed to check room key request from {}: {}", sender, e);
+                        continue;
+                    }
+                }
+            }
+
+            match crypto.receive_to_device(&event.event_type, sender, content).await {
+                Ok(Some(reply)) => {
+                    if let Err(e) = client.send_to_device(&reply.event_type, &reply.messages).await {
+                        warn!("Failed to send {} to {}: {}", reply.event_type, sender, e);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Failed to handle to-device {} from {}: {}", event.event_type, sender, e),
+            }
+        }
+    }
+
+    /// Room keys are only shared when key sharing is enabled and the requester is in the room,
+    /// after fetching the keys of the requesting device so the key can be encrypted for it.
+    async fn can_share_room_key(
+        &self,
+        crypto: &CryptoMachine,
+        client: &crate::matrix::client::MatrixClient,
+        sender: &str,
+        content: &serde_json::Value,
+    ) -> anyhow::Result<bool> {
+        if !self.config.bridge.encryption.allow_key_sharing {
+            debug!("Ignoring room key request from {}: key sharing is disabled", sender);
+            return Ok(false);
+        }
+        let (Some(room_id), Some(device_id)) = (
+            content.pointer("/body/room_id").and_then(|r| r.as_str()),
+            content.get("requesting_device_id").and_then(|d| d.as_str()),
+        ) else {
+            return Ok(false);
+        };
+        if !self.room_state.joined_members(client, room_id).await?.joined.contains_key(sender) {
+            debug!("Ignoring room key request from {}: not in {}", sender, room_id);
+            return Ok(false);
+        }
+
+        if !crypto.has_device_keys(sender, device_id).await? {
+            for (_, keys) in client.query_device_keys(sender).await? {
+                match serde_json::from_value::<DeviceKeys>(keys) {
+                    Ok(keys) => crypto.save_device_keys(&keys).await?,
+                    Err(e) => debug!("Skipping invalid device keys of {}: {}", sender, e),
+                }
+            }
+        }
+        Ok(true)
+    }
+
     pub fn command_processor(&self) -> &CommandProcessor {
         &self.command_processor
     }
+
+    pub fn admin_command_processor(&self) -> &AdminCommandProcessor {
+        &self.admin_command_processor
+    }
+
+    /// The permission level of a user, set with `set-permission` or else from the config.
+    pub fn get_permission(&self, mxid: &str) -> PermissionLevel {
+        let overrides = self.permission_overrides.read().unwrap_or_else(|e| e.into_inner());
+        overrides.get(mxid).copied()
+            .unwrap_or_else(|| self.config.bridge.get_permission(mxid))
+    }
+
+    /// Sets the permission level of a user, or with `None` goes back to the config.
+    pub async fn set_permission_override(&self, mxid: &str, level: Option<PermissionLevel>) -> anyhow::Result<()> {
+        match level {
+            Some(level) => {
+                self.db.upsert_permission_override(&crate::database::PermissionOverride {
+                    mxid: mxid.to_string(),
+                    level: level.as_str().to_string(),
+                }).await?;
+            }
+            None => self.db.delete_permission_override(mxid).await?,
+        }
+        let mut overrides = self.permission_overrides.write().unwrap_or_else(|e| e.into_inner());
+        match level {
+            Some(level) => overrides.insert(mxid.to_string(), level),
+            None => overrides.remove(mxid),
+        };
+        Ok(())
+    }
 }
 
 impl Clone for WechatBridge {
@@ -860,7 +3811,29 @@ impl Clone for WechatBridge {
             config: self.config.clone(),
             db: self.db.clone(),
             wechat_service: self.wechat_service.clone(),
+            backends: self.backends.clone(),
+            room_state: self.room_state.clone(),
+            http: self.http.clone(),
+            message_status: self.message_status.clone(),
+            send_retry: self.send_retry.clone(),
+            matrix_queue: self.matrix_queue.clone(),
+            connection_notifier: self.connection_notifier.clone(),
+            dedup: self.dedup.clone(),
+            event_acks: self.event_acks.clone(),
+            homeserver_status: self.homeserver_status.clone(),
+            activity: self.activity.clone(),
+            debug_log: self.debug_log.clone(),
+            media_cache: self.media_cache.clone(),
+            session_supervisor: self.session_supervisor.clone(),
+            crypto: self.crypto.clone(),
+            intents: self.intents.clone(),
             command_processor: self.command_processor.clone(),
+            admin_command_processor: self.admin_command_processor.clone(),
+            permission_overrides: self.permission_overrides.clone(),
+            shutdown: self.shutdown.clone(),
+            tasks: self.tasks.clone(),
+            transactions: self.transactions.clone(),
+            transaction_queue: self.transaction_queue.clone(),
             users_by_mxid: RwLock::new(HashMap::new()),
             users_by_uin: RwLock::new(HashMap::new()),
             portals_by_key: RwLock::new(HashMap::new()),
@@ -872,16 +3845,37 @@ impl Clone for WechatBridge {
 }
 
 impl AppServiceBridge for WechatBridge {
-    fn handle_transaction(&self, _txn_id: &str, events: Vec<RoomEvent>) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + '_>> {
-        Box::pin(async move {
-            let handler = crate::matrix::event_handler::MatrixEventHandler::new(Arc::new(self.clone()));
-            for event in events {
-                if let Err(e) = handler.handle_event(&event).await {
-                    warn!("Error handling event: {}", e);
+    fn handle_transaction(&self, txn_id: &str, events: Vec<RoomEvent>) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + '_>> {
+        let txn_id = txn_id.to_string();
+        Box::pin(self.tasks.track_future(async move {
+            self.debug_log.record_transaction(&txn_id, &events).await;
+            if !txn_id.is_empty() && self.transactions.is_processed(&txn_id).await? {
+                debug!("Skipping already handled transaction {}", txn_id);
+                return Ok(());
+            }
+
+            if self.config.appservice.async_transactions {
+                if self.transaction_queue.len().await < TRANSACTION_QUEUE_CAPACITY {
+                    self.transaction_queue.push(QueueMessage::new(txn_id, events)).await?;
+                    return Ok(());
                 }
+                warn!("Transaction queue is full, handling {} inline", txn_id);
             }
-            Ok(())
-        })
+            self.process_transaction(&txn_id, events).await
+        }))
+    }
+
+    fn query_room_alias(&self, alias: &str) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>> + Send + '_>> {
+        let alias = alias.to_string();
+        Box::pin(async move { self.handle_alias_query(&alias).await })
+    }
+
+    fn handle_to_device(&self, events: Vec<ToDeviceEvent>) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(self.tasks.track_future(self.process_to_device(events)))
+    }
+
+    fn is_stopping(&self) -> bool {
+        self.shutdown.is_cancelled()
     }
 
     fn is_user_in_namespace(&self, mxid: &str) -> bool {
@@ -889,3 +3883,37 @@ impl AppServiceBridge for WechatBridge {
         mxid.starts_with(&prefix)
     }
 }
+
+/// The row of a WeChat message about to be sent to Matrix by `intent`, see
+/// [`WechatBridge::record_send`].
+fn pending_message(key: &PortalKey, event: &Event, intent: &Intent) -> DbMessage {
+    DbMessage {
+        chat_uid: key.uid.clone(),
+        chat_receiver: key.receiver.clone(),
+        msg_id: event.id.clone(),
+        mxid: DbMessage::pending_mxid(&event.id),
+        sender: intent.mxid().to_string(),
+        timestamp: event.timestamp,
+        sent: false,
+        error: None,
+        msg_type: String::new(),
+    }
+}
+
+/// Puts a sender prefix before a message's text, or the caption of a file.
+fn prefix_content(content: &mut serde_json::Value, text_prefix: &str, html_prefix: &str) {
+    let body = content.get("body").and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let msgtype = content.get("msgtype").and_then(|v| v.as_str()).unwrap_or("");
+    if matches!(msgtype, "m.image" | "m.video" | "m.audio" | "m.file") && content.get("filename").is_none() {
+        content["filename"] = body.clone().into();
+    }
+
+    let html = content.get("formatted_body")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| crate::formatter::escape_html(&body));
+
+    content["body"] = format!("{}{}", text_prefix, body).into();
+    content["format"] = "org.matrix.custom.html".into();
+    content["formatted_body"] = format!("{}{}", html_prefix, html).into();
+}
@@ -67,6 +67,37 @@ fn parse_duration(s: &str) -> Result<Duration, anyhow::Error> {
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplayProtectionConfig {
+    #[serde(default = "default_replay_protection_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_replay_tolerance")]
+    pub tolerance: String,
+}
+
+fn default_replay_protection_enabled() -> bool {
+    true
+}
+
+fn default_replay_tolerance() -> String {
+    "10s".to_string()
+}
+
+impl Default for ReplayProtectionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_replay_protection_enabled(),
+            tolerance: default_replay_tolerance(),
+        }
+    }
+}
+
+impl ReplayProtectionConfig {
+    pub fn tolerance_duration(&self) -> Duration {
+        parse_duration(&self.tolerance).unwrap_or(Duration::from_secs(10))
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ManagementRoomTexts {
     #[serde(default = "default_welcome")]
@@ -102,13 +133,510 @@ impl Default for ManagementRoomTexts {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct NoPuppetConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub prefix_private_chats: bool,
+    #[serde(default = "default_no_puppet_text_template")]
+    pub text_template: String,
+    #[serde(default = "default_no_puppet_html_template")]
+    pub html_template: String,
+}
+
+fn default_no_puppet_text_template() -> String {
+    "{{.Avatar}} {{.Name}}: ".to_string()
+}
+
+fn default_no_puppet_html_template() -> String {
+    "{{.Avatar}} <strong>{{.Name}}</strong>: ".to_string()
+}
+
+impl Default for NoPuppetConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            prefix_private_chats: false,
+            text_template: default_no_puppet_text_template(),
+            html_template: default_no_puppet_html_template(),
+        }
+    }
+}
+
+impl NoPuppetConfig {
+    pub fn format_prefix(&self, uin: &str, name: &str) -> (String, String) {
+        let avatar = crate::formatter::emoji::avatar_emoji(uin);
+        let name = if name.is_empty() { uin } else { name };
+
+        let text = self
+            .text_template
+            .replace("{{.Avatar}}", avatar)
+            .replace("{{.Name}}", name)
+            .replace("{{.Uin}}", uin);
+        let html = self
+            .html_template
+            .replace("{{.Avatar}}", avatar)
+            .replace("{{.Name}}", &crate::formatter::escape_html(name))
+            .replace("{{.Uin}}", &crate::formatter::escape_html(uin));
+
+        (text, html)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MessageMarkerConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default = "default_message_marker_suffix")]
+    pub suffix: String,
+}
+
+fn default_message_marker_suffix() -> String {
+    " [via Matrix]".to_string()
+}
+
+impl Default for MessageMarkerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            prefix: String::new(),
+            suffix: default_message_marker_suffix(),
+        }
+    }
+}
+
+impl MessageMarkerConfig {
+    pub fn format(&self, mxid: &str) -> (String, String) {
+        let prefix = self.prefix.replace("{{.MXID}}", mxid);
+        let suffix = self.suffix.replace("{{.MXID}}", mxid);
+        (prefix, suffix)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UnsupportedMessagesConfig {
+    #[serde(default = "default_unsupported_notices")]
+    pub notices: bool,
+    #[serde(default)]
+    pub suppress: Vec<String>,
+}
+
+fn default_unsupported_notices() -> bool {
+    true
+}
+
+impl Default for UnsupportedMessagesConfig {
+    fn default() -> Self {
+        Self {
+            notices: default_unsupported_notices(),
+            suppress: Vec::new(),
+        }
+    }
+}
+
+impl UnsupportedMessagesConfig {
+    pub fn should_notify(&self, event_type: &str) -> bool {
+        self.notices && !self.suppress.iter().any(|t| t == event_type)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RelayConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_relay_admin_only")]
+    pub admin_only: bool,
+    #[serde(default = "default_relay_message_formats")]
+    pub message_formats: HashMap<String, String>,
+}
+
+fn default_relay_admin_only() -> bool {
+    true
+}
+
+fn default_relay_message_formats() -> HashMap<String, String> {
+    [
+        ("m.text", "{{.Sender.Displayname}}: {{.Message}}"),
+        ("m.notice", "{{.Sender.Displayname}}: {{.Message}}"),
+        ("m.emote", "* {{.Sender.Displayname}} {{.Message}}"),
+        ("m.image", "{{.Sender.Displayname}} sent an image"),
+        ("m.video", "{{.Sender.Displayname}} sent a video"),
+        ("m.audio", "{{.Sender.Displayname}} sent an audio file"),
+        ("m.file", "{{.Sender.Displayname}} sent a file"),
+        ("m.sticker", "{{.Sender.Displayname}} sent a sticker"),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect()
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            admin_only: default_relay_admin_only(),
+            message_formats: default_relay_message_formats(),
+        }
+    }
+}
+
+impl RelayConfig {
+    /// Formats a message relayed on behalf of a Matrix user, falling back to the m.text format.
+    pub fn format_message(&self, msgtype: &str, displayname: &str, mxid: &str, body: &str) -> String {
+        let template = self.message_formats.get(msgtype)
+            .or_else(|| self.message_formats.get("m.text"))
+            .map(String::as_str)
+            .unwrap_or("{{.Sender.Displayname}}: {{.Message}}");
+        let displayname = if displayname.is_empty() { mxid } else { displayname };
+
+        template
+            .replace("{{.Sender.Displayname}}", displayname)
+            .replace("{{.Sender.UserID}}", mxid)
+            .replace("{{.Message}}", body)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProvisioningConfig {
+    #[serde(default = "default_provisioning_prefix")]
+    pub prefix: String,
+    #[serde(default)]
+    pub shared_secret: String,
+}
+
+fn default_provisioning_prefix() -> String {
+    "/_matrix/provision".to_string()
+}
+
+impl Default for ProvisioningConfig {
+    fn default() -> Self {
+        Self {
+            prefix: default_provisioning_prefix(),
+            shared_secret: String::new(),
+        }
+    }
+}
+
+impl ProvisioningConfig {
+    /// The provisioning API is only served when a shared secret is configured.
+    pub fn enabled(&self) -> bool {
+        !self.shared_secret.is_empty() && self.shared_secret != "disable"
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SendRetryConfig {
+    #[serde(default = "default_send_retry_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_send_retry_initial_delay")]
+    pub initial_delay: String,
+    #[serde(default = "default_send_retry_max_delay")]
+    pub max_delay: String,
+}
+
+fn default_send_retry_max_retries() -> u32 {
+    5
+}
+
+fn default_send_retry_initial_delay() -> String {
+    "10s".to_string()
+}
+
+fn default_send_retry_max_delay() -> String {
+    "10m".to_string()
+}
+
+fn default_matrix_send_retry() -> SendRetryConfig {
+    SendRetryConfig {
+        max_retries: 10,
+        ..SendRetryConfig::default()
+    }
+}
+
+/// Endpoints under `/_matrix/app/v1/debug/` for inspecting recent traffic and replaying
+/// WeChat events, for diagnosing lost messages.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DebugApiConfig {
+    /// Sent as `Authorization: Bearer <secret>`. The debug API is disabled when empty or
+    /// `disable`.
+    #[serde(default)]
+    pub shared_secret: String,
+    /// How many WeChat events and transactions are kept for inspection.
+    #[serde(default = "default_debug_history_size")]
+    pub history_size: usize,
+}
+
+fn default_debug_history_size() -> usize {
+    100
+}
+
+impl Default for DebugApiConfig {
+    fn default() -> Self {
+        Self {
+            shared_secret: String::new(),
+            history_size: default_debug_history_size(),
+        }
+    }
+}
+
+impl DebugApiConfig {
+    pub fn enabled(&self) -> bool {
+        !self.shared_secret.is_empty() && self.shared_secret != "disable"
+    }
+}
+
+/// Limits on the size of media bridged, in bytes. `0` means no limit.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MediaConfig {
+    /// WeChat media larger than this isn't downloaded or uploaded to Matrix.
+    #[serde(default = "default_max_incoming_bytes")]
+    pub max_incoming_bytes: u64,
+    /// Matrix media larger than this isn't sent to WeChat.
+    #[serde(default = "default_max_outgoing_bytes")]
+    pub max_outgoing_bytes: u64,
+    /// Images sent to WeChat are scaled down to fit within this many pixels wide and high.
+    #[serde(default = "default_max_image_resolution")]
+    pub max_image_resolution: u32,
+}
+
+fn default_max_incoming_bytes() -> u64 {
+    50 * 1024 * 1024
+}
+
+fn default_max_outgoing_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_max_image_resolution() -> u32 {
+    4096
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        Self {
+            max_incoming_bytes: default_max_incoming_bytes(),
+            max_outgoing_bytes: default_max_outgoing_bytes(),
+            max_image_resolution: default_max_image_resolution(),
+        }
+    }
+}
+
+impl MediaConfig {
+    pub fn incoming_limit(&self) -> Option<u64> {
+        (self.max_incoming_bytes > 0).then_some(self.max_incoming_bytes)
+    }
+
+    pub fn outgoing_limit(&self) -> Option<u64> {
+        (self.max_outgoing_bytes > 0).then_some(self.max_outgoing_bytes)
+    }
+
+    pub fn image_resolution_limit(&self) -> Option<u32> {
+        (self.max_image_resolution > 0).then_some(self.max_image_resolution)
+    }
+}
+
+/// Periodic cleanup of portal rooms nobody uses any more.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PortalGcConfig {
+    /// How often to look for unused portals. `0` disables the cleanup.
+    #[serde(default = "default_portal_gc_interval")]
+    pub interval: String,
+    /// Only report the portals that would be cleaned up to the admin room.
+    #[serde(default = "default_portal_gc_dry_run")]
+    pub dry_run: bool,
+    /// Also clean up portals whose contact or group is missing from the account's lists.
+    #[serde(default = "default_portal_gc_check_chats")]
+    pub check_chats: bool,
+}
+
+fn default_portal_gc_interval() -> String {
+    "0".to_string()
+}
+
+fn default_portal_gc_dry_run() -> bool {
+    true
+}
+
+fn default_portal_gc_check_chats() -> bool {
+    true
+}
+
+impl Default for PortalGcConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_portal_gc_interval(),
+            dry_run: default_portal_gc_dry_run(),
+            check_chats: default_portal_gc_check_chats(),
+        }
+    }
+}
+
+impl PortalGcConfig {
+    /// Returns `None` when portals aren't cleaned up.
+    pub fn interval_duration(&self) -> Option<Duration> {
+        parse_duration(&self.interval).ok().filter(|d| !d.is_zero())
+    }
+}
+
+/// How WeChat agents authenticate, besides the shared `listen_secret`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AgentAuthConfig {
+    /// Agents must sign a challenge with their token instead of sending the token itself. The
+    /// shared secret can't be used then.
+    #[serde(default)]
+    pub require_challenge: bool,
+}
+
+/// Budgets for requests to the homeserver, so bursts such as group syncs are spread out
+/// instead of being rejected.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitsConfig {
+    /// How many times a request rejected with `M_LIMIT_EXCEEDED` is retried.
+    #[serde(default = "default_rate_limit_max_retries")]
+    pub max_retries: u32,
+    /// Budgets by endpoint group: `send`, `state`, `membership`, `profile` and `media`.
+    /// Groups without a budget share the `default` one; without that, they aren't limited.
+    #[serde(default = "default_rate_limit_endpoints")]
+    pub endpoints: HashMap<String, RateLimitBudget>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct RateLimitBudget {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+fn default_rate_limit_max_retries() -> u32 {
+    5
+}
+
+fn default_rate_limit_endpoints() -> HashMap<String, RateLimitBudget> {
+    [
+        ("send", 10.0, 50),
+        ("state", 5.0, 20),
+        ("membership", 5.0, 20),
+        ("profile", 2.0, 10),
+        ("media", 5.0, 20),
+        ("default", 20.0, 100),
+    ]
+    .into_iter()
+    .map(|(name, per_second, burst)| (name.to_string(), RateLimitBudget { per_second, burst }))
+    .collect()
+}
+
+impl Default for RateLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: default_rate_limit_max_retries(),
+            endpoints: default_rate_limit_endpoints(),
+        }
+    }
+}
+
+impl Default for SendRetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: default_send_retry_max_retries(),
+            initial_delay: default_send_retry_initial_delay(),
+            max_delay: default_send_retry_max_delay(),
+        }
+    }
+}
+
+impl SendRetryConfig {
+    /// How long to wait before retry number `retry_num`, or `None` once retries are exhausted.
+    pub fn delay(&self, retry_num: u32) -> Option<Duration> {
+        if retry_num >= self.max_retries {
+            return None;
+        }
+        let initial = parse_duration(&self.initial_delay).unwrap_or(Duration::from_secs(10));
+        let max = parse_duration(&self.max_delay).unwrap_or(Duration::from_secs(600));
+        Some(initial.saturating_mul(2u32.saturating_pow(retry_num)).min(max))
+    }
+}
+
+/// An agent that can't hold a connection open. It posts its events to `/wechat/v1/events` on
+/// `listen_address` and is sent requests at `url`, both signed with `secret`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HttpAgentConfig {
+    /// Sent as `X-Agent-Id`, to tell which secret a webhook is signed with.
+    pub id: String,
+    pub url: String,
+    pub secret: String,
+    /// The Matrix users whose WeChat accounts the agent serves.
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+/// How agents connect to `listen_address`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentTransport {
+    /// JSON messages over a websocket.
+    #[default]
+    Websocket,
+    /// The same messages over a gRPC stream, see `proto/agent.proto`.
+    Grpc,
+}
+
+/// What a Matrix user may do with the bridge, ordered from least to most access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum PermissionLevel {
+    /// Messages and commands are ignored.
+    Block,
+    /// Messages are bridged through the portal's relay user, only basic commands are allowed.
+    Relay,
+    /// Can log in with their own WeChat account and use the regular commands.
     User,
+    /// Can additionally enable double puppeting.
+    Puppeting,
+    /// Can use every command, including ones affecting other users' portals.
     Admin,
 }
 
+impl PermissionLevel {
+    pub fn can_relay(&self) -> bool {
+        *self >= Self::Relay
+    }
+
+    pub fn can_use(&self) -> bool {
+        *self >= Self::User
+    }
+
+    pub fn is_admin(&self) -> bool {
+        *self == Self::Admin
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Block => "block",
+            Self::Relay => "relay",
+            Self::User => "user",
+            Self::Puppeting => "puppeting",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+impl std::str::FromStr for PermissionLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "block" => Ok(Self::Block),
+            "relay" => Ok(Self::Relay),
+            "user" => Ok(Self::User),
+            "puppeting" => Ok(Self::Puppeting),
+            "admin" => Ok(Self::Admin),
+            _ => anyhow::bail!("unknown permission level {}", s),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct BridgeConfig {
     #[serde(default)]
@@ -116,8 +644,20 @@ pub struct BridgeConfig {
 
     pub username_template: String,
     pub displayname_template: String,
+    #[serde(default = "default_alias_template")]
+    pub alias_template: String,
     pub listen_address: String,
     pub listen_secret: String,
+    #[serde(default)]
+    pub agent_transport: AgentTransport,
+    #[serde(default)]
+    pub http_agents: Vec<HttpAgentConfig>,
+    #[serde(default)]
+    pub agent_auth: AgentAuthConfig,
+    #[serde(default = "default_agent_ping_interval")]
+    pub agent_ping_interval: String,
+    #[serde(default = "default_agent_ping_timeout")]
+    pub agent_ping_timeout: String,
 
     #[serde(default = "default_user_prefix")]
     pub user_prefix: String,
@@ -131,12 +671,28 @@ pub struct BridgeConfig {
     pub message_error_notices: bool,
     #[serde(default = "default_portal_message_buffer")]
     pub portal_message_buffer: usize,
+    #[serde(default = "default_event_handling_concurrency")]
+    pub event_handling_concurrency: usize,
+    #[serde(default = "default_room_state_cache_ttl")]
+    pub room_state_cache_ttl: String,
+    #[serde(default = "default_timestamp_massaging")]
+    pub timestamp_massaging: bool,
+    #[serde(default)]
+    pub call_events: bool,
+    #[serde(default = "default_url_previews")]
+    pub url_previews: bool,
+    #[serde(default)]
+    pub emoji_map_path: Option<String>,
 
     #[serde(default)]
     pub allow_redaction: bool,
 
     #[serde(default = "default_user_avatar_sync")]
     pub user_avatar_sync: bool,
+    #[serde(default = "default_media_cache_ttl")]
+    pub media_cache_ttl: String,
+    #[serde(default)]
+    pub media: MediaConfig,
 
     #[serde(default)]
     pub sync_direct_chat_list: bool,
@@ -144,6 +700,8 @@ pub struct BridgeConfig {
     pub default_bridge_presence: bool,
     #[serde(default)]
     pub send_presence_on_typing: bool,
+    #[serde(default)]
+    pub presence: bool,
 
     #[serde(default)]
     pub double_puppet_server_map: HashMap<String, String>,
@@ -156,6 +714,16 @@ pub struct BridgeConfig {
     pub private_chat_portal_meta: String,
     #[serde(default)]
     pub parallel_member_sync: bool,
+    #[serde(default = "default_puppet_warmup_concurrency")]
+    pub puppet_warmup_concurrency: usize,
+    #[serde(default = "default_member_sync_interval")]
+    pub member_sync_interval: String,
+    #[serde(default = "default_contact_sync_interval")]
+    pub contact_sync_interval: String,
+    #[serde(default = "default_group_info_sync_interval")]
+    pub group_info_sync_interval: String,
+    #[serde(default = "default_user_existence_check_interval")]
+    pub user_existence_check_interval: String,
     #[serde(default)]
     pub resend_bridge_info: bool,
     #[serde(default)]
@@ -170,16 +738,54 @@ pub struct BridgeConfig {
 
     #[serde(default)]
     pub disable_bridge_alerts: bool,
+    #[serde(default = "default_agent_offline_alert")]
+    pub agent_offline_alert: String,
+    #[serde(default = "default_session_check_interval")]
+    pub session_check_interval: String,
 
     #[serde(default = "default_command_prefix")]
     pub command_prefix: String,
 
+    #[serde(default)]
+    pub admin_room: Option<String>,
+
     #[serde(default)]
     pub management_room_text: ManagementRoomTexts,
 
     #[serde(default)]
     pub encryption: EncryptionConfig,
 
+    #[serde(default)]
+    pub no_puppet: NoPuppetConfig,
+
+    #[serde(default)]
+    pub message_marker: MessageMarkerConfig,
+
+    #[serde(default)]
+    pub replay_protection: ReplayProtectionConfig,
+
+    #[serde(default)]
+    pub unsupported_messages: UnsupportedMessagesConfig,
+
+    #[serde(default)]
+    pub relay: RelayConfig,
+
+    #[serde(default)]
+    pub provisioning: ProvisioningConfig,
+    #[serde(default)]
+    pub debug_api: DebugApiConfig,
+
+    #[serde(default)]
+    pub portal_gc: PortalGcConfig,
+
+    #[serde(default)]
+    pub send_retry: SendRetryConfig,
+    #[serde(default = "default_matrix_send_retry")]
+    pub matrix_send_retry: SendRetryConfig,
+
+    #[serde(default)]
+    pub rate_limits: RateLimitsConfig,
+
     pub permissions: HashMap<String, PermissionLevel>,
 }
 
@@ -191,10 +797,66 @@ fn default_portal_message_buffer() -> usize {
     128
 }
 
+fn default_member_sync_interval() -> String {
+    "6h".to_string()
+}
+
+fn default_agent_ping_interval() -> String {
+    "15s".to_string()
+}
+
+fn default_agent_ping_timeout() -> String {
+    "45s".to_string()
+}
+
+fn default_agent_offline_alert() -> String {
+    "5m".to_string()
+}
+
+fn default_session_check_interval() -> String {
+    "5m".to_string()
+}
+
+fn default_contact_sync_interval() -> String {
+    "6h".to_string()
+}
+
+fn default_group_info_sync_interval() -> String {
+    "6h".to_string()
+}
+
+fn default_user_existence_check_interval() -> String {
+    "24h".to_string()
+}
+
+fn default_event_handling_concurrency() -> usize {
+    16
+}
+
+fn default_puppet_warmup_concurrency() -> usize {
+    8
+}
+
+fn default_room_state_cache_ttl() -> String {
+    "5m".to_string()
+}
+
+fn default_timestamp_massaging() -> bool {
+    true
+}
+
+fn default_url_previews() -> bool {
+    true
+}
+
 fn default_user_avatar_sync() -> bool {
     true
 }
 
+fn default_media_cache_ttl() -> String {
+    "720h".to_string()
+}
+
 fn default_private_chat_portal_meta() -> String {
     "default".to_string()
 }
@@ -207,11 +869,80 @@ fn default_command_prefix() -> String {
     "!wechat".to_string()
 }
 
+fn default_alias_template() -> String {
+    "wechat_{{.}}".to_string()
+}
+
 fn default_user_prefix() -> String {
     "wechat_".to_string()
 }
 
 impl BridgeConfig {
+    /// Returns `None` when periodic member sync is disabled.
+    pub fn member_sync_interval_duration(&self) -> Option<Duration> {
+        parse_duration(&self.member_sync_interval)
+            .ok()
+            .filter(|d| !d.is_zero())
+    }
+
+    /// Returns `None` when agents aren't pinged, so dead connections are only noticed when
+    /// sending to them fails.
+    pub fn agent_ping_interval_duration(&self) -> Option<Duration> {
+        parse_duration(&self.agent_ping_interval)
+            .ok()
+            .filter(|d| !d.is_zero())
+    }
+
+    pub fn agent_ping_timeout_duration(&self) -> Duration {
+        parse_duration(&self.agent_ping_timeout).unwrap_or(Duration::from_secs(45))
+    }
+
+    /// Returns `None` when users aren't reminded of agents that stay disconnected.
+    pub fn agent_offline_alert_duration(&self) -> Option<Duration> {
+        parse_duration(&self.agent_offline_alert)
+            .ok()
+            .filter(|d| !d.is_zero())
+    }
+
+    /// Returns `None` when WeChat sessions aren't checked in the background.
+    pub fn session_check_interval_duration(&self) -> Option<Duration> {
+        parse_duration(&self.session_check_interval)
+            .ok()
+            .filter(|d| !d.is_zero())
+    }
+
+    /// Returns `None` when periodic contact sync is disabled.
+    pub fn contact_sync_interval_duration(&self) -> Option<Duration> {
+        parse_duration(&self.contact_sync_interval)
+            .ok()
+            .filter(|d| !d.is_zero())
+    }
+
+    /// Returns `None` when periodic group info sync is disabled.
+    pub fn group_info_sync_interval_duration(&self) -> Option<Duration> {
+        parse_duration(&self.group_info_sync_interval)
+            .ok()
+            .filter(|d| !d.is_zero())
+    }
+
+    /// Returns `None` when users aren't checked for deactivated Matrix accounts in the background.
+    pub fn user_existence_check_interval_duration(&self) -> Option<Duration> {
+        parse_duration(&self.user_existence_check_interval)
+            .ok()
+            .filter(|d| !d.is_zero())
+    }
+
+    /// Returns `None` when uploads aren't deduplicated.
+    pub fn media_cache_ttl_duration(&self) -> Option<Duration> {
+        parse_duration(&self.media_cache_ttl)
+            .ok()
+            .filter(|d| !d.is_zero())
+    }
+
+    pub fn room_state_cache_ttl_duration(&self) -> Duration {
+        parse_duration(&self.room_state_cache_ttl).unwrap_or(Duration::from_secs(300))
+    }
+
     pub fn get_permission(&self, mxid: &str) -> PermissionLevel {
         if let Some(level) = self.permissions.get(mxid) {
             return *level;
@@ -227,23 +958,12 @@ impl BridgeConfig {
         self.permissions
             .get("*")
             .copied()
-            .unwrap_or(PermissionLevel::User)
+            .unwrap_or(PermissionLevel::Block)
     }
 
     pub fn format_displayname(&self, uin: &str, name: &str, remark: &str) -> (String, i8) {
-        let displayname = if !name.is_empty() {
-            name.to_string()
-        } else if !remark.is_empty() {
-            remark.to_string()
-        } else {
-            uin.to_string()
-        };
-
-        let result = self
-            .displayname_template
-            .replace("{{.Name}}", name)
-            .replace("{{.Uin}}", uin)
-            .replace("{{.Remark}}", remark);
+        let result = render_displayname_template(&self.displayname_template, uin, name, remark)
+            .unwrap_or_else(|_| fallback_displayname(uin, name, remark).to_string());
 
         let quality = if !name.is_empty() {
             NAME_QUALITY_NAME
@@ -254,3 +974,160 @@ impl BridgeConfig {
         (result, quality)
     }
 }
+
+fn fallback_displayname<'a>(uin: &'a str, name: &'a str, remark: &'a str) -> &'a str {
+    if !name.is_empty() {
+        name
+    } else if !remark.is_empty() {
+        remark
+    } else {
+        uin
+    }
+}
+
+/// A parsed piece of a displayname template.
+///
+/// Templates use a small subset of Go template syntax, as in mautrix bridges:
+/// `{{.Var}}`, `{{if .Var}}...{{else if .Var}}...{{else}}...{{end}}`.
+#[derive(Debug, Clone)]
+enum TemplateNode {
+    Text(String),
+    Var(String),
+    If {
+        branches: Vec<(String, Vec<TemplateNode>)>,
+        otherwise: Vec<TemplateNode>,
+    },
+}
+
+const TEMPLATE_VARS: &[&str] = &["Name", "Nickname", "Remark", "Alias", "Uin", "ID", "Wxid"];
+
+/// Renders a displayname template for a WeChat contact.
+///
+/// `.Name`/`.Nickname` fall back to the remark and then the WeChat ID when the
+/// contact has no nickname, so a template never renders an empty name.
+pub fn render_displayname_template(template: &str, uin: &str, name: &str, remark: &str) -> Result<String, anyhow::Error> {
+    let nodes = parse_template(template)?;
+    let vars = TemplateVars { uin, name, remark };
+    let mut out = String::new();
+    render_nodes(&nodes, &vars, &mut out);
+    Ok(out)
+}
+
+struct TemplateVars<'a> {
+    uin: &'a str,
+    name: &'a str,
+    remark: &'a str,
+}
+
+impl<'a> TemplateVars<'a> {
+    fn get(&self, var: &str) -> &'a str {
+        match var {
+            "Name" | "Nickname" => fallback_displayname(self.uin, self.name, self.remark),
+            "Remark" | "Alias" => self.remark,
+            _ => self.uin,
+        }
+    }
+}
+
+pub fn validate_displayname_template(template: &str) -> Result<(), anyhow::Error> {
+    parse_template(template).map(|_| ())
+}
+
+fn parse_template(template: &str) -> Result<Vec<TemplateNode>, anyhow::Error> {
+    let mut tokens = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        if start > 0 {
+            tokens.push((false, &rest[..start]));
+        }
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| anyhow::anyhow!("unclosed {{{{ in template"))?;
+        tokens.push((true, rest[start + 2..start + end].trim()));
+        rest = &rest[start + end + 2..];
+    }
+    if !rest.is_empty() {
+        tokens.push((false, rest));
+    }
+
+    let mut pos = 0;
+    let (nodes, terminator) = parse_nodes(&tokens, &mut pos)?;
+    if let Some(terminator) = terminator {
+        anyhow::bail!("unexpected {{{{{}}}}} in template", terminator);
+    }
+    Ok(nodes)
+}
+
+fn parse_nodes<'a>(
+    tokens: &[(bool, &'a str)],
+    pos: &mut usize,
+) -> Result<(Vec<TemplateNode>, Option<&'a str>), anyhow::Error> {
+    let mut nodes = Vec::new();
+    while let Some(&(is_action, token)) = tokens.get(*pos) {
+        *pos += 1;
+        if !is_action {
+            nodes.push(TemplateNode::Text(token.to_string()));
+        } else if token == "end" || token == "else" || token.starts_with("else if ") {
+            return Ok((nodes, Some(token)));
+        } else if let Some(cond) = token.strip_prefix("if ") {
+            let mut branches = vec![(parse_var(cond)?, Vec::new())];
+            let mut otherwise = Vec::new();
+            loop {
+                let (body, terminator) = parse_nodes(tokens, pos)?;
+                match terminator {
+                    Some("end") => {
+                        branches.last_mut().unwrap().1 = body;
+                        break;
+                    }
+                    Some("else") => {
+                        branches.last_mut().unwrap().1 = body;
+                        let (body, terminator) = parse_nodes(tokens, pos)?;
+                        if terminator != Some("end") {
+                            anyhow::bail!("expected {{{{end}}}} after {{{{else}}}} in template");
+                        }
+                        otherwise = body;
+                        break;
+                    }
+                    Some(other) => {
+                        branches.last_mut().unwrap().1 = body;
+                        let cond = other.trim_start_matches("else if ");
+                        branches.push((parse_var(cond)?, Vec::new()));
+                    }
+                    None => anyhow::bail!("missing {{{{end}}}} in template"),
+                }
+            }
+            nodes.push(TemplateNode::If { branches, otherwise });
+        } else {
+            nodes.push(TemplateNode::Var(parse_var(token)?));
+        }
+    }
+    Ok((nodes, None))
+}
+
+fn parse_var(token: &str) -> Result<String, anyhow::Error> {
+    let var = token
+        .trim()
+        .strip_prefix('.')
+        .ok_or_else(|| anyhow::anyhow!("unsupported template action: {}", token))?;
+    if !TEMPLATE_VARS.contains(&var) {
+        anyhow::bail!("unknown template variable: .{}", var);
+    }
+    Ok(var.to_string())
+}
+
+fn render_nodes(nodes: &[TemplateNode], vars: &TemplateVars, out: &mut String) {
+    for node in nodes {
+        match node {
+            TemplateNode::Text(text) => out.push_str(text),
+            TemplateNode::Var(var) => out.push_str(vars.get(var)),
+            TemplateNode::If { branches, otherwise } => {
+                let body = branches
+                    .iter()
+                    .find(|(cond, _)| !vars.get(cond).is_empty())
+                    .map(|(_, body)| body)
+                    .unwrap_or(otherwise);
+                render_nodes(body, vars, out);
+            }
+        }
+    }
+}
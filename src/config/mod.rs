@@ -20,6 +20,14 @@ pub struct HomeserverConfig {
     pub websocket: bool,
     #[serde(default)]
     pub ping_interval_seconds: u64,
+    /// How often to check that the bridge and the homeserver can reach each other, `0` to only
+    /// check at startup.
+    #[serde(default = "default_connectivity_check_interval_seconds")]
+    pub connectivity_check_interval_seconds: u64,
+}
+
+fn default_connectivity_check_interval_seconds() -> u64 {
+    300
 }
 
 fn default_software() -> String {
@@ -78,6 +86,32 @@ pub struct AppServiceConfig {
     pub async_transactions: bool,
     pub as_token: String,
     pub hs_token: String,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Octal mode of the socket file when listening on a Unix socket, such as `660`.
+    #[serde(default)]
+    pub socket_permissions: Option<String>,
+}
+
+impl AppServiceConfig {
+    /// Where the appservice listens for the homeserver: `host:port`, or a `unix://` socket URI
+    /// when either `hostname` or `address` is one.
+    pub fn listen_address(&self) -> String {
+        if self.hostname.starts_with("unix://") {
+            return self.hostname.clone();
+        }
+        if self.address.starts_with("unix://") {
+            return self.address.clone();
+        }
+        format!("{}:{}", self.hostname, self.port)
+    }
+}
+
+/// A PEM certificate chain and private key to serve the appservice over HTTPS.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -98,6 +132,53 @@ pub struct LoggingWriterConfig {
 pub struct LoggingConfig {
     pub min_level: String,
     pub writers: Vec<LoggingWriterConfig>,
+    #[serde(default)]
+    pub sentry: SentryConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(feature = "sentry"), allow(dead_code))]
+pub struct SentryConfig {
+    #[serde(default)]
+    pub dsn: Option<String>,
+    #[serde(default)]
+    pub environment: Option<String>,
+    #[serde(default = "default_sentry_sample_rate")]
+    pub sample_rate: f32,
+}
+
+fn default_sentry_sample_rate() -> f32 {
+    1.0
+}
+
+impl Default for SentryConfig {
+    fn default() -> Self {
+        Self {
+            dsn: None,
+            environment: None,
+            sample_rate: default_sentry_sample_rate(),
+        }
+    }
+}
+
+/// A WeCom (企业微信) app whose chats are bridged for one Matrix user. The bridge logs in with the
+/// corp ID and the app's secret, and receives messages on `/wecom/v1/callback`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WecomConfig {
+    /// The Matrix user the app's chats are bridged for.
+    pub mxid: String,
+    pub corp_id: String,
+    pub corp_secret: String,
+    pub agent_id: i64,
+    /// The `Token` and `EncodingAESKey` of the app's API receiving settings.
+    pub token: String,
+    pub encoding_aes_key: String,
+    #[serde(default = "default_wecom_api_url")]
+    pub api_url: String,
+}
+
+fn default_wecom_api_url() -> String {
+    "https://qyapi.weixin.qq.com".to_string()
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -106,16 +187,24 @@ pub struct Config {
     pub appservice: AppServiceConfig,
     pub bridge: BridgeConfig,
     pub logging: LoggingConfig,
+    /// Bridges a WeCom app through its official API, next to the agent-based accounts.
+    #[serde(default)]
+    pub wecom: Option<WecomConfig>,
 }
 
 impl Config {
     pub fn load(path: &str) -> Result<Self> {
-        let content = std::fs::read_to_string(path)?;
-        let config: Config = serde_yaml::from_str(&content)?;
+        let config = Self::read(path)?;
         config.validate()?;
         Ok(config)
     }
 
+    /// Parses a config without checking it, so all its [problems](Self::problems) can be listed.
+    pub fn read(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(serde_yaml::from_str(&content)?)
+    }
+
     pub fn load_from_bytes(bytes: &[u8]) -> Result<Self> {
         let config: Config = serde_yaml::from_slice(bytes)?;
         config.validate()?;
@@ -123,6 +212,16 @@ impl Config {
     }
 
     fn validate(&self) -> Result<()> {
+        let problems = self.problems();
+        if !problems.is_empty() {
+            anyhow::bail!("{}", problems.join("; "));
+        }
+        Ok(())
+    }
+
+    /// Everything wrong with the config that can be told without connecting anywhere.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
         let has_wildcard = self.bridge.permissions.contains_key("*");
         let has_example_domain = self.bridge.permissions.contains_key("example.com");
         let has_example_user = self.bridge.permissions.contains_key("@admin:example.com");
@@ -131,17 +230,76 @@ impl Config {
             has_wildcard as usize + has_example_domain as usize + has_example_user as usize;
 
         if self.bridge.permissions.len() <= example_count {
-            anyhow::bail!("bridge.permissions not configured");
+            problems.push("bridge.permissions not configured".to_string());
+        }
+        for key in self.bridge.permissions.keys() {
+            let valid = match key.strip_prefix('@') {
+                Some(mxid) => mxid.split_once(':').is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty()),
+                None => key == "*" || (!key.is_empty() && !key.contains(':')),
+            };
+            if !valid {
+                problems.push(format!("bridge.permissions: {} is neither *, a domain nor a user ID", key));
+            }
         }
 
         if !self.bridge.username_template.contains("{{.}}") {
-            anyhow::bail!("username template is missing user ID placeholder");
+            problems.push("username template is missing user ID placeholder".to_string());
+        }
+        if !self.bridge.alias_template.contains("{{.}}") {
+            problems.push("alias template is missing chat ID placeholder".to_string());
         }
 
-        Ok(())
+        if let Err(e) = validate_displayname_template(&self.bridge.displayname_template) {
+            problems.push(format!("invalid displayname template: {}", e));
+        }
+
+        if listeners_collide(&self.appservice.listen_address(), &self.bridge.listen_address) {
+            problems.push(format!(
+                "the appservice and bridge.listen_address both listen on {}",
+                self.bridge.listen_address
+            ));
+        }
+
+        if let Some(wecom) = &self.wecom
+            && wecom.encoding_aes_key.len() != 43
+        {
+            problems.push("wecom.encoding_aes_key must be the 43 characters set in the app's API settings".to_string());
+        }
+
+        problems
     }
 
     pub fn format_username(&self, username: &str) -> String {
         self.bridge.username_template.replace("{{.}}", username)
     }
+
+    /// The alias of the portal for a WeChat chat.
+    pub fn format_alias(&self, uid: &str) -> String {
+        format!("#{}:{}", self.bridge.alias_template.replace("{{.}}", uid), self.homeserver.domain)
+    }
+
+    /// The WeChat chat an alias in the bridge's namespace refers to.
+    pub fn parse_alias(&self, alias: &str) -> Option<String> {
+        let localpart = alias.strip_prefix('#')?
+            .strip_suffix(self.homeserver.domain.as_str())?
+            .strip_suffix(':')?;
+        let (prefix, suffix) = self.bridge.alias_template.split_once("{{.}}")?;
+        let uid = localpart.strip_prefix(prefix)?.strip_suffix(suffix)?;
+        (!uid.is_empty()).then(|| uid.to_string())
+    }
+}
+
+/// Whether two `host:port` listeners would take the same port, counting wildcard hosts as every
+/// address.
+fn listeners_collide(a: &str, b: &str) -> bool {
+    let split = |addr: &str| {
+        let (host, port) = addr.rsplit_once(':')?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        Some((host.to_string(), port.parse::<u16>().ok()?))
+    };
+    let (Some((host_a, port_a)), Some((host_b, port_b))) = (split(a), split(b)) else {
+        return false;
+    };
+    let wildcard = |host: &str| matches!(host, "" | "0.0.0.0" | "::");
+    port_a == port_b && (host_a == host_b || wildcard(&host_a) || wildcard(&host_b))
 }
@@ -1,11 +1,14 @@
 use std::sync::Arc;
-use tracing::info;
+use tracing::{debug, info};
 use base64::{Engine as _, engine::general_purpose::STANDARD};
 
 use crate::crypto::store::{CryptoStore, AccountInfo, MemoryCryptoStore};
 use crate::crypto::types::*;
 use crate::error::{CryptoError, CryptoResult};
 
+const OLM_ALGORITHM: &str = "m.olm.v1.curve25519-aes-sha2";
+const MEGOLM_ALGORITHM: &str = "m.megolm.v1.aes-sha2";
+
 pub struct CryptoMachine {
     user_id: String,
     device_id: String,
@@ -205,42 +208,205 @@ impl CryptoMachine {
         let session = self.store.get_outbound_group_session(room_id).await?
             .ok_or_else(|| CryptoError::SessionNotFound(format!("outbound session for {}", room_id)))?;
         
+        let room_key = serde_json::json!({
+            "algorithm": MEGOLM_ALGORITHM,
+            "room_id": room_id,
+            "session_id": session.session_id,
+            "session_key": session.pickle,
+        });
+        
         let mut encrypted_events = Vec::new();
         
         for (user_id, device_id) in devices {
-            let device_keys = self.store.get_device_keys(user_id, device_id).await?;
-            
-            if let Some(keys) = device_keys {
-                if let Some(curve_key) = keys.curve25519_key() {
-                    let encrypted = serde_json::json!({
-                        "algorithm": "m.megolm.v1.aes-sha2",
-                        "room_id": room_id,
-                        "session_id": session.session_id,
-                        "session_key": session.pickle,
-                    });
-                    
-                    encrypted_events.push(serde_json::json!({
-                        "type": "m.room.encrypted",
-                        "content": {
-                            "algorithm": "m.olm.v1.curve25519-aes-sha2",
-                            "sender_key": self.get_curve25519_key().await?,
-                            "ciphertext": {
-                                curve_key: {
-                                    "type": 0,
-                                    "body": STANDARD.encode(
-                                        serde_json::to_vec(&encrypted).unwrap_or_default()
-                                    ),
-                                }
-                            }
-                        }
-                    }));
-                }
+            let Some(keys) = self.store.get_device_keys(user_id, device_id).await? else {
+                continue;
+            };
+            if let Some(content) = self.encrypt_to_device(&keys, "m.room_key", &room_key).await? {
+                encrypted_events.push(serde_json::json!({
+                    "type": "m.room.encrypted",
+                    "content": content,
+                }));
             }
         }
         
         Ok(encrypted_events)
     }
     
+    /// Wraps an event in an Olm payload for a single device, or `None` if the device has no
+    /// Curve25519 key.
+    async fn encrypt_to_device(
+        &self,
+        keys: &DeviceKeys,
+        event_type: &str,
+        content: &serde_json::Value,
+    ) -> CryptoResult<Option<serde_json::Value>> {
+        let Some(curve_key) = keys.curve25519_key() else {
+            return Ok(None);
+        };
+        let payload = serde_json::json!({
+            "type": event_type,
+            "content": content,
+            "sender": self.user_id,
+            "sender_device": self.device_id,
+            "recipient": keys.user_id,
+            "recipient_keys": { "ed25519": keys.ed25519_key() },
+        });
+        let body = serde_json::to_vec(&payload)
+            .map_err(|e| CryptoError::EncryptionFailed(e.to_string()))?;
+        
+        Ok(Some(serde_json::json!({
+            "algorithm": OLM_ALGORITHM,
+            "sender_key": self.get_curve25519_key().await?,
+            "ciphertext": {
+                curve_key: {
+                    "type": 0,
+                    "body": STANDARD.encode(body),
+                }
+            }
+        })))
+    }
+    
+    /// Unwraps an Olm payload sent to this device, returning the inner event type and content.
+    async fn decrypt_to_device(&self, content: &serde_json::Value) -> CryptoResult<(String, serde_json::Value)> {
+        let own_key = self.get_curve25519_key().await?;
+        let body = content.get("ciphertext")
+            .and_then(|c| c.get(&own_key))
+            .and_then(|c| c.get("body"))
+            .and_then(|b| b.as_str())
+            .ok_or_else(|| CryptoError::DecryptionFailed("not encrypted for this device".to_string()))?;
+        
+        let decoded = STANDARD.decode(body)
+            .map_err(|e| CryptoError::DecryptionFailed(e.to_string()))?;
+        let mut payload: serde_json::Value = serde_json::from_slice(&decoded)
+            .map_err(|e| CryptoError::DecryptionFailed(e.to_string()))?;
+        let event_type = payload.get("type")
+            .and_then(|t| t.as_str())
+            .ok_or_else(|| CryptoError::DecryptionFailed("missing event type".to_string()))?
+            .to_string();
+        Ok((event_type, payload["content"].take()))
+    }
+    
+    /// Handles a to-device event sent to this device, returning the replies to send.
+    pub async fn receive_to_device(
+        &self,
+        event_type: &str,
+        sender: &str,
+        content: &serde_json::Value,
+    ) -> CryptoResult<Option<OutgoingToDevice>> {
+        match event_type {
+            "m.room.encrypted" => {
+                let (inner_type, inner_content) = self.decrypt_to_device(content).await?;
+                match inner_type.as_str() {
+                    "m.room_key" | "m.forwarded_room_key" => {
+                        self.import_room_key(&inner_content).await?;
+                        Ok(None)
+                    }
+                    _ => {
+                        debug!("Ignoring encrypted to-device {} from {}", inner_type, sender);
+                        Ok(None)
+                    }
+                }
+            }
+            "m.room_key_request" => self.answer_room_key_request(sender, content).await,
+            t if t.starts_with("m.key.verification.") => Ok(Self::cancel_verification(sender, t, content)),
+            _ => {
+                debug!("Ignoring to-device {} from {}", event_type, sender);
+                Ok(None)
+            }
+        }
+    }
+    
+    async fn import_room_key(&self, content: &serde_json::Value) -> CryptoResult<()> {
+        let field = |name: &str| {
+            content.get(name)
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+                .ok_or_else(|| CryptoError::InvalidKey(format!("room key without {}", name)))
+        };
+        let session = MegolmSession {
+            session_id: field("session_id")?,
+            sender_key: field("sender_key").unwrap_or_default(),
+            room_id: field("room_id")?,
+            created_at: chrono::Utc::now().timestamp() as u64,
+            last_used: 0,
+            pickle: field("session_key")?,
+            message_index: 0,
+        };
+        self.store.save_inbound_group_session(&session).await?;
+        info!("Imported room key {} for {}", session.session_id, session.room_id);
+        Ok(())
+    }
+    
+    /// Shares a room key we hold with the device that requested it, if its keys are known.
+    async fn answer_room_key_request(
+        &self,
+        sender: &str,
+        content: &serde_json::Value,
+    ) -> CryptoResult<Option<OutgoingToDevice>> {
+        if content.get("action").and_then(|a| a.as_str()) != Some("request") {
+            return Ok(None);
+        }
+        let (Some(device_id), Some(room_id), Some(session_id)) = (
+            content.get("requesting_device_id").and_then(|d| d.as_str()),
+            content.pointer("/body/room_id").and_then(|r| r.as_str()),
+            content.pointer("/body/session_id").and_then(|s| s.as_str()),
+        ) else {
+            return Ok(None);
+        };
+        
+        let Some(session) = self.store.get_inbound_group_session(room_id, session_id).await? else {
+            debug!("{} requested unknown room key {} for {}", sender, session_id, room_id);
+            return Ok(None);
+        };
+        let Some(keys) = self.store.get_device_keys(sender, device_id).await? else {
+            debug!("Not sharing room key {} with {} {}: unknown device", session_id, sender, device_id);
+            return Ok(None);
+        };
+        
+        let forwarded = serde_json::json!({
+            "algorithm": MEGOLM_ALGORITHM,
+            "room_id": room_id,
+            "session_id": session_id,
+            "session_key": session.pickle,
+            "sender_key": session.sender_key,
+            "forwarding_curve25519_key_chain": [],
+        });
+        let Some(encrypted) = self.encrypt_to_device(&keys, "m.forwarded_room_key", &forwarded).await? else {
+            return Ok(None);
+        };
+        info!("Sharing room key {} for {} with {} {}", session_id, room_id, sender, device_id);
+        Ok(Some(OutgoingToDevice::single("m.room.encrypted", sender, device_id, encrypted)))
+    }
+    
+    /// The bridge bot can't take part in interactive verification, so requests are cancelled
+    /// right away instead of leaving the other side waiting.
+    fn cancel_verification(sender: &str, event_type: &str, content: &serde_json::Value) -> Option<OutgoingToDevice> {
+        if !matches!(event_type, "m.key.verification.request" | "m.key.verification.start") {
+            return None;
+        }
+        let device_id = content.get("from_device").and_then(|d| d.as_str())?;
+        let transaction_id = content.get("transaction_id").and_then(|t| t.as_str())?;
+        info!("Cancelling verification {} from {} {}", transaction_id, sender, device_id);
+        Some(OutgoingToDevice::single(
+            "m.key.verification.cancel",
+            sender,
+            device_id,
+            serde_json::json!({
+                "transaction_id": transaction_id,
+                "code": "m.user",
+                "reason": "The bridge bot doesn't support interactive verification",
+            }),
+        ))
+    }
+    
+    pub async fn has_device_keys(&self, user_id: &str, device_id: &str) -> CryptoResult<bool> {
+        Ok(self.store.get_device_keys(user_id, device_id).await?.is_some())
+    }
+    
+    pub async fn save_device_keys(&self, keys: &DeviceKeys) -> CryptoResult<()> {
+        self.store.save_device_keys(keys).await
+    }
+    
     pub async fn is_room_encrypted(&self, room_id: &str) -> bool {
         self.store.get_outbound_group_session(room_id).await
             .map(|s| s.is_some())
@@ -66,6 +66,26 @@ pub struct GossipRequest {
     pub recipients: Vec<(String, String)>,
 }
 
+/// To-device messages of one event type, keyed by recipient user ID and then device ID.
+#[derive(Debug, Clone)]
+pub struct OutgoingToDevice {
+    pub event_type: String,
+    pub messages: HashMap<String, HashMap<String, serde_json::Value>>,
+}
+
+impl OutgoingToDevice {
+    pub fn single(event_type: &str, user_id: &str, device_id: &str, content: serde_json::Value) -> Self {
+        let mut devices = HashMap::new();
+        devices.insert(device_id.to_string(), content);
+        let mut messages = HashMap::new();
+        messages.insert(user_id.to_string(), devices);
+        Self {
+            event_type: event_type.to_string(),
+            messages,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct CryptoSessionInfo {
     pub device_id: Option<String>,
@@ -0,0 +1,67 @@
+use anyhow::Result;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::DbConnection;
+use super::schema::agent_token;
+
+/// A token a WeChat agent authenticates with, issued by an admin. Only the SHA-256 of its
+/// secret is kept.
+#[derive(Debug, Clone, PartialEq, Eq, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = agent_token)]
+pub struct AgentToken {
+    pub id: String,
+    pub token_hash: String,
+    pub description: String,
+    pub created_at: i64,
+    /// When an agent last connected with the token, `0` if never.
+    pub last_used: i64,
+    pub revoked: bool,
+}
+
+pub struct AgentTokenQuery;
+
+impl AgentTokenQuery {
+    pub fn get(conn: &mut DbConnection, id: &str) -> Result<Option<AgentToken>> {
+        let item = agent_token::table
+            .select(AgentToken::as_select())
+            .filter(agent_token::id.eq(id))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
+
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<AgentToken>> {
+        let items = agent_token::table
+            .select(AgentToken::as_select())
+            .order(agent_token::created_at.asc())
+            .load(conn)?;
+        Ok(items)
+    }
+
+    pub fn insert(conn: &mut DbConnection, item: &AgentToken) -> Result<()> {
+        diesel::insert_into(agent_token::table)
+            .values(item)
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Returns whether a token that wasn't revoked yet was found.
+    pub fn revoke(conn: &mut DbConnection, id: &str) -> Result<bool> {
+        let updated = diesel::update(
+            agent_token::table
+                .filter(agent_token::id.eq(id))
+                .filter(agent_token::revoked.eq(false)),
+        )
+        .set(agent_token::revoked.eq(true))
+        .execute(conn)?;
+        Ok(updated > 0)
+    }
+
+    pub fn touch(conn: &mut DbConnection, id: &str, timestamp: i64) -> Result<()> {
+        diesel::update(agent_token::table.filter(agent_token::id.eq(id)))
+            .set(agent_token::last_used.eq(timestamp))
+            .execute(conn)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+use anyhow::Result;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::DbConnection;
+use super::schema::appservice_txn;
+
+/// A homeserver transaction that was already handled, kept so retries aren't bridged twice.
+#[derive(Debug, Clone, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = appservice_txn)]
+pub struct AppserviceTxn {
+    pub txn_id: String,
+    pub processed_at: i64,
+}
+
+pub struct AppserviceTxnQuery;
+
+impl AppserviceTxnQuery {
+    pub fn exists(conn: &mut DbConnection, txn_id: &str) -> Result<bool> {
+        let count: i64 = appservice_txn::table
+            .filter(appservice_txn::txn_id.eq(txn_id))
+            .count()
+            .get_result(conn)?;
+        Ok(count > 0)
+    }
+
+    pub fn insert(conn: &mut DbConnection, item: &AppserviceTxn) -> Result<()> {
+        conn.transaction(|conn| {
+            diesel::delete(appservice_txn::table.filter(appservice_txn::txn_id.eq(&item.txn_id)))
+                .execute(conn)?;
+            diesel::insert_into(appservice_txn::table)
+                .values(item)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    pub fn delete_before(conn: &mut DbConnection, timestamp: i64) -> Result<usize> {
+        let deleted = diesel::delete(appservice_txn::table.filter(appservice_txn::processed_at.lt(timestamp)))
+            .execute(conn)?;
+        Ok(deleted)
+    }
+}
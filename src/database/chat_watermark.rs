@@ -0,0 +1,54 @@
+use anyhow::Result;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::DbConnection;
+use super::schema::chat_watermark;
+
+/// The newest WeChat event bridged for a chat of a user, used to drop events replayed by their
+/// agent.
+#[derive(Debug, Clone, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = chat_watermark)]
+pub struct ChatWatermark {
+    pub mxid: String,
+    pub chat_id: String,
+    pub last_ts: i64,
+    pub last_msg_id: String,
+}
+
+impl ChatWatermark {
+    /// Whether an event was already bridged: it's the last one, or older than it by more than
+    /// `tolerance_ms`, which allows for events arriving slightly out of order.
+    pub fn is_replay(&self, event_id: &str, timestamp: i64, tolerance_ms: i64) -> bool {
+        event_id == self.last_msg_id || timestamp + tolerance_ms < self.last_ts
+    }
+}
+
+pub struct ChatWatermarkQuery;
+
+impl ChatWatermarkQuery {
+    pub fn get(conn: &mut DbConnection, mxid: &str, chat_id: &str) -> Result<Option<ChatWatermark>> {
+        let item = chat_watermark::table
+            .select(ChatWatermark::as_select())
+            .filter(chat_watermark::mxid.eq(mxid))
+            .filter(chat_watermark::chat_id.eq(chat_id))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
+
+    pub fn upsert(conn: &mut DbConnection, item: &ChatWatermark) -> Result<()> {
+        conn.transaction(|conn| {
+            diesel::delete(
+                chat_watermark::table
+                    .filter(chat_watermark::mxid.eq(&item.mxid))
+                    .filter(chat_watermark::chat_id.eq(&item.chat_id)),
+            )
+            .execute(conn)?;
+            diesel::insert_into(chat_watermark::table)
+                .values(item)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,47 @@
+use anyhow::Result;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::DbConnection;
+use super::schema::contact;
+
+/// A WeChat friend of a logged-in user, as of the last contact sync.
+#[derive(Debug, Clone, PartialEq, Eq, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = contact)]
+pub struct Contact {
+    pub user_mxid: String,
+    pub uin: String,
+    pub name: String,
+    pub remark: Option<String>,
+    pub avatar: Option<String>,
+    /// Set when the contact disappeared from the friend list.
+    pub deleted: bool,
+    pub updated_at: i64,
+}
+
+pub struct ContactQuery;
+
+impl ContactQuery {
+    pub fn get_by_user(conn: &mut DbConnection, user_mxid: &str) -> Result<Vec<Contact>> {
+        let items = contact::table
+            .select(Contact::as_select())
+            .filter(contact::user_mxid.eq(user_mxid))
+            .load(conn)?;
+        Ok(items)
+    }
+
+    pub fn upsert(conn: &mut DbConnection, item: &Contact) -> Result<()> {
+        conn.transaction(|conn| {
+            diesel::delete(
+                contact::table
+                    .filter(contact::user_mxid.eq(&item.user_mxid))
+                    .filter(contact::uin.eq(&item.uin)),
+            )
+            .execute(conn)?;
+            diesel::insert_into(contact::table)
+                .values(item)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+}
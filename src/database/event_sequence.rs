@@ -0,0 +1,40 @@
+use anyhow::Result;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::DbConnection;
+use super::schema::event_sequence;
+
+/// The last event of a user's agent the bridge handled and acknowledged, so the agent knows
+/// which events to replay when it reconnects.
+#[derive(Debug, Clone, PartialEq, Eq, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = event_sequence)]
+pub struct EventSequence {
+    pub mxid: String,
+    pub seq: i64,
+    pub updated_at: i64,
+}
+
+pub struct EventSequenceQuery;
+
+impl EventSequenceQuery {
+    pub fn get(conn: &mut DbConnection, mxid: &str) -> Result<Option<EventSequence>> {
+        let item = event_sequence::table
+            .select(EventSequence::as_select())
+            .filter(event_sequence::mxid.eq(mxid))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
+
+    pub fn upsert(conn: &mut DbConnection, item: &EventSequence) -> Result<()> {
+        conn.transaction(|conn| {
+            diesel::delete(event_sequence::table.filter(event_sequence::mxid.eq(&item.mxid)))
+                .execute(conn)?;
+            diesel::insert_into(event_sequence::table)
+                .values(item)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+}
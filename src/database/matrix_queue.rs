@@ -0,0 +1,62 @@
+use anyhow::Result;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::DbConnection;
+use super::schema::matrix_queue;
+
+/// A WeChat event waiting to be bridged again because the homeserver was unreachable.
+#[derive(Debug, Clone, PartialEq, Eq, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = matrix_queue)]
+pub struct MatrixQueueItem {
+    /// The user whose agent reported the event.
+    pub mxid: String,
+    pub event_id: String,
+    /// Events of the same chat are bridged in the order they were queued.
+    pub chat_id: String,
+    /// The WeChat event, serialized as JSON.
+    pub event: String,
+    pub attempts: i32,
+    /// In microseconds, unique within a run of the bridge to keep the order.
+    pub queued_at: i64,
+    /// In milliseconds.
+    pub next_attempt_at: i64,
+}
+
+pub struct MatrixQueueQuery;
+
+impl MatrixQueueQuery {
+    /// Every queued event, oldest first.
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<MatrixQueueItem>> {
+        let items = matrix_queue::table
+            .select(MatrixQueueItem::as_select())
+            .order(matrix_queue::queued_at.asc())
+            .load(conn)?;
+        Ok(items)
+    }
+
+    pub fn upsert(conn: &mut DbConnection, item: &MatrixQueueItem) -> Result<()> {
+        conn.transaction(|conn| {
+            diesel::delete(
+                matrix_queue::table
+                    .filter(matrix_queue::mxid.eq(&item.mxid))
+                    .filter(matrix_queue::event_id.eq(&item.event_id)),
+            )
+            .execute(conn)?;
+            diesel::insert_into(matrix_queue::table)
+                .values(item)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    pub fn delete(conn: &mut DbConnection, mxid: &str, event_id: &str) -> Result<()> {
+        diesel::delete(
+            matrix_queue::table
+                .filter(matrix_queue::mxid.eq(mxid))
+                .filter(matrix_queue::event_id.eq(event_id)),
+        )
+        .execute(conn)?;
+        Ok(())
+    }
+}
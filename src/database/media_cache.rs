@@ -0,0 +1,62 @@
+use anyhow::Result;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::DbConnection;
+use super::schema::media_cache;
+
+/// Media that was uploaded to the homeserver, keyed by the SHA-256 of its content so the same
+/// avatar or sticker isn't uploaded again.
+#[derive(Debug, Clone, PartialEq, Eq, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = media_cache)]
+pub struct MediaCacheEntry {
+    pub hash: String,
+    pub mxc: String,
+    pub mimetype: String,
+    pub size: i64,
+    pub created_at: i64,
+    /// When the upload was last reused, in seconds.
+    pub last_used: i64,
+}
+
+pub struct MediaCacheQuery;
+
+impl MediaCacheQuery {
+    pub fn get(conn: &mut DbConnection, hash: &str) -> Result<Option<MediaCacheEntry>> {
+        let item = media_cache::table
+            .select(MediaCacheEntry::as_select())
+            .filter(media_cache::hash.eq(hash))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
+
+    pub fn upsert(conn: &mut DbConnection, item: &MediaCacheEntry) -> Result<()> {
+        conn.transaction(|conn| {
+            diesel::delete(media_cache::table.filter(media_cache::hash.eq(&item.hash)))
+                .execute(conn)?;
+            diesel::insert_into(media_cache::table)
+                .values(item)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    pub fn touch(conn: &mut DbConnection, hash: &str, timestamp: i64) -> Result<()> {
+        diesel::update(media_cache::table.filter(media_cache::hash.eq(hash)))
+            .set(media_cache::last_used.eq(timestamp))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn delete_unused_before(conn: &mut DbConnection, timestamp: i64) -> Result<usize> {
+        let deleted = diesel::delete(media_cache::table.filter(media_cache::last_used.lt(timestamp)))
+            .execute(conn)?;
+        Ok(deleted)
+    }
+
+    pub fn delete_all(conn: &mut DbConnection) -> Result<usize> {
+        let deleted = diesel::delete(media_cache::table).execute(conn)?;
+        Ok(deleted)
+    }
+}
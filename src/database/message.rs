@@ -1,11 +1,10 @@
 use super::PortalKey;
 use anyhow::Result;
 use chrono::{DateTime, Utc};
-use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use diesel::sqlite::SqliteConnection;
 use serde::{Deserialize, Serialize};
 
+use super::DbConnection;
 use super::schema::message;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -86,6 +85,16 @@ impl Message {
         self.mxid.starts_with("me.lxduo.wechat.fake::")
     }
 
+    /// Stands in for the event ID of a WeChat message until it's sent to Matrix, as event IDs
+    /// must be unique.
+    pub fn pending_mxid(msg_id: &str) -> String {
+        format!("me.lxduo.wechat.pending::{}", msg_id)
+    }
+
+    pub fn is_pending_mxid(&self) -> bool {
+        self.mxid.starts_with("me.lxduo.wechat.pending::")
+    }
+
     pub fn is_fake_msg_id(&self) -> bool {
         self.msg_id.starts_with("FAKE::") || self.msg_id == self.mxid
     }
@@ -115,124 +124,123 @@ impl Message {
 
 pub struct MessageQuery;
 
-macro_rules! impl_message_query_for_conn {
-    (
-        $get_by_id:ident,
-        $get_by_mxid:ident,
-        $get_by_msg_id:ident,
-        $get_last:ident,
-        $insert:ident,
-        $update_mxid:ident,
-        $delete:ident,
-        $conn_ty:ty
-    ) => {
-        pub fn $get_by_id(
-            conn: &mut $conn_ty,
-            key: &PortalKey,
-            msg_id: &str,
-        ) -> Result<Option<Message>> {
-            let item = message::table
-                .select(Message::as_select())
-                .filter(message::chat_uid.eq(&key.uid))
-                .filter(message::chat_receiver.eq(&key.receiver))
-                .filter(message::msg_id.eq(msg_id))
-                .first(conn)
-                .optional()?;
-            Ok(item)
-        }
+impl MessageQuery {
+    pub fn get_by_id(
+        conn: &mut DbConnection,
+        key: &PortalKey,
+        msg_id: &str,
+    ) -> Result<Option<Message>> {
+        let item = message::table
+            .select(Message::as_select())
+            .filter(message::chat_uid.eq(&key.uid))
+            .filter(message::chat_receiver.eq(&key.receiver))
+            .filter(message::msg_id.eq(msg_id))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
 
-        pub fn $get_by_mxid(conn: &mut $conn_ty, mxid: &str) -> Result<Option<Message>> {
-            let item = message::table
-                .select(Message::as_select())
-                .filter(message::mxid.eq(mxid))
-                .first(conn)
-                .optional()?;
-            Ok(item)
-        }
+    pub fn get_by_mxid(conn: &mut DbConnection, mxid: &str) -> Result<Option<Message>> {
+        let item = message::table
+            .select(Message::as_select())
+            .filter(message::mxid.eq(mxid))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
 
-        pub fn $get_by_msg_id(conn: &mut $conn_ty, msg_id: &str) -> Result<Option<Message>> {
-            let item = message::table
-                .select(Message::as_select())
-                .filter(message::msg_id.eq(msg_id))
-                .first(conn)
-                .optional()?;
-            Ok(item)
-        }
+    pub fn get_by_msg_id(conn: &mut DbConnection, msg_id: &str) -> Result<Option<Message>> {
+        let item = message::table
+            .select(Message::as_select())
+            .filter(message::msg_id.eq(msg_id))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
+
+    pub fn get_last(conn: &mut DbConnection, key: &PortalKey) -> Result<Option<Message>> {
+        let item = message::table
+            .select(Message::as_select())
+            .filter(message::chat_uid.eq(&key.uid))
+            .filter(message::chat_receiver.eq(&key.receiver))
+            .order(message::timestamp.desc())
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
+
+    pub fn insert(conn: &mut DbConnection, item: &Message) -> Result<()> {
+        diesel::insert_into(message::table)
+            .values(item)
+            .execute(conn)?;
+        Ok(())
+    }
 
-        pub fn $get_last(conn: &mut $conn_ty, key: &PortalKey) -> Result<Option<Message>> {
-            let item = message::table
-                .select(Message::as_select())
+    pub fn update_mxid(
+        conn: &mut DbConnection,
+        key: &PortalKey,
+        msg_id: &str,
+        mxid: &str,
+        msg_type: &str,
+        error: Option<&str>,
+    ) -> Result<()> {
+        diesel::update(
+            message::table
                 .filter(message::chat_uid.eq(&key.uid))
                 .filter(message::chat_receiver.eq(&key.receiver))
-                .order(message::timestamp.desc())
-                .first(conn)
-                .optional()?;
-            Ok(item)
-        }
-
-        pub fn $insert(conn: &mut $conn_ty, item: &Message) -> Result<()> {
-            diesel::insert_into(message::table)
-                .values(item)
-                .execute(conn)?;
-            Ok(())
-        }
+                .filter(message::msg_id.eq(msg_id)),
+        )
+        .set((
+            message::mxid.eq(mxid),
+            message::msg_type.eq(msg_type),
+            message::error.eq(error),
+        ))
+        .execute(conn)?;
+        Ok(())
+    }
 
-        pub fn $update_mxid(
-            conn: &mut $conn_ty,
-            key: &PortalKey,
-            msg_id: &str,
-            mxid: &str,
-            msg_type: &str,
-            error: Option<&str>,
-        ) -> Result<()> {
-            diesel::update(
-                message::table
-                    .filter(message::chat_uid.eq(&key.uid))
-                    .filter(message::chat_receiver.eq(&key.receiver))
-                    .filter(message::msg_id.eq(msg_id)),
-            )
-            .set((
-                message::mxid.eq(mxid),
-                message::msg_type.eq(msg_type),
-                message::error.eq(error),
-            ))
-            .execute(conn)?;
-            Ok(())
-        }
+    pub fn complete(
+        conn: &mut DbConnection,
+        key: &PortalKey,
+        pending_id: &str,
+        msg_id: &str,
+        mxid: &str,
+        error: Option<&str>,
+    ) -> Result<()> {
+        diesel::update(
+            message::table
+                .filter(message::chat_uid.eq(&key.uid))
+                .filter(message::chat_receiver.eq(&key.receiver))
+                .filter(message::msg_id.eq(pending_id)),
+        )
+        .set((
+            message::msg_id.eq(msg_id),
+            message::mxid.eq(mxid),
+            message::sent.eq(error.is_none()),
+            message::error.eq(error),
+        ))
+        .execute(conn)?;
+        Ok(())
+    }
 
-        pub fn $delete(conn: &mut $conn_ty, key: &PortalKey, msg_id: &str) -> Result<()> {
-            diesel::delete(
-                message::table
-                    .filter(message::chat_uid.eq(&key.uid))
-                    .filter(message::chat_receiver.eq(&key.receiver))
-                    .filter(message::msg_id.eq(msg_id)),
-            )
-            .execute(conn)?;
-            Ok(())
-        }
-    };
-}
+    pub fn get_pending(conn: &mut DbConnection) -> Result<Vec<Message>> {
+        let items = message::table
+            .select(Message::as_select())
+            .filter(message::sent.eq(false))
+            .filter(message::error.is_null())
+            .order(message::timestamp.asc())
+            .load(conn)?;
+        Ok(items)
+    }
 
-impl MessageQuery {
-    impl_message_query_for_conn!(
-        get_by_id_sqlite,
-        get_by_mxid_sqlite,
-        get_by_msg_id_sqlite,
-        get_last_sqlite,
-        insert_sqlite,
-        update_mxid_sqlite,
-        delete_sqlite,
-        SqliteConnection
-    );
-
-    impl_message_query_for_conn!(
-        get_by_id_postgres,
-        get_by_mxid_postgres,
-        get_by_msg_id_postgres,
-        get_last_postgres,
-        insert_postgres,
-        update_mxid_postgres,
-        delete_postgres,
-        PgConnection
-    );
+    pub fn delete(conn: &mut DbConnection, key: &PortalKey, msg_id: &str) -> Result<()> {
+        diesel::delete(
+            message::table
+                .filter(message::chat_uid.eq(&key.uid))
+                .filter(message::chat_receiver.eq(&key.receiver))
+                .filter(message::msg_id.eq(msg_id)),
+        )
+        .execute(conn)?;
+        Ok(())
+    }
 }
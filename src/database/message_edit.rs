@@ -0,0 +1,79 @@
+use super::PortalKey;
+use anyhow::Result;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::DbConnection;
+use super::schema::message_edit;
+
+/// An edit of a bridged message. `msg_id` is the WeChat ID of the edit, or its event ID when
+/// WeChat edited the message in place without giving the edit an ID of its own.
+#[derive(Debug, Clone, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = message_edit)]
+pub struct MessageEdit {
+    pub chat_uid: String,
+    pub chat_receiver: String,
+    pub msg_id: String,
+    pub target_msg_id: String,
+    pub mxid: String,
+    pub sender: String,
+    pub timestamp: i64,
+}
+
+impl MessageEdit {
+    pub fn key(&self) -> PortalKey {
+        PortalKey::new(&self.chat_uid, &self.chat_receiver)
+    }
+}
+
+pub struct MessageEditQuery;
+
+impl MessageEditQuery {
+    pub fn get_by_mxid(conn: &mut DbConnection, mxid: &str) -> Result<Option<MessageEdit>> {
+        let item = message_edit::table
+            .select(MessageEdit::as_select())
+            .filter(message_edit::mxid.eq(mxid))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
+
+    pub fn get_by_msg_id(conn: &mut DbConnection, msg_id: &str) -> Result<Option<MessageEdit>> {
+        let item = message_edit::table
+            .select(MessageEdit::as_select())
+            .filter(message_edit::msg_id.eq(msg_id))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
+
+    /// The edits of a message, oldest first.
+    pub fn get_by_target(conn: &mut DbConnection, key: &PortalKey, target_msg_id: &str) -> Result<Vec<MessageEdit>> {
+        let items = message_edit::table
+            .select(MessageEdit::as_select())
+            .filter(message_edit::chat_uid.eq(&key.uid))
+            .filter(message_edit::chat_receiver.eq(&key.receiver))
+            .filter(message_edit::target_msg_id.eq(target_msg_id))
+            .order(message_edit::timestamp.asc())
+            .load(conn)?;
+        Ok(items)
+    }
+
+    pub fn insert(conn: &mut DbConnection, item: &MessageEdit) -> Result<()> {
+        diesel::insert_into(message_edit::table)
+            .values(item)
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn delete_by_target(conn: &mut DbConnection, key: &PortalKey, target_msg_id: &str) -> Result<()> {
+        diesel::delete(
+            message_edit::table
+                .filter(message_edit::chat_uid.eq(&key.uid))
+                .filter(message_edit::chat_receiver.eq(&key.receiver))
+                .filter(message_edit::target_msg_id.eq(target_msg_id)),
+        )
+        .execute(conn)?;
+        Ok(())
+    }
+}
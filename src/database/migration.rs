@@ -0,0 +1,117 @@
+use anyhow::Result;
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+
+use super::DbConnection;
+use super::schema::db_version;
+
+/// A schema change, applied once in order of version and recorded in the `db_version` table.
+/// New changes go in a new file in `migrations/`; released ones must not be edited.
+#[derive(Debug, Clone, Copy)]
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    sql: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../../migrations/001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "reaction_ids_and_edits",
+        sql: include_str!("../../migrations/002_reaction_ids_and_edits.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "portal_settings",
+        sql: include_str!("../../migrations/003_portal_settings.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "media_cache",
+        sql: include_str!("../../migrations/004_media_cache.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "agent_tokens",
+        sql: include_str!("../../migrations/005_agent_tokens.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "event_sequences",
+        sql: include_str!("../../migrations/006_event_sequences.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "matrix_queue",
+        sql: include_str!("../../migrations/007_matrix_queue.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "user_chat_watermark",
+        sql: include_str!("../../migrations/008_user_chat_watermark.sql"),
+    },
+];
+
+/// The version table can't be created by a migration, as it records them.
+const CREATE_VERSION_TABLE: &str = "CREATE TABLE IF NOT EXISTS db_version (
+    version INTEGER PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    applied_at BIGINT NOT NULL
+)";
+
+impl Migration {
+    /// The SQL to run on SQLite, without the lines marked `-- only: postgres`.
+    pub fn sqlite_sql(&self) -> String {
+        self.sql
+            .lines()
+            .filter(|line| !line.starts_with("-- only: postgres"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn postgres_sql(&self) -> &'static str {
+        self.sql
+    }
+}
+
+/// The migrations that haven't been applied to a database at `current`, in order.
+pub fn pending_migrations(current: i32) -> impl Iterator<Item = &'static Migration> {
+    MIGRATIONS.iter().filter(move |m| m.version > current)
+}
+
+pub struct MigrationQuery;
+
+impl MigrationQuery {
+    /// The version of the schema, `0` for a new database. Databases created before versions
+    /// were tracked are also at `0`, which is safe as the initial migration can run again.
+    pub fn current_version(conn: &mut DbConnection) -> Result<i32> {
+        conn.batch_execute(CREATE_VERSION_TABLE)?;
+        let version: Option<i32> = db_version::table
+            .select(diesel::dsl::max(db_version::version))
+            .first(conn)?;
+        Ok(version.unwrap_or(0))
+    }
+
+    pub fn apply(conn: &mut DbConnection, migration: &Migration) -> Result<()> {
+        let sql = match conn {
+            DbConnection::Sqlite(_) => migration.sqlite_sql(),
+            DbConnection::Postgres(_) => migration.postgres_sql().to_owned(),
+        };
+        conn.transaction(|conn| {
+            conn.batch_execute(&sql)?;
+            diesel::insert_into(db_version::table)
+                .values((
+                    db_version::version.eq(migration.version),
+                    db_version::name.eq(migration.name),
+                    db_version::applied_at.eq(chrono::Utc::now().timestamp()),
+                ))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+}
@@ -1,31 +1,57 @@
 mod schema;
+mod connection;
 mod user;
 mod portal;
 mod puppet;
 mod message;
-
+mod reaction;
+mod message_edit;
+mod user_setting;
+mod portal_setting;
+mod chat_watermark;
+mod portal_relay;
+mod outgoing_retry;
+mod appservice_txn;
+mod contact;
+mod permission_override;
+mod media_cache;
+mod agent_token;
+mod event_sequence;
+mod matrix_queue;
+mod migration;
+
+pub use connection::DbConnection;
 pub use user::*;
 pub use portal::*;
 pub use puppet::*;
 pub use message::*;
+pub use reaction::*;
+pub use message_edit::*;
+pub use user_setting::*;
+pub use portal_setting::*;
+pub use chat_watermark::*;
+pub use portal_relay::*;
+pub use outgoing_retry::*;
+pub use appservice_txn::*;
+pub use contact::*;
+pub use permission_override::*;
+pub use media_cache::*;
+pub use agent_token::*;
+pub use event_sequence::*;
+pub use matrix_queue::*;
+pub use migration::*;
 
 use anyhow::Context;
 use anyhow::Result;
-use diesel::connection::SimpleConnection;
-use diesel::pg::PgConnection;
-use diesel::r2d2::{ConnectionManager, Pool};
-use diesel::sqlite::SqliteConnection;
+use diesel::r2d2::Pool;
 use tracing::info;
 
-#[derive(Debug, Clone)]
-pub struct Database {
-    inner: DatabaseInner,
-}
+use connection::{DbBackend, DbConnectionManager};
 
 #[derive(Debug, Clone)]
-enum DatabaseInner {
-    Sqlite(Pool<ConnectionManager<SqliteConnection>>),
-    Postgres(Pool<ConnectionManager<PgConnection>>),
+pub struct Database {
+    backend: DbBackend,
+    pool: Pool<DbConnectionManager>,
 }
 
 impl Database {
@@ -34,294 +60,360 @@ impl Database {
         let max_idle = max_idle.min(max_open);
         let db_type = db_type.trim().to_ascii_lowercase();
 
-        match db_type.as_str() {
+        let (backend, database_url) = match db_type.as_str() {
             "sqlite" | "sqlite3" => {
                 info!("Connecting to SQLite database with Diesel");
-                let database_url = normalize_sqlite_uri(uri);
-                let manager = ConnectionManager::<SqliteConnection>::new(database_url);
-                let pool = Pool::builder()
-                    .max_size(max_open)
-                    .min_idle(Some(max_idle))
-                    .build(manager)
-                    .context("failed to create sqlite connection pool")?;
-                Ok(Self {
-                    inner: DatabaseInner::Sqlite(pool),
-                })
+                (DbBackend::Sqlite, normalize_sqlite_uri(uri))
             }
             "postgres" | "postgresql" | "pgsql" => {
                 info!("Connecting to PostgreSQL database with Diesel");
-                let manager = ConnectionManager::<PgConnection>::new(uri.to_owned());
-                let pool = Pool::builder()
-                    .max_size(max_open)
-                    .min_idle(Some(max_idle))
-                    .build(manager)
-                    .context("failed to create postgres connection pool")?;
-                Ok(Self {
-                    inner: DatabaseInner::Postgres(pool),
-                })
+                (DbBackend::Postgres, uri.to_owned())
             }
             _ => anyhow::bail!(
                 "Unsupported database type: {db_type}. Supported types: sqlite/sqlite3/postgres/postgresql/pgsql"
             ),
-        }
+        };
+        let pool = Pool::builder()
+            .max_size(max_open)
+            .min_idle(Some(max_idle))
+            .build(DbConnectionManager::new(backend, database_url))
+            .with_context(|| format!("failed to create {db_type} connection pool"))?;
+        Ok(Self { backend, pool })
+    }
+
+    /// Checks that a connection can be taken from the pool and run a query.
+    pub async fn ping(&self) -> Result<()> {
+        self.with_conn(|conn| {
+            diesel::connection::SimpleConnection::batch_execute(conn, "SELECT 1")?;
+            Ok(())
+        })
+        .await
     }
 
     pub fn is_sqlite(&self) -> bool {
-        matches!(self.inner, DatabaseInner::Sqlite(_))
+        self.backend == DbBackend::Sqlite
     }
 
+    /// Applies the migrations the database doesn't have yet, each in its own transaction.
     pub async fn run_migrations(&self) -> Result<()> {
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => {
-                let migration_sql = include_str!("../../migrations/001_initial.sql")
-                    .lines()
-                    .filter(|line| !line.starts_with("-- only: postgres"))
-                    .collect::<Vec<_>>()
-                    .join("\n");
-                self.with_sqlite_conn(move |conn| {
-                    conn.batch_execute(&migration_sql)?;
-                    Ok(())
-                })
-                .await?;
-            }
-            DatabaseInner::Postgres(_) => {
-                let migration_sql = include_str!("../../migrations/001_initial.sql");
-                self.with_postgres_conn(move |conn| {
-                    conn.batch_execute(migration_sql)?;
-                    Ok(())
-                })
-                .await?;
-            }
+        let current = self.schema_version().await?;
+        for migration in pending_migrations(current) {
+            info!("Applying database migration {} ({})", migration.version, migration.name);
+            let migration = *migration;
+            self.with_conn(move |conn| MigrationQuery::apply(conn, &migration))
+                .await
+                .with_context(|| format!("database migration {} failed", migration.version))?;
         }
 
         info!("Database migrations completed");
         Ok(())
     }
 
+    /// The version of the last migration applied to the database.
+    pub async fn schema_version(&self) -> Result<i32> {
+        self.with_conn(MigrationQuery::current_version).await
+    }
+
     pub async fn get_user_by_mxid(&self, mxid: &str) -> Result<Option<User>> {
         let mxid = mxid.to_owned();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => {
-                self.with_sqlite_conn(move |conn| UserQuery::get_by_mxid_sqlite(conn, &mxid))
-                    .await
-            }
-            DatabaseInner::Postgres(_) => {
-                self.with_postgres_conn(move |conn| UserQuery::get_by_mxid_postgres(conn, &mxid))
-                    .await
-            }
-        }
+        self.with_conn(move |conn| UserQuery::get_by_mxid(conn, &mxid)).await
     }
 
     pub async fn get_user_by_uin(&self, uin: &str) -> Result<Option<User>> {
         let uin = uin.to_owned();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => {
-                self.with_sqlite_conn(move |conn| UserQuery::get_by_uin_sqlite(conn, &uin))
-                    .await
-            }
-            DatabaseInner::Postgres(_) => {
-                self.with_postgres_conn(move |conn| UserQuery::get_by_uin_postgres(conn, &uin))
-                    .await
-            }
-        }
+        self.with_conn(move |conn| UserQuery::get_by_uin(conn, &uin)).await
+    }
+
+    pub async fn get_all_users(&self) -> Result<Vec<User>> {
+        self.with_conn(UserQuery::get_all).await
     }
 
     pub async fn get_all_logged_in_users(&self) -> Result<Vec<User>> {
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => self.with_sqlite_conn(UserQuery::get_all_logged_in_sqlite).await,
-            DatabaseInner::Postgres(_) => self.with_postgres_conn(UserQuery::get_all_logged_in_postgres).await,
-        }
+        self.with_conn(UserQuery::get_all_logged_in).await
     }
 
     pub async fn insert_user(&self, user: &User) -> Result<()> {
         let user = user.clone();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => self.with_sqlite_conn(move |conn| UserQuery::insert_sqlite(conn, &user)).await,
-            DatabaseInner::Postgres(_) => self.with_postgres_conn(move |conn| UserQuery::insert_postgres(conn, &user)).await,
-        }
+        self.with_conn(move |conn| UserQuery::insert(conn, &user)).await
     }
 
     pub async fn update_user(&self, user: &User) -> Result<()> {
         let user = user.clone();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => self.with_sqlite_conn(move |conn| UserQuery::update_sqlite(conn, &user)).await,
-            DatabaseInner::Postgres(_) => self.with_postgres_conn(move |conn| UserQuery::update_postgres(conn, &user)).await,
-        }
+        self.with_conn(move |conn| UserQuery::update(conn, &user)).await
+    }
+
+    pub async fn delete_user(&self, mxid: &str) -> Result<()> {
+        let mxid = mxid.to_owned();
+        self.with_conn(move |conn| UserQuery::delete(conn, &mxid)).await
+    }
+
+    pub async fn get_portal_setting(&self, key: &PortalKey, user_mxid: &str) -> Result<Option<PortalSetting>> {
+        let key = key.clone();
+        let user_mxid = user_mxid.to_owned();
+        self.with_conn(move |conn| PortalSettingQuery::get(conn, &key, &user_mxid)).await
+    }
+
+    pub async fn upsert_portal_setting(&self, setting: &PortalSetting) -> Result<()> {
+        let setting = setting.clone();
+        self.with_conn(move |conn| PortalSettingQuery::upsert(conn, &setting)).await
+    }
+
+    pub async fn get_user_setting(&self, mxid: &str) -> Result<Option<UserSetting>> {
+        let mxid = mxid.to_owned();
+        self.with_conn(move |conn| UserSettingQuery::get_by_mxid(conn, &mxid)).await
+    }
+
+    pub async fn upsert_user_setting(&self, setting: &UserSetting) -> Result<()> {
+        let setting = setting.clone();
+        self.with_conn(move |conn| UserSettingQuery::upsert(conn, &setting)).await
+    }
+
+    pub async fn get_chat_watermark(&self, mxid: &str, chat_id: &str) -> Result<Option<ChatWatermark>> {
+        let mxid = mxid.to_owned();
+        let chat_id = chat_id.to_owned();
+        self.with_conn(move |conn| ChatWatermarkQuery::get(conn, &mxid, &chat_id)).await
+    }
+
+    pub async fn upsert_chat_watermark(&self, watermark: &ChatWatermark) -> Result<()> {
+        let watermark = watermark.clone();
+        self.with_conn(move |conn| ChatWatermarkQuery::upsert(conn, &watermark)).await
     }
 
     pub async fn get_portal_by_key(&self, key: &PortalKey) -> Result<Option<Portal>> {
         let key = key.clone();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => {
-                self.with_sqlite_conn(move |conn| PortalQuery::get_by_key_sqlite(conn, &key))
-                    .await
-            }
-            DatabaseInner::Postgres(_) => {
-                self.with_postgres_conn(move |conn| PortalQuery::get_by_key_postgres(conn, &key))
-                    .await
-            }
-        }
+        self.with_conn(move |conn| PortalQuery::get_by_key(conn, &key)).await
     }
 
     pub async fn get_portal_by_mxid(&self, mxid: &str) -> Result<Option<Portal>> {
         let mxid = mxid.to_owned();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => {
-                self.with_sqlite_conn(move |conn| PortalQuery::get_by_mxid_sqlite(conn, &mxid))
-                    .await
-            }
-            DatabaseInner::Postgres(_) => {
-                self.with_postgres_conn(move |conn| PortalQuery::get_by_mxid_postgres(conn, &mxid))
-                    .await
-            }
-        }
+        self.with_conn(move |conn| PortalQuery::get_by_mxid(conn, &mxid)).await
+    }
+
+    pub async fn get_portals_by_uid(&self, uid: &str) -> Result<Vec<Portal>> {
+        let uid = uid.to_owned();
+        self.with_conn(move |conn| PortalQuery::get_by_uid(conn, &uid)).await
+    }
+
+    pub async fn get_portals_by_receiver(&self, receiver: &str) -> Result<Vec<Portal>> {
+        let receiver = receiver.to_owned();
+        self.with_conn(move |conn| PortalQuery::get_by_receiver(conn, &receiver)).await
+    }
+
+    pub async fn get_all_portals(&self) -> Result<Vec<Portal>> {
+        self.with_conn(PortalQuery::get_all).await
     }
 
     pub async fn get_all_portals_with_mxid(&self) -> Result<Vec<Portal>> {
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => self.with_sqlite_conn(PortalQuery::get_all_with_mxid_sqlite).await,
-            DatabaseInner::Postgres(_) => self.with_postgres_conn(PortalQuery::get_all_with_mxid_postgres).await,
-        }
+        self.with_conn(PortalQuery::get_all_with_mxid).await
     }
 
     pub async fn insert_portal(&self, portal: &Portal) -> Result<()> {
         let portal = portal.clone();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => self.with_sqlite_conn(move |conn| PortalQuery::insert_sqlite(conn, &portal)).await,
-            DatabaseInner::Postgres(_) => self.with_postgres_conn(move |conn| PortalQuery::insert_postgres(conn, &portal)).await,
-        }
+        self.with_conn(move |conn| PortalQuery::insert(conn, &portal)).await
     }
 
     pub async fn update_portal(&self, portal: &Portal) -> Result<()> {
         let portal = portal.clone();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => self.with_sqlite_conn(move |conn| PortalQuery::update_sqlite(conn, &portal)).await,
-            DatabaseInner::Postgres(_) => self.with_postgres_conn(move |conn| PortalQuery::update_postgres(conn, &portal)).await,
-        }
+        self.with_conn(move |conn| PortalQuery::update(conn, &portal)).await
     }
 
     pub async fn delete_portal(&self, key: &PortalKey) -> Result<()> {
         let key = key.clone();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => self.with_sqlite_conn(move |conn| PortalQuery::delete_sqlite(conn, &key)).await,
-            DatabaseInner::Postgres(_) => self.with_postgres_conn(move |conn| PortalQuery::delete_postgres(conn, &key)).await,
-        }
+        self.with_conn(move |conn| PortalQuery::delete(conn, &key)).await
+    }
+
+    pub async fn get_portal_relay(&self, key: &PortalKey) -> Result<Option<PortalRelay>> {
+        let key = key.clone();
+        self.with_conn(move |conn| PortalRelayQuery::get_by_key(conn, &key)).await
+    }
+
+    pub async fn upsert_portal_relay(&self, relay: &PortalRelay) -> Result<()> {
+        let relay = relay.clone();
+        self.with_conn(move |conn| PortalRelayQuery::upsert(conn, &relay)).await
+    }
+
+    pub async fn delete_portal_relay(&self, key: &PortalKey) -> Result<()> {
+        let key = key.clone();
+        self.with_conn(move |conn| PortalRelayQuery::delete(conn, &key)).await
+    }
+
+    pub async fn get_outgoing_retry(&self, event_id: &str) -> Result<Option<OutgoingRetry>> {
+        let event_id = event_id.to_owned();
+        self.with_conn(move |conn| OutgoingRetryQuery::get_by_event_id(conn, &event_id)).await
+    }
+
+    pub async fn get_all_outgoing_retries(&self) -> Result<Vec<OutgoingRetry>> {
+        self.with_conn(OutgoingRetryQuery::get_all).await
+    }
+
+    pub async fn upsert_outgoing_retry(&self, retry: &OutgoingRetry) -> Result<()> {
+        let retry = retry.clone();
+        self.with_conn(move |conn| OutgoingRetryQuery::upsert(conn, &retry)).await
+    }
+
+    pub async fn delete_outgoing_retry(&self, event_id: &str) -> Result<()> {
+        let event_id = event_id.to_owned();
+        self.with_conn(move |conn| OutgoingRetryQuery::delete(conn, &event_id)).await
+    }
+
+    pub async fn is_transaction_processed(&self, txn_id: &str) -> Result<bool> {
+        let txn_id = txn_id.to_owned();
+        self.with_conn(move |conn| AppserviceTxnQuery::exists(conn, &txn_id)).await
+    }
+
+    pub async fn insert_processed_transaction(&self, txn: &AppserviceTxn) -> Result<()> {
+        let txn = txn.clone();
+        self.with_conn(move |conn| AppserviceTxnQuery::insert(conn, &txn)).await
+    }
+
+    pub async fn delete_processed_transactions_before(&self, timestamp: i64) -> Result<usize> {
+        self.with_conn(move |conn| AppserviceTxnQuery::delete_before(conn, timestamp)).await
+    }
+
+    pub async fn get_media_cache(&self, hash: &str) -> Result<Option<MediaCacheEntry>> {
+        let hash = hash.to_owned();
+        self.with_conn(move |conn| MediaCacheQuery::get(conn, &hash)).await
+    }
+
+    pub async fn upsert_media_cache(&self, entry: &MediaCacheEntry) -> Result<()> {
+        let entry = entry.clone();
+        self.with_conn(move |conn| MediaCacheQuery::upsert(conn, &entry)).await
+    }
+
+    pub async fn touch_media_cache(&self, hash: &str, timestamp: i64) -> Result<()> {
+        let hash = hash.to_owned();
+        self.with_conn(move |conn| MediaCacheQuery::touch(conn, &hash, timestamp)).await
+    }
+
+    pub async fn delete_media_cache_unused_before(&self, timestamp: i64) -> Result<usize> {
+        self.with_conn(move |conn| MediaCacheQuery::delete_unused_before(conn, timestamp)).await
+    }
+
+    /// Forgets all uploads, so media is uploaded to the homeserver again when next bridged.
+    pub async fn clear_media_cache(&self) -> Result<usize> {
+        self.with_conn(MediaCacheQuery::delete_all).await
+    }
+
+    pub async fn get_agent_token(&self, id: &str) -> Result<Option<AgentToken>> {
+        let id = id.to_owned();
+        self.with_conn(move |conn| AgentTokenQuery::get(conn, &id)).await
+    }
+
+    pub async fn get_agent_tokens(&self) -> Result<Vec<AgentToken>> {
+        self.with_conn(AgentTokenQuery::get_all).await
+    }
+
+    pub async fn insert_agent_token(&self, token: &AgentToken) -> Result<()> {
+        let token = token.clone();
+        self.with_conn(move |conn| AgentTokenQuery::insert(conn, &token)).await
+    }
+
+    pub async fn revoke_agent_token(&self, id: &str) -> Result<bool> {
+        let id = id.to_owned();
+        self.with_conn(move |conn| AgentTokenQuery::revoke(conn, &id)).await
+    }
+
+    pub async fn touch_agent_token(&self, id: &str, timestamp: i64) -> Result<()> {
+        let id = id.to_owned();
+        self.with_conn(move |conn| AgentTokenQuery::touch(conn, &id, timestamp)).await
+    }
+
+    pub async fn get_event_sequence(&self, mxid: &str) -> Result<Option<EventSequence>> {
+        let mxid = mxid.to_owned();
+        self.with_conn(move |conn| EventSequenceQuery::get(conn, &mxid)).await
+    }
+
+    pub async fn upsert_event_sequence(&self, item: &EventSequence) -> Result<()> {
+        let item = item.clone();
+        self.with_conn(move |conn| EventSequenceQuery::upsert(conn, &item)).await
+    }
+
+    pub async fn get_matrix_queue(&self) -> Result<Vec<MatrixQueueItem>> {
+        self.with_conn(MatrixQueueQuery::get_all).await
+    }
+
+    pub async fn upsert_matrix_queue_item(&self, item: &MatrixQueueItem) -> Result<()> {
+        let item = item.clone();
+        self.with_conn(move |conn| MatrixQueueQuery::upsert(conn, &item)).await
+    }
+
+    pub async fn delete_matrix_queue_item(&self, mxid: &str, event_id: &str) -> Result<()> {
+        let (mxid, event_id) = (mxid.to_owned(), event_id.to_owned());
+        self.with_conn(move |conn| MatrixQueueQuery::delete(conn, &mxid, &event_id)).await
+    }
+
+    pub async fn get_contacts(&self, user_mxid: &str) -> Result<Vec<Contact>> {
+        let user_mxid = user_mxid.to_owned();
+        self.with_conn(move |conn| ContactQuery::get_by_user(conn, &user_mxid)).await
+    }
+
+    pub async fn upsert_contact(&self, contact: &Contact) -> Result<()> {
+        let contact = contact.clone();
+        self.with_conn(move |conn| ContactQuery::upsert(conn, &contact)).await
+    }
+
+    pub async fn get_permission_overrides(&self) -> Result<Vec<PermissionOverride>> {
+        self.with_conn(PermissionOverrideQuery::get_all).await
+    }
+
+    pub async fn upsert_permission_override(&self, item: &PermissionOverride) -> Result<()> {
+        let item = item.clone();
+        self.with_conn(move |conn| PermissionOverrideQuery::upsert(conn, &item)).await
+    }
+
+    pub async fn delete_permission_override(&self, mxid: &str) -> Result<()> {
+        let mxid = mxid.to_owned();
+        self.with_conn(move |conn| PermissionOverrideQuery::delete(conn, &mxid)).await
     }
 
     pub async fn get_puppet_by_uin(&self, uin: &str) -> Result<Option<Puppet>> {
         let uin = uin.to_owned();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => {
-                self.with_sqlite_conn(move |conn| PuppetQuery::get_by_uin_sqlite(conn, &uin))
-                    .await
-            }
-            DatabaseInner::Postgres(_) => {
-                self.with_postgres_conn(move |conn| PuppetQuery::get_by_uin_postgres(conn, &uin))
-                    .await
-            }
-        }
+        self.with_conn(move |conn| PuppetQuery::get_by_uin(conn, &uin)).await
     }
 
     pub async fn get_puppet_by_custom_mxid(&self, mxid: &str) -> Result<Option<Puppet>> {
         let mxid = mxid.to_owned();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => {
-                self.with_sqlite_conn(move |conn| PuppetQuery::get_by_custom_mxid_sqlite(conn, &mxid))
-                    .await
-            }
-            DatabaseInner::Postgres(_) => {
-                self.with_postgres_conn(move |conn| PuppetQuery::get_by_custom_mxid_postgres(conn, &mxid))
-                    .await
-            }
-        }
+        self.with_conn(move |conn| PuppetQuery::get_by_custom_mxid(conn, &mxid)).await
     }
 
     pub async fn get_all_puppets_with_custom_mxid(&self) -> Result<Vec<Puppet>> {
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => self.with_sqlite_conn(PuppetQuery::get_all_with_custom_mxid_sqlite).await,
-            DatabaseInner::Postgres(_) => self.with_postgres_conn(PuppetQuery::get_all_with_custom_mxid_postgres).await,
-        }
+        self.with_conn(PuppetQuery::get_all_with_custom_mxid).await
     }
 
     pub async fn insert_puppet(&self, puppet: &Puppet) -> Result<()> {
         let puppet = puppet.clone();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => self.with_sqlite_conn(move |conn| PuppetQuery::insert_sqlite(conn, &puppet)).await,
-            DatabaseInner::Postgres(_) => self.with_postgres_conn(move |conn| PuppetQuery::insert_postgres(conn, &puppet)).await,
-        }
+        self.with_conn(move |conn| PuppetQuery::insert(conn, &puppet)).await
     }
 
     pub async fn update_puppet(&self, puppet: &Puppet) -> Result<()> {
         let puppet = puppet.clone();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => self.with_sqlite_conn(move |conn| PuppetQuery::update_sqlite(conn, &puppet)).await,
-            DatabaseInner::Postgres(_) => self.with_postgres_conn(move |conn| PuppetQuery::update_postgres(conn, &puppet)).await,
-        }
+        self.with_conn(move |conn| PuppetQuery::update(conn, &puppet)).await
     }
 
     pub async fn get_message_by_id(&self, key: &PortalKey, msg_id: &str) -> Result<Option<Message>> {
         let key = key.clone();
         let msg_id = msg_id.to_owned();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => {
-                self.with_sqlite_conn(move |conn| MessageQuery::get_by_id_sqlite(conn, &key, &msg_id))
-                    .await
-            }
-            DatabaseInner::Postgres(_) => {
-                self.with_postgres_conn(move |conn| MessageQuery::get_by_id_postgres(conn, &key, &msg_id))
-                    .await
-            }
-        }
+        self.with_conn(move |conn| MessageQuery::get_by_id(conn, &key, &msg_id)).await
     }
 
     pub async fn get_message_by_mxid(&self, mxid: &str) -> Result<Option<Message>> {
         let mxid = mxid.to_owned();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => {
-                self.with_sqlite_conn(move |conn| MessageQuery::get_by_mxid_sqlite(conn, &mxid))
-                    .await
-            }
-            DatabaseInner::Postgres(_) => {
-                self.with_postgres_conn(move |conn| MessageQuery::get_by_mxid_postgres(conn, &mxid))
-                    .await
-            }
-        }
+        self.with_conn(move |conn| MessageQuery::get_by_mxid(conn, &mxid)).await
     }
 
     pub async fn get_message_by_wechat_id(&self, msg_id: &str) -> Result<Option<Message>> {
         let msg_id = msg_id.to_owned();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => {
-                self.with_sqlite_conn(move |conn| MessageQuery::get_by_msg_id_sqlite(conn, &msg_id))
-                    .await
-            }
-            DatabaseInner::Postgres(_) => {
-                self.with_postgres_conn(move |conn| MessageQuery::get_by_msg_id_postgres(conn, &msg_id))
-                    .await
-            }
-        }
+        self.with_conn(move |conn| MessageQuery::get_by_msg_id(conn, &msg_id)).await
     }
 
     pub async fn get_last_message(&self, key: &PortalKey) -> Result<Option<Message>> {
         let key = key.clone();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => {
-                self.with_sqlite_conn(move |conn| MessageQuery::get_last_sqlite(conn, &key))
-                    .await
-            }
-            DatabaseInner::Postgres(_) => {
-                self.with_postgres_conn(move |conn| MessageQuery::get_last_postgres(conn, &key))
-                    .await
-            }
-        }
+        self.with_conn(move |conn| MessageQuery::get_last(conn, &key)).await
     }
 
     pub async fn insert_message(&self, msg: &Message) -> Result<()> {
         let msg = msg.clone();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => self.with_sqlite_conn(move |conn| MessageQuery::insert_sqlite(conn, &msg)).await,
-            DatabaseInner::Postgres(_) => self.with_postgres_conn(move |conn| MessageQuery::insert_postgres(conn, &msg)).await,
-        }
+        self.with_conn(move |conn| MessageQuery::insert(conn, &msg)).await
     }
 
     pub async fn update_message_mxid(
@@ -337,70 +429,118 @@ impl Database {
         let mxid = mxid.to_owned();
         let msg_type = msg_type.to_owned();
         let error = error.map(str::to_owned);
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => {
-                self.with_sqlite_conn(move |conn| {
-                    MessageQuery::update_mxid_sqlite(conn, &key, &msg_id, &mxid, &msg_type, error.as_deref())
-                })
-                .await
-            }
-            DatabaseInner::Postgres(_) => {
-                self.with_postgres_conn(move |conn| {
-                    MessageQuery::update_mxid_postgres(conn, &key, &msg_id, &mxid, &msg_type, error.as_deref())
-                })
-                .await
-            }
-        }
+        self.with_conn(move |conn| {
+            MessageQuery::update_mxid(conn, &key, &msg_id, &mxid, &msg_type, error.as_deref())
+        })
+        .await
+    }
+
+    /// Completes a message row written before it was sent, storing the ID it was given on the
+    /// other side, or the error when sending failed.
+    pub async fn complete_message(
+        &self,
+        key: &PortalKey,
+        pending_id: &str,
+        msg_id: &str,
+        mxid: &str,
+        error: Option<&str>,
+    ) -> Result<()> {
+        let key = key.clone();
+        let pending_id = pending_id.to_owned();
+        let msg_id = msg_id.to_owned();
+        let mxid = mxid.to_owned();
+        let error = error.map(str::to_owned);
+        self.with_conn(move |conn| {
+            MessageQuery::complete(conn, &key, &pending_id, &msg_id, &mxid, error.as_deref())
+        })
+        .await
+    }
+
+    /// Messages that were written before sending but never completed, because the bridge
+    /// stopped in between.
+    pub async fn get_pending_messages(&self) -> Result<Vec<Message>> {
+        self.with_conn(MessageQuery::get_pending).await
     }
 
     pub async fn delete_message(&self, key: &PortalKey, msg_id: &str) -> Result<()> {
         let key = key.clone();
         let msg_id = msg_id.to_owned();
-        match &self.inner {
-            DatabaseInner::Sqlite(_) => {
-                self.with_sqlite_conn(move |conn| MessageQuery::delete_sqlite(conn, &key, &msg_id))
-                    .await
-            }
-            DatabaseInner::Postgres(_) => {
-                self.with_postgres_conn(move |conn| MessageQuery::delete_postgres(conn, &key, &msg_id))
-                    .await
-            }
-        }
+        self.with_conn(move |conn| MessageQuery::delete(conn, &key, &msg_id)).await
     }
 
-    async fn with_sqlite_conn<T, F>(&self, f: F) -> Result<T>
-    where
-        T: Send + 'static,
-        F: FnOnce(&mut SqliteConnection) -> Result<T> + Send + 'static,
-    {
-        let pool = match &self.inner {
-            DatabaseInner::Sqlite(pool) => pool.clone(),
-            DatabaseInner::Postgres(_) => anyhow::bail!("internal error: expected sqlite database"),
-        };
-        tokio::task::spawn_blocking(move || {
-            let mut conn = pool
-                .get()
-                .context("failed to get sqlite connection from pool")?;
-            conn.batch_execute("PRAGMA foreign_keys = ON;")?;
-            f(&mut conn)
+    pub async fn get_reaction_by_target(
+        &self,
+        key: &PortalKey,
+        target_msg_id: &str,
+        sender: &str,
+    ) -> Result<Option<Reaction>> {
+        let key = key.clone();
+        let target_msg_id = target_msg_id.to_owned();
+        let sender = sender.to_owned();
+        self.with_conn(move |conn| {
+            ReactionQuery::get_by_target(conn, &key, &target_msg_id, &sender)
         })
         .await
-        .context("diesel task join error")?
     }
 
-    async fn with_postgres_conn<T, F>(&self, f: F) -> Result<T>
+    pub async fn get_reaction_by_mxid(&self, mxid: &str) -> Result<Option<Reaction>> {
+        let mxid = mxid.to_owned();
+        self.with_conn(move |conn| ReactionQuery::get_by_mxid(conn, &mxid)).await
+    }
+
+    pub async fn get_reaction_by_wechat_id(&self, msg_id: &str) -> Result<Option<Reaction>> {
+        let msg_id = msg_id.to_owned();
+        self.with_conn(move |conn| ReactionQuery::get_by_msg_id(conn, &msg_id)).await
+    }
+
+    pub async fn upsert_reaction(&self, reaction: &Reaction) -> Result<()> {
+        let reaction = reaction.clone();
+        self.with_conn(move |conn| ReactionQuery::upsert(conn, &reaction)).await
+    }
+
+    pub async fn delete_reaction_by_mxid(&self, mxid: &str) -> Result<()> {
+        let mxid = mxid.to_owned();
+        self.with_conn(move |conn| ReactionQuery::delete_by_mxid(conn, &mxid)).await
+    }
+
+    pub async fn get_message_edit_by_mxid(&self, mxid: &str) -> Result<Option<MessageEdit>> {
+        let mxid = mxid.to_owned();
+        self.with_conn(move |conn| MessageEditQuery::get_by_mxid(conn, &mxid)).await
+    }
+
+    pub async fn get_message_edit_by_wechat_id(&self, msg_id: &str) -> Result<Option<MessageEdit>> {
+        let msg_id = msg_id.to_owned();
+        self.with_conn(move |conn| MessageEditQuery::get_by_msg_id(conn, &msg_id)).await
+    }
+
+    pub async fn get_message_edits(&self, key: &PortalKey, target_msg_id: &str) -> Result<Vec<MessageEdit>> {
+        let key = key.clone();
+        let target_msg_id = target_msg_id.to_owned();
+        self.with_conn(move |conn| MessageEditQuery::get_by_target(conn, &key, &target_msg_id)).await
+    }
+
+    pub async fn insert_message_edit(&self, edit: &MessageEdit) -> Result<()> {
+        let edit = edit.clone();
+        self.with_conn(move |conn| MessageEditQuery::insert(conn, &edit)).await
+    }
+
+    pub async fn delete_message_edits(&self, key: &PortalKey, target_msg_id: &str) -> Result<()> {
+        let key = key.clone();
+        let target_msg_id = target_msg_id.to_owned();
+        self.with_conn(move |conn| MessageEditQuery::delete_by_target(conn, &key, &target_msg_id)).await
+    }
+
+    /// Runs a query on a pooled connection, off the async runtime.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
     where
         T: Send + 'static,
-        F: FnOnce(&mut PgConnection) -> Result<T> + Send + 'static,
+        F: FnOnce(&mut DbConnection) -> Result<T> + Send + 'static,
     {
-        let pool = match &self.inner {
-            DatabaseInner::Sqlite(_) => anyhow::bail!("internal error: expected postgres database"),
-            DatabaseInner::Postgres(pool) => pool.clone(),
-        };
+        let pool = self.pool.clone();
         tokio::task::spawn_blocking(move || {
             let mut conn = pool
                 .get()
-                .context("failed to get postgres connection from pool")?;
+                .context("failed to get database connection from pool")?;
             f(&mut conn)
         })
         .await
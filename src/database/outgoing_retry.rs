@@ -0,0 +1,55 @@
+use anyhow::Result;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::DbConnection;
+use super::schema::outgoing_retry;
+
+/// A Matrix message waiting to be sent to WeChat again after a retriable failure.
+#[derive(Debug, Clone, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = outgoing_retry)]
+pub struct OutgoingRetry {
+    pub event_id: String,
+    pub room_id: String,
+    /// The original Matrix event, serialized as JSON.
+    pub event: String,
+    /// The error notice sent to the room, edited once the message is delivered or given up on.
+    pub notice_id: Option<String>,
+}
+
+pub struct OutgoingRetryQuery;
+
+impl OutgoingRetryQuery {
+    pub fn get_by_event_id(conn: &mut DbConnection, event_id: &str) -> Result<Option<OutgoingRetry>> {
+        let item = outgoing_retry::table
+            .select(OutgoingRetry::as_select())
+            .filter(outgoing_retry::event_id.eq(event_id))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
+
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<OutgoingRetry>> {
+        let items = outgoing_retry::table
+            .select(OutgoingRetry::as_select())
+            .load(conn)?;
+        Ok(items)
+    }
+
+    pub fn upsert(conn: &mut DbConnection, item: &OutgoingRetry) -> Result<()> {
+        conn.transaction(|conn| {
+            diesel::delete(outgoing_retry::table.filter(outgoing_retry::event_id.eq(&item.event_id)))
+                .execute(conn)?;
+            diesel::insert_into(outgoing_retry::table)
+                .values(item)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    pub fn delete(conn: &mut DbConnection, event_id: &str) -> Result<()> {
+        diesel::delete(outgoing_retry::table.filter(outgoing_retry::event_id.eq(event_id)))
+            .execute(conn)?;
+        Ok(())
+    }
+}
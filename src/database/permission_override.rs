@@ -0,0 +1,43 @@
+use anyhow::Result;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::DbConnection;
+use super::schema::permission_override;
+
+/// A permission level set with the `set-permission` admin command, taking precedence over the
+/// `permissions` in the config.
+#[derive(Debug, Clone, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = permission_override)]
+pub struct PermissionOverride {
+    pub mxid: String,
+    pub level: String,
+}
+
+pub struct PermissionOverrideQuery;
+
+impl PermissionOverrideQuery {
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<PermissionOverride>> {
+        let items = permission_override::table
+            .select(PermissionOverride::as_select())
+            .load(conn)?;
+        Ok(items)
+    }
+
+    pub fn upsert(conn: &mut DbConnection, item: &PermissionOverride) -> Result<()> {
+        conn.transaction(|conn| {
+            diesel::delete(permission_override::table.filter(permission_override::mxid.eq(&item.mxid)))
+                .execute(conn)?;
+            diesel::insert_into(permission_override::table)
+                .values(item)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    pub fn delete(conn: &mut DbConnection, mxid: &str) -> Result<()> {
+        diesel::delete(permission_override::table.filter(permission_override::mxid.eq(mxid)))
+            .execute(conn)?;
+        Ok(())
+    }
+}
@@ -1,11 +1,10 @@
 use super::PortalKey;
 use anyhow::Result;
 use chrono::{DateTime, Utc};
-use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use diesel::sqlite::SqliteConnection;
 use serde::{Deserialize, Serialize};
 
+use super::DbConnection;
 use super::schema::portal;
 
 #[derive(Debug, Clone, Queryable, Selectable, Insertable, Serialize, Deserialize)]
@@ -47,102 +46,91 @@ impl Portal {
 
 pub struct PortalQuery;
 
-macro_rules! impl_portal_query_for_conn {
-    (
-        $get_by_key:ident,
-        $get_by_mxid:ident,
-        $get_all_with_mxid:ident,
-        $insert:ident,
-        $update:ident,
-        $delete:ident,
-        $conn_ty:ty
-    ) => {
-        pub fn $get_by_key(conn: &mut $conn_ty, key: &PortalKey) -> Result<Option<Portal>> {
-            let item = portal::table
-                .select(Portal::as_select())
-                .filter(portal::uid.eq(&key.uid))
-                .filter(portal::receiver.eq(&key.receiver))
-                .first(conn)
-                .optional()?;
-            Ok(item)
-        }
+impl PortalQuery {
+    pub fn get_by_key(conn: &mut DbConnection, key: &PortalKey) -> Result<Option<Portal>> {
+        let item = portal::table
+            .select(Portal::as_select())
+            .filter(portal::uid.eq(&key.uid))
+            .filter(portal::receiver.eq(&key.receiver))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
 
-        pub fn $get_by_mxid(conn: &mut $conn_ty, mxid: &str) -> Result<Option<Portal>> {
-            let item = portal::table
-                .select(Portal::as_select())
-                .filter(portal::mxid.eq(mxid))
-                .first(conn)
-                .optional()?;
-            Ok(item)
-        }
+    pub fn get_by_mxid(conn: &mut DbConnection, mxid: &str) -> Result<Option<Portal>> {
+        let item = portal::table
+            .select(Portal::as_select())
+            .filter(portal::mxid.eq(mxid))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
 
-        pub fn $get_all_with_mxid(conn: &mut $conn_ty) -> Result<Vec<Portal>> {
-            let items = portal::table
-                .select(Portal::as_select())
-                .filter(portal::mxid.is_not_null())
-                .load(conn)?;
-            Ok(items)
-        }
+    pub fn get_by_uid(conn: &mut DbConnection, uid: &str) -> Result<Vec<Portal>> {
+        let items = portal::table
+            .select(Portal::as_select())
+            .filter(portal::uid.eq(uid))
+            .load(conn)?;
+        Ok(items)
+    }
 
-        pub fn $insert(conn: &mut $conn_ty, item: &Portal) -> Result<()> {
-            diesel::insert_into(portal::table).values(item).execute(conn)?;
-            Ok(())
-        }
+    pub fn get_by_receiver(conn: &mut DbConnection, receiver: &str) -> Result<Vec<Portal>> {
+        let items = portal::table
+            .select(Portal::as_select())
+            .filter(portal::receiver.eq(receiver))
+            .load(conn)?;
+        Ok(items)
+    }
 
-        pub fn $update(conn: &mut $conn_ty, item: &Portal) -> Result<()> {
-            diesel::update(
-                portal::table
-                    .filter(portal::uid.eq(&item.uid))
-                    .filter(portal::receiver.eq(&item.receiver)),
-            )
-            .set((
-                portal::mxid.eq(&item.mxid),
-                portal::name.eq(&item.name),
-                portal::name_set.eq(item.name_set),
-                portal::topic.eq(&item.topic),
-                portal::topic_set.eq(item.topic_set),
-                portal::avatar.eq(&item.avatar),
-                portal::avatar_url.eq(&item.avatar_url),
-                portal::avatar_set.eq(item.avatar_set),
-                portal::encrypted.eq(item.encrypted),
-                portal::last_sync.eq(item.last_sync),
-                portal::first_event_id.eq(&item.first_event_id),
-                portal::next_batch_id.eq(&item.next_batch_id),
-            ))
-            .execute(conn)?;
-            Ok(())
-        }
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<Portal>> {
+        let items = portal::table.select(Portal::as_select()).load(conn)?;
+        Ok(items)
+    }
 
-        pub fn $delete(conn: &mut $conn_ty, key: &PortalKey) -> Result<()> {
-            diesel::delete(
-                portal::table
-                    .filter(portal::uid.eq(&key.uid))
-                    .filter(portal::receiver.eq(&key.receiver)),
-            )
-            .execute(conn)?;
-            Ok(())
-        }
-    };
-}
+    pub fn get_all_with_mxid(conn: &mut DbConnection) -> Result<Vec<Portal>> {
+        let items = portal::table
+            .select(Portal::as_select())
+            .filter(portal::mxid.is_not_null())
+            .load(conn)?;
+        Ok(items)
+    }
 
-impl PortalQuery {
-    impl_portal_query_for_conn!(
-        get_by_key_sqlite,
-        get_by_mxid_sqlite,
-        get_all_with_mxid_sqlite,
-        insert_sqlite,
-        update_sqlite,
-        delete_sqlite,
-        SqliteConnection
-    );
+    pub fn insert(conn: &mut DbConnection, item: &Portal) -> Result<()> {
+        diesel::insert_into(portal::table).values(item).execute(conn)?;
+        Ok(())
+    }
+
+    pub fn update(conn: &mut DbConnection, item: &Portal) -> Result<()> {
+        diesel::update(
+            portal::table
+                .filter(portal::uid.eq(&item.uid))
+                .filter(portal::receiver.eq(&item.receiver)),
+        )
+        .set((
+            portal::mxid.eq(&item.mxid),
+            portal::name.eq(&item.name),
+            portal::name_set.eq(item.name_set),
+            portal::topic.eq(&item.topic),
+            portal::topic_set.eq(item.topic_set),
+            portal::avatar.eq(&item.avatar),
+            portal::avatar_url.eq(&item.avatar_url),
+            portal::avatar_set.eq(item.avatar_set),
+            portal::encrypted.eq(item.encrypted),
+            portal::last_sync.eq(item.last_sync),
+            portal::first_event_id.eq(&item.first_event_id),
+            portal::next_batch_id.eq(&item.next_batch_id),
+        ))
+        .execute(conn)?;
+        Ok(())
+    }
 
-    impl_portal_query_for_conn!(
-        get_by_key_postgres,
-        get_by_mxid_postgres,
-        get_all_with_mxid_postgres,
-        insert_postgres,
-        update_postgres,
-        delete_postgres,
-        PgConnection
-    );
+    pub fn delete(conn: &mut DbConnection, key: &PortalKey) -> Result<()> {
+        diesel::delete(
+            portal::table
+                .filter(portal::uid.eq(&key.uid))
+                .filter(portal::receiver.eq(&key.receiver)),
+        )
+        .execute(conn)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,64 @@
+use anyhow::Result;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::PortalKey;
+use super::DbConnection;
+use super::schema::portal_relay;
+
+#[derive(Debug, Clone, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = portal_relay)]
+pub struct PortalRelay {
+    pub chat_uid: String,
+    pub chat_receiver: String,
+    pub relay_mxid: String,
+}
+
+impl PortalRelay {
+    pub fn new(key: &PortalKey, relay_mxid: impl Into<String>) -> Self {
+        Self {
+            chat_uid: key.uid.clone(),
+            chat_receiver: key.receiver.clone(),
+            relay_mxid: relay_mxid.into(),
+        }
+    }
+}
+
+pub struct PortalRelayQuery;
+
+impl PortalRelayQuery {
+    pub fn get_by_key(conn: &mut DbConnection, key: &PortalKey) -> Result<Option<PortalRelay>> {
+        let item = portal_relay::table
+            .select(PortalRelay::as_select())
+            .filter(portal_relay::chat_uid.eq(&key.uid))
+            .filter(portal_relay::chat_receiver.eq(&key.receiver))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
+
+    pub fn upsert(conn: &mut DbConnection, item: &PortalRelay) -> Result<()> {
+        conn.transaction(|conn| {
+            diesel::delete(
+                portal_relay::table
+                    .filter(portal_relay::chat_uid.eq(&item.chat_uid))
+                    .filter(portal_relay::chat_receiver.eq(&item.chat_receiver)),
+            )
+            .execute(conn)?;
+            diesel::insert_into(portal_relay::table)
+                .values(item)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    pub fn delete(conn: &mut DbConnection, key: &PortalKey) -> Result<()> {
+        diesel::delete(
+            portal_relay::table
+                .filter(portal_relay::chat_uid.eq(&key.uid))
+                .filter(portal_relay::chat_receiver.eq(&key.receiver)),
+        )
+        .execute(conn)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+use super::PortalKey;
+use anyhow::Result;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::DbConnection;
+use super::schema::portal_setting;
+
+/// The preferences of a user for one portal, set with the `set` command in its room.
+#[derive(Debug, Clone, PartialEq, Eq, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = portal_setting)]
+pub struct PortalSetting {
+    pub chat_uid: String,
+    pub chat_receiver: String,
+    pub user_mxid: String,
+    /// WeChat messages in the chat aren't bridged to Matrix.
+    pub muted: bool,
+    /// The user's messages are sent as their plain body, without converting formatting.
+    pub plaintext_only: bool,
+    /// The user's messages may be sent through the portal's relay user when they aren't logged in.
+    pub relay: bool,
+    /// Used as the room name instead of the name of the WeChat chat.
+    pub room_name: Option<String>,
+}
+
+impl PortalSetting {
+    pub fn new(key: &PortalKey, user_mxid: impl Into<String>) -> Self {
+        Self {
+            chat_uid: key.uid.clone(),
+            chat_receiver: key.receiver.clone(),
+            user_mxid: user_mxid.into(),
+            muted: false,
+            plaintext_only: false,
+            relay: true,
+            room_name: None,
+        }
+    }
+
+    pub fn key(&self) -> PortalKey {
+        PortalKey::new(&self.chat_uid, &self.chat_receiver)
+    }
+}
+
+pub struct PortalSettingQuery;
+
+impl PortalSettingQuery {
+    pub fn get(conn: &mut DbConnection, key: &PortalKey, user_mxid: &str) -> Result<Option<PortalSetting>> {
+        let item = portal_setting::table
+            .select(PortalSetting::as_select())
+            .filter(portal_setting::chat_uid.eq(&key.uid))
+            .filter(portal_setting::chat_receiver.eq(&key.receiver))
+            .filter(portal_setting::user_mxid.eq(user_mxid))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
+
+    pub fn upsert(conn: &mut DbConnection, item: &PortalSetting) -> Result<()> {
+        conn.transaction(|conn| {
+            diesel::delete(
+                portal_setting::table
+                    .filter(portal_setting::chat_uid.eq(&item.chat_uid))
+                    .filter(portal_setting::chat_receiver.eq(&item.chat_receiver))
+                    .filter(portal_setting::user_mxid.eq(&item.user_mxid)),
+            )
+            .execute(conn)?;
+            diesel::insert_into(portal_setting::table)
+                .values(item)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+}
@@ -1,11 +1,10 @@
 use crate::util::UID;
 use anyhow::Result;
 use chrono::{DateTime, Utc};
-use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use diesel::sqlite::SqliteConnection;
 use serde::{Deserialize, Serialize};
 
+use super::DbConnection;
 use super::schema::puppet;
 
 #[derive(Debug, Clone, Queryable, Selectable, Insertable, Serialize, Deserialize)]
@@ -62,83 +61,54 @@ impl Puppet {
 
 pub struct PuppetQuery;
 
-macro_rules! impl_puppet_query_for_conn {
-    (
-        $get_by_uin:ident,
-        $get_by_custom_mxid:ident,
-        $get_all_with_custom_mxid:ident,
-        $insert:ident,
-        $update:ident,
-        $conn_ty:ty
-    ) => {
-        pub fn $get_by_uin(conn: &mut $conn_ty, uin: &str) -> Result<Option<Puppet>> {
-            let item = puppet::table
-                .select(Puppet::as_select())
-                .filter(puppet::uin.eq(uin))
-                .first(conn)
-                .optional()?;
-            Ok(item)
-        }
-
-        pub fn $get_by_custom_mxid(conn: &mut $conn_ty, mxid: &str) -> Result<Option<Puppet>> {
-            let item = puppet::table
-                .select(Puppet::as_select())
-                .filter(puppet::custom_mxid.eq(mxid))
-                .first(conn)
-                .optional()?;
-            Ok(item)
-        }
-
-        pub fn $get_all_with_custom_mxid(conn: &mut $conn_ty) -> Result<Vec<Puppet>> {
-            let items = puppet::table
-                .select(Puppet::as_select())
-                .filter(puppet::custom_mxid.is_not_null().and(puppet::custom_mxid.ne("")))
-                .load(conn)?;
-            Ok(items)
-        }
+impl PuppetQuery {
+    pub fn get_by_uin(conn: &mut DbConnection, uin: &str) -> Result<Option<Puppet>> {
+        let item = puppet::table
+            .select(Puppet::as_select())
+            .filter(puppet::uin.eq(uin))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
 
-        pub fn $insert(conn: &mut $conn_ty, item: &Puppet) -> Result<()> {
-            diesel::insert_into(puppet::table).values(item).execute(conn)?;
-            Ok(())
-        }
+    pub fn get_by_custom_mxid(conn: &mut DbConnection, mxid: &str) -> Result<Option<Puppet>> {
+        let item = puppet::table
+            .select(Puppet::as_select())
+            .filter(puppet::custom_mxid.eq(mxid))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
 
-        pub fn $update(conn: &mut $conn_ty, item: &Puppet) -> Result<()> {
-            diesel::update(puppet::table.filter(puppet::uin.eq(&item.uin)))
-                .set((
-                    puppet::displayname.eq(&item.displayname),
-                    puppet::name_quality.eq(item.name_quality),
-                    puppet::name_set.eq(item.name_set),
-                    puppet::avatar.eq(&item.avatar),
-                    puppet::avatar_url.eq(&item.avatar_url),
-                    puppet::avatar_set.eq(item.avatar_set),
-                    puppet::last_sync.eq(item.last_sync),
-                    puppet::custom_mxid.eq(&item.custom_mxid),
-                    puppet::access_token.eq(&item.access_token),
-                    puppet::next_batch.eq(&item.next_batch),
-                    puppet::enable_presence.eq(item.enable_presence),
-                ))
-                .execute(conn)?;
-            Ok(())
-        }
-    };
-}
+    pub fn get_all_with_custom_mxid(conn: &mut DbConnection) -> Result<Vec<Puppet>> {
+        let items = puppet::table
+            .select(Puppet::as_select())
+            .filter(puppet::custom_mxid.is_not_null().and(puppet::custom_mxid.ne("")))
+            .load(conn)?;
+        Ok(items)
+    }
 
-impl PuppetQuery {
-    impl_puppet_query_for_conn!(
-        get_by_uin_sqlite,
-        get_by_custom_mxid_sqlite,
-        get_all_with_custom_mxid_sqlite,
-        insert_sqlite,
-        update_sqlite,
-        SqliteConnection
-    );
+    pub fn insert(conn: &mut DbConnection, item: &Puppet) -> Result<()> {
+        diesel::insert_into(puppet::table).values(item).execute(conn)?;
+        Ok(())
+    }
 
-    impl_puppet_query_for_conn!(
-        get_by_uin_postgres,
-        get_by_custom_mxid_postgres,
-        get_all_with_custom_mxid_postgres,
-        insert_postgres,
-        update_postgres,
-        PgConnection
-    );
+    pub fn update(conn: &mut DbConnection, item: &Puppet) -> Result<()> {
+        diesel::update(puppet::table.filter(puppet::uin.eq(&item.uin)))
+            .set((
+                puppet::displayname.eq(&item.displayname),
+                puppet::name_quality.eq(item.name_quality),
+                puppet::name_set.eq(item.name_set),
+                puppet::avatar.eq(&item.avatar),
+                puppet::avatar_url.eq(&item.avatar_url),
+                puppet::avatar_set.eq(item.avatar_set),
+                puppet::last_sync.eq(item.last_sync),
+                puppet::custom_mxid.eq(&item.custom_mxid),
+                puppet::access_token.eq(&item.access_token),
+                puppet::next_batch.eq(&item.next_batch),
+                puppet::enable_presence.eq(item.enable_presence),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
 }
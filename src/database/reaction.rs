@@ -0,0 +1,88 @@
+use super::PortalKey;
+use anyhow::Result;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::DbConnection;
+use super::schema::reaction;
+
+#[derive(Debug, Clone, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = reaction)]
+pub struct Reaction {
+    pub chat_uid: String,
+    pub chat_receiver: String,
+    pub target_msg_id: String,
+    pub sender: String,
+    pub mxid: String,
+    pub reaction_key: String,
+    /// The WeChat ID of the reaction, unknown for reactions sent from Matrix.
+    pub msg_id: Option<String>,
+    pub timestamp: i64,
+}
+
+impl Reaction {
+    pub fn key(&self) -> PortalKey {
+        PortalKey::new(&self.chat_uid, &self.chat_receiver)
+    }
+}
+
+pub struct ReactionQuery;
+
+impl ReactionQuery {
+    pub fn get_by_target(
+        conn: &mut DbConnection,
+        key: &PortalKey,
+        target_msg_id: &str,
+        sender: &str,
+    ) -> Result<Option<Reaction>> {
+        let item = reaction::table
+            .select(Reaction::as_select())
+            .filter(reaction::chat_uid.eq(&key.uid))
+            .filter(reaction::chat_receiver.eq(&key.receiver))
+            .filter(reaction::target_msg_id.eq(target_msg_id))
+            .filter(reaction::sender.eq(sender))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
+
+    pub fn get_by_mxid(conn: &mut DbConnection, mxid: &str) -> Result<Option<Reaction>> {
+        let item = reaction::table
+            .select(Reaction::as_select())
+            .filter(reaction::mxid.eq(mxid))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
+
+    pub fn get_by_msg_id(conn: &mut DbConnection, msg_id: &str) -> Result<Option<Reaction>> {
+        let item = reaction::table
+            .select(Reaction::as_select())
+            .filter(reaction::msg_id.eq(msg_id))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
+
+    pub fn upsert(conn: &mut DbConnection, item: &Reaction) -> Result<()> {
+        conn.transaction(|conn| {
+            diesel::delete(
+                reaction::table
+                    .filter(reaction::chat_uid.eq(&item.chat_uid))
+                    .filter(reaction::chat_receiver.eq(&item.chat_receiver))
+                    .filter(reaction::target_msg_id.eq(&item.target_msg_id))
+                    .filter(reaction::sender.eq(&item.sender)),
+            )
+            .execute(conn)?;
+            diesel::insert_into(reaction::table)
+                .values(item)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    pub fn delete_by_mxid(conn: &mut DbConnection, mxid: &str) -> Result<()> {
+        diesel::delete(reaction::table.filter(reaction::mxid.eq(mxid))).execute(conn)?;
+        Ok(())
+    }
+}
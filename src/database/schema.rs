@@ -59,9 +59,170 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    reaction (chat_uid, chat_receiver, target_msg_id, sender) {
+        chat_uid -> Text,
+        chat_receiver -> Text,
+        target_msg_id -> Text,
+        sender -> Text,
+        mxid -> Text,
+        reaction_key -> Text,
+        msg_id -> Nullable<Text>,
+        timestamp -> BigInt,
+    }
+}
+
+diesel::table! {
+    message_edit (chat_uid, chat_receiver, msg_id) {
+        chat_uid -> Text,
+        chat_receiver -> Text,
+        msg_id -> Text,
+        target_msg_id -> Text,
+        mxid -> Text,
+        sender -> Text,
+        timestamp -> BigInt,
+    }
+}
+
+diesel::table! {
+    user_setting (mxid) {
+        mxid -> Text,
+        message_marker -> Nullable<Bool>,
+    }
+}
+
+diesel::table! {
+    chat_watermark (mxid, chat_id) {
+        mxid -> Text,
+        chat_id -> Text,
+        last_ts -> BigInt,
+        last_msg_id -> Text,
+    }
+}
+
+diesel::table! {
+    portal_relay (chat_uid, chat_receiver) {
+        chat_uid -> Text,
+        chat_receiver -> Text,
+        relay_mxid -> Text,
+    }
+}
+
+diesel::table! {
+    outgoing_retry (event_id) {
+        event_id -> Text,
+        room_id -> Text,
+        event -> Text,
+        notice_id -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    contact (user_mxid, uin) {
+        user_mxid -> Text,
+        uin -> Text,
+        name -> Text,
+        remark -> Nullable<Text>,
+        avatar -> Nullable<Text>,
+        deleted -> Bool,
+        updated_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    appservice_txn (txn_id) {
+        txn_id -> Text,
+        processed_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    permission_override (mxid) {
+        mxid -> Text,
+        level -> Text,
+    }
+}
+
+diesel::table! {
+    db_version (version) {
+        version -> Integer,
+        name -> Text,
+        applied_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    portal_setting (chat_uid, chat_receiver, user_mxid) {
+        chat_uid -> Text,
+        chat_receiver -> Text,
+        user_mxid -> Text,
+        muted -> Bool,
+        plaintext_only -> Bool,
+        relay -> Bool,
+        room_name -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    media_cache (hash) {
+        hash -> Text,
+        mxc -> Text,
+        mimetype -> Text,
+        size -> BigInt,
+        created_at -> BigInt,
+        last_used -> BigInt,
+    }
+}
+
+diesel::table! {
+    agent_token (id) {
+        id -> Text,
+        token_hash -> Text,
+        description -> Text,
+        created_at -> BigInt,
+        last_used -> BigInt,
+        revoked -> Bool,
+    }
+}
+
+diesel::table! {
+    event_sequence (mxid) {
+        mxid -> Text,
+        seq -> BigInt,
+        updated_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    matrix_queue (mxid, event_id) {
+        mxid -> Text,
+        event_id -> Text,
+        chat_id -> Text,
+        event -> Text,
+        attempts -> Integer,
+        queued_at -> BigInt,
+        next_attempt_at -> BigInt,
+    }
+}
+
 diesel::allow_tables_to_appear_in_same_query!(
     users,
     puppet,
     portal,
     message,
+    reaction,
+    user_setting,
+    chat_watermark,
+    portal_relay,
+    outgoing_retry,
+    appservice_txn,
+    contact,
+    permission_override,
+    db_version,
+    message_edit,
+    portal_setting,
+    media_cache,
+    agent_token,
+    event_sequence,
+    matrix_queue,
 );
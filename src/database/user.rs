@@ -1,9 +1,8 @@
 use anyhow::Result;
-use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use diesel::sqlite::SqliteConnection;
 use serde::{Deserialize, Serialize};
 
+use super::DbConnection;
 use super::schema::users;
 
 #[derive(Debug, Clone, Queryable, Selectable, Insertable, Serialize, Deserialize)]
@@ -32,68 +31,59 @@ impl User {
 
 pub struct UserQuery;
 
-macro_rules! impl_user_query_for_conn {
-    ($get_by_mxid:ident, $get_by_uin:ident, $get_all_logged_in:ident, $insert:ident, $update:ident, $conn_ty:ty) => {
-        pub fn $get_by_mxid(conn: &mut $conn_ty, mxid: &str) -> Result<Option<User>> {
-            let user = users::table
-                .select(User::as_select())
-                .filter(users::mxid.eq(mxid))
-                .first(conn)
-                .optional()?;
-            Ok(user)
-        }
+impl UserQuery {
+    pub fn get_by_mxid(conn: &mut DbConnection, mxid: &str) -> Result<Option<User>> {
+        let user = users::table
+            .select(User::as_select())
+            .filter(users::mxid.eq(mxid))
+            .first(conn)
+            .optional()?;
+        Ok(user)
+    }
 
-        pub fn $get_by_uin(conn: &mut $conn_ty, uin: &str) -> Result<Option<User>> {
-            let user = users::table
-                .select(User::as_select())
-                .filter(users::uin.eq(uin))
-                .first(conn)
-                .optional()?;
-            Ok(user)
-        }
+    pub fn get_by_uin(conn: &mut DbConnection, uin: &str) -> Result<Option<User>> {
+        let user = users::table
+            .select(User::as_select())
+            .filter(users::uin.eq(uin))
+            .first(conn)
+            .optional()?;
+        Ok(user)
+    }
 
-        pub fn $get_all_logged_in(conn: &mut $conn_ty) -> Result<Vec<User>> {
-            let items = users::table
-                .select(User::as_select())
-                .filter(users::uin.is_not_null().and(users::uin.ne("")))
-                .load(conn)?;
-            Ok(items)
-        }
+    pub fn get_all(conn: &mut DbConnection) -> Result<Vec<User>> {
+        let items = users::table
+            .select(User::as_select())
+            .order(users::mxid.asc())
+            .load(conn)?;
+        Ok(items)
+    }
 
-        pub fn $insert(conn: &mut $conn_ty, user: &User) -> Result<()> {
-            diesel::insert_into(users::table).values(user).execute(conn)?;
-            Ok(())
-        }
+    pub fn get_all_logged_in(conn: &mut DbConnection) -> Result<Vec<User>> {
+        let items = users::table
+            .select(User::as_select())
+            .filter(users::uin.is_not_null().and(users::uin.ne("")))
+            .load(conn)?;
+        Ok(items)
+    }
 
-        pub fn $update(conn: &mut $conn_ty, user: &User) -> Result<()> {
-            diesel::update(users::table.filter(users::mxid.eq(&user.mxid)))
-                .set((
-                    users::uin.eq(&user.uin),
-                    users::management_room.eq(&user.management_room),
-                    users::space_room.eq(&user.space_room),
-                ))
-                .execute(conn)?;
-            Ok(())
-        }
-    };
-}
+    pub fn insert(conn: &mut DbConnection, user: &User) -> Result<()> {
+        diesel::insert_into(users::table).values(user).execute(conn)?;
+        Ok(())
+    }
 
-impl UserQuery {
-    impl_user_query_for_conn!(
-        get_by_mxid_sqlite,
-        get_by_uin_sqlite,
-        get_all_logged_in_sqlite,
-        insert_sqlite,
-        update_sqlite,
-        SqliteConnection
-    );
+    pub fn update(conn: &mut DbConnection, user: &User) -> Result<()> {
+        diesel::update(users::table.filter(users::mxid.eq(&user.mxid)))
+            .set((
+                users::uin.eq(&user.uin),
+                users::management_room.eq(&user.management_room),
+                users::space_room.eq(&user.space_room),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
 
-    impl_user_query_for_conn!(
-        get_by_mxid_postgres,
-        get_by_uin_postgres,
-        get_all_logged_in_postgres,
-        insert_postgres,
-        update_postgres,
-        PgConnection
-    );
+    pub fn delete(conn: &mut DbConnection, mxid: &str) -> Result<()> {
+        diesel::delete(users::table.filter(users::mxid.eq(mxid))).execute(conn)?;
+        Ok(())
+    }
 }
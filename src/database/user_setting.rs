@@ -0,0 +1,46 @@
+use anyhow::Result;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::DbConnection;
+use super::schema::user_setting;
+
+#[derive(Debug, Clone, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = user_setting)]
+pub struct UserSetting {
+    pub mxid: String,
+    pub message_marker: Option<bool>,
+}
+
+impl UserSetting {
+    pub fn new(mxid: impl Into<String>) -> Self {
+        Self {
+            mxid: mxid.into(),
+            message_marker: None,
+        }
+    }
+}
+
+pub struct UserSettingQuery;
+
+impl UserSettingQuery {
+    pub fn get_by_mxid(conn: &mut DbConnection, mxid: &str) -> Result<Option<UserSetting>> {
+        let item = user_setting::table
+            .select(UserSetting::as_select())
+            .filter(user_setting::mxid.eq(mxid))
+            .first(conn)
+            .optional()?;
+        Ok(item)
+    }
+
+    pub fn upsert(conn: &mut DbConnection, item: &UserSetting) -> Result<()> {
+        conn.transaction(|conn| {
+            diesel::delete(user_setting::table.filter(user_setting::mxid.eq(&item.mxid)))
+                .execute(conn)?;
+            diesel::insert_into(user_setting::table)
+                .values(item)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+}
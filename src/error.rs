@@ -128,6 +128,9 @@ pub enum WeChatError {
     #[error("Service unavailable: {0}")]
     ServiceUnavailable(String),
 
+    #[error("Not supported by the agent: {0}")]
+    Unsupported(String),
+
     #[error("Invalid message type: {0}")]
     InvalidMessageType(String),
 
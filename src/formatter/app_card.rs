@@ -0,0 +1,95 @@
+use super::escape_html;
+use super::system::tag;
+
+const MINI_PROGRAM_APP_TYPES: &[&str] = &["33", "36"];
+const CHANNELS_APP_TYPE: &str = "51";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppCardKind {
+    MiniProgram,
+    /// A video shared from Channels (视频号).
+    Channels,
+}
+
+impl AppCardKind {
+    fn label(&self) -> &'static str {
+        match self {
+            AppCardKind::MiniProgram => "Mini program",
+            AppCardKind::Channels => "Channels",
+        }
+    }
+}
+
+/// A mini-program or Channels share card, parsed from the XML of a WeChat app message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppCard {
+    pub kind: AppCardKind,
+    pub title: String,
+    /// The name of the mini program, or the author of the Channels video.
+    pub source: Option<String>,
+    /// A web link to open the card outside WeChat, if it has one.
+    pub url: Option<String>,
+    pub thumb_url: Option<String>,
+}
+
+impl AppCard {
+    pub fn parse(xml: &str) -> Option<Self> {
+        let app_type = tag(xml, "type")?;
+        let non_empty = |name: &str| tag(xml, name).filter(|v| !v.is_empty());
+        let url = non_empty("url").filter(|u| u.starts_with("http://") || u.starts_with("https://"));
+        if MINI_PROGRAM_APP_TYPES.contains(&app_type.as_str()) {
+            Some(Self {
+                kind: AppCardKind::MiniProgram,
+                title: non_empty("title").unwrap_or_default(),
+                source: non_empty("sourcedisplayname").or_else(|| non_empty("appname")),
+                url,
+                thumb_url: non_empty("thumburl").or_else(|| non_empty("weappiconurl")),
+            })
+        } else if app_type == CHANNELS_APP_TYPE {
+            let feed = tag(xml, "finderFeed")?;
+            let field = |name: &str| tag(&feed, name).filter(|v| !v.is_empty());
+            Some(Self {
+                kind: AppCardKind::Channels,
+                title: field("desc").or_else(|| non_empty("title")).unwrap_or_default(),
+                source: field("nickname"),
+                url,
+                thumb_url: field("thumbUrl").or_else(|| field("coverUrl")),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn heading(&self) -> String {
+        match &self.source {
+            Some(source) => format!("[{}] {}", self.kind.label(), source),
+            None => format!("[{}]", self.kind.label()),
+        }
+    }
+
+    pub fn body(&self) -> String {
+        let mut body = self.heading();
+        if !self.title.is_empty() {
+            body.push_str(&format!("\n{}", self.title));
+        }
+        if let Some(url) = &self.url {
+            body.push_str(&format!("\n{}", url));
+        }
+        body
+    }
+
+    /// The card as HTML, with the thumbnail once it's uploaded to Matrix.
+    pub fn html(&self, thumb_mxc: Option<&str>) -> String {
+        let mut html = format!("<p><strong>{}</strong></p>", escape_html(&self.heading()));
+        if let Some(mxc) = thumb_mxc {
+            html.push_str(&format!("<img src=\"{}\" alt=\"{}\" height=\"160\"/>", escape_html(mxc), escape_html(&self.title)));
+        }
+        if !self.title.is_empty() {
+            html.push_str(&format!("<p>{}</p>", escape_html(&self.title)));
+        }
+        if let Some(url) = &self.url {
+            html.push_str(&format!("<a href=\"{0}\">{0}</a>", escape_html(url)));
+        }
+        html
+    }
+}
@@ -0,0 +1,139 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use super::escape_html;
+use super::system::tag;
+
+const CHAT_RECORD_APP_TYPE: &str = "19";
+
+static DATA_ITEM: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?s)<dataitem\b([^>]*)>(.*?)</dataitem>").unwrap());
+static DATA_TYPE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"datatype\s*=\s*"(\d+)""#).unwrap());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordItemKind {
+    Text,
+    Image,
+    Voice,
+    Video,
+    Link,
+    Location,
+    File,
+    /// A chat record forwarded inside another.
+    Record,
+    Other,
+}
+
+impl RecordItemKind {
+    fn from_datatype(datatype: &str) -> Self {
+        match datatype {
+            "1" => Self::Text,
+            "2" => Self::Image,
+            "3" => Self::Voice,
+            "4" => Self::Video,
+            "5" => Self::Link,
+            "6" => Self::Location,
+            "8" => Self::File,
+            "17" => Self::Record,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// One of the messages in a chat record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordItem {
+    pub kind: RecordItemKind,
+    pub sender: String,
+    /// The time as WeChat shows it, such as `2023-11-15 10:00`.
+    pub time: String,
+    pub content: String,
+    /// The title of links, files and nested records.
+    pub title: Option<String>,
+    /// The item's `<dataitem>` XML, which agents download its media with.
+    pub xml: String,
+}
+
+impl RecordItem {
+    /// The item in a line of text, with media as a placeholder.
+    pub fn summary(&self) -> String {
+        let placeholder = |label: &str, detail: Option<&str>| match detail.filter(|d| !d.is_empty()) {
+            Some(detail) => format!("[{}] {}", label, detail),
+            None => format!("[{}]", label),
+        };
+        match self.kind {
+            RecordItemKind::Text => self.content.clone(),
+            RecordItemKind::Image => placeholder("Image", None),
+            RecordItemKind::Voice => placeholder("Voice", None),
+            RecordItemKind::Video => placeholder("Video", None),
+            RecordItemKind::Link => placeholder("Link", self.title.as_deref()),
+            RecordItemKind::File => placeholder("File", self.title.as_deref()),
+            RecordItemKind::Location => placeholder("Location", Some(&self.content)),
+            RecordItemKind::Record => placeholder("Chat record", self.title.as_deref()),
+            RecordItemKind::Other if !self.content.is_empty() => self.content.clone(),
+            RecordItemKind::Other => placeholder("Message", None),
+        }
+    }
+
+    /// Whether the item's media is bridged after the record.
+    pub fn has_media(&self) -> bool {
+        matches!(self.kind, RecordItemKind::Image | RecordItemKind::Video | RecordItemKind::File)
+    }
+}
+
+/// A bundle of forwarded messages ("chat history"), parsed from the XML of a WeChat app message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatRecord {
+    pub title: String,
+    pub items: Vec<RecordItem>,
+}
+
+impl ChatRecord {
+    pub fn parse(xml: &str) -> Option<Self> {
+        if tag(xml, "type")? != CHAT_RECORD_APP_TYPE {
+            return None;
+        }
+        // The record is XML of its own, escaped or in CDATA.
+        let record = tag(xml, "recorditem")?;
+        let items = DATA_ITEM
+            .captures_iter(&record)
+            .map(|item| {
+                let datatype = DATA_TYPE.captures(&item[1]).map_or("", |c| c.get(1).map_or("", |m| m.as_str()));
+                let body = &item[2];
+                RecordItem {
+                    kind: RecordItemKind::from_datatype(datatype),
+                    sender: tag(body, "sourcename").unwrap_or_default(),
+                    time: tag(body, "sourcetime").unwrap_or_default(),
+                    content: tag(body, "datadesc").unwrap_or_default(),
+                    title: tag(body, "datatitle").filter(|t| !t.is_empty()),
+                    xml: item[0].to_string(),
+                }
+            })
+            .collect();
+        Some(Self {
+            title: tag(xml, "title").filter(|t| !t.is_empty()).unwrap_or_else(|| "Chat history".to_string()),
+            items,
+        })
+    }
+
+    pub fn body(&self) -> String {
+        let mut body = self.title.clone();
+        for item in &self.items {
+            body.push_str(&format!("\n> {} ({}): {}", item.sender, item.time, item.summary()));
+        }
+        body
+    }
+
+    pub fn html(&self) -> String {
+        let mut html = format!("<p><strong>{}</strong></p><blockquote>", escape_html(&self.title));
+        for item in &self.items {
+            html.push_str(&format!(
+                "<p><strong>{}</strong> <small>{}</small><br/>{}</p>",
+                escape_html(&item.sender),
+                escape_html(&item.time),
+                escape_html(&item.summary()).replace('\n', "<br/>")
+            ));
+        }
+        html.push_str("</blockquote>");
+        html
+    }
+}
@@ -1,159 +1,380 @@
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
 
-static WECHAT_TO_UNICODE: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
-    let mut map = HashMap::new();
-    map.insert("[微笑]", "🙂");
-    map.insert("[撇嘴]", "🙎");
-    map.insert("[色]", "😍");
-    map.insert("[发呆]", "😐");
-    map.insert("[得意]", "😏");
-    map.insert("[流泪]", "😢");
-    map.insert("[害羞]", "😊");
-    map.insert("[闭嘴]", "😶");
-    map.insert("[睡]", "😴");
-    map.insert("[大哭]", "😭");
-    map.insert("[尴尬]", "😅");
-    map.insert("[发怒]", "😠");
-    map.insert("[调皮]", "😜");
-    map.insert("[呲牙]", "😁");
-    map.insert("[惊讶]", "😲");
-    map.insert("[难过]", "😔");
-    map.insert("[酷]", "😎");
-    map.insert("[冷汗]", "😰");
-    map.insert("[抓狂]", "😫");
-    map.insert("[吐]", "🤮");
-    map.insert("[偷笑]", "🤭");
-    map.insert("[愉快]", "😊");
-    map.insert("[白眼]", "🙄");
-    map.insert("[傲慢]", "😤");
-    map.insert("[饥饿]", "🤤");
-    map.insert("[困]", "😪");
-    map.insert("[惊恐]", "😱");
-    map.insert("[流汗]", "😓");
-    map.insert("[憨笑]", "😃");
-    map.insert("[悠闲]", "😌");
-    map.insert("[奋斗]", "💪");
-    map.insert("[咒骂]", "🤬");
-    map.insert("[疑问]", "❓");
-    map.insert("[嘘]", "🤫");
-    map.insert("[晕]", "😵");
-    map.insert("[疯了]", "🤪");
-    map.insert("[衰]", "☹️");
-    map.insert("[骷髅]", "💀");
-    map.insert("[敲打]", "敲打");
-    map.insert("[再见]", "👋");
-    map.insert("[擦汗]", "😅");
-    map.insert("[抠鼻]", "🤔");
-    map.insert("[鼓掌]", "👏");
-    map.insert("[糗大了]", "😳");
-    map.insert("[坏笑]", "🤭");
-    map.insert("[左哼哼]", "😤");
-    map.insert("[右哼哼]", "😤");
-    map.insert("[哈欠]", "🥱");
-    map.insert("[鄙视]", "😒");
-    map.insert("[委屈]", "🥺");
-    map.insert("[快哭了]", "😢");
-    map.insert("[阴险]", "😏");
-    map.insert("[亲亲]", "😘");
-    map.insert("[吓]", "😨");
-    map.insert("[可怜]", "🥺");
-    map.insert("[菜刀]", "🔪");
-    map.insert("[西瓜]", "🍉");
-    map.insert("[啤酒]", "🍺");
-    map.insert("[篮球]", "🏀");
-    map.insert("[乒乓]", "🏓");
-    map.insert("[咖啡]", "☕");
-    map.insert("[饭]", "🍚");
-    map.insert("[猪头]", "🐷");
-    map.insert("[玫瑰]", "🌹");
-    map.insert("[凋谢]", "🥀");
-    map.insert("[嘴唇]", "👄");
-    map.insert("[爱心]", "❤️");
-    map.insert("[心碎]", "💔");
-    map.insert("[蛋糕]", "🎂");
-    map.insert("[闪电]", "⚡");
-    map.insert("[炸弹]", "💣");
-    map.insert("[刀]", "🔪");
-    map.insert("[足球]", "⚽");
-    map.insert("[瓢虫]", "🐞");
-    map.insert("[便便]", "💩");
-    map.insert("[月亮]", "🌙");
-    map.insert("[太阳]", "☀️");
-    map.insert("[礼物]", "🎁");
-    map.insert("[拥抱]", "🤗");
-    map.insert("[强]", "👍");
-    map.insert("[弱]", "👎");
-    map.insert("[握手]", "🤝");
-    map.insert("[胜利]", "✌️");
-    map.insert("[抱拳]", "🙏");
-    map.insert("[勾引]", "👉");
-    map.insert("[拳头]", "👊");
-    map.insert("[差劲]", "👎");
-    map.insert("[爱你]", "🤟");
-    map.insert("[NO]", "🙅");
-    map.insert("[OK]", "👌");
-    map.insert("[爱情]", "💑");
-    map.insert("[飞吻]", "😘");
-    map.insert("[跳跳]", "蹦跳");
-    map.insert("[发抖]", "🫨");
-    map.insert("[怄火]", "火");
-    map.insert("[转圈]", "旋转");
-    map.insert("[磕头]", "🙇");
-    map.insert("[回头]", "👀");
-    map.insert("[跳绳]", "🏃");
-    map.insert("[激动]", "🤩");
-    map.insert("[街舞]", "街舞");
-    map.insert("[献吻]", "💋");
-    map.insert("[左太极]", "太极");
-    map.insert("[右太极]", "太极");
-    map.insert("[双喜]", "囍");
-    map.insert("[鞭炮]", "🧨");
-    map.insert("[灯笼]", "🏮");
-    map.insert("[发财]", "🧧");
-    map.insert("[K歌]", "🎤");
-    map.insert("[购物]", "🛒");
-    map.insert("[邮件]", "📧");
-    map.insert("[帅气]", "帅");
-    map.insert("[喝彩]", "🎉");
-    map.insert("[祈祷]", "🙏");
-    map.insert("[爆筋]", "💪");
-    map.insert("[棒棒糖]", "🍭");
-    map.insert("[喝奶]", "🍼");
-    map.insert("[面条]", "🍜");
-    map.insert("[香蕉]", "🍌");
-    map.insert("[飞机]", "✈️");
-    map.insert("[汽车]", "🚗");
-    map.insert("[火车]", "🚂");
-    map.insert("[公交]", "🚌");
-    map.insert("[轮船]", "🚢");
-    map.insert("[钞票]", "💵");
-    map.insert("[熊猫]", "🐼");
-    map.insert("[兔子]", "🐰");
-    map.insert("[_Onerous]", "😫");
-    map
-});
+use regex::Regex;
 
-static UNICODE_TO_WECHAT: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
-    let mut map = HashMap::new();
-    for (k, v) in WECHAT_TO_UNICODE.iter() {
-        map.insert(*v, *k);
+/// WeChat emoji codes with their closest Unicode emoji, Chinese codes first. Codes of the
+/// English client map to the same emoji.
+static WECHAT_EMOJIS: &[(&str, &str)] = &[
+    ("[微笑]", "🙂"),
+    ("[撇嘴]", "🙎"),
+    ("[色]", "😍"),
+    ("[发呆]", "😐"),
+    ("[得意]", "😏"),
+    ("[流泪]", "😢"),
+    ("[害羞]", "😊"),
+    ("[闭嘴]", "😶"),
+    ("[睡]", "😴"),
+    ("[大哭]", "😭"),
+    ("[尴尬]", "😅"),
+    ("[发怒]", "😠"),
+    ("[调皮]", "😜"),
+    ("[呲牙]", "😁"),
+    ("[惊讶]", "😲"),
+    ("[难过]", "😔"),
+    ("[酷]", "😎"),
+    ("[冷汗]", "😰"),
+    ("[抓狂]", "😫"),
+    ("[吐]", "🤮"),
+    ("[偷笑]", "🤭"),
+    ("[愉快]", "😊"),
+    ("[白眼]", "🙄"),
+    ("[傲慢]", "😤"),
+    ("[饥饿]", "🤤"),
+    ("[困]", "😪"),
+    ("[惊恐]", "😱"),
+    ("[流汗]", "😓"),
+    ("[憨笑]", "😃"),
+    ("[悠闲]", "😌"),
+    ("[奋斗]", "💪"),
+    ("[咒骂]", "🤬"),
+    ("[疑问]", "❓"),
+    ("[嘘]", "🤫"),
+    ("[晕]", "😵"),
+    ("[疯了]", "🤪"),
+    ("[衰]", "☹️"),
+    ("[骷髅]", "💀"),
+    ("[敲打]", "敲打"),
+    ("[再见]", "👋"),
+    ("[擦汗]", "😅"),
+    ("[抠鼻]", "🤔"),
+    ("[鼓掌]", "👏"),
+    ("[糗大了]", "😳"),
+    ("[坏笑]", "🤭"),
+    ("[左哼哼]", "😤"),
+    ("[右哼哼]", "😤"),
+    ("[哈欠]", "🥱"),
+    ("[鄙视]", "😒"),
+    ("[委屈]", "🥺"),
+    ("[快哭了]", "😢"),
+    ("[阴险]", "😏"),
+    ("[亲亲]", "😘"),
+    ("[吓]", "😨"),
+    ("[可怜]", "🥺"),
+    ("[菜刀]", "🔪"),
+    ("[西瓜]", "🍉"),
+    ("[啤酒]", "🍺"),
+    ("[篮球]", "🏀"),
+    ("[乒乓]", "🏓"),
+    ("[咖啡]", "☕"),
+    ("[饭]", "🍚"),
+    ("[猪头]", "🐷"),
+    ("[玫瑰]", "🌹"),
+    ("[凋谢]", "🥀"),
+    ("[嘴唇]", "👄"),
+    ("[爱心]", "❤️"),
+    ("[心碎]", "💔"),
+    ("[蛋糕]", "🎂"),
+    ("[闪电]", "⚡"),
+    ("[炸弹]", "💣"),
+    ("[刀]", "🔪"),
+    ("[足球]", "⚽"),
+    ("[瓢虫]", "🐞"),
+    ("[便便]", "💩"),
+    ("[月亮]", "🌙"),
+    ("[太阳]", "☀️"),
+    ("[礼物]", "🎁"),
+    ("[拥抱]", "🤗"),
+    ("[强]", "👍"),
+    ("[弱]", "👎"),
+    ("[握手]", "🤝"),
+    ("[胜利]", "✌️"),
+    ("[抱拳]", "🙏"),
+    ("[勾引]", "👉"),
+    ("[拳头]", "👊"),
+    ("[差劲]", "👎"),
+    ("[爱你]", "🤟"),
+    ("[NO]", "🙅"),
+    ("[OK]", "👌"),
+    ("[爱情]", "💑"),
+    ("[飞吻]", "😘"),
+    ("[跳跳]", "蹦跳"),
+    ("[发抖]", "🫨"),
+    ("[怄火]", "火"),
+    ("[转圈]", "旋转"),
+    ("[磕头]", "🙇"),
+    ("[回头]", "👀"),
+    ("[跳绳]", "🏃"),
+    ("[激动]", "🤩"),
+    ("[街舞]", "街舞"),
+    ("[献吻]", "💋"),
+    ("[左太极]", "太极"),
+    ("[右太极]", "太极"),
+    ("[双喜]", "囍"),
+    ("[鞭炮]", "🧨"),
+    ("[灯笼]", "🏮"),
+    ("[发财]", "🧧"),
+    ("[K歌]", "🎤"),
+    ("[购物]", "🛒"),
+    ("[邮件]", "📧"),
+    ("[帅气]", "帅"),
+    ("[喝彩]", "🎉"),
+    ("[祈祷]", "🙏"),
+    ("[爆筋]", "💪"),
+    ("[棒棒糖]", "🍭"),
+    ("[喝奶]", "🍼"),
+    ("[面条]", "🍜"),
+    ("[香蕉]", "🍌"),
+    ("[飞机]", "✈️"),
+    ("[汽车]", "🚗"),
+    ("[火车]", "🚂"),
+    ("[公交]", "🚌"),
+    ("[轮船]", "🚢"),
+    ("[钞票]", "💵"),
+    ("[熊猫]", "🐼"),
+    ("[兔子]", "🐰"),
+    ("[_Onerous]", "😫"),
+    ("[嘿哈]", "😆"),
+    ("[捂脸]", "🤦"),
+    ("[奸笑]", "😏"),
+    ("[机智]", "🤓"),
+    ("[皱眉]", "😟"),
+    ("[耶]", "✌️"),
+    ("[吃瓜]", "🍉"),
+    ("[加油]", "💪"),
+    ("[汗]", "😓"),
+    ("[天啊]", "😱"),
+    ("[Emm]", "🤔"),
+    ("[社会社会]", "🤙"),
+    ("[旺柴]", "🐶"),
+    ("[好的]", "👌"),
+    ("[打脸]", "🤕"),
+    ("[哇]", "🤩"),
+    ("[翻白眼]", "🙄"),
+    ("[666]", "👍"),
+    ("[让我看看]", "👀"),
+    ("[叹气]", "😮‍💨"),
+    ("[苦涩]", "😖"),
+    ("[裂开]", "💔"),
+    ("[合十]", "🙏"),
+    ("[烟花]", "🎆"),
+    ("[爆竹]", "🧨"),
+    ("[福]", "🧧"),
+    ("[红包]", "🧧"),
+    ("[鸡]", "🐔"),
+    ("[庆祝]", "🎉"),
+    ("[破涕为笑]", "😂"),
+    ("[脸红]", "😳"),
+    ("[笑脸]", "😄"),
+    ("[恐惧]", "😱"),
+    ("[失望]", "😞"),
+    ("[无语]", "😑"),
+    ("[生病]", "😷"),
+    ("[吐舌]", "😛"),
+    ("[囧]", "😳"),
+    ("[Smile]", "🙂"),
+    ("[Grimace]", "🙎"),
+    ("[Drool]", "😍"),
+    ("[Scowl]", "😐"),
+    ("[CoolGuy]", "😏"),
+    ("[Sob]", "😢"),
+    ("[Shy]", "😊"),
+    ("[Silent]", "😶"),
+    ("[Sleep]", "😴"),
+    ("[Cry]", "😭"),
+    ("[Awkward]", "😅"),
+    ("[Angry]", "😠"),
+    ("[Tongue]", "😜"),
+    ("[Grin]", "😁"),
+    ("[Surprise]", "😲"),
+    ("[Frown]", "😔"),
+    ("[Ruthless]", "😎"),
+    ("[Blush]", "😰"),
+    ("[Scream]", "😫"),
+    ("[Puke]", "🤮"),
+    ("[Chuckle]", "🤭"),
+    ("[Joyful]", "😊"),
+    ("[Slight]", "🙄"),
+    ("[Smug]", "😤"),
+    ("[Hungry]", "🤤"),
+    ("[Drowsy]", "😪"),
+    ("[Panic]", "😱"),
+    ("[Sweat]", "😓"),
+    ("[Laugh]", "😃"),
+    ("[Commando]", "💪"),
+    ("[Scold]", "🤬"),
+    ("[Shocked]", "❓"),
+    ("[Shhh]", "🤫"),
+    ("[Dizzy]", "😵"),
+    ("[Tormented]", "🤪"),
+    ("[Toasted]", "☹️"),
+    ("[Skull]", "💀"),
+    ("[Wave]", "👋"),
+    ("[Speechless]", "😅"),
+    ("[NosePick]", "🤔"),
+    ("[Clap]", "👏"),
+    ("[Shame]", "😳"),
+    ("[Trick]", "🤭"),
+    ("[Yawn]", "🥱"),
+    ("[Pooh-pooh]", "😒"),
+    ("[Shrunken]", "🥺"),
+    ("[TearingUp]", "😢"),
+    ("[Sly]", "😏"),
+    ("[Kiss]", "😘"),
+    ("[Wrath]", "😨"),
+    ("[Whimper]", "🥺"),
+    ("[Cleaver]", "🔪"),
+    ("[Watermelon]", "🍉"),
+    ("[Beer]", "🍺"),
+    ("[Basketball]", "🏀"),
+    ("[PingPong]", "🏓"),
+    ("[Coffee]", "☕"),
+    ("[Rice]", "🍚"),
+    ("[Pig]", "🐷"),
+    ("[Rose]", "🌹"),
+    ("[Wilt]", "🥀"),
+    ("[Lips]", "👄"),
+    ("[Heart]", "❤️"),
+    ("[BrokenHeart]", "💔"),
+    ("[Cake]", "🎂"),
+    ("[Lightning]", "⚡"),
+    ("[Bomb]", "💣"),
+    ("[Dagger]", "🔪"),
+    ("[Soccer]", "⚽"),
+    ("[Ladybug]", "🐞"),
+    ("[Poop]", "💩"),
+    ("[Moon]", "🌙"),
+    ("[Sun]", "☀️"),
+    ("[Gift]", "🎁"),
+    ("[Hug]", "🤗"),
+    ("[ThumbsUp]", "👍"),
+    ("[ThumbsDown]", "👎"),
+    ("[Shake]", "🤝"),
+    ("[Peace]", "✌️"),
+    ("[Fight]", "🙏"),
+    ("[Beckon]", "👉"),
+    ("[Fist]", "👊"),
+    ("[Pinky]", "👎"),
+    ("[RockOn]", "🤟"),
+    ("[Nuh-uh]", "🙅"),
+    ("[InLove]", "💑"),
+    ("[Blowkiss]", "😘"),
+    ("[Tremble]", "🫨"),
+    ("[Kotow]", "🙇"),
+    ("[Lookback]", "👀"),
+    ("[JumpRope]", "🏃"),
+    ("[Surrender]", "🤩"),
+    ("[Firecracker]", "🧨"),
+    ("[Lantern]", "🏮"),
+    ("[Packet]", "🧧"),
+    ("[Party]", "🎉"),
+    ("[Hey]", "😆"),
+    ("[Facepalm]", "🤦"),
+    ("[Smirk]", "😏"),
+    ("[Smart]", "🤓"),
+    ("[Concerned]", "😟"),
+    ("[Yeah!]", "✌️"),
+    ("[Onlooker]", "🍉"),
+    ("[GoForIt]", "💪"),
+    ("[Sweats]", "😓"),
+    ("[OMG]", "😱"),
+    ("[Respect]", "🤙"),
+    ("[Doge]", "🐶"),
+    ("[NoProb]", "👌"),
+    ("[MyBad]", "🤕"),
+    ("[Wow]", "🤩"),
+    ("[Boring]", "🙄"),
+    ("[Awesome]", "👍"),
+    ("[LetMeSee]", "👀"),
+    ("[Sigh]", "😮‍💨"),
+    ("[Hurt]", "😖"),
+    ("[Broken]", "💔"),
+    ("[Worship]", "🙏"),
+    ("[Fireworks]", "🎆"),
+    ("[Blessing]", "🧧"),
+    ("[Rich]", "🧧"),
+    ("[Chick]", "🐔"),
+];
+
+static BUILTIN: Lazy<EmojiTable> = Lazy::new(|| EmojiTable::new(WECHAT_EMOJIS.iter().map(|(k, v)| (k.to_string(), v.to_string()))));
+
+/// Mappings from `bridge.emoji_map_path`, which take precedence over the built-in table.
+static CUSTOM: Lazy<RwLock<Arc<EmojiTable>>> = Lazy::new(|| RwLock::new(Arc::new(EmojiTable::default())));
+
+static CODE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\[[^\[\]\s]{1,16}\]").unwrap());
+
+#[derive(Default)]
+struct EmojiTable {
+    to_unicode: HashMap<String, String>,
+    /// Longest emoji first, so sequences win over the emoji they start with.
+    to_wechat: Vec<(String, String)>,
+}
+
+impl EmojiTable {
+    fn new(mappings: impl IntoIterator<Item = (String, String)>) -> Self {
+        let mut to_unicode = HashMap::new();
+        let mut to_wechat: Vec<(String, String)> = Vec::new();
+        for (code, emoji) in mappings {
+            // Some codes have no emoji and map to words, which mustn't be turned back into codes.
+            if !emoji.chars().any(char::is_alphanumeric) && !to_wechat.iter().any(|(e, _)| *e == emoji) {
+                to_wechat.push((emoji.clone(), code.clone()));
+            }
+            to_unicode.insert(code, emoji);
+        }
+        to_wechat.sort_by_key(|(emoji, _)| std::cmp::Reverse(emoji.len()));
+        Self { to_unicode, to_wechat }
     }
-    map
-});
+}
+
+/// Replaces the custom emoji mappings, as `[code]: emoji` pairs.
+pub fn set_custom_map(map: HashMap<String, String>) {
+    let mut mappings: Vec<_> = map.into_iter().collect();
+    mappings.sort();
+    *CUSTOM.write().unwrap() = Arc::new(EmojiTable::new(mappings));
+}
+
+/// Loads custom emoji mappings from a YAML or JSON file of `"[code]": emoji` pairs. Returns
+/// how many were loaded.
+pub fn load_custom_map(path: &Path) -> anyhow::Result<usize> {
+    let data = std::fs::read_to_string(path)?;
+    let map: HashMap<String, String> = serde_yaml::from_str(&data)?;
+    let count = map.len();
+    set_custom_map(map);
+    Ok(count)
+}
+
+static AVATAR_EMOJIS: &[&str] = &[
+    "🐶", "🐱", "🐭", "🐹", "🐰", "🦊", "🐻", "🐼", "🐨", "🐯", "🦁", "🐮",
+    "🐷", "🐸", "🐵", "🐔", "🐧", "🐦", "🐤", "🦆", "🦉", "🐺", "🐴", "🦄",
+];
+
+pub fn avatar_emoji(seed: &str) -> &'static str {
+    let hash = seed
+        .bytes()
+        .fold(0u32, |acc, b| acc.wrapping_mul(31).wrapping_add(b as u32));
+    AVATAR_EMOJIS[hash as usize % AVATAR_EMOJIS.len()]
+}
 
 pub fn wechat_to_unicode(text: &str) -> String {
-    let mut result = text.to_string();
-    for (wechat, unicode) in WECHAT_TO_UNICODE.iter() {
-        result = result.replace(wechat, unicode);
-    }
-    result
+    let custom = CUSTOM.read().unwrap().clone();
+    CODE_REGEX.replace_all(text, |c: &regex::Captures| {
+        custom.to_unicode.get(&c[0])
+            .or_else(|| BUILTIN.to_unicode.get(&c[0]))
+            .cloned()
+            .unwrap_or_else(|| c[0].to_string())
+    })
+    .into_owned()
 }
 
 pub fn unicode_to_wechat(text: &str) -> String {
+    let custom = CUSTOM.read().unwrap().clone();
     let mut result = text.to_string();
-    for (unicode, wechat) in UNICODE_TO_WECHAT.iter() {
-        result = result.replace(unicode, wechat);
+    for (unicode, wechat) in custom.to_wechat.iter().chain(&BUILTIN.to_wechat) {
+        if result.contains(unicode.as_str()) {
+            result = result.replace(unicode.as_str(), wechat);
+        }
     }
     result
 }
@@ -1,3 +1,289 @@
-pub fn matrix_to_wechat(text: &str) -> String {
-    super::html_to_plain(text)
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use super::wechat_to_matrix::MENTION_SEPARATOR;
+
+static PILL_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?s)<a\s+href="https://matrix\.to/#/((?:@|%40)[^"?]+)(?:\?[^"]*)?"\s*>(.*?)</a>"#).unwrap()
+});
+static TAG_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?s)<(/?)([a-zA-Z][a-zA-Z0-9-]*)((?:[^>"']|"[^"]*"|'[^']*')*?)(/?)>"#).unwrap()
+});
+static ATTR_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"([a-zA-Z_:][a-zA-Z0-9_:.-]*)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#).unwrap()
+});
+static ENTITY_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").unwrap());
+static WHITESPACE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"[ \t\r\n]+").unwrap());
+static BLANK_LINES_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n{3,}").unwrap());
+
+const VOID_ELEMENTS: &[&str] = &["br", "img", "hr", "input", "meta", "link"];
+
+/// A user pill in a Matrix formatted body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pill {
+    pub mxid: String,
+    pub name: String,
+}
+
+/// Finds the user pills in a formatted body, in order.
+pub fn find_pills(html: &str) -> Vec<Pill> {
+    PILL_REGEX.captures_iter(html)
+        .map(|c| Pill {
+            mxid: urlencoding::decode(&c[1]).map(|m| m.into_owned()).unwrap_or_else(|_| c[1].to_string()),
+            name: pill_name(&c[2]),
+        })
+        .collect()
+}
+
+fn pill_name(html: &str) -> String {
+    let name = decode_entities(&super::strip_html(html));
+    name.trim().trim_start_matches('@').to_string()
+}
+
+/// Splits the reply fallback off the body of a Matrix reply, returning the quoted text of the
+/// original message without its sender, and the reply itself.
+pub fn strip_reply_fallback(body: &str) -> (Option<String>, &str) {
+    if !body.starts_with("> ") {
+        return (None, body);
+    }
+    let (fallback, rest) = match body.split_once("\n\n") {
+        Some((fallback, rest)) if fallback.lines().all(|l| l.starts_with('>')) => (fallback, rest),
+        _ => return (None, body),
+    };
+
+    let quoted: Vec<&str> = fallback.lines()
+        .map(|l| l.trim_start_matches('>').trim_start())
+        .collect();
+    let mut quoted = quoted.join("\n");
+    // The first line starts with the sender of the original message, as `<@user:server>`,
+    // or `* <@user:server>` for emotes.
+    if (quoted.starts_with("<@") || quoted.starts_with("* <@"))
+        && let Some(end) = quoted.find("> ")
+    {
+        quoted.replace_range(..end + 2, "");
+    }
+    (Some(quoted), rest)
+}
+
+/// Shortens quoted text to a snippet that fits in a WeChat quote.
+pub fn quote_snippet(text: &str, max_chars: usize) -> String {
+    let text = text.trim();
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut snippet: String = text.chars().take(max_chars).collect();
+    snippet.push('\u{2026}');
+    snippet
+}
+
+/// Converts a formatted body to WeChat text. WeChat can't show formatting, so it's downgraded
+/// to Markdown-style plaintext: `**bold**`, `- list items`, `[text](url)` and `> quotes`.
+/// Pills become WeChat `@name` mentions and reply fallbacks are dropped.
+pub fn matrix_to_wechat(html: &str) -> String {
+    let nodes = parse(html);
+    let text = render_nodes(&nodes, false);
+    let text = text.lines().map(str::trim_end).collect::<Vec<_>>().join("\n");
+    BLANK_LINES_REGEX.replace_all(&text, "\n\n").trim().to_string()
+}
+
+/// An element that hasn't been closed yet, with the children parsed so far.
+type OpenElement = (String, Vec<(String, String)>, Vec<Node>);
+
+enum Node {
+    Element {
+        tag: String,
+        attrs: Vec<(String, String)>,
+        children: Vec<Node>,
+    },
+    Text(String),
+}
+
+impl Node {
+    fn attr(&self, name: &str) -> Option<&str> {
+        match self {
+            Node::Element { attrs, .. } => attrs.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str()),
+            Node::Text(_) => None,
+        }
+    }
+}
+
+/// Builds a lenient tree: unclosed elements are closed by their parent's end tag and stray end
+/// tags are ignored.
+fn parse(html: &str) -> Vec<Node> {
+    let mut stack: Vec<OpenElement> = vec![(String::new(), Vec::new(), Vec::new())];
+    let mut last = 0;
+
+    for captures in TAG_REGEX.captures_iter(html) {
+        let whole = captures.get(0).unwrap();
+        if whole.start() > last {
+            stack.last_mut().unwrap().2.push(Node::Text(html[last..whole.start()].to_string()));
+        }
+        last = whole.end();
+
+        let tag = captures[2].to_ascii_lowercase();
+        if &captures[1] == "/" {
+            let Some(pos) = stack.iter().rposition(|(t, _, _)| *t == tag) else {
+                continue;
+            };
+            while stack.len() > pos {
+                let (tag, attrs, children) = stack.pop().unwrap();
+                stack.last_mut().unwrap().2.push(Node::Element { tag, attrs, children });
+            }
+            continue;
+        }
+
+        let attrs = ATTR_REGEX.captures_iter(&captures[3])
+            .map(|a| {
+                let value = a.get(2).or(a.get(3)).or(a.get(4)).map(|v| v.as_str()).unwrap_or_default();
+                (a[1].to_ascii_lowercase(), decode_entities(value))
+            })
+            .collect();
+        if &captures[4] == "/" || VOID_ELEMENTS.contains(&tag.as_str()) {
+            stack.last_mut().unwrap().2.push(Node::Element { tag, attrs, children: Vec::new() });
+        } else {
+            stack.push((tag, attrs, Vec::new()));
+        }
+    }
+    if last < html.len() {
+        stack.last_mut().unwrap().2.push(Node::Text(html[last..].to_string()));
+    }
+
+    while stack.len() > 1 {
+        let (tag, attrs, children) = stack.pop().unwrap();
+        stack.last_mut().unwrap().2.push(Node::Element { tag, attrs, children });
+    }
+    stack.pop().map(|(_, _, children)| children).unwrap_or_default()
+}
+
+fn render_nodes(nodes: &[Node], in_pre: bool) -> String {
+    nodes.iter().map(|node| render_node(node, in_pre)).collect()
+}
+
+fn render_node(node: &Node, in_pre: bool) -> String {
+    let (tag, children) = match node {
+        Node::Text(text) if in_pre => return decode_entities(text),
+        // Whitespace between block elements is only there to format the HTML source.
+        Node::Text(text) if text.trim().is_empty() && text.contains('\n') => return String::new(),
+        Node::Text(text) => return WHITESPACE_REGEX.replace_all(&decode_entities(text), " ").into_owned(),
+        Node::Element { tag, children, .. } => (tag.as_str(), children),
+    };
+    let inner = || render_nodes(children, in_pre);
+
+    match tag {
+        "mx-reply" | "script" | "style" | "head" => String::new(),
+        "br" => "\n".to_string(),
+        "hr" => "\n---\n".to_string(),
+        "img" => node.attr("alt").or(node.attr("title")).unwrap_or_default().to_string(),
+        "strong" | "b" => wrap(&inner(), "**"),
+        "em" | "i" => wrap(&inner(), "_"),
+        "del" | "s" | "strike" => wrap(&inner(), "~~"),
+        "code" if in_pre => inner(),
+        "code" => wrap(&inner(), "`"),
+        "pre" => format!("\n```\n{}\n```\n", render_nodes(children, true).trim_end_matches('\n')),
+        "a" => render_link(node, &inner()),
+        "p" | "div" | "table" | "tr" | "details" | "summary" => format!("\n{}\n", inner().trim_matches(' ')),
+        "td" | "th" => format!("{} ", inner()),
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            let level = tag[1..].parse().unwrap_or(1);
+            format!("\n{} {}\n", "#".repeat(level), inner().trim())
+        }
+        "blockquote" => {
+            let quoted = inner();
+            let lines: Vec<String> = quoted.trim().lines().map(|l| format!("> {}", l).trim_end().to_string()).collect();
+            format!("\n{}\n", lines.join("\n"))
+        }
+        "ul" | "ol" => render_list(node, children, in_pre),
+        _ => inner(),
+    }
+}
+
+fn render_list(node: &Node, children: &[Node], in_pre: bool) -> String {
+    let ordered = matches!(node, Node::Element { tag, .. } if tag == "ol");
+    let mut number: usize = node.attr("start").and_then(|s| s.parse().ok()).unwrap_or(1);
+    let mut out = String::from("\n");
+
+    for child in children {
+        let Node::Element { tag, children: item, .. } = child else {
+            continue;
+        };
+        if tag != "li" {
+            continue;
+        }
+        let marker = if ordered {
+            number += 1;
+            format!("{}. ", number - 1)
+        } else {
+            "- ".to_string()
+        };
+        let text = render_nodes(item, in_pre);
+        let text = BLANK_LINES_REGEX.replace_all(text.trim(), "\n");
+        let indent = " ".repeat(marker.chars().count());
+        for (i, line) in text.lines().filter(|l| !l.trim().is_empty()).enumerate() {
+            if i == 0 {
+                out.push_str(&marker);
+            } else {
+                out.push_str(&indent);
+            }
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+fn render_link(node: &Node, text: &str) -> String {
+    let href = node.attr("href").unwrap_or_default();
+    let text = text.trim();
+    if let Some(target) = href.strip_prefix("https://matrix.to/#/")
+        && (target.starts_with('@') || target.starts_with("%40"))
+    {
+        return format!("@{}{}", text.trim_start_matches('@'), MENTION_SEPARATOR);
+    }
+    if href.is_empty() || text == href {
+        return text.to_string();
+    }
+    if text.is_empty() {
+        return href.to_string();
+    }
+    if let Some(address) = href.strip_prefix("mailto:")
+        && address == text
+    {
+        return text.to_string();
+    }
+    format!("[{}]({})", text, href)
+}
+
+fn wrap(text: &str, marker: &str) -> String {
+    let trimmed = text.trim();
+    if trimmed.is_empty() {
+        return text.to_string();
+    }
+    // Keep surrounding spaces outside the markers so they still read as formatting.
+    let leading = &text[..text.len() - text.trim_start().len()];
+    let trailing = &text[text.trim_end().len()..];
+    format!("{}{}{}{}{}", leading, marker, trimmed, marker, trailing)
+}
+
+/// Decodes named and numeric HTML character references.
+pub fn decode_entities(text: &str) -> String {
+    ENTITY_REGEX.replace_all(text, |c: &regex::Captures| {
+        let entity = &c[1];
+        let decoded = if let Some(hex) = entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+            u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)
+        } else if let Some(dec) = entity.strip_prefix('#') {
+            dec.parse().ok().and_then(char::from_u32)
+        } else {
+            match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                _ => None,
+            }
+        };
+        decoded.map(String::from).unwrap_or_else(|| c[0].to_string())
+    })
+    .into_owned()
 }
@@ -1,5 +1,9 @@
+pub mod app_card;
+pub mod chat_record;
 pub mod emoji;
 pub mod matrix_to_wechat;
+pub mod payment;
+pub mod system;
 pub mod wechat_to_matrix;
 
 use once_cell::sync::Lazy;
@@ -11,15 +15,16 @@ pub fn strip_html(html: &str) -> String {
     HTML_TAG_REGEX.replace_all(html, "").to_string()
 }
 
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Converts HTML to readable plaintext, see [`matrix_to_wechat::matrix_to_wechat`].
 pub fn html_to_plain(html: &str) -> String {
-    let text = html
-        .replace("<br>", "\n")
-        .replace("<br/>", "\n")
-        .replace("<br />", "\n")
-        .replace("</p>", "\n")
-        .replace("</div>", "\n");
-
-    HTML_TAG_REGEX.replace_all(&text, "").to_string()
+    matrix_to_wechat::matrix_to_wechat(html)
 }
 
 pub fn wechat_to_matrix(text: &str) -> String {
@@ -32,3 +37,7 @@ pub fn matrix_to_wechat(text: &str) -> String {
     let text = emoji::unicode_to_wechat(&text);
     text
 }
+
+pub fn add_message_marker(text: &str, prefix: &str, suffix: &str) -> String {
+    format!("{}{}{}", prefix, text, suffix)
+}
@@ -0,0 +1,103 @@
+use super::escape_html;
+use super::system::tag;
+
+const RED_PACKET_APP_TYPE: &str = "2001";
+const TRANSFER_APP_TYPE: &str = "2000";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentKind {
+    RedPacket,
+    Transfer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentStatus {
+    Sent,
+    Claimed,
+    Returned,
+    Expired,
+}
+
+/// A red packet (hongbao) or money transfer, parsed from the XML of a WeChat app message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaymentMessage {
+    pub kind: PaymentKind,
+    pub status: PaymentStatus,
+    /// The amount as WeChat shows it, such as `￥0.01`. Red packets don't reveal it.
+    pub amount: Option<String>,
+    pub note: Option<String>,
+}
+
+impl PaymentMessage {
+    pub fn parse(xml: &str) -> Option<Self> {
+        let kind = match tag(xml, "type")?.as_str() {
+            RED_PACKET_APP_TYPE => PaymentKind::RedPacket,
+            TRANSFER_APP_TYPE => PaymentKind::Transfer,
+            _ => return None,
+        };
+        let status = match tag(xml, "paysubtype").as_deref() {
+            Some("3") => PaymentStatus::Claimed,
+            Some("4") => PaymentStatus::Returned,
+            Some("5") => PaymentStatus::Expired,
+            _ => PaymentStatus::Sent,
+        };
+        let note = match kind {
+            PaymentKind::RedPacket => tag(xml, "sendertitle").or_else(|| tag(xml, "receivertitle")),
+            PaymentKind::Transfer => tag(xml, "pay_memo"),
+        };
+        Some(Self {
+            kind,
+            status,
+            amount: tag(xml, "feedesc").filter(|a| !a.is_empty()),
+            note: note.filter(|n| !n.is_empty()),
+        })
+    }
+
+    /// Claims, returns and expiries update an earlier payment and are bridged as notices.
+    pub fn is_status_update(&self) -> bool {
+        self.status != PaymentStatus::Sent
+    }
+
+    fn title(&self) -> String {
+        match (self.kind, &self.amount) {
+            (PaymentKind::RedPacket, Some(amount)) => format!("\u{1f9e7} Red packet of {}", amount),
+            (PaymentKind::RedPacket, None) => "\u{1f9e7} Red packet".to_string(),
+            (PaymentKind::Transfer, Some(amount)) => format!("\u{1f4b8} Transfer of {}", amount),
+            (PaymentKind::Transfer, None) => "\u{1f4b8} Transfer".to_string(),
+        }
+    }
+
+    fn status_text(&self) -> &'static str {
+        match (self.kind, self.status) {
+            (PaymentKind::RedPacket, PaymentStatus::Sent) => "Open WeChat to claim it",
+            (PaymentKind::Transfer, PaymentStatus::Sent) => "Open WeChat to accept it",
+            (_, PaymentStatus::Claimed) => "claimed",
+            (_, PaymentStatus::Returned) => "returned",
+            (_, PaymentStatus::Expired) => "expired",
+        }
+    }
+
+    pub fn body(&self) -> String {
+        if self.is_status_update() {
+            return format!("{} was {}", self.title(), self.status_text());
+        }
+        let mut body = self.title();
+        if let Some(note) = &self.note {
+            body.push_str(&format!(": {}", note));
+        }
+        body.push_str(&format!("\n({})", self.status_text()));
+        body
+    }
+
+    pub fn html(&self) -> String {
+        let note = self.note.as_deref()
+            .map(|n| format!("<br/>{}", escape_html(n)))
+            .unwrap_or_default();
+        format!(
+            "<blockquote><strong>{}</strong>{}<br/><em>{}</em></blockquote>",
+            escape_html(&self.title()),
+            note,
+            self.status_text(),
+        )
+    }
+}
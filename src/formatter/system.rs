@@ -0,0 +1,74 @@
+use regex::Regex;
+
+/// Turns the content of a WeChat system message or notice into readable text. WeChat sends
+/// most of these as `<sysmsg>` XML, which is rendered from its template; plain text is kept.
+/// Returns `None` for payloads that don't carry anything to show.
+pub fn format_system_message(content: &str) -> Option<String> {
+    let content = content.trim();
+    if !content.starts_with('<') {
+        return Some(content.to_string()).filter(|c| !c.is_empty());
+    }
+
+    let text = if content.contains("<wcpayinfo>") {
+        red_packet(content)
+    } else if let Some(template) = tag(content, "template") {
+        Some(render_template(content, &template))
+    } else {
+        tag(content, "replacemsg").or_else(|| tag(content, "plain"))
+    };
+    text.map(|t| t.trim().to_string()).filter(|t| !t.is_empty())
+}
+
+fn red_packet(xml: &str) -> Option<String> {
+    let title = tag(xml, "sendertitle")
+        .or_else(|| tag(xml, "receivertitle"))
+        .unwrap_or_default();
+    Some(if title.is_empty() {
+        "\u{1f9e7} Red packet".to_string()
+    } else {
+        format!("\u{1f9e7} Red packet: {}", title)
+    })
+}
+
+/// Fills `$name$` placeholders with the members or title of the matching `<link>`, and
+/// `${wxid}` placeholders with the ID itself.
+fn render_template(xml: &str, template: &str) -> String {
+    let link_re = Regex::new(r#"(?s)<link\s+name="([^"]+)"[^>]*>(.*?)</link>"#).unwrap();
+    let mut text = template.to_string();
+    for link in link_re.captures_iter(xml) {
+        let body = &link[2];
+        let names: Vec<String> = tags(body, "nickname");
+        let value = if names.is_empty() {
+            tag(body, "title").unwrap_or_default()
+        } else {
+            let separator = tag(body, "separator").unwrap_or_else(|| "\u{3001}".to_string());
+            names.join(&separator)
+        };
+        text = text.replace(&format!("${}$", &link[1]), &value);
+    }
+
+    let id_re = Regex::new(r"\$\{([^}]+)\}").unwrap();
+    id_re.replace_all(&text, "$1").to_string()
+}
+
+pub(crate) fn tag(xml: &str, name: &str) -> Option<String> {
+    tags(xml, name).into_iter().next()
+}
+
+fn tags(xml: &str, name: &str) -> Vec<String> {
+    let re = Regex::new(&format!(r"(?s)<{0}(?:\s[^>]*)?>(.*?)</{0}>", regex::escape(name))).unwrap();
+    re.captures_iter(xml).map(|c| unescape(&c[1])).collect()
+}
+
+fn unescape(text: &str) -> String {
+    let text = text.trim();
+    let text = text
+        .strip_prefix("<![CDATA[")
+        .and_then(|t| t.strip_suffix("]]>"))
+        .unwrap_or(text);
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
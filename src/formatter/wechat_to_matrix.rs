@@ -1,3 +1,119 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::json;
+
+use super::escape_html;
+
+/// WeChat ends every `@name` mention with a four-per-em space.
+pub const MENTION_SEPARATOR: char = '\u{2005}';
+
+static MENTION_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new("@([^@\u{2005}]+)\u{2005}").unwrap());
+
+/// Who a WeChat mention refers to on Matrix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mention {
+    User(String),
+    /// `@all` in a group.
+    Room,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormattedText {
+    pub body: String,
+    pub html: String,
+    pub mentions: Vec<Mention>,
+}
+
+impl FormattedText {
+    /// The `m.mentions` content of the message.
+    pub fn mentions_content(&self) -> serde_json::Value {
+        let user_ids: Vec<&str> = self.mentions.iter()
+            .filter_map(|m| match m {
+                Mention::User(mxid) => Some(mxid.as_str()),
+                Mention::Room => None,
+            })
+            .collect();
+        let mut content = json!({ "user_ids": user_ids });
+        if self.mentions.contains(&Mention::Room) {
+            content["room"] = json!(true);
+        }
+        content
+    }
+}
+
 pub fn wechat_to_matrix(text: &str) -> String {
     super::emoji::wechat_to_unicode(text)
 }
+
+/// Converts a WeChat text message to Matrix, turning its `@name` mentions into pills.
+/// `mentions` are the mentioned users in the order WeChat lists them, which is the order they
+/// appear in the text; when the counts don't match, the text is left as is.
+pub fn format_text(text: &str, mentions: &[Mention]) -> FormattedText {
+    let text = wechat_to_matrix(text);
+    let found: Vec<_> = MENTION_REGEX.captures_iter(&text).collect();
+    let pill_mentions = found.len() == mentions.len();
+
+    let mut html = String::new();
+    let mut last = 0;
+    if pill_mentions {
+        for (captures, mention) in found.iter().zip(mentions) {
+            let whole = captures.get(0).unwrap();
+            html.push_str(&escape_html(&text[last..whole.start()]));
+            match mention {
+                Mention::User(mxid) => html.push_str(&format!(
+                    "<a href=\"https://matrix.to/#/{}\">{}</a> ",
+                    mxid,
+                    escape_html(&captures[1])
+                )),
+                Mention::Room => html.push_str(&format!("@{} ", escape_html(&captures[1]))),
+            }
+            last = whole.end();
+        }
+    }
+    html.push_str(&escape_html(&text[last..]));
+
+    FormattedText {
+        body: text.replace(MENTION_SEPARATOR, " "),
+        html: html.replace(MENTION_SEPARATOR, " ").replace('\n', "<br/>"),
+        mentions: mentions.to_vec(),
+    }
+}
+
+/// Prepends a quote of the message being replied to, as `> <@sender> quoted` in the body and a
+/// blockquote in the formatted body. With `original` (room and event ID) it's the rich reply
+/// fallback, shown by clients that don't render `m.in_reply_to`; without, the quote stands in
+/// for a reply to a message the bridge doesn't know.
+pub fn add_reply_fallback(
+    message: &mut serde_json::Value,
+    sender: &str,
+    quoted: &str,
+    original: Option<(&str, &str)>,
+) {
+    let body = message.get("body").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+    let html = message.get("formatted_body")
+        .and_then(|v| v.as_str())
+        .map(str::to_string)
+        .unwrap_or_else(|| escape_html(&body).replace('\n', "<br/>"));
+
+    let mut quoted_lines = quoted.lines();
+    let first = quoted_lines.next().unwrap_or_default();
+    let mut body_prefix = format!("> <{}> {}\n", sender, first);
+    for line in quoted_lines {
+        body_prefix.push_str(&format!("> {}\n", line));
+    }
+
+    let quote = format!(
+        "<blockquote>{}<a href=\"https://matrix.to/#/{}\">{}</a><br/>{}</blockquote>",
+        original
+            .map(|(room_id, event_id)| format!("<a href=\"https://matrix.to/#/{}/{}\">In reply to</a> ", room_id, event_id))
+            .unwrap_or_default(),
+        sender,
+        sender,
+        escape_html(quoted).replace('\n', "<br/>"),
+    );
+    let html_prefix = if original.is_some() { format!("<mx-reply>{}</mx-reply>", quote) } else { quote };
+
+    message["body"] = format!("{}\n{}", body_prefix, body).into();
+    message["format"] = "org.matrix.custom.html".into();
+    message["formatted_body"] = format!("{}{}", html_prefix, html).into();
+}
@@ -1,6 +1,7 @@
 pub mod config;
 pub mod database;
 pub mod wechat;
+pub mod wecom;
 pub mod bridge;
 pub mod util;
 pub mod formatter;
@@ -9,6 +10,8 @@ pub mod web;
 pub mod crypto;
 pub mod error;
 pub mod metrics;
+pub mod reporting;
+pub mod setup;
 
 pub const NAME: &str = "matrix-wechat";
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -1,12 +1,14 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 use std::sync::Arc;
-use tracing::{info, error, Level};
-use tracing_subscriber::FmtSubscriber;
+use tracing::{info, error, warn};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
 
 mod config;
 mod database;
 mod wechat;
+mod wecom;
 mod bridge;
 mod formatter;
 mod util;
@@ -15,9 +17,13 @@ mod web;
 mod crypto;
 mod error;
 mod metrics;
+mod reporting;
+mod setup;
+mod admin;
 
 use config::Config;
 use bridge::WechatBridge;
+use bridge::preflight;
 
 #[derive(Parser, Debug)]
 #[command(name = "matrix-wechat")]
@@ -31,6 +37,83 @@ struct Args {
     /// Generate example config and exit
     #[arg(long)]
     generate_config: bool,
+
+    /// Generate a registration file from the config and exit
+    #[arg(long)]
+    generate_registration: bool,
+
+    /// Path to write the generated registration file to
+    #[arg(long, default_value = "registration.yaml")]
+    registration: PathBuf,
+
+    /// Replace the as/hs tokens in the config with fresh random ones when generating the registration
+    #[arg(long, requires = "generate_registration")]
+    regenerate_tokens: bool,
+
+    /// Check the config, database, homeserver and registration file, print every problem and exit
+    #[arg(long)]
+    validate_config: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the bridge, the default without a command
+    Run,
+    /// Interactively create a config and registration file
+    Setup {
+        /// Path to write the registration file to
+        #[arg(short, long, default_value = "registration.yaml")]
+        registration: PathBuf,
+    },
+    /// Manage the database
+    #[command(subcommand)]
+    Db(DbCommand),
+    /// Manage bridge users
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manage portals
+    #[command(subcommand)]
+    Portal(PortalCommand),
+    /// Manage bridged media
+    #[command(subcommand)]
+    Media(MediaCommand),
+}
+
+// The commands below work on the database directly and are meant for when the bridge is stopped.
+
+#[derive(Subcommand, Debug)]
+enum DbCommand {
+    /// Create or upgrade the database schema
+    Migrate,
+    /// Print all portals as JSON lines
+    DumpPortals,
+}
+
+#[derive(Subcommand, Debug)]
+enum UserCommand {
+    /// Forget a user's WeChat login, so they have to log in again
+    Logout {
+        /// Matrix ID of the user
+        mxid: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PortalCommand {
+    /// Delete the portals of a WeChat chat, leaving their Matrix rooms
+    Delete {
+        /// WeChat ID of the chat
+        uid: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum MediaCommand {
+    /// Forget cached uploads and portal avatars, so they're uploaded to the homeserver again
+    Reupload,
 }
 
 const EXAMPLE_CONFIG: &str = include_str!("../example-config.yaml");
@@ -44,17 +127,54 @@ async fn main() -> anyhow::Result<()> {
         return Ok(());
     }
 
-    FmtSubscriber::builder()
-        .with_max_level(Level::DEBUG)
-        .pretty()
-        .init();
+    if args.generate_registration {
+        return setup::generate_registration(&args.config, &args.registration, args.regenerate_tokens);
+    }
+
+    let config_path = args.config.to_string_lossy();
+    match &args.command {
+        None | Some(Command::Run) => {}
+        Some(Command::Setup { registration }) => {
+            return setup::run(EXAMPLE_CONFIG, &args.config, registration).await;
+        }
+        Some(command) => {
+            let config = Config::load(&config_path)?;
+            return match command {
+                Command::Db(DbCommand::Migrate) => admin::migrate(&config).await,
+                Command::Db(DbCommand::DumpPortals) => admin::dump_portals(&config).await,
+                Command::User(UserCommand::Logout { mxid }) => admin::logout_user(&config, mxid).await,
+                Command::Portal(PortalCommand::Delete { uid }) => admin::delete_portal(&config, uid).await,
+                Command::Media(MediaCommand::Reupload) => admin::reupload_media(&config).await,
+                Command::Run | Command::Setup { .. } => unreachable!(),
+            };
+        }
+    }
+
+    if args.validate_config {
+        let config = Config::read(&config_path)?;
+        let problems = preflight::run(&config, Some(&args.registration)).await;
+        for problem in &problems {
+            println!("{}{}", if problem.fatal { "error: " } else { "warning: " }, problem.message);
+        }
+        if !problems.is_empty() {
+            anyhow::bail!("found {} problems in {}", problems.len(), config_path);
+        }
+        println!("{} is valid.", config_path);
+        return Ok(());
+    }
+
+    let subscriber = tracing_subscriber::registry()
+        .with(LevelFilter::DEBUG)
+        .with(tracing_subscriber::fmt::layer().pretty());
+    #[cfg(feature = "sentry")]
+    let subscriber = subscriber.with(sentry_tracing::layer());
+    subscriber.init();
     
     info!("Starting Matrix-WeChat bridge v{}", env!("CARGO_PKG_VERSION"));
     
-    let config_path = args.config.to_string_lossy();
     info!("Loading config from {}", config_path);
     
-    let config = match Config::load(&config_path) {
+    let config = match Config::read(&config_path) {
         Ok(c) => c,
         Err(e) => {
             error!("Failed to load config: {}", e);
@@ -62,43 +182,40 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
-    let bridge = WechatBridge::new(config.clone()).await?;
-    let bridge = Arc::new(bridge);
+    let problems = preflight::run(&config, Some(&args.registration)).await;
+    for problem in &problems {
+        if problem.fatal {
+            error!("{}", problem.message);
+        } else {
+            warn!("{}", problem.message);
+        }
+    }
+    if problems.iter().any(|p| p.fatal) {
+        anyhow::bail!("the preflight checks failed, see above");
+    }
+
+    let _report_guard = reporting::init(&config.logging.sentry);
+
+    let bridge = WechatBridge::new(config).await?;
+    let server = web::BridgeServer::new(Arc::new(bridge));
     
     info!("Bridge initialized, starting services...");
 
-    let web_router = web::create_appservice_router(bridge.clone());
-    let web_addr: &'static str = Box::leak(format!("{}:{}", config.appservice.hostname, config.appservice.port).into_boxed_str());
-    info!("Web server will listen on {}", web_addr);
-    
-    let bridge_for_task = bridge.clone();
-    let web_handle = tokio::spawn(async move {
-        use salvo::conn::TcpListener;
-        use salvo::prelude::*;
-        
-        let listener = TcpListener::new(web_addr).bind().await;
-        Server::new(listener).serve(web_router).await;
-    });
-
-    let bridge_handle = tokio::spawn(async move {
-        if let Err(e) = bridge_for_task.start().await{
-            error!("Bridge error: {}", e);
-        }
-    });
+    if let Err(e) = server.start().await {
+        error!("Failed to start bridge: {}", e);
+        return Err(e);
+    }
 
     tokio::select! {
-        _ = bridge_handle => {
-            info!("Bridge task ended");
-        }
-        _ = web_handle => {
+        _ = server.failed() => {
             info!("Web server task ended");
         }
-        _ = tokio::signal::ctrl_c() => {
+        _ = web::shutdown_signal() => {
             info!("Received shutdown signal");
         }
     }
     
-    bridge.stop().await;
+    server.stop().await;
     info!("Bridge stopped");
     
     Ok(())
@@ -20,7 +20,13 @@ pub struct AppService {
 
 pub trait AppServiceBridge: Send + Sync {
     fn handle_transaction(&self, txn_id: &str, events: Vec<RoomEvent>) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + '_>>;
+    /// Answers a homeserver query for a room alias, returning whether it exists now.
+    fn query_room_alias(&self, alias: &str) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>> + Send + '_>>;
+    /// Handles to-device events pushed with a transaction (MSC2409).
+    fn handle_to_device(&self, events: Vec<ToDeviceEvent>) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
     fn is_user_in_namespace(&self, mxid: &str) -> bool;
+    /// Transactions are refused while the bridge shuts down so the homeserver retries them later.
+    fn is_stopping(&self) -> bool;
 }
 
 impl AppService {
@@ -69,14 +75,18 @@ struct TransactionHandler {
 
 #[handler]
 impl TransactionHandler {
-    async fn handle(&self, req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    async fn handle(&self, req: &mut Request, res: &mut Response, _depot: &mut Depot) {
         let auth = req.header::<String>("Authorization");
         if !self.verify_auth(&auth) {
             res.render(StatusError::unauthorized());
             return;
         }
+        if self.as_.bridge.is_stopping() {
+            res.render(StatusError::service_unavailable());
+            return;
+        }
 
-        let txn_id = depot.get::<String>("txn_id").map(|s| s.as_str()).unwrap_or("");
+        let txn_id = req.param::<String>("txn_id").unwrap_or_default();
         
         let body: Result<Transaction, _> = req.parse_json().await;
         let transaction = match body {
@@ -90,8 +100,16 @@ impl TransactionHandler {
 
         debug!("Received transaction {} with {} events", txn_id, transaction.events.len());
 
-        if let Err(e) = self.as_.bridge.handle_transaction(txn_id, transaction.events).await {
-            error!("Error handling transaction: {}", e);
+        let mut events = transaction.events;
+        events.extend(transaction.ephemeral);
+        if let Err(e) = self.as_.bridge.handle_transaction(&txn_id, events).await {
+            // The homeserver retries the transaction; already handled ones are skipped then.
+            error!("Error handling transaction {}: {}", txn_id, e);
+            res.render(StatusError::internal_server_error());
+            return;
+        }
+        if !transaction.to_device.is_empty() {
+            self.as_.bridge.handle_to_device(transaction.to_device).await;
         }
 
         res.render(Json(serde_json::json!({})));
@@ -146,17 +164,24 @@ struct RoomHandler {
 
 #[handler]
 impl RoomHandler {
-    async fn handle(&self, req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    async fn handle(&self, req: &mut Request, res: &mut Response) {
         let auth = req.header::<String>("Authorization");
         if !self.verify_auth(&auth) {
             res.render(StatusError::unauthorized());
             return;
         }
 
-        let room_alias = depot.get::<String>("room_alias").map(|s| s.as_str()).unwrap_or("");
+        let room_alias = req.param::<String>("room_alias").unwrap_or_default();
         debug!("Room alias query: {}", room_alias);
-        
-        res.render(StatusError::not_found());
+
+        match self.as_.bridge.query_room_alias(&room_alias).await {
+            Ok(true) => res.render(Json(serde_json::json!({}))),
+            Ok(false) => res.render(StatusError::not_found()),
+            Err(e) => {
+                error!("Failed to handle alias query for {}: {}", room_alias, e);
+                res.render(StatusError::not_found());
+            }
+        }
     }
 
     fn verify_auth(&self, auth: &Option<String>) -> bool {
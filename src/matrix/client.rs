@@ -1,11 +1,16 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use anyhow::{Result, anyhow};
 use reqwest::Client;
 use serde::de::DeserializeOwned;
-use tracing::debug;
+use tracing::{debug, warn};
 
+use crate::error::MatrixError;
 use crate::matrix::types::*;
+use crate::matrix::rate_limit;
+use crate::util::{BackoffConfig, ExponentialBackoff};
 
 #[derive(Clone)]
 pub struct MatrixClient {
@@ -13,6 +18,8 @@ pub struct MatrixClient {
     access_token: String,
     client: Client,
     user_id: Option<String>,
+    impersonate: bool,
+    timestamp: Option<i64>,
 }
 
 impl MatrixClient {
@@ -22,43 +29,122 @@ impl MatrixClient {
             access_token: access_token.into(),
             client: Client::new(),
             user_id: None,
+            impersonate: false,
+            timestamp: None,
         }
     }
 
+    /// Sends requests through `client`, so clients of the same bridge share its connection pool.
+    pub fn with_http_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
     pub fn with_user_id(mut self, user_id: impl Into<String>) -> Self {
         self.user_id = Some(user_id.into());
         self
     }
 
+    /// Act as an appservice user by passing `user_id` with every request.
+    pub fn impersonate(mut self, user_id: impl Into<String>) -> Self {
+        self.user_id = Some(user_id.into());
+        self.impersonate = true;
+        self
+    }
+
+    /// Backdate events sent while impersonating to `timestamp` (in milliseconds) with the
+    /// appservice `ts` parameter, so they keep the time they were sent on WeChat.
+    pub fn with_timestamp(mut self, timestamp: i64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
     pub fn user_id(&self) -> Option<&str> {
         self.user_id.as_deref()
     }
 
-    fn url(&self, path: &str) -> String {
-        format!("{}{}", self.homeserver.trim_end_matches('/'), path)
+    pub fn url(&self, path: &str) -> String {
+        let mut url = format!("{}{}", self.homeserver.trim_end_matches('/'), path);
+        let Some(user_id) = self.user_id.as_deref().filter(|_| self.impersonate) else {
+            return url;
+        };
+        let sep = if url.contains('?') { '&' } else { '?' };
+        url.push_str(&format!("{}user_id={}", sep, urlencoding::encode(user_id)));
+        if let Some(ts) = self.timestamp {
+            url.push_str(&format!("&ts={}", ts));
+        }
+        url
+    }
+
+    /// Sends the request made by `build`, waiting for the rate limit budget of `path` first and
+    /// again for as long as the homeserver asks when it answers with `M_LIMIT_EXCEEDED`.
+    /// Idempotent requests are also retried with backoff after network errors and 5xx responses.
+    async fn send_limited(
+        &self,
+        method: &reqwest::Method,
+        path: &str,
+        build: impl Fn() -> reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response> {
+        let limits = rate_limit::current();
+        let mut retries = 0;
+        let mut backoff = is_idempotent(method).then(|| ExponentialBackoff::new(transient_backoff()));
+        loop {
+            limits.acquire(path).await;
+            let result = build().send().await;
+            let transient = match &result {
+                Ok(resp) => resp.status().is_server_error(),
+                Err(e) => !e.is_builder(),
+            };
+            if transient && let Some(delay) = backoff.as_mut().and_then(|b| b.next_delay()) {
+                match &result {
+                    Ok(resp) => warn!("Homeserver answered {} to a {} request, retrying in {:?}", resp.status(), method, delay),
+                    // The error would include the URL, and with it the access token.
+                    Err(e) => warn!("{} request to the homeserver {}, retrying in {:?}", method, transport_failure(e), delay),
+                }
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+            let resp = result.map_err(|e| MatrixError::Http(format!("request {}", transport_failure(&e))))?;
+            if resp.status() != reqwest::StatusCode::TOO_MANY_REQUESTS || retries >= limits.max_retries() {
+                return Ok(resp);
+            }
+            retries += 1;
+            let header = resp.headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+            let body = resp.text().await.unwrap_or_default();
+            let delay = rate_limit::retry_after(&body, header.as_deref());
+            warn!("Rate limited by the homeserver on {} requests, retrying in {:?}", rate_limit::endpoint_group(path), delay);
+            tokio::time::sleep(delay).await;
+        }
     }
 
     async fn request<T: DeserializeOwned>(&self, method: reqwest::Method, path: &str, body: Option<&serde_json::Value>) -> Result<T> {
         let url = self.url(path);
-        let mut req = self.client
-            .request(method.clone(), &url)
-            .bearer_auth(&self.access_token);
-        
-        if let Some(json) = body {
-            req = req.json(json);
-        }
-        
         debug!("Matrix API request: {:?} {}", method, url);
-        
-        let resp = req.send().await?;
+
+        let resp = self.send_limited(&method, path, || {
+            let req = self.client
+                .request(method.clone(), &url)
+                .bearer_auth(&self.access_token);
+            match body {
+                Some(json) => req.json(json),
+                None => req,
+            }
+        }).await?;
         let status = resp.status();
         let text = resp.text().await?;
         
         debug!("Matrix API response: {} - {}", status, text);
         
         if !status.is_success() {
+            // Proxies answer these while the homeserver is down or restarting.
+            if matches!(status.as_u16(), 502..=504) {
+                return Err(MatrixError::Http(format!("homeserver answered {}", status)).into());
+            }
             if let Ok(error) = serde_json::from_str::<ErrorResponse>(&text) {
-                return Err(anyhow!("Matrix error: {} - {}", error.errcode, error.error));
+                return Err(MatrixError::Api { code: error.errcode, message: error.error }.into());
             }
             return Err(anyhow!("Matrix request failed: {} - {}", status, text));
         }
@@ -70,6 +156,19 @@ impl MatrixClient {
         serde_json::from_str(&text).map_err(|e| anyhow!("Failed to parse response: {} - {}", e, text))
     }
 
+    /// Registers a user in the appservice namespace, succeeding if it already exists.
+    pub async fn register_appservice_user(&self, localpart: &str) -> Result<()> {
+        let body = serde_json::json!({
+            "type": "m.login.application_service",
+            "username": localpart,
+            "inhibit_login": true,
+        });
+        match self.request::<serde_json::Value>(reqwest::Method::POST, "/_matrix/client/v3/register", Some(&body)).await {
+            Err(e) if matrix_errcode(&e) != Some("M_USER_IN_USE") => Err(e),
+            _ => Ok(()),
+        }
+    }
+
     pub async fn get_user_id(&self) -> Result<String> {
         let result: serde_json::Value = self.request(reqwest::Method::GET, "/_matrix/client/v3/account/whoami", None).await?;
         result.get("user_id")
@@ -78,6 +177,17 @@ impl MatrixClient {
             .ok_or_else(|| anyhow!("No user_id in response"))
     }
 
+    /// Asks the homeserver to ping the appservice (MSC2659), returning how long the ping took in
+    /// milliseconds. Fails with the homeserver's error when the ping didn't reach the bridge.
+    pub async fn ping_appservice(&self, appservice_id: &str, transaction_id: &str) -> Result<u64> {
+        let path = format!("/_matrix/client/v1/appservice/{}/ping", urlencoding::encode(appservice_id));
+        let body = serde_json::json!({ "transaction_id": transaction_id });
+        let result: serde_json::Value = self.request(reqwest::Method::POST, &path, Some(&body)).await?;
+        result.get("duration_ms")
+            .and_then(|v| v.as_u64())
+            .ok_or_else(|| anyhow!("No duration_ms in response"))
+    }
+
     pub async fn send_message(&self, room_id: &str, event_type: &str, content: &serde_json::Value, txn_id: Option<&str>) -> Result<String> {
         let default_txn = chrono::Utc::now().timestamp_millis().to_string();
         let txn_id = txn_id.unwrap_or(&default_txn);
@@ -110,6 +220,12 @@ impl MatrixClient {
         self.send_message(room_id, "m.room.message", &content, None).await
     }
 
+    pub async fn send_notice_html(&self, room_id: &str, plain: impl Into<String>, html: impl Into<String>) -> Result<String> {
+        let content = EventContent::notice_html(plain, html);
+        let content = serde_json::to_value(&content)?;
+        self.send_message(room_id, "m.room.message", &content, None).await
+    }
+
     pub async fn send_emote(&self, room_id: &str, text: impl Into<String>) -> Result<String> {
         let content = serde_json::json!({
             "msgtype": "m.emote",
@@ -118,6 +234,29 @@ impl MatrixClient {
         self.send_message(room_id, "m.room.message", &content, None).await
     }
 
+    pub async fn edit_message(&self, room_id: &str, event_id: &str, new_content: &serde_json::Value) -> Result<String> {
+        let body = new_content.get("body").and_then(|v| v.as_str()).unwrap_or("");
+        let mut content = new_content.clone();
+        content["body"] = format!("* {}", body).into();
+        content["m.new_content"] = new_content.clone();
+        content["m.relates_to"] = serde_json::json!({
+            "rel_type": "m.replace",
+            "event_id": event_id
+        });
+        self.send_message(room_id, "m.room.message", &content, None).await
+    }
+
+    pub async fn send_reaction(&self, room_id: &str, event_id: &str, key: &str) -> Result<String> {
+        let content = serde_json::json!({
+            "m.relates_to": {
+                "rel_type": "m.annotation",
+                "event_id": event_id,
+                "key": key
+            }
+        });
+        self.send_message(room_id, "m.reaction", &content, None).await
+    }
+
     pub async fn redact(&self, room_id: &str, event_id: &str, reason: Option<&str>) -> Result<String> {
         let txn_id = chrono::Utc::now().timestamp_millis().to_string();
         let path = format!(
@@ -241,12 +380,12 @@ impl MatrixClient {
         );
         let url = self.url(&path);
         
-        let resp = self.client
-            .post(&url)
-            .header("Content-Type", content_type)
-            .body(data.to_vec())
-            .send()
-            .await?;
+        let resp = self.send_limited(&reqwest::Method::POST, &path, || {
+            self.client
+                .post(&url)
+                .header("Content-Type", content_type)
+                .body(data.to_vec())
+        }).await?;
         
         let status = resp.status();
         let text = resp.text().await?;
@@ -262,6 +401,43 @@ impl MatrixClient {
             .ok_or_else(|| anyhow!("No content_uri in response"))
     }
 
+    /// Reserves an mxc URI to upload to later with [`Self::upload_media_to`] (MSC2246), so a
+    /// message can reference media before it's available.
+    pub async fn create_media(&self) -> Result<String> {
+        let path = format!("/_matrix/media/v1/create?access_token={}", self.access_token);
+        let result: serde_json::Value = self.request(reqwest::Method::POST, &path, Some(&serde_json::json!({}))).await?;
+        result.get("content_uri")
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string())
+            .ok_or_else(|| anyhow!("No content_uri in response"))
+    }
+
+    /// Uploads the content of an mxc URI reserved with [`Self::create_media`].
+    pub async fn upload_media_to(&self, mxc_url: &str, data: &[u8], content_type: &str, filename: &str) -> Result<()> {
+        let Some((server, media_id)) = mxc_url.strip_prefix("mxc://").and_then(|m| m.split_once('/')) else {
+            return Err(anyhow!("Invalid mxc URL: {}", mxc_url));
+        };
+        let path = format!(
+            "/_matrix/media/v3/upload/{}/{}?access_token={}&filename={}",
+            server, media_id, self.access_token, urlencoding::encode(filename)
+        );
+        let url = self.url(&path);
+
+        let resp = self.send_limited(&reqwest::Method::PUT, &path, || {
+            self.client
+                .put(&url)
+                .header("Content-Type", content_type)
+                .body(data.to_vec())
+        }).await?;
+
+        let status = resp.status();
+        if !status.is_success() {
+            let text = resp.text().await?;
+            return Err(anyhow!("Media upload failed: {} - {}", status, text));
+        }
+        Ok(())
+    }
+
     pub async fn download_media(&self, mxc_url: &str) -> Result<Vec<u8>> {
         let mxc_url = mxc_url.strip_prefix("mxc://").unwrap_or(mxc_url);
         let parts: Vec<&str> = mxc_url.split('/').collect();
@@ -277,10 +453,7 @@ impl MatrixClient {
         );
         let url = self.url(&path);
         
-        let resp = self.client
-            .get(&url)
-            .send()
-            .await?;
+        let resp = self.send_limited(&reqwest::Method::GET, &path, || self.client.get(&url)).await?;
         
         let status = resp.status();
         if !status.is_success() {
@@ -308,6 +481,12 @@ impl MatrixClient {
         Ok(())
     }
 
+    pub async fn delete_room_alias(&self, alias: &str) -> Result<()> {
+        let path = format!("/_matrix/client/v3/directory/room/{}?access_token={}", urlencoding::encode(alias), self.access_token);
+        let _: serde_json::Value = self.request(reqwest::Method::DELETE, &path, None).await?;
+        Ok(())
+    }
+
     pub async fn sync(&self, since: Option<&str>, timeout_ms: u64) -> Result<serde_json::Value> {
         let mut path = format!("/_matrix/client/v3/sync?access_token={}", self.access_token);
         if let Some(s) = since {
@@ -352,8 +531,80 @@ impl MatrixClient {
         let _: serde_json::Value = self.request(reqwest::Method::POST, &path, Some(&serde_json::json!({}))).await?;
         Ok(())
     }
+
+    /// Sends to-device events, keyed by recipient user ID and then device ID.
+    pub async fn send_to_device(
+        &self,
+        event_type: &str,
+        messages: &HashMap<String, HashMap<String, serde_json::Value>>,
+    ) -> Result<()> {
+        let txn_id = format!(
+            "{}-{}",
+            chrono::Utc::now().timestamp_millis(),
+            TO_DEVICE_TXN_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = format!(
+            "/_matrix/client/v3/sendToDevice/{}/{}?access_token={}",
+            urlencoding::encode(event_type), txn_id, self.access_token
+        );
+        let body = serde_json::json!({ "messages": messages });
+        let _: serde_json::Value = self.request(reqwest::Method::PUT, &path, Some(&body)).await?;
+        Ok(())
+    }
+
+    /// Fetches the device keys of all devices of `user_id`, keyed by device ID.
+    pub async fn query_device_keys(&self, user_id: &str) -> Result<HashMap<String, serde_json::Value>> {
+        let path = format!("/_matrix/client/v3/keys/query?access_token={}", self.access_token);
+        let body = serde_json::json!({ "device_keys": { user_id: [] } });
+        let mut result: serde_json::Value = self.request(reqwest::Method::POST, &path, Some(&body)).await?;
+        let devices = result["device_keys"][user_id].take();
+        Ok(serde_json::from_value(devices).unwrap_or_default())
+    }
+}
+
+/// The Matrix error code of a failed request, such as `M_FORBIDDEN`.
+pub fn matrix_errcode(error: &anyhow::Error) -> Option<&str> {
+    match error.downcast_ref::<MatrixError>()? {
+        MatrixError::Api { code, .. } => Some(code),
+        _ => None,
+    }
 }
 
+/// Whether a request failed because the homeserver couldn't be reached or is down, rather
+/// than because it refused the request.
+pub fn is_homeserver_unavailable(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| matches!(e.downcast_ref::<MatrixError>(), Some(MatrixError::Http(_))))
+}
+
+fn transport_failure(error: &reqwest::Error) -> &'static str {
+    if error.is_timeout() {
+        "timed out"
+    } else if error.is_connect() {
+        "couldn't connect"
+    } else {
+        "failed"
+    }
+}
+
+/// Requests that can be repeated without changing the outcome, so they're retried after
+/// transient failures. `PUT` events are deduplicated by their transaction ID.
+pub fn is_idempotent(method: &reqwest::Method) -> bool {
+    matches!(*method, reqwest::Method::GET | reqwest::Method::HEAD | reqwest::Method::PUT | reqwest::Method::DELETE)
+}
+
+fn transient_backoff() -> BackoffConfig {
+    BackoffConfig {
+        initial_delay: std::time::Duration::from_millis(200),
+        max_delay: std::time::Duration::from_secs(5),
+        multiplier: 2.0,
+        max_retries: 3,
+        jitter: true,
+    }
+}
+
+/// Keeps to-device transaction IDs unique when several are sent within a millisecond.
+static TO_DEVICE_TXN_COUNTER: AtomicU64 = AtomicU64::new(0);
+
 pub struct MatrixClientBuilder {
     homeserver: String,
     access_token: String,
@@ -1,10 +1,20 @@
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use anyhow::Context;
 use tracing::{debug, info, warn, error};
 
+use crate::matrix::intent::DOUBLE_PUPPET_SOURCE;
 use crate::matrix::types::RoomEvent;
 use crate::bridge::WechatBridge;
+use crate::bridge::media_limit::check_size;
+use crate::bridge::message_status::SendFailure;
+use crate::bridge::send_retry::SendRetryQueue;
+use crate::metrics::Direction;
+use crate::formatter::escape_html;
+
+/// Longest quote of a replied-to message sent along with a reply to WeChat.
+const REPLY_QUOTE_MAX_CHARS: usize = 100;
 
 pub struct MatrixEventHandler {
     bridge: Arc<WechatBridge>,
@@ -30,11 +40,27 @@ impl MatrixEventHandler {
             return Ok(());
         }
 
+        self.bridge.room_state.apply_event(event).await;
+
         if self.is_own_event(event) {
             debug!("Dropping own event: {:?}", event.event_id);
             return Ok(());
         }
 
+        // Redactions and reactions are bridged as the sender's own WeChat account.
+        if matches!(event.event_type.as_str(), "m.room.redaction" | "m.reaction")
+            && !self.sender_level(event).can_use()
+        {
+            debug!("Dropping {} from user without bridge permission", event.event_type);
+            return Ok(());
+        }
+        if matches!(event.event_type.as_str(), "m.room.message" | "m.room.sticker" | "m.room.redaction" | "m.reaction")
+            && self.sender_level(event).can_use()
+            && let Some(sender) = &event.sender
+        {
+            self.bridge.activity.matrix_event(sender).await;
+        }
+
         match event.event_type.as_str() {
             "m.room.message" | "m.room.sticker" => {
                 self.handle_message_event(event).await?;
@@ -42,7 +68,7 @@ impl MatrixEventHandler {
             "m.room.redaction" => {
                 self.handle_redaction_event(event).await?;
             }
-            "m.room.reaction" => {
+            "m.reaction" => {
                 self.handle_reaction_event(event).await?;
             }
             "m.room.member" => {
@@ -51,6 +77,9 @@ impl MatrixEventHandler {
             "m.room.encryption" => {
                 self.handle_encryption_event(event).await?;
             }
+            "m.room.tombstone" => {
+                self.handle_tombstone_event(event).await?;
+            }
             "m.room.name" => {
                 self.handle_room_name_event(event).await?;
             }
@@ -80,6 +109,17 @@ impl MatrixEventHandler {
         Ok(())
     }
 
+    /// Sends a message queued after a retriable failure again, bypassing the event age limit.
+    pub async fn retry_message_event(&self, event: &RoomEvent) -> anyhow::Result<()> {
+        self.handle_message_event(event).await
+    }
+
+    fn sender_level(&self, event: &RoomEvent) -> crate::config::PermissionLevel {
+        event.sender.as_deref()
+            .map(|sender| self.bridge.get_permission(sender))
+            .unwrap_or(crate::config::PermissionLevel::Block)
+    }
+
     fn is_event_too_old(&self, event: &RoomEvent) -> bool {
         let Some(ts) = event.origin_server_ts else {
             return false;
@@ -95,6 +135,9 @@ impl MatrixEventHandler {
     }
 
     fn is_own_event(&self, event: &RoomEvent) -> bool {
+        if event.content.as_ref().is_some_and(|c| c.get(DOUBLE_PUPPET_SOURCE).is_some()) {
+            return true;
+        }
         let bot_mxid = self.bridge.config.appservice.bot.mxid(&self.bridge.config.homeserver.domain);
         if let Some(sender) = &event.sender {
             sender == &bot_mxid || self.is_puppet_mxid(sender)
@@ -127,12 +170,24 @@ impl MatrixEventHandler {
             .and_then(|c| c.get("body"))
             .and_then(|v| v.as_str())
             .unwrap_or("");
+        // WeChat shows the quote of a reply itself, so drop the fallback clients add to the body.
+        let body = if reply_event_id(event).is_some() {
+            crate::formatter::matrix_to_wechat::strip_reply_fallback(body).1
+        } else {
+            body
+        };
 
         if body.is_empty() && msgtype != "m.sticker" {
             debug!("Empty message body, skipping");
             return Ok(());
         }
 
+        let level = self.bridge.get_permission(sender);
+        if !level.can_relay() {
+            debug!("Ignoring message from {} without bridge permission", sender);
+            return Ok(());
+        }
+
         let command_prefix = self.bridge.command_processor().command_prefix();
         if body.starts_with(command_prefix) {
             self.handle_command(event, body).await?;
@@ -144,35 +199,174 @@ impl MatrixEventHandler {
             return Ok(());
         };
 
-        let Some(user) = self.get_user_by_mxid(sender).await? else {
+        let Some(mut user) = self.get_user_by_mxid(sender).await? else {
             debug!("No user found for mxid {}", sender);
             return Ok(());
         };
 
-        match msgtype {
-            "m.text" | "m.notice" | "m.emote" => {
-                self.handle_text_message(&user, &portal, event, body, msgtype).await?;
-            }
-            "m.image" => {
-                self.handle_image_message(&user, &portal, event).await?;
+        let mut body = body.to_string();
+        let mut msgtype = msgtype;
+        if user.uin().is_none() || !level.can_use() {
+            let Some(relay) = self.get_relay_user(&portal).await? else {
+                debug!("{} is not logged in and {} has no relay user", sender, room_id);
+                return Ok(());
+            };
+            let setting = self.bridge.db.get_portal_setting(&portal.key, sender).await?;
+            if setting.is_some_and(|s| !s.relay) {
+                debug!("{} turned off relaying their messages in {}", sender, room_id);
+                return Ok(());
             }
-            "m.video" => {
-                self.handle_video_message(&user, &portal, event).await?;
+            let Some(relay_client) = relay.get_client() else {
+                return Ok(());
+            };
+
+            let displayname = self.get_member_displayname(room_id, sender).await;
+            let text = self.bridge.config.bridge.relay.format_message(msgtype, &displayname, sender, &body);
+            if matches!(msgtype, "m.text" | "m.notice" | "m.emote") {
+                body = text;
+                msgtype = "m.text";
+            } else if let Err(e) = relay_client.send_text_message(&portal.key.uid, &text, None).await {
+                warn!("Failed to send relay caption to WeChat: {}", e);
             }
-            "m.audio" => {
-                self.handle_audio_message(&user, &portal, event).await?;
+            debug!("Relaying message from {} through {}", sender, relay.mxid);
+            user = Arc::new(relay);
+        }
+
+        if let Some(target_event_id) = self.get_replace_target(event) {
+            self.handle_edit_message(&user, &portal, event, target_event_id).await?;
+            return Ok(());
+        }
+
+        let retry_num = self.previous_send_failures(&portal, event).await?;
+        self.record_pending_send(&portal, event, msgtype).await?;
+        let result = match msgtype {
+            "m.text" | "m.notice" | "m.emote" => {
+                self.handle_text_message(&user, &portal, event, &body, msgtype).await
             }
-            "m.file" => {
-                self.handle_file_message(&user, &portal, event).await?;
+            "m.image" => self.handle_image_message(&user, &portal, event).await,
+            "m.video" => self.handle_video_message(&user, &portal, event).await,
+            "m.audio" => self.handle_audio_message(&user, &portal, event).await,
+            "m.file" => self.handle_file_message(&user, &portal, event).await,
+            "m.sticker" => self.handle_sticker_message(&user, &portal, event).await,
+            _ => Err(anyhow::anyhow!("{} messages aren't supported", msgtype)),
+        };
+
+        self.report_send_status(&portal, event, msgtype, retry_num, result).await
+    }
+
+    /// Counts earlier failed attempts to bridge this event, dropping the stored failure so the
+    /// outcome of this attempt can take its place.
+    async fn previous_send_failures(
+        &self,
+        portal: &crate::bridge::portal::BridgePortal,
+        event: &RoomEvent,
+    ) -> anyhow::Result<u32> {
+        let Some(event_id) = &event.event_id else {
+            return Ok(0);
+        };
+        match self.bridge.db.get_message_by_mxid(event_id).await? {
+            Some(msg) if !msg.sent => {
+                self.bridge.db.delete_message(&portal.key, &msg.msg_id).await?;
+                Ok(msg.error.as_deref().map(SendFailure::from_db).map_or(1, |f| f.retry_num + 1))
             }
-            "m.sticker" => {
-                self.handle_sticker_message(&user, &portal, event).await?;
+            _ => Ok(0),
+        }
+    }
+
+    /// Writes the message row before sending, so an interrupted send is found at the next start
+    /// instead of being lost. The row is keyed by the event ID until WeChat assigns its own.
+    async fn record_pending_send(
+        &self,
+        portal: &crate::bridge::portal::BridgePortal,
+        event: &RoomEvent,
+        msgtype: &str,
+    ) -> anyhow::Result<()> {
+        let Some(event_id) = &event.event_id else {
+            return Ok(());
+        };
+        let msg = crate::database::Message {
+            chat_uid: portal.key.uid.clone(),
+            chat_receiver: portal.key.receiver.clone(),
+            msg_id: event_id.clone(),
+            mxid: event_id.clone(),
+            sender: event.sender.clone().unwrap_or_default(),
+            timestamp: event.origin_server_ts.unwrap_or(0),
+            sent: false,
+            error: None,
+            msg_type: msgtype.to_string(),
+        };
+        self.bridge.db.insert_message(&msg).await
+    }
+
+    async fn report_send_status(
+        &self,
+        portal: &crate::bridge::portal::BridgePortal,
+        event: &RoomEvent,
+        msgtype: &str,
+        retry_num: u32,
+        result: anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let pending = match &event.event_id {
+            Some(event_id) => self.bridge.db.get_outgoing_retry(event_id).await?,
+            None => None,
+        };
+
+        if let Some(sender) = &event.sender {
+            crate::metrics::metrics().record_message(Direction::MatrixToWechat, msgtype, sender, result.is_ok());
+        }
+
+        let failure = match result {
+            Ok(()) => None,
+            Err(e) => {
+                warn!("Failed to bridge {:?} to WeChat: {:#}", event.event_id, e);
+                let failure = SendFailure::new(format!("{:#}", e), retry_num);
+                if SendRetryQueue::is_retriable(&e) && self.bridge.send_retry.can_retry(retry_num) {
+                    Some(failure.retriable())
+                } else {
+                    Some(failure)
+                }
             }
-            _ => {
-                warn!("Unsupported msgtype: {}", msgtype);
+        };
+
+        if let Some(event_id) = &event.event_id {
+            match &failure {
+                Some(failure) => {
+                    let error = failure.to_db();
+                    self.bridge.db.complete_message(&portal.key, event_id, event_id, event_id, Some(&error)).await?;
+                }
+                // Edits applied in place don't create a WeChat message, so nothing completed the row.
+                None => {
+                    if let Some(msg) = self.bridge.db.get_message_by_id(&portal.key, event_id).await?
+                        && !msg.sent
+                    {
+                        self.bridge.db.delete_message(&portal.key, event_id).await?;
+                    }
+                }
             }
         }
 
+        let client = self.bridge.get_matrix_client();
+        let previous_notice = pending.as_ref().and_then(|p| p.notice_id.as_deref());
+        let notice_id = self.bridge.message_status
+            .report(&client, event, msgtype, failure.as_ref(), previous_notice)
+            .await;
+
+        let Some(event_id) = &event.event_id else {
+            return Ok(());
+        };
+        if failure.as_ref().is_some_and(|f| f.will_retry) {
+            let retry = crate::database::OutgoingRetry {
+                event_id: event_id.clone(),
+                room_id: event.room_id.clone().unwrap_or_default(),
+                event: serde_json::to_string(event)?,
+                notice_id,
+            };
+            self.bridge.db.upsert_outgoing_retry(&retry).await?;
+            self.bridge.send_retry.schedule(event_id, retry_num).await;
+            debug!("Queued {} to be sent again after {} failed attempts", event_id, retry_num + 1);
+        } else if pending.is_some() {
+            self.bridge.db.delete_outgoing_retry(event_id).await?;
+        }
         Ok(())
     }
 
@@ -197,11 +391,28 @@ impl MatrixEventHandler {
             return Ok(());
         };
 
+        if let Some(reaction) = self.bridge.db.get_reaction_by_mxid(redacted_event_id).await? {
+            if let Some(sender) = &event.sender {
+                let client = self.bridge.get_client(sender);
+                if let Err(e) = client.send_reaction(&portal.key.uid, &reaction.target_msg_id, "").await {
+                    warn!("Failed to remove reaction on WeChat: {}", e);
+                }
+            }
+            self.bridge.db.delete_reaction_by_mxid(redacted_event_id).await?;
+            return Ok(());
+        }
+
+        if self.bridge.db.get_outgoing_retry(redacted_event_id).await?.is_some() {
+            // The message never reached WeChat, so there's nothing to revoke.
+            self.bridge.db.delete_outgoing_retry(redacted_event_id).await?;
+            return Ok(());
+        }
+
         let key = portal.key.clone();
         let msg = self.bridge.db.get_message_by_mxid(redacted_event_id).await?;
         
         if let Some(msg) = msg {
-            let client = self.bridge.get_client(&portal.key.receiver);
+            let client = self.bridge.get_client_by_uin(&portal.key.receiver).await?;
             if let Err(e) = client.revoke_message(&key.uid, &msg.msg_id).await {
                 warn!("Failed to revoke message on WeChat: {}", e);
             } else {
@@ -213,7 +424,66 @@ impl MatrixEventHandler {
     }
 
     async fn handle_reaction_event(&self, event: &RoomEvent) -> anyhow::Result<()> {
-        debug!("Handling reaction event: {:?}", event.event_id);
+        let Some(room_id) = &event.room_id else {
+            return Ok(());
+        };
+        let Some(sender) = &event.sender else {
+            return Ok(());
+        };
+        let Some(event_id) = &event.event_id else {
+            return Ok(());
+        };
+
+        let relates_to = event.content.as_ref()
+            .and_then(|c| c.get("m.relates_to"));
+        let rel_type = relates_to
+            .and_then(|r| r.get("rel_type"))
+            .and_then(|v| v.as_str());
+        if rel_type != Some("m.annotation") {
+            return Ok(());
+        }
+
+        let Some(target_event_id) = relates_to
+            .and_then(|r| r.get("event_id"))
+            .and_then(|v| v.as_str()) else {
+            return Ok(());
+        };
+        let key = relates_to
+            .and_then(|r| r.get("key"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("");
+
+        debug!("Handling reaction {} to {} in room {}", key, target_event_id, room_id);
+
+        let Some(portal) = self.get_portal_by_mxid(room_id).await? else {
+            return Ok(());
+        };
+
+        let Some(target) = self.bridge.db.get_message_by_mxid(target_event_id).await? else {
+            debug!("Reaction target {} not found", target_event_id);
+            return Ok(());
+        };
+
+        let client = self.bridge.get_client(sender);
+        let emoji = crate::formatter::emoji::unicode_to_wechat(key);
+        if let Err(e) = client.send_reaction(&portal.key.uid, &target.msg_id, &emoji).await {
+            warn!("Failed to send reaction to WeChat: {}", e);
+            return Ok(());
+        }
+
+        let reaction = crate::database::Reaction {
+            chat_uid: target.chat_uid.clone(),
+            chat_receiver: target.chat_receiver.clone(),
+            target_msg_id: target.msg_id.clone(),
+            sender: sender.clone(),
+            mxid: event_id.clone(),
+            reaction_key: key.to_string(),
+            msg_id: None,
+            timestamp: event.origin_server_ts.unwrap_or(0),
+        };
+        self.bridge.db.upsert_reaction(&reaction).await?;
+
+        info!("Sent reaction {} to WeChat message {}", key, target.msg_id);
         Ok(())
     }
 
@@ -261,26 +531,220 @@ impl MatrixEventHandler {
         };
         let state_key = event.state_key.as_deref();
         
+        let level = self.sender_level(event);
+
         let bot_mxid = self.bridge.config.appservice.bot.mxid(&self.bridge.config.homeserver.domain);
         if state_key == Some(bot_mxid.as_str()) {
+            if !level.can_relay() {
+                debug!("Ignoring invite to {} from user without bridge permission", room_id);
+                return Ok(());
+            }
             info!("Bot invited to room {}, auto-joining", room_id);
             let client = self.bridge.get_matrix_client();
             if let Err(e) = client.join_room(room_id).await {
                 warn!("Failed to join room {}: {}", room_id, e);
             }
+        } else if let Some(puppet_mxid) = state_key
+            && self.is_puppet_mxid(puppet_mxid)
+            && level.can_use()
+        {
+            if let Some(portal) = self.get_group_portal(room_id).await?
+                && !self.invite_to_group(event, &portal, puppet_mxid).await?
+            {
+                return Ok(());
+            }
+            // Lets users gather WeChat contacts in a room before running create-group.
+            info!("Puppet {} invited to room {}, auto-joining", puppet_mxid, room_id);
+            let intent = self.bridge.intent(puppet_mxid).await;
+            if let Err(e) = intent.ensure_joined(room_id).await {
+                warn!("Failed to join room {} as {}: {}", room_id, puppet_mxid, e);
+            }
         }
 
         Ok(())
     }
 
-    async fn handle_join(&self, _event: &RoomEvent) -> anyhow::Result<()> {
+    async fn handle_join(&self, event: &RoomEvent) -> anyhow::Result<()> {
+        let (Some(room_id), Some(user_mxid)) = (&event.room_id, &event.state_key) else {
+            return Ok(());
+        };
+        if !self.bridge.config.bridge.personal_filtering_spaces {
+            return Ok(());
+        }
+        if self.bridge.db.get_portal_by_mxid(room_id).await?.is_none() {
+            return Ok(());
+        }
+        let Some(user) = self.bridge.db.get_user_by_mxid(user_mxid).await? else {
+            return Ok(());
+        };
+        if user.uin.is_none() {
+            return Ok(());
+        }
+
+        let space = self.bridge.ensure_space(user_mxid).await?;
+        self.bridge.add_portal_to_space(&space, room_id).await
+    }
+
+    async fn handle_leave(&self, event: &RoomEvent) -> anyhow::Result<()> {
+        let (Some(room_id), Some(user_mxid), Some(sender)) = (&event.room_id, &event.state_key, &event.sender) else {
+            return Ok(());
+        };
+        if self.is_puppet_mxid(user_mxid) {
+            if sender != user_mxid
+                && let Some(portal) = self.get_group_portal(room_id).await?
+            {
+                self.remove_from_group(event, &portal, user_mxid).await?;
+            }
+            return Ok(());
+        }
+
+        let Some(user) = self.bridge.db.get_user_by_mxid(user_mxid).await? else {
+            return Ok(());
+        };
+        // Deactivated accounts are made to leave all their rooms.
+        if sender == user_mxid {
+            match self.bridge.matrix_user_exists(user_mxid).await {
+                Ok(true) => {}
+                Ok(false) => return self.bridge.clean_up_user(user_mxid).await,
+                Err(e) => debug!("Failed to check whether {} still exists: {}", user_mxid, e),
+            }
+        }
+        let portal = self.bridge.db.get_portal_by_mxid(room_id).await?;
+        if let Some(space) = &user.space_room
+            && portal.is_some()
+        {
+            self.bridge.remove_portal_from_space(space, room_id).await?;
+        }
+
+        // Leaving the room doesn't quit the WeChat group on its own, the user confirms that with a command.
+        if sender == user_mxid
+            && let Some(portal) = portal.filter(|p| p.uid.starts_with("@@"))
+            && user.uin.as_deref() == Some(portal.receiver.as_str())
+        {
+            let mut user = self.get_or_create_user_by_mxid(user_mxid).await?.as_ref().clone();
+            let client = self.bridge.get_matrix_client();
+            let bot_mxid = self.bridge.config.appservice.bot.mxid(&self.bridge.config.homeserver.domain);
+            let management_room = user.get_or_create_management_room(&client, &bot_mxid).await?;
+            let name = if portal.name.is_empty() { &portal.uid } else { &portal.name };
+            client.send_notice(&management_room, format!(
+                "You left the portal of WeChat group {}, but you are still a member on WeChat. \
+                 Send `{} leave-group {}` to quit the group.",
+                name,
+                self.bridge.command_processor().command_prefix(),
+                portal.uid,
+            )).await?;
+        }
         Ok(())
     }
 
-    async fn handle_leave(&self, _event: &RoomEvent) -> anyhow::Result<()> {
+    async fn get_group_portal(&self, room_id: &str) -> anyhow::Result<Option<crate::database::Portal>> {
+        Ok(self.bridge.db.get_portal_by_mxid(room_id).await?.filter(|p| p.uid.starts_with("@@")))
+    }
+
+    /// Adds the contact behind an invited puppet to the portal's WeChat group. Returns whether
+    /// the puppet should join the room.
+    async fn invite_to_group(
+        &self,
+        event: &RoomEvent,
+        portal: &crate::database::Portal,
+        puppet_mxid: &str,
+    ) -> anyhow::Result<bool> {
+        let (Some(room_id), Some(sender)) = (&event.room_id, &event.sender) else {
+            return Ok(false);
+        };
+        let Some(uin) = self.bridge.puppet_uin(puppet_mxid) else {
+            return Ok(false);
+        };
+        let client = self.bridge.get_matrix_client();
+        let user = self.get_or_create_user_by_mxid(sender).await?;
+        if user.uin().is_none() {
+            client.send_notice(room_id, "Please login to WeChat to invite contacts to this group.").await?;
+            return Ok(false);
+        }
+
+        match self.bridge.get_client(sender).invite_group_member(&portal.uid, &[&uin]).await {
+            Ok(()) => {
+                info!("{} invited {} to WeChat group {}", sender, uin, portal.uid);
+                Ok(true)
+            }
+            Err(e) => {
+                client.send_notice(room_id, format!("Failed to invite {} to the WeChat group: {}", uin, e)).await?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Removes the contact behind a kicked puppet from the portal's WeChat group.
+    async fn remove_from_group(
+        &self,
+        event: &RoomEvent,
+        portal: &crate::database::Portal,
+        puppet_mxid: &str,
+    ) -> anyhow::Result<()> {
+        let (Some(room_id), Some(sender)) = (&event.room_id, &event.sender) else {
+            return Ok(());
+        };
+        // The bot kicks puppets itself when they left the group on WeChat.
+        let bot_mxid = self.bridge.config.appservice.bot.mxid(&self.bridge.config.homeserver.domain);
+        if *sender == bot_mxid {
+            return Ok(());
+        }
+        let Some(uin) = self.bridge.puppet_uin(puppet_mxid) else {
+            return Ok(());
+        };
+        let user = self.get_or_create_user_by_mxid(sender).await?;
+        if user.uin().is_none() {
+            return Ok(());
+        }
+
+        match self.bridge.get_client(sender).remove_group_member(&portal.uid, &[&uin]).await {
+            Ok(()) => info!("{} removed {} from WeChat group {}", sender, uin, portal.uid),
+            Err(e) => {
+                let client = self.bridge.get_matrix_client();
+                client.send_notice(room_id, format!("Failed to remove {} from the WeChat group: {}", uin, e)).await?;
+            }
+        }
         Ok(())
     }
 
+    async fn leave_group(&self, sender: &str, group_id: &str) -> anyhow::Result<String> {
+        let user = self.get_or_create_user_by_mxid(sender).await?;
+        if user.uin().is_none() {
+            return Ok("Please login to WeChat first.".to_string());
+        }
+        match self.bridge.get_client(sender).quit_group(group_id).await {
+            Ok(()) => Ok(format!("You quit the WeChat group {}.", group_id)),
+            Err(e) => Ok(format!("Failed to quit {}: {}", group_id, e)),
+        }
+    }
+
+    async fn accept_friend(&self, sender: &str, id: &str) -> anyhow::Result<String> {
+        let user = self.get_or_create_user_by_mxid(sender).await?;
+        if user.uin().is_none() {
+            return Ok("Please login to WeChat first.".to_string());
+        }
+        match self.bridge.get_client(sender).accept_friend(id).await {
+            Ok(()) => Ok("Friend request accepted.".to_string()),
+            Err(e) => Ok(format!("Failed to accept the friend request: {}", e)),
+        }
+    }
+
+    async fn join_group(&self, sender: &str, invite: &str) -> anyhow::Result<String> {
+        let user = self.get_or_create_user_by_mxid(sender).await?;
+        let Some(uin) = user.uin() else {
+            return Ok("Please login to WeChat first.".to_string());
+        };
+        let group_id = match self.bridge.get_client(sender).join_group(invite).await {
+            Ok(group_id) => group_id,
+            Err(e) => return Ok(format!("Failed to join the group: {}", e)),
+        };
+        let key = crate::database::PortalKey::new(group_id.clone(), uin.to_string());
+        match self.bridge.open_group_portal(sender, &key).await {
+            Ok(room_id) => Ok(format!("Joined the WeChat group {}, you were invited to {}.", group_id, room_id)),
+            Err(e) => Ok(format!("Joined the WeChat group {}, but couldn't open its portal: {}", group_id, e)),
+        }
+    }
+
     async fn handle_ban(&self, _event: &RoomEvent) -> anyhow::Result<()> {
         Ok(())
     }
@@ -302,6 +766,28 @@ impl MatrixEventHandler {
         Ok(())
     }
 
+    /// Follows a portal room to the room that replaced it when it was upgraded.
+    async fn handle_tombstone_event(&self, event: &RoomEvent) -> anyhow::Result<()> {
+        let Some(room_id) = &event.room_id else {
+            return Ok(());
+        };
+        let Some(replacement) = event.content.as_ref()
+            .and_then(|c| c.get("replacement_room"))
+            .and_then(|r| r.as_str())
+        else {
+            return Ok(());
+        };
+        if self.get_portal_by_mxid(room_id).await?.is_none() {
+            return Ok(());
+        }
+
+        info!("Room {} was replaced by {}", room_id, replacement);
+        if let Err(e) = self.bridge.continue_portal(room_id, replacement).await {
+            warn!("Failed to move portal {} to {}: {}", room_id, replacement, e);
+        }
+        Ok(())
+    }
+
     async fn handle_room_name_event(&self, event: &RoomEvent) -> anyhow::Result<()> {
         debug!("Room name changed: {:?}", event);
         Ok(())
@@ -350,7 +836,20 @@ impl MatrixEventHandler {
             .unwrap_or("offline");
         
         debug!("Presence event from {}: {}", sender, presence);
-        
+
+        let bridge_config = &self.bridge.config.bridge;
+        if !bridge_config.presence || !bridge_config.default_bridge_presence {
+            return Ok(());
+        }
+        if self.get_user_by_mxid(sender).await?.is_none() {
+            return Ok(());
+        }
+
+        let client = self.bridge.get_client(sender);
+        if let Err(e) = client.set_presence(presence).await {
+            warn!("Failed to bridge presence of {} to WeChat: {}", sender, e);
+        }
+
         Ok(())
     }
 
@@ -360,7 +859,39 @@ impl MatrixEventHandler {
         };
         
         debug!("Receipt event in room {}", room_id);
-        
+
+        let Some(receipts) = event.content.as_ref().and_then(|c| c.as_object()) else {
+            return Ok(());
+        };
+        let Some(portal) = self.get_portal_by_mxid(room_id).await? else {
+            return Ok(());
+        };
+        let bot_mxid = self.bridge.config.appservice.bot.mxid(&self.bridge.config.homeserver.domain);
+
+        for (event_id, receipt) in receipts {
+            let Some(readers) = receipt.get("m.read").and_then(|r| r.as_object()) else {
+                continue;
+            };
+            let Some(message) = self.bridge.db.get_message_by_mxid(event_id).await? else {
+                continue;
+            };
+
+            for reader in readers.keys() {
+                if *reader == bot_mxid || self.is_puppet_mxid(reader) {
+                    continue;
+                }
+                if self.get_user_by_mxid(reader).await?.is_none() {
+                    continue;
+                }
+
+                let client = self.bridge.get_client(reader);
+                match client.mark_read(&portal.key.uid, &message.msg_id).await {
+                    Ok(()) => debug!("Marked {} as read for {}", message.msg_id, reader),
+                    Err(e) => warn!("Failed to mark {} as read on WeChat: {}", message.msg_id, e),
+                }
+            }
+        }
+
         Ok(())
     }
 
@@ -372,12 +903,39 @@ impl MatrixEventHandler {
             return Ok(());
         };
 
+        let level = self.bridge.get_permission(sender);
+        if !level.can_relay() {
+            debug!("Ignoring command from {} without bridge permission", sender);
+            return Ok(());
+        }
+
         let result = self.bridge.command_processor().parse_command(body);
         if let Some((cmd, args)) = result {
-            let outcome = self.bridge.command_processor().process(&cmd, &args);
+            if level.is_admin()
+                && let Some(admin_outcome) = self.bridge.admin_command_processor().process(&cmd, &args)
+            {
+                let reply = self.handle_admin_command(room_id, admin_outcome).await?;
+                self.bridge.get_matrix_client().send_notice(room_id, &reply).await?;
+                return Ok(());
+            }
+
+            let outcome = self.bridge.command_processor().process(&cmd, &args, level);
             
             let client = self.bridge.get_matrix_client();
+            let formatted = match &outcome {
+                crate::bridge::command::CommandResult::Help { body, html } => Some((body.clone(), html.clone())),
+                crate::bridge::command::CommandResult::GroupMembers(query) => {
+                    Some(self.group_members(sender, room_id, query.as_deref()).await?)
+                }
+                crate::bridge::command::CommandResult::GroupInfo => Some(self.group_info(sender, room_id).await?),
+                _ => None,
+            };
+            if let Some((body, html)) = formatted {
+                client.send_notice_html(room_id, body, html).await?;
+                return Ok(());
+            }
             let reply = match outcome {
+                crate::bridge::command::CommandResult::Help { body, .. } => body,
                 crate::bridge::command::CommandResult::Success(msg) => msg,
                 crate::bridge::command::CommandResult::Error(msg) => msg,
                 crate::bridge::command::CommandResult::NeedsLogin => {
@@ -390,18 +948,17 @@ impl MatrixEventHandler {
                     if user.is_logged_in() {
                         "You are already logged in.".to_string()
                     } else {
-                        user.set_client(self.bridge.get_client(sender));
-                        match user.login(self.bridge.wechat_service.clone()).await {
-                            Ok(_) => {
-                                if let Some(room) = user.management_room() {
-                                    let _ = user.get_or_create_management_room(&client, &self.bridge.config.appservice.bot.mxid(&self.bridge.config.homeserver.domain)).await;
-                                }
-                                "Login successful!".to_string()
+                        let bot_mxid = self.bridge.config.appservice.bot.mxid(&self.bridge.config.homeserver.domain);
+                        let qr_room = user.get_or_create_management_room(&client, &bot_mxid).await?;
+                        let wechat = self.bridge.get_client(&user.mxid);
+                        let matrix_client = client.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = user.login_with_qr(wechat, &matrix_client, &qr_room).await {
+                                warn!("QR login for {} failed: {}", user.mxid, e);
+                                let _ = matrix_client.send_notice(&qr_room, format!("Login failed: {}", e)).await;
                             }
-                            Err(e) => {
-                                format!("Login failed: {}", e)
-                            }
-                        }
+                        });
+                        "Starting login, scan the QR code with WeChat when it appears.".to_string()
                     }
                 }
                 crate::bridge::command::CommandResult::Logout => {
@@ -469,19 +1026,36 @@ impl MatrixEventHandler {
                     }
                 }
                 crate::bridge::command::CommandResult::SyncContacts => {
-                    "Syncing contacts...".to_string()
+                    let user = self.get_user_by_mxid(sender).await?;
+                    if user.as_ref().and_then(|u| u.uin()).is_none() {
+                        "Please login to WeChat first.".to_string()
+                    } else {
+                        match self.bridge.sync_contacts(sender).await {
+                            Ok(changes) => changes.summary(),
+                            Err(e) => format!("Failed to sync contacts: {}", e),
+                        }
+                    }
                 }
                 crate::bridge::command::CommandResult::SyncGroups => {
                     "Syncing groups...".to_string()
                 }
                 crate::bridge::command::CommandResult::SyncSpace => {
-                    "Syncing space...".to_string()
+                    let user = self.get_user_by_mxid(sender).await?;
+                    if user.as_ref().and_then(|u| u.uin()).is_none() {
+                        "Please login to WeChat first.".to_string()
+                    } else {
+                        match self.bridge.sync_space(sender).await {
+                            Ok(count) => format!("Added {} portals to your WeChat space.", count),
+                            Err(e) => format!("Failed to sync space: {}", e),
+                        }
+                    }
                 }
                 crate::bridge::command::CommandResult::DeletePortal => {
                     let user = self.get_user_by_mxid(sender).await?;
                     if let Some(_user) = user {
                         if let Some(portal) = self.bridge.get_portal_by_mxid(room_id).await? {
                             let mut portal = Arc::try_unwrap(portal).unwrap_or_else(|p| (*p).clone());
+                            self.bridge.remove_portal_from_spaces(room_id).await?;
                             portal.cleanup(&client).await?;
                             "Portal deleted.".to_string()
                         } else {
@@ -495,6 +1069,9 @@ impl MatrixEventHandler {
                     let portals = self.bridge.db.get_all_portals_with_mxid().await?;
                     let count = portals.len();
                     for portal in portals {
+                        if let Some(portal_room) = &portal.mxid {
+                            self.bridge.remove_portal_from_spaces(portal_room).await?;
+                        }
                         let p = crate::bridge::portal::BridgePortal::from_db(portal, self.bridge.db.clone());
                         let mut p = p;
                         if let Err(e) = p.cleanup(&client).await {
@@ -525,7 +1102,101 @@ impl MatrixEventHandler {
                         }
                     }
                 }
-            };
+                crate::bridge::command::CommandResult::ShowId => {
+                    match self.bridge.db.get_portal_by_mxid(room_id).await? {
+                        Some(portal) => format!(
+                            "WeChat chat ID: {}\nReceiver: {}\nMatrix room ID: {}",
+                            portal.uid, portal.receiver, room_id
+                        ),
+                        None => "This is not a portal room.".to_string(),
+                    }
+                }
+                crate::bridge::command::CommandResult::Open(wxid) => {
+                    match wxid {
+                        Some(wxid) => {
+                            // Only the sender's own portal, never the room of another account.
+                            let user = self.get_or_create_user_by_mxid(sender).await?;
+                            let portal = match user.uin() {
+                                Some(uin) => {
+                                    let key = crate::database::PortalKey::new(wxid.as_str(), uin);
+                                    self.bridge.db.get_portal_by_key(&key).await?
+                                }
+                                None => None,
+                            };
+                            match portal.and_then(|p| p.mxid) {
+                                Some(portal_room) => {
+                                    if let Err(e) = client.invite_user(&portal_room, sender).await {
+                                        debug!("Failed to invite {} to {}: {}", sender, portal_room, e);
+                                    }
+                                    format!("Portal for {}: {}", wxid, portal_room)
+                                }
+                                None => format!("No portal found for {}.", wxid),
+                            }
+                        }
+                        None => {
+                            "Usage: open <wxid>".to_string()
+                        }
+                    }
+                }
+                crate::bridge::command::CommandResult::MessageMarker(enabled) => {
+                    let user = self.get_or_create_user_by_mxid(sender).await?;
+                    let mut setting = self.bridge.db.get_user_setting(&user.mxid).await?
+                        .unwrap_or_else(|| crate::database::UserSetting::new(user.mxid.clone()));
+                    if let Some(enabled) = enabled {
+                        setting.message_marker = Some(enabled);
+                        self.bridge.db.upsert_user_setting(&setting).await?;
+                    }
+                    let active = setting.message_marker
+                        .unwrap_or(self.bridge.config.bridge.message_marker.enabled);
+                    if active {
+                        "Your messages to WeChat are marked as sent from Matrix.".to_string()
+                    } else {
+                        "Your messages to WeChat are not marked.".to_string()
+                    }
+                }
+                crate::bridge::command::CommandResult::CreateGroup(name) => {
+                    self.create_group(sender, room_id, &name).await?
+                }
+                crate::bridge::command::CommandResult::SetRelay => {
+                    self.set_relay(sender, room_id).await?
+                }
+                crate::bridge::command::CommandResult::UnsetRelay => {
+                    self.unset_relay(sender, room_id).await?
+                }
+                crate::bridge::command::CommandResult::StartPrivateChat(query) => {
+                    self.start_private_chat(sender, &query).await?
+                }
+                crate::bridge::command::CommandResult::Bridge(group_id) => {
+                    self.bridge_room(sender, room_id, &group_id).await?
+                }
+                crate::bridge::command::CommandResult::Unbridge => {
+                    self.unbridge_room(sender, room_id).await?
+                }
+                crate::bridge::command::CommandResult::ListConnections => {
+                    self.list_connections().await
+                }
+                crate::bridge::command::CommandResult::LeaveGroup(group_id) => {
+                    self.leave_group(sender, &group_id).await?
+                }
+                crate::bridge::command::CommandResult::AcceptFriend(id) => {
+                    self.accept_friend(sender, &id).await?
+                }
+                crate::bridge::command::CommandResult::JoinGroup(invite) => {
+                    self.join_group(sender, &invite).await?
+                }
+                crate::bridge::command::CommandResult::ListPortals => {
+                    self.list_portals(sender).await?
+                }
+                crate::bridge::command::CommandResult::SetPortalSetting(change) => {
+                    self.set_portal_setting(sender, room_id, change).await?
+                }
+                crate::bridge::command::CommandResult::ShowPortalSettings => {
+                    self.show_portal_settings(sender, room_id).await?
+                }
+                crate::bridge::command::CommandResult::GroupMembers(_) | crate::bridge::command::CommandResult::GroupInfo => {
+                    unreachable!("replied to with HTML above")
+                }
+            };
 
             client.send_notice(room_id, &reply).await?;
         }
@@ -533,6 +1204,571 @@ impl MatrixEventHandler {
         Ok(())
     }
 
+    async fn handle_admin_command(
+        &self,
+        room_id: &str,
+        outcome: crate::bridge::admin_command::AdminCommandResult,
+    ) -> anyhow::Result<String> {
+        use crate::bridge::admin_command::AdminCommandResult;
+
+        if let Some(admin_room) = &self.bridge.config.bridge.admin_room
+            && admin_room != room_id
+        {
+            return Ok("Admin commands can only be used in the admin room.".to_string());
+        }
+
+        Ok(match outcome {
+            AdminCommandResult::Success(msg) | AdminCommandResult::Error(msg) => msg,
+            AdminCommandResult::ListUsers => self.admin_list_users().await?,
+            AdminCommandResult::ListPortals => self.admin_list_portals().await?,
+            AdminCommandResult::BridgeStatus => self.admin_bridge_status().await?,
+            AdminCommandResult::Disconnect(mxid) => self.admin_disconnect(&mxid).await?,
+            AdminCommandResult::DeletePortal(uid) => self.admin_delete_portal(&uid).await?,
+            AdminCommandResult::CleanUsers => {
+                let cleaned = self.bridge.clean_gone_users().await?;
+                if cleaned.is_empty() {
+                    "No users with deactivated Matrix accounts found.".to_string()
+                } else {
+                    format!("Cleaned up {} deactivated users: {}", cleaned.len(), cleaned.join(", "))
+                }
+            }
+            AdminCommandResult::SetPermission(mxid, level) => {
+                self.bridge.set_permission_override(&mxid, level).await?;
+                match level {
+                    Some(level) => format!("Set the permission level of {} to {}.", mxid, level.as_str()),
+                    None => format!(
+                        "{} now has the permission level from the config ({}).",
+                        mxid,
+                        self.bridge.get_permission(&mxid).as_str()
+                    ),
+                }
+            }
+            AdminCommandResult::CreateAgentToken(description) => self.admin_create_agent_token(&description).await?,
+            AdminCommandResult::ListAgentTokens => self.admin_list_agent_tokens().await?,
+            AdminCommandResult::RotateAgentToken(id) => self.admin_rotate_agent_token(&id).await?,
+            AdminCommandResult::ContinuePortal(old, new) => {
+                self.bridge.continue_portal(&old, &new).await?;
+                format!("Moved the portal of {} to {}.", old, new)
+            }
+            AdminCommandResult::RevokeAgentToken(id) => {
+                if !self.bridge.db.revoke_agent_token(&id).await? {
+                    return Ok(format!("No active agent token {}.", id));
+                }
+                let disconnected = self.bridge.wechat_service.disconnect_agent(&id).await;
+                format!("Revoked agent token {} and disconnected {} agents.", id, disconnected)
+            }
+        })
+    }
+
+    async fn admin_create_agent_token(&self, description: &str) -> anyhow::Result<String> {
+        let (row, token) = crate::wechat::generate_agent_token(description);
+        self.bridge.db.insert_agent_token(&row).await?;
+        Ok(format!(
+            "Created agent token {}. Configure the agent with the token below, it won't be shown again:\n{}",
+            row.id, token
+        ))
+    }
+
+    async fn admin_list_agent_tokens(&self) -> anyhow::Result<String> {
+        let tokens = self.bridge.db.get_agent_tokens().await?;
+        let tokens: Vec<_> = tokens.into_iter().filter(|t| !t.revoked).collect();
+        if tokens.is_empty() {
+            return Ok("There are no agent tokens.".to_string());
+        }
+
+        let format_time = |ts: i64| {
+            chrono::DateTime::from_timestamp(ts, 0)
+                .map(|t| t.format("%Y-%m-%d %H:%M UTC").to_string())
+                .unwrap_or_default()
+        };
+        let mut reply = format!("{} agent tokens:\n", tokens.len());
+        for token in tokens {
+            reply.push_str(&format!(
+                "- {}{}: created {}, last used {}\n",
+                token.id,
+                if token.description.is_empty() { String::new() } else { format!(" ({})", token.description) },
+                format_time(token.created_at),
+                if token.last_used == 0 { "never".to_string() } else { format_time(token.last_used) },
+            ));
+        }
+        Ok(reply)
+    }
+
+    /// Issues a new token with the description of the old one, then revokes the old one so its
+    /// agents have to reconnect with the new one.
+    async fn admin_rotate_agent_token(&self, id: &str) -> anyhow::Result<String> {
+        let Some(old) = self.bridge.db.get_agent_token(id).await?.filter(|t| !t.revoked) else {
+            return Ok(format!("No active agent token {}.", id));
+        };
+        let (row, token) = crate::wechat::generate_agent_token(&old.description);
+        self.bridge.db.insert_agent_token(&row).await?;
+        self.bridge.db.revoke_agent_token(id).await?;
+        let disconnected = self.bridge.wechat_service.disconnect_agent(id).await;
+        Ok(format!(
+            "Replaced agent token {} by {} and disconnected {} agents. Configure them with the token below, it won't be shown again:\n{}",
+            id, row.id, disconnected, token
+        ))
+    }
+
+    async fn admin_list_users(&self) -> anyhow::Result<String> {
+        let users = self.bridge.db.get_all_logged_in_users().await?;
+        if users.is_empty() {
+            return Ok("No users are logged in to WeChat.".to_string());
+        }
+
+        let connections = self.bridge.wechat_service.list_connections().await;
+        let mut reply = format!("{} users logged in to WeChat:\n", users.len());
+        for user in users {
+            let agent = connections.iter()
+                .find(|c| c.users.contains(&user.mxid))
+                .map(|c| c.addr.as_str())
+                .unwrap_or("no agent");
+            reply.push_str(&format!(
+                "- {} as {} ({})\n",
+                user.mxid,
+                user.uin.as_deref().unwrap_or_default(),
+                agent
+            ));
+        }
+        Ok(reply)
+    }
+
+    async fn admin_list_portals(&self) -> anyhow::Result<String> {
+        const MAX_LISTED_PORTALS: usize = 50;
+
+        let portals = self.bridge.db.get_all_portals_with_mxid().await?;
+        if portals.is_empty() {
+            return Ok("There are no portals.".to_string());
+        }
+
+        let mut reply = format!("{} portals:\n", portals.len());
+        for portal in portals.iter().take(MAX_LISTED_PORTALS) {
+            reply.push_str(&format!(
+                "- {} ({}) for {}: {}\n",
+                portal.name,
+                portal.uid,
+                portal.receiver,
+                portal.mxid.as_deref().unwrap_or_default()
+            ));
+        }
+        if portals.len() > MAX_LISTED_PORTALS {
+            reply.push_str(&format!("... and {} more\n", portals.len() - MAX_LISTED_PORTALS));
+        }
+        Ok(reply)
+    }
+
+    async fn admin_bridge_status(&self) -> anyhow::Result<String> {
+        let users = self.bridge.db.get_all_logged_in_users().await?;
+        let portals = self.bridge.db.get_all_portals_with_mxid().await?;
+        let connections = self.bridge.wechat_service.list_connections().await;
+        let retries = self.bridge.db.get_all_outgoing_retries().await?;
+        Ok(format!(
+            "Matrix-WeChat bridge v{}\n- Logged in users: {}\n- Portals: {}\n- Connected agents: {}\n- Messages waiting for retry: {}",
+            env!("CARGO_PKG_VERSION"),
+            users.len(),
+            portals.len(),
+            connections.len(),
+            retries.len(),
+        ))
+    }
+
+    async fn admin_disconnect(&self, mxid: &str) -> anyhow::Result<String> {
+        if self.bridge.db.get_user_by_mxid(mxid).await?.and_then(|u| u.uin).is_none() {
+            return Ok(format!("{} is not logged in to WeChat.", mxid));
+        }
+
+        let user = self.bridge.get_user_by_mxid(mxid).await?;
+        let mut user = user.as_ref().clone();
+        user.logout().await?;
+        self.bridge.invalidate_user(mxid).await;
+        let client = self.bridge.get_matrix_client();
+        if let Err(e) = user.send_management_notice(&client, "A bridge admin logged you out of WeChat.").await {
+            debug!("Failed to notify {} of the disconnect: {}", mxid, e);
+        }
+        Ok(format!("Logged {} out of WeChat.", mxid))
+    }
+
+    async fn admin_delete_portal(&self, uid: &str) -> anyhow::Result<String> {
+        let portals = self.bridge.db.get_portals_by_uid(uid).await?;
+        if portals.is_empty() {
+            return Ok(format!("No portals found for {}.", uid));
+        }
+
+        let client = self.bridge.get_matrix_client();
+        let count = portals.len();
+        for portal in portals {
+            if let Some(portal_room) = &portal.mxid {
+                self.bridge.remove_portal_from_spaces(portal_room).await?;
+            }
+            let mut portal = crate::bridge::portal::BridgePortal::from_db(portal, self.bridge.db.clone());
+            if let Err(e) = portal.cleanup(&client).await {
+                warn!("Failed to cleanup portal: {}", e);
+            }
+        }
+        Ok(format!("Deleted {} portals of {}.", count, uid))
+    }
+
+    async fn list_connections(&self) -> String {
+        let connections = self.bridge.wechat_service.list_connections().await;
+        if connections.is_empty() {
+            return "No WeChat agents are connected.".to_string();
+        }
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut reply = format!("{} connected WeChat agents:\n", connections.len());
+        for conn in connections {
+            let users = if conn.users.is_empty() {
+                "all users".to_string()
+            } else {
+                conn.users.join(", ")
+            };
+            reply.push_str(&format!(
+                "- {}{}: {} (protocol v{}, last seen {}s ago)\n",
+                conn.addr,
+                conn.agent.map(|id| format!(" with token {}", id)).unwrap_or_default(),
+                users,
+                conn.protocol_version,
+                (now - conn.last_seen).max(0) / 1000
+            ));
+        }
+        reply
+    }
+
+    /// Whether the sender may change which WeChat chat a room is bridged to.
+    async fn can_manage_bridge(&self, sender: &str, room_id: &str) -> anyhow::Result<bool> {
+        if self.bridge.get_permission(sender).is_admin() {
+            return Ok(true);
+        }
+        let client = self.bridge.get_matrix_client();
+        let levels = self.bridge.room_state.power_levels(&client, room_id).await?;
+        let level = levels.users.get(sender).copied().unwrap_or(levels.users_default);
+        Ok(level >= levels.state_default)
+    }
+
+    async fn bridge_room(&self, sender: &str, room_id: &str, group_id: &str) -> anyhow::Result<String> {
+        if self.bridge.db.get_portal_by_mxid(room_id).await?.is_some() {
+            return Ok("This room is already bridged, use `unbridge` first.".to_string());
+        }
+        let user = self.get_or_create_user_by_mxid(sender).await?;
+        let Some(uin) = user.uin() else {
+            return Ok("Please login to WeChat first.".to_string());
+        };
+        if !self.can_manage_bridge(sender, room_id).await? {
+            return Ok("You don't have permission to change the bridge of this room.".to_string());
+        }
+        let existing = self.bridge.db.get_portals_by_uid(group_id).await?;
+        if let Some(other_room) = existing.into_iter().find_map(|p| p.mxid) {
+            return Ok(format!("{} is already bridged to {}.", group_id, other_room));
+        }
+
+        match self.bridge.bridge_group_to_room(sender, uin, group_id, room_id).await {
+            Ok(info) => Ok(format!("Bridged this room to WeChat group {} ({}).", info.name, group_id)),
+            Err(e) => Ok(format!("Failed to bridge {}: {}", group_id, e)),
+        }
+    }
+
+    async fn unbridge_room(&self, sender: &str, room_id: &str) -> anyhow::Result<String> {
+        if self.bridge.db.get_portal_by_mxid(room_id).await?.is_none() {
+            return Ok("This room is not bridged.".to_string());
+        }
+        if !self.can_manage_bridge(sender, room_id).await? {
+            return Ok("You don't have permission to change the bridge of this room.".to_string());
+        }
+
+        match self.bridge.unbridge_room(room_id).await {
+            Ok(()) => Ok("This room is no longer bridged to WeChat.".to_string()),
+            Err(e) => Ok(format!("Failed to unbridge room: {}", e)),
+        }
+    }
+
+    async fn start_private_chat(&self, sender: &str, query: &str) -> anyhow::Result<String> {
+        const MAX_LISTED_MATCHES: usize = 10;
+
+        let user = self.get_or_create_user_by_mxid(sender).await?;
+        if user.uin().is_none() {
+            return Ok("Please login to WeChat first.".to_string());
+        }
+
+        let friends = match self.bridge.get_client(sender).get_friend_list().await {
+            Ok(friends) => friends,
+            Err(e) => return Ok(format!("Failed to get contacts: {}", e)),
+        };
+        let mut matches: Vec<_> = friends.iter().filter(|f| f.matches(query)).collect();
+        let exact: Vec<_> = matches.iter().copied().filter(|f| f.matches_exactly(query)).collect();
+        if exact.len() == 1 {
+            matches = exact;
+        }
+
+        match matches.as_slice() {
+            [] => Ok(format!("No contacts found matching \"{}\".", query)),
+            [contact] => {
+                let (room_id, just_created) = self.bridge.start_private_chat(sender, &contact.id).await?;
+                let name = contact.remark.as_deref().filter(|r| !r.is_empty()).unwrap_or(&contact.name);
+                if just_created {
+                    Ok(format!("Created portal with {} ({}) and invited you: {}", name, contact.id, room_id))
+                } else {
+                    Ok(format!("You already have a portal with {} ({}): {}", name, contact.id, room_id))
+                }
+            }
+            _ => {
+                let mut lines = vec![format!("Found {} contacts matching \"{}\":", matches.len(), query)];
+                for contact in matches.iter().take(MAX_LISTED_MATCHES) {
+                    match contact.remark.as_deref().filter(|r| !r.is_empty()) {
+                        Some(remark) => lines.push(format!("- {} ({}): {}", contact.name, remark, contact.id)),
+                        None => lines.push(format!("- {}: {}", contact.name, contact.id)),
+                    }
+                }
+                if matches.len() > MAX_LISTED_MATCHES {
+                    lines.push(format!("... and {} more", matches.len() - MAX_LISTED_MATCHES));
+                }
+                lines.push("Use `pm <wxid>` to start a chat with one of them.".to_string());
+                Ok(lines.join("\n"))
+            }
+        }
+    }
+
+    async fn set_relay(&self, sender: &str, room_id: &str) -> anyhow::Result<String> {
+        let relay_config = &self.bridge.config.bridge.relay;
+        if !relay_config.enabled {
+            return Ok("Relay mode is not enabled on this bridge.".to_string());
+        }
+        if relay_config.admin_only && !self.bridge.get_permission(sender).is_admin() {
+            return Ok("Only bridge admins are allowed to enable relay mode on this instance of the bridge.".to_string());
+        }
+        let Some(portal) = self.bridge.db.get_portal_by_mxid(room_id).await? else {
+            return Ok("This command can only be used in a portal room.".to_string());
+        };
+        let user = self.get_or_create_user_by_mxid(sender).await?;
+        let Some(uin) = user.uin() else {
+            return Ok("Please login to WeChat first.".to_string());
+        };
+        // Relaying through another account's portal would send as that account.
+        if uin != portal.receiver {
+            return Ok("You can only relay through your own WeChat account's portal.".to_string());
+        }
+
+        let key = crate::database::PortalKey::new(portal.uid, portal.receiver);
+        self.bridge.db.upsert_portal_relay(&crate::database::PortalRelay::new(&key, sender)).await?;
+        info!("Set relay user of {} to {}", room_id, sender);
+        Ok("Messages from non-logged-in users in this room will now be bridged through your WeChat account.".to_string())
+    }
+
+    async fn unset_relay(&self, sender: &str, room_id: &str) -> anyhow::Result<String> {
+        let Some(portal) = self.bridge.db.get_portal_by_mxid(room_id).await? else {
+            return Ok("This command can only be used in a portal room.".to_string());
+        };
+        let key = crate::database::PortalKey::new(portal.uid, portal.receiver);
+        let Some(relay) = self.bridge.db.get_portal_relay(&key).await? else {
+            return Ok("This room does not have a relay user set.".to_string());
+        };
+        if relay.relay_mxid != sender && !self.bridge.get_permission(sender).is_admin() {
+            return Ok("Only the relay user or a bridge admin can stop relaying in this room.".to_string());
+        }
+
+        self.bridge.db.delete_portal_relay(&key).await?;
+        info!("Removed relay user {} from {}", relay.relay_mxid, room_id);
+        Ok("Messages from non-logged-in users will no longer be bridged in this room.".to_string())
+    }
+
+    /// Lists the portal rooms the user has joined.
+    async fn list_portals(&self, sender: &str) -> anyhow::Result<String> {
+        const MAX_LISTED_PORTALS: usize = 50;
+
+        let client = self.bridge.get_matrix_client();
+        let mut joined = Vec::new();
+        for portal in self.bridge.db.get_all_portals_with_mxid().await? {
+            let Some(room_id) = portal.mxid.as_deref() else {
+                continue;
+            };
+            match self.bridge.room_state.joined_members(&client, room_id).await {
+                Ok(members) if members.joined.contains_key(sender) => joined.push(portal),
+                Ok(_) => {}
+                Err(e) => debug!("Failed to get members of {}: {}", room_id, e),
+            }
+        }
+        if joined.is_empty() {
+            return Ok("You aren't in any portal rooms.".to_string());
+        }
+
+        let mut reply = format!("You are in {} portal rooms:\n", joined.len());
+        for portal in joined.iter().take(MAX_LISTED_PORTALS) {
+            reply.push_str(&format!(
+                "- {} ({}): {}\n",
+                portal.name,
+                portal.uid,
+                portal.mxid.as_deref().unwrap_or_default()
+            ));
+        }
+        if joined.len() > MAX_LISTED_PORTALS {
+            reply.push_str(&format!("... and {} more\n", joined.len() - MAX_LISTED_PORTALS));
+        }
+        Ok(reply)
+    }
+
+    async fn set_portal_setting(
+        &self,
+        sender: &str,
+        room_id: &str,
+        change: crate::bridge::command::PortalSettingChange,
+    ) -> anyhow::Result<String> {
+        use crate::bridge::command::PortalSettingChange;
+
+        let Some(portal) = self.bridge.db.get_portal_by_mxid(room_id).await? else {
+            return Ok("This command can only be used in a portal room.".to_string());
+        };
+        let key = portal.key();
+        let mut setting = self.bridge.db.get_portal_setting(&key, sender).await?
+            .unwrap_or_else(|| crate::database::PortalSetting::new(&key, sender));
+        change.apply(&mut setting);
+        self.bridge.db.upsert_portal_setting(&setting).await?;
+
+        Ok(match change {
+            PortalSettingChange::Muted(true) => "Messages from WeChat will no longer be bridged to this room.".to_string(),
+            PortalSettingChange::Muted(false) => "Messages from WeChat will be bridged to this room again.".to_string(),
+            PortalSettingChange::PlaintextOnly(true) => "Your messages in this room will be sent to WeChat without formatting.".to_string(),
+            PortalSettingChange::PlaintextOnly(false) => "Formatting in your messages will be converted for WeChat.".to_string(),
+            PortalSettingChange::Relay(true) => "Your messages may be relayed in this room when you aren't logged in.".to_string(),
+            PortalSettingChange::Relay(false) => "Your messages won't be relayed in this room.".to_string(),
+            PortalSettingChange::RoomName(name) => {
+                let name = name.unwrap_or(portal.name);
+                self.bridge.get_matrix_client().set_room_name(room_id, &name).await?;
+                format!("The room is now named {}.", name)
+            }
+        })
+    }
+
+    async fn show_portal_settings(&self, sender: &str, room_id: &str) -> anyhow::Result<String> {
+        let Some(portal) = self.bridge.db.get_portal_by_mxid(room_id).await? else {
+            return Ok("This command can only be used in a portal room.".to_string());
+        };
+        let key = portal.key();
+        let setting = self.bridge.db.get_portal_setting(&key, sender).await?
+            .unwrap_or_else(|| crate::database::PortalSetting::new(&key, sender));
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        Ok(format!(
+            "Your settings for this portal:\n- mute: {}\n- plaintext: {}\n- relay: {}\n- name: {}",
+            on_off(setting.muted),
+            on_off(setting.plaintext_only),
+            on_off(setting.relay),
+            setting.room_name.as_deref().unwrap_or("(the WeChat chat name)")
+        ))
+    }
+
+    /// The group of a portal room, when the sender is logged in and the room is a group portal.
+    async fn command_group(&self, sender: &str, room_id: &str) -> anyhow::Result<Result<String, String>> {
+        let Some(portal) = self.bridge.db.get_portal_by_mxid(room_id).await?.filter(|p| p.uid.starts_with("@@")) else {
+            return Ok(Err("This command can only be used in a WeChat group portal.".to_string()));
+        };
+        if self.get_or_create_user_by_mxid(sender).await?.uin().is_none() {
+            return Ok(Err("Please login to WeChat first.".to_string()));
+        }
+        Ok(Ok(portal.uid))
+    }
+
+    async fn group_members(&self, sender: &str, room_id: &str, query: Option<&str>) -> anyhow::Result<(String, String)> {
+        use crate::bridge::group_info::{format_members, search_members};
+
+        let group_id = match self.command_group(sender, room_id).await? {
+            Ok(group_id) => group_id,
+            Err(msg) => return Ok((msg.clone(), escape_html(&msg))),
+        };
+        match self.bridge.get_client(sender).get_group_members(&group_id).await {
+            Ok(members) => Ok(format_members(&search_members(&members, query), members.len(), query)),
+            Err(e) => {
+                let msg = format!("Failed to get the members of {}: {}", group_id, e);
+                Ok((msg.clone(), escape_html(&msg)))
+            }
+        }
+    }
+
+    async fn group_info(&self, sender: &str, room_id: &str) -> anyhow::Result<(String, String)> {
+        let group_id = match self.command_group(sender, room_id).await? {
+            Ok(group_id) => group_id,
+            Err(msg) => return Ok((msg.clone(), escape_html(&msg))),
+        };
+        let client = self.bridge.get_client(sender);
+        let info = match client.get_group_info(&group_id).await {
+            Ok(info) => info,
+            Err(e) => {
+                let msg = format!("Failed to get the info of {}: {}", group_id, e);
+                return Ok((msg.clone(), escape_html(&msg)));
+            }
+        };
+        let members = match client.get_group_members(&group_id).await {
+            Ok(members) => members,
+            Err(e) => {
+                debug!("Failed to get the members of {}: {}", group_id, e);
+                Vec::new()
+            }
+        };
+        let member_count = if members.is_empty() { info.members.len() } else { members.len() };
+        let owner = info.owner.as_deref().map(|owner| {
+            members.iter()
+                .find(|m| m.id == owner)
+                .map_or_else(|| owner.to_string(), |m| format!("{} ({})", m.name, owner))
+        });
+        let encrypted = self.bridge.room_state
+            .is_encrypted(&self.bridge.get_matrix_client(), room_id).await
+            .unwrap_or(false);
+        Ok(crate::bridge::group_info::format_group_info(&info, member_count, owner.as_deref(), encrypted))
+    }
+
+    async fn create_group(&self, sender: &str, room_id: &str, name: &str) -> anyhow::Result<String> {
+        if self.bridge.db.get_portal_by_mxid(room_id).await?.is_some() {
+            return Ok("This room is already a portal.".to_string());
+        }
+        let Some(user) = self.get_user_by_mxid(sender).await? else {
+            return Ok("Please login to WeChat first.".to_string());
+        };
+        let Some(uin) = user.uin() else {
+            return Ok("Please login to WeChat first.".to_string());
+        };
+
+        let client = self.bridge.get_matrix_client();
+        let members = self.bridge.room_state.joined_members(&client, room_id).await?;
+        let contacts: Vec<String> = members.joined.keys()
+            .filter_map(|mxid| self.bridge.puppet_uin(mxid))
+            .filter(|contact| contact != uin)
+            .collect();
+        if contacts.is_empty() {
+            return Ok("Invite the WeChat contacts for the new group to this room first.".to_string());
+        }
+
+        let wechat_client = self.bridge.get_client(sender);
+        let contact_ids: Vec<&str> = contacts.iter().map(|c| c.as_str()).collect();
+        let group_id = match wechat_client.create_group(&contact_ids, name).await {
+            Ok(group_id) => group_id,
+            Err(e) => return Ok(format!("Failed to create group: {}", e)),
+        };
+        if let Err(e) = wechat_client.set_group_name(&group_id, name).await {
+            warn!("Failed to set name of new group {}: {}", group_id, e);
+        }
+
+        let portal = crate::database::Portal {
+            uid: group_id.clone(),
+            receiver: uin.to_string(),
+            mxid: Some(room_id.to_string()),
+            name: name.to_string(),
+            name_set: true,
+            topic: String::new(),
+            topic_set: false,
+            avatar: String::new(),
+            avatar_url: None,
+            avatar_set: false,
+            encrypted: false,
+            last_sync: 0,
+            first_event_id: None,
+            next_batch_id: None,
+        };
+        self.bridge.db.insert_portal(&portal).await?;
+
+        info!("Created WeChat group {} for room {}", group_id, room_id);
+        Ok(format!(
+            "Created WeChat group {} ({}) with {} contacts and bridged it to this room.",
+            name, group_id, contacts.len()
+        ))
+    }
+
     async fn handle_text_message(
         &self,
         user: &crate::bridge::user::BridgeUser,
@@ -542,20 +1778,64 @@ impl MatrixEventHandler {
         msgtype: &str,
     ) -> anyhow::Result<()> {
         let Some(client) = user.get_client() else {
-            warn!("User has no WeChat client");
-            return Ok(());
+            anyhow::bail!("not logged in to WeChat");
+        };
+
+        let content = event.content.as_ref();
+        let original_body = content.and_then(|c| c.get("body")).and_then(|v| v.as_str());
+        let (fallback_quote, own_body) = match original_body {
+            Some(original) => {
+                let (quote, rest) = crate::formatter::matrix_to_wechat::strip_reply_fallback(original);
+                (quote, Some(rest))
+            }
+            None => (None, None),
+        };
+        let html = content
+            .filter(|c| c.get("format").and_then(|v| v.as_str()) == Some("org.matrix.custom.html"))
+            .and_then(|c| c.get("formatted_body"))
+            .and_then(|v| v.as_str());
+        let pills = html.map(crate::formatter::matrix_to_wechat::find_pills).unwrap_or_default();
+        let plaintext_only = match &event.sender {
+            Some(sender) => self.bridge.db.get_portal_setting(&portal.key, sender).await?
+                .is_some_and(|s| s.plaintext_only),
+            None => false,
         };
 
+        // Relayed messages have their body rewritten, so only use the formatted body of the user's own.
+        let body = match html {
+            Some(html) if own_body == Some(body) && !plaintext_only => {
+                crate::formatter::matrix_to_wechat(html)
+            }
+            _ => body.to_string(),
+        };
         let text = if msgtype == "m.emote" {
             format!("/me {}", body)
         } else {
-            body.to_string()
+            body
         };
+        let text = self.apply_message_marker(&user.mxid, &text).await?;
+
+        let mut mentions = Vec::new();
+        for pill in &pills {
+            if let Some(wxid) = self.mention_wxid(&pill.mxid).await?
+                && !mentions.contains(&wxid)
+            {
+                mentions.push(wxid);
+            }
+        }
 
         let reply_to = self.get_reply_target(event).await?;
+        let quote = match (&reply_to, fallback_quote) {
+            (None, _) => None,
+            (Some(_), Some(quote)) => Some(quote),
+            (Some(_), None) => self.fetch_reply_quote(event).await,
+        }
+        .map(|q| crate::formatter::matrix_to_wechat::quote_snippet(&q, REPLY_QUOTE_MAX_CHARS));
 
-        if let Err(e) = client.send_text_message(&portal.key.uid, &text, reply_to.as_deref()).await {
-            warn!("Failed to send text message to WeChat: {}", e);
+        let msg_id = client.send_text_with_mentions(&portal.key.uid, &text, reply_to.as_deref(), quote.as_deref(), &mentions).await
+            .context("failed to send the message to WeChat")?;
+        if let Some(event_id) = &event.event_id {
+            self.bridge.db.complete_message(&portal.key, event_id, &msg_id, event_id, None).await?;
         }
 
         Ok(())
@@ -568,8 +1848,7 @@ impl MatrixEventHandler {
         event: &RoomEvent,
     ) -> anyhow::Result<()> {
         let Some(client) = user.get_client() else {
-            warn!("User has no WeChat client");
-            return Ok(());
+            anyhow::bail!("not logged in to WeChat");
         };
 
         let content = event.content.as_ref();
@@ -578,18 +1857,18 @@ impl MatrixEventHandler {
             .and_then(|v| v.as_str());
         
         let Some(url) = url else {
-            warn!("Image message without URL");
-            return Ok(());
+            anyhow::bail!("message has no media URL");
         };
 
         debug!("Downloading image from {}", url);
         
-        let matrix_client = self.bridge.get_matrix_client();
-        let image_data = match matrix_client.download_media(url).await {
-            Ok(data) => data,
+        let image_data = self.download_outgoing_media(event, url).await?;
+        let max_size = self.bridge.config.bridge.media.image_resolution_limit();
+        let image_data = match crate::util::media::image::image_to_wechat(&image_data, max_size).await {
+            Ok(converted) => converted.data,
             Err(e) => {
-                warn!("Failed to download image: {}", e);
-                return Ok(());
+                warn!("Failed to convert image for WeChat, sending as is: {}", e);
+                image_data
             }
         };
 
@@ -599,30 +1878,32 @@ impl MatrixEventHandler {
             Ok(msg_id) => {
                 info!("Sent image message to WeChat: {}", msg_id);
                 if let Some(event_id) = &event.event_id {
-                    if let Some(room_id) = &event.room_id {
-                        let msg = crate::database::Message {
-                            chat_uid: portal.key.uid.clone(),
-                            chat_receiver: portal.key.receiver.clone(),
-                            msg_id,
-                            mxid: event_id.clone(),
-                            sender: event.sender.clone().unwrap_or_default(),
-                            timestamp: event.origin_server_ts.unwrap_or(0),
-                            sent: true,
-                            error: None,
-                            msg_type: "m.image".to_string(),
-                        };
-                        self.bridge.db.insert_message(&msg).await?;
-                    }
+                    self.bridge.db.complete_message(&portal.key, event_id, &msg_id, event_id, None).await?;
                 }
             }
-            Err(e) => {
-                warn!("Failed to send image message to WeChat: {}", e);
-            }
+            Err(e) => return Err(e.context("failed to send the image to WeChat")),
         }
 
         Ok(())
     }
 
+    /// Downloads the media of a Matrix message, refusing files over
+    /// `bridge.media.max_outgoing_bytes`, before downloading them when their size is given.
+    async fn download_outgoing_media(&self, event: &RoomEvent, url: &str) -> anyhow::Result<Vec<u8>> {
+        let limit = self.bridge.config.bridge.media.outgoing_limit();
+        let declared = event.content.as_ref()
+            .and_then(|c| c.get("info"))
+            .and_then(|info| info.get("size"))
+            .and_then(|v| v.as_u64());
+        if let Some(size) = declared {
+            check_size(size, limit)?;
+        }
+        let data = self.bridge.get_matrix_client().download_media(url).await
+            .context("failed to download the media from Matrix")?;
+        check_size(data.len() as u64, limit)?;
+        Ok(data)
+    }
+
     async fn handle_video_message(
         &self,
         user: &crate::bridge::user::BridgeUser,
@@ -630,8 +1911,7 @@ impl MatrixEventHandler {
         event: &RoomEvent,
     ) -> anyhow::Result<()> {
         let Some(client) = user.get_client() else {
-            warn!("User has no WeChat client");
-            return Ok(());
+            anyhow::bail!("not logged in to WeChat");
         };
 
         let content = event.content.as_ref();
@@ -640,20 +1920,12 @@ impl MatrixEventHandler {
             .and_then(|v| v.as_str());
         
         let Some(url) = url else {
-            warn!("Video message without URL");
-            return Ok(());
+            anyhow::bail!("message has no media URL");
         };
 
         debug!("Downloading video from {}", url);
         
-        let matrix_client = self.bridge.get_matrix_client();
-        let video_data = match matrix_client.download_media(url).await {
-            Ok(data) => data,
-            Err(e) => {
-                warn!("Failed to download video: {}", e);
-                return Ok(());
-            }
-        };
+        let video_data = self.download_outgoing_media(event, url).await?;
 
         let reply_to = self.get_reply_target(event).await?;
         
@@ -661,25 +1933,10 @@ impl MatrixEventHandler {
             Ok(msg_id) => {
                 info!("Sent video message to WeChat: {}", msg_id);
                 if let Some(event_id) = &event.event_id {
-                    if let Some(room_id) = &event.room_id {
-                        let msg = crate::database::Message {
-                            chat_uid: portal.key.uid.clone(),
-                            chat_receiver: portal.key.receiver.clone(),
-                            msg_id,
-                            mxid: event_id.clone(),
-                            sender: event.sender.clone().unwrap_or_default(),
-                            timestamp: event.origin_server_ts.unwrap_or(0),
-                            sent: true,
-                            error: None,
-                            msg_type: "m.video".to_string(),
-                        };
-                        self.bridge.db.insert_message(&msg).await?;
-                    }
+                    self.bridge.db.complete_message(&portal.key, event_id, &msg_id, event_id, None).await?;
                 }
             }
-            Err(e) => {
-                warn!("Failed to send video message to WeChat: {}", e);
-            }
+            Err(e) => return Err(e.context("failed to send the video to WeChat")),
         }
 
         Ok(())
@@ -692,8 +1949,7 @@ impl MatrixEventHandler {
         event: &RoomEvent,
     ) -> anyhow::Result<()> {
         let Some(client) = user.get_client() else {
-            warn!("User has no WeChat client");
-            return Ok(());
+            anyhow::bail!("not logged in to WeChat");
         };
 
         let content = event.content.as_ref();
@@ -702,20 +1958,12 @@ impl MatrixEventHandler {
             .and_then(|v| v.as_str());
         
         let Some(url) = url else {
-            warn!("Audio message without URL");
-            return Ok(());
+            anyhow::bail!("message has no media URL");
         };
 
         debug!("Downloading audio from {}", url);
         
-        let matrix_client = self.bridge.get_matrix_client();
-        let audio_data = match matrix_client.download_media(url).await {
-            Ok(data) => data,
-            Err(e) => {
-                warn!("Failed to download audio: {}", e);
-                return Ok(());
-            }
-        };
+        let audio_data = self.download_outgoing_media(event, url).await?;
 
         let reply_to = self.get_reply_target(event).await?;
         
@@ -723,30 +1971,34 @@ impl MatrixEventHandler {
             .and_then(|c| c.get("body"))
             .and_then(|v| v.as_str())
             .unwrap_or("audio");
+
+        // Only voice messages become WeChat voice notes; other audio is sent as a file.
+        let is_voice = content.is_some_and(|c| c.get("org.matrix.msc3245.voice").is_some());
+        let converted = if is_voice {
+            Some(crate::util::media::audio::matrix_to_wechat(&audio_data).await)
+        } else {
+            None
+        };
+        let (audio_data, filename) = match converted {
+            None => (audio_data, body.to_string()),
+            Some(Ok(converted)) => {
+                let stem = body.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(body);
+                (converted.data, format!("{}.{}", stem, converted.format.extension()))
+            }
+            Some(Err(e)) => {
+                warn!("Failed to convert audio for WeChat, sending as is: {}", e);
+                (audio_data, body.to_string())
+            }
+        };
         
-        match client.send_file_message(&portal.key.uid, &audio_data, body, reply_to.as_deref()).await {
+        match client.send_file_message(&portal.key.uid, &audio_data, &filename, reply_to.as_deref()).await {
             Ok(msg_id) => {
                 info!("Sent audio message to WeChat: {}", msg_id);
                 if let Some(event_id) = &event.event_id {
-                    if let Some(room_id) = &event.room_id {
-                        let msg = crate::database::Message {
-                            chat_uid: portal.key.uid.clone(),
-                            chat_receiver: portal.key.receiver.clone(),
-                            msg_id,
-                            mxid: event_id.clone(),
-                            sender: event.sender.clone().unwrap_or_default(),
-                            timestamp: event.origin_server_ts.unwrap_or(0),
-                            sent: true,
-                            error: None,
-                            msg_type: "m.audio".to_string(),
-                        };
-                        self.bridge.db.insert_message(&msg).await?;
-                    }
+                    self.bridge.db.complete_message(&portal.key, event_id, &msg_id, event_id, None).await?;
                 }
             }
-            Err(e) => {
-                warn!("Failed to send audio message to WeChat: {}", e);
-            }
+            Err(e) => return Err(e.context("failed to send the audio to WeChat")),
         }
 
         Ok(())
@@ -759,8 +2011,7 @@ impl MatrixEventHandler {
         event: &RoomEvent,
     ) -> anyhow::Result<()> {
         let Some(client) = user.get_client() else {
-            warn!("User has no WeChat client");
-            return Ok(());
+            anyhow::bail!("not logged in to WeChat");
         };
 
         let content = event.content.as_ref();
@@ -773,20 +2024,12 @@ impl MatrixEventHandler {
             .unwrap_or("file");
         
         let Some(url) = url else {
-            warn!("File message without URL");
-            return Ok(());
+            anyhow::bail!("message has no media URL");
         };
 
         debug!("Downloading file from {}", url);
         
-        let matrix_client = self.bridge.get_matrix_client();
-        let file_data = match matrix_client.download_media(url).await {
-            Ok(data) => data,
-            Err(e) => {
-                warn!("Failed to download file: {}", e);
-                return Ok(());
-            }
-        };
+        let file_data = self.download_outgoing_media(event, url).await?;
 
         let reply_to = self.get_reply_target(event).await?;
         
@@ -794,25 +2037,10 @@ impl MatrixEventHandler {
             Ok(msg_id) => {
                 info!("Sent file message to WeChat: {}", msg_id);
                 if let Some(event_id) = &event.event_id {
-                    if let Some(room_id) = &event.room_id {
-                        let msg = crate::database::Message {
-                            chat_uid: portal.key.uid.clone(),
-                            chat_receiver: portal.key.receiver.clone(),
-                            msg_id,
-                            mxid: event_id.clone(),
-                            sender: event.sender.clone().unwrap_or_default(),
-                            timestamp: event.origin_server_ts.unwrap_or(0),
-                            sent: true,
-                            error: None,
-                            msg_type: "m.file".to_string(),
-                        };
-                        self.bridge.db.insert_message(&msg).await?;
-                    }
+                    self.bridge.db.complete_message(&portal.key, event_id, &msg_id, event_id, None).await?;
                 }
             }
-            Err(e) => {
-                warn!("Failed to send file message to WeChat: {}", e);
-            }
+            Err(e) => return Err(e.context("failed to send the file to WeChat")),
         }
 
         Ok(())
@@ -825,8 +2053,7 @@ impl MatrixEventHandler {
         event: &RoomEvent,
     ) -> anyhow::Result<()> {
         let Some(client) = user.get_client() else {
-            warn!("User has no WeChat client");
-            return Ok(());
+            anyhow::bail!("not logged in to WeChat");
         };
 
         let content = event.content.as_ref();
@@ -835,18 +2062,18 @@ impl MatrixEventHandler {
             .and_then(|v| v.as_str());
         
         let Some(url) = url else {
-            warn!("Sticker message without URL");
-            return Ok(());
+            anyhow::bail!("message has no media URL");
         };
 
         debug!("Downloading sticker from {}", url);
         
-        let matrix_client = self.bridge.get_matrix_client();
-        let sticker_data = match matrix_client.download_media(url).await {
-            Ok(data) => data,
+        let sticker_data = self.download_outgoing_media(event, url).await?;
+        
+        let sticker_data = match crate::util::media::image::sticker_to_wechat(&sticker_data).await {
+            Ok(converted) => converted.data,
             Err(e) => {
-                warn!("Failed to download sticker: {}", e);
-                return Ok(());
+                warn!("Failed to convert sticker for WeChat, sending as is: {}", e);
+                sticker_data
             }
         };
         
@@ -854,51 +2081,183 @@ impl MatrixEventHandler {
             Ok(msg_id) => {
                 info!("Sent sticker message to WeChat: {}", msg_id);
                 if let Some(event_id) = &event.event_id {
-                    if let Some(room_id) = &event.room_id {
-                        let msg = crate::database::Message {
-                            chat_uid: portal.key.uid.clone(),
-                            chat_receiver: portal.key.receiver.clone(),
-                            msg_id,
-                            mxid: event_id.clone(),
-                            sender: event.sender.clone().unwrap_or_default(),
-                            timestamp: event.origin_server_ts.unwrap_or(0),
-                            sent: true,
-                            error: None,
-                            msg_type: "m.sticker".to_string(),
-                        };
-                        self.bridge.db.insert_message(&msg).await?;
-                    }
+                    self.bridge.db.complete_message(&portal.key, event_id, &msg_id, event_id, None).await?;
                 }
             }
-            Err(e) => {
-                warn!("Failed to send sticker message to WeChat: {}", e);
-            }
+            Err(e) => return Err(e.context("failed to send the sticker to WeChat")),
         }
 
         Ok(())
     }
 
-    async fn get_reply_target(&self, event: &RoomEvent) -> anyhow::Result<Option<String>> {
-        let relates_to = event.content.as_ref()
-            .and_then(|c| c.get("m.relates_to"));
-        
-        if let Some(relates_to) = relates_to {
-            let in_reply_to = relates_to.get("m.in_reply_to")
-                .and_then(|r| r.get("event_id"))
-                .and_then(|e| e.as_str());
-            
-            if let Some(event_id) = in_reply_to {
-                let Some(room_id) = &event.room_id else {
-                    return Ok(None);
-                };
-                
-                if let Some(msg) = self.bridge.db.get_message_by_mxid(event_id).await? {
-                    return Ok(Some(msg.msg_id));
+    async fn handle_edit_message(
+        &self,
+        user: &crate::bridge::user::BridgeUser,
+        portal: &crate::bridge::portal::BridgePortal,
+        event: &RoomEvent,
+        target_event_id: &str,
+    ) -> anyhow::Result<()> {
+        let Some(sender) = &event.sender else {
+            return Ok(());
+        };
+
+        let Some(target) = self.bridge.db.get_message_by_mxid(target_event_id).await? else {
+            debug!("Edit target {} not found", target_event_id);
+            return Ok(());
+        };
+        if target.key() != portal.key || target.sender != *sender {
+            debug!("Ignoring edit of {}, which {} didn't send in this portal", target_event_id, sender);
+            return Ok(());
+        }
+
+        let new_content = event.content.as_ref()
+            .and_then(|c| c.get("m.new_content"));
+        let body = new_content
+            .and_then(|c| c.get("body"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("");
+        if body.is_empty() {
+            debug!("Edit without new body, skipping");
+            return Ok(());
+        }
+        let text = if new_content.and_then(|c| c.get("msgtype")).and_then(|v| v.as_str()) == Some("m.emote") {
+            format!("/me {}", body)
+        } else {
+            body.to_string()
+        };
+
+        let client = self.bridge.get_client(&user.mxid);
+        let correction_id = match client.edit_text_message(&portal.key.uid, &target.msg_id, &text).await {
+            Ok(()) => {
+                info!("Edited WeChat message {}", target.msg_id);
+                None
+            }
+            Err(e) => {
+                debug!("Agent couldn't edit message {}, sending correction: {}", target.msg_id, e);
+                let correction = format!("* {}", text);
+                match client.send_text_message(&portal.key.uid, &correction, Some(&target.msg_id)).await {
+                    Ok(msg_id) => {
+                        if let Some(event_id) = &event.event_id {
+                            let msg = crate::database::Message {
+                                chat_uid: portal.key.uid.clone(),
+                                chat_receiver: portal.key.receiver.clone(),
+                                msg_id: msg_id.clone(),
+                                mxid: event_id.clone(),
+                                sender: sender.clone(),
+                                timestamp: event.origin_server_ts.unwrap_or(0),
+                                sent: true,
+                                error: None,
+                                msg_type: "m.text".to_string(),
+                            };
+                            self.bridge.db.insert_message(&msg).await?;
+                        }
+                        Some(msg_id)
+                    }
+                    Err(e) => {
+                        warn!("Failed to send correction to WeChat: {}", e);
+                        return Ok(());
+                    }
                 }
             }
+        };
+
+        if let Some(event_id) = &event.event_id {
+            self.bridge.db.insert_message_edit(&crate::database::MessageEdit {
+                chat_uid: portal.key.uid.clone(),
+                chat_receiver: portal.key.receiver.clone(),
+                msg_id: correction_id.unwrap_or_else(|| event_id.clone()),
+                target_msg_id: target.msg_id.clone(),
+                mxid: event_id.clone(),
+                sender: sender.clone(),
+                timestamp: event.origin_server_ts.unwrap_or(0),
+            }).await?;
+        }
+        Ok(())
+    }
+
+    async fn apply_message_marker(&self, mxid: &str, text: &str) -> anyhow::Result<String> {
+        let marker = &self.bridge.config.bridge.message_marker;
+        let enabled = self.bridge.db.get_user_setting(mxid).await?
+            .and_then(|s| s.message_marker)
+            .unwrap_or(marker.enabled);
+        if !enabled {
+            return Ok(text.to_string());
+        }
+
+        let (prefix, suffix) = marker.format(mxid);
+        Ok(crate::formatter::add_message_marker(text, &prefix, &suffix))
+    }
+
+    /// Returns the relay user of the portal with a WeChat client, if relay mode is usable there.
+    async fn get_relay_user(
+        &self,
+        portal: &crate::bridge::portal::BridgePortal,
+    ) -> anyhow::Result<Option<crate::bridge::user::BridgeUser>> {
+        if !self.bridge.config.bridge.relay.enabled {
+            return Ok(None);
+        }
+        let Some(relay) = self.bridge.db.get_portal_relay(&portal.key).await? else {
+            return Ok(None);
+        };
+
+        let user = self.bridge.get_user_by_mxid(&relay.relay_mxid).await?;
+        if user.uin().is_none() || !self.bridge.get_permission(&user.mxid).can_use() {
+            warn!("Relay user {} of {} is not logged in or lost bridge access", relay.relay_mxid, portal.key.uid);
+            return Ok(None);
         }
 
-        Ok(None)
+        let mut user = user.as_ref().clone();
+        if user.get_client().is_none() {
+            user.set_client(self.bridge.get_client(&user.mxid));
+        }
+        Ok(Some(user))
+    }
+
+    async fn get_member_displayname(&self, room_id: &str, mxid: &str) -> String {
+        let client = self.bridge.get_matrix_client();
+        self.bridge.room_state.member(&client, room_id, mxid).await
+            .ok()
+            .flatten()
+            .and_then(|m| m.displayname)
+            .unwrap_or_else(|| mxid.to_string())
+    }
+
+    fn get_replace_target<'a>(&self, event: &'a RoomEvent) -> Option<&'a str> {
+        let relates_to = event.content.as_ref()?.get("m.relates_to")?;
+        if relates_to.get("rel_type").and_then(|v| v.as_str()) != Some("m.replace") {
+            return None;
+        }
+        relates_to.get("event_id").and_then(|v| v.as_str())
+    }
+
+    /// The WeChat ID behind a mentioned Matrix user: a puppet's contact, or a logged-in user.
+    async fn mention_wxid(&self, mxid: &str) -> anyhow::Result<Option<String>> {
+        if self.is_puppet_mxid(mxid) {
+            return Ok(self.bridge.puppet_uin(mxid));
+        }
+        Ok(self.bridge.db.get_user_by_mxid(mxid).await?.and_then(|u| u.uin))
+    }
+
+    async fn get_reply_target(&self, event: &RoomEvent) -> anyhow::Result<Option<String>> {
+        let Some(event_id) = reply_event_id(event) else {
+            return Ok(None);
+        };
+        Ok(self.bridge.db.get_message_by_mxid(event_id).await?.map(|msg| msg.msg_id))
+    }
+
+    /// The text of the message replied to, for replies from clients that don't add a fallback.
+    async fn fetch_reply_quote(&self, event: &RoomEvent) -> Option<String> {
+        let (room_id, event_id) = (event.room_id.as_deref()?, reply_event_id(event)?);
+        match self.bridge.get_matrix_client().get_event(room_id, event_id).await {
+            Ok(original) => original.content.as_ref()
+                .and_then(|c| c.get("body"))
+                .and_then(|v| v.as_str())
+                .map(|body| crate::formatter::matrix_to_wechat::strip_reply_fallback(body).1.to_string()),
+            Err(e) => {
+                debug!("Failed to fetch replied-to event {}: {}", event_id, e);
+                None
+            }
+        }
     }
 
     async fn get_portal_by_mxid(&self, mxid: &str) -> anyhow::Result<Option<Arc<crate::bridge::portal::BridgePortal>>> {
@@ -921,6 +2280,14 @@ impl MatrixEventHandler {
     }
 }
 
+fn reply_event_id(event: &RoomEvent) -> Option<&str> {
+    event.content.as_ref()?
+        .get("m.relates_to")?
+        .get("m.in_reply_to")?
+        .get("event_id")?
+        .as_str()
+}
+
 pub struct MatrixEventProcessor {
     handler: Arc<dyn MatrixEventHandlerTrait + Send + Sync>,
     event_age_limit: Duration,
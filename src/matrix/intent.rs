@@ -0,0 +1,237 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+use tokio::sync::RwLock;
+use tracing::debug;
+
+use super::client::{MatrixClient, matrix_errcode};
+use super::state_cache::RoomStateCache;
+use super::types::JoinedMember;
+
+/// Marks the content of events a double puppet sent for the bridge, so they aren't bridged back.
+pub const DOUBLE_PUPPET_SOURCE: &str = "fi.mau.double_puppet_source";
+
+/// Acts as a single appservice user, such as a WeChat ghost. The user is registered on first
+/// use and joins rooms when the homeserver says it isn't in them.
+#[derive(Clone)]
+pub struct Intent {
+    mxid: String,
+    client: MatrixClient,
+    bot: MatrixClient,
+    state: Arc<IntentState>,
+    /// Acts as a real Matrix user with their own access token instead of impersonating a ghost.
+    double_puppet: bool,
+    /// Tells which rooms the user is already in without asking the homeserver.
+    room_state: Option<RoomStateCache>,
+}
+
+#[derive(Default)]
+struct IntentState {
+    registered: AtomicBool,
+    joined: RwLock<HashSet<String>>,
+    displayname: RwLock<Option<String>>,
+    avatar_url: RwLock<Option<String>>,
+}
+
+impl Intent {
+    /// `bot` is the appservice bot's client, used to register the user and invite it to rooms.
+    pub fn new(bot: MatrixClient, mxid: &str) -> Self {
+        let state = IntentState::default();
+        // The bot is the appservice's sender and always exists.
+        if bot.user_id() == Some(mxid) {
+            state.registered.store(true, Ordering::Release);
+        }
+        Self {
+            mxid: mxid.to_string(),
+            client: bot.clone().impersonate(mxid),
+            bot,
+            state: Arc::new(state),
+            double_puppet: false,
+            room_state: None,
+        }
+    }
+
+    /// Acts as the real Matrix user `client` is logged in as, for messages they sent from WeChat.
+    pub fn double_puppet(bot: MatrixClient, client: MatrixClient) -> Self {
+        let state = IntentState::default();
+        // Real users are registered by the homeserver, not the appservice.
+        state.registered.store(true, Ordering::Release);
+        Self {
+            mxid: client.user_id().unwrap_or_default().to_string(),
+            client,
+            bot,
+            state: Arc::new(state),
+            double_puppet: true,
+            room_state: None,
+        }
+    }
+
+    /// Checks and records the user's memberships in the bridge's room state cache.
+    pub fn with_room_state(mut self, room_state: RoomStateCache) -> Self {
+        self.room_state = Some(room_state);
+        self
+    }
+
+    /// A copy of this intent whose events are backdated to `timestamp` in milliseconds.
+    pub fn at(&self, timestamp: i64) -> Self {
+        Self {
+            client: self.client.clone().with_timestamp(timestamp),
+            ..self.clone()
+        }
+    }
+
+    pub fn mxid(&self) -> &str {
+        &self.mxid
+    }
+
+    /// The client acting as this user, for requests that don't need a room membership.
+    pub fn client(&self) -> &MatrixClient {
+        &self.client
+    }
+
+    pub async fn ensure_registered(&self) -> Result<()> {
+        if self.state.registered.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let localpart = self.mxid.trim_start_matches('@').split(':').next().unwrap_or_default();
+        self.bot.register_appservice_user(localpart).await?;
+        self.state.registered.store(true, Ordering::Release);
+        debug!("Registered {}", self.mxid);
+        Ok(())
+    }
+
+    pub async fn ensure_joined(&self, room_id: &str) -> Result<()> {
+        if self.state.joined.read().await.contains(room_id) {
+            return Ok(());
+        }
+        if let Some(room_state) = &self.room_state
+            && room_state.is_joined(room_id, &self.mxid).await == Some(true)
+        {
+            self.state.joined.write().await.insert(room_id.to_string());
+            return Ok(());
+        }
+        self.ensure_registered().await?;
+
+        if let Err(e) = self.client.join_room(room_id).await {
+            if matrix_errcode(&e) != Some("M_FORBIDDEN") {
+                return Err(e);
+            }
+            // Ghosts that were never invited are invited by the bot first.
+            self.bot.invite_user(room_id, &self.mxid).await?;
+            self.client.join_room(room_id).await?;
+        }
+        self.state.joined.write().await.insert(room_id.to_string());
+        if let Some(room_state) = &self.room_state {
+            let member = JoinedMember {
+                displayname: self.state.displayname.read().await.clone(),
+                avatar_url: self.state.avatar_url.read().await.clone(),
+            };
+            room_state.set_member(room_id, &self.mxid, Some(member)).await;
+        }
+        debug!("{} joined {}", self.mxid, room_id);
+        Ok(())
+    }
+
+    pub async fn leave(&self, room_id: &str) -> Result<()> {
+        self.client.leave_room(room_id).await?;
+        self.state.joined.write().await.remove(room_id);
+        if let Some(room_state) = &self.room_state {
+            room_state.set_member(room_id, &self.mxid, None).await;
+        }
+        Ok(())
+    }
+
+    pub fn is_double_puppet(&self) -> bool {
+        self.double_puppet
+    }
+
+    /// Runs a request in `room_id`, joining the room and trying again if the user isn't in it.
+    async fn in_room<T, F, Fut>(&self, room_id: &str, request: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.ensure_registered().await?;
+        match request().await {
+            Err(e) if matrix_errcode(&e) == Some("M_FORBIDDEN") => {
+                debug!("{} is not in {}, joining", self.mxid, room_id);
+                self.state.joined.write().await.remove(room_id);
+                self.ensure_joined(room_id).await?;
+                request().await
+            }
+            result => result,
+        }
+    }
+
+    pub async fn send_message(&self, room_id: &str, event_type: &str, content: &serde_json::Value) -> Result<String> {
+        let content = self.mark_content(content);
+        self.in_room(room_id, || self.client.send_message(room_id, event_type, &content, None)).await
+    }
+
+    pub async fn edit_message(&self, room_id: &str, event_id: &str, new_content: &serde_json::Value) -> Result<String> {
+        let new_content = self.mark_content(new_content);
+        self.in_room(room_id, || self.client.edit_message(room_id, event_id, &new_content)).await
+    }
+
+    fn mark_content(&self, content: &serde_json::Value) -> serde_json::Value {
+        let mut content = content.clone();
+        if self.double_puppet
+            && let Some(object) = content.as_object_mut()
+        {
+            object.insert(DOUBLE_PUPPET_SOURCE.to_string(), "wechat".into());
+        }
+        content
+    }
+
+    pub async fn send_reaction(&self, room_id: &str, event_id: &str, key: &str) -> Result<String> {
+        self.in_room(room_id, || self.client.send_reaction(room_id, event_id, key)).await
+    }
+
+    pub async fn redact(&self, room_id: &str, event_id: &str, reason: Option<&str>) -> Result<String> {
+        self.in_room(room_id, || self.client.redact(room_id, event_id, reason)).await
+    }
+
+    pub async fn send_read_receipt(&self, room_id: &str, event_id: &str) -> Result<()> {
+        self.in_room(room_id, || self.client.send_read_receipt(room_id, event_id)).await
+    }
+
+    pub async fn set_presence(&self, presence: &str) -> Result<()> {
+        self.ensure_registered().await?;
+        self.client.set_presence(presence, None).await
+    }
+
+    /// Records the profile the user is known to have, so setting it again is skipped.
+    pub async fn assume_profile(&self, displayname: Option<&str>, avatar_url: Option<&str>) {
+        if let Some(displayname) = displayname {
+            *self.state.displayname.write().await = Some(displayname.to_string());
+        }
+        if let Some(avatar_url) = avatar_url {
+            *self.state.avatar_url.write().await = Some(avatar_url.to_string());
+        }
+    }
+
+    /// Sets the displayname unless it's already the one this intent last set.
+    pub async fn set_displayname(&self, displayname: &str) -> Result<()> {
+        if self.state.displayname.read().await.as_deref() == Some(displayname) {
+            return Ok(());
+        }
+        self.ensure_registered().await?;
+        self.client.set_displayname(&self.mxid, displayname).await?;
+        *self.state.displayname.write().await = Some(displayname.to_string());
+        Ok(())
+    }
+
+    /// Sets the avatar unless it's already the one this intent last set.
+    pub async fn set_avatar_url(&self, avatar_url: &str) -> Result<()> {
+        if self.state.avatar_url.read().await.as_deref() == Some(avatar_url) {
+            return Ok(());
+        }
+        self.ensure_registered().await?;
+        self.client.set_avatar_url(&self.mxid, avatar_url).await?;
+        *self.state.avatar_url.write().await = Some(avatar_url.to_string());
+        Ok(())
+    }
+}
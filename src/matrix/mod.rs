@@ -2,9 +2,13 @@ mod appservice;
 pub mod client;
 pub mod types;
 pub mod event_handler;
+pub mod intent;
+pub mod state_cache;
+pub mod rate_limit;
 
 pub use appservice::*;
 pub use client::*;
 pub use event_handler::*;
+pub use intent::Intent;
 pub use types::*;
 pub use types::*;
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+
+use crate::config::RateLimitsConfig;
+use crate::util::TokenBucket;
+
+/// The longest wait accepted from a homeserver, so a bogus `retry_after_ms` can't stall the bridge.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+/// How long to wait when a rate limited response doesn't say.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+static LIMITS: Lazy<RwLock<Arc<RateLimits>>> =
+    Lazy::new(|| RwLock::new(Arc::new(RateLimits::new(&RateLimitsConfig::default()))));
+
+/// Request budgets shared by every [`MatrixClient`](super::MatrixClient), as the homeserver
+/// limits the appservice as a whole.
+pub struct RateLimits {
+    buckets: HashMap<String, TokenBucket>,
+    max_retries: u32,
+}
+
+impl RateLimits {
+    pub fn new(config: &RateLimitsConfig) -> Self {
+        let buckets = config.endpoints.iter()
+            .filter(|(_, budget)| budget.per_second > 0.0)
+            .map(|(group, budget)| {
+                let bucket = TokenBucket::new(group.clone(), budget.burst.max(1) as f64, budget.per_second);
+                (group.clone(), bucket)
+            })
+            .collect();
+        Self {
+            buckets,
+            max_retries: config.max_retries,
+        }
+    }
+
+    /// Waits until the budget of the endpoint group of `path` allows another request.
+    pub async fn acquire(&self, path: &str) {
+        let bucket = self.buckets.get(endpoint_group(path)).or_else(|| self.buckets.get("default"));
+        if let Some(bucket) = bucket {
+            bucket.consume(1.0).await;
+        }
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+}
+
+/// Replaces the limits used by all clients, at startup.
+pub fn configure(config: &RateLimitsConfig) {
+    *LIMITS.write().unwrap() = Arc::new(RateLimits::new(config));
+}
+
+pub fn current() -> Arc<RateLimits> {
+    LIMITS.read().unwrap().clone()
+}
+
+/// The group of endpoints `path` belongs to, which shares a budget.
+pub fn endpoint_group(path: &str) -> &'static str {
+    let path = path.split('?').next().unwrap_or_default();
+    if path.starts_with("/_matrix/media/") {
+        return "media";
+    }
+    if path.contains("/send/") || path.contains("/redact/") {
+        return "send";
+    }
+    if path.contains("/state/") {
+        return "state";
+    }
+    if path.contains("/profile/") {
+        return "profile";
+    }
+    if ["/join", "/invite", "/leave", "/kick", "/ban", "/unban"].iter().any(|m| path.ends_with(m))
+        || path.contains("/join/")
+    {
+        return "membership";
+    }
+    "default"
+}
+
+/// How long to wait before retrying a rate limited request, from the `retry_after_ms` of the
+/// error body or else the `Retry-After` header, in seconds.
+pub fn retry_after(body: &str, header: Option<&str>) -> Duration {
+    let from_body = serde_json::from_str::<serde_json::Value>(body).ok()
+        .and_then(|error| error.get("retry_after_ms").and_then(|v| v.as_u64()))
+        .map(Duration::from_millis);
+    let from_header = header.and_then(|h| h.trim().parse().ok()).map(Duration::from_secs);
+    from_body.or(from_header).unwrap_or(DEFAULT_RETRY_AFTER).min(MAX_RETRY_AFTER)
+}
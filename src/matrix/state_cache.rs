@@ -0,0 +1,147 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tracing::debug;
+
+use crate::matrix::client::MatrixClient;
+use crate::matrix::types::{JoinedMember, JoinedMembersResponse, PowerLevelsContent, RoomEvent};
+use crate::util::perf::Cache;
+
+const MAX_CACHED_ROOMS: usize = 10000;
+
+/// Caches Matrix room state that the bridge would otherwise fetch on every sync.
+///
+/// Entries are kept up to date from state events in appservice transactions, and
+/// expire after the TTL in case an update was missed.
+#[derive(Clone)]
+pub struct RoomStateCache {
+    members: Cache<String, JoinedMembersResponse>,
+    power_levels: Cache<String, PowerLevelsContent>,
+    encrypted: Cache<String, bool>,
+}
+
+impl RoomStateCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            members: Cache::with_ttl(MAX_CACHED_ROOMS, ttl),
+            power_levels: Cache::with_ttl(MAX_CACHED_ROOMS, ttl),
+            encrypted: Cache::with_ttl(MAX_CACHED_ROOMS, ttl),
+        }
+    }
+
+    pub async fn joined_members(&self, client: &MatrixClient, room_id: &str) -> Result<JoinedMembersResponse> {
+        let key = room_id.to_string();
+        if let Some(members) = self.members.get(&key).await {
+            return Ok(members);
+        }
+
+        let members = client.get_joined_members(room_id).await?;
+        self.members.insert(key, members.clone()).await;
+        Ok(members)
+    }
+
+    /// A joined member of a room, from the room's cached member list.
+    pub async fn member(&self, client: &MatrixClient, room_id: &str, mxid: &str) -> Result<Option<JoinedMember>> {
+        Ok(self.joined_members(client, room_id).await?.joined.remove(mxid))
+    }
+
+    /// Whether a user is joined to a room, if the room's members are cached. Never fetches them.
+    pub async fn is_joined(&self, room_id: &str, mxid: &str) -> Option<bool> {
+        self.members.get(&room_id.to_string()).await.map(|m| m.joined.contains_key(mxid))
+    }
+
+    /// Records a membership change in the room's cached member list, if there is one. `None`
+    /// means the user isn't joined anymore.
+    pub async fn set_member(&self, room_id: &str, mxid: &str, member: Option<JoinedMember>) {
+        let key = room_id.to_string();
+        let Some(mut entry) = self.members.get_entry(&key).await else {
+            return;
+        };
+        match member {
+            Some(member) => {
+                entry.value.joined.insert(mxid.to_string(), member);
+            }
+            None => {
+                entry.value.joined.remove(mxid);
+            }
+        }
+        // The entry keeps its expiry, so a missed update is still corrected within the TTL.
+        let ttl = entry.expires_at.map(|e| e.saturating_duration_since(Instant::now()));
+        self.members.insert_with_ttl(key, entry.value, ttl).await;
+    }
+
+    pub async fn power_levels(&self, client: &MatrixClient, room_id: &str) -> Result<PowerLevelsContent> {
+        let key = room_id.to_string();
+        if let Some(levels) = self.power_levels.get(&key).await {
+            return Ok(levels);
+        }
+
+        let content = client.get_room_state(room_id, "m.room.power_levels", "").await?;
+        let levels: PowerLevelsContent = serde_json::from_value(content)?;
+        self.power_levels.insert(key, levels.clone()).await;
+        Ok(levels)
+    }
+
+    pub async fn is_encrypted(&self, client: &MatrixClient, room_id: &str) -> Result<bool> {
+        let key = room_id.to_string();
+        if let Some(encrypted) = self.encrypted.get(&key).await {
+            return Ok(encrypted);
+        }
+
+        let encrypted = match client.get_room_state(room_id, "m.room.encryption", "").await {
+            Ok(content) => content.get("algorithm").is_some(),
+            Err(e) if e.to_string().contains("M_NOT_FOUND") => false,
+            Err(e) => return Err(e),
+        };
+        self.encrypted.insert(key, encrypted).await;
+        Ok(encrypted)
+    }
+
+    /// Updates the cache from a state event received in a transaction.
+    pub async fn apply_event(&self, event: &RoomEvent) {
+        let Some(room_id) = &event.room_id else {
+            return;
+        };
+        if event.state_key.is_none() {
+            return;
+        }
+
+        match event.event_type.as_str() {
+            "m.room.member" => {
+                let Some(mxid) = &event.state_key else {
+                    return;
+                };
+                let content = event.content.as_ref();
+                let field = |name: &str| content.and_then(|c| c.get(name)).and_then(|v| v.as_str());
+                let member = (field("membership") == Some("join")).then(|| JoinedMember {
+                    displayname: field("displayname").map(str::to_string),
+                    avatar_url: field("avatar_url").map(str::to_string),
+                });
+                debug!("Updating cached membership of {} in {}", mxid, room_id);
+                self.set_member(room_id, mxid, member).await;
+            }
+            "m.room.power_levels" => {
+                let levels = event.content.clone()
+                    .and_then(|c| serde_json::from_value::<PowerLevelsContent>(c).ok());
+                match levels {
+                    Some(levels) => self.power_levels.insert(room_id.clone(), levels).await,
+                    None => {
+                        self.power_levels.remove(room_id).await;
+                    }
+                }
+            }
+            "m.room.encryption" => {
+                self.encrypted.insert(room_id.clone(), true).await;
+            }
+            _ => {}
+        }
+    }
+
+    /// Drops everything cached for a room, e.g. after the bridge changed its state itself.
+    pub async fn invalidate(&self, room_id: &str) {
+        let key = room_id.to_string();
+        self.members.remove(&key).await;
+        self.power_levels.remove(&key).await;
+        self.encrypted.remove(&key).await;
+    }
+}
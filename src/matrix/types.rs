@@ -40,11 +40,20 @@ pub struct ToDeviceEvent {
     pub content: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub sender: Option<String>,
+    /// The recipient, set on to-device events pushed to appservices (MSC2409).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to_user_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to_device_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub events: Vec<RoomEvent>,
+    #[serde(default, alias = "de.sorunome.msc2409.ephemeral")]
+    pub ephemeral: Vec<RoomEvent>,
+    #[serde(default, alias = "de.sorunome.msc2409.to_device")]
+    pub to_device: Vec<ToDeviceEvent>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -107,6 +116,17 @@ impl EventContent {
         }
     }
 
+    pub fn notice_html(body: impl Into<String>, html: impl Into<String>) -> Self {
+        Self {
+            msgtype: "m.notice".to_string(),
+            body: body.into(),
+            formatted_body: Some(html.into()),
+            format: Some("org.matrix.custom.html".to_string()),
+            url: None,
+            info: None,
+        }
+    }
+
     pub fn image(body: impl Into<String>, url: impl Into<String>) -> Self {
         Self {
             msgtype: "m.image".to_string(),
@@ -299,6 +319,8 @@ pub struct CreateRoomRequest {
     pub initial_state: Option<Vec<serde_json::Value>>,
     #[serde(default)]
     pub power_level_content_override: Option<PowerLevelsContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creation_content: Option<serde_json::Value>,
 }
 
 impl CreateRoomRequest {
@@ -315,6 +337,7 @@ impl CreateRoomRequest {
             is_direct: true,
             initial_state: None,
             power_level_content_override: None,
+            creation_content: None,
         }
     }
 
@@ -331,6 +354,15 @@ impl CreateRoomRequest {
             is_direct: false,
             initial_state: None,
             power_level_content_override: None,
+            creation_content: None,
+        }
+    }
+
+    pub fn space(name: impl Into<String>) -> Self {
+        Self {
+            creation_content: Some(serde_json::json!({ "type": "m.space" })),
+            is_direct: false,
+            ..Self::private(name)
         }
     }
 
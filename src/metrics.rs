@@ -1,14 +1,34 @@
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::collections::HashMap;
 use std::time::Instant;
-use tokio::sync::RwLock;
 use once_cell::sync::Lazy;
 
 pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
 
+/// An `f64` updated atomically, stored as its bits.
+#[derive(Debug, Default)]
+struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    fn load(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn store(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    fn add(&self, delta: f64) {
+        let _ = self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f64::from_bits(bits) + delta).to_bits())
+        });
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Counter {
-    value: Arc<RwLock<u64>>,
+    value: Arc<AtomicU64>,
     labels: HashMap<String, String>,
 }
 
@@ -19,28 +39,25 @@ impl Counter {
     
     pub fn with_labels(labels: HashMap<String, String>) -> Self {
         Self {
-            value: Arc::new(RwLock::new(0)),
+            value: Arc::default(),
             labels,
         }
     }
     
-    pub async fn inc(&self) {
-        let mut value = self.value.write().await;
-        *value += 1;
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
     }
     
-    pub async fn inc_by(&self, delta: u64) {
-        let mut value = self.value.write().await;
-        *value += delta;
+    pub fn inc_by(&self, delta: u64) {
+        self.value.fetch_add(delta, Ordering::Relaxed);
     }
     
-    pub async fn dec(&self) {
-        let mut value = self.value.write().await;
-        *value = value.saturating_sub(1);
+    pub fn dec(&self) {
+        let _ = self.value.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| Some(v.saturating_sub(1)));
     }
     
-    pub async fn get(&self) -> u64 {
-        *self.value.read().await
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
     }
     
     pub fn labels(&self) -> &HashMap<String, String> {
@@ -48,19 +65,154 @@ impl Counter {
     }
 }
 
+/// Counters keyed by label values. Only the first increment of a key takes the write lock;
+/// later ones share the read lock and update the counter atomically.
+#[derive(Debug, Default)]
+struct CounterMap<K> {
+    values: RwLock<HashMap<K, AtomicU64>>,
+}
+
+impl<K: std::hash::Hash + Eq + Ord + Clone> CounterMap<K> {
+    fn inc<Q>(&self, key: &Q)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: std::hash::Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        if let Some(count) = self.values.read().unwrap_or_else(|e| e.into_inner()).get(key) {
+            count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.values
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(key.to_owned())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get<Q>(&self, key: &Q) -> u64
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: std::hash::Hash + Eq + ?Sized,
+    {
+        self.values
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(key)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    /// The keys and their counts, sorted by key.
+    fn snapshot(&self) -> Vec<(K, u64)> {
+        let values = self.values.read().unwrap_or_else(|e| e.into_inner());
+        let mut entries: Vec<_> = values.iter().map(|(k, v)| (k.clone(), v.load(Ordering::Relaxed))).collect();
+        entries.sort();
+        entries
+    }
+}
+
+/// A set of counters partitioned by the value of a single label.
 #[derive(Debug, Clone)]
-pub struct Gauge {
-    value: Arc<RwLock<f64>>,
-    labels: HashMap<String, String>,
+pub struct CounterVec {
+    label: String,
+    values: Arc<CounterMap<String>>,
 }
 
-impl Default for Gauge {
-    fn default() -> Self {
+impl CounterVec {
+    pub fn new(label: impl Into<String>) -> Self {
+        Self {
+            label: label.into(),
+            values: Arc::default(),
+        }
+    }
+    
+    pub fn inc(&self, value: &str) {
+        self.values.inc(value);
+    }
+    
+    pub fn get(&self, value: &str) -> u64 {
+        self.values.get(value)
+    }
+    
+    fn to_prometheus(&self, name: &str) -> String {
+        self.values
+            .snapshot()
+            .into_iter()
+            .map(|(value, count)| format!("{}{{{}=\"{}\"}} {}\n", name, self.label, escape_label_value(&value), count))
+            .collect()
+    }
+}
+
+/// A set of counters partitioned by the values of several labels, given in the order the
+/// labels were named in.
+#[derive(Debug, Clone)]
+pub struct LabeledCounter {
+    labels: Vec<String>,
+    values: Arc<CounterMap<Vec<String>>>,
+}
+
+impl LabeledCounter {
+    pub fn new(labels: &[&str]) -> Self {
         Self {
-            value: Arc::new(RwLock::new(0.0)),
-            labels: HashMap::new(),
+            labels: labels.iter().map(|l| l.to_string()).collect(),
+            values: Arc::default(),
         }
     }
+
+    pub fn inc(&self, values: &[&str]) {
+        debug_assert_eq!(values.len(), self.labels.len());
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        self.values.inc(&key);
+    }
+
+    pub fn get(&self, values: &[&str]) -> u64 {
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        self.values.get(&key)
+    }
+
+    fn to_prometheus(&self, name: &str) -> String {
+        self.values
+            .snapshot()
+            .into_iter()
+            .map(|(values, count)| {
+                let labels = self
+                    .labels
+                    .iter()
+                    .zip(&values)
+                    .map(|(label, value)| format!("{}=\"{}\"", label, escape_label_value(value)))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                format!("{}{{{}}} {}\n", name, labels, count)
+            })
+            .collect()
+    }
+}
+
+/// Escapes a label value for the Prometheus text format.
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Which way a message was bridged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    MatrixToWechat,
+    WechatToMatrix,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::MatrixToWechat => "matrix→wechat",
+            Direction::WechatToMatrix => "wechat→matrix",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Gauge {
+    value: Arc<AtomicF64>,
+    labels: HashMap<String, String>,
 }
 
 impl Gauge {
@@ -70,37 +222,33 @@ impl Gauge {
     
     pub fn with_labels(labels: HashMap<String, String>) -> Self {
         Self {
-            value: Arc::new(RwLock::new(0.0)),
+            value: Arc::default(),
             labels,
         }
     }
     
-    pub async fn set(&self, value: f64) {
-        *self.value.write().await = value;
+    pub fn set(&self, value: f64) {
+        self.value.store(value);
     }
     
-    pub async fn inc(&self) {
-        let mut value = self.value.write().await;
-        *value += 1.0;
+    pub fn inc(&self) {
+        self.value.add(1.0);
     }
     
-    pub async fn dec(&self) {
-        let mut value = self.value.write().await;
-        *value -= 1.0;
+    pub fn dec(&self) {
+        self.value.add(-1.0);
     }
     
-    pub async fn add(&self, delta: f64) {
-        let mut value = self.value.write().await;
-        *value += delta;
+    pub fn add(&self, delta: f64) {
+        self.value.add(delta);
     }
     
-    pub async fn sub(&self, delta: f64) {
-        let mut value = self.value.write().await;
-        *value -= delta;
+    pub fn sub(&self, delta: f64) {
+        self.value.add(-delta);
     }
     
-    pub async fn get(&self) -> f64 {
-        *self.value.read().await
+    pub fn get(&self) -> f64 {
+        self.value.load()
     }
     
     pub fn labels(&self) -> &HashMap<String, String> {
@@ -108,34 +256,29 @@ impl Gauge {
     }
 }
 
+/// A histogram whose fields are updated separately, so a scrape racing an observation may see
+/// it in some of them but not yet the others.
 #[derive(Debug, Clone)]
 pub struct Histogram {
     buckets: Vec<f64>,
-    counts: Arc<RwLock<Vec<u64>>>,
-    sum: Arc<RwLock<f64>>,
-    count: Arc<RwLock<u64>>,
+    counts: Arc<Vec<AtomicU64>>,
+    sum: Arc<AtomicF64>,
+    count: Arc<AtomicU64>,
     labels: HashMap<String, String>,
 }
 
 impl Histogram {
     pub fn new(buckets: Vec<f64>) -> Self {
-        let bucket_count = buckets.len();
-        Self {
-            buckets,
-            counts: Arc::new(RwLock::new(vec![0; bucket_count + 1])),
-            sum: Arc::new(RwLock::new(0.0)),
-            count: Arc::new(RwLock::new(0)),
-            labels: HashMap::new(),
-        }
+        Self::with_labels(buckets, HashMap::new())
     }
     
     pub fn with_labels(buckets: Vec<f64>, labels: HashMap<String, String>) -> Self {
-        let bucket_count = buckets.len();
+        let counts = (0..=buckets.len()).map(|_| AtomicU64::new(0)).collect();
         Self {
             buckets,
-            counts: Arc::new(RwLock::new(vec![0; bucket_count + 1])),
-            sum: Arc::new(RwLock::new(0.0)),
-            count: Arc::new(RwLock::new(0)),
+            counts: Arc::new(counts),
+            sum: Arc::default(),
+            count: Arc::default(),
             labels,
         }
     }
@@ -144,32 +287,28 @@ impl Histogram {
         vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
     }
     
-    pub async fn observe(&self, value: f64) {
-        let mut counts = self.counts.write().await;
-        let mut sum = self.sum.write().await;
-        let mut count = self.count.write().await;
-        
-        *sum += value;
-        *count += 1;
+    pub fn observe(&self, value: f64) {
+        self.sum.add(value);
+        self.count.fetch_add(1, Ordering::Relaxed);
         
         for (i, &bucket) in self.buckets.iter().enumerate() {
             if value <= bucket {
-                counts[i] += 1;
+                self.counts[i].fetch_add(1, Ordering::Relaxed);
             }
         }
-        counts[self.buckets.len()] += 1;
+        self.counts[self.buckets.len()].fetch_add(1, Ordering::Relaxed);
     }
     
-    pub async fn get_counts(&self) -> Vec<u64> {
-        self.counts.read().await.clone()
+    pub fn get_counts(&self) -> Vec<u64> {
+        self.counts.iter().map(|c| c.load(Ordering::Relaxed)).collect()
     }
     
-    pub async fn get_sum(&self) -> f64 {
-        *self.sum.read().await
+    pub fn get_sum(&self) -> f64 {
+        self.sum.load()
     }
     
-    pub async fn get_count(&self) -> u64 {
-        *self.count.read().await
+    pub fn get_count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
     }
     
     pub fn labels(&self) -> &HashMap<String, String> {
@@ -177,32 +316,35 @@ impl Histogram {
     }
 }
 
+/// Observes the time from its creation until [`observe_duration`](Self::observe_duration) is
+/// called, or until it's dropped.
 pub struct HistogramTimer {
     start: Instant,
-    histogram: Histogram,
+    histogram: Option<Histogram>,
 }
 
 impl HistogramTimer {
     pub fn new(histogram: Histogram) -> Self {
         Self {
             start: Instant::now(),
-            histogram,
+            histogram: Some(histogram),
         }
     }
     
-    pub async fn observe_duration(&self) {
-        let duration = self.start.elapsed().as_secs_f64();
-        self.histogram.observe(duration).await;
+    pub fn observe_duration(mut self) {
+        self.observe();
+    }
+    
+    fn observe(&mut self) {
+        if let Some(histogram) = self.histogram.take() {
+            histogram.observe(self.start.elapsed().as_secs_f64());
+        }
     }
 }
 
 impl Drop for HistogramTimer {
     fn drop(&mut self) {
-        let duration = self.start.elapsed().as_secs_f64();
-        let histogram = self.histogram.clone();
-        tokio::spawn(async move {
-            histogram.observe(duration).await;
-        });
+        self.observe();
     }
 }
 
@@ -213,6 +355,11 @@ pub struct Metrics {
     pub messages_received: Counter,
     pub messages_failed: Counter,
     pub messages_latency: Histogram,
+    pub unsupported_messages: CounterVec,
+    /// Messages by `direction`, `type` and `result`.
+    pub messages_by_type: LabeledCounter,
+    /// Bridged messages by the Matrix `user` they were sent by or for, and `direction`.
+    pub user_messages: LabeledCounter,
     
     pub http_requests: Counter,
     pub http_errors: Counter,
@@ -220,6 +367,7 @@ pub struct Metrics {
     
     pub websocket_connections: Gauge,
     pub websocket_messages: Counter,
+    pub wechat_listener_up: Gauge,
     
     pub database_queries: Counter,
     pub database_errors: Counter,
@@ -245,6 +393,9 @@ impl Metrics {
             messages_received: Counter::new(),
             messages_failed: Counter::new(),
             messages_latency: Histogram::new(Histogram::default_buckets()),
+            unsupported_messages: CounterVec::new("type"),
+            messages_by_type: LabeledCounter::new(&["direction", "type", "result"]),
+            user_messages: LabeledCounter::new(&["user", "direction"]),
             
             http_requests: Counter::new(),
             http_errors: Counter::new(),
@@ -252,6 +403,7 @@ impl Metrics {
             
             websocket_connections: Gauge::new(),
             websocket_messages: Counter::new(),
+            wechat_listener_up: Gauge::new(),
             
             database_queries: Counter::new(),
             database_errors: Counter::new(),
@@ -270,72 +422,107 @@ impl Metrics {
         }
     }
     
-    pub async fn to_prometheus(&self) -> String {
+    /// Counts a message bridged, or that failed to be, in the global and labeled counters.
+    /// `user` is the Matrix user who sent it, or who received it from WeChat.
+    pub fn record_message(&self, direction: Direction, msg_type: &str, user: &str, success: bool) {
+        let result = if success { "success" } else { "failure" };
+        self.messages_by_type.inc(&[direction.as_str(), msg_type, result]);
+        if !success {
+            self.messages_failed.inc();
+            return;
+        }
+        self.messages_bridged.inc();
+        match direction {
+            Direction::MatrixToWechat => self.messages_sent.inc(),
+            Direction::WechatToMatrix => self.messages_received.inc(),
+        }
+        if !user.is_empty() {
+            self.user_messages.inc(&[user, direction.as_str()]);
+        }
+    }
+    
+    pub fn to_prometheus(&self) -> String {
         let mut output = String::new();
         
         output.push_str("# HELP bridge_messages_bridged Total number of messages bridged\n");
         output.push_str("# TYPE bridge_messages_bridged counter\n");
-        output.push_str(&format!("bridge_messages_bridged {}\n", self.messages_bridged.get().await));
+        output.push_str(&format!("bridge_messages_bridged {}\n", self.messages_bridged.get()));
         
         output.push_str("# HELP bridge_messages_sent Total number of messages sent\n");
         output.push_str("# TYPE bridge_messages_sent counter\n");
-        output.push_str(&format!("bridge_messages_sent {}\n", self.messages_sent.get().await));
+        output.push_str(&format!("bridge_messages_sent {}\n", self.messages_sent.get()));
         
         output.push_str("# HELP bridge_messages_received Total number of messages received\n");
         output.push_str("# TYPE bridge_messages_received counter\n");
-        output.push_str(&format!("bridge_messages_received {}\n", self.messages_received.get().await));
+        output.push_str(&format!("bridge_messages_received {}\n", self.messages_received.get()));
         
         output.push_str("# HELP bridge_messages_failed Total number of messages failed\n");
         output.push_str("# TYPE bridge_messages_failed counter\n");
-        output.push_str(&format!("bridge_messages_failed {}\n", self.messages_failed.get().await));
+        output.push_str(&format!("bridge_messages_failed {}\n", self.messages_failed.get()));
+        
+        output.push_str("# HELP bridge_unsupported_messages Total number of WeChat messages of unsupported types\n");
+        output.push_str("# TYPE bridge_unsupported_messages counter\n");
+        output.push_str(&self.unsupported_messages.to_prometheus("bridge_unsupported_messages"));
+        
+        output.push_str("# HELP bridge_messages_by_type Total number of messages by direction, type and result\n");
+        output.push_str("# TYPE bridge_messages_by_type counter\n");
+        output.push_str(&self.messages_by_type.to_prometheus("bridge_messages_by_type"));
+        
+        output.push_str("# HELP bridge_user_messages Total number of messages bridged per user and direction\n");
+        output.push_str("# TYPE bridge_user_messages counter\n");
+        output.push_str(&self.user_messages.to_prometheus("bridge_user_messages"));
         
         output.push_str("# HELP bridge_http_requests Total number of HTTP requests\n");
         output.push_str("# TYPE bridge_http_requests counter\n");
-        output.push_str(&format!("bridge_http_requests {}\n", self.http_requests.get().await));
+        output.push_str(&format!("bridge_http_requests {}\n", self.http_requests.get()));
         
         output.push_str("# HELP bridge_http_errors Total number of HTTP errors\n");
         output.push_str("# TYPE bridge_http_errors counter\n");
-        output.push_str(&format!("bridge_http_errors {}\n", self.http_errors.get().await));
+        output.push_str(&format!("bridge_http_errors {}\n", self.http_errors.get()));
         
         output.push_str("# HELP bridge_websocket_connections Current number of WebSocket connections\n");
         output.push_str("# TYPE bridge_websocket_connections gauge\n");
-        output.push_str(&format!("bridge_websocket_connections {}\n", self.websocket_connections.get().await));
+        output.push_str(&format!("bridge_websocket_connections {}\n", self.websocket_connections.get()));
+        
+        output.push_str("# HELP bridge_wechat_listener_up Whether the WeChat agent listener is bound (1) or not (0)\n");
+        output.push_str("# TYPE bridge_wechat_listener_up gauge\n");
+        output.push_str(&format!("bridge_wechat_listener_up {}\n", self.wechat_listener_up.get()));
         
         output.push_str("# HELP bridge_database_queries Total number of database queries\n");
         output.push_str("# TYPE bridge_database_queries counter\n");
-        output.push_str(&format!("bridge_database_queries {}\n", self.database_queries.get().await));
+        output.push_str(&format!("bridge_database_queries {}\n", self.database_queries.get()));
         
         output.push_str("# HELP bridge_database_errors Total number of database errors\n");
         output.push_str("# TYPE bridge_database_errors counter\n");
-        output.push_str(&format!("bridge_database_errors {}\n", self.database_errors.get().await));
+        output.push_str(&format!("bridge_database_errors {}\n", self.database_errors.get()));
         
         output.push_str("# HELP bridge_active_users Current number of active users\n");
         output.push_str("# TYPE bridge_active_users gauge\n");
-        output.push_str(&format!("bridge_active_users {}\n", self.active_users.get().await));
+        output.push_str(&format!("bridge_active_users {}\n", self.active_users.get()));
         
         output.push_str("# HELP bridge_active_portals Current number of active portals\n");
         output.push_str("# TYPE bridge_active_portals gauge\n");
-        output.push_str(&format!("bridge_active_portals {}\n", self.active_portals.get().await));
+        output.push_str(&format!("bridge_active_portals {}\n", self.active_portals.get()));
         
         output.push_str("# HELP bridge_active_puppets Current number of active puppets\n");
         output.push_str("# TYPE bridge_active_puppets gauge\n");
-        output.push_str(&format!("bridge_active_puppets {}\n", self.active_puppets.get().await));
+        output.push_str(&format!("bridge_active_puppets {}\n", self.active_puppets.get()));
         
         output.push_str("# HELP bridge_encryption_operations Total number of encryption operations\n");
         output.push_str("# TYPE bridge_encryption_operations counter\n");
-        output.push_str(&format!("bridge_encryption_operations {}\n", self.encryption_operations.get().await));
+        output.push_str(&format!("bridge_encryption_operations {}\n", self.encryption_operations.get()));
         
         output.push_str("# HELP bridge_encryption_errors Total number of encryption errors\n");
         output.push_str("# TYPE bridge_encryption_errors counter\n");
-        output.push_str(&format!("bridge_encryption_errors {}\n", self.encryption_errors.get().await));
+        output.push_str(&format!("bridge_encryption_errors {}\n", self.encryption_errors.get()));
         
         output.push_str("# HELP bridge_reconnection_attempts Total number of reconnection attempts\n");
         output.push_str("# TYPE bridge_reconnection_attempts counter\n");
-        output.push_str(&format!("bridge_reconnection_attempts {}\n", self.reconnection_attempts.get().await));
+        output.push_str(&format!("bridge_reconnection_attempts {}\n", self.reconnection_attempts.get()));
         
         output.push_str("# HELP bridge_reconnection_success Total number of successful reconnections\n");
         output.push_str("# TYPE bridge_reconnection_success counter\n");
-        output.push_str(&format!("bridge_reconnection_success {}\n", self.reconnection_success.get().await));
+        output.push_str(&format!("bridge_reconnection_success {}\n", self.reconnection_success.get()));
         
         output
     }
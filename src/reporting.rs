@@ -0,0 +1,72 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::config::SentryConfig;
+
+#[cfg_attr(not(feature = "sentry"), allow(dead_code))]
+static SECRET_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?i)(access_token|as_token|hs_token|listen_secret|password|secret)([=:]\s*"?)[^&\s"]+"#).unwrap()
+});
+
+/// Redacts tokens and secrets from text that is about to leave the bridge.
+#[cfg_attr(not(feature = "sentry"), allow(dead_code))]
+pub fn scrub(text: &str) -> String {
+    SECRET_REGEX.replace_all(text, "${1}${2}[redacted]").into_owned()
+}
+
+/// Keeps error reporting active for as long as it is held.
+pub struct ReportGuard {
+    #[cfg(feature = "sentry")]
+    _guard: Option<sentry::ClientInitGuard>,
+}
+
+#[cfg(feature = "sentry")]
+pub fn init(config: &SentryConfig) -> ReportGuard {
+    let Some(dsn) = config.dsn.as_deref().filter(|dsn| !dsn.is_empty()) else {
+        return ReportGuard { _guard: None };
+    };
+
+    let guard = sentry::init((
+        dsn,
+        sentry::ClientOptions {
+            release: sentry::release_name!(),
+            environment: config.environment.clone().map(Into::into),
+            sample_rate: config.sample_rate,
+            send_default_pii: false,
+            before_send: Some(std::sync::Arc::new(|mut event| {
+                scrub_event(&mut event);
+                Some(event)
+            })),
+            ..Default::default()
+        },
+    ));
+    tracing::info!("Error reporting enabled");
+    ReportGuard { _guard: Some(guard) }
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn init(config: &SentryConfig) -> ReportGuard {
+    if config.dsn.as_deref().is_some_and(|dsn| !dsn.is_empty()) {
+        tracing::warn!("logging.sentry.dsn is set, but the bridge was built without the sentry feature");
+    }
+    ReportGuard {}
+}
+
+#[cfg(feature = "sentry")]
+fn scrub_event(event: &mut sentry::protocol::Event<'static>) {
+    if let Some(message) = &mut event.message {
+        *message = scrub(message);
+    }
+    for exception in event.exception.values.iter_mut() {
+        if let Some(value) = &mut exception.value {
+            *value = scrub(value);
+        }
+    }
+    for breadcrumb in event.breadcrumbs.values.iter_mut() {
+        if let Some(message) = &mut breadcrumb.message {
+            *message = scrub(message);
+        }
+    }
+    event.request = None;
+    event.user = None;
+}
@@ -0,0 +1,160 @@
+use std::process::Stdio;
+
+use anyhow::{Result, anyhow};
+use tokio::process::Command;
+
+use super::{FFMPEG, TempFile, run_piped};
+
+// From https://github.com/kn007/silk-v3-decoder, which isn't packaged; see the README.
+const SILK_DECODER: &str = "silk_v3_decoder";
+const SILK_ENCODER: &str = "silk_v3_encoder";
+
+/// WeChat voice notes are mono 24kHz; PCM is used as the intermediate format.
+const PCM_SAMPLE_RATE: u64 = 24000;
+const PCM_BYTES_PER_SECOND: u64 = PCM_SAMPLE_RATE * 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Silk,
+    Amr,
+    Ogg,
+    Unknown,
+}
+
+impl AudioFormat {
+    pub fn mimetype(&self) -> &'static str {
+        match self {
+            Self::Silk => "audio/silk",
+            Self::Amr => "audio/amr",
+            Self::Ogg => "audio/ogg",
+            Self::Unknown => "application/octet-stream",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Silk => "silk",
+            Self::Amr => "amr",
+            Self::Ogg => "ogg",
+            Self::Unknown => "bin",
+        }
+    }
+}
+
+pub fn detect_format(data: &[u8]) -> AudioFormat {
+    // WeChat prefixes the SILK header with an extra 0x02 byte.
+    let data = data.strip_prefix(&[0x02]).unwrap_or(data);
+    if data.starts_with(b"#!SILK_V3") {
+        AudioFormat::Silk
+    } else if data.starts_with(b"#!AMR") {
+        AudioFormat::Amr
+    } else if data.starts_with(b"OggS") {
+        AudioFormat::Ogg
+    } else {
+        AudioFormat::Unknown
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConvertedAudio {
+    pub data: Vec<u8>,
+    pub format: AudioFormat,
+    pub duration_ms: u64,
+}
+
+/// Converts a WeChat voice note (SILK or AMR) to OGG Opus.
+pub async fn wechat_to_matrix(data: &[u8]) -> Result<ConvertedAudio> {
+    let pcm = match detect_format(data) {
+        AudioFormat::Silk => silk_to_pcm(data).await?,
+        _ => ffmpeg(data, &["-f", "s16le", "-ar", "24000", "-ac", "1"]).await?,
+    };
+    let ogg = ffmpeg_from_pcm(&pcm, &["-c:a", "libopus", "-b:a", "32k", "-f", "ogg"]).await?;
+
+    Ok(ConvertedAudio {
+        data: ogg,
+        format: AudioFormat::Ogg,
+        duration_ms: pcm_duration_ms(&pcm),
+    })
+}
+
+/// Converts a Matrix voice message to SILK, which WeChat plays as a voice note.
+pub async fn matrix_to_wechat(data: &[u8]) -> Result<ConvertedAudio> {
+    if detect_format(data) == AudioFormat::Silk {
+        return Ok(ConvertedAudio {
+            data: data.to_vec(),
+            format: AudioFormat::Silk,
+            duration_ms: 0,
+        });
+    }
+
+    let pcm = ffmpeg(data, &["-f", "s16le", "-ar", "24000", "-ac", "1"]).await?;
+    let silk = pcm_to_silk(&pcm).await?;
+
+    Ok(ConvertedAudio {
+        data: silk,
+        format: AudioFormat::Silk,
+        duration_ms: pcm_duration_ms(&pcm),
+    })
+}
+
+/// The length of mono 16-bit PCM at the sample rate used for voice notes.
+pub fn pcm_duration_ms(pcm: &[u8]) -> u64 {
+    pcm.len() as u64 * 1000 / PCM_BYTES_PER_SECOND
+}
+
+async fn ffmpeg(input: &[u8], output_args: &[&str]) -> Result<Vec<u8>> {
+    let mut args = vec!["-hide_banner", "-loglevel", "error", "-i", "pipe:0"];
+    args.extend_from_slice(output_args);
+    args.push("pipe:1");
+    run_piped(FFMPEG, &args, input).await
+}
+
+async fn ffmpeg_from_pcm(pcm: &[u8], output_args: &[&str]) -> Result<Vec<u8>> {
+    let mut args = vec![
+        "-hide_banner", "-loglevel", "error",
+        "-f", "s16le", "-ar", "24000", "-ac", "1", "-i", "pipe:0",
+    ];
+    args.extend_from_slice(output_args);
+    args.push("pipe:1");
+    run_piped(FFMPEG, &args, pcm).await
+}
+
+async fn silk_to_pcm(silk: &[u8]) -> Result<Vec<u8>> {
+    let input = TempFile::new("silk");
+    let output = TempFile::new("pcm");
+    tokio::fs::write(&input.0, silk).await?;
+
+    let status = Command::new(SILK_DECODER)
+        .arg(&input.0)
+        .arg(&output.0)
+        .args(["-Fs_API", "24000", "-quiet"])
+        .stdout(Stdio::null())
+        .status()
+        .await
+        .map_err(|e| anyhow!("failed to run {}: {}", SILK_DECODER, e))?;
+    if !status.success() {
+        return Err(anyhow!("{} exited with {}", SILK_DECODER, status));
+    }
+
+    Ok(tokio::fs::read(&output.0).await?)
+}
+
+async fn pcm_to_silk(pcm: &[u8]) -> Result<Vec<u8>> {
+    let input = TempFile::new("pcm");
+    let output = TempFile::new("silk");
+    tokio::fs::write(&input.0, pcm).await?;
+
+    let status = Command::new(SILK_ENCODER)
+        .arg(&input.0)
+        .arg(&output.0)
+        .args(["-Fs_API", "24000", "-tencent", "-quiet"])
+        .stdout(Stdio::null())
+        .status()
+        .await
+        .map_err(|e| anyhow!("failed to run {}: {}", SILK_ENCODER, e))?;
+    if !status.success() {
+        return Err(anyhow!("{} exited with {}", SILK_ENCODER, status));
+    }
+
+    Ok(tokio::fs::read(&output.0).await?)
+}
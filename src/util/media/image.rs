@@ -0,0 +1,245 @@
+use anyhow::Result;
+
+use super::{FFMPEG, run_piped};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Gif,
+    Png,
+    Jpeg,
+    Webp,
+    Avif,
+    Heic,
+    Unknown,
+}
+
+impl ImageFormat {
+    pub fn mimetype(&self) -> &'static str {
+        match self {
+            Self::Gif => "image/gif",
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::Webp => "image/webp",
+            Self::Avif => "image/avif",
+            Self::Heic => "image/heic",
+            Self::Unknown => "application/octet-stream",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Gif => "gif",
+            Self::Png => "png",
+            Self::Jpeg => "jpg",
+            Self::Webp => "webp",
+            Self::Avif => "avif",
+            Self::Heic => "heic",
+            Self::Unknown => "bin",
+        }
+    }
+}
+
+pub fn detect_format(data: &[u8]) -> ImageFormat {
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        ImageFormat::Gif
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        ImageFormat::Png
+    } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        ImageFormat::Jpeg
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        ImageFormat::Webp
+    } else if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        // ISO base media files, told apart by their major brand.
+        match &data[8..12] {
+            b"avif" | b"avis" => ImageFormat::Avif,
+            b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" | b"mif1" | b"msf1" => ImageFormat::Heic,
+            _ => ImageFormat::Unknown,
+        }
+    } else {
+        ImageFormat::Unknown
+    }
+}
+
+/// Reads the width and height from the image header without decoding it.
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    match detect_format(data) {
+        ImageFormat::Gif => {
+            let w = u16::from_le_bytes([*data.get(6)?, *data.get(7)?]);
+            let h = u16::from_le_bytes([*data.get(8)?, *data.get(9)?]);
+            Some((w as u32, h as u32))
+        }
+        ImageFormat::Png => {
+            let w = u32::from_be_bytes(data.get(16..20)?.try_into().ok()?);
+            let h = u32::from_be_bytes(data.get(20..24)?.try_into().ok()?);
+            Some((w, h))
+        }
+        ImageFormat::Jpeg => jpeg_dimensions(data),
+        ImageFormat::Webp => webp_dimensions(data),
+        ImageFormat::Avif | ImageFormat::Heic | ImageFormat::Unknown => None,
+    }
+}
+
+fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let mut i = 2;
+    while i + 9 < data.len() {
+        if data[i] != 0xff {
+            i += 1;
+            continue;
+        }
+        let marker = data[i + 1];
+        let len = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+        // SOF0..SOF15, except DHT (C4), JPG (C8) and DAC (CC).
+        if (0xc0..=0xcf).contains(&marker) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+            let h = u16::from_be_bytes([data[i + 5], data[i + 6]]);
+            let w = u16::from_be_bytes([data[i + 7], data[i + 8]]);
+            return Some((w as u32, h as u32));
+        }
+        i += 2 + len;
+    }
+    None
+}
+
+fn webp_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let chunk = data.get(12..16)?;
+    match chunk {
+        b"VP8X" => {
+            let b = data.get(24..30)?;
+            let w = u32::from_le_bytes([b[0], b[1], b[2], 0]) + 1;
+            let h = u32::from_le_bytes([b[3], b[4], b[5], 0]) + 1;
+            Some((w, h))
+        }
+        b"VP8 " => {
+            let b = data.get(26..30)?;
+            let w = u16::from_le_bytes([b[0], b[1]]) & 0x3fff;
+            let h = u16::from_le_bytes([b[2], b[3]]) & 0x3fff;
+            Some((w as u32, h as u32))
+        }
+        b"VP8L" => {
+            let b = data.get(21..25)?;
+            let bits = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+            Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConvertedImage {
+    pub data: Vec<u8>,
+    pub format: ImageFormat,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl ConvertedImage {
+    pub fn from_data(data: Vec<u8>) -> Self {
+        let format = detect_format(&data);
+        let (width, height) = dimensions(&data).unzip();
+        Self { data, format, width, height }
+    }
+}
+
+/// Prepares a WeChat emoji for use as a Matrix sticker, turning GIFs into animated WebP.
+pub async fn sticker_to_matrix(data: &[u8]) -> Result<ConvertedImage> {
+    if detect_format(data) != ImageFormat::Gif {
+        return Ok(ConvertedImage::from_data(data.to_vec()));
+    }
+
+    let webp = run_piped(
+        FFMPEG,
+        &[
+            "-hide_banner", "-loglevel", "error",
+            "-f", "gif", "-i", "pipe:0",
+            "-c:v", "libwebp", "-lossless", "0", "-q:v", "80", "-loop", "0",
+            "-f", "webp", "pipe:1",
+        ],
+        data,
+    )
+    .await?;
+    Ok(ConvertedImage::from_data(webp))
+}
+
+/// Converts a Matrix sticker to a GIF, the only animated format WeChat emoji accept.
+pub async fn sticker_to_wechat(data: &[u8]) -> Result<ConvertedImage> {
+    if matches!(detect_format(data), ImageFormat::Gif | ImageFormat::Png | ImageFormat::Jpeg) {
+        return Ok(ConvertedImage::from_data(data.to_vec()));
+    }
+
+    let gif = run_piped(
+        FFMPEG,
+        &[
+            "-hide_banner", "-loglevel", "error",
+            "-i", "pipe:0",
+            "-filter_complex", "[0:v]split[a][b];[a]palettegen=reserve_transparent=1[p];[b][p]paletteuse",
+            "-loop", "0",
+            "-f", "gif", "pipe:1",
+        ],
+        data,
+    )
+    .await?;
+    Ok(ConvertedImage::from_data(gif))
+}
+
+/// The format an image has to be converted to before WeChat accepts it, if any. WeChat takes
+/// JPEG, PNG and GIF up to `max_size` pixels wide and high; WebP, which may be transparent,
+/// becomes PNG and the rest JPEG. `None` for `max_size` is no limit.
+pub fn wechat_image_format(format: ImageFormat, dimensions: Option<(u32, u32)>, max_size: Option<u32>) -> Option<ImageFormat> {
+    let too_large = max_size.zip(dimensions).is_some_and(|(max, (w, h))| w.max(h) > max);
+    match format {
+        ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Gif => too_large.then_some(format),
+        ImageFormat::Webp => Some(ImageFormat::Png),
+        ImageFormat::Avif | ImageFormat::Heic | ImageFormat::Unknown => Some(ImageFormat::Jpeg),
+    }
+}
+
+/// Converts an image WeChat wouldn't accept, see [`wechat_image_format`], scaling it down to
+/// fit within `max_size`.
+pub async fn image_to_wechat(data: &[u8], max_size: Option<u32>) -> Result<ConvertedImage> {
+    let format = detect_format(data);
+    let Some(target) = wechat_image_format(format, dimensions(data), max_size) else {
+        return Ok(ConvertedImage::from_data(data.to_vec()));
+    };
+
+    let scale = match max_size {
+        Some(size) => format!("scale='min({size},iw)':'min({size},ih)':force_original_aspect_ratio=decrease"),
+        None => "null".to_string(),
+    };
+    let gif_filter = format!("[0:v]{}[s];[s]split[a][b];[a]palettegen=reserve_transparent=1[p];[b][p]paletteuse", scale);
+    let mut args = vec!["-hide_banner", "-loglevel", "error", "-i", "pipe:0"];
+    match target {
+        ImageFormat::Gif => args.extend(["-filter_complex", &gif_filter, "-loop", "0", "-f", "gif"]),
+        ImageFormat::Png => args.extend(["-vf", &scale, "-frames:v", "1", "-c:v", "png", "-f", "image2"]),
+        _ => args.extend(["-vf", &scale, "-frames:v", "1", "-c:v", "mjpeg", "-q:v", "3", "-f", "image2"]),
+    }
+    args.push("pipe:1");
+    let converted = run_piped(FFMPEG, &args, data).await?;
+    Ok(ConvertedImage::from_data(converted))
+}
+
+/// Largest width or height of generated thumbnails.
+pub const THUMBNAIL_SIZE: u32 = 800;
+
+/// Scales an image down to fit within [`THUMBNAIL_SIZE`], encoded as JPEG.
+pub async fn thumbnail(data: &[u8]) -> Result<ConvertedImage> {
+    let scale = thumbnail_scale_filter();
+    let jpeg = run_piped(
+        FFMPEG,
+        &[
+            "-hide_banner", "-loglevel", "error",
+            "-i", "pipe:0",
+            "-vf", &scale, "-frames:v", "1",
+            "-c:v", "mjpeg", "-q:v", "4",
+            "-f", "image2", "pipe:1",
+        ],
+        data,
+    )
+    .await?;
+    Ok(ConvertedImage::from_data(jpeg))
+}
+
+pub(super) fn thumbnail_scale_filter() -> String {
+    format!(
+        "scale='min({size},iw)':'min({size},ih)':force_original_aspect_ratio=decrease",
+        size = THUMBNAIL_SIZE
+    )
+}
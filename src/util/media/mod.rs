@@ -0,0 +1,62 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Result, anyhow};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+pub mod audio;
+pub mod image;
+pub mod video;
+
+const FFMPEG: &str = "ffmpeg";
+const FFPROBE: &str = "ffprobe";
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub(super) async fn run_piped(program: &str, args: &[&str], input: &[u8]) -> Result<Vec<u8>> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("failed to run {}: {}", program, e))?;
+
+    let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("no stdin for {}", program))?;
+    let input = input.to_vec();
+    let writer = tokio::spawn(async move {
+        let _ = stdin.write_all(&input).await;
+    });
+
+    let output = child.wait_with_output().await?;
+    let _ = writer.await;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(output.stdout)
+}
+
+/// A file in the temp directory that is removed when dropped, for tools that can't read from a pipe.
+pub(super) struct TempFile(pub(super) PathBuf);
+
+impl TempFile {
+    pub(super) fn new(extension: &str) -> Self {
+        let id = TEMP_COUNTER.fetch_add(1, Ordering::SeqCst);
+        let name = format!("matrix-wechat-{}-{}.{}", std::process::id(), id, extension);
+        Self(std::env::temp_dir().join(name))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
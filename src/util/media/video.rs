@@ -0,0 +1,86 @@
+use anyhow::{Result, anyhow};
+use tokio::process::Command;
+
+use super::image::{ConvertedImage, thumbnail_scale_filter};
+use super::{FFMPEG, FFPROBE, TempFile};
+
+#[derive(Debug, Clone, Default)]
+pub struct VideoInfo {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub duration_ms: Option<u64>,
+}
+
+/// Reads the dimensions and duration of a video with ffprobe.
+pub async fn probe(data: &[u8]) -> Result<VideoInfo> {
+    // MP4 files often keep their index at the end, so ffprobe needs a seekable file.
+    let input = TempFile::new("mp4");
+    tokio::fs::write(&input.0, data).await?;
+
+    let output = Command::new(FFPROBE)
+        .args([
+            "-v", "error",
+            "-select_streams", "v:0",
+            "-show_entries", "stream=width,height:format=duration",
+            "-of", "json",
+        ])
+        .arg(&input.0)
+        .output()
+        .await
+        .map_err(|e| anyhow!("failed to run {}: {}", FFPROBE, e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} exited with {}: {}",
+            FFPROBE,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let probe: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    Ok(parse_probe(&probe))
+}
+
+fn parse_probe(probe: &serde_json::Value) -> VideoInfo {
+    let stream = probe.get("streams").and_then(|s| s.get(0));
+    let dimension = |key: &str| {
+        stream
+            .and_then(|s| s.get(key))
+            .and_then(|v| v.as_u64())
+            .map(|v| v as u32)
+    };
+    // ffprobe reports the duration in seconds as a string.
+    let duration_ms = probe.get("format")
+        .and_then(|f| f.get("duration"))
+        .and_then(|v| v.as_str())
+        .and_then(|v| v.parse::<f64>().ok())
+        .map(|secs| (secs * 1000.0) as u64);
+
+    VideoInfo {
+        width: dimension("width"),
+        height: dimension("height"),
+        duration_ms,
+    }
+}
+
+/// Grabs the first frame of a video as a JPEG thumbnail.
+pub async fn thumbnail(data: &[u8]) -> Result<ConvertedImage> {
+    let input = TempFile::new("mp4");
+    let output = TempFile::new("jpg");
+    tokio::fs::write(&input.0, data).await?;
+
+    let scale = thumbnail_scale_filter();
+    let status = Command::new(FFMPEG)
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(&input.0)
+        .args(["-vf", &scale, "-frames:v", "1", "-c:v", "mjpeg", "-q:v", "4", "-f", "image2"])
+        .arg(&output.0)
+        .status()
+        .await
+        .map_err(|e| anyhow!("failed to run {}: {}", FFMPEG, e))?;
+    if !status.success() {
+        return Err(anyhow!("{} exited with {}", FFMPEG, status));
+    }
+
+    Ok(ConvertedImage::from_data(tokio::fs::read(&output.0).await?))
+}
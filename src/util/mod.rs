@@ -2,6 +2,7 @@ mod uid;
 mod contact;
 pub mod retry;
 pub mod perf;
+pub mod media;
 
 pub use uid::*;
 pub use contact::*;
@@ -0,0 +1,102 @@
+use std::sync::Arc;
+
+use salvo::prelude::*;
+use serde_json::json;
+use subtle::ConstantTimeEq;
+use tracing::warn;
+
+use crate::bridge::WechatBridge;
+use crate::config::DebugApiConfig;
+
+const DEFAULT_LIMIT: usize = 20;
+
+fn render_error(res: &mut Response, status: StatusCode, message: &str) {
+    res.status_code(status);
+    res.render(Json(json!({ "error": message })));
+}
+
+fn get_bridge(depot: &Depot, res: &mut Response) -> Option<Arc<WechatBridge>> {
+    match depot.get::<Arc<WechatBridge>>("bridge") {
+        Ok(b) => Some(b.clone()),
+        Err(_) => {
+            render_error(res, StatusCode::INTERNAL_SERVER_ERROR, "bridge not available");
+            None
+        }
+    }
+}
+
+/// Routes of the debug API, served under `/_matrix/app/v1/debug`.
+pub fn router(config: &DebugApiConfig) -> Router {
+    Router::with_path("/_matrix/app/v1/debug")
+        .hoop(DebugAuth { shared_secret: config.shared_secret.clone() })
+        .push(Router::with_path("events").get(list_events))
+        .push(Router::with_path("events/{id}/redeliver").post(redeliver_event))
+        .push(Router::with_path("transactions").get(list_transactions))
+        .push(Router::with_path("pending_requests").get(list_pending_requests))
+}
+
+struct DebugAuth {
+    shared_secret: String,
+}
+
+#[async_trait::async_trait]
+impl Handler for DebugAuth {
+    async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+        let auth = req.header::<String>("Authorization");
+        let authorized = auth
+            .as_deref()
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .is_some_and(|token| bool::from(token.as_bytes().ct_eq(self.shared_secret.as_bytes())));
+        if !authorized {
+            render_error(res, StatusCode::UNAUTHORIZED, "invalid or missing shared secret");
+            ctrl.skip_rest();
+            return;
+        }
+        ctrl.call_next(req, depot, res).await;
+    }
+}
+
+/// The last inbound WeChat events, newest first, at most `limit`.
+#[handler]
+pub async fn list_events(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let Some(bridge) = get_bridge(depot, res) else {
+        return;
+    };
+    let limit = req.query::<usize>("limit").unwrap_or(DEFAULT_LIMIT);
+    res.render(Json(json!({ "events": bridge.debug_log.events(limit).await })));
+}
+
+/// The last transactions from the homeserver, newest first, at most `limit`.
+#[handler]
+pub async fn list_transactions(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let Some(bridge) = get_bridge(depot, res) else {
+        return;
+    };
+    let limit = req.query::<usize>("limit").unwrap_or(DEFAULT_LIMIT);
+    res.render(Json(json!({ "transactions": bridge.debug_log.transactions(limit).await })));
+}
+
+#[handler]
+pub async fn redeliver_event(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let Some(bridge) = get_bridge(depot, res) else {
+        return;
+    };
+    let id = req.param::<String>("id").unwrap_or_default();
+    match bridge.redeliver_event(&id).await {
+        Ok(true) => res.render(Json(json!({ "redelivered": true }))),
+        Ok(false) => render_error(res, StatusCode::NOT_FOUND, "event isn't among the recent events"),
+        Err(e) => {
+            warn!("Failed to re-deliver WeChat event {}: {}", id, e);
+            render_error(res, StatusCode::INTERNAL_SERVER_ERROR, &e.to_string());
+        }
+    }
+}
+
+/// The requests to agents that are waiting for a response.
+#[handler]
+pub async fn list_pending_requests(res: &mut Response, depot: &mut Depot) {
+    let Some(bridge) = get_bridge(depot, res) else {
+        return;
+    };
+    res.render(Json(json!({ "pending_requests": bridge.wechat_service.pending_requests().await })));
+}
@@ -1,6 +1,9 @@
 use salvo::prelude::*;
 use serde_json::json;
 
+use std::sync::Arc;
+
+use crate::bridge::WechatBridge;
 use crate::web::web_state;
 
 #[handler]
@@ -9,18 +12,113 @@ pub async fn health_check(res: &mut Response) {
 }
 
 #[handler]
-pub async fn get_status(res: &mut Response) {
+pub async fn readiness_check(depot: &mut Depot, res: &mut Response) {
+    let listening = depot
+        .get::<Arc<WechatBridge>>("bridge")
+        .map(|bridge| bridge.wechat_service.is_listening())
+        .unwrap_or(false);
+
+    if !listening {
+        res.status_code(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    res.render(Json(json!({
+        "ready": listening,
+        "wechat_listener": if listening { "listening" } else { "down" },
+    })));
+}
+
+#[handler]
+pub async fn get_metrics(res: &mut Response) {
+    res.render(Text::Plain(crate::metrics::metrics().to_prometheus()));
+}
+
+/// Includes the last homeserver connectivity check, `null` before the first one, so a
+/// homeserver that can't reach the bridge can be told apart from the bridge being down.
+///
+/// Requests with the provisioning shared secret, as `Authorization: Bearer <secret>`, also
+/// get the state of the database, agents and users under `details`. Those details aren't public, as they
+/// include Matrix IDs and WeChat accounts.
+#[handler]
+pub async fn get_status(req: &mut Request, depot: &mut Depot, res: &mut Response) {
     let state = web_state();
     let uptime_seconds = state.started_at.elapsed().as_secs();
+    let bridge = depot.get::<Arc<WechatBridge>>("bridge").ok().cloned();
+    let homeserver = match &bridge {
+        Some(bridge) => bridge.homeserver_status.last().await,
+        None => None,
+    };
 
-    let status = json!({
+    let mut status = json!({
         "status": "running",
         "version": state.version,
         "uptime_seconds": uptime_seconds,
         "bridge": {
             "name": state.bridge_name,
-        }
+        },
+        "homeserver": homeserver,
     });
 
+    if let Some(bridge) = bridge
+        && let Some(token) = req.header::<String>("Authorization")
+    {
+        let provisioning = &bridge.config.bridge.provisioning;
+        let authorized = provisioning.enabled()
+            && token.strip_prefix("Bearer ").is_some_and(|token| token == provisioning.shared_secret);
+        if !authorized {
+            res.status_code(StatusCode::UNAUTHORIZED);
+            res.render(Json(json!({ "error": "invalid shared secret" })));
+            return;
+        }
+        status["details"] = status_details(&bridge).await;
+    }
+
     res.render(Json(status));
 }
+
+async fn status_details(bridge: &WechatBridge) -> serde_json::Value {
+    let database = match bridge.db.ping().await {
+        Ok(()) => json!({ "ok": true }),
+        Err(e) => json!({ "ok": false, "error": e.to_string() }),
+    };
+
+    let connections = bridge.wechat_service.list_connections().await;
+    let agents: Vec<_> = connections.iter()
+        .map(|conn| json!({
+            "address": conn.addr,
+            "token": conn.agent,
+            "protocol_version": conn.protocol_version,
+            "users": conn.users,
+            "last_seen": conn.last_seen / 1000,
+        }))
+        .collect();
+
+    let mut users = Vec::new();
+    if let Ok(db_users) = bridge.db.get_all_users().await {
+        for user in db_users {
+            let logged_in = user.uin.as_deref().is_some_and(|uin| !uin.is_empty());
+            let agent_connected = connections.iter().any(|c| c.users.contains(&user.mxid));
+            users.push(json!({
+                "mxid": user.mxid,
+                "logged_in": logged_in,
+                "wechat_id": user.uin,
+                "agent_connected": agent_connected,
+                "last_event": bridge.activity.get(&user.mxid).await,
+            }));
+        }
+    }
+
+    let (cached_users, cached_portals, cached_puppets) = bridge.cache_stats().await;
+    json!({
+        "database": database,
+        "agents": {
+            "count": agents.len(),
+            "connections": agents,
+        },
+        "cache": {
+            "users": cached_users,
+            "portals": cached_portals,
+            "puppets": cached_puppets,
+        },
+        "users": users,
+    })
+}
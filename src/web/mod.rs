@@ -1,16 +1,20 @@
+pub mod debug;
 pub mod health;
 pub mod provisioning;
 pub mod thirdparty;
+pub mod server;
 
 use std::sync::Arc;
 use std::time::Instant;
 
 use salvo::prelude::*;
-use tracing::info;
+use tracing::{debug, error, info};
 
 use crate::bridge::WechatBridge;
 use crate::matrix::AppService;
 
+pub use server::{BridgeServer, shutdown_signal};
+
 #[derive(Clone)]
 pub struct WebState {
     pub started_at: Instant,
@@ -54,14 +58,19 @@ pub fn create_appservice_router(bridge: Arc<WechatBridge>) -> Router {
         Arc::new((*bridge_for_appservice).clone()),
     ));
     
-    Router::new()
+    let provisioning_config = bridge.config.bridge.provisioning.clone();
+    let debug_api_config = bridge.config.bridge.debug_api.clone();
+
+    let mut router = Router::new()
         .hoop(BridgeHoop { bridge: bridge_for_hoop })
         .push(Router::with_path("/_matrix/app/v1/transactions/{txn_id}")
             .put(AppserviceTransactionHandler { appservice: appservice.clone() }))
         .push(Router::with_path("/_matrix/app/v1/users/{user_id}")
             .get(AppserviceUserHandler { appservice: appservice.clone() }))
         .push(Router::with_path("/_matrix/app/v1/rooms/{room_alias}")
-            .get(AppserviceRoomHandler { appservice }))
+            .get(AppserviceRoomHandler { appservice: appservice.clone() }))
+        .push(Router::with_path("/_matrix/app/v1/ping")
+            .post(AppservicePingHandler { appservice }))
         .push(Router::with_path("/_matrix/app/v1/thirdparty/protocol")
             .get(thirdparty::get_protocol))
         .push(Router::with_path("/_matrix/app/v1/thirdparty/protocol/wechat")
@@ -72,16 +81,46 @@ pub fn create_appservice_router(bridge: Arc<WechatBridge>) -> Router {
             .get(thirdparty::get_networks))
         .push(Router::with_path("/_matrix/app/v1/thirdparty/location")
             .get(thirdparty::get_locations))
+        .push(Router::with_path("/_matrix/app/v1/thirdparty/location/wechat")
+            .get(thirdparty::get_locations))
         .push(Router::with_path("/_matrix/app/v1/thirdparty/user")
             .get(thirdparty::get_users))
+        .push(Router::with_path("/_matrix/app/v1/thirdparty/user/wechat")
+            .get(thirdparty::get_users))
         .push(Router::with_path("/_matrix/app/v1/bridges")
             .get(provisioning::list_rooms)
             .post(provisioning::create_bridge))
         .push(Router::with_path("/_matrix/app/v1/bridge")
             .get(provisioning::get_bridge_info)
             .delete(provisioning::delete_bridge))
+        .push(Router::with_path("/_matrix/app/v1/bridge/by_room")
+            .get(provisioning::lookup_by_room))
+        .push(Router::with_path("/_matrix/app/v1/bridge/by_wechat")
+            .get(provisioning::lookup_by_wechat))
         .push(Router::with_path("/health").get(health::health_check))
-        .push(Router::with_path("/status").get(health::get_status))
+        .push(Router::with_path("/readyz").get(health::readiness_check))
+        .push(Router::with_path("/metrics").get(health::get_metrics))
+        .push(Router::with_path("/status").get(health::get_status));
+
+    if let Some(wecom) = &bridge.config.wecom {
+        match crate::wecom::CallbackHandler::new(wecom, bridge.wechat_service.clone()) {
+            Ok(handler) => {
+                info!("WeCom callback enabled at /wecom/v1/callback");
+                router = router.push(Router::with_path("/wecom/v1/callback").get(handler.clone()).post(handler));
+            }
+            Err(e) => error!("Failed to set up the WeCom callback: {}", e),
+        }
+    }
+    if debug_api_config.enabled() {
+        info!("Debug API enabled at /_matrix/app/v1/debug");
+        router = router.push(debug::router(&debug_api_config));
+    }
+    if provisioning_config.enabled() {
+        info!("Provisioning API enabled at {}/v1", provisioning_config.prefix);
+        router.push(provisioning::v1_router(&provisioning_config))
+    } else {
+        router
+    }
 }
 
 struct BridgeHoop {
@@ -102,14 +141,18 @@ struct AppserviceTransactionHandler {
 
 #[async_trait::async_trait]
 impl Handler for AppserviceTransactionHandler {
-    async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, _ctrl: &mut FlowCtrl) {
+    async fn handle(&self, req: &mut Request, _depot: &mut Depot, res: &mut Response, _ctrl: &mut FlowCtrl) {
         let auth = req.header::<String>("Authorization");
         if !self.verify_auth(&auth) {
             res.render(StatusError::unauthorized());
             return;
         }
+        if self.appservice.bridge.is_stopping() {
+            res.render(StatusError::service_unavailable());
+            return;
+        }
 
-        let txn_id = depot.get::<String>("txn_id").map(|s| s.as_str()).unwrap_or("");
+        let txn_id = req.param::<String>("txn_id").unwrap_or_default();
         
         let body: Result<crate::matrix::types::Transaction, _> = req.parse_json().await;
         let transaction = match body {
@@ -123,8 +166,16 @@ impl Handler for AppserviceTransactionHandler {
 
         info!("Received transaction {} with {} events", txn_id, transaction.events.len());
 
-        if let Err(e) = self.appservice.bridge.handle_transaction(txn_id, transaction.events).await {
-            info!("Error handling transaction: {}", e);
+        let mut events = transaction.events;
+        events.extend(transaction.ephemeral);
+        if let Err(e) = self.appservice.bridge.handle_transaction(&txn_id, events).await {
+            // The homeserver retries the transaction; already handled ones are skipped then.
+            error!("Error handling transaction {}: {}", txn_id, e);
+            res.render(StatusError::internal_server_error());
+            return;
+        }
+        if !transaction.to_device.is_empty() {
+            self.appservice.bridge.handle_to_device(transaction.to_device).await;
         }
 
         res.render(Json(serde_json::json!({})));
@@ -183,17 +234,24 @@ struct AppserviceRoomHandler {
 
 #[async_trait::async_trait]
 impl Handler for AppserviceRoomHandler {
-    async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, _ctrl: &mut FlowCtrl) {
+    async fn handle(&self, req: &mut Request, _depot: &mut Depot, res: &mut Response, _ctrl: &mut FlowCtrl) {
         let auth = req.header::<String>("Authorization");
         if !self.verify_auth(&auth) {
             res.render(StatusError::unauthorized());
             return;
         }
 
-        let room_alias = depot.get::<String>("room_alias").map(|s| s.as_str()).unwrap_or("");
+        let room_alias = req.param::<String>("room_alias").unwrap_or_default();
         info!("Room alias query: {}", room_alias);
-        
-        res.render(StatusError::not_found());
+
+        match self.appservice.bridge.query_room_alias(&room_alias).await {
+            Ok(true) => res.render(Json(serde_json::json!({}))),
+            Ok(false) => res.render(StatusError::not_found()),
+            Err(e) => {
+                error!("Failed to handle alias query for {}: {}", room_alias, e);
+                res.render(StatusError::not_found());
+            }
+        }
     }
 }
 
@@ -207,3 +265,33 @@ impl AppserviceRoomHandler {
         }
     }
 }
+
+/// Answers the homeserver's pings (MSC2659), which it sends when the bridge asks it to check
+/// that it can reach the appservice.
+struct AppservicePingHandler {
+    appservice: Arc<AppService>,
+}
+
+#[async_trait::async_trait]
+impl Handler for AppservicePingHandler {
+    async fn handle(&self, req: &mut Request, _depot: &mut Depot, res: &mut Response, _ctrl: &mut FlowCtrl) {
+        let auth = req.header::<String>("Authorization");
+        if !self.verify_auth(&auth) {
+            res.render(StatusError::unauthorized());
+            return;
+        }
+
+        let body: serde_json::Value = req.parse_json().await.unwrap_or_default();
+        debug!(
+            "Received ping from the homeserver (transaction {})",
+            body.get("transaction_id").and_then(|v| v.as_str()).unwrap_or("none")
+        );
+        res.render(Json(serde_json::json!({})));
+    }
+}
+
+impl AppservicePingHandler {
+    fn verify_auth(&self, auth: &Option<String>) -> bool {
+        auth.as_deref().and_then(|header| header.strip_prefix("Bearer ")) == Some(self.appservice.hs_token.as_str())
+    }
+}
@@ -1,7 +1,12 @@
+use std::sync::Arc;
+
+use base64::{Engine as _, engine::general_purpose::STANDARD};
 use salvo::prelude::*;
 use serde_json::json;
+use subtle::ConstantTimeEq;
 
 use crate::bridge::WechatBridge;
+use crate::config::ProvisioningConfig;
 use crate::database::PortalKey;
 
 fn render_error(res: &mut Response, status: StatusCode, message: &str) {
@@ -232,3 +237,450 @@ pub async fn get_bridge_info(req: &mut Request, res: &mut Response, depot: &mut
         }
     }
 }
+
+#[handler]
+pub async fn lookup_by_room(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let bridge = match depot.get::<std::sync::Arc<WechatBridge>>("bridge") {
+        Ok(b) => b.clone(),
+        Err(_) => {
+            render_error(res, StatusCode::INTERNAL_SERVER_ERROR, "bridge not available");
+            return;
+        }
+    };
+
+    let room_id = match req.query::<String>("room_id") {
+        Some(v) if !v.is_empty() => v,
+        _ => {
+            render_error(res, StatusCode::BAD_REQUEST, "missing room_id query parameter");
+            return;
+        }
+    };
+
+    match bridge.db.get_portal_by_mxid(&room_id).await {
+        Ok(Some(portal)) => {
+            res.render(Json(json!({
+                "uid": portal.uid,
+                "receiver": portal.receiver,
+                "mxid": portal.mxid,
+            })));
+        }
+        Ok(None) => {
+            render_error(res, StatusCode::NOT_FOUND, "portal not found");
+        }
+        Err(err) => {
+            render_error(
+                res,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("database error: {}", err),
+            );
+        }
+    }
+}
+
+#[handler]
+pub async fn lookup_by_wechat(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let bridge = match depot.get::<std::sync::Arc<WechatBridge>>("bridge") {
+        Ok(b) => b.clone(),
+        Err(_) => {
+            render_error(res, StatusCode::INTERNAL_SERVER_ERROR, "bridge not available");
+            return;
+        }
+    };
+
+    let uid = match req.query::<String>("uid") {
+        Some(v) if !v.is_empty() => v,
+        _ => {
+            render_error(res, StatusCode::BAD_REQUEST, "missing uid query parameter");
+            return;
+        }
+    };
+
+    match bridge.db.get_portals_by_uid(&uid).await {
+        Ok(portals) if portals.is_empty() => {
+            render_error(res, StatusCode::NOT_FOUND, "portal not found");
+        }
+        Ok(portals) => {
+            let rooms: Vec<serde_json::Value> = portals
+                .into_iter()
+                .map(|p| {
+                    json!({
+                        "uid": p.uid,
+                        "receiver": p.receiver,
+                        "mxid": p.mxid,
+                    })
+                })
+                .collect();
+            res.render(Json(json!({ "rooms": rooms })));
+        }
+        Err(err) => {
+            render_error(
+                res,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("database error: {}", err),
+            );
+        }
+    }
+}
+
+/// Routes of the versioned provisioning API, served under `<prefix>/v1`.
+pub fn v1_router(config: &ProvisioningConfig) -> Router {
+    Router::with_path(format!("{}/v1", config.prefix.trim_end_matches('/')))
+        .hoop(ProvisioningAuth { shared_secret: config.shared_secret.clone() })
+        .push(Router::with_path("ping").get(ping))
+        .push(Router::with_path("login").post(start_login))
+        .push(Router::with_path("login/status").get(login_status))
+        .push(Router::with_path("logout").post(logout))
+        .push(Router::with_path("contacts").get(list_contacts))
+        .push(Router::with_path("groups").get(list_groups))
+        .push(Router::with_path("resolve_identifier/{wxid}").get(resolve_identifier))
+        .push(Router::with_path("pm/{wxid}").post(start_pm))
+}
+
+struct ProvisioningAuth {
+    shared_secret: String,
+}
+
+#[async_trait::async_trait]
+impl Handler for ProvisioningAuth {
+    async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+        let auth = req.header::<String>("Authorization");
+        let authorized = auth
+            .as_deref()
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .is_some_and(|token| bool::from(token.as_bytes().ct_eq(self.shared_secret.as_bytes())));
+        if !authorized {
+            render_error(res, StatusCode::UNAUTHORIZED, "invalid or missing shared secret");
+            ctrl.skip_rest();
+            return;
+        }
+        ctrl.call_next(req, depot, res).await;
+    }
+}
+
+fn get_bridge(depot: &Depot, res: &mut Response) -> Option<Arc<WechatBridge>> {
+    match depot.get::<Arc<WechatBridge>>("bridge") {
+        Ok(b) => Some(b.clone()),
+        Err(_) => {
+            render_error(res, StatusCode::INTERNAL_SERVER_ERROR, "bridge not available");
+            None
+        }
+    }
+}
+
+/// Reads the `user_id` query parameter, which must belong to a user allowed to use the bridge.
+fn get_user_id(bridge: &WechatBridge, req: &mut Request, res: &mut Response) -> Option<String> {
+    match req.query::<String>("user_id") {
+        Some(v) if v.starts_with('@') && v.contains(':') => {
+            if bridge.get_permission(&v).can_use() {
+                Some(v)
+            } else {
+                render_error(res, StatusCode::FORBIDDEN, "user is not allowed to use the bridge");
+                None
+            }
+        }
+        _ => {
+            render_error(res, StatusCode::BAD_REQUEST, "missing or invalid user_id query parameter");
+            None
+        }
+    }
+}
+
+/// Returns the user's WeChat ID, or renders an error if they aren't logged in.
+async fn get_logged_in_uin(bridge: &WechatBridge, user_id: &str, res: &mut Response) -> Option<String> {
+    match bridge.get_user_by_mxid(user_id).await {
+        Ok(user) => match user.uin() {
+            Some(uin) => Some(uin.to_string()),
+            None => {
+                render_error(res, StatusCode::BAD_REQUEST, "not logged in");
+                None
+            }
+        },
+        Err(err) => {
+            render_error(res, StatusCode::INTERNAL_SERVER_ERROR, &format!("database error: {}", err));
+            None
+        }
+    }
+}
+
+/// Stores the WeChat account of a user whose QR login went through.
+async fn finish_login(bridge: &WechatBridge, user_id: &str) -> anyhow::Result<crate::wechat::UserInfo> {
+    let info = bridge.get_client(user_id).get_self().await?;
+    let user = bridge.get_user_by_mxid(user_id).await?;
+    if user.uin() != Some(info.id.as_str()) {
+        let mut user = user.as_ref().clone();
+        user.set_uin(&info.id).await?;
+    }
+    bridge.invalidate_user(user_id).await;
+    Ok(info)
+}
+
+fn render_logged_in(res: &mut Response, info: &crate::wechat::UserInfo) {
+    res.render(Json(json!({
+        "status": "logged_in",
+        "uin": info.id,
+        "name": info.name,
+    })));
+}
+
+#[handler]
+pub async fn ping(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let Some(bridge) = get_bridge(depot, res) else {
+        return;
+    };
+    let Some(user_id) = get_user_id(&bridge, req, res) else {
+        return;
+    };
+
+    match bridge.get_user_by_mxid(&user_id).await {
+        Ok(user) => {
+            let wechat = match user.uin() {
+                Some(uin) => json!({
+                    "uin": uin,
+                    "connected": bridge.get_client(&user_id).is_logged_in().await.unwrap_or(false),
+                }),
+                None => serde_json::Value::Null,
+            };
+            res.render(Json(json!({
+                "mxid": user.mxid,
+                "management_room": user.management_room(),
+                "space_room": user.space_room(),
+                "wechat": wechat,
+            })));
+        }
+        Err(err) => {
+            render_error(res, StatusCode::INTERNAL_SERVER_ERROR, &format!("database error: {}", err));
+        }
+    }
+}
+
+/// Starts a QR login, returning the QR code as base64 PNG. Poll `login/status` for the result.
+#[handler]
+pub async fn start_login(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let Some(bridge) = get_bridge(depot, res) else {
+        return;
+    };
+    let Some(user_id) = get_user_id(&bridge, req, res) else {
+        return;
+    };
+
+    match bridge.get_user_by_mxid(&user_id).await {
+        Ok(user) if user.uin().is_some() => {
+            render_error(res, StatusCode::CONFLICT, "already logged in");
+            return;
+        }
+        Ok(_) => {}
+        Err(err) => {
+            render_error(res, StatusCode::INTERNAL_SERVER_ERROR, &format!("database error: {}", err));
+            return;
+        }
+    }
+
+    let client = bridge.get_client(&user_id);
+    if let Err(err) = client.connect().await {
+        render_error(res, StatusCode::BAD_GATEWAY, &format!("failed to connect to WeChat: {}", err));
+        return;
+    }
+
+    match client.is_logged_in().await {
+        Ok(true) => match finish_login(&bridge, &user_id).await {
+            Ok(info) => render_logged_in(res, &info),
+            Err(err) => render_error(res, StatusCode::BAD_GATEWAY, &format!("{}", err)),
+        },
+        Ok(false) => match client.get_qrcode().await {
+            Ok(qrcode) => {
+                res.render(Json(json!({
+                    "status": "waiting",
+                    "qr": STANDARD.encode(&qrcode),
+                    "mimetype": "image/png",
+                })));
+            }
+            Err(err) => {
+                render_error(res, StatusCode::BAD_GATEWAY, &format!("failed to get QR code: {}", err));
+            }
+        },
+        Err(err) => {
+            render_error(res, StatusCode::BAD_GATEWAY, &format!("{}", err));
+        }
+    }
+}
+
+#[handler]
+pub async fn login_status(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let Some(bridge) = get_bridge(depot, res) else {
+        return;
+    };
+    let Some(user_id) = get_user_id(&bridge, req, res) else {
+        return;
+    };
+
+    match bridge.get_client(&user_id).is_logged_in().await {
+        Ok(true) => match finish_login(&bridge, &user_id).await {
+            Ok(info) => render_logged_in(res, &info),
+            Err(err) => render_error(res, StatusCode::BAD_GATEWAY, &format!("{}", err)),
+        },
+        Ok(false) => {
+            res.render(Json(json!({ "status": "waiting" })));
+        }
+        Err(err) => {
+            render_error(res, StatusCode::BAD_GATEWAY, &format!("{}", err));
+        }
+    }
+}
+
+#[handler]
+pub async fn logout(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let Some(bridge) = get_bridge(depot, res) else {
+        return;
+    };
+    let Some(user_id) = get_user_id(&bridge, req, res) else {
+        return;
+    };
+    if get_logged_in_uin(&bridge, &user_id, res).await.is_none() {
+        return;
+    }
+
+    if let Err(e) = bridge.get_client(&user_id).disconnect().await {
+        tracing::warn!("Failed to disconnect {}: {}", user_id, e);
+    }
+    let result = match bridge.get_user_by_mxid(&user_id).await {
+        Ok(user) => user.as_ref().clone().logout().await,
+        Err(err) => Err(err),
+    };
+    bridge.invalidate_user(&user_id).await;
+
+    match result {
+        Ok(()) => res.render(Json(json!({ "ok": true }))),
+        Err(err) => render_error(res, StatusCode::INTERNAL_SERVER_ERROR, &format!("{}", err)),
+    }
+}
+
+#[handler]
+pub async fn list_contacts(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let Some(bridge) = get_bridge(depot, res) else {
+        return;
+    };
+    let Some(user_id) = get_user_id(&bridge, req, res) else {
+        return;
+    };
+    if get_logged_in_uin(&bridge, &user_id, res).await.is_none() {
+        return;
+    }
+
+    match bridge.get_client(&user_id).get_friend_list().await {
+        Ok(friends) => {
+            let contacts: Vec<serde_json::Value> = friends
+                .into_iter()
+                .map(|f| {
+                    json!({
+                        "wxid": f.id,
+                        "name": f.name,
+                        "remark": f.remark,
+                        "avatar": f.avatar,
+                        "mxid": bridge.puppet_mxid(&f.id),
+                    })
+                })
+                .collect();
+            res.render(Json(json!({ "contacts": contacts })));
+        }
+        Err(err) => {
+            render_error(res, StatusCode::BAD_GATEWAY, &format!("failed to get contacts: {}", err));
+        }
+    }
+}
+
+#[handler]
+pub async fn list_groups(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let Some(bridge) = get_bridge(depot, res) else {
+        return;
+    };
+    let Some(user_id) = get_user_id(&bridge, req, res) else {
+        return;
+    };
+    if get_logged_in_uin(&bridge, &user_id, res).await.is_none() {
+        return;
+    }
+
+    let groups = match bridge.get_client(&user_id).get_group_list().await {
+        Ok(groups) => groups,
+        Err(err) => {
+            render_error(res, StatusCode::BAD_GATEWAY, &format!("failed to get groups: {}", err));
+            return;
+        }
+    };
+
+    let mut result = Vec::with_capacity(groups.len());
+    for group in groups {
+        let room_id = bridge.db.get_portals_by_uid(&group.id).await
+            .ok()
+            .and_then(|portals| portals.into_iter().find_map(|p| p.mxid));
+        result.push(json!({
+            "id": group.id,
+            "name": group.name,
+            "avatar": group.avatar,
+            "member_count": group.members.len(),
+            "room_id": room_id,
+        }));
+    }
+    res.render(Json(json!({ "groups": result })));
+}
+
+#[handler]
+pub async fn resolve_identifier(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let Some(bridge) = get_bridge(depot, res) else {
+        return;
+    };
+    let Some(user_id) = get_user_id(&bridge, req, res) else {
+        return;
+    };
+    let wxid = req.param::<String>("wxid").unwrap_or_default();
+    let Some(uin) = get_logged_in_uin(&bridge, &user_id, res).await else {
+        return;
+    };
+
+    match bridge.get_client(&user_id).get_user_info(&wxid).await {
+        Ok(info) => {
+            let key = PortalKey::new(wxid.clone(), uin);
+            let room_id = bridge.db.get_portal_by_key(&key).await.ok().flatten().and_then(|p| p.mxid);
+            res.render(Json(json!({
+                "wxid": info.id,
+                "name": info.name,
+                "avatar": info.avatar,
+                "mxid": bridge.puppet_mxid(&info.id),
+                "room_id": room_id,
+            })));
+        }
+        Err(err) => {
+            render_error(res, StatusCode::NOT_FOUND, &format!("contact not found: {}", err));
+        }
+    }
+}
+
+#[handler]
+pub async fn start_pm(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+    let Some(bridge) = get_bridge(depot, res) else {
+        return;
+    };
+    let Some(user_id) = get_user_id(&bridge, req, res) else {
+        return;
+    };
+    let wxid = req.param::<String>("wxid").unwrap_or_default();
+    if get_logged_in_uin(&bridge, &user_id, res).await.is_none() {
+        return;
+    }
+
+    match bridge.start_private_chat(&user_id, &wxid).await {
+        Ok((room_id, just_created)) => {
+            if just_created {
+                res.status_code(StatusCode::CREATED);
+            }
+            res.render(Json(json!({
+                "room_id": room_id,
+                "just_created": just_created,
+                "mxid": bridge.puppet_mxid(&wxid),
+            })));
+        }
+        Err(err) => {
+            render_error(res, StatusCode::INTERNAL_SERVER_ERROR, &format!("failed to start chat: {}", err));
+        }
+    }
+}
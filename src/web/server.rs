@@ -0,0 +1,199 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use salvo::conn::rustls::{Keycert, RustlsConfig};
+use salvo::conn::{Acceptor, Listener, TcpListener};
+use salvo::prelude::*;
+use salvo::server::ServerHandle;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::bridge::WechatBridge;
+use crate::config::TlsConfig;
+
+/// How long in-flight homeserver requests get to finish when the listener is stopped.
+const LISTENER_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The bridge together with the web listener the homeserver talks to. It starts and stops
+/// both, and can move the listener to another address or TLS setup without a restart.
+pub struct BridgeServer {
+    bridge: Arc<WechatBridge>,
+    listener: Mutex<Option<RunningListener>>,
+    /// Cancelled when the listener stops without being asked to.
+    failed: CancellationToken,
+}
+
+struct RunningListener {
+    addr: String,
+    handle: ServerHandle,
+    stopping: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+impl BridgeServer {
+    pub fn new(bridge: Arc<WechatBridge>) -> Self {
+        Self {
+            bridge,
+            listener: Mutex::new(None),
+            failed: CancellationToken::new(),
+        }
+    }
+
+    /// Binds the appservice listener from the config, then starts the bridge and with it the
+    /// WeChat service.
+    pub async fn start(&self) -> anyhow::Result<()> {
+        let appservice = &self.bridge.config.appservice;
+        self.rebind(&appservice.listen_address(), appservice.tls.as_ref()).await?;
+        self.bridge.start().await
+    }
+
+    /// Moves the appservice listener to `addr`. On a new address the new listener is bound
+    /// before the old one stops, so a failed bind leaves the old one running.
+    pub async fn rebind(&self, addr: &str, tls: Option<&TlsConfig>) -> anyhow::Result<()> {
+        let mut listener = self.listener.lock().await;
+        if listener.as_ref().is_some_and(|l| l.addr == addr)
+            && let Some(old) = listener.take()
+        {
+            old.stop().await;
+        }
+
+        let router = super::create_appservice_router(self.bridge.clone());
+        if let Some(path) = unix_socket_path(addr) {
+            if tls.is_some() {
+                anyhow::bail!("TLS isn't supported on the Unix socket {}", path);
+            }
+            let permissions = self.bridge.config.appservice.socket_permissions.as_deref();
+            let new = self.serve(addr, bind_unix_socket(path, permissions).await?, router);
+            info!("Appservice listening on Unix socket {}", path);
+            if let Some(old) = listener.replace(new) {
+                old.stop().await;
+            }
+            return Ok(());
+        }
+
+        let tcp = TcpListener::new(addr.to_string());
+        let new = match tls {
+            Some(tls) => {
+                let keycert = Keycert::new()
+                    .cert_from_path(&tls.cert)
+                    .with_context(|| format!("failed to read TLS certificate {}", tls.cert))?
+                    .key_from_path(&tls.key)
+                    .with_context(|| format!("failed to read TLS key {}", tls.key))?;
+                let acceptor = tcp.rustls(RustlsConfig::new(keycert)).try_bind().await
+                    .with_context(|| format!("failed to bind appservice listener to {}", addr))?;
+                self.serve(addr, acceptor, router)
+            }
+            None => {
+                let acceptor = tcp.try_bind().await
+                    .with_context(|| format!("failed to bind appservice listener to {}", addr))?;
+                self.serve(addr, acceptor, router)
+            }
+        };
+        info!("Appservice listening on {}{}", addr, if tls.is_some() { " with TLS" } else { "" });
+
+        if let Some(old) = listener.replace(new) {
+            old.stop().await;
+        }
+        Ok(())
+    }
+
+    fn serve<A: Acceptor + Send + 'static>(&self, addr: &str, acceptor: A, router: Router) -> RunningListener {
+        let server = Server::new(acceptor);
+        let handle = server.handle();
+        let stopping = CancellationToken::new();
+        let task = tokio::spawn({
+            let (addr, stopping, failed) = (addr.to_string(), stopping.clone(), self.failed.clone());
+            async move {
+                server.serve(router).await;
+                if !stopping.is_cancelled() {
+                    error!("Appservice listener on {} stopped", addr);
+                    failed.cancel();
+                }
+            }
+        });
+        RunningListener {
+            addr: addr.to_string(),
+            handle,
+            stopping,
+            task,
+        }
+    }
+
+    /// Resolves when the appservice listener stops on its own, e.g. because its socket failed.
+    pub async fn failed(&self) {
+        self.failed.cancelled().await
+    }
+
+    /// Stops the listener, letting in-flight requests finish, and then the bridge.
+    pub async fn stop(&self) {
+        if let Some(listener) = self.listener.lock().await.take() {
+            listener.stop().await;
+        }
+        self.bridge.stop().await;
+    }
+}
+
+impl RunningListener {
+    async fn stop(self) {
+        self.stopping.cancel();
+        self.handle.stop_graceful(LISTENER_STOP_TIMEOUT);
+        if tokio::time::timeout(LISTENER_STOP_TIMEOUT + Duration::from_secs(1), self.task).await.is_err() {
+            warn!("Appservice listener on {} didn't stop in time", self.addr);
+        }
+        if let Some(path) = unix_socket_path(&self.addr) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// The socket path of a `unix://` listen address.
+pub fn unix_socket_path(addr: &str) -> Option<&str> {
+    addr.strip_prefix("unix://").filter(|path| !path.is_empty())
+}
+
+#[cfg(unix)]
+async fn bind_unix_socket(path: &str, permissions: Option<&str>) -> anyhow::Result<salvo::conn::unix::UnixAcceptor> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let permissions = permissions
+        .filter(|mode| !mode.is_empty())
+        .map(|mode| u32::from_str_radix(mode, 8).with_context(|| format!("invalid socket permissions {}", mode)))
+        .transpose()?
+        .map(std::fs::Permissions::from_mode);
+    // A socket left by a previous run that didn't shut down cleanly would fail the bind.
+    if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path).with_context(|| format!("failed to remove stale socket {}", path))?;
+    }
+    salvo::conn::UnixListener::new(path.to_string())
+        .permissions(permissions)
+        .try_bind()
+        .await
+        .with_context(|| format!("failed to bind appservice listener to Unix socket {}", path))
+}
+
+#[cfg(not(unix))]
+async fn bind_unix_socket(path: &str, _permissions: Option<&str>) -> anyhow::Result<salvo::conn::tcp::TcpAcceptor> {
+    anyhow::bail!("Unix sockets aren't supported on this platform, can't listen on {}", path)
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM where there are signals, as sent by service managers.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => warn!("Failed to listen for SIGTERM: {}", e),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
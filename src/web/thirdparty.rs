@@ -167,6 +167,8 @@ pub async fn get_networks(depot: &mut Depot, res: &mut Response) {
     }
 }
 
+/// Answers location queries: a `chat_id` field is resolved with the agents to the alias of its
+/// portal, which is created on demand for groups; otherwise the existing portals are listed.
 #[handler]
 pub async fn get_locations(req: &mut Request, depot: &mut Depot, res: &mut Response) {
     let bridge = match depot.get::<std::sync::Arc<WechatBridge>>("bridge") {
@@ -177,28 +179,43 @@ pub async fn get_locations(req: &mut Request, depot: &mut Depot, res: &mut Respo
         }
     };
 
-    let chat_filter = req.query::<String>("chat_id");
+    if let Some(chat_id) = req.query::<String>("chat_id").filter(|c| !c.is_empty()) {
+        match bridge.lookup_remote_location(&chat_id).await {
+            Ok(Some(alias)) => res.render(Json(vec![ThirdPartyLocation {
+                alias,
+                protocol: "wechat".to_string(),
+                fields: HashMap::from([("chat_id".to_string(), chat_id)]),
+            }])),
+            Ok(None) => res.render(Json(Vec::<ThirdPartyLocation>::new())),
+            Err(err) => render_error(
+                res,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("lookup failed: {}", err),
+            ),
+        }
+        return;
+    }
+
+    let alias_filter = req.query::<String>("alias");
 
     match bridge.db.get_all_portals_with_mxid().await {
         Ok(portals) => {
             let locations: Vec<ThirdPartyLocation> = portals
                 .into_iter()
-                .filter(|portal| {
-                    chat_filter
+                .map(|portal| ThirdPartyLocation {
+                    alias: bridge.config.format_alias(&portal.uid),
+                    protocol: "wechat".to_string(),
+                    fields: HashMap::from([
+                        ("chat_id".to_string(), portal.uid.clone()),
+                        ("receiver".to_string(), portal.receiver.clone()),
+                    ]),
+                })
+                .filter(|location| {
+                    alias_filter
                         .as_ref()
-                        .map(|chat| portal.uid.contains(chat))
+                        .map(|alias| &location.alias == alias)
                         .unwrap_or(true)
                 })
-                .filter_map(|portal| {
-                    portal.mxid.map(|mxid| ThirdPartyLocation {
-                        alias: mxid,
-                        protocol: "wechat".to_string(),
-                        fields: HashMap::from([
-                            ("chat_id".to_string(), portal.uid.clone()),
-                            ("receiver".to_string(), portal.receiver.clone()),
-                        ]),
-                    })
-                })
                 .collect();
             
             res.render(Json(locations));
@@ -264,6 +281,8 @@ pub async fn get_user(req: &mut Request, depot: &mut Depot, res: &mut Response)
     }
 }
 
+/// Answers user queries: a `user_id` field is looked up with the agents and resolved to the
+/// user's ghost; otherwise the known puppets are listed.
 #[handler]
 pub async fn get_users(req: &mut Request, depot: &mut Depot, res: &mut Response) {
     let bridge = match depot.get::<std::sync::Arc<WechatBridge>>("bridge") {
@@ -274,7 +293,27 @@ pub async fn get_users(req: &mut Request, depot: &mut Depot, res: &mut Response)
         }
     };
 
-    let user_filter = req.query::<String>("user_id").or_else(|| req.query::<String>("userid"));
+    if let Some(wxid) = req.query::<String>("user_id").filter(|u| !u.is_empty()) {
+        match bridge.lookup_remote_user(&wxid).await {
+            Ok(Some((mxid, info))) => res.render(Json(vec![ThirdPartyUser {
+                userid: mxid,
+                protocol: "wechat".to_string(),
+                fields: HashMap::from([
+                    ("user_id".to_string(), info.id),
+                    ("displayname".to_string(), info.name),
+                ]),
+            }])),
+            Ok(None) => res.render(Json(Vec::<ThirdPartyUser>::new())),
+            Err(err) => render_error(
+                res,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &format!("lookup failed: {}", err),
+            ),
+        }
+        return;
+    }
+
+    let user_filter = req.query::<String>("userid");
     let domain = &bridge.config.homeserver.domain;
     let user_prefix = &bridge.config.bridge.user_prefix;
 
@@ -282,12 +321,6 @@ pub async fn get_users(req: &mut Request, depot: &mut Depot, res: &mut Response)
         Ok(puppets) => {
             let users: Vec<ThirdPartyUser> = puppets
                 .into_iter()
-                .filter(|puppet| {
-                    user_filter
-                        .as_ref()
-                        .map(|filter| puppet.uin.contains(filter))
-                        .unwrap_or(true)
-                })
                 .map(|puppet| {
                     let mxid = puppet.custom_mxid.unwrap_or_else(|| {
                         format!("@{}{}:{}", user_prefix, puppet.uin, domain)
@@ -301,6 +334,12 @@ pub async fn get_users(req: &mut Request, depot: &mut Depot, res: &mut Response)
                         ]),
                     }
                 })
+                .filter(|user| {
+                    user_filter
+                        .as_ref()
+                        .map(|filter| &user.userid == filter)
+                        .unwrap_or(true)
+                })
                 .collect();
             
             res.render(Json(users));
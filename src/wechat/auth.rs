@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::config::AgentAuthConfig;
+use crate::database::{AgentToken, Database};
+
+/// How long an agent has to answer a challenge.
+pub const CHALLENGE_TTL: Duration = Duration::from_secs(60);
+/// Caps the unanswered challenges, which anyone can ask for.
+const MAX_PENDING_CHALLENGES: usize = 1024;
+
+/// What an agent connection authenticated with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgentIdentity {
+    /// The `listen_secret` shared by all agents.
+    SharedSecret,
+    /// The agent token with this ID.
+    Token(String),
+}
+
+impl AgentIdentity {
+    pub fn token_id(&self) -> Option<&str> {
+        match self {
+            AgentIdentity::SharedSecret => None,
+            AgentIdentity::Token(id) => Some(id),
+        }
+    }
+}
+
+/// Checks the `Authorization` header agents connect with. Accepted are:
+///
+/// - `Basic <listen_secret>`, unless the shared secret is `disable`d;
+/// - `Bearer <token>`, with a token issued by the `agent-token` command;
+/// - `HMAC <token id>:<nonce>:<signature>`, answering a challenge from `/challenge` with
+///   [`sign_challenge`], so the token itself is never sent.
+///
+/// With `require_challenge` only the last one is.
+#[derive(Clone)]
+pub struct AgentAuth {
+    shared_secret: String,
+    db: Option<Database>,
+    require_challenge: bool,
+    /// Unanswered challenges and when they were issued.
+    challenges: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl AgentAuth {
+    pub fn new(shared_secret: impl Into<String>) -> Self {
+        Self {
+            shared_secret: shared_secret.into(),
+            db: None,
+            require_challenge: false,
+            challenges: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Also accepts the agent tokens stored in `db`.
+    pub fn with_tokens(mut self, db: Database, config: &AgentAuthConfig) -> Self {
+        self.db = Some(db);
+        self.require_challenge = config.require_challenge;
+        self
+    }
+
+    fn shared_secret_enabled(&self) -> bool {
+        !self.require_challenge && !self.shared_secret.is_empty() && self.shared_secret != "disable"
+    }
+
+    /// Issues a nonce for an agent to sign. Each can be answered once, within [`CHALLENGE_TTL`].
+    /// Returns `None` when too many are waiting for an answer.
+    pub async fn issue_challenge(&self) -> Option<String> {
+        let mut challenges = self.challenges.lock().await;
+        challenges.retain(|_, issued| issued.elapsed() < CHALLENGE_TTL);
+        if challenges.len() >= MAX_PENDING_CHALLENGES {
+            return None;
+        }
+        let nonce = random_hex(16);
+        challenges.insert(nonce.clone(), Instant::now());
+        Some(nonce)
+    }
+
+    /// Returns who the header authenticates, or `None` if it doesn't.
+    pub async fn authenticate(&self, authorization: Option<&str>) -> Option<AgentIdentity> {
+        let (scheme, credentials) = authorization?.split_once(' ')?;
+        match scheme {
+            "Basic" if self.shared_secret_enabled() => {
+                bool::from(credentials.as_bytes().ct_eq(self.shared_secret.as_bytes()))
+                    .then_some(AgentIdentity::SharedSecret)
+            }
+            "Bearer" if !self.require_challenge => {
+                let (id, secret) = credentials.split_once('.')?;
+                let token = self.valid_token(id).await?;
+                let matches = hash_secret(secret).as_bytes().ct_eq(token.token_hash.as_bytes());
+                self.accept(token, matches.into()).await
+            }
+            "HMAC" => {
+                let mut parts = credentials.splitn(3, ':');
+                let (id, nonce, signature) = (parts.next()?, parts.next()?, parts.next()?);
+                if !self.take_challenge(nonce).await {
+                    return None;
+                }
+                let token = self.valid_token(id).await?;
+                // The signature is the client key masked with an HMAC keyed by its stored hash,
+                // so unmasking it and hashing the result must give the stored hash back.
+                let mask = sign_with_key(&unhex(&token.token_hash)?, nonce);
+                let client_key = xor(&unhex(signature)?, &mask)?;
+                let matches = hex(&Sha256::digest(&client_key)).as_bytes().ct_eq(token.token_hash.as_bytes());
+                self.accept(token, matches.into()).await
+            }
+            _ => None,
+        }
+    }
+
+    async fn take_challenge(&self, nonce: &str) -> bool {
+        self.challenges
+            .lock()
+            .await
+            .remove(nonce)
+            .is_some_and(|issued| issued.elapsed() < CHALLENGE_TTL)
+    }
+
+    async fn valid_token(&self, id: &str) -> Option<AgentToken> {
+        match self.db.as_ref()?.get_agent_token(id).await {
+            Ok(token) => token.filter(|t| !t.revoked),
+            Err(e) => {
+                warn!("Failed to look up agent token {}: {}", id, e);
+                None
+            }
+        }
+    }
+
+    async fn accept(&self, token: AgentToken, matches: bool) -> Option<AgentIdentity> {
+        if !matches {
+            return None;
+        }
+        if let Some(db) = &self.db
+            && let Err(e) = db.touch_agent_token(&token.id, chrono::Utc::now().timestamp()).await
+        {
+            warn!("Failed to record use of agent token {}: {}", token.id, e);
+        }
+        Some(AgentIdentity::Token(token.id))
+    }
+}
+
+/// Creates an agent token, returning the row to store and the token to hand to the agent,
+/// as `<id>.<secret>`. The token can't be recovered from the row.
+pub fn generate_agent_token(description: &str) -> (AgentToken, String) {
+    let id = random_hex(4);
+    let secret = random_hex(32);
+    let row = AgentToken {
+        id: id.clone(),
+        token_hash: hash_secret(&secret),
+        description: description.to_string(),
+        created_at: chrono::Utc::now().timestamp(),
+        last_used: 0,
+        revoked: false,
+    };
+    (row, format!("{}.{}", id, secret))
+}
+
+/// The hex SHA-256 of a token's client key, as stored.
+pub fn hash_secret(secret: &str) -> String {
+    hex(&Sha256::digest(client_key(secret)))
+}
+
+/// The `Authorization` header value answering a challenge with an agent token. The signature
+/// is the hex client key of the token's secret, XORed with the HMAC-SHA256 of the nonce keyed
+/// with the key's SHA-256. The bridge only stores that SHA-256, so a leaked row can check
+/// signatures but not make them. Returns `None` for a malformed token.
+pub fn sign_challenge(token: &str, nonce: &str) -> Option<String> {
+    let (id, secret) = token.split_once('.')?;
+    let client_key = client_key(secret);
+    let mask = sign_with_key(&Sha256::digest(&client_key), nonce);
+    Some(format!("HMAC {}:{}:{}", id, nonce, hex(&xor(&client_key, &mask)?)))
+}
+
+/// The `X-Signature` of a webhook exchanged with an HTTP agent: the hex HMAC-SHA256 of
+/// `<timestamp>.<body>`, keyed with the agent's secret, where the timestamp is the
+/// `X-Timestamp` header in unix seconds.
+pub fn sign_webhook(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    hex(&mac.finalize().into_bytes())
+}
+
+/// The key a token's secret signs challenges with.
+fn client_key(secret: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(b"agent client key");
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn sign_with_key(key: &[u8], nonce: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(nonce.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Option<Vec<u8>> {
+    (a.len() == b.len()).then(|| a.iter().zip(b).map(|(x, y)| x ^ y).collect())
+}
+
+fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    getrandom::fill(&mut bytes).expect("the OS random number generator is available");
+    hex(&bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
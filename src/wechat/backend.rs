@@ -0,0 +1,71 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use super::{GroupInfo, GroupMember, UserInfo};
+
+/// What the bridge needs from a WeChat account. `WechatClient` implements it over the agent
+/// websocket; other agent protocols or test doubles can be plugged in with a `BackendFactory`.
+#[async_trait]
+pub trait WechatBackend: Send + Sync {
+    /// The Matrix user whose WeChat account this is.
+    fn mxid(&self) -> &str;
+
+    async fn connect(&self) -> Result<()>;
+    async fn disconnect(&self) -> Result<()>;
+    async fn is_logged_in(&self) -> Result<bool>;
+    async fn get_qrcode(&self) -> Result<Vec<u8>>;
+
+    async fn get_self(&self) -> Result<UserInfo>;
+    async fn get_user_info(&self, wxid: &str) -> Result<UserInfo>;
+    async fn get_friend_list(&self) -> Result<Vec<UserInfo>>;
+    async fn accept_friend(&self, v3: &str) -> Result<()>;
+    async fn set_nickname(&self, nickname: &str) -> Result<()>;
+    async fn set_avatar(&self, avatar_data: &[u8]) -> Result<()>;
+    async fn set_presence(&self, presence: &str) -> Result<()>;
+    async fn refresh_contacts(&self) -> Result<()>;
+    async fn sync_messages(&self) -> Result<()>;
+
+    async fn get_group_list(&self) -> Result<Vec<GroupInfo>>;
+    async fn get_group_info(&self, group_id: &str) -> Result<GroupInfo>;
+    async fn get_group_members(&self, group_id: &str) -> Result<Vec<GroupMember>>;
+    async fn get_group_member_nickname(&self, group_id: &str, member_id: &str) -> Result<String>;
+    async fn create_group(&self, user_ids: &[&str], name: &str) -> Result<String>;
+    async fn set_group_name(&self, group_id: &str, name: &str) -> Result<()>;
+    async fn invite_group_member(&self, group_id: &str, user_ids: &[&str]) -> Result<()>;
+    async fn remove_group_member(&self, group_id: &str, user_ids: &[&str]) -> Result<()>;
+    async fn quit_group(&self, group_id: &str) -> Result<()>;
+    /// Accepts a group invite, returning the ID of the group joined.
+    async fn join_group(&self, invite: &str) -> Result<String>;
+
+    async fn send_text_message(&self, chat_id: &str, text: &str, reply_to: Option<&str>) -> Result<String> {
+        self.send_text_with_mentions(chat_id, text, reply_to, None, &[]).await
+    }
+    /// Sends a text message that mentions the given WeChat users, who are written as `@name`
+    /// in the text. `quote` is the text of the message replied to, shown in the WeChat quote.
+    async fn send_text_with_mentions(
+        &self,
+        chat_id: &str,
+        text: &str,
+        reply_to: Option<&str>,
+        quote: Option<&str>,
+        mentions: &[String],
+    ) -> Result<String>;
+    async fn edit_text_message(&self, chat_id: &str, msg_id: &str, text: &str) -> Result<()>;
+    async fn send_image_message(&self, chat_id: &str, image_data: &[u8], reply_to: Option<&str>) -> Result<String>;
+    async fn send_video_message(&self, chat_id: &str, video_data: &[u8], reply_to: Option<&str>) -> Result<String>;
+    async fn send_file_message(&self, chat_id: &str, file_data: &[u8], filename: &str, reply_to: Option<&str>) -> Result<String>;
+    async fn send_emoji_message(&self, chat_id: &str, emoji_data: &[u8]) -> Result<String>;
+    async fn send_reaction(&self, chat_id: &str, msg_id: &str, emoji: &str) -> Result<()>;
+    async fn mark_read(&self, chat_id: &str, msg_id: &str) -> Result<()>;
+    async fn revoke_message(&self, chat_id: &str, msg_id: &str) -> Result<()>;
+
+    async fn download_image(&self, xml: &str) -> Result<Vec<u8>>;
+    async fn download_video(&self, xml: &str) -> Result<Vec<u8>>;
+    async fn download_audio(&self, xml: &str) -> Result<Vec<u8>>;
+    async fn download_file(&self, xml: &str) -> Result<Vec<u8>>;
+}
+
+/// Creates the backend for a Matrix user's WeChat account.
+pub type BackendFactory = Arc<dyn Fn(&str) -> Arc<dyn WechatBackend> + Send + Sync>;
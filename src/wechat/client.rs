@@ -1,9 +1,10 @@
 use std::sync::Arc;
 
 use anyhow::{Result, anyhow};
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 
-use super::{WechatService, Request, RequestType, UserInfo, GroupInfo};
+use super::{WechatBackend, WechatService, Request, RequestType, EventType, UserInfo, GroupInfo};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GroupMember {
@@ -11,6 +12,8 @@ pub struct GroupMember {
     pub name: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub nickname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<String>,
 }
 
 #[derive(Clone)]
@@ -24,11 +27,42 @@ impl WechatClient {
         Self { mxid, service }
     }
 
-    pub fn mxid(&self) -> &str {
+    /// Sends a message, remembering it so it isn't bridged back when the agent reports it.
+    async fn send_tracked(&self, chat_id: &str, event_type: EventType, text: Option<&str>, request: Request) -> Result<String> {
+        let echoes = self.service.echoes();
+        let handle = echoes.sending(&self.mxid, chat_id, event_type, text);
+        let result = self.request_msg_id(&request).await;
+        match &result {
+            Ok(msg_id) => echoes.sent(handle, msg_id),
+            Err(_) => echoes.failed(handle),
+        }
+        result
+    }
+
+    async fn request_msg_id(&self, request: &Request) -> Result<String> {
+        let response = self.service.request(&self.mxid, request).await?;
+        
+        if let Some(error) = response.error {
+            return Err(anyhow!("{}", error));
+        }
+        
+        if let Some(data) = &response.data
+            && let Some(msg_id) = data.get("msg_id").and_then(|v| v.as_str())
+        {
+            return Ok(msg_id.to_string());
+        }
+        
+        Err(anyhow!("no msg_id in response"))
+    }
+}
+
+#[async_trait]
+impl WechatBackend for WechatClient {
+    fn mxid(&self) -> &str {
         &self.mxid
     }
 
-    pub async fn connect(&self) -> Result<()> {
+    async fn connect(&self) -> Result<()> {
         self.service.request(&self.mxid, &Request {
             request_type: RequestType::Connect,
             data: None,
@@ -36,7 +70,7 @@ impl WechatClient {
         Ok(())
     }
 
-    pub async fn disconnect(&self) -> Result<()> {
+    async fn disconnect(&self) -> Result<()> {
         self.service.request(&self.mxid, &Request {
             request_type: RequestType::Disconnect,
             data: None,
@@ -44,7 +78,7 @@ impl WechatClient {
         Ok(())
     }
 
-    pub async fn is_logged_in(&self) -> Result<bool> {
+    async fn is_logged_in(&self) -> Result<bool> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::IsLogin,
             data: None,
@@ -57,7 +91,7 @@ impl WechatClient {
         Ok(response.data.as_ref().and_then(|d| d.as_bool()).unwrap_or(false))
     }
 
-    pub async fn get_self(&self) -> Result<UserInfo> {
+    async fn get_self(&self) -> Result<UserInfo> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::GetSelf,
             data: None,
@@ -74,7 +108,7 @@ impl WechatClient {
         Err(anyhow!("invalid response"))
     }
 
-    pub async fn get_user_info(&self, wxid: &str) -> Result<UserInfo> {
+    async fn get_user_info(&self, wxid: &str) -> Result<UserInfo> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::GetUserInfo,
             data: Some(serde_json::json!([wxid])),
@@ -91,7 +125,7 @@ impl WechatClient {
         Err(anyhow!("invalid response"))
     }
 
-    pub async fn get_friend_list(&self) -> Result<Vec<UserInfo>> {
+    async fn get_friend_list(&self) -> Result<Vec<UserInfo>> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::GetFriendList,
             data: None,
@@ -108,7 +142,7 @@ impl WechatClient {
         Err(anyhow!("invalid response"))
     }
 
-    pub async fn get_group_list(&self) -> Result<Vec<GroupInfo>> {
+    async fn get_group_list(&self) -> Result<Vec<GroupInfo>> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::GetGroupList,
             data: None,
@@ -125,7 +159,7 @@ impl WechatClient {
         Err(anyhow!("invalid response"))
     }
 
-    pub async fn get_group_info(&self, group_id: &str) -> Result<GroupInfo> {
+    async fn get_group_info(&self, group_id: &str) -> Result<GroupInfo> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::GetGroupInfo,
             data: Some(serde_json::json!([group_id])),
@@ -142,7 +176,7 @@ impl WechatClient {
         Err(anyhow!("invalid response"))
     }
 
-    pub async fn get_group_members(&self, group_id: &str) -> Result<Vec<GroupMember>> {
+    async fn get_group_members(&self, group_id: &str) -> Result<Vec<GroupMember>> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::GetGroupMembers,
             data: Some(serde_json::json!([group_id])),
@@ -159,7 +193,7 @@ impl WechatClient {
         Err(anyhow!("invalid response"))
     }
 
-    pub async fn get_group_member_nickname(&self, group_id: &str, member_id: &str) -> Result<String> {
+    async fn get_group_member_nickname(&self, group_id: &str, member_id: &str) -> Result<String> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::GetGroupMemberNickname,
             data: Some(serde_json::json!([group_id, member_id])),
@@ -176,8 +210,15 @@ impl WechatClient {
         Err(anyhow!("invalid response"))
     }
 
-    pub async fn send_text_message(&self, chat_id: &str, text: &str, reply_to: Option<&str>) -> Result<String> {
-        let data = if let Some(reply) = reply_to {
+    async fn send_text_with_mentions(
+        &self,
+        chat_id: &str,
+        text: &str,
+        reply_to: Option<&str>,
+        quote: Option<&str>,
+        mentions: &[String],
+    ) -> Result<String> {
+        let mut data = if let Some(reply) = reply_to {
             serde_json::json!({
                 "chat_id": chat_id,
                 "text": text,
@@ -189,26 +230,39 @@ impl WechatClient {
                 "text": text,
             })
         };
+        if let (Some(_), Some(quote)) = (reply_to, quote) {
+            data["quote"] = serde_json::json!(quote);
+        }
+        if !mentions.is_empty() {
+            data["mentions"] = serde_json::json!(mentions);
+        }
         
-        let response = self.service.request(&self.mxid, &Request {
+        self.send_tracked(chat_id, EventType::Text, Some(text), Request {
             request_type: RequestType::SendText,
             data: Some(data),
+        }).await
+    }
+
+    async fn edit_text_message(&self, chat_id: &str, msg_id: &str, text: &str) -> Result<()> {
+        let data = serde_json::json!({
+            "chat_id": chat_id,
+            "msg_id": msg_id,
+            "text": text,
+        });
+        
+        let response = self.service.request(&self.mxid, &Request {
+            request_type: RequestType::EditText,
+            data: Some(data),
         }).await?;
         
         if let Some(error) = response.error {
             return Err(anyhow!("{}", error));
         }
         
-        if let Some(data) = &response.data {
-            if let Some(msg_id) = data.get("msg_id").and_then(|v| v.as_str()) {
-                return Ok(msg_id.to_string());
-            }
-        }
-        
-        Err(anyhow!("no msg_id in response"))
+        Ok(())
     }
 
-    pub async fn send_image_message(&self, chat_id: &str, image_data: &[u8], reply_to: Option<&str>) -> Result<String> {
+    async fn send_image_message(&self, chat_id: &str, image_data: &[u8], reply_to: Option<&str>) -> Result<String> {
         let image_base64 = base64_encode(image_data);
         let data = if let Some(reply) = reply_to {
             serde_json::json!({
@@ -223,25 +277,13 @@ impl WechatClient {
             })
         };
         
-        let response = self.service.request(&self.mxid, &Request {
+        self.send_tracked(chat_id, EventType::Photo, None, Request {
             request_type: RequestType::SendImage,
             data: Some(data),
-        }).await?;
-        
-        if let Some(error) = response.error {
-            return Err(anyhow!("{}", error));
-        }
-        
-        if let Some(data) = &response.data {
-            if let Some(msg_id) = data.get("msg_id").and_then(|v| v.as_str()) {
-                return Ok(msg_id.to_string());
-            }
-        }
-        
-        Err(anyhow!("no msg_id in response"))
+        }).await
     }
 
-    pub async fn send_video_message(&self, chat_id: &str, video_data: &[u8], reply_to: Option<&str>) -> Result<String> {
+    async fn send_video_message(&self, chat_id: &str, video_data: &[u8], reply_to: Option<&str>) -> Result<String> {
         let video_base64 = base64_encode(video_data);
         let data = if let Some(reply) = reply_to {
             serde_json::json!({
@@ -256,25 +298,13 @@ impl WechatClient {
             })
         };
         
-        let response = self.service.request(&self.mxid, &Request {
+        self.send_tracked(chat_id, EventType::Video, None, Request {
             request_type: RequestType::SendVideo,
             data: Some(data),
-        }).await?;
-        
-        if let Some(error) = response.error {
-            return Err(anyhow!("{}", error));
-        }
-        
-        if let Some(data) = &response.data {
-            if let Some(msg_id) = data.get("msg_id").and_then(|v| v.as_str()) {
-                return Ok(msg_id.to_string());
-            }
-        }
-        
-        Err(anyhow!("no msg_id in response"))
+        }).await
     }
 
-    pub async fn send_file_message(&self, chat_id: &str, file_data: &[u8], filename: &str, reply_to: Option<&str>) -> Result<String> {
+    async fn send_file_message(&self, chat_id: &str, file_data: &[u8], filename: &str, reply_to: Option<&str>) -> Result<String> {
         let file_base64 = base64_encode(file_data);
         let data = if let Some(reply) = reply_to {
             serde_json::json!({
@@ -291,33 +321,52 @@ impl WechatClient {
             })
         };
         
-        let response = self.service.request(&self.mxid, &Request {
+        self.send_tracked(chat_id, EventType::File, None, Request {
             request_type: RequestType::SendFile,
             data: Some(data),
+        }).await
+    }
+
+    async fn send_emoji_message(&self, chat_id: &str, emoji_data: &[u8]) -> Result<String> {
+        let emoji_base64 = base64_encode(emoji_data);
+        let data = serde_json::json!({
+            "chat_id": chat_id,
+            "emoji": emoji_base64,
+        });
+        
+        self.send_tracked(chat_id, EventType::Sticker, None, Request {
+            request_type: RequestType::SendEmoji,
+            data: Some(data),
+        }).await
+    }
+
+    async fn send_reaction(&self, chat_id: &str, msg_id: &str, emoji: &str) -> Result<()> {
+        let data = serde_json::json!({
+            "chat_id": chat_id,
+            "msg_id": msg_id,
+            "emoji": emoji,
+        });
+        
+        let response = self.service.request(&self.mxid, &Request {
+            request_type: RequestType::SendReaction,
+            data: Some(data),
         }).await?;
         
         if let Some(error) = response.error {
             return Err(anyhow!("{}", error));
         }
         
-        if let Some(data) = &response.data {
-            if let Some(msg_id) = data.get("msg_id").and_then(|v| v.as_str()) {
-                return Ok(msg_id.to_string());
-            }
-        }
-        
-        Err(anyhow!("no msg_id in response"))
+        Ok(())
     }
 
-    pub async fn send_emoji_message(&self, chat_id: &str, emoji_data: &[u8]) -> Result<String> {
-        let emoji_base64 = base64_encode(emoji_data);
+    async fn mark_read(&self, chat_id: &str, msg_id: &str) -> Result<()> {
         let data = serde_json::json!({
             "chat_id": chat_id,
-            "emoji": emoji_base64,
+            "msg_id": msg_id,
         });
         
         let response = self.service.request(&self.mxid, &Request {
-            request_type: RequestType::SendEmoji,
+            request_type: RequestType::MarkRead,
             data: Some(data),
         }).await?;
         
@@ -325,16 +374,27 @@ impl WechatClient {
             return Err(anyhow!("{}", error));
         }
         
-        if let Some(data) = &response.data {
-            if let Some(msg_id) = data.get("msg_id").and_then(|v| v.as_str()) {
-                return Ok(msg_id.to_string());
-            }
+        Ok(())
+    }
+
+    async fn set_presence(&self, presence: &str) -> Result<()> {
+        let data = serde_json::json!({
+            "presence": presence,
+        });
+        
+        let response = self.service.request(&self.mxid, &Request {
+            request_type: RequestType::SetPresence,
+            data: Some(data),
+        }).await?;
+        
+        if let Some(error) = response.error {
+            return Err(anyhow!("{}", error));
         }
         
-        Err(anyhow!("no msg_id in response"))
+        Ok(())
     }
 
-    pub async fn revoke_message(&self, chat_id: &str, msg_id: &str) -> Result<()> {
+    async fn revoke_message(&self, chat_id: &str, msg_id: &str) -> Result<()> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::RevokeMsg,
             data: Some(serde_json::json!([chat_id, msg_id])),
@@ -347,7 +407,7 @@ impl WechatClient {
         Ok(())
     }
 
-    pub async fn download_image(&self, xml: &str) -> Result<Vec<u8>> {
+    async fn download_image(&self, xml: &str) -> Result<Vec<u8>> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::DownloadImage,
             data: Some(serde_json::json!([xml])),
@@ -366,7 +426,7 @@ impl WechatClient {
         Err(anyhow!("no image in response"))
     }
 
-    pub async fn download_video(&self, xml: &str) -> Result<Vec<u8>> {
+    async fn download_video(&self, xml: &str) -> Result<Vec<u8>> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::DownloadVideo,
             data: Some(serde_json::json!([xml])),
@@ -385,7 +445,7 @@ impl WechatClient {
         Err(anyhow!("no video in response"))
     }
 
-    pub async fn download_audio(&self, xml: &str) -> Result<Vec<u8>> {
+    async fn download_audio(&self, xml: &str) -> Result<Vec<u8>> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::DownloadAudio,
             data: Some(serde_json::json!([xml])),
@@ -404,7 +464,7 @@ impl WechatClient {
         Err(anyhow!("no audio in response"))
     }
 
-    pub async fn download_file(&self, xml: &str) -> Result<Vec<u8>> {
+    async fn download_file(&self, xml: &str) -> Result<Vec<u8>> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::DownloadFile,
             data: Some(serde_json::json!([xml])),
@@ -423,7 +483,7 @@ impl WechatClient {
         Err(anyhow!("no file in response"))
     }
 
-    pub async fn set_nickname(&self, nickname: &str) -> Result<()> {
+    async fn set_nickname(&self, nickname: &str) -> Result<()> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::SetNickname,
             data: Some(serde_json::json!([nickname])),
@@ -436,7 +496,7 @@ impl WechatClient {
         Ok(())
     }
 
-    pub async fn set_avatar(&self, avatar_data: &[u8]) -> Result<()> {
+    async fn set_avatar(&self, avatar_data: &[u8]) -> Result<()> {
         let avatar_base64 = base64_encode(avatar_data);
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::SetAvatar,
@@ -450,7 +510,7 @@ impl WechatClient {
         Ok(())
     }
 
-    pub async fn get_qrcode(&self) -> Result<Vec<u8>> {
+    async fn get_qrcode(&self) -> Result<Vec<u8>> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::GetQRCode,
             data: None,
@@ -469,7 +529,7 @@ impl WechatClient {
         Err(anyhow!("no qrcode in response"))
     }
 
-    pub async fn accept_friend(&self, v3: &str) -> Result<()> {
+    async fn accept_friend(&self, v3: &str) -> Result<()> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::AcceptFriend,
             data: Some(serde_json::json!([v3])),
@@ -482,7 +542,7 @@ impl WechatClient {
         Ok(())
     }
 
-    pub async fn create_group(&self, user_ids: &[&str], name: &str) -> Result<String> {
+    async fn create_group(&self, user_ids: &[&str], name: &str) -> Result<String> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::CreateGroup,
             data: Some(serde_json::json!([user_ids, name])),
@@ -501,7 +561,7 @@ impl WechatClient {
         Err(anyhow!("no group_id in response"))
     }
 
-    pub async fn set_group_name(&self, group_id: &str, name: &str) -> Result<()> {
+    async fn set_group_name(&self, group_id: &str, name: &str) -> Result<()> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::SetGroupName,
             data: Some(serde_json::json!([group_id, name])),
@@ -514,7 +574,7 @@ impl WechatClient {
         Ok(())
     }
 
-    pub async fn invite_group_member(&self, group_id: &str, user_ids: &[&str]) -> Result<()> {
+    async fn invite_group_member(&self, group_id: &str, user_ids: &[&str]) -> Result<()> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::InviteGroupMember,
             data: Some(serde_json::json!([group_id, user_ids])),
@@ -527,7 +587,7 @@ impl WechatClient {
         Ok(())
     }
 
-    pub async fn remove_group_member(&self, group_id: &str, user_ids: &[&str]) -> Result<()> {
+    async fn remove_group_member(&self, group_id: &str, user_ids: &[&str]) -> Result<()> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::RemoveGroupMember,
             data: Some(serde_json::json!([group_id, user_ids])),
@@ -540,7 +600,7 @@ impl WechatClient {
         Ok(())
     }
 
-    pub async fn quit_group(&self, group_id: &str) -> Result<()> {
+    async fn quit_group(&self, group_id: &str) -> Result<()> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::QuitGroup,
             data: Some(serde_json::json!([group_id])),
@@ -553,7 +613,26 @@ impl WechatClient {
         Ok(())
     }
 
-    pub async fn refresh_contacts(&self) -> Result<()> {
+    async fn join_group(&self, invite: &str) -> Result<String> {
+        let response = self.service.request(&self.mxid, &Request {
+            request_type: RequestType::JoinGroup,
+            data: Some(serde_json::json!([invite])),
+        }).await?;
+        
+        if let Some(error) = response.error {
+            return Err(anyhow!("{}", error));
+        }
+        
+        if let Some(data) = &response.data
+            && let Some(group_id) = data.get("group_id").and_then(|v| v.as_str())
+        {
+            return Ok(group_id.to_string());
+        }
+        
+        Err(anyhow!("no group_id in response"))
+    }
+
+    async fn refresh_contacts(&self) -> Result<()> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::RefreshContacts,
             data: None,
@@ -566,7 +645,7 @@ impl WechatClient {
         Ok(())
     }
 
-    pub async fn sync_messages(&self) -> Result<()> {
+    async fn sync_messages(&self) -> Result<()> {
         let response = self.service.request(&self.mxid, &Request {
             request_type: RequestType::SyncMessages,
             data: None,
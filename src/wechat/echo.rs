@@ -0,0 +1,91 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{Event, EventType};
+
+/// How long after being sent a message can still come back from the agent as an event.
+pub const ECHO_TTL: Duration = Duration::from_secs(120);
+/// How far the timestamp of an echo may be from when the message was sent, in milliseconds.
+const ECHO_TIMESTAMP_TOLERANCE_MS: i64 = 30_000;
+
+struct SentMessage {
+    handle: u64,
+    mxid: String,
+    chat_id: String,
+    event_type: EventType,
+    /// The text of text messages, to recognize echoes by before the message ID is known.
+    text: Option<String>,
+    sent_at: i64,
+    msg_id: Option<String>,
+    registered: Instant,
+}
+
+/// Remembers the messages the bridge recently sent to WeChat, so agents that report them back
+/// as new events don't get them bridged into Matrix a second time. Echoes are recognized by
+/// message ID, or, while a message is still being sent and its ID isn't known, by chat,
+/// type, text and timestamp.
+#[derive(Clone, Default)]
+pub struct EchoTracker {
+    sent: Arc<Mutex<Vec<SentMessage>>>,
+    next_handle: Arc<AtomicU64>,
+}
+
+impl EchoTracker {
+    /// Records that a message is about to be sent, returning a handle to complete it with.
+    pub fn sending(&self, mxid: &str, chat_id: &str, event_type: EventType, text: Option<&str>) -> u64 {
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        let mut sent = self.sent.lock().unwrap_or_else(|e| e.into_inner());
+        sent.retain(|m| m.registered.elapsed() < ECHO_TTL);
+        sent.push(SentMessage {
+            handle,
+            mxid: mxid.to_string(),
+            chat_id: chat_id.to_string(),
+            event_type,
+            text: text.map(str::to_string),
+            sent_at: chrono::Utc::now().timestamp_millis(),
+            msg_id: None,
+            registered: Instant::now(),
+        });
+        handle
+    }
+
+    /// Records the ID WeChat gave a message that was sent.
+    pub fn sent(&self, handle: u64, msg_id: &str) {
+        let mut sent = self.sent.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(message) = sent.iter_mut().find(|m| m.handle == handle) {
+            message.msg_id = Some(msg_id.to_string());
+        }
+    }
+
+    /// Forgets a message that couldn't be sent.
+    pub fn failed(&self, handle: u64) {
+        self.sent.lock().unwrap_or_else(|e| e.into_inner()).retain(|m| m.handle != handle);
+    }
+
+    /// Whether an event is a message the bridge sent. Each sent message matches one event.
+    pub fn is_echo(&self, event: &Event) -> bool {
+        let mut sent = self.sent.lock().unwrap_or_else(|e| e.into_inner());
+        sent.retain(|m| m.registered.elapsed() < ECHO_TTL);
+        let position = sent
+            .iter()
+            .position(|m| m.msg_id.as_deref() == Some(event.id.as_str()))
+            .or_else(|| {
+                sent.iter().position(|m| {
+                    m.msg_id.is_none()
+                        && m.mxid == event.mxid
+                        && m.chat_id == event.chat.id
+                        && m.event_type == event.event_type
+                        && m.text.as_ref().is_none_or(|text| event.content.as_ref() == Some(text))
+                        && (event.timestamp - m.sent_at).abs() <= ECHO_TIMESTAMP_TOLERANCE_MS
+                })
+            });
+        match position {
+            Some(i) => {
+                sent.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -1,8 +1,14 @@
+mod auth;
+mod backend;
+mod echo;
 mod protocol;
 mod types;
 mod service;
 mod client;
 
+pub use auth::*;
+pub use backend::*;
+pub use echo::*;
 pub use protocol::*;
 pub use types::*;
 pub use service::*;
@@ -21,6 +21,7 @@ impl std::fmt::Display for MessageType {
 #[serde(rename_all = "snake_case")]
 pub enum RequestType {
     Event,
+    Register,
     Connect,
     Disconnect,
     LoginQr,
@@ -33,11 +34,15 @@ pub enum RequestType {
     GetFriendList,
     GetGroupList,
     SendText,
+    EditText,
     SendImage,
     SendVideo,
     SendAudio,
     SendFile,
     SendEmoji,
+    SendReaction,
+    MarkRead,
+    SetPresence,
     RevokeMsg,
     DownloadImage,
     DownloadVideo,
@@ -52,14 +57,22 @@ pub enum RequestType {
     InviteGroupMember,
     RemoveGroupMember,
     QuitGroup,
+    /// Accepts a group invite that needs confirmation, see [`Event::group_invite_ticket`].
+    JoinGroup,
     RefreshContacts,
     SyncMessages,
+    /// The version handshake, see [`Hello`].
+    Hello,
+    /// Sent by the bridge with the `seq` up to which it handled the events of a user, see
+    /// [`Event::seq`].
+    Ack,
 }
 
 impl std::fmt::Display for RequestType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Event => write!(f, "event"),
+            Self::Register => write!(f, "register"),
             Self::Connect => write!(f, "connect"),
             Self::Disconnect => write!(f, "disconnect"),
             Self::LoginQr => write!(f, "login_qr"),
@@ -72,11 +85,15 @@ impl std::fmt::Display for RequestType {
             Self::GetFriendList => write!(f, "get_friend_list"),
             Self::GetGroupList => write!(f, "get_group_list"),
             Self::SendText => write!(f, "send_text"),
+            Self::EditText => write!(f, "edit_text"),
             Self::SendImage => write!(f, "send_image"),
             Self::SendVideo => write!(f, "send_video"),
             Self::SendAudio => write!(f, "send_audio"),
             Self::SendFile => write!(f, "send_file"),
             Self::SendEmoji => write!(f, "send_emoji"),
+            Self::SendReaction => write!(f, "send_reaction"),
+            Self::MarkRead => write!(f, "mark_read"),
+            Self::SetPresence => write!(f, "set_presence"),
             Self::RevokeMsg => write!(f, "revoke_msg"),
             Self::DownloadImage => write!(f, "download_image"),
             Self::DownloadVideo => write!(f, "download_video"),
@@ -91,8 +108,11 @@ impl std::fmt::Display for RequestType {
             Self::InviteGroupMember => write!(f, "invite_group_member"),
             Self::RemoveGroupMember => write!(f, "remove_group_member"),
             Self::QuitGroup => write!(f, "quit_group"),
+            Self::JoinGroup => write!(f, "join_group"),
             Self::RefreshContacts => write!(f, "refresh_contacts"),
             Self::SyncMessages => write!(f, "sync_messages"),
+            Self::Hello => write!(f, "hello"),
+            Self::Ack => write!(f, "ack"),
         }
     }
 }
@@ -101,6 +121,7 @@ impl std::fmt::Display for RequestType {
 #[serde(rename_all = "snake_case")]
 pub enum ResponseType {
     Event,
+    Register,
     Connect,
     Disconnect,
     LoginQr,
@@ -113,11 +134,15 @@ pub enum ResponseType {
     GetFriendList,
     GetGroupList,
     SendText,
+    EditText,
     SendImage,
     SendVideo,
     SendAudio,
     SendFile,
     SendEmoji,
+    SendReaction,
+    MarkRead,
+    SetPresence,
     RevokeMsg,
     DownloadImage,
     DownloadVideo,
@@ -132,14 +157,22 @@ pub enum ResponseType {
     InviteGroupMember,
     RemoveGroupMember,
     QuitGroup,
+    /// Accepts a group invite that needs confirmation, see [`Event::group_invite_ticket`].
+    JoinGroup,
     RefreshContacts,
     SyncMessages,
+    /// The version handshake, see [`Hello`].
+    Hello,
+    /// Sent by the bridge with the `seq` up to which it handled the events of a user, see
+    /// [`Event::seq`].
+    Ack,
 }
 
 impl std::fmt::Display for ResponseType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Event => write!(f, "event"),
+            Self::Register => write!(f, "register"),
             Self::Connect => write!(f, "connect"),
             Self::Disconnect => write!(f, "disconnect"),
             Self::LoginQr => write!(f, "login_qr"),
@@ -152,11 +185,15 @@ impl std::fmt::Display for ResponseType {
             Self::GetFriendList => write!(f, "get_friend_list"),
             Self::GetGroupList => write!(f, "get_group_list"),
             Self::SendText => write!(f, "send_text"),
+            Self::EditText => write!(f, "edit_text"),
             Self::SendImage => write!(f, "send_image"),
             Self::SendVideo => write!(f, "send_video"),
             Self::SendAudio => write!(f, "send_audio"),
             Self::SendFile => write!(f, "send_file"),
             Self::SendEmoji => write!(f, "send_emoji"),
+            Self::SendReaction => write!(f, "send_reaction"),
+            Self::MarkRead => write!(f, "mark_read"),
+            Self::SetPresence => write!(f, "set_presence"),
             Self::RevokeMsg => write!(f, "revoke_msg"),
             Self::DownloadImage => write!(f, "download_image"),
             Self::DownloadVideo => write!(f, "download_video"),
@@ -171,8 +208,11 @@ impl std::fmt::Display for ResponseType {
             Self::InviteGroupMember => write!(f, "invite_group_member"),
             Self::RemoveGroupMember => write!(f, "remove_group_member"),
             Self::QuitGroup => write!(f, "quit_group"),
+            Self::JoinGroup => write!(f, "join_group"),
             Self::RefreshContacts => write!(f, "refresh_contacts"),
             Self::SyncMessages => write!(f, "sync_messages"),
+            Self::Hello => write!(f, "hello"),
+            Self::Ack => write!(f, "ack"),
         }
     }
 }
@@ -193,8 +233,8 @@ impl std::fmt::Display for ChatType {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
 pub enum EventType {
     Text,
     Photo,
@@ -206,8 +246,25 @@ pub enum EventType {
     Notice,
     App,
     Revoke,
+    Reaction,
+    Read,
+    Presence,
     Voip,
     System,
+    /// The name, avatar or announcement of a group changed.
+    GroupUpdate,
+    /// Members were removed from a group, `data.members` lists their IDs.
+    GroupRemove,
+    /// The WeChat session of the agent's user ended, such as when they logged in elsewhere.
+    Logout,
+    /// Someone asked to be the user's friend. `content` is their greeting, and `data.v3` the
+    /// ticket to accept the request with.
+    FriendRequest,
+    /// `from` invited the user to the group `chat`. Invites that need to be confirmed carry a
+    /// ticket in `data.invite`, otherwise the user is already in the group.
+    GroupInvite,
+    /// A type this version of the bridge doesn't know about, kept verbatim.
+    Unknown(String),
 }
 
 impl std::fmt::Display for EventType {
@@ -223,12 +280,55 @@ impl std::fmt::Display for EventType {
             Self::Notice => write!(f, "notice"),
             Self::App => write!(f, "app"),
             Self::Revoke => write!(f, "revoke"),
+            Self::Reaction => write!(f, "reaction"),
+            Self::Read => write!(f, "read"),
+            Self::Presence => write!(f, "presence"),
             Self::Voip => write!(f, "voip"),
             Self::System => write!(f, "system"),
+            Self::GroupUpdate => write!(f, "group_update"),
+            Self::GroupRemove => write!(f, "group_remove"),
+            Self::Logout => write!(f, "logout"),
+            Self::FriendRequest => write!(f, "friend_request"),
+            Self::GroupInvite => write!(f, "group_invite"),
+            Self::Unknown(t) => write!(f, "{}", t),
         }
     }
 }
 
+impl From<String> for EventType {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            "text" => Self::Text,
+            "photo" => Self::Photo,
+            "sticker" => Self::Sticker,
+            "audio" => Self::Audio,
+            "video" => Self::Video,
+            "file" => Self::File,
+            "location" => Self::Location,
+            "notice" => Self::Notice,
+            "app" => Self::App,
+            "revoke" => Self::Revoke,
+            "reaction" => Self::Reaction,
+            "read" => Self::Read,
+            "presence" => Self::Presence,
+            "voip" => Self::Voip,
+            "system" => Self::System,
+            "group_update" => Self::GroupUpdate,
+            "group_remove" => Self::GroupRemove,
+            "logout" => Self::Logout,
+            "friend_request" => Self::FriendRequest,
+            "group_invite" => Self::GroupInvite,
+            _ => Self::Unknown(s),
+        }
+    }
+}
+
+impl From<EventType> for String {
+    fn from(t: EventType) -> Self {
+        t.to_string()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ErrorResponse {
     #[serde(skip)]
@@ -344,4 +444,106 @@ pub struct Event {
     pub reply: Option<ReplyInfo>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<serde_json::Value>,
+    /// Numbers the events of a user, increasing across agent restarts. Numbered events are
+    /// kept by the agent until the bridge acknowledges them, and replayed after reconnecting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// Set on messages the account sent itself, e.g. from the phone app. Events from the
+    /// account's own ID are treated the same when agents don't set it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub from_self: bool,
+    /// The Matrix user the agent sent the event for, set on receipt.
+    #[serde(skip)]
+    pub mxid: String,
+}
+
+impl Event {
+    /// The ticket to accept a friend request with, the requester's ID when the agent sent none.
+    pub fn friend_request_ticket(&self) -> &str {
+        self.data
+            .as_ref()
+            .and_then(|d| d.get("v3"))
+            .and_then(|v| v.as_str())
+            .unwrap_or(&self.from.id)
+    }
+
+    /// The ticket to join a group with, when the invite needs to be confirmed.
+    pub fn group_invite_ticket(&self) -> Option<&str> {
+        self.data.as_ref()?.get("invite")?.as_str().filter(|t| !t.is_empty())
+    }
+}
+
+/// The newest protocol version the bridge speaks.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// The oldest protocol version the bridge still accepts. Agents that don't send a `hello` are
+/// at version 1, from before versions were exchanged.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The data of the `hello` request an agent sends right after connecting, and of the bridge's
+/// response to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    /// The newest version the sender speaks.
+    pub protocol_version: u32,
+    /// The oldest version the sender still speaks, `protocol_version` if not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_protocol_version: Option<u32>,
+    #[serde(default)]
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    /// What the bridge announces to agents.
+    pub fn bridge() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: Some(MIN_PROTOCOL_VERSION),
+            capabilities: Capabilities {
+                requests: Some(vec![RequestType::Event, RequestType::Register, RequestType::Hello]),
+                media_streaming: false,
+                reactions: true,
+            },
+        }
+    }
+
+    /// The newest version both the sender and the bridge speak, or `None` if they have none
+    /// in common.
+    pub fn negotiate(&self) -> Option<u32> {
+        let version = self.protocol_version.min(PROTOCOL_VERSION);
+        let min = self.min_protocol_version.unwrap_or(self.protocol_version).max(MIN_PROTOCOL_VERSION);
+        (version >= min).then_some(version)
+    }
+}
+
+/// What the sender of a [`Hello`] can do beyond the base protocol.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// The request types the sender handles, all of them if not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requests: Option<Vec<RequestType>>,
+    /// Media can be streamed in chunks instead of sent whole.
+    #[serde(default)]
+    pub media_streaming: bool,
+    /// Reactions can be sent, and are reported as events.
+    #[serde(default)]
+    pub reactions: bool,
+}
+
+impl Capabilities {
+    /// What agents from before the handshake are assumed to handle: everything the bridge
+    /// sent them back then.
+    pub fn legacy() -> Self {
+        Self {
+            requests: None,
+            media_streaming: false,
+            reactions: true,
+        }
+    }
+
+    pub fn supports(&self, request_type: RequestType) -> bool {
+        if request_type == RequestType::SendReaction && !self.reactions {
+            return false;
+        }
+        self.requests.as_ref().is_none_or(|requests| requests.contains(&request_type))
+    }
 }
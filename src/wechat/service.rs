@@ -1,57 +1,260 @@
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicI64, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Result, anyhow};
+use serde::Serialize;
 use salvo::conn::TcpListener;
 use salvo::prelude::*;
 use salvo::websocket::{WebSocketUpgrade, Message, WebSocket};
-use tokio::sync::{Mutex, RwLock, mpsc, oneshot, broadcast};
-use tracing::{info, warn};
+use tokio::sync::{Mutex, Notify, RwLock, mpsc, oneshot, broadcast};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 
 use super::{Message as WxMessage, Request as WxRequest, Response as WxResponse, Event, RequestType, MessageType};
 use super::{UserInfo, GroupInfo};
+use super::{Capabilities, ErrorResponse, Hello, ResponseType, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use super::{AgentAuth, EchoTracker, CHALLENGE_TTL};
+use crate::config::{AgentAuthConfig, HttpAgentConfig};
+use crate::database::Database;
+use crate::error::WeChatError;
+use crate::util::retry::{BackoffConfig, ExponentialBackoff};
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod http_agent;
 
 const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const BIND_MAX_RETRIES: u32 = 5;
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often agents are pinged, and how long one may stay silent before it's considered dead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(45),
+        }
+    }
+}
 
 #[derive(Clone)]
 struct Connection {
     addr: String,
     tx: mpsc::UnboundedSender<String>,
+    /// When anything, including a pong, was last received from the agent, in unix milliseconds.
+    last_seen: Arc<AtomicI64>,
+    /// The agent token the connection authenticated with, if not the shared secret.
+    agent: Option<String>,
+    /// Cancelled to close the connection, e.g. when its token is revoked.
+    close: CancellationToken,
+    /// As agreed in the agent's `hello`, or assumed for agents that don't send one.
+    protocol_version: u32,
+    capabilities: Capabilities,
+}
+
+impl Connection {
+    fn last_seen(&self) -> i64 {
+        self.last_seen.load(Ordering::SeqCst)
+    }
+}
+
+/// An agent connection as shown by the `list-connections` command.
+#[derive(Debug, Clone)]
+pub struct AgentConnectionInfo {
+    pub addr: String,
+    pub agent: Option<String>,
+    pub protocol_version: u32,
+    pub users: Vec<String>,
+    pub last_seen: i64,
+}
+
+/// A request waiting for an agent's response, as shown by the debug API.
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingRequestInfo {
+    pub id: i64,
+    pub mxid: String,
+    pub request_type: RequestType,
+    /// The agent connection it was sent on.
+    pub addr: String,
+    pub sent_at: i64,
+}
+
+/// A change in whether a user's WeChat account is reachable through an agent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgentStatus {
+    /// An agent registered for the user.
+    Connected(String),
+    /// The last agent serving the user disconnected.
+    Disconnected(String),
+    /// The agent reported that the user's WeChat session ended.
+    LoggedOut { mxid: String, reason: Option<String> },
+}
+
+#[derive(Default)]
+struct Connections {
+    by_addr: HashMap<String, Connection>,
+    /// The connections serving each Matrix user, as announced by the agents.
+    by_mxid: HashMap<String, Vec<String>>,
+    /// Users whose missing agent was already reported, until their agent registers again.
+    missing: HashSet<String>,
+}
+
+impl Connections {
+    /// Picks the most recently active connection serving the user.
+    fn get(&self, mxid: &str) -> Option<Connection> {
+        let registered = self.by_mxid.get(mxid).into_iter().flatten().filter_map(|addr| self.by_addr.get(addr));
+        if let Some(conn) = registered.max_by_key(|conn| conn.last_seen()) {
+            return Some(conn.clone());
+        }
+        // Agents that never registered a user serve everyone, like before routing existed.
+        self.by_addr
+            .values()
+            .filter(|conn| !self.by_mxid.values().flatten().any(|addr| *addr == conn.addr))
+            .max_by_key(|conn| conn.last_seen())
+            .cloned()
+    }
+
+    fn register(&mut self, mxid: &str, addr: &str) {
+        let addrs = self.by_mxid.entry(mxid.to_string()).or_default();
+        if !addrs.iter().any(|a| a == addr) {
+            addrs.push(addr.to_string());
+        }
+        self.missing.remove(mxid);
+    }
+
+    /// Removes a closed connection, returning the users it was the last agent of.
+    fn remove(&mut self, addr: &str) -> Vec<String> {
+        self.by_addr.remove(addr);
+        for addrs in self.by_mxid.values_mut() {
+            addrs.retain(|a| a != addr);
+        }
+        let orphaned = self.by_mxid.iter()
+            .filter(|(_, addrs)| addrs.is_empty())
+            .map(|(mxid, _)| mxid.clone())
+            .collect();
+        self.by_mxid.retain(|_, addrs| !addrs.is_empty());
+        orphaned
+    }
+
+    fn users_of(&self, addr: &str) -> Vec<String> {
+        let mut users: Vec<_> = self.by_mxid
+            .iter()
+            .filter(|(_, addrs)| addrs.iter().any(|a| a == addr))
+            .map(|(mxid, _)| mxid.clone())
+            .collect();
+        users.sort();
+        users
+    }
 }
 
 struct PendingRequest {
-    tx: oneshot::Sender<WxResponse>,
+    /// Fails with why the agent couldn't answer, e.g. when an HTTP agent is unreachable.
+    tx: oneshot::Sender<Result<WxResponse>>,
+    mxid: String,
+    request_type: RequestType,
+    /// In unix milliseconds.
+    sent_at: i64,
+    /// The connection the request was sent on and the request itself, for failing over.
+    addr: String,
+    json: String,
 }
 
 #[derive(Clone)]
 pub struct WechatService {
     addr: String,
-    secret: String,
-    connections: Arc<RwLock<HashMap<String, Connection>>>,
+    auth: AgentAuth,
+    /// `None` when agents aren't pinged.
+    keepalive: Option<Keepalive>,
+    /// Agents that post their events and are sent requests over HTTP.
+    http_agents: Vec<HttpAgentConfig>,
+    connections: Arc<RwLock<Connections>>,
     pending_requests: Arc<Mutex<HashMap<i64, PendingRequest>>>,
     request_id: Arc<AtomicI64>,
     event_tx: broadcast::Sender<Event>,
+    missing_tx: broadcast::Sender<String>,
+    status_tx: broadcast::Sender<AgentStatus>,
+    echoes: EchoTracker,
+    listening: Arc<AtomicBool>,
+    connected: Arc<Notify>,
+    shutdown: CancellationToken,
 }
 
 impl WechatService {
     pub fn new(addr: impl Into<String>, secret: impl Into<String>) -> Self {
         let (event_tx, _) = broadcast::channel(1024);
+        let (missing_tx, _) = broadcast::channel(64);
+        let (status_tx, _) = broadcast::channel(64);
         Self {
             addr: addr.into(),
-            secret: secret.into(),
-            connections: Arc::new(RwLock::new(HashMap::new())),
+            auth: AgentAuth::new(secret),
+            keepalive: Some(Keepalive::default()),
+            http_agents: Vec::new(),
+            connections: Arc::new(RwLock::new(Connections::default())),
             pending_requests: Arc::new(Mutex::new(HashMap::new())),
             request_id: Arc::new(AtomicI64::new(0)),
             event_tx,
+            missing_tx,
+            status_tx,
+            echoes: EchoTracker::default(),
+            listening: Arc::new(AtomicBool::new(false)),
+            connected: Arc::new(Notify::new()),
+            shutdown: CancellationToken::new(),
         }
     }
 
+    /// Also lets agents authenticate with the agent tokens stored in `db`.
+    pub fn with_agent_tokens(mut self, db: Database, config: &AgentAuthConfig) -> Self {
+        self.auth = self.auth.with_tokens(db, config);
+        self
+    }
+
+    pub fn with_keepalive(mut self, keepalive: Option<Keepalive>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    pub fn with_http_agents(mut self, agents: Vec<HttpAgentConfig>) -> Self {
+        self.http_agents = agents;
+        self
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::SeqCst)
+    }
+
     pub fn subscribe_events(&self) -> broadcast::Receiver<Event> {
         self.event_tx.subscribe()
     }
 
+    /// Hands the bridge an event that didn't come from an agent, like a WeCom callback.
+    pub fn publish_event(&self, event: Event) {
+        let _ = self.event_tx.send(event);
+    }
+
+    /// Receives the mxid of users whose requests failed because their agent isn't connected,
+    /// once per user until the agent registers again.
+    pub fn subscribe_missing_agents(&self) -> broadcast::Receiver<String> {
+        self.missing_tx.subscribe()
+    }
+
+    /// Receives agents connecting and disconnecting for users, and WeChat sessions ending.
+    pub fn subscribe_agent_status(&self) -> broadcast::Receiver<AgentStatus> {
+        self.status_tx.subscribe()
+    }
+
+    /// The messages recently sent through the agents, see [`EchoTracker`].
+    pub fn echoes(&self) -> &EchoTracker {
+        &self.echoes
+    }
+
     fn next_request_id(&self) -> i64 {
         self.request_id.fetch_add(1, Ordering::SeqCst) + 1
     }
@@ -60,31 +263,40 @@ impl WechatService {
         let id = self.next_request_id();
         let (tx, rx) = oneshot::channel();
         
-        {
-            let mut pending = self.pending_requests.lock().await;
-            pending.insert(id, PendingRequest { tx });
-        }
-        
         let msg = WxMessage {
             id,
             mxid: mxid.to_string(),
             msg_type: MessageType::Request,
             data: serde_json::to_value(req).ok(),
         };
+        let json = serde_json::to_string(&msg)?;
         
-        let conn = self.get_connection().await;
-        if let Some(conn) = conn {
-            let json = serde_json::to_string(&msg)?;
-            conn.tx.send(json)?;
-        } else {
+        let Some(conn) = self.get_connection(mxid).await else {
+            self.report_missing_agent(mxid).await;
+            return Err(WeChatError::ServiceUnavailable(format!("no agent connection available for {}", mxid)).into());
+        };
+        if !conn.capabilities.supports(req.request_type) {
+            return Err(WeChatError::Unsupported(format!(
+                "the agent of {} at {} can't handle {} requests",
+                mxid, conn.addr, req.request_type
+            )).into());
+        }
+        {
             let mut pending = self.pending_requests.lock().await;
-            pending.remove(&id);
-            return Err(anyhow!("no agent connection available"));
+            pending.insert(id, PendingRequest {
+                tx,
+                mxid: mxid.to_string(),
+                request_type: req.request_type,
+                sent_at: chrono::Utc::now().timestamp_millis(),
+                addr: conn.addr.clone(),
+                json: json.clone(),
+            });
         }
+        conn.tx.send(json)?;
         
         match tokio::time::timeout(REQUEST_TIMEOUT, rx).await {
-            Ok(Ok(response)) => Ok(response),
-            Ok(Err(_)) => Err(anyhow!("response channel closed")),
+            Ok(Ok(response)) => response,
+            Ok(Err(_)) => Err(anyhow!("agent disconnected before responding")),
             Err(_) => {
                 let mut pending = self.pending_requests.lock().await;
                 pending.remove(&id);
@@ -93,9 +305,96 @@ impl WechatService {
         }
     }
 
-    async fn get_connection(&self) -> Option<Connection> {
+    /// Lists the connected agents and the users registered on each.
+    pub async fn list_connections(&self) -> Vec<AgentConnectionInfo> {
+        let conns = self.connections.read().await;
+        let mut list: Vec<_> = conns.by_addr
+            .values()
+            .map(|conn| AgentConnectionInfo {
+                addr: conn.addr.clone(),
+                agent: conn.agent.clone(),
+                protocol_version: conn.protocol_version,
+                users: conns.users_of(&conn.addr),
+                last_seen: conn.last_seen(),
+            })
+            .collect();
+        list.sort_by(|a, b| a.addr.cmp(&b.addr));
+        list
+    }
+
+    /// Tells the agents of a user that its events up to `seq` were handled, so they can stop
+    /// keeping them for replay. Nothing is waited for, as a lost ack is sent again with the
+    /// next one.
+    pub async fn ack_events(&self, mxid: &str, seq: u64) {
+        let ack = WxRequest {
+            request_type: RequestType::Ack,
+            data: Some(serde_json::json!({ "seq": seq })),
+        };
+        let Ok(json) = serde_json::to_string(&WxMessage::request(self.next_request_id(), mxid, &ack)) else {
+            return;
+        };
+        let conns = self.connections.read().await;
+        let registered: Vec<_> = conns.by_mxid
+            .get(mxid)
+            .into_iter()
+            .flatten()
+            .filter_map(|addr| conns.by_addr.get(addr).cloned())
+            .collect();
+        let targets = if registered.is_empty() { conns.get(mxid).into_iter().collect() } else { registered };
+        for conn in targets {
+            let _ = conn.tx.send(json.clone());
+        }
+    }
+
+    /// Closes the connections authenticated with an agent token, returning how many there were.
+    pub async fn disconnect_agent(&self, token_id: &str) -> usize {
         let conns = self.connections.read().await;
-        conns.values().next().cloned()
+        let matching: Vec<_> = conns.by_addr
+            .values()
+            .filter(|conn| conn.agent.as_deref() == Some(token_id))
+            .collect();
+        for conn in &matching {
+            conn.close.cancel();
+        }
+        matching.len()
+    }
+
+    /// The requests waiting for a response, oldest first.
+    pub async fn pending_requests(&self) -> Vec<PendingRequestInfo> {
+        let mut list: Vec<_> = self.pending_requests.lock().await
+            .iter()
+            .map(|(id, req)| PendingRequestInfo {
+                id: *id,
+                mxid: req.mxid.clone(),
+                request_type: req.request_type,
+                addr: req.addr.clone(),
+                sent_at: req.sent_at,
+            })
+            .collect();
+        list.sort_by_key(|req| req.id);
+        list
+    }
+
+    async fn get_connection(&self, mxid: &str) -> Option<Connection> {
+        self.connections.read().await.get(mxid)
+    }
+
+    async fn report_missing_agent(&self, mxid: &str) {
+        if self.connections.write().await.missing.insert(mxid.to_string()) {
+            warn!("No agent connection available for {}", mxid);
+            let _ = self.missing_tx.send(mxid.to_string());
+        }
+    }
+
+    /// Returns once an agent is connected, immediately if one already is.
+    pub async fn wait_for_connection(&self) {
+        loop {
+            let connected = self.connected.notified();
+            if !self.connections.read().await.by_addr.is_empty() {
+                return;
+            }
+            connected.await;
+        }
     }
 
     async fn handle_json_message(&self, json: &str) {
@@ -106,7 +405,8 @@ impl WechatService {
                         if let Ok(request) = serde_json::from_value::<WxRequest>(data.clone()) {
                             if request.request_type == RequestType::Event {
                                 if let Some(event_data) = &request.data {
-                                    if let Ok(event) = serde_json::from_value::<Event>(event_data.clone()) {
+                                    if let Ok(mut event) = serde_json::from_value::<Event>(event_data.clone()) {
+                                        event.mxid = msg.mxid.clone();
                                         let _ = self.event_tx.send(event);
                                     }
                                 }
@@ -119,7 +419,7 @@ impl WechatService {
                         if let Ok(response) = serde_json::from_value::<WxResponse>(data.clone()) {
                             let mut pending = self.pending_requests.lock().await;
                             if let Some(req) = pending.remove(&msg.id) {
-                                let _ = req.tx.send(response);
+                                let _ = req.tx.send(Ok(response));
                             }
                         }
                     }
@@ -128,83 +428,283 @@ impl WechatService {
         }
     }
 
+    fn hub(&self) -> AgentHub {
+        AgentHub {
+            connections: self.connections.clone(),
+            pending_requests: self.pending_requests.clone(),
+            event_tx: self.event_tx.clone(),
+            status_tx: self.status_tx.clone(),
+            connected: self.connected.clone(),
+        }
+    }
+
+    /// Binds the listener with `bind`, retrying for a while, e.g. while an old process exits.
+    async fn bind_with_retry<T, E, F>(&self, bind: impl Fn() -> F) -> Result<T>
+    where
+        E: std::fmt::Display,
+        F: Future<Output = std::result::Result<T, E>>,
+    {
+        let mut backoff = ExponentialBackoff::new(BackoffConfig {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            max_retries: BIND_MAX_RETRIES,
+            ..Default::default()
+        });
+        loop {
+            match bind().await {
+                Ok(listener) => return Ok(listener),
+                Err(e) => match backoff.next_delay() {
+                    Some(delay) => {
+                        warn!("Failed to bind WeChat service to {}: {}, retrying in {:?}", self.addr, e, delay);
+                        tokio::time::sleep(delay).await;
+                    }
+                    None => {
+                        return Err(anyhow!("failed to bind WeChat service to {}: {}", self.addr, e));
+                    }
+                },
+            }
+        }
+    }
+
     pub async fn start(self: Arc<Self>) -> Result<()> {
         let addr = self.addr.clone();
-        info!("WeChat service listening on {}", addr);
-        
+
         let router = Router::new()
+            .push(Router::with_path("challenge").get(ChallengeHandler {
+                auth: self.auth.clone(),
+            }))
+            .push(Router::with_path("wechat/v1/events").post(http_agent::EventsHandler::new(
+                &self.http_agents,
+                self.hub(),
+            )))
             .push(Router::with_path("/").get(WebSocketHandler {
-                secret: self.secret.clone(),
-                connections: self.connections.clone(),
-                pending_requests: self.pending_requests.clone(),
-                event_tx: self.event_tx.clone(),
+                auth: self.auth.clone(),
+                keepalive: self.keepalive,
+                hub: self.hub(),
+                shutdown: self.shutdown.clone(),
             }));
 
-        let listener = TcpListener::new(addr).bind().await;
-        Server::new(listener).serve(router).await;
-        
+        let acceptor = self.bind_with_retry(|| TcpListener::new(addr.clone()).try_bind()).await?;
+
+        info!("WeChat service listening on {}", addr);
+        self.set_listening(true).await;
+        self.open_http_agents().await;
+
+        let service = self.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = Server::new(acceptor).serve(router) => {
+                    error!("WeChat service listener on {} stopped", service.addr);
+                }
+                _ = service.shutdown.cancelled() => {}
+            }
+            service.set_listening(false).await;
+        });
+
         Ok(())
     }
+
+    /// Stops accepting agents and closes the connected ones with a close frame.
+    pub async fn stop(&self) {
+        self.shutdown.cancel();
+        let closed = tokio::time::timeout(CLOSE_TIMEOUT, async {
+            while !self.connections.read().await.by_addr.is_empty() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        });
+        if closed.await.is_err() {
+            warn!("Timed out waiting for agent connections to close");
+        }
+        self.set_listening(false).await;
+    }
+
+    async fn set_listening(&self, listening: bool) {
+        self.listening.store(listening, Ordering::SeqCst);
+        crate::metrics::metrics()
+            .wechat_listener_up
+            .set(if listening { 1.0 } else { 0.0 });
+    }
 }
 
-struct WebSocketHandler {
-    secret: String,
-    connections: Arc<RwLock<HashMap<String, Connection>>>,
+/// Hands out nonces for agents to sign with their token, as `{"nonce", "expires_in"}`.
+struct ChallengeHandler {
+    auth: AgentAuth,
+}
+
+#[handler]
+impl ChallengeHandler {
+    async fn handle(&self, res: &mut Response) -> Result<(), StatusError> {
+        let Some(nonce) = self.auth.issue_challenge().await else {
+            return Err(StatusError::service_unavailable().brief("too many pending challenges"));
+        };
+        res.render(Json(serde_json::json!({
+            "nonce": nonce,
+            "expires_in": CHALLENGE_TTL.as_secs(),
+        })));
+        Ok(())
+    }
+}
+
+/// The state agent connections share, whichever transport they came in through.
+#[derive(Clone)]
+struct AgentHub {
+    connections: Arc<RwLock<Connections>>,
     pending_requests: Arc<Mutex<HashMap<i64, PendingRequest>>>,
     event_tx: broadcast::Sender<Event>,
+    status_tx: broadcast::Sender<AgentStatus>,
+    connected: Arc<Notify>,
+}
+
+impl AgentHub {
+    /// Adds a connection, which is sent the bridge's messages as JSON through `tx`.
+    async fn open(&self, addr: &str, agent: Option<String>, tx: mpsc::UnboundedSender<String>) -> Connection {
+        match &agent {
+            Some(id) => info!("Agent connected from {} with token {}", addr, id),
+            None => info!("Agent connected from {}", addr),
+        }
+        let conn = Connection {
+            addr: addr.to_string(),
+            tx,
+            last_seen: Arc::new(AtomicI64::new(chrono::Utc::now().timestamp_millis())),
+            agent,
+            close: CancellationToken::new(),
+            protocol_version: MIN_PROTOCOL_VERSION,
+            capabilities: Capabilities::legacy(),
+        };
+        {
+            let mut conns = self.connections.write().await;
+            conns.by_addr.insert(addr.to_string(), conn.clone());
+            update_connection_gauge(&conns);
+        }
+        self.connected.notify_waiters();
+        conn
+    }
+
+    /// Handles a message from the agent at `addr`, returning the reply to send it, if any, and
+    /// whether to keep the connection.
+    async fn handle_message(&self, addr: &str, wx_msg: &WxMessage) -> (Option<WxMessage>, bool) {
+        let Some(data) = &wx_msg.data else {
+            return (None, true);
+        };
+        match wx_msg.msg_type {
+            MessageType::Request => {
+                let Ok(request) = serde_json::from_value::<WxRequest>(data.clone()) else {
+                    return (None, true);
+                };
+                if request.request_type == RequestType::Hello {
+                    let (reply, keep) = handle_hello(addr, wx_msg, &request, &self.connections).await;
+                    return (Some(reply), keep);
+                } else if request.request_type == RequestType::Register {
+                    info!("Agent {} registered for {}", addr, wx_msg.mxid);
+                    self.connections.write().await.register(&wx_msg.mxid, addr);
+                    self.connected.notify_waiters();
+                    let _ = self.status_tx.send(AgentStatus::Connected(wx_msg.mxid.clone()));
+                } else if request.request_type == RequestType::Event
+                    && let Some(event_data) = &request.data
+                    && let Ok(mut event) = serde_json::from_value::<Event>(event_data.clone())
+                {
+                    event.mxid = wx_msg.mxid.clone();
+                    if event.event_type == super::EventType::Logout {
+                        let _ = self.status_tx.send(AgentStatus::LoggedOut {
+                            mxid: wx_msg.mxid.clone(),
+                            reason: event.content.filter(|r| !r.is_empty()),
+                        });
+                    } else {
+                        let _ = self.event_tx.send(event);
+                    }
+                }
+            }
+            MessageType::Response => {
+                if let Ok(response) = serde_json::from_value::<WxResponse>(data.clone()) {
+                    let mut pending = self.pending_requests.lock().await;
+                    if let Some(req) = pending.remove(&wx_msg.id) {
+                        let _ = req.tx.send(Ok(response));
+                    }
+                }
+            }
+        }
+        (None, true)
+    }
+
+    /// Answers a request waiting for an agent, or fails it.
+    async fn finish_request(&self, id: i64, result: Result<WxResponse>) {
+        if let Some(req) = self.pending_requests.lock().await.remove(&id) {
+            let _ = req.tx.send(result);
+        }
+    }
+
+    /// Removes a closed connection, failing over the requests that were waiting on it.
+    async fn close(&self, addr: &str) {
+        {
+            let mut conns = self.connections.write().await;
+            for mxid in conns.remove(addr) {
+                let _ = self.status_tx.send(AgentStatus::Disconnected(mxid));
+            }
+            update_connection_gauge(&conns);
+            fail_over_pending(addr, &conns, &mut *self.pending_requests.lock().await);
+        }
+        info!("Agent disconnected from {}", addr);
+    }
+}
+
+#[derive(Clone)]
+struct WebSocketHandler {
+    auth: AgentAuth,
+    keepalive: Option<Keepalive>,
+    hub: AgentHub,
+    shutdown: CancellationToken,
 }
 
 #[handler]
 impl WebSocketHandler {
     async fn handle(&self, req: &mut Request, res: &mut Response) -> Result<(), StatusError> {
         let auth_header: Option<String> = req.header::<String>("Authorization");
-        
-        let authorized = match auth_header {
-            Some(header) if header.starts_with("Basic ") => {
-                &header[6..] == self.secret
-            }
-            _ => false,
-        };
-        
-        if !authorized {
+        let Some(identity) = self.auth.authenticate(auth_header.as_deref()).await else {
             return Err(StatusError::forbidden());
-        }
+        };
 
         let addr = req.remote_addr().to_string();
-        let connections = self.connections.clone();
-        let pending_requests = self.pending_requests.clone();
-        let event_tx = self.event_tx.clone();
+        let agent = identity.token_id().map(str::to_string);
+        let handler = self.clone();
         
         WebSocketUpgrade::new()
             .upgrade(req, res, move |socket: WebSocket| async move {
-                handle_socket(socket, addr, connections, pending_requests, event_tx).await
+                handle_socket(socket, addr, agent, handler).await
             })
             .await
     }
 }
 
-async fn handle_socket(
-    mut socket: WebSocket,
-    addr: String,
-    connections: Arc<RwLock<HashMap<String, Connection>>>,
-    pending_requests: Arc<Mutex<HashMap<i64, PendingRequest>>>,
-    event_tx: broadcast::Sender<Event>,
-) {
-    info!("Agent connected from {}", addr);
-    
+async fn handle_socket(mut socket: WebSocket, addr: String, agent: Option<String>, handler: WebSocketHandler) {
+    let WebSocketHandler { keepalive, hub, shutdown, .. } = handler;
     let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    let conn = hub.open(&addr, agent, tx).await;
+    let last_seen = conn.last_seen.clone();
+    let close = conn.close.clone();
     
-    let conn = Connection {
-        addr: addr.clone(),
-        tx,
-    };
-    {
-        let mut conns = connections.write().await;
-        conns.insert(addr.clone(), conn);
-    }
-    
+    let mut health_check = keepalive.map(|k| tokio::time::interval(k.interval));
     loop {
         tokio::select! {
+            _ = shutdown.cancelled() => {
+                let _ = socket.send(Message::close_with(1001u16, "bridge shutting down")).await;
+                break;
+            }
+            _ = close.cancelled() => {
+                let _ = socket.send(Message::close_with(1008u16, "agent token revoked")).await;
+                break;
+            }
+            _ = tick(&mut health_check) => {
+                let timeout = keepalive.unwrap_or_default().timeout;
+                let silent_ms = chrono::Utc::now().timestamp_millis() - last_seen.load(Ordering::SeqCst);
+                if silent_ms > timeout.as_millis() as i64 {
+                    warn!("Agent {} hasn't responded for {}s, dropping the connection", addr, silent_ms / 1000);
+                    let _ = socket.send(Message::close_with(1001u16, "health check failed")).await;
+                    break;
+                }
+                if socket.send(Message::ping(Vec::new())).await.is_err() {
+                    break;
+                }
+            }
             json = rx.recv() => {
                 match json {
                     Some(json) => {
@@ -216,36 +716,21 @@ async fn handle_socket(
                 }
             }
             msg = socket.recv() => {
+                if matches!(msg, Some(Ok(_))) {
+                    last_seen.store(chrono::Utc::now().timestamp_millis(), Ordering::SeqCst);
+                }
                 match msg {
                     Some(Ok(msg)) if msg.is_text() => {
-                        if let Ok(text) = msg.as_str() {
-                            if let Ok(wx_msg) = serde_json::from_str::<WxMessage>(text) {
-                                match wx_msg.msg_type {
-                                    MessageType::Request => {
-                                        if let Some(data) = &wx_msg.data {
-                                            if let Ok(request) = serde_json::from_value::<WxRequest>(data.clone()) {
-                                                if request.request_type == RequestType::Event {
-                                                    if let Some(event_data) = &request.data {
-                                                        if let Ok(event) = serde_json::from_value::<Event>(event_data.clone()) {
-                                                            let _ = event_tx.send(event);
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                    MessageType::Response => {
-                                        if let Some(data) = &wx_msg.data {
-                                            if let Ok(response) = serde_json::from_value::<WxResponse>(data.clone()) {
-                                                let mut pending = pending_requests.lock().await;
-                                                if let Some(req) = pending.remove(&wx_msg.id) {
-                                                    let _ = req.tx.send(response);
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
+                        let Some(wx_msg) = msg.as_str().ok().and_then(|text| serde_json::from_str::<WxMessage>(text).ok()) else {
+                            continue;
+                        };
+                        let (reply, keep) = hub.handle_message(&addr, &wx_msg).await;
+                        if let Some(json) = reply.and_then(|reply| serde_json::to_string(&reply).ok()) {
+                            let _ = socket.send(Message::text(json)).await;
+                        }
+                        if !keep {
+                            let _ = socket.send(Message::close_with(1002u16, "unsupported protocol version")).await;
+                            break;
                         }
                     }
                     Some(Ok(msg)) if msg.is_close() => break,
@@ -260,9 +745,100 @@ async fn handle_socket(
         }
     }
     
-    {
-        let mut conns = connections.write().await;
-        conns.remove(&addr);
+    hub.close(&addr).await;
+}
+
+/// Waits for the next health check, forever when agents aren't pinged.
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn update_connection_gauge(conns: &Connections) {
+    crate::metrics::metrics()
+        .websocket_connections
+        .set(conns.by_addr.len() as f64);
+}
+
+/// Answers an agent's `hello` with the bridge's and records the agreed version and the agent's
+/// capabilities. Returns the answer, and `false` when there's no version both speak.
+async fn handle_hello(
+    addr: &str,
+    msg: &WxMessage,
+    request: &WxRequest,
+    connections: &RwLock<Connections>,
+) -> (WxMessage, bool) {
+    let hello = request.data.clone().and_then(|data| serde_json::from_value::<Hello>(data).ok());
+    let version = hello.as_ref().and_then(Hello::negotiate);
+    let response = match (&hello, version) {
+        (Some(hello), Some(version)) => {
+            info!("Agent {} speaks protocol version {} with {:?}", addr, version, hello.capabilities);
+            if let Some(conn) = connections.write().await.by_addr.get_mut(addr) {
+                conn.protocol_version = version;
+                conn.capabilities = hello.capabilities.clone();
+            }
+            WxResponse {
+                response_type: ResponseType::Hello,
+                error: None,
+                data: serde_json::to_value(Hello { protocol_version: version, ..Hello::bridge() }).ok(),
+            }
+        }
+        _ => {
+            let message = match &hello {
+                Some(hello) => format!(
+                    "protocol versions {}-{} aren't supported, the bridge speaks {}-{}",
+                    hello.min_protocol_version.unwrap_or(hello.protocol_version),
+                    hello.protocol_version,
+                    MIN_PROTOCOL_VERSION,
+                    PROTOCOL_VERSION
+                ),
+                None => "malformed hello".to_string(),
+            };
+            warn!("Refusing agent {}: {}", addr, message);
+            WxResponse {
+                response_type: ResponseType::Hello,
+                error: Some(ErrorResponse {
+                    http_status: 0,
+                    code: "unsupported_version".to_string(),
+                    message,
+                }),
+                data: serde_json::to_value(Hello::bridge()).ok(),
+            }
+        }
+    };
+
+    let reply = WxMessage {
+        id: msg.id,
+        mxid: msg.mxid.clone(),
+        msg_type: MessageType::Response,
+        data: serde_json::to_value(&response).ok(),
+    };
+    (reply, version.is_some())
+}
+
+/// Resends requests that were waiting on a closed connection through another agent serving the
+/// same user. Requests nobody else can serve fail right away instead of timing out.
+fn fail_over_pending(addr: &str, conns: &Connections, pending: &mut HashMap<i64, PendingRequest>) {
+    let stranded: Vec<i64> = pending.iter()
+        .filter(|(_, req)| req.addr == addr)
+        .map(|(id, _)| *id)
+        .collect();
+    for id in stranded {
+        let Some(req) = pending.get_mut(&id) else {
+            continue;
+        };
+        match conns.get(&req.mxid) {
+            Some(conn) if conn.tx.send(req.json.clone()).is_ok() => {
+                info!("Failed over request {} for {} from {} to {}", id, req.mxid, addr, conn.addr);
+                req.addr = conn.addr;
+            }
+            _ => {
+                pending.remove(&id);
+            }
+        }
     }
-    info!("Agent disconnected from {}", addr);
 }
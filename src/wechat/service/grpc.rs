@@ -0,0 +1,244 @@
+//! The gRPC transport for agents, an alternative to the websocket. Agents open a `Connect` stream
+//! and exchange the same messages as over the websocket, with the request or response as JSON in
+//! a protobuf envelope; see `proto/agent.proto`.
+
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use anyhow::Result;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_util::sync::CancellationToken;
+use tonic::codegen::{BoxFuture, BoxStream, Context, Poll, Service, http};
+use tonic::server::{Grpc, StreamingService, UnaryService};
+use tonic::transport::server::TcpIncoming;
+use tonic::{Status, Streaming};
+use tracing::{error, info, warn};
+
+use super::{AgentAuth, AgentHub, CHALLENGE_TTL, MessageType, WechatService, WxMessage};
+
+const SERVICE_NAME: &str = "wechat.agent.v1.Agent";
+
+/// A message between the bridge and an agent, like a websocket text message.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Frame {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+    #[prost(string, tag = "2")]
+    pub mxid: String,
+    #[prost(enumeration = "FrameType", tag = "3")]
+    pub r#type: i32,
+    /// The request or response as JSON, empty when there's none.
+    #[prost(string, tag = "4")]
+    pub data: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum FrameType {
+    Request = 0,
+    Response = 1,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ChallengeRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ChallengeResponse {
+    #[prost(string, tag = "1")]
+    pub nonce: String,
+    #[prost(uint64, tag = "2")]
+    pub expires_in: u64,
+}
+
+impl Frame {
+    pub fn from_message(msg: &WxMessage) -> Self {
+        Self {
+            id: msg.id,
+            mxid: msg.mxid.clone(),
+            r#type: match msg.msg_type {
+                MessageType::Request => FrameType::Request,
+                MessageType::Response => FrameType::Response,
+            } as i32,
+            data: msg.data.as_ref().map(|data| data.to_string()).unwrap_or_default(),
+        }
+    }
+
+    pub fn into_message(self) -> Result<WxMessage> {
+        let msg_type = match FrameType::try_from(self.r#type)? {
+            FrameType::Request => MessageType::Request,
+            FrameType::Response => MessageType::Response,
+        };
+        let data = if self.data.is_empty() { None } else { Some(serde_json::from_str(&self.data)?) };
+        Ok(WxMessage { id: self.id, mxid: self.mxid, msg_type, data })
+    }
+}
+
+/// Serves `Connect` and `Challenge` for agents.
+#[derive(Clone)]
+struct AgentService {
+    auth: AgentAuth,
+    hub: AgentHub,
+    shutdown: CancellationToken,
+}
+
+impl AgentService {
+    async fn connect(&self, request: tonic::Request<Streaming<Frame>>) -> Result<BoxStream<Frame>, Status> {
+        let auth_header = request.metadata().get("authorization").and_then(|v| v.to_str().ok());
+        let Some(identity) = self.auth.authenticate(auth_header).await else {
+            return Err(Status::permission_denied("invalid agent credentials"));
+        };
+        let addr = request.remote_addr().map(|addr| addr.to_string()).unwrap_or_else(|| "grpc".to_string());
+        let agent = identity.token_id().map(str::to_string);
+        let mut inbound = request.into_inner();
+
+        let (tx, rx) = mpsc::unbounded_channel::<String>();
+        let conn = self.hub.open(&addr, agent, tx).await;
+        let hub = self.hub.clone();
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            loop {
+                let frame = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = conn.close.cancelled() => break,
+                    frame = inbound.message() => frame,
+                };
+                let frame = match frame {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("gRPC stream error from {}: {}", addr, e);
+                        break;
+                    }
+                };
+                conn.last_seen.store(chrono::Utc::now().timestamp_millis(), Ordering::SeqCst);
+                let wx_msg = match frame.into_message() {
+                    Ok(wx_msg) => wx_msg,
+                    Err(e) => {
+                        warn!("Ignoring malformed frame from agent {}: {}", addr, e);
+                        continue;
+                    }
+                };
+                let (reply, keep) = hub.handle_message(&addr, &wx_msg).await;
+                if let Some(json) = reply.and_then(|reply| serde_json::to_string(&reply).ok()) {
+                    let _ = conn.tx.send(json);
+                }
+                if !keep {
+                    break;
+                }
+            }
+            // The outbound stream ends once the connection is dropped.
+            hub.close(&addr).await;
+        });
+
+        let outbound = UnboundedReceiverStream::new(rx).filter_map(|json| {
+            let msg = serde_json::from_str::<WxMessage>(&json).ok()?;
+            Some(Ok(Frame::from_message(&msg)))
+        });
+        Ok(Box::pin(outbound))
+    }
+
+    async fn challenge(&self) -> Result<ChallengeResponse, Status> {
+        let Some(nonce) = self.auth.issue_challenge().await else {
+            return Err(Status::resource_exhausted("too many pending challenges"));
+        };
+        Ok(ChallengeResponse { nonce, expires_in: CHALLENGE_TTL.as_secs() })
+    }
+}
+
+struct ConnectSvc(AgentService);
+
+impl StreamingService<Frame> for ConnectSvc {
+    type Response = Frame;
+    type ResponseStream = BoxStream<Frame>;
+    type Future = BoxFuture<tonic::Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, request: tonic::Request<Streaming<Frame>>) -> Self::Future {
+        let service = self.0.clone();
+        Box::pin(async move { service.connect(request).await.map(tonic::Response::new) })
+    }
+}
+
+struct ChallengeSvc(AgentService);
+
+impl UnaryService<ChallengeRequest> for ChallengeSvc {
+    type Response = ChallengeResponse;
+    type Future = BoxFuture<tonic::Response<Self::Response>, Status>;
+
+    fn call(&mut self, _request: tonic::Request<ChallengeRequest>) -> Self::Future {
+        let service = self.0.clone();
+        Box::pin(async move { service.challenge().await.map(tonic::Response::new) })
+    }
+}
+
+impl<B> Service<http::Request<B>> for AgentService
+where
+    B: tonic::codegen::Body + Send + 'static,
+    B::Error: Into<tonic::codegen::StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::Body>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let service = self.clone();
+        match req.uri().path().strip_prefix(&format!("/{}/", SERVICE_NAME)) {
+            Some("Connect") => Box::pin(async move {
+                let mut grpc = Grpc::new(tonic_prost::ProstCodec::default());
+                Ok(grpc.streaming(ConnectSvc(service), req).await)
+            }),
+            Some("Challenge") => Box::pin(async move {
+                let mut grpc = Grpc::new(tonic_prost::ProstCodec::default());
+                Ok(grpc.unary(ChallengeSvc(service), req).await)
+            }),
+            _ => Box::pin(async move { Ok(Status::unimplemented("").into_http()) }),
+        }
+    }
+}
+
+impl tonic::server::NamedService for AgentService {
+    const NAME: &'static str = SERVICE_NAME;
+}
+
+impl WechatService {
+    /// Like [`WechatService::start`], but agents connect over gRPC.
+    pub async fn start_grpc(self: Arc<Self>) -> Result<()> {
+        let addr = self.addr.clone();
+        let listener = self.bind_with_retry(|| tokio::net::TcpListener::bind(addr.clone())).await?;
+        let service = AgentService {
+            auth: self.auth.clone(),
+            hub: self.hub(),
+            shutdown: self.shutdown.clone(),
+        };
+        // Unlike the websocket, HTTP/2 pings keep the connection alive.
+        let keepalive = self.keepalive;
+        let server = tonic::transport::Server::builder()
+            .http2_keepalive_interval(keepalive.map(|k| k.interval))
+            .http2_keepalive_timeout(keepalive.map(|k| k.timeout));
+
+        info!("WeChat gRPC service listening on {}", addr);
+        self.set_listening(true).await;
+
+        let wechat = self.clone();
+        tokio::spawn(async move {
+            let shutdown = wechat.shutdown.clone();
+            let result = server
+                .serve_with_incoming_shutdown(service, TcpIncoming::from(listener), shutdown.cancelled_owned())
+                .await;
+            if !wechat.shutdown.is_cancelled() {
+                match result {
+                    Ok(()) => error!("WeChat gRPC service on {} stopped", wechat.addr),
+                    Err(e) => error!("WeChat gRPC service on {} failed: {}", wechat.addr, e),
+                }
+            }
+            wechat.set_listening(false).await;
+        });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+//! Agents that can't hold a connection open talk to the bridge over plain HTTP: they POST their
+//! messages to `/wechat/v1/events`, and the bridge POSTs its requests to the agent, which answers
+//! them in the HTTP response. Both directions are signed with the agent's secret, see
+//! [`sign_webhook`].
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use anyhow::anyhow;
+use salvo::prelude::*;
+use subtle::ConstantTimeEq;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use super::{AgentHub, AgentStatus, MessageType, REQUEST_TIMEOUT, WechatService, WxMessage, WxResponse};
+use crate::config::HttpAgentConfig;
+use crate::wechat::sign_webhook;
+
+/// How far a webhook's timestamp may be off, so captured webhooks can't be replayed later.
+const MAX_CLOCK_SKEW_SECS: i64 = 300;
+
+/// The address HTTP agents are listed with, as they have no connection of their own.
+fn connection_addr(agent: &HttpAgentConfig) -> String {
+    format!("http:{}", agent.id)
+}
+
+/// Checks the `X-Timestamp` and `X-Signature` of a webhook signed with `secret`.
+fn verify(secret: &str, timestamp: Option<&str>, signature: Option<&str>, body: &[u8]) -> bool {
+    let (Some(timestamp), Some(signature)) = (timestamp.and_then(|t| t.parse::<i64>().ok()), signature) else {
+        return false;
+    };
+    if (chrono::Utc::now().timestamp() - timestamp).abs() > MAX_CLOCK_SKEW_SECS {
+        return false;
+    }
+    let expected = sign_webhook(secret, timestamp, body);
+    signature.to_ascii_lowercase().as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+impl AgentHub {
+    async fn touch(&self, addr: &str) {
+        if let Some(conn) = self.connections.read().await.by_addr.get(addr) {
+            conn.last_seen.store(chrono::Utc::now().timestamp_millis(), Ordering::SeqCst);
+        }
+    }
+}
+
+/// Receives the messages of HTTP agents, answering with the reply to them, if any.
+#[derive(Clone)]
+pub(super) struct EventsHandler {
+    agents: Arc<HashMap<String, HttpAgentConfig>>,
+    hub: AgentHub,
+}
+
+impl EventsHandler {
+    pub(super) fn new(agents: &[HttpAgentConfig], hub: AgentHub) -> Self {
+        let agents = agents.iter().map(|agent| (agent.id.clone(), agent.clone())).collect();
+        Self { agents: Arc::new(agents), hub }
+    }
+}
+
+#[handler]
+impl EventsHandler {
+    async fn handle(&self, req: &mut Request, res: &mut Response) -> Result<(), StatusError> {
+        let Some(agent) = req.header::<String>("X-Agent-Id").and_then(|id| self.agents.get(&id)) else {
+            return Err(StatusError::forbidden().brief("unknown agent"));
+        };
+        let timestamp = req.header::<String>("X-Timestamp");
+        let signature = req.header::<String>("X-Signature");
+        let body = req.payload().await.map_err(|_| StatusError::bad_request())?.clone();
+        if !verify(&agent.secret, timestamp.as_deref(), signature.as_deref(), &body) {
+            return Err(StatusError::forbidden().brief("invalid signature"));
+        }
+        let msg = serde_json::from_slice::<WxMessage>(&body)
+            .map_err(|_| StatusError::bad_request().brief("malformed message"))?;
+        if !agent.users.contains(&msg.mxid) {
+            return Err(StatusError::forbidden().brief("the agent doesn't serve this user"));
+        }
+
+        let addr = connection_addr(agent);
+        self.hub.touch(&addr).await;
+        let (reply, _) = self.hub.handle_message(&addr, &msg).await;
+        res.render(Json(reply.map_or_else(|| serde_json::json!({}), |reply| serde_json::json!(reply))));
+        Ok(())
+    }
+}
+
+impl WechatService {
+    /// Lists each HTTP agent as a connection serving its users, until the service stops. What
+    /// the bridge sends on it is posted to the agent.
+    pub(super) async fn open_http_agents(&self) {
+        let http = reqwest::Client::new();
+        for agent in &self.http_agents {
+            let hub = self.hub();
+            let addr = connection_addr(agent);
+            let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+            hub.open(&addr, None, tx).await;
+            for mxid in &agent.users {
+                hub.connections.write().await.register(mxid, &addr);
+                let _ = hub.status_tx.send(AgentStatus::Connected(mxid.clone()));
+            }
+            hub.connected.notify_waiters();
+
+            let agent = agent.clone();
+            let http = http.clone();
+            let shutdown = self.shutdown.clone();
+            tokio::spawn(async move {
+                loop {
+                    let json = tokio::select! {
+                        _ = shutdown.cancelled() => break,
+                        json = rx.recv() => json,
+                    };
+                    let Some(json) = json else {
+                        break;
+                    };
+                    tokio::spawn(post_to_agent(http.clone(), agent.clone(), hub.clone(), json));
+                }
+                hub.close(&addr).await;
+            });
+        }
+    }
+}
+
+/// Posts a message for an HTTP agent, handling the response it answers with.
+async fn post_to_agent(http: reqwest::Client, agent: HttpAgentConfig, hub: AgentHub, json: String) {
+    let Ok(msg) = serde_json::from_str::<WxMessage>(&json) else {
+        return;
+    };
+    let timestamp = chrono::Utc::now().timestamp();
+    let signature = sign_webhook(&agent.secret, timestamp, json.as_bytes());
+    let result = http
+        .post(&agent.url)
+        .timeout(REQUEST_TIMEOUT)
+        .header("Content-Type", "application/json")
+        .header("X-Agent-Id", &agent.id)
+        .header("X-Timestamp", timestamp.to_string())
+        .header("X-Signature", signature)
+        .body(json)
+        .send()
+        .await;
+    let body = match result {
+        Ok(response) if response.status().is_success() => response.bytes().await,
+        Ok(response) => {
+            let error = anyhow!("agent {} answered {}", agent.id, response.status());
+            warn!("{}", error);
+            hub.finish_request(msg.id, Err(error)).await;
+            return;
+        }
+        Err(e) => Err(e),
+    };
+    let body = match body {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to reach agent {}: {}", agent.id, e);
+            hub.finish_request(msg.id, Err(anyhow!("agent {} is unreachable: {}", agent.id, e))).await;
+            return;
+        }
+    };
+    hub.touch(&connection_addr(&agent)).await;
+
+    // Messages that need no answer, like acks, may get an empty one.
+    if msg.msg_type != MessageType::Request || body.is_empty() {
+        return;
+    }
+    let result = serde_json::from_slice::<WxResponse>(&body)
+        .map_err(|e| anyhow!("agent {} answered with a malformed response: {}", agent.id, e));
+    hub.finish_request(msg.id, result).await;
+}
@@ -71,6 +71,27 @@ pub struct UserInfo {
     pub remark: Option<String>,
 }
 
+impl UserInfo {
+    fn search_fields(&self) -> impl Iterator<Item = &str> {
+        [Some(self.id.as_str()), Some(self.name.as_str()), self.remark.as_deref()]
+            .into_iter()
+            .flatten()
+            .filter(|s| !s.is_empty())
+    }
+
+    /// Whether the wxid, nickname or remark contains the query, ignoring case.
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        self.search_fields().any(|f| f.to_lowercase().contains(&query))
+    }
+
+    /// Whether the wxid, nickname or remark is exactly the query, ignoring case.
+    pub fn matches_exactly(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        self.search_fields().any(|f| f.to_lowercase() == query)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GroupInfo {
     pub id: String,
@@ -81,4 +102,7 @@ pub struct GroupInfo {
     pub notice: Option<String>,
     #[serde(default)]
     pub members: Vec<String>,
+    /// The ID of the member who owns the group, if the agent reports it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
 }
@@ -0,0 +1,175 @@
+//! WeCom posts the messages sent to the app to its callback URL, encrypted with the app's
+//! `EncodingAESKey` and signed with its token.
+
+use std::sync::Arc;
+
+use aes::cipher::block_padding::NoPadding;
+use aes::cipher::{BlockDecryptMut, KeyIvInit};
+use anyhow::{Result, anyhow};
+use base64::Engine;
+use base64::alphabet;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig, STANDARD};
+use salvo::prelude::*;
+use sha1::{Digest, Sha1};
+use subtle::ConstantTimeEq;
+use tracing::{debug, warn};
+
+use crate::config::WecomConfig;
+use crate::formatter::system::tag;
+use crate::wechat::{Chat, ChatType, Event, EventType, User, WechatService};
+
+type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+
+/// WeCom pads messages to a multiple of 32 bytes, not the AES block size.
+const PAD_BLOCK: usize = 32;
+
+/// Checks and decrypts the callbacks of a WeCom app.
+pub struct WecomCrypto {
+    token: String,
+    key: Vec<u8>,
+    corp_id: String,
+}
+
+impl WecomCrypto {
+    pub fn new(config: &WecomConfig) -> Result<Self> {
+        // The key is 43 characters of base64 without its padding, whose last bits aren't zeroed.
+        let engine = GeneralPurpose::new(
+            &alphabet::STANDARD,
+            GeneralPurposeConfig::new().with_decode_allow_trailing_bits(true),
+        );
+        let key = engine.decode(format!("{}=", config.encoding_aes_key))?;
+        if key.len() != 32 {
+            anyhow::bail!("invalid WeCom EncodingAESKey");
+        }
+        Ok(Self { token: config.token.clone(), key, corp_id: config.corp_id.clone() })
+    }
+
+    /// The `msg_signature` of an encrypted message: the SHA-1 of the token, timestamp, nonce and
+    /// message, sorted and joined.
+    pub fn signature(&self, timestamp: &str, nonce: &str, encrypted: &str) -> String {
+        let mut parts = [self.token.as_str(), timestamp, nonce, encrypted];
+        parts.sort_unstable();
+        Sha1::digest(parts.concat().as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn verify(&self, signature: &str, timestamp: &str, nonce: &str, encrypted: &str) -> bool {
+        let expected = self.signature(timestamp, nonce, encrypted);
+        signature.to_ascii_lowercase().as_bytes().ct_eq(expected.as_bytes()).into()
+    }
+
+    /// Decrypts a message, which is 16 random bytes, the message's length as a big-endian `u32`,
+    /// the message and the corp ID it's for.
+    pub fn decrypt(&self, encrypted: &str) -> Result<String> {
+        let mut data = STANDARD.decode(encrypted.trim())?;
+        let decrypted = Aes256CbcDec::new_from_slices(&self.key, &self.key[..16])
+            .map_err(|e| anyhow!("invalid key: {}", e))?
+            .decrypt_padded_mut::<NoPadding>(&mut data)
+            .map_err(|e| anyhow!("malformed message: {}", e))?;
+        let pad = decrypted.last().copied().unwrap_or(0) as usize;
+        if pad == 0 || pad > PAD_BLOCK || pad > decrypted.len() {
+            anyhow::bail!("malformed message padding");
+        }
+        let plain = &decrypted[..decrypted.len() - pad];
+        if plain.len() < 20 {
+            anyhow::bail!("message too short");
+        }
+        let len = u32::from_be_bytes([plain[16], plain[17], plain[18], plain[19]]) as usize;
+        let Some((msg, corp_id)) = plain.get(20..).and_then(|rest| (rest.len() >= len).then(|| rest.split_at(len))) else {
+            anyhow::bail!("message length out of range");
+        };
+        if corp_id != self.corp_id.as_bytes() {
+            anyhow::bail!("message for another corp");
+        }
+        Ok(String::from_utf8(msg.to_vec())?)
+    }
+}
+
+/// Turns a decrypted callback into the event of a private chat with the sender, for `mxid`.
+/// Returns `None` for messages the bridge doesn't handle, like app events.
+pub fn callback_event(xml: &str, mxid: &str) -> Option<Event> {
+    let from = tag(xml, "FromUserName")?;
+    let media = || tag(xml, "MediaId").map(|id| serde_json::json!({ "xml": id }));
+    let (event_type, content, data) = match tag(xml, "MsgType")?.as_str() {
+        "text" => (EventType::Text, tag(xml, "Content"), None),
+        "image" => (EventType::Photo, None, media()),
+        "voice" => (EventType::Audio, None, media()),
+        "video" => (EventType::Video, None, media()),
+        "location" => (EventType::Location, None, Some(serde_json::json!({
+            "latitude": tag(xml, "Location_X")?.parse::<f64>().ok()?,
+            "longitude": tag(xml, "Location_Y")?.parse::<f64>().ok()?,
+            "name": tag(xml, "Label").unwrap_or_default(),
+        }))),
+        "link" => (EventType::Text, Some(format!(
+            "{}\n{}",
+            tag(xml, "Title").unwrap_or_default(),
+            tag(xml, "Url").unwrap_or_default(),
+        )), None),
+        _ => return None,
+    };
+    let timestamp = tag(xml, "CreateTime").and_then(|t| t.parse::<i64>().ok()).unwrap_or_default();
+    Some(Event {
+        id: tag(xml, "MsgId")?,
+        thread_id: None,
+        timestamp: timestamp * 1000,
+        from: User { id: from.clone(), username: from.clone(), remark: None },
+        chat: Chat { id: from, chat_type: ChatType::Private, title: None },
+        event_type,
+        content,
+        mentions: Vec::new(),
+        reply: None,
+        data,
+        seq: None,
+        from_self: false,
+        mxid: mxid.to_string(),
+    })
+}
+
+/// Answers WeCom's URL verification and receives the messages sent to the app.
+#[derive(Clone)]
+pub struct CallbackHandler {
+    crypto: Arc<WecomCrypto>,
+    mxid: String,
+    service: Arc<WechatService>,
+}
+
+impl CallbackHandler {
+    pub fn new(config: &WecomConfig, service: Arc<WechatService>) -> Result<Self> {
+        Ok(Self { crypto: Arc::new(WecomCrypto::new(config)?), mxid: config.mxid.clone(), service })
+    }
+
+    /// Checks the signature of `encrypted` against the query, and decrypts it.
+    fn open(&self, req: &Request, encrypted: &str) -> Result<String, StatusError> {
+        let query = |name: &str| req.query::<String>(name).unwrap_or_default();
+        if !self.crypto.verify(&query("msg_signature"), &query("timestamp"), &query("nonce"), encrypted) {
+            return Err(StatusError::forbidden().brief("invalid signature"));
+        }
+        self.crypto.decrypt(encrypted).map_err(|e| {
+            warn!("Failed to decrypt WeCom callback: {}", e);
+            StatusError::bad_request().brief("malformed message")
+        })
+    }
+}
+
+#[handler]
+impl CallbackHandler {
+    async fn handle(&self, req: &mut Request, res: &mut Response) -> Result<(), StatusError> {
+        if req.method() == salvo::http::Method::GET {
+            let echo = req.query::<String>("echostr").unwrap_or_default();
+            res.render(Text::Plain(self.open(req, &echo)?));
+            return Ok(());
+        }
+
+        let body = req.payload().await.map_err(|_| StatusError::bad_request())?.clone();
+        let Some(encrypted) = tag(&String::from_utf8_lossy(&body), "Encrypt") else {
+            return Err(StatusError::bad_request().brief("missing Encrypt"));
+        };
+        let xml = self.open(req, &encrypted)?;
+        match callback_event(&xml, &self.mxid) {
+            Some(event) => self.service.publish_event(event),
+            None => debug!("Ignoring WeCom callback: {}", tag(&xml, "MsgType").unwrap_or_default()),
+        }
+        // An empty answer tells WeCom there's no passive reply.
+        res.render(Text::Plain(""));
+        Ok(())
+    }
+}
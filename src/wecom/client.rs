@@ -0,0 +1,389 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+use crate::config::WecomConfig;
+use crate::error::WeChatError;
+use crate::wechat::{GroupInfo, GroupMember, UserInfo, WechatBackend};
+
+/// Errors of an expired or revoked access token, after which a new one is fetched.
+const TOKEN_ERRCODES: [i64; 3] = [40014, 41001, 42001];
+
+/// Renew access tokens a bit before WeCom expires them.
+const TOKEN_MARGIN: Duration = Duration::from_secs(300);
+
+fn unsupported(what: &str) -> anyhow::Error {
+    WeChatError::Unsupported(format!("{} on WeCom", what)).into()
+}
+
+/// A WeCom app, as the WeChat account of its Matrix user. Private chats are the app's chats with
+/// the corp's members, and groups are the app's group chats (`appchat`).
+pub struct WecomClient {
+    config: WecomConfig,
+    http: reqwest::Client,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl WecomClient {
+    pub fn new(config: WecomConfig, http: reqwest::Client) -> Self {
+        Self { config, http, token: Mutex::new(None) }
+    }
+
+    fn url(&self, path: &str, query: &[(&str, &str)]) -> String {
+        let query: Vec<String> = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, urlencoding::encode(value)))
+            .collect();
+        format!("{}/cgi-bin/{}?{}", self.config.api_url.trim_end_matches('/'), path, query.join("&"))
+    }
+
+    async fn access_token(&self) -> Result<String> {
+        let mut token = self.token.lock().await;
+        if let Some((token, expires)) = token.as_ref()
+            && Instant::now() < *expires
+        {
+            return Ok(token.clone());
+        }
+        let response: Value = self.http
+            .get(self.url("gettoken", &[("corpid", &self.config.corp_id), ("corpsecret", &self.config.corp_secret)]))
+            .send()
+            .await?
+            .json()
+            .await?;
+        let response = check(response)?;
+        let access_token = response["access_token"].as_str().ok_or_else(|| anyhow!("invalid response"))?.to_string();
+        let expires_in = Duration::from_secs(response["expires_in"].as_u64().unwrap_or(7200));
+        *token = Some((access_token.clone(), Instant::now() + expires_in.saturating_sub(TOKEN_MARGIN)));
+        Ok(access_token)
+    }
+
+    /// Calls an API method, with a new access token if WeCom rejected the cached one.
+    async fn call(&self, method: reqwest::Method, path: &str, query: &[(&str, &str)], body: Option<&Value>) -> Result<Value> {
+        for attempt in 0..2 {
+            let token = self.access_token().await?;
+            let query = [&[("access_token", token.as_str())], query].concat();
+            let mut request = self.http.request(method.clone(), self.url(path, &query));
+            if let Some(body) = body {
+                request = request.json(body);
+            }
+            let response: Value = request.send().await?.json().await?;
+            if attempt == 0 && TOKEN_ERRCODES.contains(&response["errcode"].as_i64().unwrap_or(0)) {
+                *self.token.lock().await = None;
+                continue;
+            }
+            return check(response);
+        }
+        unreachable!("the second attempt returns")
+    }
+
+    async fn get(&self, path: &str, query: &[(&str, &str)]) -> Result<Value> {
+        self.call(reqwest::Method::GET, path, query, None).await
+    }
+
+    async fn post(&self, path: &str, body: Value) -> Result<Value> {
+        self.call(reqwest::Method::POST, path, &[], Some(&body)).await
+    }
+
+    /// Whether `chat_id` is one of the app's group chats rather than a member.
+    async fn is_group(&self, chat_id: &str) -> bool {
+        self.get("appchat/get", &[("chatid", chat_id)]).await.is_ok()
+    }
+
+    /// Sends a message of `msgtype` with its `content` to a member or group chat.
+    async fn send(&self, chat_id: &str, msgtype: &str, content: Value) -> Result<String> {
+        let mut body = if self.is_group(chat_id).await {
+            serde_json::json!({ "chatid": chat_id, "msgtype": msgtype })
+        } else {
+            serde_json::json!({ "touser": chat_id, "agentid": self.config.agent_id, "msgtype": msgtype })
+        };
+        body[msgtype] = content;
+        let path = if body.get("chatid").is_some() { "appchat/send" } else { "message/send" };
+        let response = self.post(path, body).await?;
+        // Group messages get no ID, which only makes them impossible to recall.
+        Ok(response["msgid"]
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("wecom-{}", chrono::Utc::now().timestamp_millis())))
+    }
+
+    async fn upload(&self, media_type: &str, data: &[u8], filename: &str) -> Result<String> {
+        // reqwest is built without multipart support, so the form is written by hand.
+        let boundary = format!("wecom-{}", chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default());
+        let mut body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"media\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            filename.replace('"', "")
+        ).into_bytes();
+        body.extend_from_slice(data);
+        body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+        let token = self.access_token().await?;
+        let response: Value = self.http
+            .post(self.url("media/upload", &[("access_token", &token), ("type", media_type)]))
+            .header("Content-Type", format!("multipart/form-data; boundary={boundary}"))
+            .body(body)
+            .send()
+            .await?
+            .json()
+            .await?;
+        let response = check(response)?;
+        response["media_id"].as_str().map(str::to_string).ok_or_else(|| anyhow!("invalid response"))
+    }
+
+    async fn send_media(&self, chat_id: &str, media_type: &str, data: &[u8], filename: &str) -> Result<String> {
+        let media_id = self.upload(media_type, data, filename).await?;
+        self.send(chat_id, media_type, serde_json::json!({ "media_id": media_id })).await
+    }
+
+    async fn download(&self, media_id: &str) -> Result<Vec<u8>> {
+        let token = self.access_token().await?;
+        let response = self.http
+            .get(self.url("media/get", &[("access_token", &token), ("media_id", media_id)]))
+            .send()
+            .await?;
+        // Errors come back as JSON instead of the file.
+        let is_json = response.headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("application/json") || v.starts_with("text/plain"));
+        let body = response.bytes().await?;
+        if is_json {
+            check(serde_json::from_slice(&body)?)?;
+        }
+        Ok(body.to_vec())
+    }
+
+    async fn update_group(&self, group_id: &str, update: Value) -> Result<()> {
+        let mut body = serde_json::json!({ "chatid": group_id });
+        if let (Some(body), Some(update)) = (body.as_object_mut(), update.as_object()) {
+            body.extend(update.clone());
+        }
+        self.post("appchat/update", body).await?;
+        Ok(())
+    }
+}
+
+/// Fails with WeCom's error message unless `errcode` is 0.
+fn check(response: Value) -> Result<Value> {
+    match response["errcode"].as_i64() {
+        Some(0) | None => Ok(response),
+        Some(code) => Err(anyhow!(
+            "WeCom error {}: {}",
+            code,
+            response["errmsg"].as_str().unwrap_or_default()
+        )),
+    }
+}
+
+fn user_info(user: &Value) -> Result<UserInfo> {
+    Ok(UserInfo {
+        id: user["userid"].as_str().ok_or_else(|| anyhow!("invalid response"))?.to_string(),
+        name: user["name"].as_str().unwrap_or_default().to_string(),
+        avatar: user["avatar"].as_str().filter(|a| !a.is_empty()).map(str::to_string),
+        remark: None,
+    })
+}
+
+#[async_trait]
+impl WechatBackend for WecomClient {
+    fn mxid(&self) -> &str {
+        &self.config.mxid
+    }
+
+    /// Logs in with the corp ID and the app's secret.
+    async fn connect(&self) -> Result<()> {
+        self.access_token().await?;
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        *self.token.lock().await = None;
+        Ok(())
+    }
+
+    async fn is_logged_in(&self) -> Result<bool> {
+        Ok(self.access_token().await.is_ok())
+    }
+
+    async fn get_qrcode(&self) -> Result<Vec<u8>> {
+        Err(unsupported("QR code login"))
+    }
+
+    /// The app itself, which sends the user's messages.
+    async fn get_self(&self) -> Result<UserInfo> {
+        let agent_id = self.config.agent_id.to_string();
+        let agent = self.get("agent/get", &[("agentid", &agent_id)]).await?;
+        Ok(UserInfo {
+            id: agent_id,
+            name: agent["name"].as_str().unwrap_or_default().to_string(),
+            avatar: agent["square_logo_url"].as_str().filter(|a| !a.is_empty()).map(str::to_string),
+            remark: None,
+        })
+    }
+
+    async fn get_user_info(&self, wxid: &str) -> Result<UserInfo> {
+        user_info(&self.get("user/get", &[("userid", wxid)]).await?)
+    }
+
+    async fn get_friend_list(&self) -> Result<Vec<UserInfo>> {
+        let response = self.get("user/simplelist", &[("department_id", "1"), ("fetch_child", "1")]).await?;
+        response["userlist"]
+            .as_array()
+            .map(|users| users.iter().map(user_info).collect())
+            .unwrap_or_else(|| Ok(Vec::new()))
+    }
+
+    async fn accept_friend(&self, _v3: &str) -> Result<()> {
+        Err(unsupported("friend requests"))
+    }
+
+    async fn set_nickname(&self, _nickname: &str) -> Result<()> {
+        Err(unsupported("setting the nickname"))
+    }
+
+    async fn set_avatar(&self, _avatar_data: &[u8]) -> Result<()> {
+        Err(unsupported("setting the avatar"))
+    }
+
+    async fn set_presence(&self, _presence: &str) -> Result<()> {
+        Err(unsupported("presence"))
+    }
+
+    /// Contacts are looked up when needed, and messages pushed to the callback.
+    async fn refresh_contacts(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn sync_messages(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// WeCom can't list the app's group chats, they're bridged as messages are sent to them.
+    async fn get_group_list(&self) -> Result<Vec<GroupInfo>> {
+        Ok(Vec::new())
+    }
+
+    async fn get_group_info(&self, group_id: &str) -> Result<GroupInfo> {
+        let response = self.get("appchat/get", &[("chatid", group_id)]).await?;
+        let chat = &response["chat_info"];
+        Ok(GroupInfo {
+            id: group_id.to_string(),
+            name: chat["name"].as_str().unwrap_or_default().to_string(),
+            avatar: None,
+            notice: None,
+            members: chat["userlist"]
+                .as_array()
+                .map(|users| users.iter().filter_map(|u| u.as_str().map(str::to_string)).collect())
+                .unwrap_or_default(),
+            owner: chat["owner"].as_str().map(str::to_string),
+        })
+    }
+
+    async fn get_group_members(&self, group_id: &str) -> Result<Vec<GroupMember>> {
+        let mut members = Vec::new();
+        for id in self.get_group_info(group_id).await?.members {
+            let name = match self.get_user_info(&id).await {
+                Ok(user) => user.name,
+                Err(_) => id.clone(),
+            };
+            members.push(GroupMember { id, name, nickname: None, avatar: None });
+        }
+        Ok(members)
+    }
+
+    async fn get_group_member_nickname(&self, _group_id: &str, member_id: &str) -> Result<String> {
+        Ok(self.get_user_info(member_id).await?.name)
+    }
+
+    async fn create_group(&self, user_ids: &[&str], name: &str) -> Result<String> {
+        let response = self.post("appchat/create", serde_json::json!({
+            "name": name,
+            "userlist": user_ids,
+        })).await?;
+        response["chatid"].as_str().map(str::to_string).ok_or_else(|| anyhow!("invalid response"))
+    }
+
+    async fn set_group_name(&self, group_id: &str, name: &str) -> Result<()> {
+        self.update_group(group_id, serde_json::json!({ "name": name })).await
+    }
+
+    async fn invite_group_member(&self, group_id: &str, user_ids: &[&str]) -> Result<()> {
+        self.update_group(group_id, serde_json::json!({ "add_user_list": user_ids })).await
+    }
+
+    async fn remove_group_member(&self, group_id: &str, user_ids: &[&str]) -> Result<()> {
+        self.update_group(group_id, serde_json::json!({ "del_user_list": user_ids })).await
+    }
+
+    async fn quit_group(&self, _group_id: &str) -> Result<()> {
+        Err(unsupported("leaving groups"))
+    }
+
+    async fn join_group(&self, _invite: &str) -> Result<String> {
+        Err(unsupported("group invites"))
+    }
+
+    /// WeCom has no quotes or mentions in app messages, so replies are sent as plain text.
+    async fn send_text_with_mentions(
+        &self,
+        chat_id: &str,
+        text: &str,
+        _reply_to: Option<&str>,
+        _quote: Option<&str>,
+        _mentions: &[String],
+    ) -> Result<String> {
+        self.send(chat_id, "text", serde_json::json!({ "content": text })).await
+    }
+
+    async fn edit_text_message(&self, _chat_id: &str, _msg_id: &str, _text: &str) -> Result<()> {
+        Err(unsupported("editing messages"))
+    }
+
+    async fn send_image_message(&self, chat_id: &str, image_data: &[u8], _reply_to: Option<&str>) -> Result<String> {
+        self.send_media(chat_id, "image", image_data, "image.png").await
+    }
+
+    async fn send_video_message(&self, chat_id: &str, video_data: &[u8], _reply_to: Option<&str>) -> Result<String> {
+        self.send_media(chat_id, "video", video_data, "video.mp4").await
+    }
+
+    async fn send_file_message(&self, chat_id: &str, file_data: &[u8], filename: &str, _reply_to: Option<&str>) -> Result<String> {
+        self.send_media(chat_id, "file", file_data, filename).await
+    }
+
+    async fn send_emoji_message(&self, chat_id: &str, emoji_data: &[u8]) -> Result<String> {
+        self.send_media(chat_id, "image", emoji_data, "emoji.gif").await
+    }
+
+    async fn send_reaction(&self, _chat_id: &str, _msg_id: &str, _emoji: &str) -> Result<()> {
+        Err(unsupported("reactions"))
+    }
+
+    async fn mark_read(&self, _chat_id: &str, _msg_id: &str) -> Result<()> {
+        Err(unsupported("read receipts"))
+    }
+
+    async fn revoke_message(&self, _chat_id: &str, msg_id: &str) -> Result<()> {
+        self.post("message/recall", serde_json::json!({ "msgid": msg_id })).await?;
+        Ok(())
+    }
+
+    /// Media of WeCom events carry their `MediaId` instead of the XML of WeChat messages.
+    async fn download_image(&self, xml: &str) -> Result<Vec<u8>> {
+        self.download(xml).await
+    }
+
+    async fn download_video(&self, xml: &str) -> Result<Vec<u8>> {
+        self.download(xml).await
+    }
+
+    async fn download_audio(&self, xml: &str) -> Result<Vec<u8>> {
+        self.download(xml).await
+    }
+
+    async fn download_file(&self, xml: &str) -> Result<Vec<u8>> {
+        self.download(xml).await
+    }
+}
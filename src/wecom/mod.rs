@@ -0,0 +1,8 @@
+//! WeCom (企业微信) apps, bridged through the official API rather than an agent. The app's
+//! chats go through the same portals and puppets as the agent-based accounts.
+
+mod callback;
+mod client;
+
+pub use callback::*;
+pub use client::*;
@@ -1,3 +1,16 @@
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Result, anyhow};
+use matrix_bridge_wechat::bridge::WechatBridge;
+use matrix_bridge_wechat::config::Config;
+use matrix_bridge_wechat::database::{Database, Message, Portal, User};
+use matrix_bridge_wechat::matrix::RoomEvent;
+use matrix_bridge_wechat::wechat::{BackendFactory, GroupInfo, GroupMember, UserInfo, WechatBackend};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
 pub fn init_test_logger() {
     let _ = tracing_subscriber::fmt()
         .with_env_filter(
@@ -7,3 +20,376 @@ pub fn init_test_logger() {
         .with_test_writer()
         .try_init();
 }
+
+/// A file in the temp directory, removed when dropped. Names are made unique per test process.
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("wechat-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+
+    pub fn uri(&self) -> String {
+        self.0.to_string_lossy().to_string()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// A migrated SQLite database, deleted when dropped.
+pub struct TestDb {
+    db: Database,
+    _file: TempFile,
+}
+
+impl Deref for TestDb {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        &self.db
+    }
+}
+
+/// An SQLite database without any schema yet.
+pub async fn empty_db(name: &str) -> TestDb {
+    let file = TempFile::new(&format!("{}.db", name));
+    let db = Database::connect("sqlite", &file.uri(), 1, 1).await.unwrap();
+    TestDb { db, _file: file }
+}
+
+pub async fn test_db(name: &str) -> TestDb {
+    let db = empty_db(name).await;
+    db.run_migrations().await.unwrap();
+    db
+}
+
+/// The example config, with an SQLite database that is deleted with the returned file.
+pub fn test_config(name: &str) -> (Config, TempFile) {
+    let file = TempFile::new(&format!("{}.db", name));
+    let example = std::fs::read_to_string("example-config.yaml").unwrap();
+    let mut config: Config = serde_yaml::from_str(&example).unwrap();
+    config.appservice.database.r#type = "sqlite".to_string();
+    config.appservice.database.uri = file.uri();
+    (config, file)
+}
+
+/// A portal without a room or any synced info.
+pub fn portal(uid: &str, receiver: &str) -> Portal {
+    Portal {
+        uid: uid.to_string(),
+        receiver: receiver.to_string(),
+        mxid: None,
+        name: String::new(),
+        name_set: false,
+        topic: String::new(),
+        topic_set: false,
+        avatar: String::new(),
+        avatar_url: None,
+        avatar_set: false,
+        encrypted: false,
+        last_sync: 0,
+        first_event_id: None,
+        next_batch_id: None,
+    }
+}
+
+/// The WeChat side of a test: only [`ALICE`] has an agent, logged in as `wxid_me`, which is in
+/// the group `123@chatroom` with Alice and Bob and records what the bridge asks of it. Requests
+/// for anyone else fail, like with agents routed per user.
+#[derive(Default)]
+pub struct FakeWechat {
+    calls: Mutex<Vec<String>>,
+    /// Makes the agent refuse to edit messages, like agents without edit support.
+    pub edits_fail: AtomicBool,
+}
+
+impl FakeWechat {
+    pub fn backends(self: &Arc<Self>) -> BackendFactory {
+        let wechat = self.clone();
+        Arc::new(move |mxid: &str| -> Arc<dyn WechatBackend> {
+            Arc::new(FakeBackend { mxid: mxid.to_string(), wechat: wechat.clone() })
+        })
+    }
+
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn record(&self, call: String) {
+        self.calls.lock().unwrap().push(call);
+    }
+}
+
+pub const GROUP_ID: &str = "123@chatroom";
+
+struct FakeBackend {
+    mxid: String,
+    wechat: Arc<FakeWechat>,
+}
+
+impl FakeBackend {
+    fn agent(&self) -> Result<&FakeWechat> {
+        if self.mxid != ALICE {
+            return Err(anyhow!("no agent is connected for {:?}", self.mxid));
+        }
+        Ok(&self.wechat)
+    }
+}
+
+fn user_info(id: &str) -> UserInfo {
+    let name = id.strip_prefix("wxid_").unwrap_or(id);
+    UserInfo { id: id.to_string(), name: name.to_string(), avatar: None, remark: None }
+}
+
+#[async_trait::async_trait]
+impl WechatBackend for FakeBackend {
+    fn mxid(&self) -> &str { &self.mxid }
+    async fn connect(&self) -> Result<()> { Ok(()) }
+    async fn disconnect(&self) -> Result<()> { Ok(()) }
+    async fn is_logged_in(&self) -> Result<bool> { Ok(true) }
+    async fn get_qrcode(&self) -> Result<Vec<u8>> { Err(anyhow!("unsupported")) }
+    async fn get_self(&self) -> Result<UserInfo> { Ok(user_info("wxid_me")) }
+    async fn get_user_info(&self, wxid: &str) -> Result<UserInfo> {
+        self.agent()?;
+        Ok(user_info(wxid))
+    }
+    async fn get_friend_list(&self) -> Result<Vec<UserInfo>> { Ok(vec![user_info("wxid_alice"), user_info("wxid_bob")]) }
+    async fn accept_friend(&self, _: &str) -> Result<()> { Err(anyhow!("unsupported")) }
+    async fn set_nickname(&self, _: &str) -> Result<()> { Err(anyhow!("unsupported")) }
+    async fn set_avatar(&self, _: &[u8]) -> Result<()> { Err(anyhow!("unsupported")) }
+    async fn set_presence(&self, _: &str) -> Result<()> { Ok(()) }
+    async fn refresh_contacts(&self) -> Result<()> { Ok(()) }
+    async fn sync_messages(&self) -> Result<()> { Ok(()) }
+    async fn get_group_list(&self) -> Result<Vec<GroupInfo>> {
+        Ok(vec![self.get_group_info(GROUP_ID).await?])
+    }
+    async fn get_group_info(&self, group_id: &str) -> Result<GroupInfo> {
+        self.agent()?;
+        let info = serde_json::json!({
+            "id": group_id,
+            "name": "Family",
+            "members": ["wxid_me", "wxid_alice", "wxid_bob"],
+        });
+        Ok(serde_json::from_value(info)?)
+    }
+    async fn get_group_members(&self, _: &str) -> Result<Vec<GroupMember>> {
+        self.agent()?;
+        Ok(["wxid_me", "wxid_alice", "wxid_bob"].iter().map(|id| GroupMember {
+            id: id.to_string(),
+            name: user_info(id).name,
+            nickname: None,
+            avatar: None,
+        }).collect())
+    }
+    async fn get_group_member_nickname(&self, _: &str, member_id: &str) -> Result<String> { Ok(user_info(member_id).name) }
+    async fn create_group(&self, user_ids: &[&str], name: &str) -> Result<String> {
+        // Sorted, as the members of a room come in no particular order.
+        let mut user_ids = user_ids.to_vec();
+        user_ids.sort();
+        self.agent()?.record(format!("create_group {} {}", user_ids.join(","), name));
+        Ok("new@chatroom".to_string())
+    }
+    async fn set_group_name(&self, group_id: &str, name: &str) -> Result<()> {
+        self.agent()?.record(format!("set_group_name {} {}", group_id, name));
+        Ok(())
+    }
+    async fn invite_group_member(&self, _: &str, _: &[&str]) -> Result<()> { Err(anyhow!("unsupported")) }
+    async fn remove_group_member(&self, _: &str, _: &[&str]) -> Result<()> { Err(anyhow!("unsupported")) }
+    async fn quit_group(&self, _: &str) -> Result<()> { Err(anyhow!("unsupported")) }
+    async fn join_group(&self, _: &str) -> Result<String> { Err(anyhow!("unsupported")) }
+    async fn send_text_with_mentions(&self, chat_id: &str, text: &str, reply_to: Option<&str>, _: Option<&str>, _: &[String]) -> Result<String> {
+        self.agent()?.record(format!("send_text {} {} reply={}", chat_id, text, reply_to.unwrap_or("-")));
+        Ok(format!("sent:{}", text))
+    }
+    async fn edit_text_message(&self, chat_id: &str, msg_id: &str, text: &str) -> Result<()> {
+        if self.agent()?.edits_fail.load(Ordering::SeqCst) {
+            return Err(anyhow!("unsupported"));
+        }
+        self.wechat.record(format!("edit_text {} {} {}", chat_id, msg_id, text));
+        Ok(())
+    }
+    async fn send_image_message(&self, _: &str, _: &[u8], _: Option<&str>) -> Result<String> { Err(anyhow!("unsupported")) }
+    async fn send_video_message(&self, _: &str, _: &[u8], _: Option<&str>) -> Result<String> { Err(anyhow!("unsupported")) }
+    async fn send_file_message(&self, _: &str, _: &[u8], _: &str, _: Option<&str>) -> Result<String> { Err(anyhow!("unsupported")) }
+    async fn send_emoji_message(&self, _: &str, _: &[u8]) -> Result<String> { Err(anyhow!("unsupported")) }
+    async fn send_reaction(&self, _: &str, _: &str, _: &str) -> Result<()> { Err(anyhow!("unsupported")) }
+    async fn mark_read(&self, _: &str, _: &str) -> Result<()> { Ok(()) }
+    async fn revoke_message(&self, chat_id: &str, msg_id: &str) -> Result<()> {
+        self.agent()?.record(format!("revoke {} {}", chat_id, msg_id));
+        Ok(())
+    }
+    async fn download_image(&self, _: &str) -> Result<Vec<u8>> { Err(anyhow!("unsupported")) }
+    async fn download_video(&self, _: &str) -> Result<Vec<u8>> { Err(anyhow!("unsupported")) }
+    async fn download_audio(&self, _: &str) -> Result<Vec<u8>> { Err(anyhow!("unsupported")) }
+    async fn download_file(&self, _: &str) -> Result<Vec<u8>> { Err(anyhow!("unsupported")) }
+}
+
+/// A sent message of `wxid_me` in the chat `chat_uid`.
+pub fn message(chat_uid: &str, msg_id: &str, mxid: &str, sender: &str) -> Message {
+    Message {
+        chat_uid: chat_uid.to_string(),
+        chat_receiver: "wxid_me".to_string(),
+        msg_id: msg_id.to_string(),
+        mxid: mxid.to_string(),
+        sender: sender.to_string(),
+        timestamp: 0,
+        sent: true,
+        error: None,
+        msg_type: "m.text".to_string(),
+    }
+}
+
+/// A request the [`MockHomeserver`] received.
+#[derive(Debug, Clone)]
+pub struct HomeserverRequest {
+    pub method: String,
+    /// The path with its query.
+    pub path: String,
+    pub body: serde_json::Value,
+}
+
+/// A homeserver that accepts every request and records it. Answers have the fields of the
+/// usual responses, with a new event and room ID each time, and whatever was set with
+/// [`MockHomeserver::respond_with`].
+pub struct MockHomeserver {
+    pub url: String,
+    requests: Arc<Mutex<Vec<HomeserverRequest>>>,
+    fields: Arc<Mutex<serde_json::Map<String, serde_json::Value>>>,
+}
+
+impl MockHomeserver {
+    pub async fn start() -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let fields = Arc::new(Mutex::new(serde_json::Map::new()));
+        let extra_fields = fields.clone();
+        let counter = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let recorded = recorded.clone();
+                let extra_fields = extra_fields.lock().unwrap().clone();
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let Some(request) = read_request(&mut socket).await else {
+                        return;
+                    };
+                    recorded.lock().unwrap().push(request);
+                    let mut body = serde_json::json!({
+                        "event_id": format!("$mock{}", n),
+                        "room_id": format!("!mock{}:example.com", n),
+                        "content_uri": "mxc://example.com/mock",
+                        "user_id": "@wechatbot:example.com",
+                        "joined": {},
+                        "users": {},
+                        "chunk": [],
+                    });
+                    body.as_object_mut().unwrap().extend(extra_fields);
+                    let body = body.to_string();
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(), body
+                    );
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        Self { url, requests, fields }
+    }
+
+    /// Adds `field` to every answer from now on, e.g. `joined` for the members of rooms.
+    pub fn respond_with(&self, field: &str, value: serde_json::Value) {
+        self.fields.lock().unwrap().insert(field.to_string(), value);
+    }
+
+    pub fn requests(&self) -> Vec<HomeserverRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The requests whose path contains `part`.
+    pub fn requests_to(&self, part: &str) -> Vec<HomeserverRequest> {
+        self.requests().into_iter().filter(|r| r.path.contains(part)).collect()
+    }
+
+    /// The bodies of the messages sent to `room_id`, e.g. the replies of the bot.
+    pub fn messages_in(&self, room_id: &str) -> Vec<String> {
+        self.requests_to(&format!("/rooms/{}/send/m.room.message/", room_id))
+            .into_iter()
+            .filter_map(|r| r.body["body"].as_str().map(str::to_string))
+            .collect()
+    }
+}
+
+async fn read_request(socket: &mut tokio::net::TcpStream) -> Option<HomeserverRequest> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 8192];
+    let header_end = loop {
+        let n = socket.read(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        data.extend_from_slice(&buf[..n]);
+        if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+    let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+    let length = head.lines()
+        .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    while data.len() < header_end + length {
+        let n = socket.read(&mut buf).await.ok()?;
+        if n == 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..n]);
+    }
+    let mut request_line = head.lines().next()?.split(' ');
+    Some(HomeserverRequest {
+        method: request_line.next()?.to_string(),
+        path: urlencoding::decode(request_line.next()?).ok()?.to_string(),
+        body: serde_json::from_slice(&data[header_end..]).unwrap_or(serde_json::Value::Null),
+    })
+}
+
+/// A bridge talking to a [`MockHomeserver`] and [`FakeWechat`], where `@alice:example.com` is
+/// logged in as `wxid_me`.
+pub struct TestBridge {
+    pub bridge: Arc<WechatBridge>,
+    pub wechat: Arc<FakeWechat>,
+    pub homeserver: MockHomeserver,
+    _db: TempFile,
+}
+
+pub const ALICE: &str = "@alice:example.com";
+
+/// A message from [`ALICE`] in `room_id`, e.g. a command.
+pub fn alice_says(room_id: &str, body: &str) -> RoomEvent {
+    static EVENTS: AtomicUsize = AtomicUsize::new(0);
+    serde_json::from_value(serde_json::json!({
+        "type": "m.room.message",
+        "room_id": room_id,
+        "sender": ALICE,
+        "event_id": format!("$alice{}", EVENTS.fetch_add(1, Ordering::SeqCst)),
+        "content": { "msgtype": "m.text", "body": body },
+    }))
+    .unwrap()
+}
+
+pub async fn test_bridge(name: &str) -> TestBridge {
+    let homeserver = MockHomeserver::start().await;
+    let (mut config, db_file) = test_config(name);
+    config.homeserver.address = homeserver.url.clone();
+    let wechat = Arc::new(FakeWechat::default());
+    let bridge = WechatBridge::new(config).await.unwrap().with_backends(wechat.backends());
+    bridge.db.insert_user(&User { uin: Some("wxid_me".to_string()), ..User::new(ALICE) }).await.unwrap();
+    TestBridge { bridge: Arc::new(bridge), wechat, homeserver, _db: db_file }
+}
@@ -55,6 +55,311 @@ mod error_tests {
     }
 }
 
+#[cfg(test)]
+mod reporting_tests {
+    use matrix_bridge_wechat::reporting::scrub;
+
+    #[test]
+    fn test_scrub_secrets() {
+        let text = "GET /_matrix/client/v3/sync?access_token=syt_abc123&since=s1 as_token: \"hunter2\"";
+        let scrubbed = scrub(text);
+
+        assert!(!scrubbed.contains("syt_abc123"));
+        assert!(!scrubbed.contains("hunter2"));
+        assert!(scrubbed.contains("access_token=[redacted]&since=s1"));
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use matrix_bridge_wechat::config::{
+        ProvisioningConfig, RelayConfig, render_displayname_template, validate_displayname_template,
+    };
+
+    #[test]
+    fn test_displayname_template() {
+        let template = "{{if .Remark}}{{.Remark}}{{else if .Nickname}}{{.Nickname}}{{else}}{{.Uin}}{{end}} (WeChat)";
+
+        let render = |uin, name, remark| render_displayname_template(template, uin, name, remark).unwrap();
+        assert_eq!(render("wxid_1", "Alice", "Ally"), "Ally (WeChat)");
+        assert_eq!(render("wxid_1", "Alice", ""), "Alice (WeChat)");
+        assert_eq!(render("wxid_1", "", ""), "wxid_1 (WeChat)");
+
+        let nickname_only = render_displayname_template("{{.Nickname}}", "wxid_1", "", "Ally").unwrap();
+        assert_eq!(nickname_only, "Ally");
+    }
+
+    #[test]
+    fn test_invalid_displayname_template() {
+        assert!(validate_displayname_template("{{if .Name}}{{.Name}}").is_err());
+        assert!(validate_displayname_template("{{.Phone}}").is_err());
+        assert!(validate_displayname_template("{{.Name}} (WeChat)").is_ok());
+    }
+
+    #[test]
+    fn test_provisioning_requires_secret() {
+        let mut provisioning = ProvisioningConfig::default();
+        assert!(!provisioning.enabled());
+        provisioning.shared_secret = "disable".to_string();
+        assert!(!provisioning.enabled());
+        provisioning.shared_secret = "s3cret".to_string();
+        assert!(provisioning.enabled());
+    }
+
+    #[test]
+    fn test_relay_message_format() {
+        let relay = RelayConfig::default();
+        assert_eq!(relay.format_message("m.text", "Bob", "@bob:example.com", "hi"), "Bob: hi");
+        assert_eq!(relay.format_message("m.emote", "Bob", "@bob:example.com", "waves"), "* Bob waves");
+        assert_eq!(relay.format_message("m.image", "", "@bob:example.com", "cat.jpg"), "@bob:example.com sent an image");
+        assert_eq!(relay.format_message("m.location", "Bob", "@bob:example.com", "geo"), "Bob: geo");
+    }
+
+    #[test]
+    fn test_no_puppet_sender_prefix() {
+        use matrix_bridge_wechat::config::NoPuppetConfig;
+        use matrix_bridge_wechat::formatter::emoji::avatar_emoji;
+
+        let no_puppet = NoPuppetConfig::default();
+        let avatar = avatar_emoji("wxid_bob");
+        // The same sender always gets the same avatar.
+        assert_eq!(avatar_emoji("wxid_bob"), avatar);
+
+        let (text, html) = no_puppet.format_prefix("wxid_bob", "Bob <3");
+        assert_eq!(text, format!("{} Bob <3: ", avatar));
+        assert_eq!(html, format!("{} <strong>Bob &lt;3</strong>: ", avatar));
+        // Senders without a name are shown by their ID.
+        let (text, _) = no_puppet.format_prefix("wxid_bob", "");
+        assert_eq!(text, format!("{} wxid_bob: ", avatar));
+
+        let custom = NoPuppetConfig { text_template: "[{{.Uin}}] ".to_string(), ..NoPuppetConfig::default() };
+        assert_eq!(custom.format_prefix("wxid_bob", "Bob").0, "[wxid_bob] ");
+    }
+
+    #[test]
+    fn test_timestamp_massaging_toggle() {
+        use matrix_bridge_wechat::config::Config;
+
+        let example = std::fs::read_to_string("example-config.yaml").unwrap();
+        let config: Config = serde_yaml::from_str(&example).unwrap();
+        assert!(config.bridge.timestamp_massaging);
+
+        let disabled = example.replace("timestamp_massaging: true", "timestamp_massaging: false");
+        let config: Config = serde_yaml::from_str(&disabled).unwrap();
+        assert!(!config.bridge.timestamp_massaging);
+    }
+
+    #[test]
+    fn test_portal_alias() {
+        use matrix_bridge_wechat::config::Config;
+
+        let example = std::fs::read_to_string("example-config.yaml").unwrap();
+        let config: Config = serde_yaml::from_str(&example).unwrap();
+        let alias = config.format_alias("@@group1");
+        assert_eq!(alias, "#wechat_@@group1:example.com");
+        assert_eq!(config.parse_alias(&alias).as_deref(), Some("@@group1"));
+        assert_eq!(config.parse_alias("#wechat_@@group1:other.com"), None);
+        assert_eq!(config.parse_alias("#wechat_:example.com"), None);
+        assert_eq!(config.parse_alias("#random:example.com"), None);
+    }
+
+    #[test]
+    fn test_agent_keepalive() {
+        use matrix_bridge_wechat::config::Config;
+        use std::time::Duration;
+
+        let example = std::fs::read_to_string("example-config.yaml").unwrap();
+        let config: Config = serde_yaml::from_str(&example).unwrap();
+        assert_eq!(config.bridge.agent_ping_interval_duration(), Some(Duration::from_secs(15)));
+        assert_eq!(config.bridge.agent_ping_timeout_duration(), Duration::from_secs(45));
+
+        let disabled = example.replace("agent_ping_interval: 15s", "agent_ping_interval: 0");
+        let config: Config = serde_yaml::from_str(&disabled).unwrap();
+        assert_eq!(config.bridge.agent_ping_interval_duration(), None);
+    }
+}
+
+#[cfg(test)]
+mod preflight_tests {
+    use matrix_bridge_wechat::bridge::preflight;
+    use matrix_bridge_wechat::config::PermissionLevel;
+
+    use crate::common::{TempFile, test_config};
+
+    #[test]
+    fn reports_every_config_problem() {
+        let (mut config, _db) = test_config("preflight-problems");
+        config.bridge.permissions.insert("example.org".to_string(), PermissionLevel::User);
+        config.bridge.permissions.insert("@admin".to_string(), PermissionLevel::Admin);
+        config.bridge.listen_address = format!("127.0.0.1:{}", config.appservice.port);
+
+        let problems = config.problems();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].contains("@admin"));
+        assert!(problems[1].contains("both listen on"));
+
+        config.bridge.permissions.remove("@admin");
+        config.bridge.listen_address = format!("127.0.0.1:{}", config.appservice.port + 1);
+        assert!(config.problems().is_empty());
+    }
+
+    #[test]
+    fn registration_must_match_config() {
+        let (config, _db) = test_config("preflight-registration");
+        let file = TempFile::new("preflight-registration.yaml");
+        let registration = format!(
+            "id: {}\nurl: {}\nas_token: stale\nhs_token: {}\nsender_localpart: {}\n",
+            config.appservice.id, config.appservice.address, config.appservice.hs_token, config.appservice.bot.username,
+        );
+        std::fs::write(file.uri(), registration).unwrap();
+
+        let problems = preflight::check_registration(&config, std::path::Path::new(&file.uri()));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].fatal);
+        assert!(problems[0].message.starts_with("as_token"));
+    }
+
+    #[tokio::test]
+    async fn unreachable_homeserver_is_not_fatal() {
+        let (mut config, _db) = test_config("preflight-homeserver");
+        config.bridge.permissions.insert("example.org".to_string(), PermissionLevel::User);
+        config.homeserver.address = "http://127.0.0.1:1".to_string();
+
+        let problems = preflight::run(&config, None).await;
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(!problems[0].fatal);
+        assert!(problems[0].message.contains("whoami"));
+    }
+}
+
+#[cfg(test)]
+mod command_tests {
+    use matrix_bridge_wechat::bridge::command::{CommandProcessor, CommandResult};
+    use matrix_bridge_wechat::config::PermissionLevel;
+
+    #[test]
+    fn test_command_permissions() {
+        let processor = CommandProcessor::new("!wechat".to_string());
+        let denied = |cmd: &str, level| matches!(processor.process(cmd, &[], level), CommandResult::Error(_));
+
+        assert!(!denied("help", PermissionLevel::Relay));
+        assert!(denied("login", PermissionLevel::Relay));
+        assert!(!denied("login", PermissionLevel::User));
+        assert!(denied("dp", PermissionLevel::User));
+        assert!(!denied("dp", PermissionLevel::Puppeting));
+        assert!(denied("delete-all-portals", PermissionLevel::Puppeting));
+        assert!(!denied("delete-all-portals", PermissionLevel::Admin));
+        assert!(denied("list-connections", PermissionLevel::Puppeting));
+        assert!(!denied("list-connections", PermissionLevel::Admin));
+    }
+
+    #[test]
+    fn test_leave_group_command() {
+        let processor = CommandProcessor::new("!wechat".to_string());
+        let args = vec!["@@group".to_string()];
+        assert!(matches!(
+            processor.process("leave-group", &args, PermissionLevel::User),
+            CommandResult::LeaveGroup(id) if id == "@@group"
+        ));
+        assert!(matches!(processor.process("leave-group", &[], PermissionLevel::User), CommandResult::Error(_)));
+    }
+
+    #[test]
+    fn test_set_portal_setting_command() {
+        use matrix_bridge_wechat::bridge::command::PortalSettingChange;
+        use matrix_bridge_wechat::database::{PortalKey, PortalSetting};
+
+        let processor = CommandProcessor::new("!wechat".to_string());
+        let set = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+            match processor.process("set", &args, PermissionLevel::User) {
+                CommandResult::SetPortalSetting(change) => Some(change),
+                _ => None,
+            }
+        };
+        assert_eq!(set(&["mute", "on"]), Some(PortalSettingChange::Muted(true)));
+        assert_eq!(set(&["plaintext", "off"]), Some(PortalSettingChange::PlaintextOnly(false)));
+        assert_eq!(set(&["name", "Team", "chat"]), Some(PortalSettingChange::RoomName(Some("Team chat".to_string()))));
+        assert_eq!(set(&["name", "reset"]), Some(PortalSettingChange::RoomName(None)));
+        assert_eq!(set(&["relay", "maybe"]), None);
+        assert_eq!(set(&["volume", "on"]), None);
+
+        let mut setting = PortalSetting::new(&PortalKey::new("wxid_alice", "wxid_me"), "@me:example.com");
+        assert!(setting.relay);
+        PortalSettingChange::Relay(false).apply(&mut setting);
+        assert!(!setting.relay);
+    }
+
+    #[test]
+    fn test_help_command() {
+        let processor = CommandProcessor::new("!wechat".to_string());
+        assert_eq!(processor.find("dp").map(|spec| spec.name), Some("double-puppet"));
+        assert_eq!(processor.find("?").map(|spec| spec.level), Some(PermissionLevel::Relay));
+
+        let CommandResult::Help { body, html } = processor.process("help", &[], PermissionLevel::User) else {
+            panic!("expected help");
+        };
+        assert!(body.contains("- leave-group <group_id>: Quit a WeChat group"));
+        assert!(html.contains("<li><code>leave-group &lt;group_id&gt;</code>"));
+        assert!(!body.contains("delete-all-portals"));
+
+        let CommandResult::Help { body, .. } = processor.process("help", &["dp".to_string()], PermissionLevel::Admin) else {
+            panic!("expected help");
+        };
+        assert!(body.starts_with("!wechat double-puppet <access_token>\n"));
+        assert!(body.ends_with("Aliases: dp"));
+        assert!(matches!(
+            processor.process("help", &["dp".to_string()], PermissionLevel::User),
+            CommandResult::Error(_)
+        ));
+        assert!(matches!(
+            processor.process("sync", &["nothing".to_string()], PermissionLevel::User),
+            CommandResult::Error(msg) if msg == "Usage: sync contacts|groups|space"
+        ));
+    }
+}
+
+#[cfg(test)]
+mod message_status_tests {
+    use matrix_bridge_wechat::bridge::message_status::SendFailure;
+
+    #[test]
+    fn test_send_failure_db_roundtrip() {
+        let failure = SendFailure::new("failed to send the image to WeChat: timeout", 2);
+        assert_eq!(SendFailure::from_db(&failure.to_db()), failure);
+
+        let legacy = SendFailure::from_db("media_not_found");
+        assert_eq!(legacy.reason, "media_not_found");
+        assert_eq!(legacy.retry_num, 0);
+    }
+}
+
+#[cfg(test)]
+mod send_retry_tests {
+    use std::time::Duration;
+    use matrix_bridge_wechat::bridge::send_retry::SendRetryQueue;
+    use matrix_bridge_wechat::config::SendRetryConfig;
+    use matrix_bridge_wechat::error::WeChatError;
+
+    #[test]
+    fn test_send_retry_schedule() {
+        let config = SendRetryConfig {
+            max_retries: 4,
+            initial_delay: "10s".to_string(),
+            max_delay: "1m".to_string(),
+        };
+        assert_eq!(config.delay(0), Some(Duration::from_secs(10)));
+        assert_eq!(config.delay(2), Some(Duration::from_secs(40)));
+        assert_eq!(config.delay(3), Some(Duration::from_secs(60)));
+        assert_eq!(config.delay(4), None);
+
+        let disconnected = anyhow::Error::from(WeChatError::ServiceUnavailable("no agent connection available".to_string()))
+            .context("failed to send the message to WeChat");
+        assert!(SendRetryQueue::is_retriable(&disconnected));
+        assert!(!SendRetryQueue::is_retriable(&anyhow::anyhow!("request timeout")));
+    }
+}
+
 #[cfg(test)]
 mod retry_tests {
     use std::time::Duration;
@@ -169,6 +474,75 @@ mod cache_tests {
         let len = cache.len().await;
         assert!(len <= 3);
     }
+
+    #[tokio::test]
+    async fn test_room_state_cache_applies_events() {
+        use matrix_bridge_wechat::matrix::state_cache::RoomStateCache;
+        use matrix_bridge_wechat::matrix::{MatrixClient, RoomEvent};
+
+        let cache = RoomStateCache::new(Duration::from_secs(60));
+        let client = MatrixClient::new("http://127.0.0.1:1", "token");
+        let event: RoomEvent = serde_json::from_value(serde_json::json!({
+            "type": "m.room.power_levels",
+            "room_id": "!room:example.com",
+            "state_key": "",
+            "content": { "users": { "@admin:example.com": 100 } }
+        }))
+        .unwrap();
+
+        cache.apply_event(&event).await;
+        let levels = cache.power_levels(&client, "!room:example.com").await.unwrap();
+        assert_eq!(levels.users.get("@admin:example.com"), Some(&100));
+
+        cache.invalidate("!room:example.com").await;
+        assert!(cache.power_levels(&client, "!room:example.com").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_room_state_cache_updates_members_from_events() {
+        use matrix_bridge_wechat::matrix::state_cache::RoomStateCache;
+        use matrix_bridge_wechat::matrix::{MatrixClient, RoomEvent};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // The homeserver answers a single request, so later lookups must come from the cache.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let body = r#"{"joined":{"@wechatbot:example.com":{"display_name":"WeChat bridge"}}}"#;
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = socket.read(&mut buf).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(), body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let cache = RoomStateCache::new(Duration::from_secs(60));
+        let client = MatrixClient::new(format!("http://{}", address), "token");
+        let room = "!room:example.com";
+        assert_eq!(cache.is_joined(room, "@wechat_alice:example.com").await, None);
+        assert_eq!(cache.joined_members(&client, room).await.unwrap().joined.len(), 1);
+
+        let member = |mxid: &str, membership: &str| -> RoomEvent {
+            serde_json::from_value(serde_json::json!({
+                "type": "m.room.member",
+                "room_id": room,
+                "state_key": mxid,
+                "content": { "membership": membership, "displayname": "Alice" }
+            }))
+            .unwrap()
+        };
+        cache.apply_event(&member("@wechat_alice:example.com", "join")).await;
+        assert_eq!(cache.is_joined(room, "@wechat_alice:example.com").await, Some(true));
+        let alice = cache.member(&client, room, "@wechat_alice:example.com").await.unwrap().unwrap();
+        assert_eq!(alice.displayname.as_deref(), Some("Alice"));
+
+        cache.apply_event(&member("@wechatbot:example.com", "leave")).await;
+        assert_eq!(cache.is_joined(room, "@wechatbot:example.com").await, Some(false));
+        assert_eq!(cache.joined_members(&client, room).await.unwrap().joined.len(), 1);
+    }
 }
 
 #[cfg(test)]
@@ -215,47 +589,3159 @@ mod queue_tests {
 
 #[cfg(test)]
 mod metrics_tests {
-    use matrix_bridge_wechat::metrics::Metrics;
+    use matrix_bridge_wechat::metrics::{Histogram, HistogramTimer, Metrics};
     
-    #[tokio::test]
-    async fn test_counter() {
+    #[test]
+    fn test_counter() {
         let metrics = Metrics::new();
         
-        assert_eq!(metrics.messages_bridged.get().await, 0);
+        assert_eq!(metrics.messages_bridged.get(), 0);
         
-        metrics.messages_bridged.inc().await;
-        assert_eq!(metrics.messages_bridged.get().await, 1);
+        metrics.messages_bridged.inc();
+        assert_eq!(metrics.messages_bridged.get(), 1);
         
-        metrics.messages_bridged.inc_by(5).await;
-        assert_eq!(metrics.messages_bridged.get().await, 6);
+        metrics.messages_bridged.inc_by(5);
+        assert_eq!(metrics.messages_bridged.get(), 6);
     }
     
-    #[tokio::test]
-    async fn test_gauge() {
+    #[test]
+    fn test_gauge() {
         let metrics = Metrics::new();
         
-        assert_eq!(metrics.active_users.get().await, 0.0);
+        assert_eq!(metrics.active_users.get(), 0.0);
         
-        metrics.active_users.set(10.0).await;
-        assert_eq!(metrics.active_users.get().await, 10.0);
+        metrics.active_users.set(10.0);
+        assert_eq!(metrics.active_users.get(), 10.0);
         
-        metrics.active_users.inc().await;
-        assert_eq!(metrics.active_users.get().await, 11.0);
+        metrics.active_users.inc();
+        assert_eq!(metrics.active_users.get(), 11.0);
         
-        metrics.active_users.dec().await;
-        assert_eq!(metrics.active_users.get().await, 10.0);
+        metrics.active_users.dec();
+        assert_eq!(metrics.active_users.get(), 10.0);
     }
     
-    #[tokio::test]
-    async fn test_prometheus_output() {
+    #[test]
+    fn test_prometheus_output() {
         let metrics = Metrics::new();
         
-        metrics.messages_bridged.inc().await;
-        metrics.active_users.set(5.0).await;
+        metrics.messages_bridged.inc();
+        metrics.active_users.set(5.0);
         
-        let output = metrics.to_prometheus().await;
+        let output = metrics.to_prometheus();
         
         assert!(output.contains("bridge_messages_bridged 1"));
         assert!(output.contains("bridge_active_users 5"));
     }
+    
+    #[test]
+    fn test_counter_vec_prometheus_output() {
+        let metrics = Metrics::new();
+        
+        metrics.unsupported_messages.inc("voip");
+        metrics.unsupported_messages.inc("voip");
+        metrics.unsupported_messages.inc("red_packet");
+        assert_eq!(metrics.unsupported_messages.get("voip"), 2);
+        
+        let output = metrics.to_prometheus();
+        
+        assert!(output.contains("bridge_unsupported_messages{type=\"voip\"} 2"));
+        assert!(output.contains("bridge_unsupported_messages{type=\"red_packet\"} 1"));
+    }    
+    #[test]
+    fn test_histogram_timer_observes_once() {
+        let histogram = Histogram::new(Histogram::default_buckets());
+        
+        HistogramTimer::new(histogram.clone()).observe_duration();
+        drop(HistogramTimer::new(histogram.clone()));
+        
+        assert_eq!(histogram.get_count(), 2);
+        assert_eq!(histogram.get_counts()[0], 2);
+    }
+}
+
+#[cfg(test)]
+mod media_tests {
+    use matrix_bridge_wechat::util::media::audio::{self, AudioFormat};
+    use matrix_bridge_wechat::util::media::image::{self, ImageFormat};
+    
+    #[test]
+    fn test_audio_format_detection() {
+        assert_eq!(audio::detect_format(b"\x02#!SILK_V3\x0c\x00"), AudioFormat::Silk);
+        assert_eq!(audio::detect_format(b"#!AMR\n\x3c"), AudioFormat::Amr);
+        assert_eq!(audio::detect_format(b"OggS\x00\x02"), AudioFormat::Ogg);
+        assert_eq!(audio::detect_format(b"RIFF"), AudioFormat::Unknown);
+        // Only a single leading 0x02 is WeChat's SILK prefix.
+        assert_eq!(audio::detect_format(b"#!SILK_V3"), AudioFormat::Silk);
+        assert_eq!(audio::detect_format(b"\x02\x02#!SILK_V3"), AudioFormat::Unknown);
+        assert_eq!(audio::detect_format(b"\x02OggS"), AudioFormat::Ogg);
+        assert_eq!(audio::detect_format(b""), AudioFormat::Unknown);
+    }
+
+    #[test]
+    fn test_pcm_duration() {
+        // Mono 16-bit samples at 24kHz, 48000 bytes a second.
+        assert_eq!(audio::pcm_duration_ms(&[0; 48000]), 1000);
+        assert_eq!(audio::pcm_duration_ms(&[0; 12000]), 250);
+        assert_eq!(audio::pcm_duration_ms(&[0; 47]), 0);
+        assert_eq!(audio::pcm_duration_ms(&[]), 0);
+    }
+    
+    #[test]
+    fn test_image_dimensions() {
+        let gif = b"GIF89a\x40\x01\xf0\x00\x00\x00\x00";
+        assert_eq!(image::detect_format(gif), ImageFormat::Gif);
+        assert_eq!(image::dimensions(gif), Some((320, 240)));
+        
+        let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        png.extend_from_slice(&[0, 0, 0, 64, 0, 0, 0, 32]);
+        assert_eq!(image::dimensions(&png), Some((64, 32)));
+    }
+
+    #[test]
+    fn test_wechat_image_format() {
+        assert_eq!(image::detect_format(b"\x00\x00\x00\x1cftypavif\x00\x00"), ImageFormat::Avif);
+        assert_eq!(image::detect_format(b"\x00\x00\x00\x18ftypheic\x00\x00"), ImageFormat::Heic);
+
+        let fits = Some(4096);
+        assert_eq!(image::wechat_image_format(ImageFormat::Jpeg, Some((4000, 3000)), fits), None);
+        assert_eq!(image::wechat_image_format(ImageFormat::Jpeg, Some((8000, 6000)), fits), Some(ImageFormat::Jpeg));
+        assert_eq!(image::wechat_image_format(ImageFormat::Gif, Some((8000, 60)), None), None);
+        assert_eq!(image::wechat_image_format(ImageFormat::Webp, Some((64, 64)), fits), Some(ImageFormat::Png));
+        assert_eq!(image::wechat_image_format(ImageFormat::Heic, None, fits), Some(ImageFormat::Jpeg));
+    }
+}
+
+#[cfg(test)]
+mod wechat_types_tests {
+    use matrix_bridge_wechat::wechat::{EventType, UserInfo};
+
+    #[test]
+    fn test_contact_search() {
+        let contact = UserInfo {
+            id: "wxid_alice".to_string(),
+            name: "Alice".to_string(),
+            avatar: None,
+            remark: Some("Ally".to_string()),
+        };
+        assert!(contact.matches("ALI"));
+        assert!(contact.matches("ally"));
+        assert!(!contact.matches("bob"));
+        assert!(contact.matches_exactly("alice"));
+        assert!(contact.matches_exactly("wxid_alice"));
+        assert!(!contact.matches_exactly("ali"));
+    }
+
+    #[test]
+    fn test_group_update_event_type() {
+        let event_type: EventType = serde_json::from_str("\"group_update\"").unwrap();
+        assert_eq!(event_type, EventType::GroupUpdate);
+        assert_eq!(serde_json::to_string(&event_type).unwrap(), "\"group_update\"");
+    }
+}
+
+#[cfg(test)]
+mod wechat_service_tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use matrix_bridge_wechat::error::WeChatError;
+    use matrix_bridge_wechat::wechat::{Request, RequestType, WechatService};
+
+    #[tokio::test]
+    async fn test_stop_closes_listener() {
+        let service = Arc::new(WechatService::new("127.0.0.1:0", "secret"));
+        service.clone().start().await.unwrap();
+        assert!(service.is_listening());
+
+        service.stop().await;
+        assert!(!service.is_listening());
+
+        let wait = tokio::time::timeout(Duration::from_millis(100), service.wait_for_connection());
+        assert!(wait.await.is_err());
+    }
+
+    // Paused time skips the backoff between bind attempts.
+    #[tokio::test(start_paused = true)]
+    async fn test_bind_failure_fails_start() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let service = Arc::new(WechatService::new(taken.local_addr().unwrap().to_string(), "secret"));
+
+        let err = service.clone().start().await.unwrap_err();
+        assert!(err.to_string().contains("failed to bind"), "{}", err);
+        assert!(!service.is_listening());
+    }
+
+    #[tokio::test]
+    async fn test_missing_agent_reported_once() {
+        let service = WechatService::new("127.0.0.1:0", "secret");
+        let mut missing = service.subscribe_missing_agents();
+        let request = Request { request_type: RequestType::IsLogin, data: None };
+
+        for _ in 0..2 {
+            let err = service.request("@alice:example.com", &request).await.unwrap_err();
+            assert!(matches!(err.downcast_ref::<WeChatError>(), Some(WeChatError::ServiceUnavailable(_))));
+        }
+        assert_eq!(missing.try_recv().unwrap(), "@alice:example.com");
+        assert!(missing.try_recv().is_err());
+        assert!(service.list_connections().await.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod transaction_tests {
+    use matrix_bridge_wechat::bridge::transactions::TransactionStore;
+    use crate::common::test_db;
+
+    #[tokio::test]
+    async fn test_transaction_store_survives_restart() {
+        let db = test_db("txn").await;
+
+        let store = TransactionStore::new(db.clone());
+        assert!(!store.is_processed("txn1").await.unwrap());
+        store.mark_processed("txn1").await.unwrap();
+        assert!(store.is_processed("txn1").await.unwrap());
+
+        let restarted = TransactionStore::new(db.clone());
+        assert!(restarted.is_processed("txn1").await.unwrap());
+        assert!(!restarted.is_processed("txn2").await.unwrap());
+        assert_eq!(restarted.prune().await.unwrap(), 0);
+    }
+}
+
+#[cfg(test)]
+mod crypto_tests {
+    use matrix_bridge_wechat::crypto::CryptoMachine;
+    use serde_json::json;
+
+    async fn machine(user_id: &str, device_id: &str) -> CryptoMachine {
+        CryptoMachine::new_with_memory_store(user_id.to_string(), device_id.to_string()).await.unwrap()
+    }
+
+    fn key_request(room_id: &str, session_id: &str, device_id: &str) -> serde_json::Value {
+        json!({
+            "action": "request",
+            "requesting_device_id": device_id,
+            "request_id": "req1",
+            "body": { "algorithm": "m.megolm.v1.aes-sha2", "room_id": room_id, "session_id": session_id },
+        })
+    }
+
+    #[tokio::test]
+    async fn test_room_key_request_shares_key() {
+        let bridge = machine("@bot:example.com", "BRIDGE").await;
+        let alice = machine("@alice:example.com", "ALICE").await;
+        let encrypted = bridge.encrypt_for_room("!room:example.com", "m.room.message", &json!({"body": "hi"})).await.unwrap();
+        let session_id = encrypted["session_id"].as_str().unwrap();
+
+        let request = key_request("!room:example.com", session_id, "ALICE");
+        assert!(bridge.receive_to_device("m.room_key_request", "@alice:example.com", &request).await.unwrap().is_none());
+
+        bridge.save_device_keys(&alice.get_device_keys().await.unwrap()).await.unwrap();
+        let reply = bridge.receive_to_device("m.room_key_request", "@alice:example.com", &request).await.unwrap().unwrap();
+        assert_eq!(reply.event_type, "m.room.encrypted");
+        let content = &reply.messages["@alice:example.com"]["ALICE"];
+
+        // Alice imports the forwarded key and can share it with her other device.
+        alice.receive_to_device("m.room.encrypted", "@bot:example.com", content).await.unwrap();
+        let other = machine("@alice:example.com", "OTHER").await;
+        alice.save_device_keys(&other.get_device_keys().await.unwrap()).await.unwrap();
+        let request = key_request("!room:example.com", session_id, "OTHER");
+        assert!(alice.receive_to_device("m.room_key_request", "@alice:example.com", &request).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_verification_request_cancelled() {
+        let bridge = machine("@bot:example.com", "BRIDGE").await;
+        let request = json!({ "from_device": "ALICE", "transaction_id": "t1", "methods": ["m.sas.v1"] });
+        let reply = bridge.receive_to_device("m.key.verification.request", "@alice:example.com", &request).await.unwrap().unwrap();
+        assert_eq!(reply.event_type, "m.key.verification.cancel");
+        assert_eq!(reply.messages["@alice:example.com"]["ALICE"]["transaction_id"], "t1");
+    }
+}
+
+#[cfg(test)]
+mod intent_tests {
+    use matrix_bridge_wechat::error::MatrixError;
+    use matrix_bridge_wechat::matrix::{Intent, MatrixClient, matrix_errcode};
+
+    #[test]
+    fn test_matrix_errcode() {
+        let error = anyhow::Error::from(MatrixError::Api { code: "M_FORBIDDEN".into(), message: "not in room".into() });
+        assert_eq!(matrix_errcode(&error), Some("M_FORBIDDEN"));
+        assert_eq!(matrix_errcode(&anyhow::anyhow!("timeout")), None);
+    }
+
+    #[tokio::test]
+    async fn test_bot_intent_is_registered() {
+        // Nothing listens on this address, so any registration request would fail.
+        let bot = MatrixClient::new("http://127.0.0.1:9", "as_token").with_user_id("@wechatbot:example.com");
+        let intent = Intent::new(bot.clone(), "@wechatbot:example.com");
+        intent.ensure_registered().await.unwrap();
+
+        let ghost = Intent::new(bot, "@wechat_alice:example.com");
+        assert_eq!(ghost.client().user_id(), Some("@wechat_alice:example.com"));
+        assert!(ghost.ensure_registered().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_assumed_profile_is_not_set_again() {
+        // Nothing listens on this address, so only profile changes make requests that fail.
+        let bot = MatrixClient::new("http://127.0.0.1:9", "as_token").with_user_id("@wechatbot:example.com");
+        let ghost = Intent::new(bot, "@wechat_alice:example.com");
+        ghost.assume_profile(Some("Alice"), Some("mxc://example.com/alice")).await;
+        ghost.set_displayname("Alice").await.unwrap();
+        ghost.set_avatar_url("mxc://example.com/alice").await.unwrap();
+        assert!(ghost.set_displayname("Alice (WeChat)").await.is_err());
+
+        let example = std::fs::read_to_string("example-config.yaml").unwrap();
+        let config: matrix_bridge_wechat::config::Config = serde_yaml::from_str(&example).unwrap();
+        assert_eq!(config.bridge.puppet_warmup_concurrency, 8);
+    }
+
+    #[test]
+    fn test_impersonation_query() {
+        let bot = MatrixClient::new("https://hs.example.com/", "as_token");
+        assert_eq!(bot.url("/_matrix/client/v3/sync"), "https://hs.example.com/_matrix/client/v3/sync");
+
+        let ghost = bot.impersonate("@wechat_alice:example.com").with_timestamp(1700000000000);
+        assert_eq!(
+            ghost.url("/_matrix/client/v3/rooms/!r/send/m.room.message/1"),
+            "https://hs.example.com/_matrix/client/v3/rooms/!r/send/m.room.message/1?user_id=%40wechat_alice%3Aexample.com&ts=1700000000000"
+        );
+    }
+}
+
+#[cfg(test)]
+mod contact_sync_tests {
+    use matrix_bridge_wechat::bridge::contact_sync::{contact_from_info, diff_contacts};
+    use matrix_bridge_wechat::wechat::UserInfo;
+
+    fn friend(id: &str, name: &str) -> UserInfo {
+        UserInfo { id: id.into(), name: name.into(), avatar: None, remark: None }
+    }
+
+    #[test]
+    fn test_diff_contacts() {
+        let mut gone = contact_from_info("@alice:example.com", &friend("wxid_gone", "Gone"));
+        let renamed = contact_from_info("@alice:example.com", &friend("wxid_bob", "Bob"));
+        let same = contact_from_info("@alice:example.com", &friend("wxid_carol", "Carol"));
+        let mut back = contact_from_info("@alice:example.com", &friend("wxid_dave", "Dave"));
+        back.deleted = true;
+
+        let friends = [friend("wxid_bob", "Bobby"), friend("wxid_carol", "Carol"), friend("wxid_dave", "Dave"), friend("wxid_eve", "Eve")];
+        let changes = diff_contacts(&[gone.clone(), renamed, same, back], &friends);
+        let ids = |list: &[UserInfo]| list.iter().map(|f| f.id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids(&changes.updated), ["wxid_bob"]);
+        assert_eq!(ids(&changes.added), ["wxid_dave", "wxid_eve"]);
+        assert_eq!(changes.removed, ["wxid_gone"]);
+
+        gone.deleted = true;
+        assert!(diff_contacts(&[gone], &[]).is_empty());
+    }
+}
+
+#[cfg(test)]
+mod system_message_tests {
+    use matrix_bridge_wechat::formatter::payment::{PaymentKind, PaymentMessage, PaymentStatus};
+    use matrix_bridge_wechat::formatter::system::format_system_message;
+
+    #[test]
+    fn test_group_invite_template() {
+        let xml = r#"<sysmsg type="sysmsgtemplate"><sysmsgtemplate><content_template type="tmpl_type_profile">
+            <template><![CDATA["$username$" invited "$names$" to the group chat]]></template>
+            <link_list>
+                <link name="username" type="link_profile"><memberlist><member><username><![CDATA[wxid_a]]></username><nickname><![CDATA[Alice]]></nickname></member></memberlist></link>
+                <link name="names" type="link_profile"><memberlist>
+                    <member><username><![CDATA[wxid_b]]></username><nickname><![CDATA[Bob]]></nickname></member>
+                    <member><username><![CDATA[wxid_c]]></username><nickname><![CDATA[Carol &amp; co]]></nickname></member>
+                </memberlist><separator><![CDATA[, ]]></separator></link>
+            </link_list>
+        </content_template></sysmsgtemplate></sysmsg>"#;
+        assert_eq!(
+            format_system_message(xml).as_deref(),
+            Some(r#""Alice" invited "Bob, Carol & co" to the group chat"#)
+        );
+    }
+
+    #[test]
+    fn test_other_payloads() {
+        let red_packet = "<msg><appmsg><type>2001</type><wcpayinfo><sendertitle><![CDATA[Best wishes]]></sendertitle></wcpayinfo></appmsg></msg>";
+        assert_eq!(format_system_message(red_packet).as_deref(), Some("\u{1f9e7} Red packet: Best wishes"));
+        let pat = r#"<sysmsg type="pat"><pat><template><![CDATA["${wxid_a}" patted "${wxid_b}"]]></template></pat></sysmsg>"#;
+        assert_eq!(format_system_message(pat).as_deref(), Some(r#""wxid_a" patted "wxid_b""#));
+        assert_eq!(format_system_message(" Alice changed the group name to \"Team\" ").as_deref(), Some("Alice changed the group name to \"Team\""));
+        assert_eq!(format_system_message(r#"<sysmsg type="unknown"><data/></sysmsg>"#), None);
+    }
+
+    #[test]
+    fn test_payment_messages() {
+        let transfer = PaymentMessage::parse(
+            "<msg><appmsg><title>Transfer</title><type>2000</type><wcpayinfo><paysubtype>1</paysubtype>\
+             <feedesc><![CDATA[\u{ffe5}0.01]]></feedesc><pay_memo><![CDATA[lunch]]></pay_memo></wcpayinfo></appmsg></msg>",
+        ).unwrap();
+        assert_eq!(transfer.kind, PaymentKind::Transfer);
+        assert!(!transfer.is_status_update());
+        assert_eq!(transfer.body(), "\u{1f4b8} Transfer of \u{ffe5}0.01: lunch\n(Open WeChat to accept it)");
+
+        let claimed = PaymentMessage::parse(
+            "<msg><appmsg><type>2001</type><wcpayinfo><paysubtype>3</paysubtype><sendertitle>Best wishes</sendertitle></wcpayinfo></appmsg></msg>",
+        ).unwrap();
+        assert_eq!(claimed.status, PaymentStatus::Claimed);
+        assert_eq!(claimed.body(), "\u{1f9e7} Red packet was claimed");
+
+        assert!(PaymentMessage::parse("<msg><appmsg><type>5</type></appmsg></msg>").is_none());
+    }
+}
+
+#[cfg(test)]
+mod voip_tests {
+    use matrix_bridge_wechat::bridge::voip::{CallEvent, CallStatus};
+    use matrix_bridge_wechat::wechat::Event;
+    use serde_json::json;
+
+    fn voip_event(data: serde_json::Value) -> Event {
+        serde_json::from_value(json!({
+            "id": "msg1",
+            "timestamp": 1700000000000i64,
+            "from": { "id": "wxid_alice", "username": "Alice" },
+            "chat": { "id": "wxid_alice", "type": "private" },
+            "type": "voip",
+            "data": data,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_call_notices() {
+        let ended = CallEvent::from_event(&voip_event(json!({ "status": "ended", "media": "video", "duration": 83 }))).unwrap();
+        assert_eq!(ended.status, CallStatus::Ended);
+        assert_eq!(ended.call_id, "msg1");
+        assert_eq!(ended.notice(), "\u{1f4de} Video call ended after 1:23");
+        assert_eq!(ended.matrix_event().0, "m.call.hangup");
+
+        let missed = CallEvent::from_event(&voip_event(json!({ "status": "missed", "call_id": "c1" }))).unwrap();
+        assert_eq!(missed.notice(), "\u{1f4de} Missed voice call");
+        assert_eq!(missed.matrix_event().1["call_id"], "c1");
+
+        assert!(CallEvent::from_event(&voip_event(json!({ "status": "unknown" }))).is_none());
+    }
+}
+
+#[cfg(test)]
+mod mention_tests {
+    use matrix_bridge_wechat::formatter::matrix_to_wechat::{find_pills, matrix_to_wechat, quote_snippet, strip_reply_fallback};
+    use matrix_bridge_wechat::formatter::wechat_to_matrix::{Mention, add_reply_fallback, format_text};
+    use serde_json::json;
+
+    #[test]
+    fn test_wechat_mentions_to_pills() {
+        let mentions = [Mention::User("@wechat_bob:example.com".to_string()), Mention::Room];
+        let text = format_text("@Bob <3\u{2005}hi @all\u{2005}!", &mentions);
+        assert_eq!(text.body, "@Bob <3 hi @all !");
+        assert_eq!(text.html, "<a href=\"https://matrix.to/#/@wechat_bob:example.com\">Bob &lt;3</a> hi @all !");
+        assert_eq!(text.mentions_content()["user_ids"][0], "@wechat_bob:example.com");
+        assert_eq!(text.mentions_content()["room"], true);
+
+        let unmatched = format_text("hello @Bob", &mentions[..1]);
+        assert_eq!(unmatched.html, "hello @Bob");
+    }
+
+    #[test]
+    fn test_pills_to_wechat_mentions() {
+        let html = r#"Hi <a href="https://matrix.to/#/%40wechat_bob%3Aexample.com">@Bob</a>, look"#;
+        let pills = find_pills(html);
+        assert_eq!(pills.len(), 1);
+        assert_eq!(pills[0].mxid, "@wechat_bob:example.com");
+        assert_eq!(pills[0].name, "Bob");
+        assert_eq!(matrix_to_wechat(html), "Hi @Bob\u{2005}, look");
+    }
+
+    #[test]
+    fn test_reply_fallbacks() {
+        let body = "> <@alice:example.com> first line\n> second line\n\nmy reply";
+        let (quote, rest) = strip_reply_fallback(body);
+        assert_eq!(quote.as_deref(), Some("first line\nsecond line"));
+        assert_eq!(rest, "my reply");
+        assert_eq!(strip_reply_fallback("> not a fallback"), (None, "> not a fallback"));
+        assert_eq!(quote_snippet("abcdef", 3), "abc\u{2026}");
+
+        let mut message = json!({ "msgtype": "m.text", "body": "reply" });
+        add_reply_fallback(&mut message, "@wechat_bob:example.com", "a < b", Some(("!room:example.com", "$event")));
+        assert_eq!(message["body"], "> <@wechat_bob:example.com> a < b\n\nreply");
+        assert_eq!(
+            message["formatted_body"],
+            "<mx-reply><blockquote><a href=\"https://matrix.to/#/!room:example.com/$event\">In reply to</a> \
+             <a href=\"https://matrix.to/#/@wechat_bob:example.com\">@wechat_bob:example.com</a><br/>a &lt; b</blockquote></mx-reply>reply"
+        );
+    }
+
+    #[test]
+    fn test_html_downgrade() {
+        let html = concat!(
+            "<mx-reply><blockquote>In reply to</blockquote></mx-reply>",
+            "<p>Some <strong>bold</strong> and <em>italic</em> &amp; <code>code</code>, see <a href=\"https://example.com\">the docs</a></p>\n",
+            "<ul>\n<li>one</li>\n<li>two<ol start=\"3\"><li>three</li></ol></li>\n</ul>\n",
+            "<blockquote><p>quoted<br>text</p></blockquote>\n",
+            "<pre><code>let x = 1 &lt; 2;\n</code></pre>",
+        );
+        assert_eq!(
+            matrix_to_wechat(html),
+            "Some **bold** and _italic_ & `code`, see [the docs](https://example.com)\n\n\
+             - one\n- two\n  3. three\n\n\
+             > quoted\n> text\n\n\
+             ```\nlet x = 1 < 2;\n```"
+        );
+    }
+}
+
+#[cfg(test)]
+mod emoji_tests {
+    use matrix_bridge_wechat::formatter::emoji::{load_custom_map, unicode_to_wechat, wechat_to_unicode};
+
+    #[test]
+    fn test_builtin_emoji() {
+        assert_eq!(wechat_to_unicode("[微笑][Facepalm][unknown]"), "🙂🤦[unknown]");
+        // Codes without an emoji stay words and aren't turned back into codes.
+        assert_eq!(unicode_to_wechat("🙂 太极"), "[微笑] 太极");
+    }
+
+    #[test]
+    fn test_custom_emoji_map() {
+        let path = std::env::temp_dir().join(format!("emoji-map-{}.yaml", std::process::id()));
+        std::fs::write(&path, "\"[TestOnlyCode]\": \"🦀\"\n").unwrap();
+        assert_eq!(load_custom_map(&path).unwrap(), 1);
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(wechat_to_unicode("hi [TestOnlyCode]"), "hi 🦀");
+        assert_eq!(unicode_to_wechat("🦀"), "[TestOnlyCode]");
+    }
+}
+
+#[cfg(test)]
+mod admin_command_tests {
+    use matrix_bridge_wechat::bridge::admin_command::{AdminCommandProcessor, AdminCommandResult};
+    use matrix_bridge_wechat::config::PermissionLevel;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_admin_commands() {
+        let processor = AdminCommandProcessor::new();
+        assert!(matches!(processor.process("list-users", &[]), Some(AdminCommandResult::ListUsers)));
+        assert!(matches!(
+            processor.process("delete-portal", &args(&["wxid_a"])),
+            Some(AdminCommandResult::DeletePortal(uid)) if uid == "wxid_a"
+        ));
+        // Without an argument it's the regular command for the current room.
+        assert!(processor.process("delete-portal", &[]).is_none());
+        assert!(processor.process("login", &[]).is_none());
+        assert!(matches!(processor.process("disconnect", &args(&["bob"])), Some(AdminCommandResult::Error(_))));
+    }
+
+    #[test]
+    fn test_set_permission_command() {
+        let processor = AdminCommandProcessor::new();
+        assert!(matches!(
+            processor.process("set-permission", &args(&["@bob:example.org", "Puppeting"])),
+            Some(AdminCommandResult::SetPermission(mxid, Some(PermissionLevel::Puppeting))) if mxid == "@bob:example.org"
+        ));
+        assert!(matches!(
+            processor.process("set-permission", &args(&["@bob:example.org", "default"])),
+            Some(AdminCommandResult::SetPermission(_, None))
+        ));
+        assert!(matches!(
+            processor.process("set-permission", &args(&["@bob:example.org", "root"])),
+            Some(AdminCommandResult::Error(_))
+        ));
+    }
+
+    #[test]
+    fn test_continue_portal_command() {
+        let processor = AdminCommandProcessor::new();
+        assert!(matches!(
+            processor.process("continue-portal", &args(&["!old:example.org", "!new:example.org"])),
+            Some(AdminCommandResult::ContinuePortal(old, new)) if old == "!old:example.org" && new == "!new:example.org"
+        ));
+        assert!(matches!(
+            processor.process("continue-portal", &args(&["!old:example.org"])),
+            Some(AdminCommandResult::Error(_))
+        ));
+        assert!(matches!(
+            processor.process("continue-portal", &args(&["#alias:example.org", "!new:example.org"])),
+            Some(AdminCommandResult::Error(_))
+        ));
+    }
+}
+
+#[cfg(test)]
+mod connection_notifier_tests {
+    use std::time::{Duration, Instant};
+
+    use matrix_bridge_wechat::bridge::connection_notifier::{ConnectionNotice, ConnectionNotifier};
+
+    #[tokio::test]
+    async fn test_connection_notices() {
+        let notifier = ConnectionNotifier::new(Some(Duration::from_secs(300)));
+        let mxid = "@alice:example.com";
+
+        assert_eq!(notifier.on_connected(mxid).await, None);
+        assert_eq!(notifier.on_disconnected(mxid).await, Some(ConnectionNotice::AgentDisconnected));
+        assert_eq!(notifier.on_disconnected(mxid).await, None);
+        assert!(notifier.overdue(Instant::now()).await.is_empty());
+
+        let later = Instant::now() + Duration::from_secs(301);
+        assert_eq!(
+            notifier.overdue(later).await,
+            vec![(mxid.to_string(), ConnectionNotice::StillDisconnected(Duration::from_secs(300)))]
+        );
+        assert!(notifier.overdue(later).await.is_empty());
+
+        assert_eq!(notifier.on_connected(mxid).await, Some(ConnectionNotice::AgentReconnected));
+        assert!(notifier.overdue(later).await.is_empty());
+        assert!(ConnectionNotice::LoggedOut(Some("logged in on another device".to_string()))
+            .text()
+            .contains("logged in on another device"));
+    }
+}
+
+#[cfg(test)]
+mod session_supervisor_tests {
+    use std::time::{Duration, Instant};
+
+    use matrix_bridge_wechat::bridge::session_supervisor::{SessionAction, SessionSupervisor};
+    use matrix_bridge_wechat::util::retry::BackoffConfig;
+
+    #[tokio::test]
+    async fn test_relogin_backoff_and_escalation() {
+        let supervisor = SessionSupervisor::new(BackoffConfig {
+            initial_delay: Duration::from_secs(30),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            max_retries: 2,
+            jitter: false,
+        });
+        let mxid = "@alice:example.com";
+        let start = Instant::now();
+
+        assert_eq!(supervisor.on_session_lost(mxid, start).await, SessionAction::Relogin);
+        assert_eq!(supervisor.on_session_lost(mxid, start + Duration::from_secs(10)).await, SessionAction::Wait);
+        assert_eq!(supervisor.on_session_lost(mxid, start + Duration::from_secs(30)).await, SessionAction::Relogin);
+        assert_eq!(supervisor.on_session_lost(mxid, start + Duration::from_secs(60)).await, SessionAction::Wait);
+        assert_eq!(supervisor.on_session_lost(mxid, start + Duration::from_secs(90)).await, SessionAction::Escalate);
+
+        // A restored session starts over.
+        assert_eq!(supervisor.on_session_lost(mxid, start).await, SessionAction::Relogin);
+        assert!(supervisor.on_session_ok(mxid).await);
+        assert!(!supervisor.on_session_ok(mxid).await);
+        assert_eq!(supervisor.on_session_lost(mxid, start).await, SessionAction::Relogin);
+    }
+}
+
+#[cfg(test)]
+mod dedup_tests {
+    use matrix_bridge_wechat::bridge::dedup::MessageDedup;
+    use matrix_bridge_wechat::database::{Message, PortalKey};
+    use matrix_bridge_wechat::wechat::EventType;
+    use crate::common::{portal, test_db};
+
+    #[tokio::test]
+    async fn test_replayed_events_are_duplicates() {
+        let db = test_db("dedup").await;
+        let chat = PortalKey::new("wxid_alice", "wxid_me");
+
+        let dedup = MessageDedup::new(db.clone());
+        assert!(!dedup.is_duplicate(&chat, &EventType::Text, "msg1").await.unwrap());
+        dedup.mark_seen(&chat, &EventType::Text, "msg1").await;
+        assert!(dedup.is_duplicate(&chat, &EventType::Text, "msg1").await.unwrap());
+        // A revoke of msg1 may carry its ID, it's still a new event.
+        assert!(!dedup.is_duplicate(&chat, &EventType::Revoke, "msg1").await.unwrap());
+        // Calls reuse IDs across status updates.
+        dedup.mark_seen(&chat, &EventType::Voip, "call1").await;
+        assert!(!dedup.is_duplicate(&chat, &EventType::Voip, "call1").await.unwrap());
+
+        // After a restart, bridged messages are found in the database.
+        db.insert_portal(&portal("wxid_alice", "wxid_me")).await.unwrap();
+        db.insert_message(&Message {
+            chat_uid: "wxid_alice".to_string(),
+            chat_receiver: "wxid_me".to_string(),
+            msg_id: "msg2".to_string(),
+            mxid: "$event".to_string(),
+            sender: "wxid_alice".to_string(),
+            timestamp: 0,
+            sent: true,
+            error: None,
+            msg_type: "message".to_string(),
+        }).await.unwrap();
+        let restarted = MessageDedup::new(db.clone());
+        assert!(restarted.is_duplicate(&chat, &EventType::Photo, "msg2").await.unwrap());
+        assert!(!restarted.is_duplicate(&chat, &EventType::Text, "msg1").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_accounts_sharing_a_chat_get_every_event() {
+        let db = test_db("dedup-accounts").await;
+        let mine = PortalKey::new("123@chatroom", "wxid_me");
+        let theirs = PortalKey::new("123@chatroom", "wxid_other");
+
+        let dedup = MessageDedup::new(db.clone());
+        dedup.mark_seen(&mine, &EventType::Text, "msg1").await;
+        assert!(!dedup.is_duplicate(&theirs, &EventType::Text, "msg1").await.unwrap());
+
+        db.insert_portal(&portal("123@chatroom", "wxid_me")).await.unwrap();
+        db.insert_message(&Message {
+            chat_uid: "123@chatroom".to_string(),
+            chat_receiver: "wxid_me".to_string(),
+            msg_id: "msg2".to_string(),
+            mxid: "$event".to_string(),
+            sender: "wxid_alice".to_string(),
+            timestamp: 0,
+            sent: true,
+            error: None,
+            msg_type: "message".to_string(),
+        }).await.unwrap();
+        assert!(dedup.is_duplicate(&mine, &EventType::Text, "msg2").await.unwrap());
+        assert!(!dedup.is_duplicate(&theirs, &EventType::Text, "msg2").await.unwrap());
+    }
+}
+
+#[cfg(test)]
+mod pending_message_tests {
+    use matrix_bridge_wechat::database::{Message, Portal, PortalKey};
+    use crate::common::{portal, test_db};
+
+    #[tokio::test]
+    async fn test_pending_messages_are_completed() {
+        let db = test_db("pending").await;
+
+        db.insert_portal(&Portal { mxid: Some("!room:example.com".to_string()), ..portal("wxid_alice", "wxid_me") }).await.unwrap();
+        let pending = |msg_id: &str, mxid: String| Message {
+            chat_uid: "wxid_alice".to_string(),
+            chat_receiver: "wxid_me".to_string(),
+            msg_id: msg_id.to_string(),
+            mxid,
+            sender: "@alice:example.com".to_string(),
+            timestamp: 0,
+            sent: false,
+            error: None,
+            msg_type: "m.text".to_string(),
+        };
+        // A Matrix message is keyed by its event ID until WeChat assigns one.
+        db.insert_message(&pending("$out", "$out".to_string())).await.unwrap();
+        db.insert_message(&pending("wx1", Message::pending_mxid("wx1"))).await.unwrap();
+        db.insert_message(&pending("wx2", Message::pending_mxid("wx2"))).await.unwrap();
+        assert_eq!(db.get_pending_messages().await.unwrap().len(), 3);
+
+        let key = PortalKey::new("wxid_alice", "wxid_me");
+        db.complete_message(&key, "$out", "wx_out", "$out", None).await.unwrap();
+        db.complete_message(&key, "wx1", "wx1", "$in", None).await.unwrap();
+        db.complete_message(&key, "wx2", "wx2", &Message::pending_mxid("wx2"), Some("failed")).await.unwrap();
+        assert!(db.get_pending_messages().await.unwrap().is_empty());
+
+        let sent = db.get_message_by_mxid("$out").await.unwrap().unwrap();
+        assert_eq!(sent.msg_id, "wx_out");
+        assert!(sent.sent);
+        let failed = db.get_message_by_id(&key, "wx2").await.unwrap().unwrap();
+        assert!(!failed.sent && failed.is_pending_mxid());
+        assert_eq!(failed.error.as_deref(), Some("failed"));
+    }
+}
+
+#[cfg(test)]
+mod migration_tests {
+    use matrix_bridge_wechat::database::{MIGRATIONS, pending_migrations};
+    use crate::common::empty_db;
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i32 + 1);
+        }
+        assert_eq!(pending_migrations(0).count(), MIGRATIONS.len());
+        assert_eq!(pending_migrations(MIGRATIONS.len() as i32).count(), 0);
+        assert!(!MIGRATIONS[0].sqlite_sql().contains("-- only: postgres"));
+    }
+
+    #[tokio::test]
+    async fn test_migrations_are_applied_once() {
+        let db = empty_db("migration").await;
+
+        assert_eq!(db.schema_version().await.unwrap(), 0);
+        db.run_migrations().await.unwrap();
+        let latest = MIGRATIONS.last().unwrap().version;
+        assert_eq!(db.schema_version().await.unwrap(), latest);
+        // Starting again finds nothing to apply.
+        db.run_migrations().await.unwrap();
+        assert_eq!(db.schema_version().await.unwrap(), latest);
+    }
+}
+
+#[cfg(test)]
+mod reaction_edit_mapping_tests {
+    use matrix_bridge_wechat::database::{MessageEdit, PortalKey, Reaction};
+    use crate::common::{portal, test_db};
+
+    #[tokio::test]
+    async fn test_reaction_and_edit_mappings() {
+        let db = test_db("mapping").await;
+
+        db.insert_portal(&portal("wxid_alice", "wxid_me")).await.unwrap();
+        let key = PortalKey::new("wxid_alice", "wxid_me");
+
+        db.upsert_reaction(&Reaction {
+            chat_uid: key.uid.clone(),
+            chat_receiver: key.receiver.clone(),
+            target_msg_id: "msg1".to_string(),
+            sender: "wxid_alice".to_string(),
+            mxid: "$reaction".to_string(),
+            reaction_key: "\u{1f44d}".to_string(),
+            msg_id: Some("react1".to_string()),
+            timestamp: 10,
+        }).await.unwrap();
+        let reaction = db.get_reaction_by_wechat_id("react1").await.unwrap().unwrap();
+        assert_eq!(reaction.mxid, "$reaction");
+        assert_eq!(reaction.timestamp, 10);
+
+        for (msg_id, mxid, timestamp) in [("edit2", "$edit2", 20), ("edit1", "$edit1", 10)] {
+            db.insert_message_edit(&MessageEdit {
+                chat_uid: key.uid.clone(),
+                chat_receiver: key.receiver.clone(),
+                msg_id: msg_id.to_string(),
+                target_msg_id: "msg1".to_string(),
+                mxid: mxid.to_string(),
+                sender: "wxid_alice".to_string(),
+                timestamp,
+            }).await.unwrap();
+        }
+        let edits: Vec<String> = db.get_message_edits(&key, "msg1").await.unwrap()
+            .into_iter()
+            .map(|e| e.msg_id)
+            .collect();
+        assert_eq!(edits, ["edit1", "edit2"]);
+        assert_eq!(db.get_message_edit_by_mxid("$edit2").await.unwrap().unwrap().msg_id, "edit2");
+        assert_eq!(db.get_message_edit_by_wechat_id("edit1").await.unwrap().unwrap().mxid, "$edit1");
+        db.delete_message_edits(&key, "msg1").await.unwrap();
+        assert!(db.get_message_edits(&key, "msg1").await.unwrap().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod media_cache_tests {
+    use matrix_bridge_wechat::bridge::media_cache::{MediaCache, content_hash};
+    use matrix_bridge_wechat::database::MediaCacheEntry;
+    use crate::common::test_db;
+
+    #[test]
+    fn test_content_hash() {
+        assert_eq!(
+            content_hash(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unused_uploads_are_pruned() {
+        let db = test_db("media-cache").await;
+
+        let now = chrono::Utc::now().timestamp();
+        for (data, last_used) in [(&b"avatar"[..], now), (&b"sticker"[..], now - 7200)] {
+            db.upsert_media_cache(&MediaCacheEntry {
+                hash: content_hash(data),
+                mxc: format!("mxc://example.com/{}", String::from_utf8_lossy(data)),
+                mimetype: "image/png".to_string(),
+                size: data.len() as i64,
+                created_at: last_used,
+                last_used,
+            }).await.unwrap();
+        }
+
+        let cache = MediaCache::new(db.clone(), Some(std::time::Duration::from_secs(3600)));
+        assert_eq!(cache.prune().await.unwrap(), 1);
+        let avatar = db.get_media_cache(&content_hash(b"avatar")).await.unwrap().unwrap();
+        assert_eq!(avatar.mxc, "mxc://example.com/avatar");
+        assert!(db.get_media_cache(&content_hash(b"sticker")).await.unwrap().is_none());
+    }
+}
+
+#[cfg(test)]
+mod async_media_tests {
+    use matrix_bridge_wechat::matrix::MatrixClient;
+
+    #[tokio::test]
+    async fn test_upload_to_reserved_media() {
+        let client = MatrixClient::new("http://127.0.0.1:1", "as_token");
+        let err = client.upload_media_to("https://example.com/file", b"data", "video/mp4", "video.mp4").await.unwrap_err();
+        assert!(err.to_string().contains("Invalid mxc URL"));
+        // Without a homeserver nothing can be reserved, so the caller falls back to uploading first.
+        assert!(client.create_media().await.is_err());
+        assert!(client.upload_media_to("mxc://example.com/abc", b"data", "video/mp4", "video.mp4").await.is_err());
+    }
+}
+
+#[cfg(test)]
+mod event_dispatcher_tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use matrix_bridge_wechat::bridge::event_dispatcher::EventDispatcher;
+
+    #[tokio::test]
+    async fn test_portals_are_handled_in_order_and_concurrently() {
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let log = handled.clone();
+        let dispatcher = EventDispatcher::new(2, 4, move |(chat, n, delay): (&'static str, u32, u64)| {
+            let log = log.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                log.lock().unwrap().push((chat, n));
+            }
+        });
+
+        // A slow event in one chat doesn't hold up another, and the full queue waits for room.
+        dispatcher.dispatch("slow", ("slow", 1, 200)).await;
+        for n in 1..=4 {
+            dispatcher.dispatch("fast", ("fast", n, 1)).await;
+        }
+        dispatcher.dispatch("slow", ("slow", 2, 0)).await;
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let handled = handled.lock().unwrap().clone();
+        assert_eq!(handled.len(), 6);
+        let fast: Vec<u32> = handled.iter().filter(|(c, _)| *c == "fast").map(|(_, n)| *n).collect();
+        assert_eq!(fast, vec![1, 2, 3, 4]);
+        assert_eq!(handled.iter().position(|e| *e == ("slow", 1)), Some(4));
+        assert_eq!(handled[5], ("slow", 2));
+    }
+}
+
+#[cfg(test)]
+mod rate_limit_tests {
+    use std::time::Duration;
+
+    use matrix_bridge_wechat::matrix::MatrixClient;
+    use matrix_bridge_wechat::matrix::rate_limit::{endpoint_group, retry_after};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_endpoint_groups_and_retry_after() {
+        assert_eq!(endpoint_group("/_matrix/client/v3/rooms/!a:b/send/m.room.message/1?access_token=x"), "send");
+        assert_eq!(endpoint_group("/_matrix/client/v3/rooms/!a:b/state/m.room.name/"), "state");
+        assert_eq!(endpoint_group("/_matrix/client/v3/rooms/!a:b/invite"), "membership");
+        assert_eq!(endpoint_group("/_matrix/client/v3/join/%21a%3Ab"), "membership");
+        assert_eq!(endpoint_group("/_matrix/client/v3/profile/@a:b/displayname"), "profile");
+        assert_eq!(endpoint_group("/_matrix/media/v3/upload?filename=a"), "media");
+        assert_eq!(endpoint_group("/_matrix/client/v3/account/whoami"), "default");
+
+        let body = r#"{"errcode":"M_LIMIT_EXCEEDED","error":"Too many requests","retry_after_ms":1500}"#;
+        assert_eq!(retry_after(body, Some("5")), Duration::from_millis(1500));
+        assert_eq!(retry_after("", Some("5")), Duration::from_secs(5));
+        assert_eq!(retry_after("", None), Duration::from_secs(1));
+        assert_eq!(retry_after(r#"{"retry_after_ms":3600000}"#, None), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_requests_are_retried() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let responses = [
+                ("429 Too Many Requests", r#"{"errcode":"M_LIMIT_EXCEEDED","error":"Slow down","retry_after_ms":10}"#),
+                ("200 OK", r#"{"user_id":"@wechatbot:example.com"}"#),
+            ];
+            for (status, body) in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = socket.read(&mut buf).await.unwrap();
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status, body.len(), body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let client = MatrixClient::new(format!("http://{}", address), "as_token");
+        assert_eq!(client.get_user_id().await.unwrap(), "@wechatbot:example.com");
+    }
+}
+
+#[cfg(test)]
+mod matrix_retry_tests {
+    use matrix_bridge_wechat::matrix::{MatrixClient, is_homeserver_unavailable, is_idempotent};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Answers one request per response, in order, and returns the homeserver URL.
+    async fn serve(responses: Vec<(&'static str, &'static str)>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = socket.read(&mut buf).await.unwrap();
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status, body.len(), body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_requests_are_retried() {
+        assert!(is_idempotent(&reqwest::Method::GET));
+        assert!(is_idempotent(&reqwest::Method::PUT));
+        assert!(!is_idempotent(&reqwest::Method::POST));
+
+        let homeserver = serve(vec![
+            ("502 Bad Gateway", "{}"),
+            ("200 OK", r#"{"user_id":"@wechatbot:example.com"}"#),
+        ]).await;
+        let client = MatrixClient::new(homeserver, "as_token").with_http_client(reqwest::Client::new());
+        assert_eq!(client.get_user_id().await.unwrap(), "@wechatbot:example.com");
+
+        // Creating media isn't idempotent, so the error is returned as is.
+        let homeserver = serve(vec![
+            ("502 Bad Gateway", "{}"),
+            ("200 OK", r#"{"content_uri":"mxc://example.com/abc"}"#),
+        ]).await;
+        let client = MatrixClient::new(homeserver, "as_token");
+        assert!(client.create_media().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_homeserver_unavailable_errors() {
+        let homeserver = serve(vec![("503 Service Unavailable", "{}")]).await;
+        let client = MatrixClient::new(homeserver, "as_token");
+        let error = client.create_media().await.unwrap_err();
+        assert!(is_homeserver_unavailable(&error));
+
+        // Nothing listens on the port anymore.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let client = MatrixClient::new(format!("http://{}", address), "as_token");
+        let error = client.create_media().await.unwrap_err();
+        assert!(is_homeserver_unavailable(&error));
+
+        // A homeserver that rejects the request is reachable.
+        let homeserver = serve(vec![("403 Forbidden", r#"{"errcode":"M_FORBIDDEN","error":"no"}"#)]).await;
+        let client = MatrixClient::new(homeserver, "as_token");
+        let error = client.create_media().await.unwrap_err();
+        assert!(!is_homeserver_unavailable(&error));
+    }
+}
+
+#[cfg(test)]
+mod matrix_queue_tests {
+    use matrix_bridge_wechat::bridge::matrix_queue::MatrixQueue;
+    use matrix_bridge_wechat::config::SendRetryConfig;
+    use matrix_bridge_wechat::wechat::Event;
+    use crate::common::test_db;
+
+    fn event(id: &str) -> Event {
+        let event: Event = serde_json::from_value(serde_json::json!({
+            "id": id,
+            "timestamp": 1700000000000i64,
+            "from": { "id": "wxid_bob", "username": "Bob" },
+            "chat": { "id": "wxid_bob", "type": "private" },
+            "type": "text",
+            "content": "hi",
+        })).unwrap();
+        Event { mxid: "@alice:example.com".to_string(), ..event }
+    }
+
+    #[tokio::test]
+    async fn test_queued_events_keep_their_order() {
+        let queue = MatrixQueue::new(SendRetryConfig { max_retries: 2, ..SendRetryConfig::default() });
+        let first = queue.item(&event("msg1")).unwrap();
+        let second = queue.item(&event("msg2")).unwrap();
+        assert!(second.queued_at > first.queued_at);
+        assert_eq!(first.attempts, 0);
+        assert!(queue.delay(1).is_some());
+        assert!(queue.delay(2).is_none());
+
+        let restored: Event = serde_json::from_str(&second.event).unwrap();
+        assert_eq!(restored.id, "msg2");
+        assert_eq!(second.mxid, "@alice:example.com");
+
+        let queued = event("msg3");
+        assert!(!queue.is_waiting(&queued).await);
+        queue.set_waiting("@alice:example.com", "wxid_bob", true).await;
+        assert!(queue.is_waiting(&queued).await);
+
+        let db = test_db("matrix-queue").await;
+
+        db.upsert_matrix_queue_item(&second).await.unwrap();
+        db.upsert_matrix_queue_item(&first).await.unwrap();
+        let mut retried = first.clone();
+        retried.attempts = 1;
+        db.upsert_matrix_queue_item(&retried).await.unwrap();
+        let items = db.get_matrix_queue().await.unwrap();
+        assert_eq!(items.iter().map(|i| i.event_id.as_str()).collect::<Vec<_>>(), ["msg1", "msg2"]);
+        assert_eq!(items[0].attempts, 1);
+
+        db.delete_matrix_queue_item("@alice:example.com", "msg1").await.unwrap();
+        assert_eq!(db.get_matrix_queue().await.unwrap().len(), 1);
+    }
+}
+
+#[cfg(test)]
+mod server_tests {
+    use std::sync::Arc;
+    use crate::common::test_config;
+
+    use matrix_bridge_wechat::bridge::WechatBridge;
+    use matrix_bridge_wechat::web::BridgeServer;
+
+    fn free_address() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    async fn is_healthy(addr: &str) -> bool {
+        match reqwest::get(format!("http://{}/health", addr)).await {
+            Ok(resp) => resp.status().is_success(),
+            Err(_) => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_listener_can_be_rebound() {
+        let (config, _db_file) = test_config("server");
+        let server = BridgeServer::new(Arc::new(WechatBridge::new(config).await.unwrap()));
+
+        let first = free_address();
+        server.rebind(&first, None).await.unwrap();
+        assert!(is_healthy(&first).await);
+
+        let second = free_address();
+        server.rebind(&second, None).await.unwrap();
+        assert!(is_healthy(&second).await);
+        assert!(!is_healthy(&first).await);
+
+        // Binding the same address again replaces the listener in place.
+        server.rebind(&second, None).await.unwrap();
+        assert!(is_healthy(&second).await);
+
+        server.stop().await;
+        assert!(!is_healthy(&second).await);
+    }
+
+    #[tokio::test]
+    async fn test_readiness_follows_wechat_listener() {
+        let (mut config, _db_file) = test_config("readiness");
+        config.bridge.listen_address = free_address();
+        let bridge = Arc::new(WechatBridge::new(config).await.unwrap());
+        let server = BridgeServer::new(bridge.clone());
+        let addr = free_address();
+        server.rebind(&addr, None).await.unwrap();
+
+        let ready = || async {
+            let resp = reqwest::get(format!("http://{}/readyz", addr)).await.unwrap();
+            let status = resp.status().as_u16();
+            (status, resp.json::<serde_json::Value>().await.unwrap()["wechat_listener"].clone())
+        };
+        assert_eq!(ready().await, (503, "down".into()));
+        bridge.wechat_service.clone().start().await.unwrap();
+        assert_eq!(ready().await, (200, "listening".into()));
+        bridge.wechat_service.stop().await;
+        assert_eq!(ready().await, (503, "down".into()));
+
+        server.stop().await;
+    }
+}
+
+#[cfg(test)]
+mod thirdparty_lookup_tests {
+    use matrix_bridge_wechat::bridge::WechatBridge;
+    use matrix_bridge_wechat::database::PortalKey;
+    use crate::common::test_config;
+
+    #[tokio::test]
+    async fn test_lookups_resolve_known_chats_only() {
+        let (config, _db_file) = test_config("thirdparty");
+        let bridge = WechatBridge::new(config).await.unwrap();
+
+        let key = PortalKey::new("@@family", "wxid_me");
+        bridge.get_portal_by_key(&key).await.unwrap();
+        let mut portal = bridge.db.get_portal_by_key(&key).await.unwrap().unwrap();
+        portal.mxid = Some("!family:example.com".to_string());
+        bridge.db.update_portal(&portal).await.unwrap();
+
+        let alias = bridge.lookup_remote_location("@@family").await.unwrap();
+        assert_eq!(alias, Some(bridge.config.format_alias("@@family")));
+        // Without logged-in users there's no agent to ask about other chats.
+        assert_eq!(bridge.lookup_remote_location("@@strangers").await.unwrap(), None);
+        assert_eq!(bridge.lookup_remote_location("wxid_friend").await.unwrap(), None);
+        assert!(bridge.lookup_remote_user("wxid_friend").await.unwrap().is_none());
+    }
+}
+
+#[cfg(all(test, unix))]
+mod unix_socket_tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Arc;
+    use crate::common::test_config;
+
+    use matrix_bridge_wechat::bridge::WechatBridge;
+    use matrix_bridge_wechat::web::BridgeServer;
+    use matrix_bridge_wechat::web::server::unix_socket_path;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_listen_on_unix_socket() {
+        let (mut config, _db_file) = test_config("unix");
+        let socket = std::env::temp_dir().join(format!("wechat-unix-test-{}.sock", std::process::id()));
+        config.appservice.hostname = format!("unix://{}", socket.display());
+        let addr = config.appservice.listen_address();
+        assert_eq!(unix_socket_path(&addr), Some(socket.to_str().unwrap()));
+        assert_eq!(unix_socket_path("0.0.0.0:17778"), None);
+
+        // A socket left behind by an earlier run is replaced.
+        let stale = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        drop(stale);
+
+        let server = BridgeServer::new(Arc::new(WechatBridge::new(config).await.unwrap()));
+        server.rebind(&addr, None).await.unwrap();
+        let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        let mut stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+        stream.write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        server.stop().await;
+        assert!(!socket.exists());
+    }
+}
+
+#[cfg(test)]
+mod agent_auth_tests {
+    use matrix_bridge_wechat::bridge::admin_command::{AdminCommandProcessor, AdminCommandResult};
+    use matrix_bridge_wechat::config::AgentAuthConfig;
+    use matrix_bridge_wechat::wechat::{AgentAuth, AgentIdentity, generate_agent_token, sign_challenge};
+    use crate::common::test_db;
+
+    #[tokio::test]
+    async fn test_shared_secret() {
+        let auth = AgentAuth::new("foobar");
+        assert_eq!(auth.authenticate(Some("Basic foobar")).await, Some(AgentIdentity::SharedSecret));
+        assert_eq!(auth.authenticate(Some("Basic foobaz")).await, None);
+        assert_eq!(auth.authenticate(None).await, None);
+        assert_eq!(AgentAuth::new("disable").authenticate(Some("Basic disable")).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_tokens_and_revocation() {
+        let db = test_db("agent-auth-tokens").await;
+        let auth = AgentAuth::new("foobar").with_tokens(db.clone(), &AgentAuthConfig::default());
+
+        let (row, token) = generate_agent_token("laptop");
+        db.insert_agent_token(&row).await.unwrap();
+        assert_ne!(row.token_hash, token);
+        let header = format!("Bearer {}", token);
+        assert_eq!(auth.authenticate(Some(&header)).await, Some(AgentIdentity::Token(row.id.clone())));
+        assert!(db.get_agent_token(&row.id).await.unwrap().unwrap().last_used > 0);
+        assert_eq!(auth.authenticate(Some(&format!("Bearer {}.wrong", row.id))).await, None);
+
+        assert!(db.revoke_agent_token(&row.id).await.unwrap());
+        assert_eq!(auth.authenticate(Some(&header)).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_challenge_response() {
+        let db = test_db("agent-auth-challenge").await;
+        let config = AgentAuthConfig { require_challenge: true };
+        let auth = AgentAuth::new("foobar").with_tokens(db.clone(), &config);
+        let (row, token) = generate_agent_token("");
+        db.insert_agent_token(&row).await.unwrap();
+
+        // The token itself and the shared secret aren't accepted.
+        assert_eq!(auth.authenticate(Some(&format!("Bearer {}", token))).await, None);
+        assert_eq!(auth.authenticate(Some("Basic foobar")).await, None);
+
+        let nonce = auth.issue_challenge().await.unwrap();
+        let header = sign_challenge(&token, &nonce).unwrap();
+        assert_eq!(auth.authenticate(Some(&header)).await, Some(AgentIdentity::Token(row.id.clone())));
+        // Each challenge can only be answered once.
+        assert_eq!(auth.authenticate(Some(&header)).await, None);
+        // Nor can one the bridge didn't issue.
+        assert_eq!(auth.authenticate(Some(&sign_challenge(&token, "made-up").unwrap())).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_stored_row_cannot_answer_challenges() {
+        use hmac::{Hmac, Mac};
+        use sha2::Sha256;
+
+        let db = test_db("agent-auth-row").await;
+        let config = AgentAuthConfig { require_challenge: true };
+        let auth = AgentAuth::new("foobar").with_tokens(db.clone(), &config);
+        let (row, _) = generate_agent_token("");
+        db.insert_agent_token(&row).await.unwrap();
+
+        // Someone who read the row still can't sign with what's in it.
+        let nonce = auth.issue_challenge().await.unwrap();
+        assert_eq!(auth.authenticate(Some(&sign_challenge(&format!("{}.{}", row.id, row.token_hash), &nonce).unwrap())).await, None);
+        let nonce = auth.issue_challenge().await.unwrap();
+        let key: Vec<u8> = (0..row.token_hash.len()).step_by(2).map(|i| u8::from_str_radix(&row.token_hash[i..i + 2], 16).unwrap()).collect();
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key).unwrap();
+        mac.update(nonce.as_bytes());
+        let signature: String = mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(auth.authenticate(Some(&format!("HMAC {}:{}:{}", row.id, nonce, signature))).await, None);
+    }
+
+    #[test]
+    fn test_agent_token_command() {
+        let processor = AdminCommandProcessor::new();
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(matches!(
+            processor.process("agent-token", &args(&["create", "home", "pc"])),
+            Some(AdminCommandResult::CreateAgentToken(d)) if d == "home pc"
+        ));
+        assert!(matches!(
+            processor.process("agent-token", &args(&["rotate", "abcd1234"])),
+            Some(AdminCommandResult::RotateAgentToken(id)) if id == "abcd1234"
+        ));
+        assert!(matches!(
+            processor.process("agent-token", &args(&["revoke"])),
+            Some(AdminCommandResult::Error(_))
+        ));
+    }
+}
+
+#[cfg(test)]
+mod protocol_version_tests {
+    use matrix_bridge_wechat::wechat::{Capabilities, Hello, PROTOCOL_VERSION, RequestType};
+
+    #[test]
+    fn test_negotiate() {
+        let hello = |max, min| Hello { protocol_version: max, min_protocol_version: min, capabilities: Capabilities::default() };
+        assert_eq!(hello(PROTOCOL_VERSION, None).negotiate(), Some(PROTOCOL_VERSION));
+        // A newer agent that still speaks the bridge's version.
+        assert_eq!(hello(PROTOCOL_VERSION + 3, Some(1)).negotiate(), Some(PROTOCOL_VERSION));
+        assert_eq!(hello(1, None).negotiate(), Some(1));
+        assert_eq!(hello(PROTOCOL_VERSION + 1, None).negotiate(), None);
+        assert_eq!(hello(0, None).negotiate(), None);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let hello: Hello = serde_json::from_value(serde_json::json!({
+            "protocol_version": 2,
+            "capabilities": { "requests": ["send_text", "send_reaction"], "media_streaming": true },
+        }))
+        .unwrap();
+        let caps = &hello.capabilities;
+        assert!(caps.media_streaming);
+        assert!(caps.supports(RequestType::SendText));
+        assert!(!caps.supports(RequestType::SendImage));
+        // Reactions need the flag too.
+        assert!(!caps.supports(RequestType::SendReaction));
+
+        let legacy = Capabilities::legacy();
+        assert!(legacy.supports(RequestType::SendReaction));
+        assert!(legacy.supports(RequestType::SyncMessages));
+    }
+}
+
+#[cfg(test)]
+mod event_ack_tests {
+    use matrix_bridge_wechat::bridge::event_ack::EventAcks;
+    use matrix_bridge_wechat::wechat::Event;
+    use crate::common::test_db;
+
+    #[tokio::test]
+    async fn test_acks_follow_handled_events() {
+        let db = test_db("event-ack").await;
+        let user = "@alice:example.com";
+
+        let acks = EventAcks::new(db.clone());
+        for seq in 1..=3 {
+            assert!(acks.received(user, seq).await.unwrap());
+        }
+        // A replay of an event that's still being handled.
+        assert!(!acks.received(user, 2).await.unwrap());
+
+        // Events of different portals finish out of order.
+        assert_eq!(acks.handled(user, 2).await.unwrap(), None);
+        assert_eq!(acks.handled(user, 1).await.unwrap(), Some(2));
+        assert_eq!(acks.handled(user, 3).await.unwrap(), Some(3));
+        assert!(!acks.received(user, 3).await.unwrap());
+
+        // After a restart, the agent's replay of handled events is recognized.
+        let acks = EventAcks::new(db.clone());
+        assert_eq!(acks.acked(user).await.unwrap(), 3);
+        assert!(!acks.received(user, 1).await.unwrap());
+        assert!(acks.received(user, 4).await.unwrap());
+        assert_eq!(acks.acked("@bob:example.com").await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_event_sequence_number() {
+        let event: Event = serde_json::from_value(serde_json::json!({
+            "id": "msg1",
+            "seq": 42,
+            "timestamp": 1700000000000i64,
+            "from": { "id": "wxid_alice", "username": "Alice" },
+            "chat": { "id": "wxid_alice", "type": "private" },
+            "type": "text",
+        }))
+        .unwrap();
+        assert_eq!(event.seq, Some(42));
+    }
+}
+
+#[cfg(test)]
+mod homeserver_check_tests {
+    use matrix_bridge_wechat::bridge::homeserver_check::{HomeserverMonitor, HomeserverStatus};
+    use matrix_bridge_wechat::matrix::MatrixClient;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn status(reachable: bool, pingable: Option<bool>) -> HomeserverStatus {
+        HomeserverStatus {
+            checked_at: 0,
+            reachable,
+            pingable,
+            ping_duration_ms: None,
+            error: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connectivity_changes() {
+        let monitor = HomeserverMonitor::default();
+        assert!(monitor.last().await.is_none());
+        assert!(monitor.record(status(true, Some(true))).await);
+        // Homeservers without appservice pings are fine.
+        assert!(!monitor.record(status(true, None)).await);
+        assert!(monitor.record(status(true, Some(false))).await);
+        assert!(!monitor.record(status(false, None)).await);
+        assert!(monitor.record(status(true, Some(true))).await);
+        assert!(monitor.last().await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_ping_appservice() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4096];
+            let read = socket.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..read]).to_string();
+            assert!(request.starts_with("POST /_matrix/client/v1/appservice/wechat/ping"));
+            let body = r#"{"duration_ms":12}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(), body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let client = MatrixClient::new(format!("http://{}", address), "as_token");
+        assert_eq!(client.ping_appservice("wechat", "txn1").await.unwrap(), 12);
+    }
+}
+
+#[cfg(test)]
+mod status_tests {
+    use matrix_bridge_wechat::bridge::activity::{LastEvents, UserActivity};
+    use matrix_bridge_wechat::database::User;
+    use crate::common::test_db;
+
+    #[tokio::test]
+    async fn test_user_activity() {
+        let activity = UserActivity::default();
+        assert_eq!(activity.get("@alice:example.com").await, LastEvents::default());
+
+        activity.wechat_event("@alice:example.com").await;
+        let last = activity.get("@alice:example.com").await;
+        assert!(last.wechat.is_some());
+        assert!(last.matrix.is_none());
+
+        activity.matrix_event("@alice:example.com").await;
+        assert!(activity.get("@alice:example.com").await.matrix.is_some());
+        assert_eq!(activity.get("@bob:example.com").await, LastEvents::default());
+    }
+
+    #[tokio::test]
+    async fn test_database_ping_and_users() {
+        let db = test_db("status").await;
+        db.ping().await.unwrap();
+
+        db.insert_user(&User::new("@bob:example.com")).await.unwrap();
+        let mut alice = User::new("@alice:example.com");
+        alice.uin = Some("wxid_alice".to_string());
+        db.insert_user(&alice).await.unwrap();
+        let users: Vec<_> = db.get_all_users().await.unwrap().into_iter().map(|u| u.mxid).collect();
+        assert_eq!(users, vec!["@alice:example.com", "@bob:example.com"]);
+        assert_eq!(db.get_all_logged_in_users().await.unwrap().len(), 1);
+    }
+}
+
+#[cfg(test)]
+mod debug_log_tests {
+    use matrix_bridge_wechat::bridge::debug_log::DebugLog;
+    use matrix_bridge_wechat::wechat::Event;
+
+    fn event(id: &str) -> Event {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "timestamp": 1700000000000i64,
+            "from": { "id": "wxid_alice", "username": "Alice" },
+            "chat": { "id": "wxid_alice", "type": "private" },
+            "type": "text",
+            "content": "hi",
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_keeps_the_last_events() {
+        let log = DebugLog::new(2);
+        for id in ["msg1", "msg2", "msg3"] {
+            log.record_event(&event(id)).await;
+        }
+        let ids: Vec<_> = log.events(10).await.into_iter().map(|r| r.event.id).collect();
+        assert_eq!(ids, vec!["msg3", "msg2"]);
+        assert_eq!(log.events(1).await.len(), 1);
+        assert!(log.find_event("msg2").await.is_some());
+        assert!(log.find_event("msg1").await.is_none());
+
+        log.record_transaction("txn1", &[]).await;
+        assert_eq!(log.transactions(10).await[0].txn_id, "txn1");
+    }
+
+    #[tokio::test]
+    async fn test_disabled_log_keeps_nothing() {
+        let log = DebugLog::new(0);
+        log.record_event(&event("msg1")).await;
+        log.record_transaction("txn1", &[]).await;
+        assert!(log.events(10).await.is_empty());
+        assert!(log.transactions(10).await.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod metrics_labels_tests {
+    use matrix_bridge_wechat::metrics::{Direction, Metrics};
+
+    #[test]
+    fn test_records_messages_by_labels() {
+        let metrics = Metrics::new();
+        metrics.record_message(Direction::MatrixToWechat, "m.text", "@alice:example.org", true);
+        metrics.record_message(Direction::MatrixToWechat, "m.text", "@alice:example.org", true);
+        metrics.record_message(Direction::WechatToMatrix, "photo", "@alice:example.org", false);
+
+        assert_eq!(metrics.messages_by_type.get(&["matrix→wechat", "m.text", "success"]), 2);
+        assert_eq!(metrics.messages_by_type.get(&["wechat→matrix", "photo", "failure"]), 1);
+        assert_eq!(metrics.user_messages.get(&["@alice:example.org", "matrix→wechat"]), 2);
+        assert_eq!(metrics.user_messages.get(&["@alice:example.org", "wechat→matrix"]), 0);
+        assert_eq!(metrics.messages_bridged.get(), 2);
+        assert_eq!(metrics.messages_sent.get(), 2);
+        assert_eq!(metrics.messages_failed.get(), 1);
+    }
+
+    #[test]
+    fn test_exports_labels() {
+        let metrics = Metrics::new();
+        metrics.record_message(Direction::WechatToMatrix, "text", "@bob:example.org", true);
+        let output = metrics.to_prometheus();
+        assert!(output.contains("bridge_messages_by_type{direction=\"wechat→matrix\",type=\"text\",result=\"success\"} 1\n"));
+        assert!(output.contains("bridge_user_messages{user=\"@bob:example.org\",direction=\"wechat→matrix\"} 1\n"));
+    }
+}
+
+#[cfg(test)]
+mod user_cleanup_tests {
+    use matrix_bridge_wechat::bridge::admin_command::{AdminCommandProcessor, AdminCommandResult};
+    use matrix_bridge_wechat::database::User;
+    use crate::common::{portal, test_db};
+
+    #[tokio::test]
+    async fn test_portals_by_receiver_and_user_deletion() {
+        let db = test_db("user-cleanup").await;
+
+        db.insert_portal(&portal("wxid_bob", "wxid_alice")).await.unwrap();
+        db.insert_portal(&portal("@@group", "wxid_alice")).await.unwrap();
+        db.insert_portal(&portal("wxid_bob", "wxid_carol")).await.unwrap();
+        assert_eq!(db.get_portals_by_receiver("wxid_alice").await.unwrap().len(), 2);
+
+        db.insert_user(&User::new("@alice:example.com")).await.unwrap();
+        db.delete_user("@alice:example.com").await.unwrap();
+        assert!(db.get_user_by_mxid("@alice:example.com").await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_clean_users_command() {
+        let processor = AdminCommandProcessor::new();
+        assert!(matches!(processor.process("clean-users", &[]), Some(AdminCommandResult::CleanUsers)));
+    }
+}
+
+#[cfg(test)]
+mod portal_gc_tests {
+    use matrix_bridge_wechat::bridge::portal_gc::{GcCandidate, GcReason, gc_report};
+    use matrix_bridge_wechat::config::PortalGcConfig;
+    use matrix_bridge_wechat::database::PortalKey;
+
+    #[test]
+    fn test_gc_reason() {
+        assert_eq!(GcReason::of(true, Some(true), 1), None);
+        // Chats that couldn't be listed aren't considered gone.
+        assert_eq!(GcReason::of(true, None, 1), None);
+        assert_eq!(GcReason::of(true, Some(false), 1), Some(GcReason::ChatGone));
+        assert_eq!(GcReason::of(true, None, 0), Some(GcReason::NoUsers));
+        assert_eq!(GcReason::of(false, None, 1), Some(GcReason::ReceiverGone));
+    }
+
+    #[test]
+    fn test_gc_report_and_defaults() {
+        let config = PortalGcConfig::default();
+        assert!(config.interval_duration().is_none());
+        assert!(config.dry_run);
+
+        let candidates = vec![GcCandidate {
+            key: PortalKey::new("wxid_bob", "wxid_alice"),
+            room_id: "!room:example.com".to_string(),
+            reason: GcReason::NoUsers,
+        }];
+        let report = gc_report(&candidates, true);
+        assert!(report.starts_with("Portal cleanup (dry run) found 1 unused portal rooms:"));
+        assert!(report.contains("- !room:example.com (wxid_bob of wxid_alice): all Matrix users left"));
+    }
+}
+
+#[cfg(test)]
+mod group_portal_key_tests {
+    use matrix_bridge_wechat::bridge::WechatBridge;
+    use matrix_bridge_wechat::database::{Portal, PortalKey, User};
+    use matrix_bridge_wechat::wechat::Event;
+    use crate::common::{portal, test_config};
+
+    #[tokio::test]
+    async fn test_group_messages_reach_joined_group_portals() {
+        let (config, _db_file) = test_config("group-key");
+        let bridge = WechatBridge::new(config).await.unwrap();
+        bridge.db.insert_user(&User { uin: Some("wxid_me".to_string()), ..User::new("@alice:example.com") }).await.unwrap();
+        // The portal `join-group` opens for the account.
+        let joined = PortalKey::new("123@chatroom", "wxid_me");
+        bridge.db.insert_portal(&Portal { mxid: Some("!group:example.com".to_string()), ..portal(&joined.uid, &joined.receiver) }).await.unwrap();
+
+        for sender in ["wxid_bob", "wxid_carol"] {
+            let event: Event = serde_json::from_value(serde_json::json!({
+                "id": format!("msg-{}", sender),
+                "timestamp": 1700000000000i64,
+                "from": { "id": sender, "username": sender },
+                "chat": { "id": "123@chatroom", "type": "group" },
+                "type": "text",
+                "content": "hi",
+            })).unwrap();
+            let event = Event { mxid: "@alice:example.com".to_string(), ..event };
+            let key = bridge.event_portal_key(&event).await.unwrap();
+            assert_eq!(key, joined);
+            let portal = bridge.get_portal_by_key(&key).await.unwrap();
+            assert_eq!(portal.inner.mxid.as_deref(), Some("!group:example.com"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod private_chat_key_tests {
+    use matrix_bridge_wechat::bridge::portal::private_chat_key;
+    use matrix_bridge_wechat::database::PortalKey;
+    use matrix_bridge_wechat::matrix::Intent;
+    use matrix_bridge_wechat::matrix::client::MatrixClient;
+
+    #[test]
+    fn test_both_sides_share_a_portal() {
+        let received = private_chat_key("wxid_bob", "wxid_bob", Some("wxid_alice"));
+        let sent_from_phone = private_chat_key("wxid_bob", "wxid_alice", Some("wxid_alice"));
+        assert_eq!(received, PortalKey::new("wxid_bob", "wxid_alice"));
+        assert_eq!(sent_from_phone, received);
+        // Agents that put the account itself in the chat of its own messages.
+        assert_eq!(private_chat_key("wxid_alice", "wxid_bob", Some("wxid_alice")), received);
+        assert_eq!(private_chat_key("wxid_bob", "wxid_bob", None), PortalKey::new("wxid_bob", "wxid_bob"));
+    }
+
+    #[test]
+    fn test_double_puppet_intent() {
+        let bot = MatrixClient::new("http://localhost:8008", "as_token").with_user_id("@wechatbot:example.com");
+        let user = MatrixClient::new("http://localhost:8008", "user_token").with_user_id("@alice:example.com");
+        let intent = Intent::double_puppet(bot, user);
+        assert_eq!(intent.mxid(), "@alice:example.com");
+        assert_eq!(intent.client().user_id(), Some("@alice:example.com"));
+    }
+}
+
+#[cfg(test)]
+mod self_sent_tests {
+    use matrix_bridge_wechat::matrix::Intent;
+    use matrix_bridge_wechat::matrix::client::MatrixClient;
+    use matrix_bridge_wechat::wechat::Event;
+
+    #[test]
+    fn test_from_self_flag() {
+        let json = serde_json::json!({
+            "id": "msg1",
+            "timestamp": 1700000000000i64,
+            "from": { "id": "wxid_alice", "username": "Alice" },
+            "chat": { "id": "wxid_bob", "type": "private" },
+            "type": "text",
+        });
+        let event: Event = serde_json::from_value(json.clone()).unwrap();
+        assert!(!event.from_self);
+        assert!(serde_json::to_value(&event).unwrap().get("from_self").is_none());
+
+        let mut json = json;
+        json["from_self"] = serde_json::json!(true);
+        let event: Event = serde_json::from_value(json).unwrap();
+        assert!(event.from_self);
+    }
+
+    #[test]
+    fn test_only_double_puppets_are_marked() {
+        let bot = MatrixClient::new("http://localhost:8008", "as_token").with_user_id("@wechatbot:example.com");
+        let user = MatrixClient::new("http://localhost:8008", "user_token").with_user_id("@alice:example.com");
+        assert!(Intent::double_puppet(bot.clone(), user).is_double_puppet());
+        assert!(!Intent::new(bot, "@wechat_bob:example.com").is_double_puppet());
+    }
+}
+
+#[cfg(test)]
+mod echo_tests {
+    use matrix_bridge_wechat::wechat::{EchoTracker, Event, EventType};
+
+    fn event(id: &str, text: &str, timestamp: i64) -> Event {
+        let mut event: Event = serde_json::from_value(serde_json::json!({
+            "id": id,
+            "timestamp": timestamp,
+            "from": { "id": "wxid_alice", "username": "Alice" },
+            "chat": { "id": "wxid_bob", "type": "private" },
+            "type": "text",
+            "content": text,
+        }))
+        .unwrap();
+        event.mxid = "@alice:example.com".to_string();
+        event
+    }
+
+    #[test]
+    fn test_echo_by_msg_id() {
+        let echoes = EchoTracker::default();
+        let handle = echoes.sending("@alice:example.com", "wxid_bob", EventType::Text, Some("hi"));
+        echoes.sent(handle, "msg1");
+        assert!(echoes.is_echo(&event("msg1", "hi", 0)));
+        assert!(!echoes.is_echo(&event("msg1", "hi", 0)));
+
+        let handle = echoes.sending("@alice:example.com", "wxid_bob", EventType::Text, Some("hi"));
+        echoes.failed(handle);
+        let now = chrono::Utc::now().timestamp_millis();
+        assert!(!echoes.is_echo(&event("msg2", "hi", now)));
+    }
+
+    #[test]
+    fn test_echo_by_content_before_msg_id() {
+        let echoes = EchoTracker::default();
+        echoes.sending("@alice:example.com", "wxid_bob", EventType::Text, Some("hi"));
+        let now = chrono::Utc::now().timestamp_millis();
+        assert!(!echoes.is_echo(&event("msg1", "hello", now)));
+        assert!(!echoes.is_echo(&event("msg1", "hi", now - 120_000)));
+        assert!(echoes.is_echo(&event("msg1", "hi", now)));
+        assert!(!echoes.is_echo(&event("msg2", "hi", now)));
+    }
+}
+
+#[cfg(test)]
+mod friend_request_tests {
+    use matrix_bridge_wechat::bridge::command::{CommandProcessor, CommandResult};
+    use matrix_bridge_wechat::config::PermissionLevel;
+    use matrix_bridge_wechat::wechat::{Event, EventType};
+
+    #[test]
+    fn test_friend_request_ticket() {
+        let mut json = serde_json::json!({
+            "id": "req1",
+            "timestamp": 1700000000000i64,
+            "from": { "id": "wxid_carol", "username": "Carol" },
+            "chat": { "id": "wxid_carol", "type": "private" },
+            "type": "friend_request",
+            "content": "Hi, it's Carol",
+        });
+        let event: Event = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(event.event_type, EventType::FriendRequest);
+        assert_eq!(event.friend_request_ticket(), "wxid_carol");
+
+        json["data"] = serde_json::json!({ "v3": "v3_abc@stranger" });
+        let event: Event = serde_json::from_value(json).unwrap();
+        assert_eq!(event.friend_request_ticket(), "v3_abc@stranger");
+    }
+
+    #[test]
+    fn test_accept_friend_command() {
+        let processor = CommandProcessor::new("!wechat".to_string());
+        let args = vec!["v3_abc@stranger".to_string()];
+        assert!(matches!(
+            processor.process("accept-friend", &args, PermissionLevel::User),
+            CommandResult::AcceptFriend(id) if id == "v3_abc@stranger"
+        ));
+        assert!(matches!(processor.process("accept-friend", &[], PermissionLevel::User), CommandResult::Error(_)));
+        assert!(matches!(processor.process("accept-friend", &args, PermissionLevel::Relay), CommandResult::Error(_)));
+    }
+}
+
+#[cfg(test)]
+mod group_invite_tests {
+    use matrix_bridge_wechat::bridge::command::{CommandProcessor, CommandResult};
+    use matrix_bridge_wechat::config::PermissionLevel;
+    use matrix_bridge_wechat::wechat::{Event, EventType, RequestType};
+
+    #[test]
+    fn test_group_invite_ticket() {
+        let mut json = serde_json::json!({
+            "id": "inv1",
+            "timestamp": 1700000000000i64,
+            "from": { "id": "wxid_bob", "username": "Bob" },
+            "chat": { "id": "123@chatroom", "type": "group", "title": "Hiking" },
+            "type": "group_invite",
+        });
+        let event: Event = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(event.event_type, EventType::GroupInvite);
+        assert_eq!(event.group_invite_ticket(), None);
+
+        json["data"] = serde_json::json!({ "invite": "https://support.weixin.qq.com/cgi-bin/invite?x=1" });
+        let event: Event = serde_json::from_value(json).unwrap();
+        assert_eq!(event.group_invite_ticket(), Some("https://support.weixin.qq.com/cgi-bin/invite?x=1"));
+        assert_eq!(serde_json::to_value(RequestType::JoinGroup).unwrap(), "join_group");
+    }
+
+    #[test]
+    fn test_join_group_command() {
+        let processor = CommandProcessor::new("!wechat".to_string());
+        let args = vec!["ticket".to_string()];
+        assert!(matches!(
+            processor.process("join-group", &args, PermissionLevel::User),
+            CommandResult::JoinGroup(invite) if invite == "ticket"
+        ));
+        assert!(matches!(processor.process("join-group", &[], PermissionLevel::User), CommandResult::Error(_)));
+    }
+}
+
+#[cfg(test)]
+mod link_preview_tests {
+    use matrix_bridge_wechat::bridge::link_preview::{LinkPreview, PreviewImage};
+
+    #[test]
+    fn test_open_graph_tags() {
+        let html = r#"<html><head><title>Fallback</title>
+            <meta property="og:title" content="Spring &amp; Summer">
+            <meta content='A walk in the park' name="description">
+            <meta property="og:image" content="/img/cover.jpg" />
+            </head></html>"#;
+        let preview = LinkPreview::from_html("https://example.com/post/1", html);
+        assert_eq!(preview.title.as_deref(), Some("Spring & Summer"));
+        assert_eq!(preview.description.as_deref(), Some("A walk in the park"));
+        assert_eq!(preview.image_url.as_deref(), Some("https://example.com/img/cover.jpg"));
+
+        let preview = LinkPreview::from_html("https://example.com", "<title> Plain page </title>");
+        assert_eq!(preview.title.as_deref(), Some("Plain page"));
+        assert_eq!(preview.image_url, None);
+    }
+
+    #[test]
+    fn test_beeper_preview() {
+        let mut preview = LinkPreview {
+            url: "https://example.com".to_string(),
+            title: Some("From the card".to_string()),
+            ..LinkPreview::default()
+        };
+        assert!(!preview.is_complete());
+        preview.merge(LinkPreview {
+            url: "https://example.com".to_string(),
+            title: Some("From the page".to_string()),
+            description: None,
+            image_url: Some("https://example.com/a.png".to_string()),
+        });
+        assert!(preview.is_complete());
+        let image = PreviewImage {
+            mxc: "mxc://example.com/abc".to_string(),
+            mimetype: "image/png".to_string(),
+            size: 1234,
+        };
+        assert_eq!(
+            preview.to_beeper(Some(&image)),
+            serde_json::json!({
+                "matched_url": "https://example.com",
+                "og:url": "https://example.com",
+                "og:title": "From the card",
+                "og:image": "mxc://example.com/abc",
+                "og:image:type": "image/png",
+                "matrix:image:size": 1234,
+            })
+        );
+    }
+}
+
+#[cfg(test)]
+mod chat_record_tests {
+    use matrix_bridge_wechat::formatter::chat_record::{ChatRecord, RecordItemKind};
+
+    const RECORD: &str = r#"<msg><appmsg appid="" sdkver="0"><title>Chat history of Alice and Bob</title><type>19</type>
+        <recorditem><![CDATA[<recordinfo><datalist count="3">
+        <dataitem datatype="1" dataid="a"><sourcename>Alice</sourcename><sourcetime>2023-11-15 10:00</sourcetime><datadesc>Lunch at <noon>?</datadesc></dataitem>
+        <dataitem datatype="2" dataid="b"><sourcename>Bob</sourcename><sourcetime>2023-11-15 10:01</sourcetime><cdndataurl>x</cdndataurl></dataitem>
+        <dataitem datatype="8" dataid="c"><sourcename>Bob</sourcename><sourcetime>2023-11-15 10:02</sourcetime><datatitle>menu.pdf</datatitle></dataitem>
+        </datalist></recordinfo>]]></recorditem></appmsg></msg>"#;
+
+    #[test]
+    fn test_parse_chat_record() {
+        let record = ChatRecord::parse(RECORD).unwrap();
+        assert_eq!(record.title, "Chat history of Alice and Bob");
+        let kinds: Vec<_> = record.items.iter().map(|i| i.kind).collect();
+        assert_eq!(kinds, [RecordItemKind::Text, RecordItemKind::Image, RecordItemKind::File]);
+        assert!(record.items[1].xml.contains("<cdndataurl>x</cdndataurl>"));
+        assert_eq!(record.items.iter().filter(|i| i.has_media()).count(), 2);
+        assert!(ChatRecord::parse("<msg><appmsg><type>5</type></appmsg></msg>").is_none());
+    }
+
+    #[test]
+    fn test_render_chat_record() {
+        let record = ChatRecord::parse(RECORD).unwrap();
+        assert_eq!(
+            record.body(),
+            "Chat history of Alice and Bob\n> Alice (2023-11-15 10:00): Lunch at <noon>?\n\
+             > Bob (2023-11-15 10:01): [Image]\n> Bob (2023-11-15 10:02): [File] menu.pdf"
+        );
+        let html = record.html();
+        assert!(html.starts_with("<p><strong>Chat history of Alice and Bob</strong></p><blockquote>"));
+        assert!(html.contains("<strong>Alice</strong> <small>2023-11-15 10:00</small><br/>Lunch at &lt;noon&gt;?"));
+        assert!(html.ends_with("</blockquote>"));
+    }
+}
+
+#[cfg(test)]
+mod app_card_tests {
+    use matrix_bridge_wechat::formatter::app_card::{AppCard, AppCardKind};
+
+    #[test]
+    fn test_mini_program_card() {
+        let xml = r#"<msg><appmsg><title>Order your coffee</title><type>33</type>
+            <url>https://example.com/coffee</url><sourcedisplayname>Coffee &amp; Co</sourcedisplayname>
+            <weappinfo><appid>wx123</appid><weappiconurl>https://example.com/icon.png</weappiconurl></weappinfo>
+            </appmsg></msg>"#;
+        let card = AppCard::parse(xml).unwrap();
+        assert_eq!(card.kind, AppCardKind::MiniProgram);
+        assert_eq!(card.source.as_deref(), Some("Coffee & Co"));
+        assert_eq!(card.thumb_url.as_deref(), Some("https://example.com/icon.png"));
+        assert_eq!(card.body(), "[Mini program] Coffee & Co\nOrder your coffee\nhttps://example.com/coffee");
+        assert_eq!(
+            card.html(Some("mxc://example.com/thumb")),
+            "<p><strong>[Mini program] Coffee &amp; Co</strong></p>\
+             <img src=\"mxc://example.com/thumb\" alt=\"Order your coffee\" height=\"160\"/>\
+             <p>Order your coffee</p><a href=\"https://example.com/coffee\">https://example.com/coffee</a>"
+        );
+    }
+
+    #[test]
+    fn test_channels_card() {
+        let xml = r#"<msg><appmsg><title>当前版本不支持展示该内容</title><type>51</type>
+            <finderFeed><nickname>Travel Vlog</nickname><desc>Sunrise over the mountains</desc>
+            <mediaList><media><coverUrl>https://example.com/cover.jpg</coverUrl></media></mediaList></finderFeed>
+            </appmsg></msg>"#;
+        let card = AppCard::parse(xml).unwrap();
+        assert_eq!(card.kind, AppCardKind::Channels);
+        assert_eq!(card.body(), "[Channels] Travel Vlog\nSunrise over the mountains");
+        assert_eq!(card.thumb_url.as_deref(), Some("https://example.com/cover.jpg"));
+        assert_eq!(card.url, None);
+        assert!(AppCard::parse("<msg><appmsg><type>5</type></appmsg></msg>").is_none());
+    }
+}
+
+#[cfg(test)]
+mod media_limit_tests {
+    use matrix_bridge_wechat::bridge::media_limit::{MediaTooLarge, check_size, declared_size, format_size};
+
+    #[test]
+    fn test_declared_size() {
+        assert_eq!(declared_size(&serde_json::json!({ "size": 1234 })), Some(1234));
+        let file = serde_json::json!({ "xml": "<msg><appmsg><appattach><totallen>52428801</totallen></appattach></appmsg></msg>" });
+        assert_eq!(declared_size(&file), Some(52428801));
+        let video = serde_json::json!({ "xml": r#"<msg><videomsg aeskey="k" length="2048" playlength="3"/></msg>"# });
+        assert_eq!(declared_size(&video), Some(2048));
+        assert_eq!(declared_size(&serde_json::json!({ "xml": "<msg><img/></msg>" })), None);
+    }
+
+    #[test]
+    fn test_check_size() {
+        assert_eq!(check_size(100, Some(100)), Ok(()));
+        assert_eq!(check_size(u64::MAX, None), Ok(()));
+        let err = check_size(60 * 1024 * 1024, Some(50 * 1024 * 1024)).unwrap_err();
+        assert_eq!(err, MediaTooLarge { size: 60 * 1024 * 1024, limit: 50 * 1024 * 1024 });
+        assert_eq!(err.to_string(), "the file is 60.0 MB, larger than the limit of 50.0 MB");
+        assert_eq!(format_size(512), "512 bytes");
+        assert_eq!(format_size(1536), "1.5 KB");
+    }
+}
+
+#[cfg(test)]
+mod group_info_tests {
+    use matrix_bridge_wechat::bridge::command::{CommandProcessor, CommandResult};
+    use matrix_bridge_wechat::bridge::group_info::{format_group_info, format_members, search_members};
+    use matrix_bridge_wechat::config::PermissionLevel;
+    use matrix_bridge_wechat::wechat::{GroupInfo, GroupMember};
+
+    fn member(id: &str, name: &str, nickname: Option<&str>) -> GroupMember {
+        GroupMember {
+            id: id.to_string(),
+            name: name.to_string(),
+            nickname: nickname.map(str::to_string),
+            avatar: None,
+        }
+    }
+
+    #[test]
+    fn test_members_search() {
+        let processor = CommandProcessor::new("!wc".to_string());
+        let args = vec!["big".to_string(), "bob".to_string()];
+        assert!(matches!(
+            processor.process("members", &args, PermissionLevel::User),
+            CommandResult::GroupMembers(Some(query)) if query == "big bob"
+        ));
+        assert!(matches!(processor.process("members", &[], PermissionLevel::User), CommandResult::GroupMembers(None)));
+
+        let members = vec![
+            member("wxid_alice", "Alice", Some("Ally <3")),
+            member("wxid_bob", "Bob", None),
+        ];
+        let found = search_members(&members, Some("ALLY"));
+        assert_eq!(found.len(), 1);
+        let (body, html) = format_members(&found, members.len(), Some("ALLY"));
+        assert!(body.contains("1 of 2 members"));
+        assert!(body.contains("Ally <3 (Alice) wxid_alice"));
+        assert!(html.contains("<strong>Ally &lt;3</strong>"));
+        assert_eq!(search_members(&members, None).len(), 2);
+        assert!(format_members(&[], 2, Some("carol")).0.starts_with("No members match"));
+    }
+
+    #[test]
+    fn test_group_info() {
+        let info: GroupInfo = serde_json::from_value(serde_json::json!({
+            "id": "@@family",
+            "name": "Family",
+            "owner": "wxid_alice",
+            "notice": "Dinner at 7"
+        }))
+        .unwrap();
+        assert_eq!(info.owner.as_deref(), Some("wxid_alice"));
+        let (body, html) = format_group_info(&info, 12, Some("Alice (wxid_alice)"), true);
+        assert!(body.starts_with("Family"));
+        assert!(body.contains("- Group ID: @@family"));
+        assert!(body.contains("- Members: 12"));
+        assert!(body.contains("- Owner: Alice (wxid_alice)"));
+        assert!(body.contains("- Encrypted: yes"));
+        assert!(html.contains("<li>Announcement: Dinner at 7</li>"));
+    }
+}
+
+#[cfg(test)]
+mod wechat_backend_tests {
+    use matrix_bridge_wechat::bridge::BridgeUser;
+    use crate::common::{ALICE, test_bridge};
+
+    #[tokio::test]
+    async fn test_bridge_uses_replaced_backend() {
+        let test = test_bridge("backend").await;
+        let bridge = &test.bridge;
+
+        let wechat = bridge.get_client(ALICE);
+        assert_eq!(wechat.mxid(), ALICE);
+        // The provided method goes through the backend's own send.
+        assert_eq!(wechat.send_text_message("wxid_bob", "hi", None).await.unwrap(), "sent:hi");
+        assert_eq!(test.wechat.calls(), ["send_text wxid_bob hi reply=-"]);
+
+        let mut user = BridgeUser::new("@carol:example.com".to_string(), bridge.db.clone());
+        assert!(!user.is_logged_in());
+        user.login(wechat).await.unwrap();
+        assert!(user.is_logged_in());
+        assert_eq!(user.inner.uin.as_deref(), Some("wxid_me"));
+    }
+
+    #[tokio::test]
+    async fn test_reloaded_users_keep_their_backend() {
+        let test = test_bridge("backend-reload").await;
+        let bridge = &test.bridge;
+
+        // Alice logged in before the bridge started, like after a restart.
+        let alice = bridge.get_user_by_mxid(ALICE).await.unwrap();
+        assert_eq!(alice.uin(), Some("wxid_me"));
+        assert_eq!(alice.get_client().unwrap().mxid(), ALICE);
+        // And again once a login elsewhere drops the cached user.
+        bridge.invalidate_user(ALICE).await;
+        assert!(bridge.get_user_by_mxid(ALICE).await.unwrap().get_client().is_some());
+        assert!(bridge.get_user_by_mxid("@bob:example.com").await.unwrap().get_client().is_none());
+    }
+}
+
+#[cfg(all(test, feature = "grpc"))]
+mod grpc_transport_tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use matrix_bridge_wechat::wechat::grpc::{ChallengeRequest, ChallengeResponse, Frame, FrameType};
+    use matrix_bridge_wechat::wechat::{Message, MessageType, Request, RequestType, WechatService};
+    use tonic::codegen::http::uri::PathAndQuery;
+
+    #[test]
+    fn test_frames_carry_websocket_messages() {
+        let request = Request { request_type: RequestType::Register, data: None };
+        let msg = Message::request(7, "@alice:example.com", &request);
+        let frame = Frame::from_message(&msg);
+        assert_eq!(frame.r#type, FrameType::Request as i32);
+        assert_eq!(frame.mxid, "@alice:example.com");
+
+        let decoded = <Frame as prost::Message>::decode(prost::Message::encode_to_vec(&frame).as_slice()).unwrap();
+        let back = decoded.into_message().unwrap();
+        assert_eq!(back.id, 7);
+        assert_eq!(back.msg_type, MessageType::Request);
+        assert_eq!(back.data, msg.data);
+
+        let empty = Frame { r#type: FrameType::Response as i32, ..frame };
+        assert!(Frame { data: String::new(), ..empty.clone() }.into_message().unwrap().data.is_none());
+        assert!(Frame { r#type: 9, ..empty }.into_message().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_agents_connect_over_grpc() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let service = Arc::new(WechatService::new(format!("127.0.0.1:{}", port), "foobar"));
+        service.clone().start_grpc().await.unwrap();
+        let mut statuses = service.subscribe_agent_status();
+
+        let channel = tonic::transport::Endpoint::from_shared(format!("http://127.0.0.1:{}", port))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = tonic::client::Grpc::new(channel);
+
+        client.ready().await.unwrap();
+        let challenge: tonic::Response<ChallengeResponse> = client
+            .unary(
+                tonic::Request::new(ChallengeRequest {}),
+                PathAndQuery::from_static("/wechat.agent.v1.Agent/Challenge"),
+                tonic_prost::ProstCodec::default(),
+            )
+            .await
+            .unwrap();
+        assert!(!challenge.get_ref().nonce.is_empty());
+
+        let register = Message::request(1, "@alice:example.com", &Request { request_type: RequestType::Register, data: None });
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tx.send(Frame::from_message(&register)).unwrap();
+        let mut request = tonic::Request::new(tokio_stream::wrappers::UnboundedReceiverStream::new(rx));
+        request.metadata_mut().insert("authorization", "Basic foobar".parse().unwrap());
+        client.ready().await.unwrap();
+        let mut frames: tonic::Streaming<Frame> = client
+            .streaming(
+                request,
+                PathAndQuery::from_static("/wechat.agent.v1.Agent/Connect"),
+                tonic_prost::ProstCodec::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner();
+        let status = tokio::time::timeout(Duration::from_secs(5), statuses.recv()).await.unwrap().unwrap();
+        assert_eq!(status, matrix_bridge_wechat::wechat::AgentStatus::Connected("@alice:example.com".to_string()));
+
+        // Requests for the user go out on the stream and its responses come back.
+        let requester = service.clone();
+        let pending = tokio::spawn(async move {
+            requester.request("@alice:example.com", &Request { request_type: RequestType::GetSelf, data: None }).await
+        });
+        let sent = frames.message().await.unwrap().unwrap().into_message().unwrap();
+        assert_eq!(sent.mxid, "@alice:example.com");
+        let response = serde_json::json!({ "type": "get_self", "data": { "id": "wxid_me", "name": "Me" } });
+        let reply = Message { id: sent.id, mxid: sent.mxid, msg_type: MessageType::Response, data: Some(response) };
+        tx.send(Frame::from_message(&reply)).unwrap();
+        let response = pending.await.unwrap().unwrap();
+        assert_eq!(response.data.unwrap()["id"], "wxid_me");
+
+        service.stop().await;
+    }
+}
+
+#[cfg(test)]
+mod http_agent_tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use matrix_bridge_wechat::config::HttpAgentConfig;
+    use matrix_bridge_wechat::wechat::{Message, Request, RequestType, WechatService, sign_webhook};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Answers one request with `body`, returning the agent URL and the request received.
+    async fn agent(body: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/requests", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, content)) = text.split_once("\r\n\r\n") {
+                    let length = head.lines()
+                        .find_map(|l| l.to_ascii_lowercase().strip_prefix("content-length: ").map(|v| v.parse::<usize>().unwrap()))
+                        .unwrap_or(0);
+                    if content.len() >= length {
+                        break;
+                    }
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(), body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request).to_string()
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn test_http_agents_post_events_and_answer_requests() {
+        let (url, received) = agent(r#"{"type":"get_self","data":{"id":"wxid_me","name":"Me"}}"#).await;
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let service = Arc::new(
+            WechatService::new(format!("127.0.0.1:{}", port), "disable").with_http_agents(vec![HttpAgentConfig {
+                id: "office".to_string(),
+                url,
+                secret: "s3cret".to_string(),
+                users: vec!["@alice:example.com".to_string()],
+            }]),
+        );
+        let mut events = service.subscribe_events();
+        service.clone().start().await.unwrap();
+
+        let event = serde_json::json!({
+            "id": "msg1",
+            "timestamp": 1700000000000i64,
+            "from": { "id": "wxid_bob", "username": "Bob" },
+            "chat": { "id": "wxid_bob", "type": "private" },
+            "type": "text",
+            "content": "hi",
+        });
+        let request = Request { request_type: RequestType::Event, data: Some(event) };
+        let body = serde_json::to_vec(&Message::request(1, "@alice:example.com", &request)).unwrap();
+        let post = |signature: String| {
+            let timestamp = chrono::Utc::now().timestamp();
+            reqwest::Client::new()
+                .post(format!("http://127.0.0.1:{}/wechat/v1/events", port))
+                .header("X-Agent-Id", "office")
+                .header("X-Timestamp", timestamp.to_string())
+                .header("X-Signature", if signature.is_empty() { sign_webhook("s3cret", timestamp, &body) } else { signature })
+                .body(body.clone())
+                .send()
+        };
+        assert_eq!(post("00ff".to_string()).await.unwrap().status(), 403);
+        assert!(post(String::new()).await.unwrap().status().is_success());
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+        assert_eq!((event.id.as_str(), event.mxid.as_str()), ("msg1", "@alice:example.com"));
+
+        // Requests for the agent's users are posted to it and answered in the response.
+        let response = service
+            .request("@alice:example.com", &Request { request_type: RequestType::GetSelf, data: None })
+            .await
+            .unwrap();
+        assert_eq!(response.data.unwrap()["id"], "wxid_me");
+        let posted = received.await.unwrap();
+        assert!(posted.to_ascii_lowercase().contains("x-agent-id: office"));
+        assert!(posted.contains(r#""type":"get_self""#));
+
+        service.stop().await;
+    }
+}
+
+#[cfg(test)]
+mod wecom_tests {
+    use aes::cipher::block_padding::NoPadding;
+    use aes::cipher::{BlockEncryptMut, KeyIvInit};
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use matrix_bridge_wechat::config::WecomConfig;
+    use matrix_bridge_wechat::wechat::{ChatType, EventType, WechatBackend};
+    use matrix_bridge_wechat::wecom::{WecomClient, WecomCrypto, callback_event};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const KEY: [u8; 32] = *b"0123456789abcdef0123456789abcdef";
+
+    fn config(api_url: &str) -> WecomConfig {
+        WecomConfig {
+            mxid: "@alice:example.com".to_string(),
+            corp_id: "ww123".to_string(),
+            corp_secret: "secret".to_string(),
+            agent_id: 1000002,
+            token: "token".to_string(),
+            encoding_aes_key: STANDARD.encode(KEY).trim_end_matches('=').to_string(),
+            api_url: api_url.to_string(),
+        }
+    }
+
+    /// Encrypts a callback the way WeCom does.
+    fn encrypt(msg: &str, corp_id: &str) -> String {
+        let mut plain = b"randomrandomrand".to_vec();
+        plain.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+        plain.extend_from_slice(msg.as_bytes());
+        plain.extend_from_slice(corp_id.as_bytes());
+        let pad = 32 - plain.len() % 32;
+        plain.extend(std::iter::repeat_n(pad as u8, pad));
+        let len = plain.len();
+        let encrypted = cbc::Encryptor::<aes::Aes256>::new_from_slices(&KEY, &KEY[..16])
+            .unwrap()
+            .encrypt_padded_mut::<NoPadding>(&mut plain, len)
+            .unwrap()
+            .to_vec();
+        STANDARD.encode(encrypted)
+    }
+
+    #[test]
+    fn test_callbacks_are_verified_decrypted_and_mapped() {
+        let crypto = WecomCrypto::new(&config("")).unwrap();
+        let xml = "<xml><ToUserName><![CDATA[ww123]]></ToUserName><FromUserName><![CDATA[zhangsan]]></FromUserName>\
+            <CreateTime>1700000000</CreateTime><MsgType><![CDATA[text]]></MsgType><Content><![CDATA[hello]]></Content>\
+            <MsgId>1234567890</MsgId><AgentID>1000002</AgentID></xml>";
+        let encrypted = encrypt(xml, "ww123");
+
+        let signature = crypto.signature("1700000000", "nonce", &encrypted);
+        assert!(crypto.verify(&signature, "1700000000", "nonce", &encrypted));
+        assert!(!crypto.verify(&signature, "1700000001", "nonce", &encrypted));
+        assert_eq!(crypto.decrypt(&encrypted).unwrap(), xml);
+        assert!(crypto.decrypt(&encrypt(xml, "ww456")).is_err());
+
+        let event = callback_event(xml, "@alice:example.com").unwrap();
+        assert_eq!(event.event_type, EventType::Text);
+        assert_eq!((event.id.as_str(), event.chat.id.as_str()), ("1234567890", "zhangsan"));
+        assert_eq!(event.chat.chat_type, ChatType::Private);
+        assert_eq!((event.content.as_deref(), event.timestamp), (Some("hello"), 1700000000000));
+        assert_eq!(event.mxid, "@alice:example.com");
+
+        let image = xml.replace("<MsgType><![CDATA[text]]></MsgType>", "<MsgType><![CDATA[image]]></MsgType><MediaId>media1</MediaId>");
+        let event = callback_event(&image, "@alice:example.com").unwrap();
+        assert_eq!(event.event_type, EventType::Photo);
+        assert_eq!(event.data.unwrap()["xml"], "media1");
+        assert!(callback_event(&xml.replace("text", "event"), "@alice:example.com").is_none());
+    }
+
+    /// Answers each request with the response for its API path, returning the paths requested.
+    async fn api(responses: &'static [(&'static str, &'static str)]) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let mut requested = Vec::new();
+            for _ in 0..responses.len() {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let n = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                let path = request.split_whitespace().nth(1).unwrap().to_string();
+                let body = responses.iter().find(|(p, _)| path.starts_with(p)).map(|(_, body)| *body).unwrap();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(), body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                requested.push(path);
+            }
+            requested
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn test_client_logs_in_and_sends_to_members() {
+        let (url, requested) = api(&[
+            ("/cgi-bin/gettoken", r#"{"errcode":0,"access_token":"tok","expires_in":7200}"#),
+            ("/cgi-bin/appchat/get", r#"{"errcode":86003,"errmsg":"chat not found"}"#),
+            ("/cgi-bin/message/send", r#"{"errcode":0,"errmsg":"ok","msgid":"msg1"}"#),
+        ]).await;
+        let client = WecomClient::new(config(&url), reqwest::Client::new());
+        client.connect().await.unwrap();
+        assert_eq!(client.send_text_message("zhangsan", "hi", None).await.unwrap(), "msg1");
+
+        let requested = requested.await.unwrap();
+        assert_eq!(requested[0], "/cgi-bin/gettoken?corpid=ww123&corpsecret=secret");
+        assert_eq!(requested[2], "/cgi-bin/message/send?access_token=tok");
+    }
+}
+
+#[cfg(test)]
+mod offline_admin_tests {
+    use matrix_bridge_wechat::database::{MediaCacheEntry, Portal};
+    use crate::common::{portal, test_db};
+
+    #[tokio::test]
+    async fn test_all_portals_are_listed_and_media_cache_cleared() {
+        let db = test_db("offline-admin").await;
+
+        for (uid, mxid) in [("wxid_bob", Some("!room:example.com")), ("@@group", None)] {
+            db.insert_portal(&Portal { mxid: mxid.map(str::to_string), ..portal(uid, "wxid_alice") }).await.unwrap();
+        }
+        assert_eq!(db.get_all_portals().await.unwrap().len(), 2);
+        assert_eq!(db.get_all_portals_with_mxid().await.unwrap().len(), 1);
+
+        db.upsert_media_cache(&MediaCacheEntry {
+            hash: "abc".to_string(),
+            mxc: "mxc://example.com/abc".to_string(),
+            mimetype: "image/png".to_string(),
+            size: 3,
+            created_at: 0,
+            last_used: chrono::Utc::now().timestamp(),
+        }).await.unwrap();
+        assert_eq!(db.clear_media_cache().await.unwrap(), 1);
+        assert!(db.get_media_cache("abc").await.unwrap().is_none());
+    }
+}
+
+#[cfg(test)]
+mod chat_watermark_tests {
+    use matrix_bridge_wechat::database::ChatWatermark;
+
+    use crate::common::test_db;
+
+    fn watermark(mxid: &str, last_ts: i64) -> ChatWatermark {
+        ChatWatermark {
+            mxid: mxid.to_string(),
+            chat_id: "123@chatroom".to_string(),
+            last_ts,
+            last_msg_id: "m2".to_string(),
+        }
+    }
+
+    #[test]
+    fn replays_are_recognized() {
+        let watermark = watermark("@alice:example.com", 10_000);
+        // The last bridged event itself, whatever its timestamp.
+        assert!(watermark.is_replay("m2", 20_000, 0));
+        // Older events beyond the tolerance.
+        assert!(watermark.is_replay("m1", 4_000, 5_000));
+        // Older events within the tolerance may just be out of order.
+        assert!(!watermark.is_replay("m1", 6_000, 5_000));
+        // Events with the same timestamp but another ID.
+        assert!(!watermark.is_replay("m3", 10_000, 0));
+        assert!(!watermark.is_replay("m3", 11_000, 0));
+    }
+
+    #[tokio::test]
+    async fn watermarks_are_kept_per_user() {
+        let db = test_db("chat-watermark").await;
+        db.upsert_chat_watermark(&watermark("@alice:example.com", 10_000)).await.unwrap();
+        db.upsert_chat_watermark(&watermark("@bob:example.com", 3_000)).await.unwrap();
+        db.upsert_chat_watermark(&watermark("@alice:example.com", 12_000)).await.unwrap();
+
+        let alice = db.get_chat_watermark("@alice:example.com", "123@chatroom").await.unwrap().unwrap();
+        assert_eq!(alice.last_ts, 12_000);
+        // Alice being ahead doesn't make Bob's events look replayed.
+        let bob = db.get_chat_watermark("@bob:example.com", "123@chatroom").await.unwrap().unwrap();
+        assert_eq!(bob.last_ts, 3_000);
+        assert!(!bob.is_replay("m5", 5_000, 0));
+        assert!(db.get_chat_watermark("@carol:example.com", "123@chatroom").await.unwrap().is_none());
+    }
+}
+
+#[cfg(test)]
+mod last_message_tests {
+    use matrix_bridge_wechat::database::{Message, PortalKey};
+    use crate::common::{portal, test_db};
+
+    #[tokio::test]
+    async fn test_last_message_is_per_portal() {
+        let db = test_db("last-message").await;
+        for (receiver, msg_id, timestamp) in [("wxid_me", "m1", 1000), ("wxid_other", "m2", 2000)] {
+            db.insert_portal(&portal("123@chatroom", receiver)).await.unwrap();
+            db.insert_message(&Message {
+                chat_uid: "123@chatroom".to_string(),
+                chat_receiver: receiver.to_string(),
+                msg_id: msg_id.to_string(),
+                mxid: format!("${}", msg_id),
+                sender: "wxid_alice".to_string(),
+                timestamp,
+                sent: true,
+                error: None,
+                msg_type: "m.text".to_string(),
+            }).await.unwrap();
+        }
+
+        // Pats and read receipts of one account never target the other's newer message.
+        let mine = PortalKey::new("123@chatroom", "wxid_me");
+        assert_eq!(db.get_last_message(&mine).await.unwrap().unwrap().msg_id, "m1");
+        assert!(db.get_message_by_id(&mine, "m2").await.unwrap().is_none());
+    }
+}
+
+#[cfg(test)]
+mod registration_tests {
+    use matrix_bridge_wechat::config::Config;
+    use matrix_bridge_wechat::setup;
+
+    use crate::common::TempFile;
+
+    fn write_config(name: &str) -> TempFile {
+        let file = TempFile::new(name);
+        let example = std::fs::read_to_string("example-config.yaml").unwrap();
+        std::fs::write(file.uri(), example.replace("\"example.com\": user", "\"example.org\": user")).unwrap();
+        file
+    }
+
+    fn registration_value(path: &TempFile, key: &str) -> String {
+        let yaml: serde_yaml::Value = serde_yaml::from_str(&std::fs::read_to_string(path.uri()).unwrap()).unwrap();
+        yaml[key].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_registration_matches_config() {
+        let config_file = write_config("registration-config.yaml");
+        let registration = TempFile::new("registration.yaml");
+        setup::generate_registration(config_file.uri().as_ref(), registration.uri().as_ref(), false).unwrap();
+
+        let config = Config::load(&config_file.uri()).unwrap();
+        assert_eq!(registration_value(&registration, "id"), config.appservice.id);
+        assert_eq!(registration_value(&registration, "url"), config.appservice.address);
+        assert_eq!(registration_value(&registration, "as_token"), config.appservice.as_token);
+        assert_eq!(registration_value(&registration, "hs_token"), config.appservice.hs_token);
+        assert_eq!(registration_value(&registration, "sender_localpart"), config.appservice.bot.username);
+    }
+
+    #[test]
+    fn test_regenerate_tokens_only_replaces_tokens() {
+        let config_file = write_config("regenerate-config.yaml");
+        let before = std::fs::read_to_string(config_file.uri()).unwrap();
+        let old = Config::load(&config_file.uri()).unwrap();
+        let registration = TempFile::new("regenerated.yaml");
+        setup::generate_registration(config_file.uri().as_ref(), registration.uri().as_ref(), true).unwrap();
+
+        let after = std::fs::read_to_string(config_file.uri()).unwrap();
+        let new = Config::load(&config_file.uri()).unwrap();
+        assert_ne!(new.appservice.as_token, old.appservice.as_token);
+        assert_ne!(new.appservice.hs_token, old.appservice.hs_token);
+        assert_ne!(new.appservice.as_token, new.appservice.hs_token);
+        assert_eq!(new.appservice.as_token.len(), 64);
+        assert!(new.appservice.as_token.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_eq!(registration_value(&registration, "as_token"), new.appservice.as_token);
+        assert_eq!(registration_value(&registration, "hs_token"), new.appservice.hs_token);
+
+        // Every other line, comments included, is left as it was.
+        let changed: Vec<_> = before.lines().zip(after.lines()).filter(|(b, a)| b != a).map(|(_, a)| a.trim()).collect();
+        assert_eq!(before.lines().count(), after.lines().count());
+        assert_eq!(changed.len(), 2);
+        assert!(changed[0].starts_with("as_token:") && changed[1].starts_with("hs_token:"));
+    }
+}
+
+#[cfg(test)]
+mod edit_tests {
+    use std::sync::atomic::Ordering;
+    use matrix_bridge_wechat::database::{Portal, PortalKey, User};
+    use matrix_bridge_wechat::matrix::{MatrixEventHandler, RoomEvent};
+    use matrix_bridge_wechat::wechat::Event;
+    use crate::common::{ALICE, TestBridge, message, portal, test_bridge};
+
+    const ROOM: &str = "!bob:example.com";
+
+    async fn bridge_with_message(name: &str) -> TestBridge {
+        let test = test_bridge(name).await;
+        let db = &test.bridge.db;
+        db.insert_portal(&Portal { mxid: Some(ROOM.to_string()), ..portal("wxid_bob", "wxid_me") }).await.unwrap();
+        db.insert_message(&message("wxid_bob", "wx1", "$orig", ALICE)).await.unwrap();
+        test
+    }
+
+    fn matrix_edit() -> RoomEvent {
+        serde_json::from_value(serde_json::json!({
+            "type": "m.room.message",
+            "room_id": ROOM,
+            "sender": ALICE,
+            "event_id": "$edit",
+            "content": {
+                "msgtype": "m.text",
+                "body": "* fixed",
+                "m.new_content": { "msgtype": "m.text", "body": "fixed" },
+                "m.relates_to": { "rel_type": "m.replace", "event_id": "$orig" },
+            },
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_matrix_edits_edit_the_wechat_message() {
+        let test = bridge_with_message("edit-matrix").await;
+        MatrixEventHandler::new(test.bridge.clone()).handle_event(&matrix_edit()).await.unwrap();
+        assert_eq!(test.wechat.calls(), ["edit_text wxid_bob wx1 fixed"]);
+    }
+
+    #[tokio::test]
+    async fn test_matrix_edits_of_others_messages_are_ignored() {
+        let test = bridge_with_message("edit-others").await;
+        test.bridge.db.insert_message(&message("wxid_bob", "wx2", "$bobs", "@bob:example.com")).await.unwrap();
+        let mut edit = matrix_edit();
+        edit.content.as_mut().unwrap()["m.relates_to"]["event_id"] = "$bobs".into();
+        MatrixEventHandler::new(test.bridge.clone()).handle_event(&edit).await.unwrap();
+        assert!(test.wechat.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_matrix_edits_need_a_login_or_relay() {
+        let test = bridge_with_message("edit-logged-out").await;
+        test.bridge.db.update_user(&User::new(ALICE)).await.unwrap();
+        MatrixEventHandler::new(test.bridge.clone()).handle_event(&matrix_edit()).await.unwrap();
+        assert!(test.wechat.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_matrix_edits_fall_back_to_a_correction() {
+        let test = bridge_with_message("edit-correction").await;
+        test.wechat.edits_fail.store(true, Ordering::SeqCst);
+        MatrixEventHandler::new(test.bridge.clone()).handle_event(&matrix_edit()).await.unwrap();
+
+        assert_eq!(test.wechat.calls(), ["send_text wxid_bob * fixed reply=wx1"]);
+        let key = PortalKey::new("wxid_bob", "wxid_me");
+        let correction = test.bridge.db.get_message_by_id(&key, "sent:* fixed").await.unwrap().unwrap();
+        assert_eq!(correction.mxid, "$edit");
+    }
+
+    fn wechat_correction(edit_of: &str) -> Event {
+        let event: Event = serde_json::from_value(serde_json::json!({
+            "id": "wx2",
+            "timestamp": 1700000000000i64,
+            "from": { "id": "wxid_bob", "username": "Bob" },
+            "chat": { "id": "wxid_bob", "type": "private" },
+            "type": "text",
+            "content": "fixed",
+            "data": { "edit_of": edit_of },
+        }))
+        .unwrap();
+        Event { mxid: ALICE.to_string(), ..event }
+    }
+
+    #[tokio::test]
+    async fn test_wechat_corrections_become_matrix_edits() {
+        let test = bridge_with_message("edit-wechat").await;
+        test.bridge.handle_wechat_event(wechat_correction("wx1")).await.unwrap();
+
+        let sends = test.homeserver.requests_to(&format!("/rooms/{}/send/m.room.message/", ROOM));
+        assert_eq!(sends.len(), 1, "{:?}", test.homeserver.requests());
+        assert_eq!(sends[0].method, "PUT");
+        let content = &sends[0].body;
+        assert_eq!(content["m.relates_to"]["rel_type"], "m.replace");
+        assert_eq!(content["m.relates_to"]["event_id"], "$orig");
+        assert_eq!(content["m.new_content"]["body"], "fixed");
+    }
+
+    #[tokio::test]
+    async fn test_wechat_corrections_only_edit_messages_in_their_chat() {
+        let test = bridge_with_message("edit-wechat-other-chat").await;
+        let db = &test.bridge.db;
+        db.insert_portal(&Portal { mxid: Some("!carol:example.com".to_string()), ..portal("wxid_carol", "wxid_me") }).await.unwrap();
+        db.insert_message(&message("wxid_carol", "wx9", "$carol", ALICE)).await.unwrap();
+        test.bridge.handle_wechat_event(wechat_correction("wx9")).await.unwrap();
+
+        let sends = test.homeserver.requests_to(&format!("/rooms/{}/send/m.room.message/", ROOM));
+        assert_eq!(sends.len(), 1, "{:?}", test.homeserver.requests());
+        assert!(sends[0].body.get("m.new_content").is_none());
+        assert_eq!(sends[0].body["body"], "fixed");
+    }
+
+    #[tokio::test]
+    async fn test_wechat_replies_only_quote_messages_in_their_chat() {
+        let test = bridge_with_message("reply-wechat").await;
+        let db = &test.bridge.db;
+        db.insert_portal(&Portal { mxid: Some("!carol:example.com".to_string()), ..portal("wxid_carol", "wxid_me") }).await.unwrap();
+        db.insert_message(&message("wxid_carol", "wx9", "$carol", ALICE)).await.unwrap();
+        for (id, quoted) in [("wx2", "wx1"), ("wx3", "wx9")] {
+            let event: Event = serde_json::from_value(serde_json::json!({
+                "id": id,
+                "timestamp": 1700000000000i64,
+                "from": { "id": "wxid_bob", "username": "Bob" },
+                "chat": { "id": "wxid_bob", "type": "private" },
+                "type": "text",
+                "content": "indeed",
+                "reply": { "id": quoted, "ts": 0, "sender": "wxid_me", "content": "hi" },
+            }))
+            .unwrap();
+            test.bridge.handle_wechat_event(Event { mxid: ALICE.to_string(), ..event }).await.unwrap();
+        }
+
+        let sends = test.homeserver.requests_to(&format!("/rooms/{}/send/m.room.message/", ROOM));
+        assert_eq!(sends.len(), 2, "{:?}", test.homeserver.requests());
+        assert_eq!(sends[0].body["m.relates_to"]["m.in_reply_to"]["event_id"], "$orig");
+        assert!(sends[1].body.get("m.relates_to").is_none());
+    }
+}
+
+#[cfg(test)]
+mod lookup_command_tests {
+    use matrix_bridge_wechat::database::Portal;
+    use matrix_bridge_wechat::matrix::MatrixEventHandler;
+    use crate::common::{ALICE, alice_says, portal, test_bridge};
+
+    const ROOM: &str = "!commands:example.com";
+
+    #[tokio::test]
+    async fn test_id_prints_the_chat_of_the_portal() {
+        let test = test_bridge("lookup-id").await;
+        test.bridge.db.insert_portal(&Portal { mxid: Some(ROOM.to_string()), ..portal("wxid_bob", "wxid_me") }).await.unwrap();
+
+        MatrixEventHandler::new(test.bridge.clone()).handle_event(&alice_says(ROOM, "!wechat id")).await.unwrap();
+
+        assert_eq!(
+            test.homeserver.messages_in(ROOM),
+            [format!("WeChat chat ID: wxid_bob\nReceiver: wxid_me\nMatrix room ID: {}", ROOM)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_open_only_invites_to_the_senders_portal() {
+        let test = test_bridge("lookup-open").await;
+        let handler = MatrixEventHandler::new(test.bridge.clone());
+        let db = &test.bridge.db;
+        db.insert_portal(&Portal { mxid: Some("!theirs:example.com".to_string()), ..portal("wxid_bob", "wxid_other") }).await.unwrap();
+
+        handler.handle_event(&alice_says(ROOM, "!wechat open wxid_bob")).await.unwrap();
+        assert!(test.homeserver.requests_to("/invite").is_empty());
+
+        db.insert_portal(&Portal { mxid: Some("!mine:example.com".to_string()), ..portal("wxid_bob", "wxid_me") }).await.unwrap();
+        handler.handle_event(&alice_says(ROOM, "!wechat open wxid_bob")).await.unwrap();
+
+        let invites = test.homeserver.requests_to("/invite");
+        assert_eq!(invites.len(), 1);
+        assert!(invites[0].path.contains("/rooms/!mine:example.com/invite"));
+        assert_eq!(invites[0].body["user_id"], ALICE);
+        assert_eq!(
+            test.homeserver.messages_in(ROOM),
+            ["No portal found for wxid_bob.", "Portal for wxid_bob: !mine:example.com"]
+        );
+    }
+}
+
+#[cfg(test)]
+mod message_marker_tests {
+    use matrix_bridge_wechat::database::Portal;
+    use matrix_bridge_wechat::matrix::MatrixEventHandler;
+    use crate::common::{ALICE, alice_says, portal, test_bridge};
+
+    const ROOM: &str = "!bob:example.com";
+
+    #[tokio::test]
+    async fn test_marker_command_marks_outgoing_text() {
+        let test = test_bridge("marker").await;
+        test.bridge.db.insert_portal(&Portal { mxid: Some(ROOM.to_string()), ..portal("wxid_bob", "wxid_me") }).await.unwrap();
+        let handler = MatrixEventHandler::new(test.bridge.clone());
+
+        handler.handle_event(&alice_says(ROOM, "hello")).await.unwrap();
+        handler.handle_event(&alice_says(ROOM, "!wechat marker on")).await.unwrap();
+        handler.handle_event(&alice_says(ROOM, "hello again")).await.unwrap();
+
+        assert_eq!(
+            test.wechat.calls(),
+            ["send_text wxid_bob hello reply=-", "send_text wxid_bob hello again [via Matrix] reply=-"]
+        );
+        let setting = test.bridge.db.get_user_setting(ALICE).await.unwrap().unwrap();
+        assert_eq!(setting.message_marker, Some(true));
+        assert_eq!(test.homeserver.messages_in(ROOM), ["Your messages to WeChat are marked as sent from Matrix."]);
+    }
+}
+
+#[cfg(test)]
+mod create_group_tests {
+    use matrix_bridge_wechat::database::PortalKey;
+    use matrix_bridge_wechat::matrix::MatrixEventHandler;
+    use crate::common::{ALICE, alice_says, test_bridge};
+
+    const ROOM: &str = "!plans:example.com";
+
+    #[tokio::test]
+    async fn test_create_group_binds_the_room() {
+        let test = test_bridge("create-group").await;
+        test.homeserver.respond_with("joined", serde_json::json!({
+            ALICE: {},
+            "@wechatbot:example.com": {},
+            "@wechat_wxid_bob:example.com": {},
+            "@wechat_wxid_carol:example.com": {},
+        }));
+
+        MatrixEventHandler::new(test.bridge.clone()).handle_event(&alice_says(ROOM, "!wechat create-group Trip")).await.unwrap();
+
+        assert_eq!(
+            test.wechat.calls(),
+            ["create_group wxid_bob,wxid_carol Trip", "set_group_name new@chatroom Trip"]
+        );
+        let portal = test.bridge.db.get_portal_by_key(&PortalKey::new("new@chatroom", "wxid_me")).await.unwrap().unwrap();
+        assert_eq!(portal.mxid.as_deref(), Some(ROOM));
+        assert_eq!(portal.name, "Trip");
+    }
+
+    #[tokio::test]
+    async fn test_create_group_needs_contacts_in_the_room() {
+        let test = test_bridge("create-group-empty").await;
+        test.homeserver.respond_with("joined", serde_json::json!({ ALICE: {} }));
+
+        MatrixEventHandler::new(test.bridge.clone()).handle_event(&alice_says(ROOM, "!wechat create-group Trip")).await.unwrap();
+
+        assert!(test.wechat.calls().is_empty());
+        assert_eq!(
+            test.homeserver.messages_in(ROOM),
+            ["Invite the WeChat contacts for the new group to this room first."]
+        );
+    }
+}
+
+#[cfg(test)]
+mod group_member_sync_tests {
+    use matrix_bridge_wechat::database::Portal;
+    use crate::common::{GROUP_ID, portal, test_bridge};
+
+    const ROOM: &str = "!family:example.com";
+
+    #[tokio::test]
+    async fn test_sync_invites_members_and_removes_leavers() {
+        let test = test_bridge("member-sync").await;
+        test.homeserver.respond_with("joined", serde_json::json!({
+            "@wechatbot:example.com": {},
+            "@wechat_wxid_bob:example.com": {},
+            "@wechat_wxid_gone:example.com": {},
+        }));
+        let group = Portal { mxid: Some(ROOM.to_string()), ..portal(GROUP_ID, "wxid_me") };
+        test.bridge.db.insert_portal(&group).await.unwrap();
+
+        test.bridge.sync_group_members(group).await.unwrap();
+
+        let invited: Vec<_> = test.homeserver.requests_to(&format!("/rooms/{}/invite", ROOM))
+            .into_iter()
+            .map(|r| r.body["user_id"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(invited, ["@wechat_wxid_me:example.com", "@wechat_wxid_alice:example.com"]);
+        let joins = test.homeserver.requests_to(&format!("/join/{}", ROOM));
+        assert_eq!(joins.len(), 2);
+        assert!(joins[1].path.contains("user_id=@wechat_wxid_alice:example.com"), "{}", joins[1].path);
+
+        let kicks = test.homeserver.requests_to(&format!("/rooms/{}/kick", ROOM));
+        assert_eq!(kicks.len(), 1);
+        assert_eq!(kicks[0].body["user_id"], "@wechat_wxid_gone:example.com");
+
+        let displaynames = test.homeserver.requests_to("/profile/@wechat_wxid_alice:example.com/displayname");
+        assert!(!displaynames.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sync_needs_the_receiver_logged_in() {
+        let test = test_bridge("member-sync-receiver").await;
+        let group = Portal { mxid: Some(ROOM.to_string()), ..portal(GROUP_ID, "wxid_other") };
+        test.bridge.db.insert_portal(&group).await.unwrap();
+
+        let err = test.bridge.sync_group_members(group).await.unwrap_err();
+        assert_eq!(err.to_string(), "no user is logged in as wxid_other");
+        assert!(test.homeserver.requests_to("/invite").is_empty());
+    }
+}
+
+#[cfg(test)]
+mod bridge_command_tests {
+    use matrix_bridge_wechat::database::{Portal, PortalKey};
+    use matrix_bridge_wechat::matrix::MatrixEventHandler;
+    use crate::common::{GROUP_ID, alice_says, portal, test_bridge};
+
+    const ROOM: &str = "!existing:example.com";
+
+    #[tokio::test]
+    async fn test_bridge_and_unbridge_an_existing_room() {
+        let test = test_bridge("bridge-command").await;
+        test.homeserver.respond_with("joined", serde_json::json!({
+            "@wechatbot:example.com": {},
+            "@wechat_wxid_bob:example.com": {},
+        }));
+        let handler = MatrixEventHandler::new(test.bridge.clone());
+        let key = PortalKey::new(GROUP_ID, "wxid_me");
+
+        handler.handle_event(&alice_says(ROOM, &format!("!wechat bridge {}", GROUP_ID))).await.unwrap();
+        let portal = test.bridge.db.get_portal_by_key(&key).await.unwrap().unwrap();
+        assert_eq!(portal.mxid.as_deref(), Some(ROOM));
+        let bridge_states = test.homeserver.requests_to(&format!("/rooms/{}/state/m.room.bridge/", ROOM));
+        assert_eq!(bridge_states.len(), 1);
+        assert_eq!(bridge_states[0].body["channel"]["id"], GROUP_ID);
+
+        handler.handle_event(&alice_says(ROOM, "!wechat unbridge")).await.unwrap();
+        assert!(test.bridge.db.get_portal_by_key(&key).await.unwrap().is_none());
+        let bridge_states = test.homeserver.requests_to(&format!("/rooms/{}/state/m.room.bridge/", ROOM));
+        assert_eq!(bridge_states.last().unwrap().body, serde_json::json!({}));
+        let mut kicked: Vec<_> = test.homeserver.requests_to(&format!("/rooms/{}/kick", ROOM))
+            .into_iter()
+            .map(|r| r.body["user_id"].as_str().unwrap().to_string())
+            .collect();
+        kicked.sort();
+        assert_eq!(
+            kicked,
+            ["@wechat_wxid_alice:example.com", "@wechat_wxid_bob:example.com", "@wechat_wxid_me:example.com"]
+        );
+
+        assert_eq!(
+            test.homeserver.messages_in(ROOM),
+            [
+                format!("Bridged this room to WeChat group Family ({}).", GROUP_ID),
+                "This room is no longer bridged to WeChat.".to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bridge_refuses_a_group_bridged_elsewhere() {
+        let test = test_bridge("bridge-command-taken").await;
+        let elsewhere = Portal { mxid: Some("!elsewhere:example.com".to_string()), ..portal(GROUP_ID, "wxid_me") };
+        test.bridge.db.insert_portal(&elsewhere).await.unwrap();
+
+        MatrixEventHandler::new(test.bridge.clone())
+            .handle_event(&alice_says(ROOM, &format!("!wechat bridge {}", GROUP_ID)))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            test.homeserver.messages_in(ROOM),
+            [format!("{} is already bridged to !elsewhere:example.com.", GROUP_ID)]
+        );
+        let portal = test.bridge.db.get_portal_by_key(&PortalKey::new(GROUP_ID, "wxid_me")).await.unwrap().unwrap();
+        assert_eq!(portal.mxid.as_deref(), Some("!elsewhere:example.com"));
+    }
+}
+
+#[cfg(test)]
+mod puppet_sender_tests {
+    use matrix_bridge_wechat::database::{Portal, PortalKey};
+    use matrix_bridge_wechat::wechat::Event;
+    use crate::common::{ALICE, GROUP_ID, portal, test_bridge};
+
+    const ROOM: &str = "!family:example.com";
+    const BOB: &str = "@wechat_wxid_bob:example.com";
+
+    fn group_text(id: &str, content: &str, data: serde_json::Value) -> Event {
+        let event: Event = serde_json::from_value(serde_json::json!({
+            "id": id,
+            "timestamp": 1700000000000i64,
+            "from": { "id": "wxid_bob", "username": "Bob" },
+            "chat": { "id": GROUP_ID, "type": "group" },
+            "type": "text",
+            "content": content,
+            "data": data,
+        }))
+        .unwrap();
+        Event { mxid: ALICE.to_string(), ..event }
+    }
+
+    #[tokio::test]
+    async fn test_messages_and_edits_come_from_the_senders_ghost() {
+        let test = test_bridge("puppet-sender").await;
+        test.bridge.db.insert_portal(&Portal { mxid: Some(ROOM.to_string()), ..portal(GROUP_ID, "wxid_me") }).await.unwrap();
+
+        test.bridge.handle_wechat_event(group_text("wx1", "hi", serde_json::json!({}))).await.unwrap();
+        test.bridge.handle_wechat_event(group_text("wx2", "hi all", serde_json::json!({ "edit_of": "wx1" }))).await.unwrap();
+
+        let sends = test.homeserver.requests_to(&format!("/rooms/{}/send/m.room.message/", ROOM));
+        assert_eq!(sends.len(), 2, "{:?}", test.homeserver.requests());
+        for send in &sends {
+            assert!(send.path.contains(&format!("user_id={}", BOB)), "{}", send.path);
+        }
+        let key = PortalKey::new(GROUP_ID, "wxid_me");
+        let message = test.bridge.db.get_message_by_id(&key, "wx1").await.unwrap().unwrap();
+        assert_eq!(message.sender, BOB);
+    }
+
+    #[tokio::test]
+    async fn test_recalls_are_redacted_by_the_sender_in_the_portal() {
+        let test = test_bridge("puppet-recall").await;
+        test.bridge.db.insert_portal(&Portal { mxid: Some(ROOM.to_string()), ..portal(GROUP_ID, "wxid_me") }).await.unwrap();
+        test.bridge.handle_wechat_event(group_text("wx1", "hi", serde_json::json!({}))).await.unwrap();
+        let key = PortalKey::new(GROUP_ID, "wxid_me");
+        let message = test.bridge.db.get_message_by_id(&key, "wx1").await.unwrap().unwrap();
+
+        let recall: Event = serde_json::from_value(serde_json::json!({
+            "id": "wx2",
+            "timestamp": 1700000001000i64,
+            "from": { "id": "wxid_bob", "username": "Bob" },
+            "chat": { "id": GROUP_ID, "type": "group" },
+            "type": "revoke",
+            "data": { "msg_id": "wx1" },
+        }))
+        .unwrap();
+        test.bridge.handle_wechat_event(Event { mxid: ALICE.to_string(), ..recall }).await.unwrap();
+
+        let redactions = test.homeserver.requests_to(&format!("/rooms/{}/redact/{}/", ROOM, message.mxid));
+        assert_eq!(redactions.len(), 1, "{:?}", test.homeserver.requests());
+        assert!(redactions[0].path.contains(&format!("user_id={}", BOB)), "{}", redactions[0].path);
+    }
+}
+
+#[cfg(test)]
+mod redaction_tests {
+    use matrix_bridge_wechat::database::Portal;
+    use matrix_bridge_wechat::matrix::{MatrixEventHandler, RoomEvent};
+    use crate::common::{ALICE, message, portal, test_bridge};
+
+    const ROOM: &str = "!bob:example.com";
+
+    #[tokio::test]
+    async fn test_redactions_revoke_with_the_portal_owners_agent() {
+        let test = test_bridge("redaction").await;
+        let db = &test.bridge.db;
+        db.insert_portal(&Portal { mxid: Some(ROOM.to_string()), ..portal("wxid_bob", "wxid_me") }).await.unwrap();
+        db.insert_message(&message("wxid_bob", "wx1", "$orig", ALICE)).await.unwrap();
+
+        let redaction: RoomEvent = serde_json::from_value(serde_json::json!({
+            "type": "m.room.redaction",
+            "room_id": ROOM,
+            "sender": ALICE,
+            "event_id": "$redaction",
+            "redacts": "$orig",
+            "content": {},
+        }))
+        .unwrap();
+        MatrixEventHandler::new(test.bridge.clone()).handle_event(&redaction).await.unwrap();
+
+        assert_eq!(test.wechat.calls(), ["revoke wxid_bob wx1"]);
+    }
+}
+
+#[cfg(test)]
+mod puppet_profile_tests {
+    use std::time::Duration;
+
+    use matrix_bridge_wechat::database::Portal;
+    use matrix_bridge_wechat::wechat::Event;
+    use crate::common::{ALICE, portal, test_bridge};
+
+    #[tokio::test]
+    async fn test_profiles_are_fetched_with_the_users_agent() {
+        let test = test_bridge("puppet-profile").await;
+
+        test.bridge.sync_puppet_profile(ALICE, "wxid_carol", None).await.unwrap();
+        let names = test.homeserver.requests_to("/profile/@wechat_wxid_carol:example.com/displayname");
+        assert!(names[0].body["displayname"].as_str().unwrap().contains("carol"), "{:?}", names);
+
+        assert!(test.bridge.sync_puppet_profile("@nobody:example.com", "wxid_dave", None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_new_senders_get_a_profile() {
+        let test = test_bridge("puppet-profile-sender").await;
+        let room = "!dave:example.com";
+        test.bridge.db.insert_portal(&Portal { mxid: Some(room.to_string()), ..portal("wxid_dave", "wxid_me") }).await.unwrap();
+        let event: Event = serde_json::from_value(serde_json::json!({
+            "id": "wx1",
+            "timestamp": 1700000000000i64,
+            "from": { "id": "wxid_dave", "username": "Dave" },
+            "chat": { "id": "wxid_dave", "type": "private" },
+            "type": "text",
+            "content": "hi",
+        }))
+        .unwrap();
+        test.bridge.handle_wechat_event(Event { mxid: ALICE.to_string(), ..event }).await.unwrap();
+
+        // The profile is synced in the background.
+        let path = "/profile/@wechat_wxid_dave:example.com/displayname";
+        for _ in 0..50 {
+            if !test.homeserver.requests_to(path).is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let names = test.homeserver.requests_to(path);
+        assert!(names[0].body["displayname"].as_str().unwrap().contains("dave"), "{:?}", names);
+    }
+}
+
+#[cfg(test)]
+mod mute_tests {
+    use matrix_bridge_wechat::database::{Portal, PortalKey, PortalSetting, User};
+    use matrix_bridge_wechat::wechat::Event;
+    use crate::common::{ALICE, GROUP_ID, portal, test_bridge};
+
+    const BOB: &str = "@bob:example.com";
+
+    fn group_text(mxid: &str, id: &str) -> Event {
+        let event: Event = serde_json::from_value(serde_json::json!({
+            "id": id,
+            "timestamp": 1700000000000i64,
+            "from": { "id": "wxid_carol", "username": "Carol" },
+            "chat": { "id": GROUP_ID, "type": "group" },
+            "type": "text",
+            "content": "hi",
+        }))
+        .unwrap();
+        Event { mxid: mxid.to_string(), ..event }
+    }
+
+    #[tokio::test]
+    async fn test_muting_only_silences_the_users_portal() {
+        let test = test_bridge("mute").await;
+        let db = &test.bridge.db;
+        db.insert_user(&User { uin: Some("wxid_other".to_string()), ..User::new(BOB) }).await.unwrap();
+        db.insert_portal(&Portal { mxid: Some("!alice:example.com".to_string()), ..portal(GROUP_ID, "wxid_me") }).await.unwrap();
+        db.insert_portal(&Portal { mxid: Some("!bob:example.com".to_string()), ..portal(GROUP_ID, "wxid_other") }).await.unwrap();
+        let muted = PortalSetting { muted: true, ..PortalSetting::new(&PortalKey::new(GROUP_ID, "wxid_me"), ALICE) };
+        db.upsert_portal_setting(&muted).await.unwrap();
+
+        test.bridge.handle_wechat_event(group_text(ALICE, "wx1")).await.unwrap();
+        test.bridge.handle_wechat_event(group_text(BOB, "wx1")).await.unwrap();
+
+        assert!(test.homeserver.messages_in("!alice:example.com").is_empty());
+        assert_eq!(test.homeserver.messages_in("!bob:example.com"), ["hi"]);
+    }
+}
+
+#[cfg(test)]
+mod sticker_tests {
+    use matrix_bridge_wechat::database::Portal;
+    use matrix_bridge_wechat::wechat::Event;
+    use crate::common::{ALICE, portal, test_bridge};
+
+    const ROOM: &str = "!bob:example.com";
+
+    fn sticker(url: &str) -> Event {
+        let event: Event = serde_json::from_value(serde_json::json!({
+            "id": "wx1",
+            "timestamp": 1700000000000i64,
+            "from": { "id": "wxid_bob", "username": "Bob" },
+            "chat": { "id": "wxid_bob", "type": "private" },
+            "type": "sticker",
+            "data": { "url": url },
+        }))
+        .unwrap();
+        Event { mxid: ALICE.to_string(), ..event }
+    }
+
+    #[tokio::test]
+    async fn test_custom_emoji_are_downloaded_from_their_url() {
+        let test = test_bridge("sticker-url").await;
+        test.bridge.db.insert_portal(&Portal { mxid: Some(ROOM.to_string()), ..portal("wxid_bob", "wxid_me") }).await.unwrap();
+
+        test.bridge.handle_wechat_event(sticker(&format!("{}/emoji.gif", test.homeserver.url))).await.unwrap();
+
+        assert_eq!(test.homeserver.requests_to("/emoji.gif").len(), 1);
+        assert_eq!(test.homeserver.requests_to(&format!("/rooms/{}/send/m.sticker/", ROOM)).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_downloads_leave_a_notice() {
+        let test = test_bridge("sticker-failed").await;
+        test.bridge.db.insert_portal(&Portal { mxid: Some(ROOM.to_string()), ..portal("wxid_bob", "wxid_me") }).await.unwrap();
+
+        test.bridge.handle_wechat_event(sticker("http://127.0.0.1:1/emoji.gif")).await.unwrap();
+
+        assert!(test.homeserver.requests_to("/send/m.sticker/").is_empty());
+        let notices = test.homeserver.messages_in(ROOM);
+        assert_eq!(notices.len(), 1);
+        assert!(notices[0].starts_with("A WeChat sticker wasn't bridged: "), "{:?}", notices);
+    }
 }